@@ -0,0 +1,321 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use chrono::{DateTime, Utc};
+use rocket::tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use rocket::tokio::sync::Notify;
+
+use crate::registry_interface::{ActiveTransfer, AsyncSeekRead, TransferDirection};
+
+/*
+ * Uploads and downloads in flight through this frontend, so an admin can see how far each has got
+ * and cut off a runaway one (see DELETE /api/v1/active-transfers/<uuid> in routes/admin.rs).
+ *
+ * Blob uploads are known by the upload's uuid, which every request writing to the upload shares.
+ * Downloads, manifest pushes and imports are given a uuid of their own when they start.
+ *
+ * Cancelling a transfer fails it. An upload stops straight away, even if the client has stalled,
+ * and stops writing to storage. A download fails the next time more of the blob is read, which
+ * closes the connection.
+ */
+
+// Why a cancelled transfer failed, so it can be told apart from the client going away
+#[derive(Debug)]
+struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transfer cancelled by an admin")
+    }
+}
+
+impl StdError for Cancelled {}
+
+fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, Cancelled)
+}
+
+/// Whether the transfer failed because it was cancelled
+pub fn is_cancelled(e: &io::Error) -> bool {
+    e.get_ref().map_or(false, |inner| inner.is::<Cancelled>())
+}
+
+struct Entry {
+    uuid: String,
+    direction: TransferDirection,
+    repo_name: String,
+    digest: Option<String>,
+    started: DateTime<Utc>,
+    bytes: AtomicU64,
+    cancelled: AtomicBool,
+    // Wakes an upload waiting on a stalled client
+    cancel: Notify,
+}
+
+impl Entry {
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ActiveTransfers {
+    entries: Arc<Mutex<Vec<Arc<Entry>>>>,
+}
+
+impl ActiveTransfers {
+    fn track(
+        &self,
+        direction: TransferDirection,
+        repo_name: &str,
+        uuid: Option<&str>,
+        digest: Option<&str>,
+    ) -> Transfer {
+        let entry = Arc::new(Entry {
+            uuid: uuid
+                .map(str::to_string)
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            direction,
+            repo_name: repo_name.to_string(),
+            digest: digest.map(str::to_string),
+            started: Utc::now(),
+            bytes: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            cancel: Notify::new(),
+        });
+        self.entries.lock().unwrap().push(entry.clone());
+        Transfer {
+            entry,
+            transfers: self.clone(),
+        }
+    }
+
+    /// Tracks a request writing to the blob upload
+    pub fn upload(&self, repo_name: &str, uuid: &str) -> Transfer {
+        self.track(TransferDirection::Upload, repo_name, Some(uuid), None)
+    }
+
+    /// Tracks a push that isn't part of a blob upload, such as a manifest or an image archive
+    pub fn push(&self, repo_name: &str) -> Transfer {
+        self.track(TransferDirection::Upload, repo_name, None, None)
+    }
+
+    /// Tracks a download of the blob
+    pub fn download(&self, repo_name: &str, digest: &str) -> Transfer {
+        self.track(TransferDirection::Download, repo_name, None, Some(digest))
+    }
+
+    /// Oldest first
+    pub fn list(&self) -> Vec<ActiveTransfer> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| ActiveTransfer {
+                uuid: e.uuid.clone(),
+                direction: e.direction,
+                repo_name: e.repo_name.clone(),
+                digest: e.digest.clone(),
+                bytes: e.bytes.load(Ordering::Relaxed),
+                started: e.started,
+            })
+            .collect()
+    }
+
+    /// Cancels every transfer with the uuid, returning how many there were
+    pub fn cancel(&self, uuid: &str) -> usize {
+        let entries = self.entries.lock().unwrap();
+        let mut n = 0;
+        for e in entries.iter().filter(|e| e.uuid == uuid) {
+            e.cancelled.store(true, Ordering::Relaxed);
+            e.cancel.notify_one();
+            n += 1;
+        }
+        n
+    }
+}
+
+/// A transfer in flight, which stops being tracked when dropped
+pub struct Transfer {
+    entry: Arc<Entry>,
+    transfers: ActiveTransfers,
+}
+
+impl Transfer {
+    /// Runs the upload until it finishes, failing it if it's cancelled first
+    pub async fn until_cancelled<T>(
+        &self,
+        transfer: impl Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        if self.entry.is_cancelled() {
+            return Err(cancelled());
+        }
+        rocket::tokio::select! {
+            res = transfer => res,
+            _ = self.entry.cancel.notified() => Err(cancelled()),
+        }
+    }
+
+    /// Counts what's written to the sink, refusing writes once the transfer is cancelled
+    pub fn writer<'a, W>(&'a self, inner: &'a mut W) -> CountingWriter<'a, W> {
+        CountingWriter {
+            inner,
+            entry: &self.entry,
+        }
+    }
+
+    /// Counts what's read from the blob, failing reads once the transfer is cancelled
+    pub fn reader(self, inner: Pin<Box<dyn AsyncSeekRead>>) -> Pin<Box<dyn AsyncSeekRead>> {
+        Box::pin(CountingReader {
+            inner,
+            transfer: self,
+        })
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.transfers
+            .entries
+            .lock()
+            .unwrap()
+            .retain(|e| !Arc::ptr_eq(e, &self.entry));
+    }
+}
+
+pub struct CountingWriter<'a, W> {
+    inner: &'a mut W,
+    entry: &'a Entry,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.entry.is_cancelled() {
+            return Poll::Ready(Err(cancelled()));
+        }
+        let res = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.entry.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.entry.is_cancelled() {
+            return Poll::Ready(Err(cancelled()));
+        }
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+struct CountingReader {
+    inner: Pin<Box<dyn AsyncSeekRead>>,
+    transfer: Transfer,
+}
+
+impl AsyncRead for CountingReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.transfer.entry.is_cancelled() {
+            return Poll::Ready(Err(cancelled()));
+        }
+        let before = buf.filled().len();
+        let res = self.inner.as_mut().poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let n = buf.filled().len() - before;
+            self.transfer
+                .entry
+                .bytes
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+impl AsyncSeek for CountingReader {
+    fn start_seek(mut self: Pin<&mut Self>, pos: io::SeekFrom) -> io::Result<()> {
+        self.inner.as_mut().start_seek(pos)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        self.inner.as_mut().poll_complete(cx)
+    }
+}
+
+impl AsyncSeekRead for CountingReader {}
+
+#[cfg(test)]
+mod test {
+    use super::{is_cancelled, ActiveTransfers};
+    use crate::registry_interface::TransferDirection;
+    use rocket::tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+    use std::time::Duration;
+
+    #[rocket::async_test]
+    async fn cancels_stalled_uploads() {
+        let transfers = ActiveTransfers::default();
+        let transfer = transfers.upload("org/app", "abc");
+        let (mut client, mut server) = io::duplex(64);
+        client.write_all(b"some").await.unwrap();
+
+        let cancel = transfers.clone();
+        rocket::tokio::spawn(async move {
+            rocket::tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(cancel.cancel("abc"), 1);
+        });
+        let mut sink = vec![];
+        let mut writer = transfer.writer(&mut sink);
+        // The client never closes its end, so only cancelling ends the copy
+        let err = transfer
+            .until_cancelled(io::copy(&mut server, &mut writer))
+            .await
+            .unwrap_err();
+        assert!(is_cancelled(&err));
+
+        let listed = transfers.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].direction, TransferDirection::Upload);
+        assert_eq!(listed[0].bytes, 4);
+        drop(client);
+        drop(transfer);
+        assert!(transfers.list().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn cancels_downloads() {
+        let transfers = ActiveTransfers::default();
+        let blob = std::io::Cursor::new(vec![7u8; 16]);
+        let mut reader = transfers
+            .download("org/app", "sha256:abc")
+            .reader(Box::pin(blob));
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).await.unwrap();
+
+        let listed = transfers.list();
+        assert_eq!(listed[0].bytes, 4);
+        assert_eq!(listed[0].digest.as_deref(), Some("sha256:abc"));
+        assert_eq!(transfers.cancel("unknown"), 0);
+        assert_eq!(transfers.cancel(&listed[0].uuid), 1);
+        let err = reader.read_exact(&mut buf).await.unwrap_err();
+        assert!(is_cancelled(&err));
+        drop(reader);
+        assert!(transfers.list().is_empty());
+    }
+}
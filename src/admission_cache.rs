@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/*
+ * Cache of the backend's admission decisions, so a large rollout, creating many pods with the
+ * same images, doesn't make a backend call for every pod the webhook is asked about.
+ *
+ * Decisions are for a pod's images in a namespace, under a generation of the policy. Reloading the
+ * policy through this frontend drops the decisions made under the old one, and pushes through it
+ * drop the denials, as the image may have been missing. Other changes, e.g. to TrowPolicy
+ * resources, a change freeze starting or a push through another frontend, can take up to the TTL
+ * to be seen. Requests with a break-glass annotation always go to the backend, so the override is
+ * logged.
+ */
+
+// Dropped when the cache gets this big, rather than tracking which entries are least used
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedDecision {
+    pub allowed: bool,
+    // Blank if allowed
+    pub reason: String,
+}
+
+// Images, namespace and policy generation
+type Key = (Vec<String>, String, u64);
+
+struct Entry {
+    added: Instant,
+    decision: CachedDecision,
+}
+
+struct Decisions {
+    generation: u64,
+    entries: HashMap<Key, Entry>,
+}
+
+pub struct AdmissionCache {
+    ttl: Duration,
+    decisions: Mutex<Decisions>,
+}
+
+impl AdmissionCache {
+    pub fn new(ttl: Duration) -> AdmissionCache {
+        AdmissionCache {
+            ttl,
+            decisions: Mutex::new(Decisions {
+                generation: 0,
+                entries: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn get(&self, images: &[String], namespace: &str) -> Option<CachedDecision> {
+        let decisions = self.decisions.lock().unwrap();
+        let key = (images.to_vec(), namespace.to_string(), decisions.generation);
+        decisions
+            .entries
+            .get(&key)
+            .filter(|e| e.added.elapsed() < self.ttl)
+            .map(|e| e.decision.clone())
+    }
+
+    /// Adds the decision the backend made under the given generation of the policy
+    pub fn insert(
+        &self,
+        images: &[String],
+        namespace: &str,
+        generation: u64,
+        decision: CachedDecision,
+    ) {
+        let mut decisions = self.decisions.lock().unwrap();
+        // Made under rules that have since been replaced
+        if generation != decisions.generation {
+            return;
+        }
+        let ttl = self.ttl;
+        if decisions.entries.len() >= MAX_ENTRIES {
+            decisions.entries.retain(|_, e| e.added.elapsed() < ttl);
+            if decisions.entries.len() >= MAX_ENTRIES {
+                decisions.entries.clear();
+            }
+        }
+        decisions.entries.insert(
+            (images.to_vec(), namespace.to_string(), generation),
+            Entry {
+                added: Instant::now(),
+                decision,
+            },
+        );
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.decisions.lock().unwrap().generation
+    }
+
+    /// After a push, which may be of an image that was denied as it was missing
+    pub fn invalidate_denials(&self) {
+        self.decisions
+            .lock()
+            .unwrap()
+            .entries
+            .retain(|_, e| e.decision.allowed);
+    }
+
+    /// After the policy is reloaded, dropping the decisions made under earlier generations
+    pub fn set_generation(&self, generation: u64) {
+        let mut decisions = self.decisions.lock().unwrap();
+        decisions.generation = generation;
+        decisions.entries.retain(|(_, _, g), _| *g == generation);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AdmissionCache, CachedDecision};
+    use std::time::Duration;
+
+    fn images(images: &[&str]) -> Vec<String> {
+        images.iter().map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn caches_decisions() {
+        let cache = AdmissionCache::new(Duration::from_secs(60));
+        let app = images(&["trow.test/app:v1"]);
+        let allowed = CachedDecision {
+            allowed: true,
+            reason: "".to_string(),
+        };
+        assert!(cache.get(&app, "prod").is_none());
+
+        cache.insert(&app, "prod", 0, allowed.clone());
+        assert_eq!(cache.get(&app, "prod").unwrap(), allowed);
+        assert!(cache.get(&app, "dev").is_none());
+        assert!(cache
+            .get(&images(&["trow.test/app:v1", "quay.io/sidecar:v1"]), "prod")
+            .is_none());
+
+        // Reloading the policy drops decisions under the old one, including any still being made
+        let generation = cache.generation();
+        cache.set_generation(1);
+        assert!(cache.get(&app, "prod").is_none());
+        cache.insert(&app, "prod", generation, allowed.clone());
+        assert!(cache.get(&app, "prod").is_none());
+        cache.insert(&app, "prod", 1, allowed);
+        assert!(cache.get(&app, "prod").is_some());
+
+        cache.insert(
+            &app,
+            "dev",
+            1,
+            CachedDecision {
+                allowed: false,
+                reason: "denied".to_string(),
+            },
+        );
+        cache.invalidate_denials();
+        assert!(cache.get(&app, "dev").is_none());
+        assert!(cache.get(&app, "prod").is_some());
+
+        let cache = AdmissionCache::new(Duration::ZERO);
+        cache.insert(
+            &app,
+            "prod",
+            0,
+            CachedDecision {
+                allowed: false,
+                reason: "denied".to_string(),
+            },
+        );
+        assert!(cache.get(&app, "prod").is_none());
+    }
+}
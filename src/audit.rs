@@ -0,0 +1,224 @@
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use chrono::{SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use log::error;
+use serde::Serialize;
+
+use crate::response::trow_token::TrowToken;
+
+/*
+ * Audit log of registry operations: pushes, pulls, deletes and admission decisions.
+ *
+ * Each operation is written as a line of JSON saying who did what to which repository, from
+ * where, and whether it worked. The log goes to the file given by --audit-log, or stdout if
+ * that's "-", kept apart from the normal log so it can be shipped and retained separately.
+ *
+ * Like the normal log, there's one audit log for the process, set up by init. Until then records
+ * are dropped, so auditing is off by default.
+ */
+
+lazy_static! {
+    static ref AUDIT_LOG: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+}
+
+/// Start writing audit records to the file at path, or stdout for "-"
+pub fn init(path: &str) -> Result<()> {
+    let out: Box<dyn Write + Send> = if path == "-" {
+        Box::new(io::stdout())
+    } else {
+        Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+    };
+    *AUDIT_LOG.lock().unwrap() = Some(out);
+    Ok(())
+}
+
+/// Write the record to the audit log, if there is one
+pub fn record(record: AuditRecord) {
+    let mut log = AUDIT_LOG.lock().unwrap();
+    if let Some(out) = log.as_mut() {
+        let mut line = serde_json::to_vec(&record).expect("Audit record is always valid JSON");
+        line.push(b'\n');
+        // Flushed per record so nothing is lost if we're killed
+        if let Err(e) = out.write_all(&line).and_then(|_| out.flush()) {
+            error!("Failed to write audit record: {}", e);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Push,
+    Pull,
+    Delete,
+    Admit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditResult {
+    Success,
+    Failure,
+    // For admission decisions
+    Allowed,
+    Denied,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: String,
+    pub user: String,
+    pub client_ip: Option<IpAddr>,
+    // Matches the request_id in log lines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub action: AuditAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    // Admission decisions are for a pod's images in a namespace rather than a repository
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+    pub result: AuditResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(action: AuditAction, user: &str, client_ip: Option<IpAddr>) -> AuditRecord {
+        AuditRecord {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            user: user.to_string(),
+            client_ip,
+            request_id: trow_server::request_id::current(),
+            action,
+            repository: None,
+            tag: None,
+            digest: None,
+            namespace: None,
+            images: vec![],
+            result: AuditResult::Success,
+            reason: None,
+        }
+    }
+
+    /// Record for an operation by an authenticated registry client
+    pub fn for_caller(action: AuditAction, caller: &TrowToken) -> AuditRecord {
+        AuditRecord::new(action, &caller.user, caller.client_ip)
+    }
+
+    pub fn repository(mut self, repository: &str) -> AuditRecord {
+        self.repository = Some(repository.to_string());
+        self
+    }
+
+    /// Sets the tag or digest, depending on what the client referred to
+    pub fn reference(mut self, reference: &str) -> AuditRecord {
+        if reference.contains(':') {
+            self.digest = Some(reference.to_string());
+        } else {
+            self.tag = Some(reference.to_string());
+        }
+        self
+    }
+
+    pub fn digest(mut self, digest: impl ToString) -> AuditRecord {
+        self.digest = Some(digest.to_string());
+        self
+    }
+
+    /// Success, or failure with the error as the reason
+    pub fn outcome<T, E: Display>(mut self, res: &Result<T, E>) -> AuditRecord {
+        if let Err(e) = res {
+            self.result = AuditResult::Failure;
+            self.reason = Some(e.to_string());
+        }
+        self
+    }
+
+    pub fn failed(mut self, reason: &str) -> AuditRecord {
+        self.result = AuditResult::Failure;
+        self.reason = Some(reason.to_string());
+        self
+    }
+
+    pub fn admission(
+        mut self,
+        namespace: &str,
+        images: Vec<String>,
+        allowed: bool,
+        reason: Option<String>,
+    ) -> AuditRecord {
+        self.namespace = Some(namespace.to_string());
+        self.images = images;
+        self.result = if allowed {
+            AuditResult::Allowed
+        } else {
+            AuditResult::Denied
+        };
+        self.reason = reason;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditAction, AuditRecord};
+    use serde_json::json;
+
+    #[test]
+    fn serializes_only_relevant_fields() {
+        let rec = AuditRecord::new(AuditAction::Push, "alice", "10.0.0.3".parse().ok())
+            .repository("myapp")
+            .reference("v1")
+            .digest("sha256:abcd")
+            .outcome(&Ok::<(), String>(()));
+        let mut v = serde_json::to_value(&rec).unwrap();
+        v.as_object_mut().unwrap().remove("timestamp");
+        assert_eq!(
+            v,
+            json!({
+                "user": "alice",
+                "client_ip": "10.0.0.3",
+                "action": "push",
+                "repository": "myapp",
+                "tag": "v1",
+                "digest": "sha256:abcd",
+                "result": "success",
+            })
+        );
+
+        let rec = AuditRecord::new(AuditAction::Admit, "none", None).admission(
+            "default",
+            vec!["trow/myapp:v1".to_string()],
+            false,
+            Some("not allowed".to_string()),
+        );
+        let v = serde_json::to_value(&rec).unwrap();
+        assert_eq!(v["result"], "denied");
+        assert_eq!(v["images"], json!(["trow/myapp:v1"]));
+        assert!(v.get("repository").is_none());
+    }
+
+    #[test]
+    fn reference_is_tag_or_digest() {
+        let rec = AuditRecord::new(AuditAction::Pull, "alice", None).reference("sha256:abcd");
+        assert_eq!(rec.digest.as_deref(), Some("sha256:abcd"));
+        assert!(rec.tag.is_none());
+
+        let rec =
+            AuditRecord::new(AuditAction::Pull, "alice", None).outcome::<(), _>(&Err("missing"));
+        assert_eq!(rec.reason.as_deref(), Some("missing"));
+    }
+}
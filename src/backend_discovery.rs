@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use rocket::tokio;
+use rocket::tokio::sync::mpsc::Sender;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tower::discover::Change;
+
+use crate::client_metrics;
+
+/*
+ * Balancing calls to the backend across several backends found through DNS, e.g. the pods of a
+ * headless Kubernetes Service, which has a record for each of them.
+ *
+ * The name is resolved again every REFRESH_INTERVAL, and backends are added to or removed from
+ * the channel as their addresses come and go, so the frontend follows backends being scaled or
+ * moved, and calls are spread across the backends that are up. If the name can't be resolved,
+ * or resolves to nothing, the backends already known are kept.
+ *
+ * The backends must share the data dir (see --ha), as consecutive calls for the same upload or
+ * manifest can go to different backends.
+ */
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+// Changes waiting for the channel to pick them up
+const CHANGE_BUFFER: usize = 16;
+
+/*
+ * Checks the address is HOST:PORT, as there's no default port to fall back on.
+ */
+fn check_address(address: &str) -> Result<()> {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(anyhow!(
+            "Invalid backend address {}, expected HOST:PORT",
+            address
+        )),
+    }
+}
+
+/*
+ * The backends to start and stop using, to go from those in use to those found
+ */
+fn changes(
+    current: &HashSet<SocketAddr>,
+    found: &HashSet<SocketAddr>,
+) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+    let mut added: Vec<SocketAddr> = found.difference(current).cloned().collect();
+    let mut removed: Vec<SocketAddr> = current.difference(found).cloned().collect();
+    added.sort_unstable();
+    removed.sort_unstable();
+    (added, removed)
+}
+
+fn endpoint(addr: SocketAddr, tls: &Option<ClientTlsConfig>) -> Result<Endpoint> {
+    Ok(match tls {
+        // The TLS config names the server to expect, as the URL only has its IP address
+        Some(tls) => Endpoint::from_shared(format!("https://{}", addr))?.tls_config(tls.clone())?,
+        None => Endpoint::from_shared(format!("http://{}", addr))?,
+    })
+}
+
+/*
+ * Keeps the channel's backends in line with what the address resolves to, until the channel is
+ * dropped.
+ */
+async fn follow(
+    address: String,
+    tls: Option<ClientTlsConfig>,
+    channel: Sender<Change<SocketAddr, Endpoint>>,
+) {
+    let mut current = HashSet::new();
+    loop {
+        match tokio::net::lookup_host(address.as_str()).await {
+            Ok(addrs) => {
+                let found: HashSet<SocketAddr> = addrs.collect();
+                if found.is_empty() {
+                    warn!(
+                        "No backends found at {}, keeping {} backends",
+                        address,
+                        current.len()
+                    );
+                } else {
+                    let (added, removed) = changes(&current, &found);
+                    for addr in removed {
+                        info!("Backend {} is gone from {}", addr, address);
+                        if channel.send(Change::Remove(addr)).await.is_err() {
+                            return;
+                        }
+                        current.remove(&addr);
+                    }
+                    for addr in added {
+                        let endpoint = match endpoint(addr, &tls) {
+                            Ok(e) => e,
+                            Err(e) => {
+                                warn!("Failed to add backend {}: {}", addr, e);
+                                continue;
+                            }
+                        };
+                        info!("Found backend {} at {}", addr, address);
+                        if channel.send(Change::Insert(addr, endpoint)).await.is_err() {
+                            return;
+                        }
+                        current.insert(addr);
+                    }
+                }
+            }
+            Err(e) => warn!(
+                "Failed to resolve {}, keeping {} backends: {}",
+                address,
+                current.len(),
+                e
+            ),
+        }
+        client_metrics::GRPC_CLIENT_BACKENDS.set(current.len() as i64);
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+    }
+}
+
+/*
+ * A channel balancing calls across the backends at address, a DNS name and port. Must be called
+ * from within a Tokio runtime, which keeps the backends up to date in the background.
+ *
+ * Calls wait until at least one backend has been found.
+ */
+pub fn balanced_channel(address: &str, tls: Option<ClientTlsConfig>) -> Result<Channel> {
+    check_address(address)?;
+    let (channel, changes) = Channel::balance_channel(CHANGE_BUFFER);
+    tokio::spawn(follow(address.to_string(), tls, changes));
+    Ok(channel)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{changes, check_address};
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+
+    fn addrs(list: &[&str]) -> HashSet<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn finds_changed_backends() {
+        let current = addrs(&["10.0.0.1:51000", "10.0.0.2:51000"]);
+        let found = addrs(&["10.0.0.2:51000", "10.0.0.3:51000", "10.0.0.4:51000"]);
+        let (added, removed) = changes(&current, &found);
+        assert_eq!(
+            added,
+            vec![
+                "10.0.0.3:51000".parse::<SocketAddr>().unwrap(),
+                "10.0.0.4:51000".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            removed,
+            vec!["10.0.0.1:51000".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(changes(&found, &found), (vec![], vec![]));
+    }
+
+    #[test]
+    fn needs_host_and_port() {
+        assert!(check_address("trow-backend.trow.svc.cluster.local:51000").is_ok());
+        assert!(check_address("[::1]:51000").is_ok());
+        assert!(check_address("trow-backend").is_err());
+        assert!(check_address(":51000").is_err());
+        assert!(check_address("trow-backend:grpc").is_err());
+    }
+}
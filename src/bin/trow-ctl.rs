@@ -0,0 +1,375 @@
+use anyhow::{anyhow, Result};
+use clap::{Arg, ArgMatches, Command};
+use serde::Serialize;
+use std::env;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use trow::ctl::{format_bytes, format_table, AdminClient, JobStatus};
+
+const PROGRAM_NAME: &str = "trow-ctl";
+const PROGRAM_DESC: &str = "\nManages a Trow registry through its admin API";
+const DEFAULT_URL: &str = "https://localhost:8443";
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/*
+  Parses command line arguments and returns ArgMatches object.
+
+  Will cause the program to exit if error or on help/version argument.
+*/
+fn parse_args() -> ArgMatches {
+    let repo = || {
+        Arg::new("repo")
+            .help("Repository name e.g. org/app")
+            .required(true)
+    };
+    Command::new(PROGRAM_NAME)
+        .version("0.1")
+        .author("From Container Solutions")
+        .about(PROGRAM_DESC)
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .arg(
+            Arg::new("url")
+                .long("url")
+                .value_name("url")
+                .help(format!("Where Trow is served. Defaults to $TROW_URL or {}.", DEFAULT_URL).as_str())
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("user")
+                .short('u')
+                .long("user")
+                .value_name("user")
+                .help("User to log in as. Defaults to $TROW_USER.")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("password")
+                .short('p')
+                .long("password")
+                .value_name("password")
+                .help("Password for --user. Defaults to $TROW_PASSWORD, which keeps it out of the shell history.")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("cacert")
+                .long("cacert")
+                .value_name("cacert")
+                .help("PEM file of a CA certificate to trust, for registries with a private CA.")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Print the API's JSON response rather than a table.")
+                .global(true),
+        )
+        .subcommand(Command::new("repos").about("Lists repositories with their tag counts and sizes"))
+        .subcommand(Command::new("tags").about("Lists the tags in a repository").arg(repo()))
+        .subcommand(
+            Command::new("delete")
+                .about("Deletes a manifest by digest or tag. Every tag in the repository pointing to the same manifest is removed too.")
+                .arg(repo())
+                .arg(
+                    Arg::new("reference")
+                        .help("Tag or digest of the manifest")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("delete-repo")
+                .about("Deletes every tag and manifest in a repository")
+                .arg(repo()),
+        )
+        .subcommand(
+            Command::new("gc")
+                .about("Starts garbage collection, which frees the space used by deleted images")
+                .arg(
+                    Arg::new("wait")
+                        .long("wait")
+                        .help("Wait for garbage collection to finish"),
+                ),
+        )
+        .subcommand(
+            Command::new("prewarm")
+                .about("Makes sure every blob of an image is in the registry, fetching a proxied image from upstream, e.g. before a rollout")
+                .arg(
+                    Arg::new("image")
+                        .help("Repository with a tag or digest, e.g. f/docker/library/nginx:1.21")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("notify")
+                        .long("notify")
+                        .help("Tell node agents listening for registry events to pull the image"),
+                )
+                .arg(
+                    Arg::new("wait")
+                        .long("wait")
+                        .help("Wait for the image to be in the registry"),
+                ),
+        )
+        .subcommand(
+            Command::new("jobs")
+                .about("Lists background jobs, or shows one")
+                .arg(Arg::new("id").help("Id of the job to show")),
+        )
+        .subcommand(Command::new("uploads").about("Lists uploads that have been started but not finished"))
+        .subcommand(Command::new("transfers").about("Lists uploads and downloads in flight through the registry"))
+        .subcommand(
+            Command::new("cancel")
+                .about("Cuts off an upload or download, removing an upload's data straight away")
+                .arg(
+                    Arg::new("uuid")
+                        .help("Uuid of the upload or transfer, from uploads or transfers")
+                        .required(true),
+                ),
+        )
+        .get_matches()
+}
+
+fn client(matches: &ArgMatches) -> Result<AdminClient> {
+    let url = matches
+        .value_of("url")
+        .map(str::to_string)
+        .or_else(|| env::var("TROW_URL").ok())
+        .unwrap_or_else(|| DEFAULT_URL.to_string());
+    let user = matches
+        .value_of("user")
+        .map(str::to_string)
+        .or_else(|| env::var("TROW_USER").ok());
+    let credentials = match user {
+        Some(user) => {
+            let pass = matches
+                .value_of("password")
+                .map(str::to_string)
+                .or_else(|| env::var("TROW_PASSWORD").ok())
+                .ok_or_else(|| {
+                    anyhow!("Either --password or $TROW_PASSWORD must be set with a user")
+                })?;
+            Some((user, pass))
+        }
+        None => None,
+    };
+    AdminClient::new(&url, credentials, matches.value_of("cacert").map(Path::new))
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn job_rows(jobs: &[JobStatus]) -> Vec<Vec<String>> {
+    let mut rows = vec![["ID", "KIND", "STATE", "PROGRESS", "STARTED", "MESSAGE"]
+        .iter()
+        .map(|h| h.to_string())
+        .collect()];
+    for job in jobs {
+        rows.push(vec![
+            job.id.clone(),
+            job.kind.clone(),
+            job.state.clone(),
+            format!("{}%", job.progress),
+            job.created.format("%Y-%m-%d %H:%M:%S").to_string(),
+            job.message.clone(),
+        ]);
+    }
+    rows
+}
+
+// Prints the job just started, once it's finished if wait is set
+fn show_job(
+    client: &AdminClient,
+    mut job: JobStatus,
+    wait: bool,
+    json: bool,
+    what: &str,
+) -> Result<()> {
+    if wait {
+        while job.state == "running" {
+            thread::sleep(JOB_POLL_INTERVAL);
+            job = client.job(&job.id)?;
+        }
+    }
+    if json {
+        print_json(&job)?;
+    } else {
+        print!("{}", format_table(&job_rows(std::slice::from_ref(&job))));
+    }
+    if job.state == "failed" {
+        return Err(anyhow!("{} failed: {}", what, job.message));
+    }
+    Ok(())
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let client = client(matches)?;
+    let json = matches.is_present("json");
+    match matches.subcommand() {
+        Some(("repos", _)) => {
+            let list = client.repositories()?;
+            if json {
+                return print_json(&list);
+            }
+            let mut rows = vec![vec![
+                "NAME".to_string(),
+                "TAGS".to_string(),
+                "SIZE".to_string(),
+            ]];
+            for repo in list.repositories {
+                rows.push(vec![
+                    repo.name,
+                    repo.tags.to_string(),
+                    format_bytes(repo.bytes),
+                ]);
+            }
+            print!("{}", format_table(&rows));
+        }
+        Some(("tags", args)) => {
+            let tags = client.tags(args.value_of("repo").unwrap())?;
+            if json {
+                return print_json(&tags);
+            }
+            for tag in tags.list() {
+                println!("{}", tag);
+            }
+        }
+        Some(("delete", args)) => {
+            let repo = args.value_of("repo").unwrap();
+            let reference = args.value_of("reference").unwrap();
+            let digest = if reference.contains(':') {
+                reference.to_string()
+            } else {
+                client.resolve(repo, reference)?
+            };
+            client.delete_manifest(repo, &digest)?;
+            println!("Deleted {}@{}", repo, digest);
+        }
+        Some(("delete-repo", args)) => {
+            let deleted = client.delete_repository(args.value_of("repo").unwrap())?;
+            if json {
+                return print_json(&deleted);
+            }
+            println!(
+                "Deleted {} manifests from {}. Run trow-ctl gc to free the space.",
+                deleted.manifests, deleted.name
+            );
+        }
+        Some(("gc", args)) => {
+            let job = client.start_job("gc")?;
+            show_job(
+                &client,
+                job,
+                args.is_present("wait"),
+                json,
+                "Garbage collection",
+            )?;
+        }
+        Some(("prewarm", args)) => {
+            let job = client.prewarm(args.value_of("image").unwrap(), args.is_present("notify"))?;
+            show_job(&client, job, args.is_present("wait"), json, "Prewarm")?;
+        }
+        Some(("jobs", args)) => {
+            let jobs = match args.value_of("id") {
+                Some(id) => {
+                    let job = client.job(id)?;
+                    if json {
+                        return print_json(&job);
+                    }
+                    vec![job]
+                }
+                None => {
+                    let list = client.jobs()?;
+                    if json {
+                        return print_json(&list);
+                    }
+                    list.jobs
+                }
+            };
+            print!("{}", format_table(&job_rows(&jobs)));
+        }
+        Some(("uploads", _)) => {
+            let list = client.uploads()?;
+            if json {
+                return print_json(&list);
+            }
+            let mut rows = vec![["REPOSITORY", "UUID", "RECEIVED", "LAST DATA"]
+                .iter()
+                .map(|h| h.to_string())
+                .collect()];
+            for upload in list.uploads {
+                rows.push(vec![
+                    upload.repo_name,
+                    upload.uuid,
+                    format_bytes(upload.bytes),
+                    upload
+                        .last_modified
+                        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                ]);
+            }
+            print!("{}", format_table(&rows));
+        }
+        Some(("transfers", _)) => {
+            let list = client.transfers()?;
+            if json {
+                return print_json(&list);
+            }
+            let mut rows = vec![[
+                "UUID",
+                "DIRECTION",
+                "REPOSITORY",
+                "DIGEST",
+                "BYTES",
+                "STARTED",
+            ]
+            .iter()
+            .map(|h| h.to_string())
+            .collect()];
+            for transfer in list.transfers {
+                rows.push(vec![
+                    transfer.uuid,
+                    format!("{:?}", transfer.direction).to_lowercase(),
+                    transfer.repo_name,
+                    transfer.digest.unwrap_or_else(|| "-".to_string()),
+                    format_bytes(transfer.bytes),
+                    transfer.started.format("%Y-%m-%d %H:%M:%S").to_string(),
+                ]);
+            }
+            print!("{}", format_table(&rows));
+        }
+        Some(("cancel", args)) => {
+            let cancelled = client.cancel_transfer(args.value_of("uuid").unwrap())?;
+            if json {
+                return print_json(&cancelled);
+            }
+            match cancelled.freed_bytes {
+                Some(bytes) => println!(
+                    "Cancelled {}, cutting off {} requests and freeing {}",
+                    cancelled.uuid,
+                    cancelled.transfers,
+                    format_bytes(bytes)
+                ),
+                None => println!(
+                    "Cancelled {}, cutting off {} requests",
+                    cancelled.uuid, cancelled.transfers
+                ),
+            }
+        }
+        _ => unreachable!("A subcommand is required"),
+    }
+    Ok(())
+}
+
+fn main() {
+    let matches = parse_args();
+    if let Err(e) = run(&matches) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
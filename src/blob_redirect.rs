@@ -0,0 +1,138 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use openssl::hash::{hash, MessageDigest};
+use reqwest::Url;
+
+use crate::registry_interface::Digest;
+
+/*
+ * Redirecting blob downloads to another server, so large layers don't pass through the
+ * frontend.
+ *
+ * Trow doesn't have an object storage backend, so rather than pre-signed S3 or GCS URLs the
+ * redirect goes to a web server with the blobs directory of the data volume (or a copy of it)
+ * under the base URL, i.e. BASE/<alg>/<hex>. URLs are signed in the format used by nginx's
+ * secure_link module, so the server only serves them until they expire:
+ *
+ *   location /blobs/ {
+ *     secure_link $arg_md5,$arg_expires;
+ *     secure_link_md5 "$secure_link_expires$uri SECRET";
+ *     if ($secure_link = "") { return 403; }
+ *     if ($secure_link = "0") { return 410; }
+ *     alias /data/blobs/;
+ *   }
+ *
+ * Trow still checks the client can pull the blob and that it exists before redirecting.
+ */
+
+#[derive(Clone)]
+pub struct BlobRedirect {
+    base: Url,
+    secret: String,
+    expiry: Duration,
+}
+
+impl std::fmt::Debug for BlobRedirect {
+    // Leaves out the secret
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("BlobRedirect")
+            .field("base", &self.base.as_str())
+            .field("expiry", &self.expiry)
+            .finish()
+    }
+}
+
+impl BlobRedirect {
+    pub fn new(base: &str, secret: String, expiry: Duration) -> Result<BlobRedirect> {
+        let base = Url::parse(base).map_err(|e| anyhow!("Invalid redirect URL {}: {}", base, e))?;
+        if base.cannot_be_a_base() || base.query().is_some() {
+            return Err(anyhow!("Redirect URL {} can't have blob paths added", base));
+        }
+        if secret.is_empty() {
+            return Err(anyhow!("The secret for signing redirects can't be empty"));
+        }
+        if expiry.is_zero() {
+            return Err(anyhow!("Redirects need an expiry"));
+        }
+        Ok(BlobRedirect {
+            base,
+            secret,
+            expiry,
+        })
+    }
+
+    pub fn base(&self) -> &str {
+        self.base.as_str()
+    }
+
+    pub fn expiry(&self) -> Duration {
+        self.expiry
+    }
+
+    fn signed_url(&self, digest: &Digest, expires: u64) -> String {
+        let path = format!(
+            "{}/{}/{}",
+            self.base.path().trim_end_matches('/'),
+            digest.algo,
+            digest.hash
+        );
+        let signed = format!("{}{} {}", expires, path, self.secret);
+        let md5 = hash(MessageDigest::md5(), signed.as_bytes()).expect("MD5 is always available");
+        let mut url = self.base.clone();
+        url.set_path(&path);
+        url.query_pairs_mut()
+            .append_pair("md5", &base64::encode_config(md5, base64::URL_SAFE_NO_PAD))
+            .append_pair("expires", &expires.to_string());
+        url.to_string()
+    }
+
+    /// Where to download the blob from
+    pub fn location(&self, digest: &Digest) -> String {
+        let expires = (SystemTime::now() + self.expiry)
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        self.signed_url(digest, expires)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BlobRedirect;
+    use crate::registry_interface::digest;
+    use std::time::Duration;
+
+    #[test]
+    fn signs_like_nginx() {
+        let redirect = BlobRedirect::new(
+            "https://blobs.example.com/blobs/",
+            "secret".to_string(),
+            Duration::from_secs(300),
+        )
+        .unwrap();
+        let digest = digest::parse(
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        )
+        .unwrap();
+        assert_eq!(
+            redirect.signed_url(&digest, 1700000000),
+            "https://blobs.example.com/blobs/sha256/\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\
+             ?md5=BUbkQVXiwTgZB16f-BBp6A&expires=1700000000"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_config() {
+        let expiry = Duration::from_secs(300);
+        assert!(BlobRedirect::new("not a url", "secret".to_string(), expiry).is_err());
+        assert!(
+            BlobRedirect::new("https://b.example.com/?x=1", "secret".to_string(), expiry).is_err()
+        );
+        assert!(BlobRedirect::new("https://b.example.com/", "".to_string(), expiry).is_err());
+        assert!(
+            BlobRedirect::new("https://b.example.com/", "s".to_string(), Duration::ZERO).is_err()
+        );
+    }
+}
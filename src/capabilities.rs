@@ -0,0 +1,191 @@
+use serde::Serialize;
+
+use crate::rate_limit::RateLimitConfig;
+use crate::TrowConfig;
+
+/*
+ * What this deployment supports, logged at startup and served at GET /trow/v1/capabilities, so
+ * operators and tools can check a registry without reading its flags.
+ *
+ * Only says what's enabled, never how it's configured, as it's served without logging in.
+ */
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Storage {
+    // Where blobs and manifests are kept, "filesystem", "azure" or "gcs"
+    pub driver: &'static str,
+    // Where tags and manifests are looked up, "filesystem" or "sqlite"
+    pub metadata: &'static str,
+    // Whether the data dir can be shared by several replicas
+    pub shared: bool,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Auth {
+    // e.g. "htpasswd", "oidc", or "none" if anyone can do anything
+    pub modes: Vec<&'static str>,
+    pub anonymous_pull: bool,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Proxy {
+    pub docker_hub: bool,
+    // Cached tags are compared with upstream in the background
+    pub cache_check: bool,
+    pub mirror_on_admission: bool,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub storage: Storage,
+    pub auth: Auth,
+    pub proxy: Proxy,
+    // Vulnerability scanning of pushed images
+    pub scanning: bool,
+    // The OCI referrers API
+    pub referrers: bool,
+    // Other optional features that are turned on, by name
+    pub features: Vec<&'static str>,
+}
+
+fn auth_modes(config: &TrowConfig) -> Vec<&'static str> {
+    let mut modes = vec![];
+    if config.user.is_some() {
+        modes.push("user");
+    }
+    if config.htpasswd.is_some() {
+        modes.push("htpasswd");
+    }
+    if config.setup.is_some() {
+        modes.push("setup");
+    }
+    if config.oidc.is_some() {
+        modes.push("oidc");
+    }
+    if config.service_accounts.is_some() {
+        modes.push("service-accounts");
+    }
+    if matches!(config.spiffe, Some(ref s) if !s.rules.is_empty()) {
+        modes.push("spiffe");
+    }
+    if modes.is_empty() {
+        modes.push("none");
+    }
+    modes
+}
+
+fn features(config: &TrowConfig) -> Vec<&'static str> {
+    // Always on
+    let mut features = vec![
+        "admission-validation",
+        "helm-charts",
+        "image-archives",
+        "manifest-history",
+        "platform-summaries",
+        "resumable-uploads",
+        "schema2-conversion",
+        "transfer-accounting",
+    ];
+    let optional = [
+        ("admission-cache", !config.admission_cache_ttl.is_zero()),
+        ("audit-log", config.audit_log.is_some()),
+        ("backend-discovery", config.grpc.backend_address.is_some()),
+        ("backups", config.backup_dir.is_some()),
+        ("blob-redirect", config.blob_redirect.is_some()),
+        ("change-freezes", !config.freeze_windows.is_empty()),
+        ("cors", config.cors),
+        ("estargz", config.estargz_interval.is_some()),
+        ("events", !config.event_sinks.is_empty()),
+        ("immutable-tags", !config.immutable_tags.is_empty()),
+        ("layer-transcoding", config.transcode_min_pulls.is_some()),
+        (
+            "layer-transcoding-on-demand",
+            config.transcode_min_pulls.is_some() && config.transcode_on_demand,
+        ),
+        ("manifest-cache", !config.manifest_cache_ttl.is_zero()),
+        ("policy-crd", config.policy_crd),
+        ("pull-stats", config.metadata_db.is_some()),
+        ("quotas", !config.quotas.is_empty()),
+        (
+            "rate-limits",
+            config.rate_limits.config() != RateLimitConfig::default(),
+        ),
+        (
+            "retention",
+            !config.retention.is_empty() || config.retention_interval != "0",
+        ),
+        ("tls", config.tls.is_some()),
+        ("tracing", config.tracing.is_some()),
+        ("trash", config.trash_retention != "0"),
+        ("upload-expiry", config.upload_ttl != "0"),
+        ("usage-report", config.usage_interval != "0"),
+        ("watch-data-dir", config.watch_data_dir),
+        ("web-ui", config.web_ui),
+    ];
+    features.extend(optional.iter().filter(|(_, on)| *on).map(|(f, _)| *f));
+    features.sort_unstable();
+    features
+}
+
+impl Capabilities {
+    pub fn new(config: &TrowConfig) -> Capabilities {
+        let modes = auth_modes(config);
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION"),
+            storage: Storage {
+                driver: match config.storage.as_deref() {
+                    Some(url) if url.starts_with("azure://") => "azure",
+                    Some(url) if url.starts_with("gs://") => "gcs",
+                    _ => "filesystem",
+                },
+                metadata: if config.metadata_db.is_some() {
+                    "sqlite"
+                } else {
+                    "filesystem"
+                },
+                shared: config.ha,
+            },
+            auth: Auth {
+                anonymous_pull: modes == ["none"] || config.allows_anonymous_pull(),
+                modes,
+            },
+            proxy: Proxy {
+                docker_hub: config.proxy_hub,
+                cache_check: config.proxy_hub && config.proxy_check_interval != "0",
+                mirror_on_admission: config.proxy_hub && config.mirror_workers > 0,
+            },
+            scanning: false,
+            referrers: true,
+            features: features(config),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Capabilities;
+    use crate::response::test_helper::test_config;
+    use std::time::Duration;
+
+    #[test]
+    fn reports_enabled_features() {
+        let mut config = test_config();
+        let caps = Capabilities::new(&config);
+        assert_eq!(caps.auth.modes, vec!["none"]);
+        assert!(caps.auth.anonymous_pull);
+        assert!(caps.proxy.docker_hub);
+        assert!(!caps.proxy.mirror_on_admission);
+        assert_eq!(caps.storage.metadata, "filesystem");
+        assert!(!caps.features.contains(&"manifest-cache"));
+
+        config.manifest_cache_ttl = Duration::from_secs(5);
+        config.mirror_workers = 4;
+        config.metadata_db = Some("/data/metadata.db".to_string());
+        let caps = Capabilities::new(&config);
+        assert!(caps.proxy.mirror_on_admission);
+        assert_eq!(caps.storage.metadata, "sqlite");
+        assert!(caps.features.contains(&"manifest-cache"));
+        assert!(caps.features.windows(2).all(|w| w[0] < w[1]));
+    }
+}
@@ -2,65 +2,136 @@ pub mod trow_proto {
     include!("../trow-protobuf/out/trow.rs");
 }
 
+use crate::active_transfers::{self, ActiveTransfers, Transfer};
+use crate::admission_cache::{AdmissionCache, CachedDecision};
+use crate::backend_discovery;
+use crate::blob_redirect::BlobRedirect;
+use crate::client_metrics::{self, Measured};
+use crate::manifest_cache::ManifestCache;
 use crate::registry_interface::blob_storage::Stored;
-use crate::registry_interface::digest::{self, Digest, DigestAlgorithm};
+use crate::registry_interface::digest::{self, Digest};
 use crate::registry_interface::{
-    validation, BlobReader, CatalogOperations, ContentInfo, ManifestHistory, ManifestReader,
-    Metrics, MetricsError, MetricsResponse, Validation, ValidationError,
+    validation, ActiveTransferList, Admin, BlobMetadata, BlobReader, CatalogOperations,
+    ChartVersion, Charts, ContentInfo, Events, ImageArchive, ImageArchives, ImageImported,
+    ImportedManifest, IndexSummary, JobError, JobList, JobStatus, Jobs, Maintenance,
+    ManifestHistory, ManifestMetadata, ManifestReader, Metrics, MetricsError, MetricsResponse,
+    MissingBlob, PlatformImage, Policies, PolicyDecision, PolicyRequest, PolicyRules, PullStats,
+    QuotaUsage, Quotas, ReadOnlyStatus, ReadRange, Reference, ReferencePulls, Referrer, Referrers,
+    RegistryEvent, RepositoryDeleted, RepositoryInfo, RepositoryList, RepositoryPulls,
+    RepositoryStorage, Retention, RetentionDeletion, RetentionReport, ScrubReport, StorageReport,
+    TagHistory, TagHistoryEntry, Tenancy, Tenant, TenantMember, TransferCancelled, Trash,
+    TrashEntry, UnusedImage, UploadCheck, UploadList, UploadSession, Usage, UsageReport,
+    Validation, ValidationError,
 };
+use crate::request_id;
+use crate::request_limits::TransferLimits;
+use crate::telemetry::Traced;
 use anyhow::Result;
+use futures::future::{self, Either};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use hyper::client::HttpConnector;
 use log::{debug, info, warn};
-use rocket::data::DataStream;
-use rocket::tokio::io::{AsyncSeek, AsyncSeekExt, AsyncWrite};
+use rocket::data::{DataStream, N};
+use rocket::tokio;
+use rocket::tokio::io::{AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufWriter};
 use thiserror::Error;
+use tonic::codegen::InterceptedService;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint, Uri};
 use tonic::{Code, Request};
+use tower::service_fn;
 use trow_proto::{
-    admission_controller_client::AdmissionControllerClient, registry_client::RegistryClient,
-    BlobRef, CatalogRequest, CompleteRequest, HealthRequest, ListTagsRequest,
-    ManifestHistoryRequest, ManifestRef, MetricsRequest, ReadinessRequest, UploadRef,
-    UploadRequest, VerifyManifestRequest,
+    admission_controller_client::AdmissionControllerClient, manifest_ref,
+    registry_client::RegistryClient, BlobRef, CancelUploadRequest, CatalogRequest, ChunkRef,
+    CompleteRequest, HealthRequest, ImportChunk, JobRef, ListChartsRequest, ListJobsRequest,
+    ListRepositoriesRequest, ListTagsRequest, ListTenantsRequest, ListTrashRequest,
+    ListUploadsRequest, ManifestHistoryRequest, ManifestRef, MetricsRequest,
+    PolicyGenerationRequest, PolicyUpdate, PrewarmRequest, PullStatsRequest, QuotaUsageRequest,
+    ReadOnlyRequest, ReadOnlyUpdate, ReadinessRequest, ReferrersRequest, RegistryUsageRequest,
+    RepoUsage, RepositoryRef, RetentionRequest, ScrubReportRequest, StartJobRequest, StoredUpload,
+    TenantRef, TranscodedManifestRef, TrashRef, UploadCheckRequest, UploadRef, UploadRequest,
+    UsageRequest, VerifyManifestRequest, WatchEventsRequest, WriteLocation,
 };
 
 use crate::registry_interface::{BlobStorage, ManifestStorage, StorageDriverError};
 use crate::types::{self, *};
 use chrono::TimeZone;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::io::SeekFrom;
-
-// BIG TODO:
-// Creating a new runtime for each request is awful.
-// Best fix is to move to Rocket 0.5 or another framework
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+// No space left on device, for writes to the uploads dir
+const ENOSPC: i32 = 28;
+
+// Buffer for each upload in progress. Smaller writes make too many trips to the blocking thread
+// pool, and larger ones could be too big for a gRPC message when sent as an import chunk.
+pub const DEFAULT_UPLOAD_BUFFER_SIZE: usize = 256 * 1024;
+const MIN_UPLOAD_BUFFER_SIZE: usize = 4 * 1024;
+const MAX_UPLOAD_BUFFER_SIZE: usize = 2 * 1024 * 1024;
+
+// Import chunks queued to be sent to the backend, each up to the upload buffer size
+const IMPORT_CHUNKS_QUEUED: usize = 4;
+// How long to wait on one address of the backend before trying the next as well
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// Connection to the backend passing on request IDs and trace context, and recording metrics
+type Interceptor = fn(tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status>;
+type WithRequestId = InterceptedService<Measured<Traced<Channel>>, Interceptor>;
+
+/*
+ * Implements the registry interface by calling out to a Trow backend over gRPC, whether it's a
+ * separate process or server or runs in this process.
+ */
+#[derive(Clone)]
 pub struct ClientInterface {
-    server: String,
+    backend: Backend,
+    blob_redirect: Option<BlobRedirect>,
+    manifest_cache: Option<Arc<ManifestCache>>,
+    admission_cache: Option<Arc<AdmissionCache>>,
+    transfer_limits: TransferLimits,
+    // Bytes of an upload held in memory before being written to the backend's sink
+    upload_buffer_size: usize,
+    transfers: ActiveTransfers,
 }
 
-/**
- * This is really bad way to do things on several levels, but it works for the moment.
- *
- * The major problem is Rust doesn't have TCO so we could be DOS'd by a malicious request.
+/*
+ * Connects to a backend given by name, which may resolve to both IPv6 and IPv4 addresses on a
+ * dual-stack cluster. Rather than waiting for an unreachable address to time out, the addresses
+ * are raced Happy Eyeballs style (RFC 8305), starting another attempt on the other family if the
+ * first hasn't connected within CONNECTION_ATTEMPT_DELAY.
  */
-fn extract_images<'a>(blob: &Value, images: &'a mut Vec<String>) -> &'a Vec<String> {
-    match blob {
-        Value::Array(vals) => {
-            for v in vals {
-                extract_images(v, images);
-            }
-        }
-        Value::Object(m) => {
-            for (k, v) in m {
-                if k == "image" {
-                    if let Value::String(image) = v {
-                        images.push(image.to_owned())
-                    }
-                } else {
-                    extract_images(v, images);
-                }
-            }
-        }
-        _ => (),
+fn connector() -> HttpConnector {
+    let mut http = HttpConnector::new();
+    // The scheme is https with TLS, which the channel adds on top
+    http.enforce_http(false);
+    http.set_nodelay(true);
+    http.set_happy_eyeballs_timeout(Some(CONNECTION_ATTEMPT_DELAY));
+    http
+}
+
+#[derive(Clone)]
+enum Backend {
+    // Backend reached over the network, connected to on each request
+    Remote(Endpoint),
+    // Channel balancing calls across the backends found at an address, see backend_discovery.rs
+    Balanced(Channel),
+    // Channel to a backend on the same host, through a Unix socket
+    Unix(Channel),
+}
+
+fn manifest_ref(repo_name: &RepoName, reference: &Reference) -> ManifestRef {
+    ManifestRef {
+        repo_name: repo_name.0.clone(),
+        reference: Some(match reference {
+            Reference::Tag(tag) => manifest_ref::Reference::Tag(tag.clone()),
+            Reference::Digest(digest) => manifest_ref::Reference::Digest(digest.to_string()),
+        }),
     }
-    images
 }
 
 // TODO: Each function should have it's own enum of the errors it can return
@@ -69,14 +140,60 @@ fn extract_images<'a>(blob: &Value, images: &'a mut Vec<String>) -> &'a Vec<Stri
 pub enum RegistryError {
     #[error("Invalid repository or tag")]
     InvalidName,
-    #[error("Invalid manifest")]
-    InvalidManifest,
+    #[error("{0}")]
+    InvalidManifest(String),
+    #[error("Manifest refers to unknown blob {0}")]
+    BlobUnknown(String),
     #[error("Invalid Range")]
     ManifestClipped,
+    #[error("{0}")]
+    QuotaExceeded(String),
+    #[error("{0}")]
+    TagImmutable(String),
+    #[error("{0}")]
+    ReadOnly(String),
+    #[error("{0}")]
+    TimedOut(String),
     #[error("Manifest over data limit")]
     Internal,
 }
 
+/*
+ * An upload cut off by the transfer limits is the client's fault, and one cancelled by an admin is
+ * gone as far as the client is concerned. Anything else is ours.
+ */
+fn stream_error(e: io::Error, uuid: &str) -> StorageDriverError {
+    match e.kind() {
+        _ if active_transfers::is_cancelled(&e) => {
+            StorageDriverError::UploadUnknown(uuid.to_string())
+        }
+        io::ErrorKind::TimedOut => StorageDriverError::TimedOut(e.to_string()),
+        _ if e.raw_os_error() == Some(ENOSPC) => StorageDriverError::InsufficientStorage(
+            "Registry storage is full, the upload can be retried once space is freed".to_string(),
+        ),
+        _ => StorageDriverError::Internal,
+    }
+}
+
+// The backend refuses with ResourceExhausted for both quotas and storage below its reserve
+fn exhausted_error(ts: &tonic::Status) -> StorageDriverError {
+    if ts
+        .metadata()
+        .contains_key(trow_server::INSUFFICIENT_STORAGE)
+    {
+        StorageDriverError::InsufficientStorage(ts.message().to_string())
+    } else {
+        StorageDriverError::QuotaExceeded(ts.message().to_string())
+    }
+}
+
+// Why the backend refused a write, if it's read-only for maintenance
+fn read_only_reason(e: &anyhow::Error) -> Option<String> {
+    e.downcast_ref::<tonic::Status>()
+        .filter(|ts| ts.code() == Code::Unavailable)
+        .map(|ts| ts.message().to_string())
+}
+
 #[rocket::async_trait]
 impl ManifestStorage for ClientInterface {
     async fn get_manifest(
@@ -85,14 +202,60 @@ impl ManifestStorage for ClientInterface {
         tag: &str,
     ) -> Result<ManifestReader, StorageDriverError> {
         let rn = RepoName(name.to_string());
-        let mr = self.get_reader_for_manifest(&rn, tag).await.map_err(|e| {
-            warn!("Error getting manifest {:?}", e);
-            StorageDriverError::Internal
-        })?;
+        let reference: Reference = tag
+            .parse()
+            .map_err(|_| StorageDriverError::InvalidName(format!("{}:{}", name, tag)))?;
+        let mr = self
+            .get_reader_for_manifest(&rn, &reference)
+            .await
+            .map_err(|e| {
+                warn!("Error getting manifest {:?}", e);
+                StorageDriverError::Internal
+            })?;
 
         Ok(mr)
     }
 
+    async fn get_transcoded_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        compression: &str,
+    ) -> Result<ManifestReader, StorageDriverError> {
+        let tr = TranscodedManifestRef {
+            repo_name: name.to_string(),
+            reference: reference.to_string(),
+            compression: compression.to_string(),
+        };
+        let resp = self
+            .connect_registry()
+            .await
+            .map_err(|e| {
+                warn!("Error connecting to backend {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .get_transcoded_manifest(Request::new(tr))
+            .await
+            .map_err(|e| match e.code() {
+                Code::NotFound => StorageDriverError::InvalidManifest,
+                _ => {
+                    warn!("Error getting transcoded manifest {:?}", e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+
+        let digest = digest::parse(&resp.digest).map_err(|_| StorageDriverError::Internal)?;
+        let file = rocket::tokio::fs::File::open(resp.path)
+            .await
+            .map_err(|_| StorageDriverError::Internal)?;
+        Ok(ManifestReader {
+            reader: Box::pin(file),
+            content_type: resp.content_type,
+            digest,
+        })
+    }
+
     async fn store_manifest<'a>(
         &self,
         name: &str,
@@ -100,14 +263,35 @@ impl ManifestStorage for ClientInterface {
         data: DataStream<'a>,
     ) -> Result<Digest, StorageDriverError> {
         let repo = RepoName(name.to_string());
+        let reference: Reference = tag
+            .parse()
+            .map_err(|_| StorageDriverError::InvalidName(format!("{}:{}", name, tag)))?;
 
-        match self.upload_manifest(&repo, tag, data).await {
+        let res = self.upload_manifest(&repo, &reference, data).await;
+        if let Some(cache) = &self.manifest_cache {
+            cache.invalidate_tag(name, tag);
+        }
+        if let (Some(cache), Ok(_)) = (&self.admission_cache, &res) {
+            cache.invalidate_denials();
+        }
+        match res {
             Ok(vm) => Ok(vm.digest().clone()),
             Err(RegistryError::InvalidName) => {
                 Err(StorageDriverError::InvalidName(format!("{}:{}", name, tag)))
             }
-            Err(RegistryError::InvalidManifest) => Err(StorageDriverError::InvalidManifest),
+            Err(RegistryError::InvalidManifest(reason)) => {
+                Err(StorageDriverError::ManifestRejected(reason))
+            }
+            Err(RegistryError::BlobUnknown(digest)) => Err(StorageDriverError::BlobUnknown(digest)),
             Err(RegistryError::ManifestClipped) => Err(StorageDriverError::InvalidContentRange),
+            Err(RegistryError::QuotaExceeded(reason)) => {
+                Err(StorageDriverError::QuotaExceeded(reason))
+            }
+            Err(RegistryError::TagImmutable(reason)) => {
+                Err(StorageDriverError::TagImmutable(reason))
+            }
+            Err(RegistryError::ReadOnly(reason)) => Err(StorageDriverError::ReadOnly(reason)),
+            Err(RegistryError::TimedOut(reason)) => Err(StorageDriverError::TimedOut(reason)),
             Err(_) => Err(StorageDriverError::Internal),
         }
     }
@@ -120,17 +304,97 @@ impl ManifestStorage for ClientInterface {
                 match ts.code() {
                     Code::InvalidArgument => StorageDriverError::Unsupported,
                     Code::NotFound => StorageDriverError::InvalidManifest,
+                    Code::Unavailable => StorageDriverError::ReadOnly(ts.message().to_string()),
                     _ => StorageDriverError::Internal,
                 }
             } else {
                 StorageDriverError::Internal
             }
         })?;
+        if let Some(cache) = &self.manifest_cache {
+            cache.invalidate_manifest(name, digest);
+        }
         Ok(())
     }
 
-    async fn has_manifest(&self, _name: &str, _algo: &DigestAlgorithm, _reference: &str) -> bool {
-        todo!()
+    async fn manifest_metadata(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<ManifestMetadata, StorageDriverError> {
+        let reference: Reference = reference
+            .parse()
+            .map_err(|_| StorageDriverError::InvalidName(format!("{}:{}", name, reference)))?;
+        let mr = manifest_ref(&RepoName(name.to_string()), &reference);
+        let stat = self
+            .connect_registry()
+            .await
+            .map_err(|e| {
+                warn!("Error connecting to backend {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .stat_manifest(Request::new(mr))
+            .await
+            .map_err(|e| match e.code() {
+                Code::NotFound => StorageDriverError::InvalidManifest,
+                _ => {
+                    warn!("Error getting manifest metadata {:?}", e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+
+        Ok(ManifestMetadata {
+            digest: digest::parse(&stat.digest).map_err(|_| StorageDriverError::Internal)?,
+            content_type: stat.content_type,
+            size: stat.size,
+        })
+    }
+
+    async fn index_summary(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<IndexSummary, StorageDriverError> {
+        let reference: Reference = reference
+            .parse()
+            .map_err(|_| StorageDriverError::InvalidName(format!("{}:{}", name, reference)))?;
+        let mr = manifest_ref(&RepoName(name.to_string()), &reference);
+        let summary = self
+            .connect_registry()
+            .await
+            .map_err(|e| {
+                warn!("Error connecting to backend {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .get_index_summary(Request::new(mr))
+            .await
+            .map_err(|e| match e.code() {
+                Code::NotFound => StorageDriverError::InvalidManifest,
+                _ => {
+                    warn!("Error getting index summary {:?}", e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+
+        Ok(IndexSummary {
+            digest: summary.digest,
+            media_type: summary.media_type,
+            platforms: summary
+                .platforms
+                .into_iter()
+                .map(|p| PlatformImage {
+                    os: p.os,
+                    architecture: p.architecture,
+                    variant: Some(p.variant).filter(|v| !v.is_empty()),
+                    digest: p.digest,
+                    media_type: p.media_type,
+                    manifest_size: p.manifest_size,
+                    image_size: Some(p.image_size).filter(|_| p.stored),
+                })
+                .collect(),
+        })
     }
 }
 
@@ -159,54 +423,81 @@ impl BlobStorage for ClientInterface {
     ) -> Result<Stored, StorageDriverError> {
         let rn = RepoName(name.to_string());
         let uuid = Uuid(session_id.to_string());
+        let range = data_info.as_ref().map(|i| i.range);
+        let (mut sink, location) = match self.get_write_sink_for_chunk(&rn, &uuid, range).await {
+            Ok(s) => s,
+            Err(e) => return Err(self.chunk_error(&rn, &uuid, e).await),
+        };
+        if let (true, Some(info)) = (location.part, &data_info) {
+            // Sent ahead of the data so far, by a client uploading ranges in parallel
+            return self
+                .store_blob_part(&rn, &uuid, info, data, sink, location.offset)
+                .await;
+        }
+
+        let res = self
+            .append_chunk(
+                &rn,
+                &uuid,
+                data_info.as_ref(),
+                data,
+                &mut sink,
+                location.offset,
+            )
+            .await;
+
+        /*
+         * Ends the chunk, so the next can be sent. Data past the limit, or from a chunk that
+         * failed, isn't acknowledged, so is dropped when the client sends it again.
+         */
+        let acknowledged = match &res {
+            Ok(stored) if stored.complete => stored.total_stored,
+            _ => location.offset,
+        };
+        if let Err(e) = self.upload_stored(&rn, &uuid, acknowledged).await {
+            warn!("Failed to record progress of upload {}: {:?}", uuid, e);
+        }
+        res
+    }
+
+    async fn upload_blob_complete<'a>(
+        &self,
+        name: &str,
+        digest: &Digest,
+        data: DataStream<'a>,
+    ) -> Result<crate::registry_interface::UploadInfo, StorageDriverError> {
+        let uuid = self.start_blob_upload(name).await?;
         let mut sink = self
-            .get_write_sink_for_upload(&rn, &uuid)
+            .get_write_sink_for_upload(&RepoName(name.to_string()), &Uuid(uuid.clone()))
             .await
             .map_err(|e| {
                 warn!("Error finding write sink for blob {:?}", e);
-                StorageDriverError::InvalidName(format!("{} {}", name, session_id))
+                StorageDriverError::Internal
             })?;
 
-        let have_range = data_info.is_some();
-        let info = data_info.unwrap_or(ContentInfo {
-            length: 0,
-            range: (0, 0),
-        });
-
-        let start_index = sink.seek(SeekFrom::End(0)).await.unwrap_or(0);
-        if have_range && (start_index != info.range.0) {
-            warn!(
-                "Asked to store blob with invalid start index. Expected {} got {}",
-                start_index, info.range.0
-            );
-            return Err(StorageDriverError::InvalidContentRange);
+        let transfer = self.transfers.upload(name, &uuid);
+        let written = self
+            .stream_upload(data, &mut sink, &transfer)
+            .await
+            .map_err(|e| {
+                warn!("Error writing blob {:?}", e);
+                stream_error(e, &uuid)
+            })?;
+        if !written.complete {
+            return Err(StorageDriverError::TooLarge);
         }
-
-        let stream_res = data.stream_to(&mut sink).await.map_err(|e| {
+        sink.flush().await.map_err(|e| {
             warn!("Error writing blob {:?}", e);
             StorageDriverError::Internal
         })?;
 
-        let chunk_len = stream_res.written;
-        let complete = stream_res.complete;
-
-        let total = sink.seek(SeekFrom::End(0)).await.unwrap_or(chunk_len);
-        if have_range {
-            if (info.range.1 + 1) != total {
-                warn!("total {} r + 1 {}", total, info.range.1 + 1);
-                return Err(StorageDriverError::InvalidContentRange);
-            }
-            //Check length if chunked upload
-            if info.length != chunk_len {
-                warn!("info.length {} len {}", info.length, chunk_len);
-                return Err(StorageDriverError::InvalidContentRange);
-            }
-        }
-
-        Ok(Stored {
-            total_stored: total,
-            chunk: chunk_len,
-            complete,
+        // Nothing is acknowledged before the upload completes, so progress isn't recorded
+        self.complete_and_verify_blob_upload(name, &uuid, digest)
+            .await?;
+        Ok(crate::registry_interface::UploadInfo {
+            name: name.to_string(),
+            session_id: uuid,
+            uploaded: written.written,
         })
     }
 
@@ -221,6 +512,14 @@ impl BlobStorage for ClientInterface {
             .map_err(|e| match e.downcast::<tonic::Status>() {
                 Ok(ts) => match ts.code() {
                     Code::InvalidArgument => StorageDriverError::InvalidDigest,
+                    Code::OutOfRange => {
+                        warn!("{}", ts.message());
+                        StorageDriverError::InvalidContentRange
+                    }
+                    Code::ResourceExhausted => {
+                        StorageDriverError::QuotaExceeded(ts.message().to_string())
+                    }
+                    Code::Unavailable => StorageDriverError::ReadOnly(ts.message().to_string()),
                     _ => StorageDriverError::Internal,
                 },
                 Err(e) => {
@@ -232,12 +531,17 @@ impl BlobStorage for ClientInterface {
     }
 
     async fn start_blob_upload(&self, name: &str) -> Result<String, StorageDriverError> {
-        self.request_upload(name).await.map_err(|e| {
-            match e.downcast::<tonic::Status>().map(|s| s.code()) {
-                Ok(Code::InvalidArgument) => StorageDriverError::InvalidName(name.to_string()),
-                _ => StorageDriverError::Internal,
-            }
-        })
+        self.request_upload(name)
+            .await
+            .map_err(|e| match e.downcast::<tonic::Status>() {
+                Ok(ts) => match ts.code() {
+                    Code::InvalidArgument => StorageDriverError::InvalidName(name.to_string()),
+                    Code::ResourceExhausted => exhausted_error(&ts),
+                    Code::Unavailable => StorageDriverError::ReadOnly(ts.message().to_string()),
+                    _ => StorageDriverError::Internal,
+                },
+                Err(_) => StorageDriverError::Internal,
+            })
     }
 
     async fn delete_blob(&self, name: &str, digest: &Digest) -> Result<(), StorageDriverError> {
@@ -246,129 +550,1491 @@ impl BlobStorage for ClientInterface {
 
         self.delete_blob_local(&rn, digest)
             .await
-            .map_err(|_| StorageDriverError::InvalidDigest)?;
+            .map_err(|e| match read_only_reason(&e) {
+                Some(reason) => StorageDriverError::ReadOnly(reason),
+                None => StorageDriverError::InvalidDigest,
+            })?;
         Ok(())
     }
 
-    async fn status_blob_upload(
-        &self,
-        _name: &str,
-        _session_id: &str,
-    ) -> crate::registry_interface::UploadInfo {
-        todo!()
+    async fn status_blob_upload(
+        &self,
+        name: &str,
+        session_id: &str,
+    ) -> Result<crate::registry_interface::UploadInfo, StorageDriverError> {
+        let rn = RepoName(name.to_string());
+        let uuid = Uuid(session_id.to_string());
+        // Only what's been acknowledged, not a chunk still being written
+        let location = self.get_write_location(&rn, &uuid).await.map_err(|e| {
+            warn!("Error finding upload {} {:?}", session_id, e);
+            StorageDriverError::InvalidName(format!("{} {}", name, session_id))
+        })?;
+        Ok(crate::registry_interface::UploadInfo {
+            name: name.to_string(),
+            session_id: session_id.to_string(),
+            uploaded: location.offset,
+        })
+    }
+
+    async fn cancel_blob_upload(
+        &self,
+        _name: &str,
+        _session_id: &str,
+    ) -> Result<(), StorageDriverError> {
+        todo!()
+    }
+
+    async fn blob_exists(
+        &self,
+        name: &str,
+        digest: &Digest,
+    ) -> Result<BlobMetadata, StorageDriverError> {
+        let br = BlobRef {
+            digest: digest.to_string(),
+            repo_name: name.to_string(),
+        };
+        let stat = self
+            .connect_registry()
+            .await
+            .map_err(|e| {
+                warn!("Error connecting to backend {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .stat_blob(Request::new(br))
+            .await
+            .map_err(|e| match e.code() {
+                Code::NotFound => StorageDriverError::InvalidDigest,
+                _ => {
+                    warn!("Error getting blob metadata {:?}", e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+
+        Ok(BlobMetadata {
+            digest: digest.clone(),
+            size: stat.size,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl CatalogOperations for ClientInterface {
+    async fn get_catalog(
+        &self,
+        start_value: Option<&str>,
+        num_results: Option<u32>,
+        prefix: Option<&str>,
+        user: Option<&str>,
+    ) -> Result<Vec<String>, StorageDriverError> {
+        let num_results = num_results.unwrap_or(u32::MAX);
+        let start_value = start_value.unwrap_or_default();
+        let prefix = prefix.unwrap_or_default();
+        let user = user.unwrap_or_default();
+
+        self.get_catalog_part(num_results, start_value, prefix, user)
+            .await
+            .map_err(|_| StorageDriverError::Internal)
+            .map(|rc| rc.raw())
+    }
+
+    async fn get_tags(
+        &self,
+        repo: &str,
+        start_value: Option<&str>,
+        num_results: Option<u32>,
+    ) -> Result<Vec<String>, StorageDriverError> {
+        let num_results = num_results.unwrap_or(u32::MAX);
+        let start_value = start_value.unwrap_or_default();
+
+        self.list_tags(repo, num_results, start_value)
+            .await
+            .map_err(
+                |e| match e.downcast_ref::<tonic::Status>().map(|s| s.code()) {
+                    Some(Code::NotFound) => StorageDriverError::NameUnknown(repo.to_string()),
+                    _ => StorageDriverError::Internal,
+                },
+            )
+            .map(|rc| rc.raw())
+    }
+
+    async fn get_history(
+        &self,
+        repo: &str,
+        name: &str,
+        start_value: Option<&str>,
+        num_results: Option<u32>,
+    ) -> Result<ManifestHistory, StorageDriverError> {
+        let num_results = num_results.unwrap_or(u32::MAX);
+        let start_value = start_value.unwrap_or_default();
+
+        self.get_manifest_history(repo, name, num_results, start_value)
+            .await
+            .map_err(|_| StorageDriverError::Internal)
+    }
+
+    async fn get_tag_history(
+        &self,
+        repo: &str,
+        tag: &str,
+    ) -> Result<TagHistory, StorageDriverError> {
+        let mr = ManifestHistoryRequest {
+            tag: tag.to_string(),
+            repo_name: repo.to_string(),
+            limit: u32::MAX,
+            last_digest: String::new(),
+        };
+        let mut stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .get_manifest_history(Request::new(mr))
+            .await
+            .map_err(|e| match e.code() {
+                Code::NotFound => StorageDriverError::NameUnknown(format!("{}:{}", repo, tag)),
+                Code::InvalidArgument => StorageDriverError::InvalidName(tag.to_string()),
+                _ => {
+                    warn!("Error getting history of {}:{} {:?}", repo, tag, e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+
+        let mut history = vec![];
+        while let Some(entry) = stream.message().await.map_err(|e| {
+            warn!("Error reading history of {}:{} {:?}", repo, tag, e);
+            StorageDriverError::Internal
+        })? {
+            history.push(TagHistoryEntry {
+                digest: entry.digest,
+                pushed: entry
+                    .date
+                    .map(|ts| chrono::Utc.timestamp(ts.seconds, ts.nanos.try_into().unwrap_or(0))),
+            });
+        }
+        Ok(TagHistory {
+            repository: repo.to_string(),
+            tag: tag.to_string(),
+            history,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Validation for ClientInterface {
+    async fn validate_admission(
+        &self,
+        admission_req: &validation::AdmissionRequest,
+        host_names: &[String],
+    ) -> Result<validation::AdmissionResponse, ValidationError> {
+        self.validate_admission_internal(admission_req, host_names)
+            .await
+            .map_err(|_| ValidationError::Internal)
+    }
+
+    async fn evaluate_policy(
+        &self,
+        policy_req: &PolicyRequest,
+        host_names: &[String],
+    ) -> Result<PolicyDecision, ValidationError> {
+        let req = trow_proto::PolicyRequest {
+            images: policy_req.all_images(),
+            namespace: policy_req.namespace.clone(),
+            host_names: host_names.to_vec(),
+            break_glass: policy_req.break_glass.clone().unwrap_or_default(),
+        };
+        let resp = self
+            .connect_admission_controller()
+            .await
+            .map_err(|_| ValidationError::Internal)?
+            .evaluate_policy(Request::new(req))
+            .await
+            .map_err(|e| {
+                warn!("Error evaluating policy: {:?}", e);
+                ValidationError::Internal
+            })?
+            .into_inner();
+
+        let non_empty = |s: String| if s.is_empty() { None } else { Some(s) };
+        Ok(PolicyDecision {
+            allowed: resp.is_allowed,
+            reason: non_empty(resp.reason),
+            images: resp
+                .images
+                .into_iter()
+                .map(|d| validation::ImageDecision {
+                    image: d.image,
+                    allowed: d.is_allowed,
+                    reason: d.reason,
+                    rule: d.rule,
+                })
+                .collect(),
+            freeze_window: non_empty(resp.freeze_window),
+            break_glass: resp.break_glass,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Metrics for ClientInterface {
+    async fn is_healthy(&self) -> bool {
+        self.is_healthy().await.is_healthy
+    }
+
+    async fn is_ready(&self) -> bool {
+        self.is_ready().await.is_ready
+    }
+
+    async fn get_metrics(
+        &self,
+    ) -> Result<MetricsResponse, crate::registry_interface::MetricsError> {
+        self.get_metrics().await.map_err(|_| MetricsError::Internal)
+    }
+}
+
+#[rocket::async_trait]
+impl Jobs for ClientInterface {
+    async fn start_job(&self, kind: &str) -> Result<JobStatus, JobError> {
+        info!("Starting {} job", kind);
+        let req = StartJobRequest {
+            kind: kind.to_string(),
+        };
+        let resp = self
+            .connect_registry()
+            .await
+            .map_err(|_| JobError::Internal)?
+            .start_job(Request::new(req))
+            .await
+            .map_err(|e| job_error(kind, e))?;
+        Ok(job_status(resp.into_inner()))
+    }
+
+    async fn get_job(&self, id: &str) -> Result<JobStatus, JobError> {
+        let req = JobRef { id: id.to_string() };
+        let resp = self
+            .connect_registry()
+            .await
+            .map_err(|_| JobError::Internal)?
+            .get_job(Request::new(req))
+            .await
+            .map_err(|e| job_error(id, e))?;
+        Ok(job_status(resp.into_inner()))
+    }
+
+    async fn list_jobs(&self) -> Result<JobList, JobError> {
+        let mut stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| JobError::Internal)?
+            .list_jobs(Request::new(ListJobsRequest {}))
+            .await
+            .map_err(|_| JobError::Internal)?
+            .into_inner();
+
+        let mut jobs = vec![];
+        while let Some(job) = stream.message().await.map_err(|_| JobError::Internal)? {
+            jobs.push(job_status(job));
+        }
+        Ok(JobList { jobs })
+    }
+
+    async fn cancel_job(&self, id: &str) -> Result<JobStatus, JobError> {
+        info!("Cancelling job {}", id);
+        let req = JobRef { id: id.to_string() };
+        let resp = self
+            .connect_registry()
+            .await
+            .map_err(|_| JobError::Internal)?
+            .cancel_job(Request::new(req))
+            .await
+            .map_err(|e| job_error(id, e))?;
+        Ok(job_status(resp.into_inner()))
+    }
+
+    async fn prewarm(
+        &self,
+        repo_name: &str,
+        reference: &str,
+        notify: bool,
+    ) -> Result<JobStatus, JobError> {
+        info!("Prewarming {}:{}", repo_name, reference);
+        let req = PrewarmRequest {
+            repo_name: repo_name.to_string(),
+            reference: reference.to_string(),
+            notify,
+        };
+        let resp = self
+            .connect_registry()
+            .await
+            .map_err(|_| JobError::Internal)?
+            .prewarm_image(Request::new(req))
+            .await
+            .map_err(|e| job_error(repo_name, e))?;
+        Ok(job_status(resp.into_inner()))
+    }
+}
+
+#[rocket::async_trait]
+impl Admin for ClientInterface {
+    async fn list_repositories(&self) -> Result<RepositoryList, StorageDriverError> {
+        let mut stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .list_repositories(Request::new(ListRepositoriesRequest {}))
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .into_inner();
+
+        let mut repositories = vec![];
+        while let Some(repo) = stream
+            .message()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+        {
+            repositories.push(RepositoryInfo {
+                name: repo.repo_name,
+                tags: repo.tags,
+                bytes: repo.bytes,
+            });
+        }
+        Ok(RepositoryList { repositories })
+    }
+
+    async fn delete_repository(&self, name: &str) -> Result<RepositoryDeleted, StorageDriverError> {
+        info!("Deleting repository {}", name);
+        let req = RepositoryRef {
+            repo_name: name.to_string(),
+        };
+        let resp = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .delete_repository(Request::new(req))
+            .await
+            .map_err(|e| match e.code() {
+                Code::NotFound => StorageDriverError::NameUnknown(name.to_string()),
+                Code::InvalidArgument => StorageDriverError::InvalidName(name.to_string()),
+                Code::Unavailable => StorageDriverError::ReadOnly(e.message().to_string()),
+                _ => {
+                    warn!("Error deleting repository {}: {:?}", name, e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+        if let Some(cache) = &self.manifest_cache {
+            cache.invalidate_repo(name);
+        }
+        Ok(RepositoryDeleted {
+            name: name.to_string(),
+            manifests: resp.manifests,
+        })
+    }
+
+    async fn list_uploads(&self) -> Result<UploadList, StorageDriverError> {
+        let mut stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .list_uploads(Request::new(ListUploadsRequest {}))
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .into_inner();
+
+        let mut uploads = vec![];
+        while let Some(upload) = stream
+            .message()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+        {
+            uploads.push(UploadSession {
+                repo_name: upload.repo_name,
+                uuid: upload.uuid,
+                bytes: upload.bytes,
+                last_modified: upload
+                    .last_modified
+                    .map(|ts| chrono::Utc.timestamp(ts.seconds, ts.nanos.try_into().unwrap_or(0))),
+            });
+        }
+        Ok(UploadList { uploads })
+    }
+
+    async fn active_transfers(&self) -> Result<ActiveTransferList, StorageDriverError> {
+        Ok(ActiveTransferList {
+            transfers: self.transfers.list(),
+        })
+    }
+
+    /*
+     * Cuts off transfers here first, so nothing more is written to the upload once the backend
+     * has removed it. Only transfers through this frontend are cut off, but with several
+     * frontends the next write to a cancelled upload through any of them fails.
+     */
+    async fn cancel_transfer(&self, uuid: &str) -> Result<TransferCancelled, StorageDriverError> {
+        let cut_off = self.transfers.cancel(uuid);
+        let freed_bytes = match self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .cancel_upload(Request::new(CancelUploadRequest {
+                uuid: uuid.to_string(),
+            }))
+            .await
+        {
+            Ok(resp) => Some(resp.into_inner().bytes),
+            Err(ts) if matches!(ts.code(), Code::NotFound | Code::InvalidArgument) => None,
+            Err(ts) => {
+                warn!("Error cancelling upload {}: {:?}", uuid, ts);
+                return Err(StorageDriverError::Internal);
+            }
+        };
+        if cut_off == 0 && freed_bytes.is_none() {
+            return Err(StorageDriverError::UploadUnknown(uuid.to_string()));
+        }
+        Ok(TransferCancelled {
+            uuid: uuid.to_string(),
+            transfers: cut_off as u64,
+            freed_bytes,
+        })
+    }
+
+    async fn pull_stats(&self, repo_name: Option<&str>) -> Result<PullStats, StorageDriverError> {
+        let req = PullStatsRequest {
+            repo_name: repo_name.unwrap_or("").to_string(),
+        };
+        let mut stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .pull_stats(Request::new(req))
+            .await
+            .map_err(|e| match e.code() {
+                Code::FailedPrecondition => StorageDriverError::Unsupported,
+                _ => {
+                    warn!("Error getting pull counts: {:?}", e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+
+        let to_date = |ts: prost_types::Timestamp| {
+            chrono::Utc.timestamp(ts.seconds, ts.nanos.try_into().unwrap_or(0))
+        };
+        let mut repositories = vec![];
+        while let Some(repo) = stream
+            .message()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+        {
+            repositories.push(RepositoryPulls {
+                name: repo.repo_name,
+                pulls: repo.pulls,
+                last_pulled: repo.last_pulled.map(to_date),
+                references: repo
+                    .references
+                    .into_iter()
+                    .map(|r| ReferencePulls {
+                        reference: r.reference,
+                        pulls: r.pulls,
+                        last_pulled: r.last_pulled.map(to_date),
+                    })
+                    .collect(),
+            });
+        }
+        Ok(PullStats { repositories })
+    }
+
+    async fn repo_storage(&self, name: &str) -> Result<RepositoryStorage, StorageDriverError> {
+        let req = RepositoryRef {
+            repo_name: name.to_string(),
+        };
+        let resp = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .get_repo_usage(Request::new(req))
+            .await
+            .map_err(|e| match e.code() {
+                Code::NotFound => StorageDriverError::NameUnknown(name.to_string()),
+                _ => {
+                    warn!("Error getting storage used by {}: {:?}", name, e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+        Ok(repo_storage(resp))
+    }
+
+    async fn storage_report(&self) -> Result<StorageReport, StorageDriverError> {
+        let resp = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .get_registry_usage(Request::new(RegistryUsageRequest {}))
+            .await
+            .map_err(|e| {
+                warn!("Error getting storage used: {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .into_inner();
+        Ok(StorageReport {
+            repositories: resp.repositories,
+            tags: resp.tags,
+            blobs: resp.blobs,
+            bytes: resp.bytes,
+            stored_blobs: resp.stored_blobs,
+            stored_bytes: resp.stored_bytes,
+            repositories_by_size: resp
+                .repositories_by_size
+                .into_iter()
+                .map(repo_storage)
+                .collect(),
+        })
+    }
+}
+
+fn repo_storage(usage: RepoUsage) -> RepositoryStorage {
+    RepositoryStorage {
+        name: usage.repo_name,
+        tags: usage.tags,
+        blobs: usage.blobs,
+        unique_bytes: usage.unique_bytes,
+        shared_bytes: usage.shared_bytes,
+    }
+}
+
+#[rocket::async_trait]
+impl Policies for ClientInterface {
+    async fn update_policy(
+        &self,
+        generation: u64,
+        rules: &PolicyRules,
+    ) -> Result<(), StorageDriverError> {
+        let req = PolicyUpdate {
+            generation,
+            allow_prefixes: rules.allow_prefixes.clone(),
+            allow_images: rules.allow_images.clone(),
+            deny_local_prefixes: rules.deny_prefixes.clone(),
+            deny_local_images: rules.deny_images.clone(),
+            quotas: rules.quotas.clone(),
+            immutable_tags: rules.immutable_tags.clone(),
+            freeze_windows: rules.freeze_windows.clone(),
+        };
+        self.connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .update_policy(Request::new(req))
+            .await
+            .map_err(|e| match e.code() {
+                Code::InvalidArgument => StorageDriverError::InvalidPolicy(e.message().to_string()),
+                _ => {
+                    warn!("Error updating policy: {:?}", e);
+                    StorageDriverError::Internal
+                }
+            })?;
+        if let Some(cache) = &self.admission_cache {
+            cache.set_generation(generation);
+        }
+        Ok(())
+    }
+
+    async fn policy_generation(&self) -> Result<u64, StorageDriverError> {
+        let resp = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .get_policy_generation(Request::new(PolicyGenerationRequest {}))
+            .await
+            .map_err(|e| {
+                warn!("Error getting policy generation: {:?}", e);
+                StorageDriverError::Internal
+            })?;
+        Ok(resp.into_inner().generation)
+    }
+}
+
+#[rocket::async_trait]
+impl Quotas for ClientInterface {
+    async fn get_quota_usage(&self, name: &str) -> Result<QuotaUsage, StorageDriverError> {
+        let req = QuotaUsageRequest {
+            repo_name: name.to_string(),
+        };
+        let usage = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .get_quota_usage(Request::new(req))
+            .await
+            .map_err(|e| {
+                warn!("Error getting quota usage for {}: {:?}", name, e);
+                StorageDriverError::Internal
+            })?
+            .into_inner();
+
+        Ok(quota_usage(usage))
+    }
+
+    async fn check_upload(
+        &self,
+        name: &str,
+        size: u64,
+        tag: Option<&str>,
+    ) -> Result<UploadCheck, StorageDriverError> {
+        let req = UploadCheckRequest {
+            repo_name: name.to_string(),
+            size,
+            tag: tag.unwrap_or_default().to_string(),
+        };
+        let check = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .check_upload(Request::new(req))
+            .await
+            .map_err(|e| {
+                warn!("Error checking upload to {}: {:?}", name, e);
+                StorageDriverError::Internal
+            })?
+            .into_inner();
+
+        Ok(UploadCheck {
+            allowed: check.refusal.is_empty(),
+            reason: Some(check.refusal).filter(|r| !r.is_empty()),
+            disk_available: check.disk_available,
+            quota: quota_usage(check.quota.unwrap_or_default()),
+        })
+    }
+}
+
+fn quota_usage(usage: trow_proto::QuotaUsage) -> QuotaUsage {
+    // The backend uses 0 for no limit
+    let limit = |max: u64| if max == 0 { None } else { Some(max) };
+    QuotaUsage {
+        name: usage.name,
+        bytes: usage.bytes,
+        images: usage.images,
+        max_bytes: limit(usage.max_bytes),
+        max_images: limit(usage.max_images),
+    }
+}
+
+#[rocket::async_trait]
+impl Retention for ClientInterface {
+    async fn dry_run_retention(&self) -> Result<RetentionReport, StorageDriverError> {
+        let mut stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .dry_run_retention(Request::new(RetentionRequest {}))
+            .await
+            .map_err(|e| {
+                warn!("Error evaluating retention rules: {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .into_inner();
+
+        let mut deletions = vec![];
+        while let Some(d) = stream
+            .message()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+        {
+            deletions.push(RetentionDeletion {
+                repo_name: d.repo_name,
+                tag: if d.tag.is_empty() { None } else { Some(d.tag) },
+                digest: d.digest,
+                history_only: d.history_only,
+                rule: d.rule,
+            });
+        }
+        Ok(RetentionReport { deletions })
+    }
+}
+
+#[rocket::async_trait]
+impl Usage for ClientInterface {
+    async fn usage_report(&self, unused_for_days: u64) -> Result<UsageReport, StorageDriverError> {
+        let req = UsageRequest {
+            unused_for_secs: unused_for_days * 24 * 60 * 60,
+        };
+        let mut stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .usage_report(Request::new(req))
+            .await
+            .map_err(|e| {
+                warn!("Error building usage report: {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .into_inner();
+
+        let to_date = |ts: prost_types::Timestamp| {
+            chrono::Utc.timestamp(ts.seconds, ts.nanos.try_into().unwrap_or(0))
+        };
+        let mut images = vec![];
+        while let Some(u) = stream
+            .message()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+        {
+            images.push(UnusedImage {
+                repo_name: u.repo_name,
+                tag: u.tag,
+                digest: u.digest,
+                pushed: u.pushed.map(to_date).unwrap_or_else(chrono::Utc::now),
+                last_seen: u.last_seen.map(to_date),
+            });
+        }
+        Ok(UsageReport {
+            unused_for_days,
+            images,
+        })
+    }
+}
+
+fn job_status(job: trow_proto::JobStatus) -> JobStatus {
+    let to_date = |ts: prost_types::Timestamp| {
+        chrono::Utc.timestamp(ts.seconds, ts.nanos.try_into().unwrap_or(0))
+    };
+    JobStatus {
+        id: job.id,
+        kind: job.kind,
+        state: job.state,
+        progress: job.progress,
+        message: job.message,
+        created: job
+            .created
+            .map(to_date)
+            .unwrap_or_else(|| chrono::Utc.timestamp(0, 0)),
+        finished: job.finished.map(to_date),
+    }
+}
+
+#[rocket::async_trait]
+impl Charts for ClientInterface {
+    async fn list_charts(&self) -> Result<Vec<ChartVersion>, StorageDriverError> {
+        let mut stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .list_charts(Request::new(ListChartsRequest {}))
+            .await
+            .map_err(|e| {
+                warn!("Error listing charts: {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .into_inner();
+
+        let to_date = |ts: prost_types::Timestamp| {
+            chrono::Utc.timestamp(ts.seconds, ts.nanos.try_into().unwrap_or(0))
+        };
+        let mut charts = vec![];
+        while let Some(c) = stream
+            .message()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+        {
+            // The backend only lists charts whose metadata is a JSON object
+            let metadata = match serde_json::from_str(&c.metadata) {
+                Ok(Value::Object(metadata)) => metadata,
+                _ => continue,
+            };
+            charts.push(ChartVersion {
+                repo_name: c.repo_name,
+                tag: c.tag,
+                metadata,
+                digest: c.digest,
+                size: c.size,
+                pushed: c.pushed.map(to_date).unwrap_or_else(chrono::Utc::now),
+            });
+        }
+        Ok(charts)
+    }
+}
+
+#[rocket::async_trait]
+impl Referrers for ClientInterface {
+    async fn list_referrers(
+        &self,
+        repo_name: &str,
+        digest: &str,
+    ) -> Result<Vec<Referrer>, StorageDriverError> {
+        let req = ReferrersRequest {
+            repo_name: repo_name.to_string(),
+            digest: digest.to_string(),
+        };
+        let mut stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .list_referrers(Request::new(req))
+            .await
+            .map_err(|e| match e.code() {
+                Code::InvalidArgument => StorageDriverError::InvalidName(repo_name.to_string()),
+                _ => {
+                    warn!("Error listing referrers: {:?}", e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+
+        let mut referrers = vec![];
+        while let Some(r) = stream
+            .message()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+        {
+            referrers.push(Referrer {
+                media_type: r.media_type,
+                digest: r.digest,
+                size: r.size,
+                artifact_type: r.artifact_type,
+                annotations: serde_json::from_str(&r.annotations).unwrap_or_default(),
+            });
+        }
+        Ok(referrers)
+    }
+}
+
+fn tenant_from_proto(t: trow_proto::Tenant) -> Tenant {
+    Tenant {
+        name: t.name,
+        members: t
+            .members
+            .into_iter()
+            .map(|m| TenantMember {
+                user: m.user,
+                permission: m.permission,
+            })
+            .collect(),
+        quota: Some(t.quota).filter(|q| !q.is_empty()),
+        retention: t.retention,
+    }
+}
+
+#[rocket::async_trait]
+impl Tenancy for ClientInterface {
+    async fn list_tenants(&self) -> Result<Vec<Tenant>, StorageDriverError> {
+        let mut stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .list_tenants(Request::new(ListTenantsRequest {}))
+            .await
+            .map_err(|e| {
+                warn!("Error listing tenants: {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .into_inner();
+
+        let mut tenants = vec![];
+        while let Some(t) = stream
+            .message()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+        {
+            tenants.push(tenant_from_proto(t));
+        }
+        Ok(tenants)
+    }
+
+    async fn put_tenant(&self, tenant: &Tenant) -> Result<Tenant, StorageDriverError> {
+        let req = trow_proto::Tenant {
+            name: tenant.name.clone(),
+            members: tenant
+                .members
+                .iter()
+                .map(|m| trow_proto::TenantMember {
+                    user: m.user.clone(),
+                    permission: m.permission.clone(),
+                })
+                .collect(),
+            quota: tenant.quota.clone().unwrap_or_default(),
+            retention: tenant.retention.clone(),
+        };
+        let resp = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .put_tenant(Request::new(req))
+            .await
+            .map_err(|e| match e.code() {
+                Code::InvalidArgument => StorageDriverError::InvalidPolicy(e.message().to_string()),
+                _ => {
+                    warn!("Error storing tenant: {:?}", e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+        Ok(tenant_from_proto(resp))
+    }
+
+    async fn delete_tenant(&self, name: &str) -> Result<(), StorageDriverError> {
+        let req = TenantRef {
+            name: name.to_string(),
+        };
+        self.connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .delete_tenant(Request::new(req))
+            .await
+            .map_err(|e| match e.code() {
+                Code::NotFound => StorageDriverError::NameUnknown(name.to_string()),
+                Code::FailedPrecondition => {
+                    StorageDriverError::TenantInUse(e.message().to_string())
+                }
+                _ => {
+                    warn!("Error deleting tenant: {:?}", e);
+                    StorageDriverError::Internal
+                }
+            })?;
+        Ok(())
+    }
+}
+
+fn read_only_from_proto(status: trow_proto::ReadOnlyStatus) -> ReadOnlyStatus {
+    ReadOnlyStatus {
+        read_only: status.read_only,
+        reason: Some(status.reason).filter(|r| !r.is_empty()),
+    }
+}
+
+#[rocket::async_trait]
+impl Maintenance for ClientInterface {
+    async fn read_only_status(&self) -> Result<ReadOnlyStatus, StorageDriverError> {
+        let resp = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .get_read_only(Request::new(ReadOnlyRequest {}))
+            .await
+            .map_err(|e| {
+                warn!("Error getting read-only status: {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .into_inner();
+        Ok(read_only_from_proto(resp))
+    }
+
+    async fn set_read_only(
+        &self,
+        read_only: bool,
+        reason: Option<&str>,
+    ) -> Result<ReadOnlyStatus, StorageDriverError> {
+        let req = ReadOnlyUpdate {
+            read_only,
+            reason: reason.unwrap_or_default().to_string(),
+        };
+        let resp = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .set_read_only(Request::new(req))
+            .await
+            .map_err(|e| {
+                warn!("Error setting read-only status: {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .into_inner();
+        Ok(read_only_from_proto(resp))
+    }
+
+    async fn scrub_report(&self) -> Result<Option<ScrubReport>, StorageDriverError> {
+        let res = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .get_scrub_report(Request::new(ScrubReportRequest {}))
+            .await;
+        let resp = match res {
+            Ok(r) => r.into_inner(),
+            Err(e) if e.code() == Code::NotFound => return Ok(None),
+            Err(e) => {
+                warn!("Error getting scrub report: {:?}", e);
+                return Err(StorageDriverError::Internal);
+            }
+        };
+        let finished = resp
+            .finished
+            .map(|ts| chrono::Utc.timestamp(ts.seconds, ts.nanos.try_into().unwrap_or(0)))
+            .unwrap_or_else(|| chrono::Utc.timestamp(0, 0));
+        Ok(Some(ScrubReport {
+            checked: resp.checked,
+            corrupt: resp.corrupt,
+            missing: resp
+                .missing
+                .into_iter()
+                .map(|m| MissingBlob {
+                    digest: m.digest,
+                    referenced_by: m.referenced_by,
+                })
+                .collect(),
+            quarantined: resp.quarantined,
+            cancelled: resp.cancelled,
+            finished,
+        }))
+    }
+}
+
+#[rocket::async_trait]
+impl Events for ClientInterface {
+    async fn watch_events(
+        &self,
+        prefix: Option<&str>,
+        user: Option<&str>,
+    ) -> Result<BoxStream<'static, RegistryEvent>, StorageDriverError> {
+        let req = WatchEventsRequest {
+            prefix: prefix.unwrap_or_default().to_string(),
+            user: user.unwrap_or_default().to_string(),
+        };
+        let stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .watch_events(Request::new(req))
+            .await
+            .map_err(|e| {
+                warn!("Error watching events: {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .into_inner();
+
+        // Ends if the backend goes away, and the watcher can reconnect
+        let events = stream
+            .take_while(|e| future::ready(e.is_ok()))
+            .filter_map(|e| {
+                future::ready(e.ok().map(|e| RegistryEvent {
+                    id: e.id,
+                    action: e.action,
+                    repository: e.repository,
+                    tag: Some(e.tag).filter(|t| !t.is_empty()),
+                    digest: e.digest,
+                    timestamp: e.timestamp,
+                }))
+            })
+            .boxed();
+        Ok(events)
+    }
+}
+
+fn trash_entry_from_proto(e: trow_proto::TrashEntry) -> TrashEntry {
+    let time = |ts: Option<prost_types::Timestamp>| {
+        ts.map(|ts| chrono::Utc.timestamp(ts.seconds, ts.nanos.try_into().unwrap_or(0)))
+            .unwrap_or_else(|| chrono::Utc.timestamp(0, 0))
+    };
+    TrashEntry {
+        id: e.id,
+        repo_name: e.repo_name,
+        digest: Some(e.digest).filter(|d| !d.is_empty()),
+        tags: e.tags,
+        deleted: time(e.deleted),
+        expires: time(e.expires),
+    }
+}
+
+#[rocket::async_trait]
+impl Trash for ClientInterface {
+    async fn list_trash(&self) -> Result<Vec<TrashEntry>, StorageDriverError> {
+        let mut stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .list_trash(Request::new(ListTrashRequest {}))
+            .await
+            .map_err(|e| {
+                warn!("Error listing trash: {:?}", e);
+                StorageDriverError::Internal
+            })?
+            .into_inner();
+
+        let mut entries = vec![];
+        while let Some(e) = stream
+            .message()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+        {
+            entries.push(trash_entry_from_proto(e));
+        }
+        Ok(entries)
+    }
+
+    async fn restore_trash(&self, id: &str) -> Result<TrashEntry, StorageDriverError> {
+        let req = TrashRef { id: id.to_string() };
+        let entry = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .restore_trash(Request::new(req))
+            .await
+            .map_err(|e| match e.code() {
+                Code::NotFound => StorageDriverError::NameUnknown(id.to_string()),
+                // A tag has been pushed again since it was deleted
+                Code::FailedPrecondition => {
+                    StorageDriverError::TagImmutable(e.message().to_string())
+                }
+                Code::Unavailable => StorageDriverError::ReadOnly(e.message().to_string()),
+                _ => {
+                    warn!("Error restoring {} from the trash: {:?}", id, e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+        let entry = trash_entry_from_proto(entry);
+        if let Some(cache) = &self.manifest_cache {
+            cache.invalidate_repo(&entry.repo_name);
+        }
+        Ok(entry)
+    }
+}
+
+#[rocket::async_trait]
+impl ImageArchives for ClientInterface {
+    async fn export_image(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<ImageArchive, StorageDriverError> {
+        let parsed: Reference = reference
+            .parse()
+            .map_err(|_| StorageDriverError::InvalidName(format!("{}:{}", name, reference)))?;
+        let mr = manifest_ref(&RepoName(name.to_string()), &parsed);
+        let stream = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?
+            .export_image(Request::new(mr))
+            .await
+            .map_err(|e| match e.code() {
+                Code::NotFound => StorageDriverError::InvalidManifest,
+                Code::FailedPrecondition => {
+                    StorageDriverError::InvalidArchive(e.message().to_string())
+                }
+                _ => {
+                    warn!("Error exporting {}:{} {:?}", name, reference, e);
+                    StorageDriverError::Internal
+                }
+            })?
+            .into_inner();
+
+        info!("Exporting {}:{}", name, reference);
+        // The tarball is cut short if the backend fails part way, which the client will notice
+        let chunks = stream
+            .take_while(|c| future::ready(c.is_ok()))
+            .filter_map(|c| future::ready(c.ok().map(|c| c.data)))
+            .boxed();
+        Ok(ImageArchive {
+            repo_name: name.to_string(),
+            reference: reference.to_string(),
+            chunks,
+        })
+    }
+
+    async fn import_image<'a>(
+        &self,
+        name: &str,
+        tag: Option<&str>,
+        data: DataStream<'a>,
+    ) -> Result<ImageImported, StorageDriverError> {
+        let (mut tx, rx) = futures::channel::mpsc::channel(IMPORT_CHUNKS_QUEUED);
+        let first = ImportChunk {
+            repo_name: name.to_string(),
+            tag: tag.unwrap_or("").to_string(),
+            data: vec![],
+        };
+        tx.try_send(first)
+            .map_err(|_| StorageDriverError::Internal)?;
+        let mut writer = ImportWriter(tx);
+
+        let mut client = self
+            .connect_registry()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?;
+        let import = Box::pin(client.import_image(Request::new(rx)));
+        let transfer = self.transfers.push(name);
+        let upload = Box::pin(self.stream_upload(data, &mut writer, &transfer));
+        // The backend can reject the import before it's all been sent
+        let (stored, import) = match future::select(upload, import).await {
+            Either::Left((stored, import)) => (stored, import),
+            Either::Right((res, _)) => {
+                return Err(import_error(name, res.err()));
+            }
+        };
+        match stored {
+            Ok(n) if n.complete => {}
+            // Dropping the call cancels the import, so nothing is stored from a partial tarball
+            Ok(_) => return Err(StorageDriverError::TooLarge),
+            Err(e) => {
+                warn!("Error reading image archive for {} {:?}", name, e);
+                return Err(stream_error(e));
+            }
+        }
+        // Ends the stream, so the backend stores the images
+        drop(writer);
+
+        let imported = import
+            .await
+            .map_err(|e| import_error(name, Some(e)))?
+            .into_inner();
+        if let Some(cache) = &self.manifest_cache {
+            cache.invalidate_repo(name);
+        }
+        Ok(ImageImported {
+            repo_name: imported.repo_name,
+            manifests: imported
+                .manifests
+                .into_iter()
+                .map(|m| ImportedManifest {
+                    digest: m.digest,
+                    tag: Some(m.tag).filter(|t| !t.is_empty()),
+                })
+                .collect(),
+            blobs: imported.blobs,
+            bytes: imported.bytes,
+        })
+    }
+}
+
+// None if the backend finished the import before it was all sent
+fn import_error(name: &str, e: Option<tonic::Status>) -> StorageDriverError {
+    let e = match e {
+        Some(e) => e,
+        None => {
+            warn!("Import into {} finished before the archive was sent", name);
+            return StorageDriverError::Internal;
+        }
+    };
+    match e.code() {
+        Code::InvalidArgument => StorageDriverError::InvalidArchive(e.message().to_string()),
+        Code::ResourceExhausted => exhausted_error(&e),
+        Code::AlreadyExists => StorageDriverError::TagImmutable(e.message().to_string()),
+        Code::Unavailable => StorageDriverError::ReadOnly(e.message().to_string()),
+        _ => {
+            warn!("Error importing into {} {:?}", name, e);
+            StorageDriverError::Internal
+        }
+    }
+}
+
+/*
+ * Sends whatever's written to it to the backend as chunks of an import. Writes wait while the
+ * queue of chunks is full, so a slow backend holds back reading the archive.
+ */
+struct ImportWriter(futures::channel::mpsc::Sender<ImportChunk>);
+
+impl AsyncWrite for ImportWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let stopped = || io::Error::new(io::ErrorKind::BrokenPipe, "Backend stopped the import");
+        match self.0.poll_ready(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(stopped())),
+            Poll::Pending => return Poll::Pending,
+        }
+        let chunk = ImportChunk {
+            repo_name: String::new(),
+            tag: String::new(),
+            data: buf.to_vec(),
+        };
+        self.0.start_send(chunk).map_err(|_| stopped())?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
     }
 
-    async fn cancel_blob_upload(
-        &self,
-        _name: &str,
-        _session_id: &str,
-    ) -> Result<(), StorageDriverError> {
-        todo!()
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
     }
+}
 
-    async fn has_blob(&self, _name: &str, _digest: &Digest) -> bool {
-        todo!()
+fn job_error(id: &str, e: tonic::Status) -> JobError {
+    match e.code() {
+        Code::NotFound => JobError::NotFound(id.to_string()),
+        Code::InvalidArgument | Code::Unavailable => JobError::Invalid(e.message().to_string()),
+        _ => {
+            warn!("Error from backend for job {}: {:?}", id, e);
+            JobError::Internal
+        }
     }
 }
 
-#[rocket::async_trait]
-impl CatalogOperations for ClientInterface {
-    async fn get_catalog(
-        &self,
-        start_value: Option<&str>,
-        num_results: Option<u32>,
-    ) -> Result<Vec<String>, StorageDriverError> {
-        let num_results = num_results.unwrap_or(u32::MAX);
-        let start_value = start_value.unwrap_or_default();
+impl ClientInterface {
+    pub fn new(server: String) -> Result<Self> {
+        Ok(ClientInterface {
+            backend: Backend::Remote(Endpoint::from_shared(server)?),
+            blob_redirect: None,
+            manifest_cache: None,
+            admission_cache: None,
+            transfer_limits: TransferLimits::default(),
+            upload_buffer_size: DEFAULT_UPLOAD_BUFFER_SIZE,
+            transfers: ActiveTransfers::default(),
+        })
+    }
 
-        self.get_catalog_part(num_results, start_value)
-            .await
-            .map_err(|_| StorageDriverError::Internal)
-            .map(|rc| rc.raw())
+    pub fn new_with_tls(server: String, tls: ClientTlsConfig) -> Result<Self> {
+        Ok(ClientInterface {
+            backend: Backend::Remote(Endpoint::from_shared(server)?.tls_config(tls)?),
+            blob_redirect: None,
+            manifest_cache: None,
+            admission_cache: None,
+            transfer_limits: TransferLimits::default(),
+            upload_buffer_size: DEFAULT_UPLOAD_BUFFER_SIZE,
+            transfers: ActiveTransfers::default(),
+        })
     }
 
-    async fn get_tags(
-        &self,
-        repo: &str,
-        start_value: Option<&str>,
-        num_results: Option<u32>,
-    ) -> Result<Vec<String>, StorageDriverError> {
-        let num_results = num_results.unwrap_or(u32::MAX);
-        let start_value = start_value.unwrap_or_default();
+    /*
+     * Balance calls across the backends that address, a DNS name and port, resolves to. The name
+     * is resolved again every few seconds, to follow backends coming and going.
+     */
+    pub fn balanced(address: &str, tls: Option<ClientTlsConfig>) -> Result<Self> {
+        Ok(ClientInterface {
+            backend: Backend::Balanced(backend_discovery::balanced_channel(address, tls)?),
+            blob_redirect: None,
+            manifest_cache: None,
+            admission_cache: None,
+            transfer_limits: TransferLimits::default(),
+            upload_buffer_size: DEFAULT_UPLOAD_BUFFER_SIZE,
+            transfers: ActiveTransfers::default(),
+        })
+    }
 
-        self.list_tags(repo, num_results, start_value)
-            .await
-            .map_err(|_| StorageDriverError::Internal)
-            .map(|rc| rc.raw())
+    /*
+     * Connect to a backend listening on the Unix socket at path, e.g. one in the same pod.
+     *
+     * The connection is made when first needed, and made again if it drops, e.g. while the
+     * backend restarts.
+     */
+    pub fn unix(path: &str, tls: Option<ClientTlsConfig>) -> Result<Self> {
+        // The URI is only used for TLS, which needs the scheme to be https
+        let endpoint = match tls {
+            Some(tls) => Endpoint::from_static("https://localhost").tls_config(tls)?,
+            None => Endpoint::from_static("http://localhost"),
+        };
+        let path = path.to_string();
+        let channel = endpoint.connect_with_connector_lazy(service_fn(move |_: Uri| {
+            tokio::net::UnixStream::connect(path.clone())
+        }))?;
+        Ok(ClientInterface {
+            backend: Backend::Unix(channel),
+            blob_redirect: None,
+            manifest_cache: None,
+            admission_cache: None,
+            transfer_limits: TransferLimits::default(),
+            upload_buffer_size: DEFAULT_UPLOAD_BUFFER_SIZE,
+            transfers: ActiveTransfers::default(),
+        })
     }
 
-    async fn get_history(
-        &self,
-        repo: &str,
-        name: &str,
-        start_value: Option<&str>,
-        num_results: Option<u32>,
-    ) -> Result<ManifestHistory, StorageDriverError> {
-        let num_results = num_results.unwrap_or(u32::MAX);
-        let start_value = start_value.unwrap_or_default();
+    /// Redirect blob downloads to another server rather than sending them
+    pub fn with_blob_redirect(mut self, redirect: BlobRedirect) -> Self {
+        self.blob_redirect = Some(redirect);
+        self
+    }
 
-        self.get_manifest_history(repo, name, num_results, start_value)
-            .await
-            .map_err(|_| StorageDriverError::Internal)
+    /// Keep manifests in memory for up to ttl, see manifest_cache.rs
+    pub fn with_manifest_cache(mut self, ttl: Duration) -> Self {
+        self.manifest_cache = Some(Arc::new(ManifestCache::new(ttl)));
+        self
     }
-}
 
-#[rocket::async_trait]
-impl Validation for ClientInterface {
-    async fn validate_admission(
-        &self,
-        admission_req: &validation::AdmissionRequest,
-        host_names: &[String],
-    ) -> Result<validation::AdmissionResponse, ValidationError> {
-        self.validate_admission_internal(admission_req, host_names)
-            .await
-            .map_err(|_| ValidationError::Internal)
+    /// Keep admission decisions in memory for up to ttl, see admission_cache.rs
+    pub fn with_admission_cache(mut self, ttl: Duration) -> Self {
+        self.admission_cache = Some(Arc::new(AdmissionCache::new(ttl)));
+        self
     }
-}
 
-#[rocket::async_trait]
-impl Metrics for ClientInterface {
-    async fn is_healthy(&self) -> bool {
-        self.is_healthy().await.is_healthy
+    /// Cut off uploads and downloads that stall or take too long, see request_limits.rs
+    pub fn with_transfer_limits(mut self, limits: TransferLimits) -> Self {
+        self.transfer_limits = limits;
+        self
     }
 
-    async fn is_ready(&self) -> bool {
-        self.is_ready().await.is_ready
+    /// Write uploads to the backend's sink in pieces of up to size bytes
+    pub fn with_upload_buffer_size(mut self, size: usize) -> Self {
+        self.upload_buffer_size = size.clamp(MIN_UPLOAD_BUFFER_SIZE, MAX_UPLOAD_BUFFER_SIZE);
+        self
     }
 
-    async fn get_metrics(
+    /*
+     * Streams an upload's body to the sink through a buffer of upload_buffer_size, which is all
+     * of it held in memory at once. The body is only read while there's room in the buffer, so a
+     * slow sink holds the client back rather than the upload piling up in memory. Everything is
+     * on the sink by the time this returns, before the upload is acknowledged.
+     *
+     * The transfer counts what reaches the sink, and fails the upload if it's cancelled.
+     */
+    async fn stream_upload<W: AsyncWrite + Unpin>(
         &self,
-    ) -> Result<MetricsResponse, crate::registry_interface::MetricsError> {
-        self.get_metrics().await.map_err(|_| MetricsError::Internal)
+        data: DataStream<'_>,
+        sink: &mut W,
+        transfer: &Transfer,
+    ) -> io::Result<N> {
+        let mut counted = transfer.writer(sink);
+        let mut writer = BufWriter::with_capacity(self.upload_buffer_size, &mut counted);
+        let written = transfer
+            .until_cancelled(self.transfer_limits.stream_to(data, &mut writer))
+            .await?;
+        writer.flush().await?;
+        Ok(written)
     }
-}
 
-impl ClientInterface {
-    pub fn new(server: String) -> Result<Self> {
-        Ok(ClientInterface { server })
+    async fn connect(&self) -> Result<Channel, tonic::transport::Error> {
+        match &self.backend {
+            Backend::Remote(endpoint) => {
+                debug!("Connecting to {}", endpoint.uri());
+                let x = endpoint.connect_with_connector(connector()).await;
+                if x.is_err() {
+                    client_metrics::GRPC_CLIENT_CONNECT_ERRORS.inc();
+                }
+                debug!("Connected to {}", endpoint.uri());
+                x
+            }
+            Backend::Balanced(channel) | Backend::Unix(channel) => Ok(channel.clone()),
+        }
+    }
+
+    /*
+     * Tells the backend about a pull served from the manifest cache, so it's counted. Not waited
+     * for, as the pull doesn't depend on it.
+     */
+    fn record_cached_pull(&self, repo_name: &RepoName, reference: &Reference) {
+        let ci = self.clone();
+        let mr = manifest_ref(repo_name, reference);
+        tokio::spawn(async move {
+            let res = match ci.connect_registry().await {
+                Ok(mut client) => client.record_pull(Request::new(mr)).await.map(|_| ()),
+                Err(e) => Err(tonic::Status::unavailable(e.to_string())),
+            };
+            if let Err(e) = res {
+                debug!("Failed to record cached pull {:?}", e);
+            }
+        });
     }
 
     async fn connect_registry(
         &self,
-    ) -> Result<RegistryClient<tonic::transport::Channel>, tonic::transport::Error> {
-        debug!("Connecting to {}", self.server);
-        let x = RegistryClient::connect(self.server.to_string()).await;
-        debug!("Connected to {}", self.server);
-        x
+    ) -> Result<RegistryClient<WithRequestId>, tonic::transport::Error> {
+        let channel = self.connect().await?;
+        Ok(RegistryClient::with_interceptor(
+            Measured(Traced(channel)),
+            request_id::add_to_grpc_request as Interceptor,
+        ))
     }
 
     async fn connect_admission_controller(
         &self,
-    ) -> Result<AdmissionControllerClient<tonic::transport::Channel>, tonic::transport::Error> {
-        debug!("Connecting to {}", self.server);
-        let x = AdmissionControllerClient::connect(self.server.to_string()).await;
-        debug!("Connected to {}", self.server);
-        x
+    ) -> Result<AdmissionControllerClient<WithRequestId>, tonic::transport::Error> {
+        let channel = self.connect().await?;
+        Ok(AdmissionControllerClient::with_interceptor(
+            Measured(Traced(channel)),
+            request_id::add_to_grpc_request as Interceptor,
+        ))
     }
 
     async fn request_upload(&self, repo_name: &str) -> Result<String> {
@@ -387,6 +2053,139 @@ impl ClientInterface {
         Ok(response.uuid)
     }
 
+    /*
+     * Tells the backend how much of the upload has been stored and acknowledged, so the upload can
+     * carry on from there if the backend restarts.
+     */
+    async fn upload_stored(&self, repo_name: &RepoName, uuid: &Uuid, offset: u64) -> Result<()> {
+        let req = StoredUpload {
+            repo_name: repo_name.0.clone(),
+            uuid: uuid.0.clone(),
+            offset,
+        };
+        self.connect_registry()
+            .await?
+            .upload_stored(Request::new(req))
+            .await?;
+        Ok(())
+    }
+
+    /*
+     * Appends a chunk to the upload's data, which was offset bytes long. If the client sent a
+     * Content-Range, the chunk has to be as long as it says.
+     */
+    async fn append_chunk<'a>(
+        &self,
+        repo_name: &RepoName,
+        uuid: &Uuid,
+        info: Option<&ContentInfo>,
+        data: DataStream<'a>,
+        sink: &mut rocket::tokio::fs::File,
+        offset: u64,
+    ) -> Result<Stored, StorageDriverError> {
+        let transfer = self.transfers.upload(&repo_name.0, &uuid.0);
+        let stream_res = self
+            .stream_upload(data, sink, &transfer)
+            .await
+            .map_err(|e| {
+                warn!("Error writing blob {:?}", e);
+                stream_error(e, &uuid.0)
+            })?;
+
+        let chunk_len = stream_res.written;
+        let total = sink
+            .seek(SeekFrom::End(0))
+            .await
+            .unwrap_or(offset + chunk_len);
+        if let Some(info) = info {
+            if (info.range.1 + 1) != total {
+                warn!("total {} r + 1 {}", total, info.range.1 + 1);
+                return Err(StorageDriverError::InvalidContentRange);
+            }
+            //Check length if chunked upload
+            if info.length != chunk_len {
+                warn!("info.length {} len {}", info.length, chunk_len);
+                return Err(StorageDriverError::InvalidContentRange);
+            }
+        }
+        Ok(Stored {
+            total_stored: total,
+            chunk: chunk_len,
+            complete: stream_res.complete,
+        })
+    }
+
+    /*
+     * Stores a range of an upload that doesn't follow on from the data so far. It's kept apart
+     * until the upload is completed, so the client is only told about the data up to stored.
+     */
+    async fn store_blob_part<'a>(
+        &self,
+        repo_name: &RepoName,
+        uuid: &Uuid,
+        info: &ContentInfo,
+        data: DataStream<'a>,
+        mut sink: rocket::tokio::fs::File,
+        stored: u64,
+    ) -> Result<Stored, StorageDriverError> {
+        let transfer = self.transfers.upload(&repo_name.0, &uuid.0);
+        let stream_res = self
+            .stream_upload(data, &mut sink, &transfer)
+            .await
+            .map_err(|e| {
+                warn!("Error writing part of blob {:?}", e);
+                stream_error(e, &uuid.0)
+            })?;
+        sink.flush()
+            .await
+            .map_err(|_| StorageDriverError::Internal)?;
+
+        let chunk_len = stream_res.written;
+        if stream_res.complete
+            && (info.length != chunk_len || info.range.1 - info.range.0 + 1 != chunk_len)
+        {
+            warn!(
+                "Part {}-{} of upload {} was {} bytes",
+                info.range.0, info.range.1, uuid, chunk_len
+            );
+            return Err(StorageDriverError::InvalidContentRange);
+        }
+
+        Ok(Stored {
+            total_stored: stored,
+            chunk: chunk_len,
+            complete: stream_res.complete,
+        })
+    }
+
+    /*
+     * Why the backend wouldn't take a chunk. One out of order, or sent while another is being
+     * written, is refused with how much is stored, so the client can carry on from there.
+     */
+    async fn chunk_error(
+        &self,
+        repo_name: &RepoName,
+        uuid: &Uuid,
+        e: anyhow::Error,
+    ) -> StorageDriverError {
+        if let Some(reason) = read_only_reason(&e) {
+            return StorageDriverError::ReadOnly(reason);
+        }
+        match e.downcast_ref::<tonic::Status>() {
+            Some(ts) if ts.code() == Code::OutOfRange => {
+                warn!("{}", ts.message());
+                match self.status_blob_upload(&repo_name.0, &uuid.0).await {
+                    Ok(status) => StorageDriverError::OutOfOrder(status.uploaded),
+                    Err(e) => e,
+                }
+            }
+            _ => {
+                warn!("Error finding write sink for blob {:?}", e);
+                StorageDriverError::InvalidName(format!("{} {}", repo_name, uuid))
+            }
+        }
+    }
+
     async fn complete_upload(&self, repo_name: &str, uuid: &str, digest: &Digest) -> Result<()> {
         info!(
             "Complete Upload called for repository {} with upload id {} digest {}",
@@ -407,11 +2206,7 @@ impl ClientInterface {
         Ok(())
     }
 
-    async fn get_write_sink_for_upload(
-        &self,
-        repo_name: &RepoName,
-        uuid: &Uuid,
-    ) -> Result<impl AsyncWrite + AsyncSeek> {
+    async fn get_write_location(&self, repo_name: &RepoName, uuid: &Uuid) -> Result<WriteLocation> {
         info!(
             "Getting write location for blob in repo {} with upload id {}",
             repo_name, uuid
@@ -427,20 +2222,74 @@ impl ClientInterface {
             .get_write_location_for_blob(Request::new(br))
             .await?
             .into_inner();
+        Ok(resp)
+    }
+
+    async fn get_write_sink_for_upload(
+        &self,
+        repo_name: &RepoName,
+        uuid: &Uuid,
+    ) -> Result<impl AsyncWrite + AsyncSeek> {
+        let location = self.get_write_location(repo_name, uuid).await?;
 
         //For the moment we know it's a file location
         let file = rocket::tokio::fs::OpenOptions::new()
             .create(true)
             .append(true)
-            .open(resp.path)
+            .open(location.path)
             .await?;
         Ok(file)
     }
 
+    /*
+     * Where to write the next chunk of the upload, with the first and last byte if the client
+     * sent them. A part sent ahead of the data so far gets a file of its own, and sending the same
+     * range again replaces what was sent before. Anything else is appended, and upload_stored has
+     * to be called once it's written so the next chunk can be sent.
+     */
+    async fn get_write_sink_for_chunk(
+        &self,
+        repo_name: &RepoName,
+        uuid: &Uuid,
+        range: Option<(u64, u64)>,
+    ) -> Result<(rocket::tokio::fs::File, WriteLocation)> {
+        let (start, end) = range.unwrap_or_default();
+        let cr = ChunkRef {
+            repo_name: repo_name.0.clone(),
+            uuid: uuid.0.clone(),
+            ranged: range.is_some(),
+            start,
+            end,
+        };
+
+        let resp = self
+            .connect_registry()
+            .await?
+            .start_chunk(Request::new(cr))
+            .await?
+            .into_inner();
+
+        let mut options = rocket::tokio::fs::OpenOptions::new();
+        if resp.part {
+            options.create(true).write(true).truncate(true);
+        } else {
+            options.create(true).append(true);
+        }
+        match options.open(&resp.path).await {
+            Ok(file) => Ok((file, resp)),
+            Err(e) => {
+                if !resp.part {
+                    let _ = self.upload_stored(repo_name, uuid, resp.offset).await;
+                }
+                Err(e.into())
+            }
+        }
+    }
+
     async fn upload_manifest(
         &self,
         repo_name: &RepoName,
-        reference: &str,
+        reference: &Reference,
         manifest: DataStream<'_>,
     ) -> Result<types::VerifiedManifest, RegistryError> {
         let (mut sink_loc, uuid) = self
@@ -451,6 +2300,10 @@ impl ClientInterface {
                 if let Ok(ts) = e {
                     match ts.code() {
                         Code::InvalidArgument => RegistryError::InvalidName,
+                        Code::AlreadyExists => {
+                            RegistryError::TagImmutable(ts.message().to_string())
+                        }
+                        Code::Unavailable => RegistryError::ReadOnly(ts.message().to_string()),
                         _ => RegistryError::Internal,
                     }
                 } else {
@@ -458,10 +2311,17 @@ impl ClientInterface {
                 }
             })?;
 
-        let stream_res = manifest.stream_to(&mut sink_loc).await.map_err(|e| {
-            warn!("Error writing out manifest {:?}", e);
-            RegistryError::Internal
-        })?;
+        let transfer = self.transfers.push(&repo_name.0);
+        let stream_res = self
+            .stream_upload(manifest, &mut sink_loc, &transfer)
+            .await
+            .map_err(|e| {
+                warn!("Error writing out manifest {:?}", e);
+                match e.kind() {
+                    io::ErrorKind::TimedOut => RegistryError::TimedOut(e.to_string()),
+                    _ => RegistryError::Internal,
+                }
+            })?;
 
         //It's not going to verify if it's clipped
         if !stream_res.complete {
@@ -471,6 +2331,11 @@ impl ClientInterface {
             );
             return Err(RegistryError::ManifestClipped);
         }
+        // On disk before the backend makes it current, so a crash can't leave it torn
+        sink_loc.sync_all().await.map_err(|e| {
+            warn!("Error syncing manifest {:?}", e);
+            RegistryError::Internal
+        })?;
 
         self.verify_manifest(repo_name, reference, &uuid)
             .await
@@ -478,7 +2343,17 @@ impl ClientInterface {
                 let e = e.downcast::<tonic::Status>();
                 if let Ok(ts) = e {
                     match ts.code() {
-                        Code::InvalidArgument => RegistryError::InvalidManifest,
+                        Code::InvalidArgument => {
+                            RegistryError::InvalidManifest(ts.message().to_string())
+                        }
+                        Code::NotFound => RegistryError::BlobUnknown(ts.message().to_string()),
+                        Code::ResourceExhausted => {
+                            RegistryError::QuotaExceeded(ts.message().to_string())
+                        }
+                        Code::AlreadyExists => {
+                            RegistryError::TagImmutable(ts.message().to_string())
+                        }
+                        Code::Unavailable => RegistryError::ReadOnly(ts.message().to_string()),
                         _ => RegistryError::Internal,
                     }
                 } else {
@@ -490,16 +2365,13 @@ impl ClientInterface {
     async fn get_write_sink_for_manifest(
         &self,
         repo_name: &RepoName,
-        reference: &str,
-    ) -> Result<(impl AsyncWrite, String)> {
+        reference: &Reference,
+    ) -> Result<(rocket::tokio::fs::File, String)> {
         info!(
             "Getting write location for manifest in repo {} with ref {}",
             repo_name, reference
         );
-        let mr = ManifestRef {
-            reference: reference.to_owned(),
-            repo_name: repo_name.0.clone(),
-        };
+        let mr = manifest_ref(repo_name, reference);
 
         let resp = self
             .connect_registry()
@@ -509,9 +2381,9 @@ impl ClientInterface {
             .into_inner();
 
         //For the moment we know it's a file location
-        //Manifests don't append; just overwrite
+        //It's a new file in the scratch dir, which the backend moves into place once verified
         let file = rocket::tokio::fs::OpenOptions::new()
-            .create(true)
+            .create_new(true)
             .write(true)
             .open(resp.path)
             .await?;
@@ -521,16 +2393,23 @@ impl ClientInterface {
     async fn get_reader_for_manifest(
         &self,
         repo_name: &RepoName,
-        reference: &str,
+        reference: &Reference,
     ) -> Result<ManifestReader> {
+        let cache_key = reference.to_string();
+        if let Some(mr) = self
+            .manifest_cache
+            .as_ref()
+            .and_then(|c| c.get(&repo_name.0, &cache_key))
+        {
+            debug!("Manifest cache hit for {}:{}", repo_name, reference);
+            self.record_cached_pull(repo_name, reference);
+            return Ok(mr);
+        }
         info!(
             "Getting read location for {} with ref {}",
             repo_name, reference
         );
-        let mr = ManifestRef {
-            reference: reference.to_owned(),
-            repo_name: repo_name.0.clone(),
-        };
+        let mr = manifest_ref(repo_name, reference);
         let resp = self
             .connect_registry()
             .await?
@@ -538,9 +2417,14 @@ impl ClientInterface {
             .await?
             .into_inner();
 
+        let digest = digest::parse(&resp.digest)?;
+        if let Some(cache) = &self.manifest_cache {
+            let bytes = rocket::tokio::fs::read(resp.path).await?;
+            return Ok(cache.insert(&repo_name.0, &cache_key, resp.content_type, digest, bytes));
+        }
+
         //For the moment we know it's a file location
         let file = rocket::tokio::fs::File::open(resp.path).await?;
-        let digest = digest::parse(&resp.digest)?;
         let mr = ManifestReader {
             reader: Box::pin(file),
             content_type: resp.content_type,
@@ -607,9 +2491,18 @@ impl ClientInterface {
 
         //For the moment we know it's a file location
         let file = rocket::tokio::fs::File::open(resp.path).await?;
+        let size = file.metadata().await?.len();
+        let transfer = self.transfers.download(&repo_name.0, &digest.to_string());
         let reader = BlobReader {
-            reader: Box::pin(file),
+            reader: self.transfer_limits.reader(transfer.reader(Box::pin(file))),
             digest: digest.clone(),
+            size,
+            range: ReadRange::Whole,
+            redirect: match self.blob_redirect {
+                Some(ref r) if !resp.local_only => Some(r.location(digest)),
+                _ => None,
+            },
+            content_digest: None,
         };
         Ok(reader)
     }
@@ -636,7 +2529,7 @@ impl ClientInterface {
     async fn verify_manifest(
         &self,
         repo_name: &RepoName,
-        reference: &str,
+        reference: &Reference,
         uuid: &str,
     ) -> Result<types::VerifiedManifest> {
         info!(
@@ -644,10 +2537,7 @@ impl ClientInterface {
             reference, repo_name, uuid
         );
         let vmr = VerifyManifestRequest {
-            manifest: Some(ManifestRef {
-                reference: reference.to_owned(),
-                repo_name: repo_name.0.clone(),
-            }),
+            manifest: Some(manifest_ref(repo_name, reference)),
             uuid: uuid.to_string(),
         };
 
@@ -669,10 +2559,7 @@ impl ClientInterface {
         digest: &Digest,
     ) -> Result<ManifestDeleted> {
         info!("Attempting to delete manifest {} in {}", digest, repo_name);
-        let mr = ManifestRef {
-            reference: digest.to_string(),
-            repo_name: repo_name.0.clone(),
-        };
+        let mr = manifest_ref(repo_name, &Reference::Digest(digest.clone()));
 
         self.connect_registry()
             .await?
@@ -682,15 +2569,23 @@ impl ClientInterface {
         Ok(ManifestDeleted {})
     }
 
-    async fn get_catalog_part(&self, limit: u32, last_repo: &str) -> Result<RepoCatalog> {
+    async fn get_catalog_part(
+        &self,
+        limit: u32,
+        last_repo: &str,
+        prefix: &str,
+        user: &str,
+    ) -> Result<RepoCatalog> {
         info!(
-            "Getting image catalog limit {} last_repo {}",
-            limit, last_repo
+            "Getting image catalog limit {} last_repo {} prefix {} for {}",
+            limit, last_repo, prefix, user
         );
 
         let cr = CatalogRequest {
             limit,
             last_repo: last_repo.to_string(),
+            prefix: prefix.to_string(),
+            user: user.to_string(),
         };
         let mut stream = self
             .connect_registry()
@@ -747,22 +2642,55 @@ impl ClientInterface {
         );
         //TODO: write something to convert automatically (into()) between AdmissionRequest types
         // TODO: we should really be sending the full object to the backend.
-        let mut images = Vec::new();
-        extract_images(&req.object, &mut images);
-        let ar = trow_proto::AdmissionRequest {
-            images,
-            namespace: req.namespace.clone(),
-            operation: req.operation.clone(),
-            host_names: host_names.to_vec(),
+        let images = req.images();
+        let break_glass = req.break_glass().unwrap_or_default().to_string();
+        // Overrides need the backend to log them
+        let cache = self
+            .admission_cache
+            .as_ref()
+            .filter(|_| break_glass.is_empty());
+
+        let resp = match cache.and_then(|c| c.get(&images, &req.namespace)) {
+            Some(decision) => {
+                debug!("Using cached admission decision for {}", req.uid);
+                trow_proto::AdmissionResponse {
+                    is_allowed: decision.allowed,
+                    reason: decision.reason,
+                    break_glass: false,
+                    images: vec![],
+                }
+            }
+            None => {
+                // Taken first, so a decision made while the policy is reloaded isn't kept
+                let generation = cache.map(|c| c.generation());
+                let ar = trow_proto::AdmissionRequest {
+                    images: images.clone(),
+                    namespace: req.namespace.clone(),
+                    operation: req.operation.as_str().to_string(),
+                    host_names: host_names.to_vec(),
+                    break_glass,
+                };
+                let resp = self
+                    .connect_admission_controller()
+                    .await?
+                    .validate_admission(Request::new(ar))
+                    .await?
+                    .into_inner();
+                if let (Some(cache), Some(generation)) = (cache, generation) {
+                    cache.insert(
+                        &images,
+                        &req.namespace,
+                        generation,
+                        CachedDecision {
+                            allowed: resp.is_allowed,
+                            reason: resp.reason.clone(),
+                        },
+                    );
+                }
+                resp
+            }
         };
 
-        let resp = self
-            .connect_admission_controller()
-            .await?
-            .validate_admission(Request::new(ar))
-            .await?
-            .into_inner();
-
         //TODO: again, this should be an automatic conversion
         let st = if resp.is_allowed {
             validation::Status {
@@ -778,10 +2706,24 @@ impl ClientInterface {
                 code: None,
             }
         };
+        // Shows up in the Kubernetes audit log as well as ours
+        let audit_annotations = if resp.break_glass {
+            req.break_glass().map(|reason| {
+                let mut annotations = HashMap::new();
+                annotations.insert(
+                    validation::BREAK_GLASS_ANNOTATION.to_string(),
+                    reason.to_string(),
+                );
+                annotations
+            })
+        } else {
+            None
+        };
         Ok(validation::AdmissionResponse {
             uid: req.uid.clone(),
             allowed: resp.is_allowed,
             status: Some(st),
+            audit_annotations,
         })
     }
 
@@ -874,3 +2816,43 @@ impl ClientInterface {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::ClientInterface;
+    use crate::registry_interface::RegistryInterface;
+    use tempfile::tempdir;
+    use trow_server::ListenAddr;
+
+    #[rocket::async_test]
+    async fn unix_socket_backend() {
+        let dir = tempdir().unwrap();
+        let socket = dir.path().join("backend.sock");
+        let server = trow_server::build_server(
+            dir.path().to_str().unwrap(),
+            vec![ListenAddr::Unix(socket.clone())],
+            false,
+            None,
+            None,
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+        )
+        .get_server_future(std::future::pending())
+        .unwrap();
+        rocket::tokio::spawn(server);
+
+        let ri: Box<dyn RegistryInterface> =
+            Box::new(ClientInterface::unix(socket.to_str().unwrap(), None).unwrap());
+        assert!(ri.is_healthy().await);
+        assert!(ri
+            .get_catalog(None, None, None, None)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let uuid = ri.start_blob_upload("test/repo").await.unwrap();
+        assert!(!uuid.is_empty());
+    }
+}
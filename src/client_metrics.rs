@@ -0,0 +1,126 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+use prometheus::{
+    opts, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+};
+use tonic::codegen::{http, Service};
+use tonic::Code;
+
+/*
+ * Metrics for the frontend's calls to the backend, so slowness in the backend can be told apart
+ * from slowness in the HTTP layer.
+ *
+ * They're registered in the default registry, which the backend gathers for GET /metrics, as
+ * both run in the same process.
+ *
+ * The latency of a call is the time until the backend starts responding. For streaming calls
+ * this excludes reading the stream, and errors part way through a stream aren't counted.
+ */
+
+lazy_static! {
+    pub static ref GRPC_CLIENT_CALLS: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "grpc_client_calls_total",
+            "total number of calls from the frontend to the backend, by method and gRPC status code"
+        ),
+        &["method", "code"]
+    )
+    .unwrap();
+    pub static ref GRPC_CLIENT_CALL_DURATION: HistogramVec = register_histogram_vec!(
+        "grpc_client_call_duration_seconds",
+        "time in seconds for the backend to respond to calls from the frontend, by method",
+        &["method"]
+    )
+    .unwrap();
+    pub static ref GRPC_CLIENT_CONNECT_ERRORS: IntCounter = register_int_counter!(opts!(
+        "grpc_client_connect_errors_total",
+        "total number of failed connections from the frontend to the backend"
+    ))
+    .unwrap();
+    pub static ref GRPC_CLIENT_BACKENDS: IntGauge = register_int_gauge!(opts!(
+        "grpc_client_backends",
+        "number of backends found at --backend-address that calls are balanced across"
+    ))
+    .unwrap();
+}
+
+// Calls that failed without a response, e.g. because the connection dropped
+const TRANSPORT_ERROR: &str = "TransportError";
+
+// The method name from the call path, e.g. GetMetrics from /trow.Registry/GetMetrics
+fn method_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn status_code<R>(resp: &http::Response<R>) -> String {
+    let code = resp
+        .headers()
+        .get("grpc-status")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i32>().ok())
+        // No status in the headers means it's in the trailers of a successful response
+        .map_or(Code::Ok, Code::from_i32);
+    format!("{:?}", code)
+}
+
+/*
+ * Wraps the channel to the backend to record each call.
+ */
+#[derive(Clone)]
+pub struct Measured<S>(pub S);
+
+impl<S, B, R> Service<http::Request<B>> for Measured<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<R>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let method = method_name(req.uri().path()).to_string();
+        let start = Instant::now();
+        let fut = self.0.call(req);
+        Box::pin(async move {
+            let res = fut.await;
+            GRPC_CLIENT_CALL_DURATION
+                .with_label_values(&[&method])
+                .observe(start.elapsed().as_secs_f64());
+            let code = match &res {
+                Ok(resp) => status_code(resp),
+                Err(_) => TRANSPORT_ERROR.to_string(),
+            };
+            GRPC_CLIENT_CALLS.with_label_values(&[&method, &code]).inc();
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{method_name, status_code};
+    use tonic::codegen::http;
+
+    #[test]
+    fn names_and_codes() {
+        assert_eq!(method_name("/trow.Registry/GetMetrics"), "GetMetrics");
+
+        let ok = http::Response::builder().body(()).unwrap();
+        assert_eq!(status_code(&ok), "Ok");
+        let not_found = http::Response::builder()
+            .header("grpc-status", "5")
+            .body(())
+            .unwrap();
+        assert_eq!(status_code(&not_found), "NotFound");
+    }
+}
@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use serde_yaml::Value;
+
+/*
+ * Settings read from a YAML file given with --config, and from TROW_* environment variables, for
+ * deployments that would rather not pass dozens of flags.
+ *
+ * Each key stands in for a command line flag, grouped by what it configures, e.g. listen.port for
+ * --port or auth.oidc.issuer for --oidc-issuer. The environment variable for a key is its path in
+ * capitals with underscores, e.g. TROW_LISTEN_PORT or TROW_AUTH_OIDC_ISSUER. Flags on the command
+ * line win over environment variables, which win over the file.
+ *
+ * Values are checked as they're read, so mistakes are reported against the key they were under
+ * rather than the flag it stands in for.
+ */
+
+static ENV_PREFIX: &str = "TROW_";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    // A flag without a value
+    Switch,
+    // The opposite of a flag without a value, e.g. tls.enabled for --no-tls
+    NotSwitch,
+    Text,
+    Number,
+    // Comma separated on the command line. Can also be a sequence, or a mapping of NAME: VALUE
+    // for flags taking NAME=VALUE entries.
+    List,
+}
+
+// Key, flag and kind of value
+static KEYS: &[(&str, &str, Kind)] = &[
+    ("listen.host", "host", Kind::List),
+    ("listen.port", "port", Kind::Number),
+    ("listen.names", "names", Kind::List),
+    ("listen.max-request-size", "max-request-size", Kind::Number),
+    ("listen.max-header-size", "max-header-size", Kind::Number),
+    ("listen.idle-timeout", "idle-timeout", Kind::Text),
+    ("listen.transfer-timeout", "transfer-timeout", Kind::Text),
+    ("listen.idempotency-keys", "idempotency-keys", Kind::Number),
+    ("tls.enabled", "no-tls", Kind::NotSwitch),
+    ("tls.cert", "cert", Kind::Text),
+    ("tls.key", "key", Kind::Text),
+    ("tls.secret-dir", "tls-secret-dir", Kind::Text),
+    ("tls.grpc.ca", "grpc-tls-ca", Kind::Text),
+    ("tls.grpc.cert", "grpc-tls-cert", Kind::Text),
+    ("tls.grpc.key", "grpc-tls-key", Kind::Text),
+    ("tls.grpc.server-name", "grpc-tls-server-name", Kind::Text),
+    ("tls.grpc.required", "grpc-require-tls", Kind::Switch),
+    ("backend.listen", "grpc-listen", Kind::List),
+    ("backend.socket-mode", "grpc-socket-mode", Kind::Text),
+    ("backend.address", "backend-address", Kind::Text),
+    ("storage.data-dir", "data-dir", Kind::Text),
+    ("storage.metadata-db", "metadata-db", Kind::Text),
+    ("storage.standalone", "standalone", Kind::Switch),
+    ("storage.ha", "ha", Kind::Switch),
+    ("storage.read-only", "read-only", Kind::Switch),
+    ("storage.watch-data-dir", "watch-data-dir", Kind::Switch),
+    (
+        "storage.max-manifest-size",
+        "max-manifest-size",
+        Kind::Number,
+    ),
+    ("storage.max-blob-size", "max-blob-size", Kind::Number),
+    ("storage.max-layers", "max-layers", Kind::Number),
+    (
+        "storage.upload-buffer-size",
+        "upload-buffer-size",
+        Kind::Number,
+    ),
+    ("storage.upload-ttl", "upload-ttl", Kind::Text),
+    ("storage.trash-retention", "trash-retention", Kind::Text),
+    ("storage.url", "storage", Kind::Text),
+    ("storage.scratch-dir", "scratch-dir", Kind::Text),
+    ("storage.reserve", "storage-reserve", Kind::Text),
+    ("storage.scrub-interval", "scrub-interval", Kind::Text),
+    ("storage.scrub-quarantine", "scrub-quarantine", Kind::Switch),
+    ("storage.transcode-layers", "transcode-layers", Kind::Number),
+    (
+        "storage.transcode-interval",
+        "transcode-interval",
+        Kind::Text,
+    ),
+    (
+        "storage.transcode-on-demand",
+        "transcode-on-demand",
+        Kind::Switch,
+    ),
+    ("storage.estargz", "estargz", Kind::Switch),
+    ("storage.estargz-interval", "estargz-interval", Kind::Text),
+    ("storage.blob-redirect.url", "blob-redirect-url", Kind::Text),
+    (
+        "storage.blob-redirect.secret-file",
+        "blob-redirect-secret-file",
+        Kind::Text,
+    ),
+    (
+        "storage.blob-redirect.expiry",
+        "blob-redirect-expiry",
+        Kind::Text,
+    ),
+    ("auth.user", "user", Kind::Text),
+    ("auth.password", "password", Kind::Text),
+    ("auth.password-file", "password-file", Kind::Text),
+    ("auth.htpasswd", "htpasswd", Kind::Text),
+    ("auth.htpasswd-pull", "htpasswd-pull", Kind::Switch),
+    ("auth.first-run-setup", "first-run-setup", Kind::Switch),
+    ("auth.setup-secret", "setup-secret", Kind::Text),
+    ("auth.delete-users", "delete-users", Kind::List),
+    ("auth.k8s.rules", "k8s-auth-rules", Kind::List),
+    ("auth.k8s.audience", "k8s-token-audience", Kind::Text),
+    ("auth.oidc.issuer", "oidc-issuer", Kind::Text),
+    ("auth.oidc.audience", "oidc-audience", Kind::Text),
+    ("auth.oidc.roles", "oidc-roles", Kind::List),
+    ("auth.oidc.groups-claim", "oidc-groups-claim", Kind::Text),
+    ("auth.spiffe.bundle", "spiffe-bundle", Kind::Text),
+    ("auth.spiffe.rules", "spiffe-rules", Kind::List),
+    ("auth.spiffe.svid", "spiffe-svid", Kind::Text),
+    ("auth.spiffe.svid-key", "spiffe-svid-key", Kind::Text),
+    (
+        "admission.allow-docker-official",
+        "allow-docker-official",
+        Kind::Switch,
+    ),
+    ("admission.deny-k8s-images", "deny-k8s-images", Kind::Switch),
+    ("admission.allow-prefixes", "allow-prefixes", Kind::List),
+    ("admission.allow-images", "allow-images", Kind::List),
+    (
+        "admission.deny-local-prefixes",
+        "disallow-local-prefixes",
+        Kind::List,
+    ),
+    (
+        "admission.deny-local-images",
+        "disallow-local-images",
+        Kind::List,
+    ),
+    ("admission.freeze-windows", "freeze-windows", Kind::List),
+    ("admission.immutable-tags", "immutable-tags", Kind::List),
+    ("admission.policy-crd", "policy-crd", Kind::Switch),
+    (
+        "admission.require-existing-images",
+        "require-existing-images",
+        Kind::Switch,
+    ),
+    ("admission.cache-ttl", "admission-cache-ttl", Kind::Text),
+    ("quotas", "quotas", Kind::List),
+    ("proxy.docker-hub", "proxy-docker-hub", Kind::Switch),
+    ("proxy.hub-user", "hub-user", Kind::Text),
+    ("proxy.hub-token", "hub-token", Kind::Text),
+    ("proxy.hub-token-file", "hub-token-file", Kind::Text),
+    ("proxy.check-interval", "proxy-check-interval", Kind::Text),
+    ("proxy.check-sample", "proxy-check-sample", Kind::Number),
+    (
+        "proxy.mirror-on-admission",
+        "mirror-on-admission",
+        Kind::Switch,
+    ),
+    ("proxy.mirror-workers", "mirror-workers", Kind::Number),
+    ("proxy.mirror-queue-size", "mirror-queue-size", Kind::Number),
+    ("proxy.upstream", "upstream-proxies", Kind::List),
+    ("cors.enabled", "enable-cors", Kind::Switch),
+    ("cors.origins", "cors-origins", Kind::List),
+    ("cors.methods", "cors-methods", Kind::List),
+    ("cors.headers", "cors-headers", Kind::List),
+    ("web-ui", "web-ui", Kind::Switch),
+];
+
+// Flags that need another to be set, as clap checks for the command line
+static REQUIRES: &[(&str, &str)] = &[
+    ("blob-redirect-url", "blob-redirect-secret-file"),
+    ("blob-redirect-secret-file", "blob-redirect-url"),
+    ("htpasswd-pull", "htpasswd"),
+    ("first-run-setup", "htpasswd"),
+    ("setup-secret", "first-run-setup"),
+    ("k8s-token-audience", "k8s-auth-rules"),
+    ("oidc-issuer", "oidc-audience"),
+    ("oidc-issuer", "oidc-roles"),
+    ("oidc-audience", "oidc-issuer"),
+    ("oidc-roles", "oidc-issuer"),
+    ("oidc-groups-claim", "oidc-issuer"),
+    ("spiffe-svid", "spiffe-svid-key"),
+    ("spiffe-svid-key", "spiffe-svid"),
+    ("grpc-tls-ca", "grpc-tls-cert"),
+    ("grpc-tls-cert", "grpc-tls-key"),
+    ("grpc-tls-key", "grpc-tls-ca"),
+    ("grpc-tls-server-name", "grpc-tls-cert"),
+    ("transcode-interval", "transcode-layers"),
+    ("transcode-on-demand", "transcode-layers"),
+    ("estargz-interval", "estargz"),
+    ("proxy-check-interval", "proxy-docker-hub"),
+    ("proxy-check-sample", "proxy-docker-hub"),
+    ("mirror-on-admission", "proxy-docker-hub"),
+    ("mirror-workers", "mirror-on-admission"),
+    ("mirror-queue-size", "mirror-on-admission"),
+    ("cors-origins", "enable-cors"),
+    ("cors-methods", "enable-cors"),
+    ("cors-headers", "enable-cors"),
+];
+
+fn env_name(key: &str) -> String {
+    format!(
+        "{}{}",
+        ENV_PREFIX,
+        key.to_uppercase().replace(['.', '-'], "_")
+    )
+}
+
+fn key_for_flag(flag: &str) -> &str {
+    KEYS.iter()
+        .find(|(_, f, _)| *f == flag)
+        .map_or(flag, |(key, _, _)| key)
+}
+
+fn is_section(path: &str) -> bool {
+    KEYS.iter().any(|(key, _, _)| {
+        key.strip_prefix(path)
+            .map_or(false, |rest| rest.starts_with('.'))
+    })
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn list(value: &Value) -> Option<String> {
+    let entries: Option<Vec<String>> = match value {
+        Value::Sequence(seq) => seq.iter().map(scalar).collect(),
+        Value::Mapping(map) => map
+            .iter()
+            .map(|(k, v)| Some(format!("{}={}", scalar(k)?, scalar(v)?)))
+            .collect(),
+        _ => return scalar(value),
+    };
+    entries.map(|e| e.join(","))
+}
+
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    values: HashMap<&'static str, String>,
+    switches: HashMap<&'static str, bool>,
+}
+
+impl ConfigFile {
+    /// Reads the file at path if there is one, then TROW_* environment variables over it
+    pub fn load(path: Option<&str>) -> Result<ConfigFile> {
+        match path {
+            Some(path) => {
+                let yaml = fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Failed to read config file {}: {}", path, e))?;
+                ConfigFile::parse(path, &yaml)
+            }
+            None => {
+                let mut config = ConfigFile::default();
+                config.read_env(std::env::vars())?;
+                Ok(config)
+            }
+        }
+    }
+
+    /// Reads the contents of the file at path, then TROW_* environment variables over it
+    pub fn parse(path: &str, yaml: &str) -> Result<ConfigFile> {
+        let mut config = ConfigFile::default();
+        config
+            .read_yaml(yaml)
+            .map_err(|e| anyhow!("Error in {}: {}", path, e))?;
+        config.read_env(std::env::vars())?;
+        Ok(config)
+    }
+
+    fn read_yaml(&mut self, yaml: &str) -> Result<()> {
+        let doc: Value = serde_yaml::from_str(yaml)?;
+        match doc {
+            Value::Null => Ok(()),
+            Value::Mapping(_) => self.read_section("", &doc),
+            _ => Err(anyhow!("Expected a mapping of settings")),
+        }
+    }
+
+    fn read_section(&mut self, prefix: &str, section: &Value) -> Result<()> {
+        let map = match section {
+            Value::Mapping(map) => map,
+            _ => return Err(anyhow!("Invalid {}: expected a mapping", prefix)),
+        };
+        for (name, value) in map {
+            let name = scalar(name).ok_or_else(|| anyhow!("Invalid key in {:?}", prefix))?;
+            let path = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}.{}", prefix, name)
+            };
+            if let Some(&(key, flag, kind)) = KEYS.iter().find(|(k, _, _)| *k == path) {
+                // Left empty, as in a template
+                if value.is_null() {
+                    continue;
+                }
+                self.set(flag, kind, value)
+                    .map_err(|e| anyhow!("Invalid {}: {}", key, e))?;
+            } else if is_section(&path) {
+                if !value.is_null() {
+                    self.read_section(&path, value)?;
+                }
+            } else {
+                return Err(anyhow!("Unknown key {}", path));
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Applies TROW_* variables for known keys. Others are ignored, as Kubernetes sets variables
+     * such as TROW_PORT for a service called trow.
+     */
+    fn read_env(&mut self, vars: impl Iterator<Item = (String, String)>) -> Result<()> {
+        let names: HashMap<String, (&str, &'static str, Kind)> = KEYS
+            .iter()
+            .map(|&(key, flag, kind)| (env_name(key), (key, flag, kind)))
+            .collect();
+        for (name, value) in vars {
+            if let Some(&(key, flag, kind)) = names.get(&name) {
+                if value.is_empty() {
+                    continue;
+                }
+                self.set(flag, kind, &Value::String(value))
+                    .map_err(|e| anyhow!("Invalid {} ({}): {}", name, key, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, flag: &'static str, kind: Kind, value: &Value) -> Result<(), String> {
+        match kind {
+            Kind::Switch | Kind::NotSwitch => {
+                let on = match value {
+                    Value::Bool(b) => *b,
+                    Value::String(s) => s
+                        .parse()
+                        .map_err(|_| format!("expected true or false, got {:?}", s))?,
+                    _ => return Err("expected true or false".to_string()),
+                };
+                self.switches.insert(flag, on != (kind == Kind::NotSwitch));
+            }
+            Kind::Text => {
+                let s = scalar(value).ok_or("expected a string")?;
+                self.values.insert(flag, s);
+            }
+            Kind::Number => {
+                let s = scalar(value).ok_or("expected a whole number")?;
+                if s.parse::<u64>().is_err() {
+                    return Err(format!("expected a whole number, got {:?}", s));
+                }
+                self.values.insert(flag, s);
+            }
+            Kind::List => {
+                let s = list(value).ok_or("expected a list of strings")?;
+                self.values.insert(flag, s);
+            }
+        }
+        Ok(())
+    }
+
+    /// The value for a flag, if set
+    pub fn value_of(&self, flag: &str) -> Option<&str> {
+        self.values.get(flag).map(|s| s.as_str())
+    }
+
+    /// Whether a flag is set, or turned on if it doesn't take a value
+    pub fn is_present(&self, flag: &str) -> bool {
+        self.values.contains_key(flag) || self.switches.get(flag) == Some(&true)
+    }
+
+    /// The entries for a flag taking a list, split as they are on the command line
+    pub fn list_of(&self, flag: &str) -> Vec<String> {
+        self.value_of(flag)
+            .unwrap_or_default()
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|e| !e.is_empty())
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    /// Keys set differently in the two, other than those for the given flags
+    pub fn changed_keys(&self, other: &ConfigFile, except: &[&str]) -> Vec<&'static str> {
+        KEYS.iter()
+            .filter(|(_, flag, _)| !except.contains(flag))
+            .filter(|(_, flag, _)| {
+                self.values.get(flag) != other.values.get(flag)
+                    || self.switches.get(flag) != other.switches.get(flag)
+            })
+            .map(|(key, _, _)| *key)
+            .collect()
+    }
+
+    /*
+     * Checks settings that need others are accompanied by them, either here or in the flags
+     * passed, reporting the keys involved.
+     */
+    pub fn check_requires(&self, flag_passed: impl Fn(&str) -> bool) -> Result<()> {
+        for (flag, needs) in REQUIRES {
+            if self.is_present(flag) && !self.is_present(needs) && !flag_passed(needs) {
+                return Err(anyhow!(
+                    "{} needs {} to be set",
+                    key_for_flag(flag),
+                    key_for_flag(needs)
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConfigFile;
+
+    static YAML: &str = r#"
+listen:
+  port: 8000
+  names: [registry.example.com, trow.kube-public]
+tls:
+  enabled: false
+storage:
+  data-dir: /data
+  blob-redirect:
+auth:
+  oidc:
+    issuer: https://dex.example.com
+    audience: trow
+    roles:
+      developers: push
+      "*": pull
+quotas:
+  myorg: 10GiB:100
+proxy:
+  docker-hub: true
+"#;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn reads_keys_as_flags() {
+        let mut config = ConfigFile::default();
+        config.read_yaml(YAML).unwrap();
+        assert_eq!(config.value_of("port"), Some("8000"));
+        assert_eq!(
+            config.value_of("names"),
+            Some("registry.example.com,trow.kube-public")
+        );
+        assert!(config.is_present("no-tls"));
+        assert!(config.is_present("proxy-docker-hub"));
+        assert!(!config.is_present("blob-redirect-url"));
+        assert_eq!(
+            config.value_of("oidc-roles"),
+            Some("developers=push,*=pull")
+        );
+        assert_eq!(config.value_of("quotas"), Some("myorg=10GiB:100"));
+        assert_eq!(config.list_of("quotas"), vec!["myorg=10GiB:100"]);
+        assert!(config.list_of("allow-images").is_empty());
+        assert!(config.check_requires(|_| false).is_ok());
+        let before = ConfigFile {
+            values: config.values.clone(),
+            switches: config.switches.clone(),
+        };
+
+        config
+            .read_env(vars(&[
+                ("TROW_LISTEN_PORT", "9000"),
+                ("TROW_PROXY_DOCKER_HUB", "false"),
+                ("TROW_PORT", "tcp://10.0.0.1:8000"),
+            ]))
+            .unwrap();
+        assert_eq!(config.value_of("port"), Some("9000"));
+        assert!(!config.is_present("proxy-docker-hub"));
+        assert_eq!(
+            before.changed_keys(&config, &["proxy-docker-hub"]),
+            vec!["listen.port"]
+        );
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let err = |yaml: &str| {
+            ConfigFile::default()
+                .read_yaml(yaml)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(err("listen:\n  hots: x"), "Unknown key listen.hots");
+        assert_eq!(
+            err("listen:\n  port: eighty"),
+            "Invalid listen.port: expected a whole number, got \"eighty\""
+        );
+        assert_eq!(
+            err("proxy:\n  docker-hub: yes please"),
+            "Invalid proxy.docker-hub: expected true or false, got \"yes please\""
+        );
+
+        let err = ConfigFile::default()
+            .read_env(vars(&[("TROW_TLS_ENABLED", "no")]))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid TROW_TLS_ENABLED (tls.enabled): expected true or false, got \"no\""
+        );
+
+        let mut config = ConfigFile::default();
+        config
+            .read_yaml("auth:\n  oidc:\n    issuer: https://dex.example.com")
+            .unwrap();
+        assert_eq!(
+            config.check_requires(|_| false).unwrap_err().to_string(),
+            "auth.oidc.issuer needs auth.oidc.audience to be set"
+        );
+        assert!(config.check_requires(|_| true).is_ok());
+    }
+}
@@ -0,0 +1,323 @@
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::config_file::ConfigFile;
+use crate::registry_interface::{Policies, PolicyRules};
+
+/*
+ * Reloading rules from the --config file while Trow is running.
+ *
+ * The file is read every CHECK_INTERVAL and compared with what was last read, rather than
+ * checking its modification time, so a mounted ConfigMap being swapped for a new version is
+ * always noticed. When it changes, the admission allow and deny lists, quotas, immutable tags and
+ * change freezes are sent to the backend, and the frontend picks up the users who can delete.
+ * Other settings need a restart, so changes to them are only logged. Anything set by a flag stays
+ * as it is, as flags win over the file.
+ *
+ * Each change that's applied starts a new generation, shown by GET /api/v1/config along with the
+ * generation the backend is using. If the new file can't be used, e.g. there's a typo in it, the
+ * old rules are kept and the error is shown there until the file is fixed.
+ */
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Flags that can be changed without a restart
+pub static RELOADABLE: &[&str] = &[
+    "allow-docker-official",
+    "deny-k8s-images",
+    "allow-prefixes",
+    "allow-images",
+    "disallow-local-prefixes",
+    "disallow-local-images",
+    "quotas",
+    "immutable-tags",
+    "freeze-windows",
+    "delete-users",
+];
+
+/*
+ * The images admitted by prefix: those listed, with the Docker official images if they're
+ * allowed, and the Kubernetes system images unless they're denied.
+ */
+pub fn allow_prefixes(
+    mut listed: Vec<String>,
+    docker_official: bool,
+    deny_k8s_images: bool,
+) -> Vec<String> {
+    if docker_official {
+        listed.push("docker.io/".to_owned());
+    }
+    if !deny_k8s_images {
+        listed.push("k8s.gcr.io/".to_owned());
+        listed.push("docker.io/containersol/trow".to_owned());
+    }
+    listed
+}
+
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct ConfigStatus {
+    pub path: Option<String>,
+    // Changes to the file applied since Trow started
+    pub generation: u64,
+    // Should match generation, unless the backend couldn't be reached
+    pub backend_generation: Option<u64>,
+    // When the current generation was applied, None if it's the one Trow started with
+    pub loaded_at: Option<DateTime<Utc>>,
+    // Why the file's current contents aren't in use, if they aren't
+    pub error: Option<String>,
+    // Keys changed since Trow started that only take effect after a restart
+    pub restart_needed: Vec<&'static str>,
+}
+
+#[derive(Debug)]
+struct State {
+    contents: Vec<u8>,
+    rules: PolicyRules,
+    status: ConfigStatus,
+}
+
+#[derive(Debug)]
+pub struct ConfigReloader {
+    path: String,
+    // Reloadable flags given on the command line
+    pinned: Vec<String>,
+    // As read at startup, to tell which changes need a restart
+    started: ConfigFile,
+    delete_users: Arc<RwLock<Vec<String>>>,
+    state: RwLock<State>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        path: &str,
+        pinned: Vec<String>,
+        delete_users: Arc<RwLock<Vec<String>>>,
+    ) -> Result<ConfigReloader> {
+        let contents =
+            fs::read(path).map_err(|e| anyhow!("Failed to read config file {}: {}", path, e))?;
+        let started = ConfigFile::parse(path, &String::from_utf8_lossy(&contents))?;
+        Ok(ConfigReloader {
+            path: path.to_string(),
+            pinned,
+            started,
+            delete_users,
+            state: RwLock::new(State {
+                contents,
+                rules: PolicyRules::default(),
+                status: ConfigStatus {
+                    path: Some(path.to_string()),
+                    generation: 0,
+                    backend_generation: None,
+                    loaded_at: None,
+                    error: None,
+                    restart_needed: vec![],
+                },
+            }),
+        })
+    }
+
+    pub fn status(&self) -> ConfigStatus {
+        self.state.read().unwrap().status.clone()
+    }
+
+    fn is_pinned(&self, flags: &[&str]) -> bool {
+        flags.iter().any(|f| self.pinned.iter().any(|p| p == f))
+    }
+
+    /*
+     * The rules from the file, keeping the current value of any set by flags
+     */
+    fn rules_from(&self, config: &ConfigFile, current: &PolicyRules) -> PolicyRules {
+        let list = |flag: &str, current: &Vec<String>| {
+            if self.is_pinned(&[flag]) {
+                current.clone()
+            } else {
+                config.list_of(flag)
+            }
+        };
+        let prefix_flags = ["allow-prefixes", "allow-docker-official", "deny-k8s-images"];
+        PolicyRules {
+            allow_prefixes: if self.is_pinned(&prefix_flags) {
+                current.allow_prefixes.clone()
+            } else {
+                allow_prefixes(
+                    config.list_of("allow-prefixes"),
+                    config.is_present("allow-docker-official"),
+                    config.is_present("deny-k8s-images"),
+                )
+            },
+            allow_images: list("allow-images", &current.allow_images),
+            deny_prefixes: list("disallow-local-prefixes", &current.deny_prefixes),
+            deny_images: list("disallow-local-images", &current.deny_images),
+            quotas: list("quotas", &current.quotas),
+            immutable_tags: list("immutable-tags", &current.immutable_tags),
+            freeze_windows: list("freeze-windows", &current.freeze_windows),
+        }
+    }
+
+    /*
+     * Sends the rules from the file to the backend as a new generation, then takes on the users
+     * who can delete.
+     */
+    async fn apply<P: Policies + Sync>(&self, contents: &[u8], backend: &P) -> Result<()> {
+        let config = ConfigFile::parse(&self.path, &String::from_utf8_lossy(contents))?;
+        let (generation, rules) = {
+            let state = self.state.read().unwrap();
+            let rules = self.rules_from(&config, &state.rules);
+            (state.status.generation + 1, rules)
+        };
+        backend
+            .update_policy(generation, &rules)
+            .await
+            .map_err(|e| anyhow!("Error in {}: {}", self.path, e))?;
+        if !self.is_pinned(&["delete-users"]) {
+            *self.delete_users.write().unwrap() = config.list_of("delete-users");
+        }
+
+        let restart_needed = self.started.changed_keys(&config, RELOADABLE);
+        if !restart_needed.is_empty() {
+            warn!(
+                "Changes to {:?} in {} need a restart to take effect",
+                restart_needed, self.path
+            );
+        }
+        let mut state = self.state.write().unwrap();
+        state.rules = rules;
+        state.status.generation = generation;
+        state.status.loaded_at = Some(Utc::now());
+        state.status.error = None;
+        state.status.restart_needed = restart_needed;
+        info!("Reloaded {}, now at generation {}", self.path, generation);
+        Ok(())
+    }
+
+    /// Applies the file's contents if they've changed since they were last read
+    pub async fn check<P: Policies + Sync>(&self, backend: &P) {
+        let contents = match fs::read(&self.path) {
+            Ok(c) => c,
+            // Missing for a moment while a ConfigMap is updated
+            Err(_) => return,
+        };
+        let unchanged = contents == self.state.read().unwrap().contents;
+        if unchanged {
+            return;
+        }
+        let res = self.apply(&contents, backend).await;
+        let mut state = self.state.write().unwrap();
+        state.contents = contents;
+        if let Err(e) = res {
+            warn!("Keeping the rules from before {} changed: {}", self.path, e);
+            state.status.error = Some(e.to_string());
+        }
+    }
+
+    /*
+     * Checks the file for changes for as long as Trow runs. The rules are those Trow started
+     * with, which reloads start from.
+     */
+    pub async fn watch<P: Policies + Sync>(self: Arc<Self>, rules: PolicyRules, backend: P) {
+        self.state.write().unwrap().rules = rules;
+        loop {
+            rocket::tokio::time::sleep(CHECK_INTERVAL).await;
+            self.check(&backend).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{allow_prefixes, ConfigReloader};
+    use crate::registry_interface::{Policies, PolicyRules, StorageDriverError};
+    use std::fs;
+    use std::sync::{Arc, Mutex, RwLock};
+
+    #[derive(Default)]
+    struct Backend {
+        updates: Mutex<Vec<(u64, PolicyRules)>>,
+    }
+
+    #[rocket::async_trait]
+    impl Policies for Backend {
+        async fn update_policy(
+            &self,
+            generation: u64,
+            rules: &PolicyRules,
+        ) -> Result<(), StorageDriverError> {
+            if rules.quotas.iter().any(|q| !q.contains('=')) {
+                return Err(StorageDriverError::InvalidPolicy(format!(
+                    "Invalid quota {}",
+                    rules.quotas[0]
+                )));
+            }
+            self.updates
+                .lock()
+                .unwrap()
+                .push((generation, rules.clone()));
+            Ok(())
+        }
+
+        async fn policy_generation(&self) -> Result<u64, StorageDriverError> {
+            Ok(self.updates.lock().unwrap().len() as u64)
+        }
+    }
+
+    #[rocket::async_test]
+    async fn reloads_changed_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trow.yaml");
+        fs::write(&path, "listen:\n  port: 8000\n").unwrap();
+        let delete_users = Arc::new(RwLock::new(vec![]));
+        let reloader = ConfigReloader::new(
+            path.to_str().unwrap(),
+            vec!["allow-images".to_string()],
+            delete_users.clone(),
+        )
+        .unwrap();
+        reloader.state.write().unwrap().rules.allow_images = vec!["quay.io/app:v1".to_string()];
+        let backend = Backend::default();
+
+        // Unchanged
+        reloader.check(&backend).await;
+        assert_eq!(reloader.status().generation, 0);
+
+        fs::write(
+            &path,
+            "listen:\n  port: 9000\nadmission:\n  allow-images: [quay.io/app:v2]\n  deny-k8s-images: true\nquotas:\n  myorg: 1GiB\nauth:\n  delete-users: [admin]\n",
+        )
+        .unwrap();
+        reloader.check(&backend).await;
+        let status = reloader.status();
+        assert_eq!(status.generation, 1);
+        assert_eq!(status.error, None);
+        assert_eq!(status.restart_needed, vec!["listen.port"]);
+        let (generation, rules) = backend.updates.lock().unwrap()[0].clone();
+        assert_eq!(generation, 1);
+        assert_eq!(rules.quotas, vec!["myorg=1GiB"]);
+        assert!(rules.allow_prefixes.is_empty());
+        // Set by a flag
+        assert_eq!(rules.allow_images, vec!["quay.io/app:v1"]);
+        assert_eq!(*delete_users.read().unwrap(), vec!["admin"]);
+
+        fs::write(&path, "quotas: [myorg]\n").unwrap();
+        reloader.check(&backend).await;
+        let status = reloader.status();
+        assert_eq!(status.generation, 1);
+        assert!(status.error.unwrap().contains("Invalid quota myorg"));
+        assert_eq!(*delete_users.read().unwrap(), vec!["admin"]);
+    }
+
+    #[test]
+    fn default_prefixes() {
+        assert_eq!(
+            allow_prefixes(vec!["quay.io/".to_string()], true, true),
+            vec!["quay.io/", "docker.io/"]
+        );
+        assert_eq!(allow_prefixes(vec![], false, false).len(), 2);
+    }
+}
@@ -0,0 +1,139 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use rocket::http::Method;
+use rocket_cors::{AllowedHeaders, AllowedOrigins, Cors, CorsOptions};
+
+/*
+ * Cross-Origin Resource Sharing, so a web UI served from another origin can call the registry and
+ * admin APIs from the browser, such as the catalog, tag lists and usage reports.
+ *
+ * By default any origin can make GET and POST requests with Authorization and Content-Type
+ * headers, which is what --enable-cors has always allowed. As credentials are allowed too, a
+ * registry reachable from the internet should list the origins its UI is served from instead.
+ *
+ * Browsers only let pages read a few response headers unless they're exposed, so the ones a UI
+ * needs are: Link to page through lists, ETag to revalidate them and Docker-Content-Digest for
+ * the digest of a manifest pulled by tag.
+ */
+
+static EXPOSED_HEADERS: &[&str] = &["Docker-Content-Digest", "ETag", "Link"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorsConfig {
+    // Exact origins, e.g. https://ui.example.com, or empty for any origin
+    pub origins: Vec<String>,
+    pub methods: Vec<Method>,
+    // Request headers a page can send, or empty for any
+    pub headers: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            origins: vec![],
+            methods: vec![Method::Get, Method::Post, Method::Options],
+            headers: vec!["Authorization".to_string(), "Content-Type".to_string()],
+        }
+    }
+}
+
+impl CorsConfig {
+    /*
+     * Takes the values of --cors-origins, --cors-methods and --cors-headers, keeping the default
+     * for any left empty. "*" allows any origin or header.
+     */
+    pub fn new(origins: Vec<String>, methods: Vec<String>, headers: Vec<String>) -> Result<Self> {
+        let mut config = CorsConfig::default();
+        if !origins.iter().any(|o| o == "*") {
+            config.origins = origins;
+        }
+        if !methods.is_empty() {
+            config.methods = methods
+                .iter()
+                .map(|m| {
+                    Method::from_str(&m.to_ascii_uppercase())
+                        .map_err(|_| anyhow!("Invalid CORS method {}", m))
+                })
+                .collect::<Result<_>>()?;
+            // Preflight requests are always answered
+            if !config.methods.contains(&Method::Options) {
+                config.methods.push(Method::Options);
+            }
+        }
+        if headers.iter().any(|h| h == "*") {
+            config.headers = vec![];
+        } else if !headers.is_empty() {
+            config.headers = headers;
+        }
+        // Catches origins that aren't URLs
+        config.to_cors()?;
+        Ok(config)
+    }
+
+    pub fn to_cors(&self) -> Result<Cors> {
+        let allowed_origins = if self.origins.is_empty() {
+            AllowedOrigins::all()
+        } else {
+            AllowedOrigins::some_exact(&self.origins)
+        };
+        let allowed_headers = if self.headers.is_empty() {
+            AllowedHeaders::all()
+        } else {
+            let headers: Vec<&str> = self.headers.iter().map(String::as_str).collect();
+            AllowedHeaders::some(&headers)
+        };
+        let cors = CorsOptions {
+            allowed_origins,
+            allowed_methods: self.methods.iter().cloned().map(From::from).collect(),
+            allowed_headers,
+            allow_credentials: true,
+            expose_headers: EXPOSED_HEADERS.iter().map(|h| h.to_string()).collect(),
+            ..Default::default()
+        }
+        .to_cors()
+        .map_err(|e| anyhow!("Invalid CORS settings: {}", e))?;
+        Ok(cors)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CorsConfig;
+    use rocket::http::Method;
+
+    fn list(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn defaults_to_any_origin() {
+        let config = CorsConfig::new(vec![], vec![], vec![]).unwrap();
+        assert_eq!(config, CorsConfig::default());
+        assert_eq!(
+            CorsConfig::new(list(&["*"]), vec![], list(&["*"]))
+                .unwrap()
+                .origins,
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn parses_settings() {
+        let config = CorsConfig::new(
+            list(&["https://ui.example.com", "http://localhost:3000"]),
+            list(&["get", "HEAD", "delete"]),
+            list(&["Authorization"]),
+        )
+        .unwrap();
+        assert_eq!(config.origins.len(), 2);
+        assert_eq!(
+            config.methods,
+            vec![Method::Get, Method::Head, Method::Delete, Method::Options]
+        );
+        assert_eq!(config.headers, list(&["Authorization"]));
+
+        assert!(CorsConfig::new(vec![], list(&["FETCH"]), vec![]).is_err());
+        assert!(CorsConfig::new(list(&["not a url"]), vec![], vec![]).is_err());
+    }
+}
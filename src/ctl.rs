@@ -0,0 +1,261 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+pub use crate::registry_interface::{
+    ActiveTransferList, JobList, JobStatus, RepositoryDeleted, RepositoryList, TransferCancelled,
+    UploadList,
+};
+pub use crate::types::TagList;
+
+/*
+ * Client for the admin API, used by the trow-ctl binary so operators don't have to put together
+ * curl commands.
+ *
+ * Everything goes through the HTTP API rather than the gRPC backend, so it works from outside the
+ * cluster and is subject to the same authentication and audit logging as any other client.
+ * Credentials are sent as basic auth on each request, which Trow accepts without logging in first.
+ */
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// So tags resolve to the digest of the manifest as it was pushed
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+pub struct AdminClient {
+    url: String,
+    client: Client,
+    credentials: Option<(String, String)>,
+}
+
+impl AdminClient {
+    /*
+     * url is where Trow is served e.g. https://trow.example.com. ca_cert is a PEM file to trust
+     * as well as the system roots, for registries using a private CA.
+     */
+    pub fn new(
+        url: &str,
+        credentials: Option<(String, String)>,
+        ca_cert: Option<&Path>,
+    ) -> Result<AdminClient> {
+        let mut builder = Client::builder().timeout(TIMEOUT);
+        if let Some(path) = ca_cert {
+            let pem =
+                fs::read(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        Ok(AdminClient {
+            url: url.trim_end_matches('/').to_string(),
+            client: builder.build()?,
+            credentials,
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let req = self.client.request(method, format!("{}{}", self.url, path));
+        match &self.credentials {
+            Some((user, pass)) => req.basic_auth(user, Some(pass)),
+            None => req,
+        }
+    }
+
+    fn send(&self, req: RequestBuilder) -> Result<Response> {
+        let resp = req.send()?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp);
+        }
+        let url = resp.url().to_string();
+        Err(anyhow!(
+            "{} returned {}{}",
+            url,
+            status,
+            error_message(&resp.text().unwrap_or_default())
+                .map(|m| format!(": {}", m))
+                .unwrap_or_default()
+        ))
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        Ok(self.send(self.request(Method::GET, path))?.json()?)
+    }
+
+    pub fn repositories(&self) -> Result<RepositoryList> {
+        self.get("/api/v1/repositories")
+    }
+
+    pub fn tags(&self, repo: &str) -> Result<TagList> {
+        self.get(&format!("/v2/{}/tags/list", repo))
+    }
+
+    // The digest of the manifest a tag points to
+    pub fn resolve(&self, repo: &str, tag: &str) -> Result<String> {
+        let resp = self.send(
+            self.request(Method::HEAD, &format!("/v2/{}/manifests/{}", repo, tag))
+                .header("Accept", MANIFEST_TYPES),
+        )?;
+        resp.headers()
+            .get("Docker-Content-Digest")
+            .and_then(|d| d.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("No digest returned for {}:{}", repo, tag))
+    }
+
+    // Removes the manifest and every tag in the repository pointing to it
+    pub fn delete_manifest(&self, repo: &str, digest: &str) -> Result<()> {
+        self.send(self.request(
+            Method::DELETE,
+            &format!("/v2/{}/manifests/{}", repo, digest),
+        ))?;
+        Ok(())
+    }
+
+    pub fn delete_repository(&self, repo: &str) -> Result<RepositoryDeleted> {
+        Ok(self
+            .send(self.request(Method::DELETE, &format!("/api/v1/repositories/{}", repo)))?
+            .json()?)
+    }
+
+    pub fn start_job(&self, kind: &str) -> Result<JobStatus> {
+        Ok(self
+            .send(
+                self.request(Method::POST, "/trow/v1/jobs")
+                    .json(&json!({ "kind": kind })),
+            )?
+            .json()?)
+    }
+
+    pub fn prewarm(&self, image: &str, notify: bool) -> Result<JobStatus> {
+        Ok(self
+            .send(
+                self.request(Method::POST, "/trow/v1/prewarm")
+                    .json(&json!({ "image": image, "notify": notify })),
+            )?
+            .json()?)
+    }
+
+    pub fn job(&self, id: &str) -> Result<JobStatus> {
+        self.get(&format!("/trow/v1/jobs/{}", id))
+    }
+
+    pub fn jobs(&self) -> Result<JobList> {
+        self.get("/trow/v1/jobs")
+    }
+
+    pub fn uploads(&self) -> Result<UploadList> {
+        self.get("/api/v1/uploads")
+    }
+
+    pub fn transfers(&self) -> Result<ActiveTransferList> {
+        self.get("/api/v1/active-transfers")
+    }
+
+    pub fn cancel_transfer(&self, uuid: &str) -> Result<TransferCancelled> {
+        Ok(self
+            .send(self.request(
+                Method::DELETE,
+                &format!("/api/v1/active-transfers/{}", uuid),
+            ))?
+            .json()?)
+    }
+}
+
+// The first message from an OCI error response, if that's what the body is
+fn error_message(body: &str) -> Option<String> {
+    let errors: serde_json::Value = serde_json::from_str(body).ok()?;
+    errors["errors"][0]["message"]
+        .as_str()
+        .filter(|m| !m.is_empty())
+        .map(str::to_string)
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/*
+ * Lines up columns for printing, with the first row as the header. Each column is as wide as its
+ * widest value, except the last which isn't padded.
+ */
+pub fn format_table(rows: &[Vec<String>]) -> String {
+    let columns = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|c| {
+            rows.iter()
+                .filter_map(|r| r.get(c))
+                .map(|v| v.chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let mut out = String::new();
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(c, v)| {
+                if c + 1 == row.len() {
+                    v.clone()
+                } else {
+                    format!("{:width$}", v, width = widths[c])
+                }
+            })
+            .collect();
+        out.push_str(&line.join("  "));
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{error_message, format_bytes, format_table};
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(28719534), "27.4 MiB");
+    }
+
+    #[test]
+    fn formats_tables() {
+        let rows = vec![
+            vec!["NAME".to_string(), "TAGS".to_string()],
+            vec!["org/app".to_string(), "3".to_string()],
+        ];
+        assert_eq!(format_table(&rows), "NAME     TAGS\norg/app  3\n");
+        assert_eq!(format_table(&[]), "");
+    }
+
+    #[test]
+    fn reads_error_messages() {
+        let body = r#"{"errors":[{"code":"NAME_UNKNOWN","message":"repository name not known to registry","detail":"org/app"}]}"#;
+        assert_eq!(
+            error_message(body),
+            Some("repository name not known to registry".to_string())
+        );
+        assert_eq!(error_message("Not Found"), None);
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+
+/*
+ * Users from an htpasswd file with bcrypt hashes, as written by `htpasswd -B` or
+ * `trow htpasswd add`. Each line is user:hash, and blank lines and lines starting with # are
+ * ignored.
+ *
+ * The file is read again when it changes, so users can be added and removed while Trow is
+ * running. If the new contents can't be used, the previous users are kept.
+ */
+
+pub struct Htpasswd {
+    path: PathBuf,
+    current: RwLock<(Option<SystemTime>, Arc<HashMap<String, String>>)>,
+}
+
+impl fmt::Debug for Htpasswd {
+    // Leaves out the hashes
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Htpasswd")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+fn parse(contents: &str) -> Result<HashMap<String, String>> {
+    let mut users = HashMap::new();
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (user, hash) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Line {} should be of the form user:hash", n + 1))?;
+        if !is_bcrypt(hash) {
+            return Err(anyhow!(
+                "Password for {} on line {} isn't a bcrypt hash",
+                user,
+                n + 1
+            ));
+        }
+        users.insert(user.to_string(), hash.to_string());
+    }
+    Ok(users)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Htpasswd {
+    pub fn load(path: &str) -> Result<Htpasswd> {
+        let path = PathBuf::from(path);
+        let modified = modified(&path);
+        let users = parse(&fs::read_to_string(&path)?)?;
+        Ok(Htpasswd {
+            path,
+            current: RwLock::new((modified, Arc::new(users))),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn users(&self) -> Arc<HashMap<String, String>> {
+        let modified = modified(&self.path);
+        {
+            let current = self.current.read().unwrap();
+            if current.0 == modified {
+                return current.1.clone();
+            }
+        }
+
+        let mut current = self.current.write().unwrap();
+        match fs::read_to_string(&self.path)
+            .map_err(anyhow::Error::from)
+            .and_then(|c| parse(&c))
+        {
+            Ok(users) => {
+                info!("Reloaded {} users from {:?}", users.len(), self.path);
+                *current = (modified, Arc::new(users));
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to reload {:?}, keeping the old users: {}",
+                    self.path, e
+                );
+                // Don't retry until it changes again
+                current.0 = modified;
+            }
+        }
+        current.1.clone()
+    }
+
+    pub fn verify(&self, user: &str, pass: &str) -> bool {
+        match self.users().get(user) {
+            Some(hash) => bcrypt::verify(pass, hash).unwrap_or(false),
+            None => false,
+        }
+    }
+}
+
+fn read_lines(path: &str) -> Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.lines().map(|l| l.to_string()).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Replaced in one go, so a running Trow never reads a partial file
+fn write_lines(path: &str, lines: &[String]) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn is_user_line(line: &str, user: &str) -> bool {
+    matches!(line.trim().split_once(':'), Some((u, _)) if u == user)
+}
+
+/// Adds the user to the file, creating it if needed, or changes their password
+pub fn add_user(path: &str, user: &str, pass: &str) -> Result<()> {
+    if user.is_empty() || user.contains(':') {
+        return Err(anyhow!("User names can't be empty or contain ':'"));
+    }
+    let hash = bcrypt::hash(pass, bcrypt::DEFAULT_COST)?;
+    let mut lines = read_lines(path)?;
+    let entry = format!("{}:{}", user, hash);
+    match lines.iter_mut().find(|l| is_user_line(l, user)) {
+        Some(line) => *line = entry,
+        None => lines.push(entry),
+    }
+    write_lines(path, &lines)
+}
+
+/// Removes the user from the file, returning whether they were in it
+pub fn remove_user(path: &str, user: &str) -> Result<bool> {
+    let lines = read_lines(path)?;
+    let kept: Vec<String> = lines
+        .iter()
+        .filter(|l| !is_user_line(l, user))
+        .cloned()
+        .collect();
+    if kept.len() == lines.len() {
+        return Ok(false);
+    }
+    write_lines(path, &kept)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{add_user, parse, remove_user, Htpasswd};
+    use std::fs;
+
+    #[test]
+    fn parse_file() {
+        let users = parse(
+            "# Trow users\n\nalice:$2y$05$6B2wO8Kt9fBQWn5oMyuiKO4Dq6z5T6Vdh0Jq1lQ6W0OoKpWnSxD9K\n",
+        )
+        .unwrap();
+        assert!(users.contains_key("alice"));
+        assert!(parse("alice").is_err());
+        // Only bcrypt is supported
+        assert!(parse("alice:$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/").is_err());
+    }
+
+    #[test]
+    fn add_and_remove_users() {
+        let path = std::env::temp_dir().join(format!("trow-htpasswd-{}", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+
+        add_user(path, "alice", "secret").unwrap();
+        add_user(path, "bob", "hunter2").unwrap();
+        let htpasswd = Htpasswd::load(path).unwrap();
+        assert!(htpasswd.verify("alice", "secret"));
+        assert!(htpasswd.verify("bob", "hunter2"));
+        assert!(!htpasswd.verify("alice", "hunter2"));
+        assert!(!htpasswd.verify("carol", "secret"));
+
+        // Changing a password replaces the line
+        add_user(path, "alice", "changed").unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap().lines().count(), 2);
+
+        assert!(remove_user(path, "bob").unwrap());
+        assert!(!remove_user(path, "bob").unwrap());
+        assert!(add_user(path, "a:b", "secret").is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use rocket::data::Data;
+use rocket::http::{Method, Status};
+use rocket::outcome::Outcome as RocketOutcome;
+use rocket::request::Request;
+use rocket::response::Response;
+use rocket::route::{Handler, Outcome, Route};
+use rocket::tokio::sync::Mutex as AsyncMutex;
+use sha2::{Digest, Sha256};
+
+/*
+ * Idempotency keys for pushes, so a client retrying a manifest PUT or blob upload completion
+ * after a dropped connection gets the response to its first attempt instead of repeating it.
+ *
+ * Clients send an Idempotency-Key header with a unique value per operation. The first successful
+ * response for a key is kept for a day and replayed, with an Idempotent-Replayed header, to
+ * requests with the same key and credentials. A request arriving while another with the same key
+ * is in progress waits for it. Using a key on a different URL is refused, as is likely a client
+ * bug. Failures aren't kept, so they can be retried.
+ *
+ * Keys are held in memory, so they don't survive a restart and aren't shared between replicas.
+ * As clients choose them, only the most recently used max_keys are kept, and expired keys are
+ * dropped by a sweep every few minutes rather than on each request.
+ */
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+pub const DEFAULT_MAX_KEYS: usize = 10_000;
+const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+const KEY_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const MAX_KEY_LEN: usize = 255;
+
+#[derive(Clone)]
+struct StoredResponse {
+    status: Status,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+struct Slot {
+    // Method and path the key was first used with
+    target: String,
+    response: Option<StoredResponse>,
+}
+
+struct Entry {
+    slot: Arc<AsyncMutex<Slot>>,
+    created: Instant,
+    // When it was last used, as a position in Slots::order
+    used: u64,
+}
+
+impl Entry {
+    // Held by a request, or one waiting for it
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.slot) > 1
+    }
+}
+
+#[derive(Default)]
+struct Slots {
+    entries: HashMap<String, Entry>,
+    // Keys from least to most recently used
+    order: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+impl Slots {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+        }
+    }
+
+    // Keys in use are skipped, so there can be more than the maximum while they are
+    fn evict_least_recent(&mut self) {
+        let unused = self
+            .order
+            .values()
+            .find(|key| self.entries.get(*key).map_or(true, |e| !e.in_use()))
+            .cloned();
+        if let Some(key) = unused {
+            self.remove(&key);
+        }
+    }
+}
+
+pub struct IdempotencyCache {
+    slots: Mutex<Slots>,
+    max_keys: usize,
+}
+
+impl IdempotencyCache {
+    pub fn new(max_keys: usize) -> IdempotencyCache {
+        IdempotencyCache {
+            slots: Mutex::new(Slots::default()),
+            max_keys,
+        }
+    }
+
+    fn slot(&self, key: String, target: &str) -> Arc<AsyncMutex<Slot>> {
+        let mut slots = self.slots.lock().unwrap();
+        let slots = &mut *slots;
+        slots.uses += 1;
+        let used = slots.uses;
+        if let Some(entry) = slots.entries.get_mut(&key) {
+            slots.order.remove(&entry.used);
+            entry.used = used;
+            slots.order.insert(used, key);
+            return entry.slot.clone();
+        }
+
+        while slots.entries.len() >= self.max_keys.max(1) {
+            let before = slots.entries.len();
+            slots.evict_least_recent();
+            if slots.entries.len() == before {
+                break;
+            }
+        }
+        let slot = Arc::new(AsyncMutex::new(Slot {
+            target: target.to_string(),
+            response: None,
+        }));
+        slots.order.insert(used, key.clone());
+        slots.entries.insert(
+            key,
+            Entry {
+                slot: slot.clone(),
+                created: Instant::now(),
+                used,
+            },
+        );
+        slot
+    }
+
+    // Drops keys older than KEY_LIFETIME that aren't in use, returning how many
+    fn expire(&self, now: Instant) -> usize {
+        let mut slots = self.slots.lock().unwrap();
+        let expired: Vec<String> = slots
+            .entries
+            .iter()
+            .filter(|(_, e)| !e.in_use() && now.duration_since(e.created) >= KEY_LIFETIME)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            slots.remove(key);
+        }
+        expired.len()
+    }
+
+    fn len(&self) -> usize {
+        self.slots.lock().unwrap().entries.len()
+    }
+
+    /// Drops expired keys every few minutes, for as long as Trow runs
+    pub async fn expire_keys(self: Arc<Self>) {
+        loop {
+            rocket::tokio::time::sleep(SWEEP_INTERVAL).await;
+            let expired = self.expire(Instant::now());
+            if expired > 0 {
+                debug!(
+                    "Dropped {} expired idempotency keys, {} left",
+                    expired,
+                    self.len()
+                );
+            }
+        }
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LEN && key.chars().all(|c| c.is_ascii_graphic())
+}
+
+// Keys are per client, so they're stored with a hash of the credentials
+fn scoped_key(req: &Request<'_>, key: &str) -> String {
+    let auth = req.headers().get_one("Authorization").unwrap_or_default();
+    format!("{}:{}", hex::encode(Sha256::digest(auth.as_bytes())), key)
+}
+
+async fn store(resp: &mut Response<'_>) -> Option<StoredResponse> {
+    let body = match resp.body_mut().to_bytes().await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to read response to keep for idempotency key: {}", e);
+            return None;
+        }
+    };
+    let stored = StoredResponse {
+        status: resp.status(),
+        headers: resp
+            .headers()
+            .iter()
+            .map(|h| (h.name().to_string(), h.value().to_string()))
+            .collect(),
+        body,
+    };
+    // The body has been read, so put it back
+    resp.set_sized_body(stored.body.len(), Cursor::new(stored.body.clone()));
+    Some(stored)
+}
+
+fn replay(stored: StoredResponse) -> Response<'static> {
+    let mut builder = Response::build();
+    builder.status(stored.status);
+    for (name, value) in stored.headers {
+        builder.raw_header_adjoin(name, value);
+    }
+    builder
+        .raw_header(REPLAYED_HEADER, "true")
+        .sized_body(stored.body.len(), Cursor::new(stored.body))
+        .finalize()
+}
+
+#[derive(Clone)]
+struct WithIdempotencyKey {
+    handler: Box<dyn Handler>,
+    cache: Arc<IdempotencyCache>,
+}
+
+#[rocket::async_trait]
+impl Handler for WithIdempotencyKey {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let key = match req.headers().get_one(IDEMPOTENCY_KEY_HEADER) {
+            Some(key) => key,
+            None => return self.handler.handle(req, data).await,
+        };
+        if !is_valid_key(key) {
+            return RocketOutcome::Failure(Status::BadRequest);
+        }
+
+        let target = format!("{} {}", req.method(), req.uri().path());
+        let slot = self.cache.slot(scoped_key(req, key), &target);
+        let mut slot = slot.lock().await;
+        if slot.target != target {
+            warn!(
+                "Idempotency key {} used for {}, but was first used for {}",
+                key, target, slot.target
+            );
+            return RocketOutcome::Failure(Status::UnprocessableEntity);
+        }
+        if let Some(ref stored) = slot.response {
+            info!("Replaying response for idempotency key {}", key);
+            return RocketOutcome::Success(replay(stored.clone()));
+        }
+
+        let mut outcome = self.handler.handle(req, data).await;
+        if let RocketOutcome::Success(ref mut resp) = outcome {
+            if resp.status().class().is_success() {
+                slot.response = store(resp).await;
+            }
+        }
+        outcome
+    }
+}
+
+/// Adds idempotency keys to the routes that complete a push
+pub fn with_idempotency_keys(routes: Vec<Route>, cache: Arc<IdempotencyCache>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            let path = route.uri.path().to_string();
+            let completes_push = route.method == Method::Put
+                && (path.contains("/manifests/") || path.contains("/blobs/uploads/"));
+            if completes_push {
+                route.handler = Box::new(WithIdempotencyKey {
+                    handler: route.handler,
+                    cache: cache.clone(),
+                });
+            }
+            route
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_valid_key, IdempotencyCache, KEY_LIFETIME};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[test]
+    fn validates_keys() {
+        assert!(is_valid_key("8e03978e-40d5-43e8-bc93-6894a57f9324"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("has space"));
+        assert!(!is_valid_key(&"k".repeat(300)));
+    }
+
+    #[tokio::test]
+    async fn slots_are_shared_per_key() {
+        let cache = IdempotencyCache::new(10);
+        let first = cache.slot("a".to_string(), "PUT /v2/x/manifests/1");
+        let again = cache.slot("a".to_string(), "PUT /v2/y/manifests/1");
+        assert!(std::sync::Arc::ptr_eq(&first, &again));
+        // The first use decides the target
+        assert_eq!(again.lock().await.target, "PUT /v2/x/manifests/1");
+        let other = cache.slot("b".to_string(), "PUT /v2/x/manifests/1");
+        assert!(!std::sync::Arc::ptr_eq(&first, &other));
+    }
+
+    #[test]
+    fn evicts_least_recently_used_keys() {
+        let cache = IdempotencyCache::new(2);
+        let a = cache.slot("a".to_string(), "PUT /v2/x/manifests/1");
+        drop(cache.slot("b".to_string(), "PUT /v2/x/manifests/2"));
+        drop(a);
+        // Used again, so b is now the oldest
+        let a = cache.slot("a".to_string(), "PUT /v2/x/manifests/1");
+        drop(cache.slot("c".to_string(), "PUT /v2/x/manifests/3"));
+        assert_eq!(cache.len(), 2);
+        assert!(Arc::ptr_eq(&a, &cache.slot("a".to_string(), "")));
+        let b = cache.slot("b".to_string(), "PUT /v2/y/manifests/2");
+        assert_eq!(b.try_lock().unwrap().target, "PUT /v2/y/manifests/2");
+        assert_eq!(cache.len(), 2);
+
+        // Keys in use aren't evicted, even if that means going over
+        let d = cache.slot("d".to_string(), "PUT /v2/x/manifests/4");
+        assert_eq!(cache.len(), 3);
+        drop((a, b, d));
+        drop(cache.slot("e".to_string(), "PUT /v2/x/manifests/5"));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn expires_keys() {
+        let cache = IdempotencyCache::new(10);
+        let in_use = cache.slot("a".to_string(), "PUT /v2/x/manifests/1");
+        drop(cache.slot("b".to_string(), "PUT /v2/x/manifests/2"));
+        assert_eq!(cache.expire(Instant::now()), 0);
+        assert_eq!(cache.expire(Instant::now() + KEY_LIFETIME), 1);
+        assert_eq!(cache.len(), 1);
+        drop(in_use);
+        assert_eq!(cache.expire(Instant::now() + KEY_LIFETIME), 1);
+        assert_eq!(cache.len(), 0);
+    }
+}
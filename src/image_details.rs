@@ -0,0 +1,193 @@
+use log::warn;
+use rocket::tokio::io::AsyncReadExt;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::registry_interface::{digest, PlatformImage, RegistryInterface, StorageDriverError};
+
+/*
+ * What the web UI shows about an image: its layers and their sizes, and when and for which
+ * platform it was built, which is only in the config blob.
+ *
+ * For an image index, the platforms it covers are listed instead, each of which can be looked up
+ * by digest in turn. Manifests of artifacts, e.g. Helm charts or signatures, have no image config,
+ * so only their layers are listed.
+ */
+
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+
+// Image configs are a few KiB, even with a long history
+const MAX_CONFIG_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, PartialEq)]
+pub struct ImageDetails {
+    pub repository: String,
+    pub reference: String,
+    pub digest: String,
+    pub media_type: String,
+    // Of the manifest itself
+    pub manifest_size: u64,
+    pub config: Option<Descriptor>,
+    pub layers: Vec<Descriptor>,
+    // Config and layers
+    pub image_size: u64,
+    // From the config, None if it isn't an image config or isn't stored
+    pub created: Option<String>,
+    pub os: Option<String>,
+    pub architecture: Option<String>,
+    // For image indexes
+    pub platforms: Vec<PlatformImage>,
+}
+
+fn descriptor(value: &Value) -> Option<Descriptor> {
+    Some(Descriptor {
+        media_type: value
+            .get("mediaType")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        digest: value.get("digest")?.as_str()?.to_string(),
+        size: value.get("size")?.as_u64()?,
+    })
+}
+
+fn parse_manifest(details: &mut ImageDetails, bytes: &[u8]) -> Result<(), StorageDriverError> {
+    let manifest: Value =
+        serde_json::from_slice(bytes).map_err(|_| StorageDriverError::InvalidManifest)?;
+    // Older manifests only give the media type in the header
+    if let Some(media_type) = manifest.get("mediaType").and_then(Value::as_str) {
+        details.media_type = media_type.to_string();
+    }
+    details.config = manifest.get("config").and_then(descriptor);
+    details.layers = manifest
+        .get("layers")
+        .and_then(Value::as_array)
+        .map(|layers| layers.iter().filter_map(descriptor).collect())
+        .unwrap_or_default();
+    details.image_size = details.config.iter().map(|c| c.size).sum::<u64>()
+        + details.layers.iter().map(|l| l.size).sum::<u64>();
+    Ok(())
+}
+
+fn parse_config(details: &mut ImageDetails, bytes: &[u8]) {
+    let config: Value = match serde_json::from_slice(bytes) {
+        Ok(config) => config,
+        Err(_) => return,
+    };
+    let field = |name: &str| config.get(name).and_then(Value::as_str).map(String::from);
+    details.created = field("created");
+    details.os = field("os");
+    details.architecture = field("architecture");
+}
+
+async fn read_config(
+    ci: &dyn RegistryInterface,
+    repo_name: &str,
+    config: &Descriptor,
+) -> Option<Vec<u8>> {
+    if config.size > MAX_CONFIG_SIZE {
+        return None;
+    }
+    let digest = digest::parse(&config.digest).ok()?;
+    let blob = match ci.get_blob(repo_name, &digest).await {
+        Ok(blob) => blob,
+        Err(e) => {
+            warn!(
+                "Failed to read config {} of {}: {}",
+                config.digest, repo_name, e
+            );
+            return None;
+        }
+    };
+    let mut bytes = vec![];
+    blob.reader
+        .take(MAX_CONFIG_SIZE)
+        .read_to_end(&mut bytes)
+        .await
+        .ok()?;
+    Some(bytes)
+}
+
+/// Details of the manifest identified by repo_name and reference, a tag or digest
+pub async fn image_details(
+    ci: &dyn RegistryInterface,
+    repo_name: &str,
+    reference: &str,
+) -> Result<ImageDetails, StorageDriverError> {
+    let mr = ci.get_manifest(repo_name, reference).await?;
+    let mut details = ImageDetails {
+        repository: repo_name.to_string(),
+        reference: reference.to_string(),
+        digest: mr.digest().to_string(),
+        media_type: mr.content_type().to_string(),
+        ..Default::default()
+    };
+    let mut bytes = vec![];
+    mr.get_reader()
+        .read_to_end(&mut bytes)
+        .await
+        .map_err(|_| StorageDriverError::Internal)?;
+    details.manifest_size = bytes.len() as u64;
+    parse_manifest(&mut details, &bytes)?;
+
+    if details.media_type == OCI_INDEX || details.media_type == DOCKER_LIST {
+        details.platforms = ci
+            .index_summary(repo_name, &details.digest)
+            .await?
+            .platforms;
+    } else if let Some(config) = &details.config {
+        if let Some(bytes) = read_config(ci, repo_name, config).await {
+            parse_config(&mut details, &bytes);
+        }
+    }
+    Ok(details)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_config, parse_manifest, ImageDetails};
+
+    #[test]
+    fn reads_layers_and_config() {
+        let manifest = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": "sha256:aaa",
+                "size": 1000
+            },
+            "layers": [
+                {"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": "sha256:bbb", "size": 2000},
+                {"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": "sha256:ccc", "size": 3000}
+            ]
+        }"#;
+        let mut details = ImageDetails::default();
+        parse_manifest(&mut details, manifest.as_bytes()).unwrap();
+        assert_eq!(details.config.as_ref().unwrap().digest, "sha256:aaa");
+        assert_eq!(details.layers.len(), 2);
+        assert_eq!(details.layers[1].size, 3000);
+        assert_eq!(details.image_size, 6000);
+
+        let config =
+            r#"{"created": "2022-05-04T10:20:30Z", "os": "linux", "architecture": "arm64"}"#;
+        parse_config(&mut details, config.as_bytes());
+        assert_eq!(details.created.as_deref(), Some("2022-05-04T10:20:30Z"));
+        assert_eq!(details.os.as_deref(), Some("linux"));
+        assert_eq!(details.architecture.as_deref(), Some("arm64"));
+
+        // e.g. an artifact with an empty config
+        let mut details = ImageDetails::default();
+        parse_config(&mut details, b"{}");
+        assert_eq!(details.created, None);
+        assert!(parse_manifest(&mut details, b"not json").is_err());
+    }
+}
@@ -0,0 +1,49 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+/*
+ * Calling the Kubernetes API from inside the cluster, as the pod's service account.
+ */
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+#[derive(Clone, Debug)]
+pub struct InCluster {
+    // e.g. https://10.96.0.1:443
+    pub base_url: String,
+    pub namespace: String,
+    sa_dir: PathBuf,
+}
+
+impl InCluster {
+    pub fn new() -> Result<InCluster> {
+        let host = env::var("KUBERNETES_SERVICE_HOST")
+            .map_err(|_| anyhow!("Not running in Kubernetes, KUBERNETES_SERVICE_HOST isn't set"))?;
+        let port = env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+        let sa_dir = Path::new(SERVICE_ACCOUNT_DIR).to_path_buf();
+        let namespace = fs::read_to_string(sa_dir.join("namespace"))?
+            .trim()
+            .to_string();
+        Ok(InCluster {
+            base_url: format!("https://{}:{}", host, port),
+            namespace,
+            sa_dir,
+        })
+    }
+
+    pub fn ca(&self) -> Result<reqwest::Certificate> {
+        Ok(reqwest::Certificate::from_pem(&fs::read(
+            self.sa_dir.join("ca.crt"),
+        )?)?)
+    }
+
+    // Read on each use, as projected tokens are rotated by the kubelet
+    pub fn token(&self) -> Result<String> {
+        Ok(fs::read_to_string(self.sa_dir.join("token"))?
+            .trim()
+            .to_string())
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::kube::InCluster;
+use crate::spiffe::Permission;
+
+/*
+ * Authenticating pods and kubelets with Kubernetes ServiceAccount tokens, so they can pull
+ * without imagePullSecrets.
+ *
+ * Tokens are sent as a bearer token, or as the password with any user name, and checked with the
+ * TokenReview API using Trow's own service account (which needs the system:auth-delegator
+ * ClusterRole). If an audience is set, tokens must be issued for it, which is done with a
+ * projected token volume and stops tokens meant for other services being used with Trow.
+ *
+ * Service accounts are given permissions by rules of the form NAMESPACE/NAME=pull|push|delete,
+ * where either part can be *. The first matching rule applies.
+ *
+ * Reviews are cached for a minute, keyed by a hash of the token, to avoid a call to the API server
+ * for every request.
+ */
+
+const SA_PREFIX: &str = "system:serviceaccount:";
+const REVIEW_CACHE_TIME: Duration = Duration::from_secs(60);
+// Dropped when the cache gets this big, rather than tracking expiry of each entry
+const MAX_CACHED_REVIEWS: usize = 10_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceAccountRule {
+    namespace: String,
+    name: String,
+    permission: Permission,
+}
+
+impl ServiceAccountRule {
+    fn matches(&self, namespace: &str, name: &str) -> bool {
+        (self.namespace == "*" || self.namespace == namespace)
+            && (self.name == "*" || self.name == name)
+    }
+}
+
+// e.g. ci/deployer=push
+impl FromStr for ServiceAccountRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (account, permission) = s.rsplit_once('=').ok_or_else(|| {
+            anyhow!(
+                "Service account rule {} should be of the form NAMESPACE/NAME=pull|push|delete",
+                s
+            )
+        })?;
+        let (namespace, name) = account
+            .split_once('/')
+            .filter(|(ns, name)| !ns.is_empty() && !name.is_empty())
+            .ok_or_else(|| anyhow!("Service account {} should be NAMESPACE/NAME", account))?;
+        let permission = permission
+            .parse()
+            .map_err(|e| anyhow!("{} in service account rule {}", e, s))?;
+        Ok(ServiceAccountRule {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+            permission,
+        })
+    }
+}
+
+impl fmt::Display for ServiceAccountRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}={}", self.namespace, self.name, self.permission)
+    }
+}
+
+// The namespace and name from a user name like system:serviceaccount:ci:deployer
+fn service_account(username: &str) -> Option<(&str, &str)> {
+    username.strip_prefix(SA_PREFIX)?.split_once(':')
+}
+
+pub struct TokenReviewer {
+    cluster: InCluster,
+    client: reqwest::Client,
+    audience: Option<String>,
+    rules: Vec<ServiceAccountRule>,
+    // Reviewed user name, or None if the token was rejected
+    reviews: Mutex<HashMap<String, (Instant, Option<String>)>>,
+}
+
+impl fmt::Debug for TokenReviewer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TokenReviewer")
+            .field("audience", &self.audience)
+            .field("rules", &self.rules)
+            .finish()
+    }
+}
+
+impl TokenReviewer {
+    pub fn new(rules: Vec<ServiceAccountRule>, audience: Option<String>) -> Result<TokenReviewer> {
+        let cluster = InCluster::new()?;
+        let client = reqwest::Client::builder()
+            .add_root_certificate(cluster.ca()?)
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(TokenReviewer {
+            cluster,
+            client,
+            audience,
+            rules,
+            reviews: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn rules(&self) -> &[ServiceAccountRule] {
+        &self.rules
+    }
+
+    pub fn audience(&self) -> Option<&str> {
+        self.audience.as_deref()
+    }
+
+    fn permission(&self, namespace: &str, name: &str) -> Option<Permission> {
+        self.rules
+            .iter()
+            .find(|r| r.matches(namespace, name))
+            .map(|r| r.permission)
+    }
+
+    async fn review(&self, token: &str) -> Result<Option<String>> {
+        let mut spec = json!({ "token": token });
+        if let Some(ref audience) = self.audience {
+            spec["audiences"] = json!([audience]);
+        }
+        let review = json!({
+            "apiVersion": "authentication.k8s.io/v1",
+            "kind": "TokenReview",
+            "spec": spec,
+        });
+        let resp: Value = self
+            .client
+            .post(&format!(
+                "{}/apis/authentication.k8s.io/v1/tokenreviews",
+                self.cluster.base_url
+            ))
+            .bearer_auth(self.cluster.token()?)
+            .json(&review)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let status = &resp["status"];
+        if status["authenticated"].as_bool() != Some(true) {
+            return Ok(None);
+        }
+        Ok(status["user"]["username"].as_str().map(|u| u.to_string()))
+    }
+
+    /*
+     * Checks the token, returning the service account as k8s:namespace/name and its permission.
+     * Errors calling the API aren't cached, so a blip doesn't lock clients out for long.
+     */
+    pub async fn verify(&self, token: &str) -> Result<(String, Permission)> {
+        let key = hex::encode(Sha256::digest(token.as_bytes()));
+        let cached = self
+            .reviews
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|(at, _)| at.elapsed() < REVIEW_CACHE_TIME)
+            .map(|(_, user)| user.clone());
+        let username = match cached {
+            Some(user) => user,
+            None => {
+                let user = self.review(token).await.map_err(|e| {
+                    warn!("TokenReview failed: {}", e);
+                    e
+                })?;
+                let mut reviews = self.reviews.lock().unwrap();
+                if reviews.len() >= MAX_CACHED_REVIEWS {
+                    reviews.clear();
+                }
+                reviews.insert(key, (Instant::now(), user.clone()));
+                user
+            }
+        };
+
+        let username = username.ok_or_else(|| anyhow!("Token rejected by TokenReview"))?;
+        let (namespace, name) = service_account(&username)
+            .ok_or_else(|| anyhow!("{} isn't a service account", username))?;
+        let permission = self
+            .permission(namespace, name)
+            .ok_or_else(|| anyhow!("No rule for service account {}/{}", namespace, name))?;
+        Ok((format!("k8s:{}/{}", namespace, name), permission))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{service_account, ServiceAccountRule};
+    use crate::spiffe::Permission;
+
+    #[test]
+    fn parse_rules() {
+        let rule: ServiceAccountRule = "ci/deployer=push".parse().unwrap();
+        assert_eq!(rule.to_string(), "ci/deployer=push");
+        assert_eq!(rule.permission, Permission::Push);
+        assert!(rule.matches("ci", "deployer"));
+        assert!(!rule.matches("ci", "default"));
+
+        let rule: ServiceAccountRule = "*/*=pull".parse().unwrap();
+        assert!(rule.matches("anything", "default"));
+
+        assert!("ci=push".parse::<ServiceAccountRule>().is_err());
+        assert!("ci/=push".parse::<ServiceAccountRule>().is_err());
+        assert!("ci/deployer=admin".parse::<ServiceAccountRule>().is_err());
+    }
+
+    #[test]
+    fn service_account_names() {
+        assert_eq!(
+            service_account("system:serviceaccount:ci:deployer"),
+            Some(("ci", "deployer"))
+        );
+        assert_eq!(service_account("system:node:worker-1"), None);
+        assert_eq!(service_account("alice"), None);
+    }
+}
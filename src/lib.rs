@@ -1,39 +1,87 @@
-use futures::Future;
 use log::{LevelFilter, SetLoggerError};
 
 use rand::rngs::OsRng;
 use rocket::fairing;
 use std::env;
 use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use uuid::Uuid;
 
+mod active_transfers;
+mod admission_cache;
+mod audit;
+mod backend_discovery;
+mod blob_redirect;
+mod capabilities;
 mod client_interface;
+mod client_metrics;
+pub mod config_file;
+pub mod config_reload;
+pub mod cors;
+pub mod ctl;
 mod fairings;
+pub mod htpasswd;
+mod idempotency;
+mod image_details;
+mod kube;
+mod kube_auth;
+mod listen;
+mod manifest_cache;
+mod names;
+pub mod oidc;
+mod rate_limit;
 
 pub mod response;
 #[allow(clippy::too_many_arguments)]
 mod routes;
+mod schema2;
+mod setup;
 pub mod types;
 
 mod registry_interface;
+mod request_id;
+mod request_limits;
+pub mod spiffe;
+mod telemetry;
+mod tenants;
+mod tls;
+mod transfer;
 #[cfg(feature = "sqlite")]
 mod users;
 
-use chrono::Utc;
-use client_interface::ClientInterface;
+use blob_redirect::BlobRedirect;
+use chrono::{SecondsFormat, Utc};
+use client_interface::{ClientInterface, DEFAULT_UPLOAD_BUFFER_SIZE};
+use config_reload::ConfigReloader;
+use cors::CorsConfig;
 use fairings::conditional_fairing::AttachConditionalFairing;
+use htpasswd::Htpasswd;
+use kube_auth::TokenReviewer;
+use oidc::{OidcConfig, OidcVerifier};
 use rand::RngCore;
+use rate_limit::{RateLimitConfig, RateLimiter};
+use registry_interface::{PolicyRules, RegistryInterface};
+use request_limits::TransferLimits;
+use setup::Setup;
+use spiffe::SpiffeConfig;
 use std::io::Write;
+use telemetry::TracingConfig;
+use trow_server::spiffe::SvidSource;
+use trow_server::UNIX_SCHEME;
 
 use anyhow::{anyhow, Result};
-use log::debug;
-use rocket::http::Method;
-use rocket_cors::AllowedHeaders;
-use rocket_cors::AllowedOrigins;
+use log::{debug, info, warn};
 use thiserror::Error;
 
+// For the backend to finish its calls and save uploads on shutdown, once the frontend has stopped
+const BACKEND_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+// The backend's socket with --standalone, in a directory of its own
+const STANDALONE_SOCKET: &str = "backend.sock";
+
 //TODO: Make this take a cause or description
 #[derive(Error, Debug)]
 #[error("invalid data directory")]
@@ -41,6 +89,7 @@ pub struct ConfigError {}
 
 #[derive(Clone, Debug)]
 pub struct NetAddr {
+    // One or more hosts or IP addresses separated by commas, e.g. "::,0.0.0.0"
     pub host: String,
     pub port: u16,
 }
@@ -66,21 +115,172 @@ pub struct TrowConfig {
     dry_run: bool,
     max_manifest_size: u32,
     max_blob_size: u32,
+    // Most layers a pushed image can have, 0 for no limit
+    max_layers: usize,
+    blob_redirect: Option<BlobRedirect>,
+    // How long manifests are cached by the frontend, zero to not cache them
+    manifest_cache_ttl: Duration,
+    // How long admission decisions are cached by the frontend, zero to not cache them
+    admission_cache_ttl: Duration,
+    // Shared with the admin API, which can change the limits
+    rate_limits: Arc<RateLimiter>,
+    // How long requests in progress get to finish on shutdown
+    shutdown_timeout: Duration,
+    // Mebibytes, for bodies other than blobs and manifests
+    max_request_size: u32,
+    // Bytes of headers a request can have, 0 for no limit
+    max_header_size: usize,
+    transfer_limits: TransferLimits,
+    // Bytes of each upload held in memory on its way to the backend
+    upload_buffer_size: usize,
+    // Most idempotency keys kept in memory, see idempotency.rs
+    idempotency_keys: usize,
     token_secret: String,
     user: Option<UserConfig>,
+    htpasswd: Option<Arc<Htpasswd>>,
+    // Whether htpasswd users have to log in to pull
+    htpasswd_pull: bool,
+    // Users who can delete as well as push, replaced when the config file is reloaded
+    delete_users: Arc<RwLock<Vec<String>>>,
+    oidc: Option<Arc<OidcVerifier>>,
+    service_accounts: Option<Arc<TokenReviewer>>,
+    setup: Option<Arc<Setup>>,
     cors: bool,
+    cors_config: CorsConfig,
     log_level: String,
+    log_format: LogFormat,
+    watch_data_dir: bool,
+    web_ui: bool,
+    standalone: bool,
+    event_sinks: Vec<String>,
+    event_format: String,
+    quotas: Vec<String>,
+    retention: Vec<String>,
+    retention_interval: String,
+    immutable_tags: Vec<String>,
+    freeze_windows: Vec<String>,
+    // Also admit images according to TrowPolicy resources
+    policy_crd: bool,
+    // Deny images from this registry that haven't been pushed to it, even if allowed
+    require_existing_images: bool,
+    upstream_proxies: Vec<String>,
+    usage_interval: String,
+    // Uploads idle for longer than this are removed, "0" to keep them
+    upload_ttl: String,
+    // Deleted manifests can be restored for this long, "0" to delete them at once
+    trash_retention: String,
+    // Bucket the registry is kept in, e.g. "gs://my-bucket", with the data dir as a working copy
+    storage: Option<String>,
+    // Uploads in progress are written here instead of the data dir, e.g. a local SSD
+    scratch_dir: Option<String>,
+    // Space to keep free in storage, e.g. "10G" or "5%", refusing uploads below it
+    storage_reserve: Option<String>,
+    proxy_check_interval: String,
+    proxy_check_sample: usize,
+    mirror_workers: usize,
+    mirror_queue_size: usize,
+    backup_dir: Option<String>,
+    backup_interval: String,
+    scrub_interval: String,
+    scrub_quarantine: bool,
+    metadata_db: Option<String>,
+    // Pulls before a layer is transcoded, None to not transcode layers
+    transcode_min_pulls: Option<u64>,
+    transcode_interval: String,
+    // Transcode layers as soon as a client asks for the other compression
+    transcode_on_demand: bool,
+    // How often to convert pushed layers to eStargz, None to not convert them
+    estargz_interval: Option<String>,
+    ha: bool,
+    read_only: bool,
+    audit_log: Option<String>,
+    tracing: Option<TracingConfig>,
+    spiffe: Option<SpiffeConfig>,
+    config_reload: Option<Arc<ConfigReloader>>,
+}
+
+/*
+ * Whether the user logged in with a Trow password. Users from outside Trow are named with a
+ * prefix, e.g. oidc:<sub>, k8s:<namespace>/<name> or a spiffe:// ID, and local user names can't
+ * contain a colon, so the two can't be mistaken for each other.
+ */
+fn is_local_user(user: &str) -> bool {
+    !user.contains(':')
+}
+
+impl TrowConfig {
+    /*
+     * Pulls don't need a login when the only users are from an htpasswd file, unless it's set to
+     * cover pulls too.
+     */
+    fn allows_anonymous_pull(&self) -> bool {
+        self.htpasswd.is_some()
+            && !self.htpasswd_pull
+            && self.user.is_none()
+            && self.oidc.is_none()
+            && self.service_accounts.is_none()
+            && !matches!(self.spiffe, Some(ref s) if !s.rules.is_empty())
+    }
+
+    // Whether any way of logging in is configured, without which everyone can do anything
+    fn auth_configured(&self) -> bool {
+        self.user.is_some()
+            || self.htpasswd.is_some()
+            || self.oidc.is_some()
+            || self.service_accounts.is_some()
+            || matches!(self.spiffe, Some(ref s) if !s.rules.is_empty())
+    }
+
+    // The --user and admins from first run setup, who can also manage tenants and use their repos
+    fn is_admin(&self, user: &str) -> bool {
+        is_local_user(user)
+            && (matches!(self.user, Some(ref u) if u.user == user)
+                || matches!(self.setup, Some(ref s) if s.is_admin(user)))
+    }
+
+    /*
+     * What a logged in user can do. The --user and admins from first run setup can do anything,
+     * others can push but only delete if listed in --delete-users.
+     */
+    fn user_permission(&self, user: &str) -> spiffe::Permission {
+        let can_delete =
+            is_local_user(user) && self.delete_users.read().unwrap().iter().any(|u| u == user);
+        if self.is_admin(user) || can_delete {
+            spiffe::Permission::Delete
+        } else {
+            spiffe::Permission::Push
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 struct GrpcConfig {
+    // One or more HOST:PORT or unix:// addresses separated by commas
     listen: String,
+    // Permissions of the Unix sockets listened on
+    socket_mode: u32,
+    tls: Option<GrpcTlsConfig>,
+    require_tls: bool,
+    // Where to find the backends to call, if not just the one in this process
+    backend_address: Option<String>,
+}
+
+// PEM files for TLS between the frontend and backend, used by both sides
+#[derive(Clone, Debug)]
+struct GrpcTlsConfig {
+    ca_file: String,
+    cert_file: String,
+    key_file: String,
+    // Name the frontend expects in the backend's certificate
+    server_name: String,
 }
 
 #[derive(Clone, Debug)]
 struct TlsConfig {
     cert_file: String,
     key_file: String,
+    // Files from a mounted secret, which may not have been provisioned yet
+    from_secret: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -89,18 +289,34 @@ struct UserConfig {
     hash_encoded: String, //Surprised not bytes
 }
 
-fn init_trow_server(
-    config: TrowConfig,
-) -> Result<impl Future<Output = Result<(), tonic::transport::Error>>> {
+fn init_trow_server(config: TrowConfig) -> Result<trow_server::TrowServerBuilder> {
     debug!("Starting Trow server");
 
+    let spiffe_svid = matches!(config.spiffe, Some(SpiffeConfig { svid: Some(_), .. }));
+    if config.grpc.tls.is_some() && spiffe_svid {
+        return Err(anyhow!(
+            "Use either --grpc-tls-cert or --spiffe-svid for the backend channel, not both"
+        ));
+    }
+    if config.grpc.require_tls && !config.standalone && config.grpc.tls.is_none() && !spiffe_svid {
+        return Err(anyhow!(
+            "--grpc-require-tls needs --grpc-tls-cert or --spiffe-svid, or --standalone"
+        ));
+    }
+    if config.grpc.backend_address.is_some() && config.standalone {
+        return Err(anyhow!(
+            "--backend-address can't be used with --standalone, which has no backend listener"
+        ));
+    }
+
     //Could pass full config here.
     //Pros: less work, new args added automatically
     //-s: ties frontend to backend, some uneeded/unwanted vars
 
+    let listen_addrs = listen::grpc_addresses(&config.grpc.listen)?;
     let ts = trow_server::build_server(
         &config.data_dir,
-        config.grpc.listen.parse::<std::net::SocketAddr>()?,
+        listen_addrs,
         config.proxy_hub,
         config.hub_user,
         config.hub_pass,
@@ -109,31 +325,145 @@ fn init_trow_server(
         config.deny_prefixes,
         config.deny_images,
     );
-    //TODO: probably shouldn't be reusing this cert
-    let ts = if let Some(tls) = config.tls {
-        ts.add_tls(fs::read(tls.cert_file)?, fs::read(tls.key_file)?)
+    let ts = if let Some(ref tls) = config.grpc.tls {
+        ts.add_tls(fs::read(&tls.cert_file)?, fs::read(&tls.key_file)?)
+            .add_root_cert(fs::read(&tls.ca_file)?)
+    } else {
+        ts
+    };
+    let ts = if config.grpc.require_tls {
+        ts.require_client_cert()
+    } else {
+        ts
+    };
+    let ts = ts.add_socket_mode(config.grpc.socket_mode);
+    let ts = if config.policy_crd {
+        ts.watch_policies()
+    } else {
+        ts
+    };
+    let ts = if config.require_existing_images {
+        ts.require_existing_images()
     } else {
         ts
     };
+    let ts = if config.watch_data_dir {
+        ts.watch_data_dir()
+    } else {
+        ts
+    };
+    let ts = if config.read_only { ts.read_only() } else { ts };
+    let ts = ts.add_event_sinks(config.event_sinks, &config.event_format)?;
+    let ts = ts.add_quotas(config.quotas)?;
+    let ts = ts.add_retention(config.retention, &config.retention_interval)?;
+    let ts = ts.add_immutable_tags(config.immutable_tags)?;
+    let ts = ts.add_freeze_windows(config.freeze_windows)?;
+    let ts = ts.add_upstream_proxies(config.upstream_proxies)?;
+    let ts = ts.add_usage_interval(&config.usage_interval)?;
+    let ts = ts.add_upload_ttl(&config.upload_ttl)?;
+    let ts = ts.add_trash_retention(&config.trash_retention)?;
+    let ts = match &config.storage {
+        Some(url) => ts.add_storage(url),
+        None => ts,
+    };
+    let ts = match &config.scratch_dir {
+        Some(dir) => ts.add_scratch_dir(dir),
+        None => ts,
+    };
+    let ts = match &config.storage_reserve {
+        Some(reserve) => ts.add_storage_reserve(reserve)?,
+        None => ts,
+    };
+    let ts = ts.add_max_layers(config.max_layers);
+    let ts = ts.add_proxy_check(&config.proxy_check_interval, config.proxy_check_sample)?;
+    let ts = ts.add_admission_mirroring(config.mirror_workers, config.mirror_queue_size);
+    let ts = match &config.backup_dir {
+        Some(dir) => ts.add_backup(dir, &config.backup_interval)?,
+        None => ts,
+    };
+    let ts = ts.add_scrub(&config.scrub_interval, config.scrub_quarantine)?;
+    let ts = if let Some(db_path) = &config.metadata_db {
+        ts.add_metadata_db(db_path)
+    } else {
+        ts
+    };
+    let ts = match config.transcode_min_pulls {
+        Some(min_pulls) => ts.add_transcoding(
+            min_pulls,
+            &config.transcode_interval,
+            config.transcode_on_demand,
+        )?,
+        None => ts,
+    };
+    let ts = match &config.estargz_interval {
+        Some(interval) => ts.add_estargz(interval)?,
+        None => ts,
+    };
+    let ts = if config.ha {
+        ts
+    } else {
+        ts.lock_data_dir().map_err(|e| {
+            anyhow!(
+                "{}\n\nUse --ha if several Trow backends are meant to share the data directory.",
+                e
+            )
+        })?
+    };
+
+    Ok(ts)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    // One JSON object per line, for log aggregators
+    Json,
+}
 
-    Ok(ts.get_server_future())
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("Unknown log format {}, expected text or json", s)),
+        }
+    }
 }
 
 /// Build the logging agent with formatting.
-fn init_logger(log_level: String) -> Result<(), SetLoggerError> {
+fn init_logger(log_level: String, log_format: LogFormat) -> Result<(), SetLoggerError> {
     // If there env variable RUST_LOG is set, then take the configuration from it.
     // Otherwise create a default logger
     let mut builder = env_logger::Builder::new();
     builder
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "{} [{}] {} {}",
-                Utc::now().format("%Y-%m-%dT%H:%M:%S"),
-                record.target(),
-                record.level(),
-                record.args()
-            )
+        .format(move |buf, record| {
+            // Set while handling a registry request, in the frontend or backend
+            let request_id = trow_server::request_id::current();
+            match log_format {
+                LogFormat::Text => writeln!(
+                    buf,
+                    "{} [{}] {} {}{}",
+                    Utc::now().format("%Y-%m-%dT%H:%M:%S"),
+                    record.target(),
+                    record.level(),
+                    request_id
+                        .map(|id| format!("[{}] ", id))
+                        .unwrap_or_default(),
+                    record.args()
+                ),
+                LogFormat::Json => {
+                    let line = serde_json::json!({
+                        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                        "level": record.level().as_str(),
+                        "target": record.target(),
+                        "request_id": request_id,
+                        "message": record.args().to_string(),
+                    });
+                    writeln!(buf, "{}", line)
+                }
+            }
         })
         .filter(None, LevelFilter::from_str(&log_level).unwrap());
     builder.init();
@@ -166,7 +496,13 @@ impl TrowBuilder {
             data_dir,
             addr,
             tls: None,
-            grpc: GrpcConfig { listen },
+            grpc: GrpcConfig {
+                listen,
+                socket_mode: 0o600,
+                tls: None,
+                require_tls: false,
+                backend_address: None,
+            },
             host_names,
             proxy_hub,
             hub_user: None,
@@ -178,10 +514,67 @@ impl TrowBuilder {
             dry_run,
             max_manifest_size,
             max_blob_size,
+            max_layers: 0,
+            blob_redirect: None,
+            manifest_cache_ttl: Duration::from_secs(10),
+            admission_cache_ttl: Duration::from_secs(5),
+            rate_limits: Arc::new(RateLimiter::default()),
+            shutdown_timeout: Duration::from_secs(25),
+            max_request_size: 1,
+            max_header_size: 32 * 1024,
+            transfer_limits: TransferLimits::new(Duration::from_secs(60), Duration::ZERO),
+            upload_buffer_size: DEFAULT_UPLOAD_BUFFER_SIZE,
+            idempotency_keys: idempotency::DEFAULT_MAX_KEYS,
             token_secret: Uuid::new_v4().to_string(),
             user: None,
+            htpasswd: None,
+            htpasswd_pull: false,
+            delete_users: Arc::new(RwLock::new(vec![])),
+            oidc: None,
+            service_accounts: None,
+            setup: None,
             cors,
+            cors_config: CorsConfig::default(),
             log_level,
+            log_format: LogFormat::Text,
+            watch_data_dir: false,
+            web_ui: false,
+            standalone: false,
+            event_sinks: vec![],
+            event_format: "json".to_string(),
+            quotas: vec![],
+            retention: vec![],
+            retention_interval: "0".to_string(),
+            immutable_tags: vec![],
+            freeze_windows: vec![],
+            policy_crd: false,
+            require_existing_images: false,
+            upstream_proxies: vec![],
+            usage_interval: "0".to_string(),
+            upload_ttl: "24h".to_string(),
+            trash_retention: "0".to_string(),
+            storage: None,
+            scratch_dir: None,
+            storage_reserve: None,
+            proxy_check_interval: "0".to_string(),
+            proxy_check_sample: 20,
+            mirror_workers: 0,
+            mirror_queue_size: 1000,
+            backup_dir: None,
+            backup_interval: "0".to_string(),
+            scrub_interval: "0".to_string(),
+            scrub_quarantine: false,
+            metadata_db: None,
+            transcode_min_pulls: None,
+            transcode_interval: "1h".to_string(),
+            transcode_on_demand: false,
+            estargz_interval: None,
+            ha: false,
+            read_only: false,
+            audit_log: None,
+            tracing: None,
+            spiffe: None,
+            config_reload: None,
         };
         TrowBuilder { config }
     }
@@ -190,11 +583,78 @@ impl TrowBuilder {
         let cfg = TlsConfig {
             cert_file,
             key_file,
+            from_secret: false,
         };
         self.config.tls = Some(cfg);
         self
     }
 
+    /*
+     * Use TLS between the frontend and backend. Both present the certificate, which must be
+     * issued by the CA for server_name (localhost by default) and allowed for client auth.
+     */
+    pub fn with_grpc_tls(
+        &mut self,
+        ca_file: String,
+        cert_file: String,
+        key_file: String,
+        server_name: Option<String>,
+    ) -> &mut TrowBuilder {
+        self.config.grpc.tls = Some(GrpcTlsConfig {
+            ca_file,
+            cert_file,
+            key_file,
+            server_name: server_name.unwrap_or_else(|| "localhost".to_string()),
+        });
+        self
+    }
+
+    /// Refuse backend clients without a certificate, and starting without gRPC TLS
+    pub fn with_grpc_tls_required(&mut self) -> &mut TrowBuilder {
+        self.config.grpc.require_tls = true;
+        self
+    }
+
+    /*
+     * Where the backend listens for gRPC, e.g. "0.0.0.0:51000" for other frontends to reach it.
+     * Takes several HOST:PORT addresses separated by commas, such as "[::]:51000,0.0.0.0:51000",
+     * and names, which are listened on at each address they resolve to. Addresses such as
+     * "unix:///var/run/trow.sock" are Unix sockets, which the frontend uses if there is one.
+     */
+    pub fn with_grpc_listen(&mut self, listen: String) -> &mut TrowBuilder {
+        self.config.grpc.listen = listen;
+        self
+    }
+
+    /// Permissions of the backend's Unix sockets in octal, e.g. "660" for the group to connect
+    pub fn with_grpc_socket_mode(&mut self, mode: &str) -> Result<&mut TrowBuilder> {
+        self.config.grpc.socket_mode = u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|m| *m <= 0o777)
+            .ok_or_else(|| anyhow!("Invalid socket mode {}, expected e.g. 660", mode))?;
+        Ok(self)
+    }
+
+    /*
+     * Call the backends address resolves to, e.g. a headless Kubernetes Service with a record for
+     * each backend, balancing calls across them (see backend_discovery.rs).
+     */
+    pub fn with_backend_address(&mut self, address: String) -> &mut TrowBuilder {
+        self.config.grpc.backend_address = Some(address);
+        self
+    }
+
+    /// Uses the tls.crt and tls.key from a mounted Kubernetes TLS secret, e.g. from cert-manager
+    pub fn with_tls_secret(&mut self, dir: &str) -> &mut TrowBuilder {
+        let (cert_file, key_file) = tls::secret_paths(dir);
+        self.config.tls = Some(TlsConfig {
+            cert_file,
+            key_file,
+            from_secret: true,
+        });
+        self
+    }
+
     pub fn with_user(&mut self, user: String, pass: String) -> &mut TrowBuilder {
         let hash_config = argon2::Config::default();
         let hash_encoded =
@@ -205,12 +665,426 @@ impl TrowBuilder {
         self
     }
 
+    /*
+     * Accept the users in an htpasswd file with bcrypt hashes. They're needed to push, and to
+     * pull if protect_pull is set.
+     */
+    pub fn with_htpasswd(&mut self, path: &str, protect_pull: bool) -> Result<&mut TrowBuilder> {
+        self.config.htpasswd = Some(Arc::new(Htpasswd::load(path)?));
+        self.config.htpasswd_pull = protect_pull;
+        Ok(self)
+    }
+
+    /*
+     * Create a bootstrap admin in the htpasswd file if it has no users and setup hasn't been
+     * done, with its password printed or written to the named Kubernetes Secret. Call before
+     * with_htpasswd, so the new user is loaded.
+     */
+    pub fn with_first_run_setup(
+        &mut self,
+        htpasswd_path: &str,
+        secret: Option<String>,
+    ) -> Result<&mut TrowBuilder> {
+        let setup = Setup::bootstrap(&self.config.data_dir, htpasswd_path, secret.as_deref())?;
+        self.config.setup = Some(Arc::new(setup));
+        Ok(self)
+    }
+
+    /// Users allowed to delete manifests, blobs and repositories
+    pub fn with_delete_users(&mut self, users: Vec<String>) -> &mut TrowBuilder {
+        *self.config.delete_users.write().unwrap() = users;
+        self
+    }
+
+    /*
+     * Reload the rules that can be changed without a restart when the config file changes,
+     * keeping those given by the pinned flags.
+     */
+    pub fn with_config_reload(
+        &mut self,
+        path: &str,
+        pinned: Vec<String>,
+    ) -> Result<&mut TrowBuilder> {
+        let reloader = ConfigReloader::new(path, pinned, self.config.delete_users.clone())?;
+        self.config.config_reload = Some(Arc::new(reloader));
+        Ok(self)
+    }
+
+    /*
+     * Accept ID tokens from an OpenID Connect provider, with users' groups mapped to permissions
+     * by rules of the form GROUP=pull|push|delete.
+     */
+    pub fn with_oidc(
+        &mut self,
+        issuer: String,
+        audience: String,
+        groups_claim: String,
+        roles: Vec<String>,
+    ) -> Result<&mut TrowBuilder> {
+        let roles = roles
+            .iter()
+            .map(|r| r.parse())
+            .collect::<Result<Vec<oidc::RoleRule>>>()?;
+        let config = OidcConfig {
+            issuer,
+            audience,
+            groups_claim,
+            roles,
+        };
+        self.config.oidc = Some(Arc::new(OidcVerifier::new(config)?));
+        Ok(self)
+    }
+
+    /*
+     * Accept Kubernetes ServiceAccount tokens, checked with the TokenReview API, with service
+     * accounts given permissions by rules of the form NAMESPACE/NAME=pull|push|delete. If an
+     * audience is given, tokens must be issued for it.
+     */
+    pub fn with_service_account_auth(
+        &mut self,
+        rules: Vec<String>,
+        audience: Option<String>,
+    ) -> Result<&mut TrowBuilder> {
+        let rules = rules
+            .iter()
+            .map(|r| r.parse())
+            .collect::<Result<Vec<kube_auth::ServiceAccountRule>>>()?;
+        self.config.service_accounts = Some(Arc::new(TokenReviewer::new(rules, audience)?));
+        Ok(self)
+    }
+
+    /*
+     * Redirect blob downloads to a server with the blobs directory under base_url, with URLs
+     * signed by the secret in secret_file that expire after expiry, e.g. "5m".
+     */
+    pub fn with_blob_redirect(
+        &mut self,
+        base_url: &str,
+        secret_file: &str,
+        expiry: &str,
+    ) -> Result<&mut TrowBuilder> {
+        let secret = fs::read_to_string(secret_file)
+            .map_err(|e| anyhow!("Failed to read {}: {}", secret_file, e))?;
+        let expiry = trow_server::parse_duration(expiry)?;
+        self.config.blob_redirect = Some(BlobRedirect::new(
+            base_url,
+            secret.trim().to_string(),
+            expiry,
+        )?);
+        Ok(self)
+    }
+
+    /// Back up to the directory every interval, e.g. "24h", copying only blobs it doesn't have
+    pub fn with_backup(&mut self, dir: String, interval: String) -> &mut TrowBuilder {
+        self.config.backup_dir = Some(dir);
+        self.config.backup_interval = interval;
+        self
+    }
+
+    /// Check the stored blobs and tags every interval, e.g. "7d", quarantining corrupt blobs if set
+    pub fn with_scrub(&mut self, interval: String, quarantine: bool) -> &mut TrowBuilder {
+        self.config.scrub_interval = interval;
+        self.config.scrub_quarantine = quarantine;
+        self
+    }
+
+    /*
+     * Limit each client to requests_per_sec and concurrent_uploads blob uploads at once, or
+     * 0 for no limit, with limits for particular clients as CLIENT=REQUESTS_PER_SEC/UPLOADS.
+     */
+    pub fn with_rate_limits(
+        &mut self,
+        requests_per_sec: u32,
+        concurrent_uploads: u32,
+        clients: Vec<String>,
+    ) -> Result<&mut TrowBuilder> {
+        let mut config = RateLimitConfig {
+            default: rate_limit::Limits {
+                requests_per_sec,
+                concurrent_uploads,
+            },
+            ..Default::default()
+        };
+        for client in clients {
+            let (name, limits) = rate_limit::parse_client_limits(&client)?;
+            config.clients.insert(name, limits);
+        }
+        self.config.rate_limits = Arc::new(RateLimiter::new(config));
+        Ok(self)
+    }
+
+    /// How long uploads and downloads in progress get to finish on SIGTERM, e.g. "60s"
+    pub fn with_shutdown_timeout(&mut self, timeout: &str) -> Result<&mut TrowBuilder> {
+        self.config.shutdown_timeout = trow_server::parse_duration(timeout)?;
+        Ok(self)
+    }
+
+    /*
+     * Limit bodies other than blobs and manifests to max_request_size mebibytes and headers to
+     * max_header_size kibibytes, and cut off transfers that send nothing for idle_timeout or take
+     * longer than transfer_timeout, e.g. "60s". Sizes and timeouts of 0 are no limit.
+     */
+    pub fn with_request_limits(
+        &mut self,
+        max_request_size: u32,
+        max_header_size: u32,
+        idle_timeout: &str,
+        transfer_timeout: &str,
+    ) -> Result<&mut TrowBuilder> {
+        self.config.max_request_size = max_request_size;
+        self.config.max_header_size = max_header_size as usize * 1024;
+        self.config.transfer_limits = TransferLimits::new(
+            trow_server::parse_duration(idle_timeout)?,
+            trow_server::parse_duration(transfer_timeout)?,
+        );
+        Ok(self)
+    }
+
+    /// Buffer uploads in pieces of up to size kibibytes on their way to the backend
+    pub fn with_upload_buffer_size(&mut self, size: u32) -> &mut TrowBuilder {
+        self.config.upload_buffer_size = size as usize * 1024;
+        self
+    }
+
+    /// How many idempotency keys to keep, dropping the least recently used ones past that
+    pub fn with_idempotency_keys(&mut self, max_keys: usize) -> &mut TrowBuilder {
+        self.config.idempotency_keys = max_keys;
+        self
+    }
+
+    /// How long to cache manifests and tags in memory, e.g. "30s", or "0" to not cache them
+    pub fn with_manifest_cache_ttl(&mut self, ttl: &str) -> Result<&mut TrowBuilder> {
+        self.config.manifest_cache_ttl = trow_server::parse_duration(ttl)?;
+        Ok(self)
+    }
+
+    /// How long to cache admission decisions in memory, e.g. "5s", or "0" to not cache them
+    pub fn with_admission_cache_ttl(&mut self, ttl: &str) -> Result<&mut TrowBuilder> {
+        self.config.admission_cache_ttl = trow_server::parse_duration(ttl)?;
+        Ok(self)
+    }
+
+    /// How often to compare a sample of proxied tags with upstream, e.g. "6h", and how many
+    pub fn with_proxy_check(&mut self, interval: String, sample_size: usize) -> &mut TrowBuilder {
+        self.config.proxy_check_interval = interval;
+        self.config.proxy_check_sample = sample_size;
+        self
+    }
+
+    /// Reject pushed images with more than max_layers layers, or 0 for no limit
+    pub fn with_max_layers(&mut self, max_layers: usize) -> &mut TrowBuilder {
+        self.config.max_layers = max_layers;
+        self
+    }
+
+    /// Mirror Docker Hub images of admitted pods into the cache, with this many fetches at once
+    pub fn with_admission_mirroring(
+        &mut self,
+        workers: usize,
+        queue_size: usize,
+    ) -> &mut TrowBuilder {
+        self.config.mirror_workers = workers;
+        self.config.mirror_queue_size = queue_size;
+        self
+    }
+
     pub fn with_hub_auth(&mut self, hub_user: String, token: String) -> &mut TrowBuilder {
         self.config.hub_pass = Some(token);
         self.config.hub_user = Some(hub_user);
         self
     }
 
+    pub fn with_data_dir_watch(&mut self) -> &mut TrowBuilder {
+        self.config.watch_data_dir = true;
+        self
+    }
+
+    /// Serve the web UI at /ui
+    pub fn with_web_ui(&mut self) -> &mut TrowBuilder {
+        self.config.web_ui = true;
+        self
+    }
+
+    pub fn with_event_sinks(&mut self, sinks: Vec<String>, format: String) -> &mut TrowBuilder {
+        self.config.event_sinks = sinks;
+        self.config.event_format = format;
+        self
+    }
+
+    pub fn with_quotas(&mut self, quotas: Vec<String>) -> &mut TrowBuilder {
+        self.config.quotas = quotas;
+        self
+    }
+
+    pub fn with_immutable_tags(&mut self, selectors: Vec<String>) -> &mut TrowBuilder {
+        self.config.immutable_tags = selectors;
+        self
+    }
+
+    pub fn with_freeze_windows(&mut self, windows: Vec<String>) -> &mut TrowBuilder {
+        self.config.freeze_windows = windows;
+        self
+    }
+
+    /// Watch TrowPolicy resources in the cluster for more admission rules
+    pub fn with_policy_crd(&mut self) -> &mut TrowBuilder {
+        self.config.policy_crd = true;
+        self
+    }
+
+    /// Deny images from this registry that haven't been pushed, whatever the allow lists say
+    pub fn with_existing_images_required(&mut self) -> &mut TrowBuilder {
+        self.config.require_existing_images = true;
+        self
+    }
+
+    pub fn with_upstream_proxies(&mut self, rules: Vec<String>) -> &mut TrowBuilder {
+        self.config.upstream_proxies = rules;
+        self
+    }
+
+    pub fn with_metadata_db(&mut self, db_path: String) -> &mut TrowBuilder {
+        self.config.metadata_db = Some(db_path);
+        self
+    }
+
+    /*
+     * Keep gzip and zstd copies of layers pulled at least min_pulls times, made every interval,
+     * and also as soon as a client asks for them if on_demand
+     */
+    pub fn with_transcoding(
+        &mut self,
+        min_pulls: u64,
+        interval: String,
+        on_demand: bool,
+    ) -> &mut TrowBuilder {
+        self.config.transcode_min_pulls = Some(min_pulls);
+        self.config.transcode_interval = interval;
+        self.config.transcode_on_demand = on_demand;
+        self
+    }
+
+    /// Convert pushed layers to eStargz every interval, for clients that pull lazily
+    pub fn with_estargz(&mut self, interval: String) -> &mut TrowBuilder {
+        self.config.estargz_interval = Some(interval);
+        self
+    }
+
+    /// Allow Cross-Origin Resource Sharing(CORS) requests from the given origins
+    pub fn with_cors(&mut self, config: CorsConfig) -> &mut TrowBuilder {
+        self.config.cors = true;
+        self.config.cors_config = config;
+        self
+    }
+
+    /// Log as "text" or "json"
+    pub fn with_log_format(&mut self, format: &str) -> Result<&mut TrowBuilder> {
+        self.config.log_format = format.parse()?;
+        Ok(self)
+    }
+
+    pub fn with_retention(&mut self, rules: Vec<String>, interval: String) -> &mut TrowBuilder {
+        self.config.retention = rules;
+        self.config.retention_interval = interval;
+        self
+    }
+
+    /// How often to record which images are running in the cluster, e.g. "1h"
+    pub fn with_usage_interval(&mut self, interval: String) -> &mut TrowBuilder {
+        self.config.usage_interval = interval;
+        self
+    }
+
+    /// How long an upload can go without any data before it's removed, e.g. "24h"
+    pub fn with_upload_ttl(&mut self, ttl: String) -> &mut TrowBuilder {
+        self.config.upload_ttl = ttl;
+        self
+    }
+
+    /// How long deleted manifests can be restored for, e.g. "7d"
+    pub fn with_trash_retention(&mut self, window: String) -> &mut TrowBuilder {
+        self.config.trash_retention = window;
+        self
+    }
+
+    /// Keep the registry in a bucket, e.g. "azure://account/container" or "gs://bucket"
+    pub fn with_storage(&mut self, url: String) -> &mut TrowBuilder {
+        self.config.storage = Some(url);
+        self
+    }
+
+    /// Write uploads in progress to the directory instead of the data dir
+    pub fn with_scratch_dir(&mut self, dir: String) -> &mut TrowBuilder {
+        self.config.scratch_dir = Some(dir);
+        self
+    }
+
+    /// Refuse new uploads while storage has less than reserve free, e.g. "10G" or "5%"
+    pub fn with_storage_reserve(&mut self, reserve: String) -> &mut TrowBuilder {
+        self.config.storage_reserve = Some(reserve);
+        self
+    }
+
+    /*
+     * Accept SPIFFE SVIDs issued by the CAs in the bundle from registry clients, authorised by
+     * the rules. If an SVID for Trow itself is given, it's used for mutual TLS between the
+     * frontend and backend.
+     */
+    pub fn with_spiffe(
+        &mut self,
+        bundle: String,
+        rules: Vec<String>,
+        svid: Option<(String, String)>,
+    ) -> Result<&mut TrowBuilder> {
+        let rules = rules
+            .iter()
+            .map(|r| r.parse())
+            .collect::<Result<Vec<spiffe::SpiffeRule>>>()?;
+        self.config.spiffe = Some(SpiffeConfig {
+            bundle,
+            rules,
+            svid,
+        });
+        Ok(self)
+    }
+
+    /*
+     * Export OpenTelemetry spans with the given exporter, "otlp" or "jaeger", to the endpoint or
+     * the exporter's default on localhost.
+     */
+    pub fn with_tracing(
+        &mut self,
+        exporter: &str,
+        endpoint: Option<String>,
+    ) -> Result<&mut TrowBuilder> {
+        self.config.tracing = Some(TracingConfig::new(exporter, endpoint)?);
+        Ok(self)
+    }
+
+    /// Write audit records to the file at path, or stdout for "-"
+    pub fn with_audit_log(&mut self, path: String) -> &mut TrowBuilder {
+        self.config.audit_log = Some(path);
+        self
+    }
+
+    /// Let several backends share the data directory instead of locking it
+    pub fn with_ha(&mut self) -> &mut TrowBuilder {
+        self.config.ha = true;
+        self
+    }
+
+    /// Start in read-only maintenance mode, which admins can switch off through the admin API
+    pub fn with_read_only(&mut self) -> &mut TrowBuilder {
+        self.config.read_only = true;
+        self
+    }
+
+    /// Run the backend in this process without a gRPC listener
+    pub fn with_standalone_backend(&mut self) -> &mut TrowBuilder {
+        self.config.standalone = true;
+        self
+    }
+
     fn build_rocket_config(&self) -> Result<rocket::config::Config> {
         // When run in production, Rocket wants a secret key for private cookies.
         // As we don't use private cookies, we just generate it here.
@@ -220,34 +1094,102 @@ impl TrowBuilder {
 
         //TODO: with Rocket 0.5 should be able to pass our config file and let Rocket pick out the parts it wants
         //This will be simpler and allow more flexibility.
-        let mut figment = rocket::Config::figment()
-            .merge(("address", self.config.addr.host.clone()))
+        let figment = rocket::Config::figment()
             .merge(("port", self.config.addr.port))
             .merge(("workers", 256))
-            .merge(("secret_key", secret_key));
+            .merge(("secret_key", secret_key))
+            .merge((
+                "limits",
+                request_limits::body_limits(self.config.max_request_size),
+            ))
+            .merge((
+                "shutdown",
+                // New connections are refused straight away, then requests get the grace period
+                rocket::config::Shutdown {
+                    grace: self.config.shutdown_timeout.as_secs() as u32,
+                    ..Default::default()
+                },
+            ));
 
         if let Some(ref tls) = self.config.tls {
             if !(Path::new(&tls.cert_file).is_file() && Path::new(&tls.key_file).is_file()) {
                 return  Err(anyhow!("Trow requires a TLS certificate and key, but failed to find them. \nExpected to find TLS certificate at {} and key at {}", tls.cert_file, tls.key_file));
             }
-
-            let tls_config =
-                rocket::config::TlsConfig::from_paths(tls.cert_file.clone(), tls.key_file.clone());
-            figment = figment.merge(("tls", tls_config));
+        } else if matches!(self.config.spiffe, Some(ref s) if !s.rules.is_empty()) {
+            return Err(anyhow!(
+                "Authorising clients by SPIFFE ID needs TLS, remove --no-tls"
+            ));
         }
         let cfg = rocket::Config::from(figment);
         Ok(cfg)
     }
 
+    /*
+     * Upgrades the data directory to the layout this version of Trow uses instead of starting,
+     * or only checks it if verify_only (see layout.rs in trow-server). Fails if it doesn't verify.
+     */
+    pub fn migrate(&self, verify_only: bool) -> Result<()> {
+        init_logger(self.config.log_level.clone(), self.config.log_format)?;
+        let config = &self.config;
+        let ts = trow_server::build_server(
+            &config.data_dir,
+            vec![],
+            false,
+            None,
+            None,
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+        )
+        .add_upstream_proxies(config.upstream_proxies.clone())?;
+        let ts = match &config.storage {
+            Some(url) => ts.add_storage(url),
+            None => ts,
+        };
+        // Not while a backend is using it
+        let ts = if config.ha { ts } else { ts.lock_data_dir()? };
+
+        let report = ts.migrate(verify_only)?;
+        if report.from == report.to {
+            println!("{} has layout {}", config.data_dir, report.to);
+        } else {
+            println!(
+                "Migrated {} from layout {} to {}",
+                config.data_dir, report.from, report.to
+            );
+        }
+        if !report.problems.is_empty() {
+            for problem in &report.problems {
+                eprintln!("{}", problem);
+            }
+            return Err(anyhow!(
+                "{} problems found in {}",
+                report.problems.len(),
+                config.data_dir
+            ));
+        }
+        Ok(())
+    }
+
     pub fn start(&self) -> Result<()> {
-        init_logger(self.config.log_level.clone())?;
+        init_logger(self.config.log_level.clone(), self.config.log_format)?;
 
+        if let Some(ref tls) = self.config.tls {
+            if tls.from_secret && !self.config.dry_run {
+                tls::wait_for(&tls.cert_file, &tls.key_file);
+            }
+        }
         let rocket_config = &self.build_rocket_config()?;
+        let http_addrs = listen::http_addresses(&self.config.addr.host, self.config.addr.port)?;
         println!(
-            "Starting Trow {} on {}:{}",
+            "Starting Trow {} on {}",
             env!("CARGO_PKG_VERSION"),
-            self.config.addr.host,
-            self.config.addr.port
+            http_addrs
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
         );
         println!(
             "\nMaximum blob size: {} Mebibytes",
@@ -257,6 +1199,59 @@ impl TrowBuilder {
             "Maximum manifest size: {} Mebibytes",
             self.config.max_manifest_size
         );
+        if self.config.max_layers > 0 {
+            println!("Maximum layers per image: {}", self.config.max_layers);
+        }
+        if let Some(ref redirect) = self.config.blob_redirect {
+            println!(
+                "Redirecting blob downloads to {}, valid for {}s",
+                redirect.base(),
+                redirect.expiry().as_secs()
+            );
+        }
+        if !self.config.manifest_cache_ttl.is_zero() {
+            println!(
+                "Caching manifests for up to {}s",
+                self.config.manifest_cache_ttl.as_secs()
+            );
+        }
+        if !self.config.admission_cache_ttl.is_zero() {
+            println!(
+                "Caching admission decisions for up to {}s",
+                self.config.admission_cache_ttl.as_secs()
+            );
+        }
+        println!(
+            "Maximum request size for other bodies: {} Mebibytes",
+            self.config.max_request_size
+        );
+        println!(
+            "Upload buffer size: {} Kibibytes",
+            self.config.upload_buffer_size / 1024
+        );
+        if self.config.max_header_size > 0 {
+            println!("Maximum header size: {} bytes", self.config.max_header_size);
+        }
+        let transfer = &self.config.transfer_limits;
+        if let Some(timeout) = transfer.idle_timeout {
+            println!(
+                "Cutting off uploads and downloads idle for {}s",
+                timeout.as_secs()
+            );
+        }
+        if let Some(timeout) = transfer.transfer_timeout {
+            println!(
+                "Cutting off uploads and downloads taking longer than {}s",
+                timeout.as_secs()
+            );
+        }
+        let limits = self.config.rate_limits.config();
+        if limits != RateLimitConfig::default() {
+            println!(
+                "Rate limits per client: {} requests/s and {} uploads at once (0 is no limit), for particular clients: {:?}",
+                limits.default.requests_per_sec, limits.default.concurrent_uploads, limits.clients
+            );
+        }
 
         println!("\n**Validation callback configuration\n");
 
@@ -285,34 +1280,526 @@ impl TrowBuilder {
 
         if self.config.proxy_hub {
             println!("  Docker Hub repostories are being proxy-cached under f/docker/\n");
+            if self.config.proxy_check_interval != "0" {
+                println!(
+                    "  Comparing {} cached tags with upstream every {}\n",
+                    self.config.proxy_check_sample, self.config.proxy_check_interval
+                );
+            }
+            if self.config.mirror_workers > 0 {
+                println!(
+                    "  Mirroring images of admitted pods with {} workers, queueing up to {}\n",
+                    self.config.mirror_workers, self.config.mirror_queue_size
+                );
+            }
         }
 
         if self.config.cors {
-            println!("  Cross-Origin Resource Sharing(CORS) requests are allowed\n");
+            let origins = if self.config.cors_config.origins.is_empty() {
+                "any origin".to_string()
+            } else {
+                self.config.cors_config.origins.join(", ")
+            };
+            println!(
+                "  Cross-Origin Resource Sharing(CORS) requests are allowed from {}\n",
+                origins
+            );
+        }
+
+        if self.config.watch_data_dir {
+            println!(
+                "Watching {} for external changes to repositories and tags\n",
+                self.config.data_dir
+            );
+        }
+
+        if self.config.web_ui {
+            println!("Serving the web UI at /ui\n");
+        }
+
+        if self.config.read_only {
+            println!("Starting read-only, uploads, manifest writes and deletes are refused\n");
+        }
+
+        if let Some(ref db_path) = self.config.metadata_db {
+            println!("Keeping tag and manifest metadata in {}\n", db_path);
+        }
+
+        if let Some(min_pulls) = self.config.transcode_min_pulls {
+            println!(
+                "Transcoding layers pulled at least {} times between gzip and zstd every {}\n",
+                min_pulls, self.config.transcode_interval
+            );
+            if self.config.transcode_on_demand {
+                println!("Transcoding layers as soon as a client asks for them\n");
+            }
         }
 
+        if let Some(interval) = &self.config.estargz_interval {
+            println!("Converting pushed layers to eStargz every {}\n", interval);
+        }
+
+        if !self.config.event_sinks.is_empty() {
+            println!(
+                "Publishing registry events as {} to: {:?}\n",
+                self.config.event_format, self.config.event_sinks
+            );
+        }
+
+        if !self.config.quotas.is_empty() {
+            println!("Storage quotas: {:?}\n", self.config.quotas);
+        }
+        if !self.config.immutable_tags.is_empty() {
+            println!("Immutable tags: {:?}\n", self.config.immutable_tags);
+        }
+        if !self.config.freeze_windows.is_empty() {
+            println!(
+                "Change freezes (UTC), only admitting running images: {:?}\n",
+                self.config.freeze_windows
+            );
+        }
+        if self.config.policy_crd {
+            println!("Admitting images according to TrowPolicy resources as well\n");
+        }
+        if self.config.require_existing_images {
+            println!("Denying images from this registry that haven't been pushed to it\n");
+        }
+        if !self.config.upstream_proxies.is_empty() {
+            println!(
+                "Proxies for upstream hosts: {:?}\n",
+                self.config.upstream_proxies
+            );
+        }
+        if !self.config.retention.is_empty() || self.config.retention_interval != "0" {
+            println!(
+                "Retention rules applied every {}: {:?}\n",
+                self.config.retention_interval, self.config.retention
+            );
+        }
+        if let Some(ref dir) = self.config.backup_dir {
+            if self.config.backup_interval == "0" {
+                println!("Backing up to {} when a backup job is started\n", dir);
+            } else {
+                println!(
+                    "Backing up to {} every {}\n",
+                    dir, self.config.backup_interval
+                );
+            }
+        }
+        if self.config.scrub_interval != "0" {
+            println!(
+                "Checking stored blobs against their digests every {}{}\n",
+                self.config.scrub_interval,
+                if self.config.scrub_quarantine {
+                    ", quarantining corrupt ones"
+                } else {
+                    ""
+                }
+            );
+        }
+        if let Some(url) = &self.config.storage {
+            println!("Keeping the registry in {}\n", url);
+        }
+        if let Some(dir) = &self.config.scratch_dir {
+            println!("Writing uploads in progress to {}\n", dir);
+        }
+        if let Some(reserve) = &self.config.storage_reserve {
+            println!(
+                "Refusing uploads while less than {} of storage is free\n",
+                reserve
+            );
+        }
+        if self.config.trash_retention != "0" {
+            println!(
+                "Deleted manifests can be restored for {}\n",
+                self.config.trash_retention
+            );
+        }
+        if self.config.upload_ttl != "0" {
+            println!(
+                "Removing uploads idle for more than {}\n",
+                self.config.upload_ttl
+            );
+        }
+        if self.config.usage_interval != "0" {
+            println!(
+                "Recording images running in the cluster every {}\n",
+                self.config.usage_interval
+            );
+        }
+
+        if let Some(ref spiffe) = self.config.spiffe {
+            println!(
+                "Accepting SPIFFE SVIDs signed by {} with rules: {:?}",
+                spiffe.bundle,
+                spiffe
+                    .rules
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect::<Vec<_>>()
+            );
+            if let Some((ref cert, _)) = spiffe.svid {
+                println!("  Using SVID {} between frontend and backend", cert);
+            }
+            println!();
+        }
+
+        if let Some(ref tls) = self.config.tls {
+            println!(
+                "Serving TLS with certificate {} and key {}, reloaded when they change\n",
+                tls.cert_file, tls.key_file
+            );
+        }
+
+        if let Some(ref tls) = self.config.grpc.tls {
+            println!(
+                "Using TLS between frontend and backend with certificate {} issued by {}",
+                tls.cert_file, tls.ca_file
+            );
+            if self.config.grpc.require_tls {
+                println!("  Backend clients must present a certificate");
+            }
+            println!();
+        }
+
+        if let Some(ref htpasswd) = self.config.htpasswd {
+            println!(
+                "Users from {} must log in to push{}\n",
+                htpasswd.path().display(),
+                if self.config.allows_anonymous_pull() {
+                    ", pulls are anonymous"
+                } else {
+                    " and pull"
+                }
+            );
+        }
+
+        {
+            let delete_users = self.config.delete_users.read().unwrap();
+            if !delete_users.is_empty() {
+                println!("Users allowed to delete: {:?}\n", delete_users);
+            }
+        }
+
+        if let Some(ref reviewer) = self.config.service_accounts {
+            match reviewer.audience() {
+                Some(audience) => println!(
+                    "Accepting ServiceAccount tokens issued for {}, with rules:",
+                    audience
+                ),
+                None => println!("Accepting ServiceAccount tokens, with rules:"),
+            }
+            for rule in reviewer.rules() {
+                println!("  {}", rule);
+            }
+            println!();
+        }
+
+        if let Some(ref setup) = self.config.setup {
+            if setup.is_complete() {
+                println!("First run setup has been completed\n");
+            } else {
+                println!("First run setup is pending, see GET /trow/v1/setup\n");
+            }
+        }
+
+        if let Some(ref oidc) = self.config.oidc {
+            let oidc = oidc.config();
+            println!(
+                "Accepting tokens from OIDC provider {} for {}",
+                oidc.issuer, oidc.audience
+            );
+            for role in &oidc.roles {
+                println!("  {}", role);
+            }
+            println!();
+        }
+
+        if let Some(ref path) = self.config.audit_log {
+            println!("Writing audit records to {}\n", path);
+        }
+
+        if let Some(ref tracing) = self.config.tracing {
+            println!(
+                "Exporting traces with {} to {}\n",
+                tracing.exporter, tracing.endpoint
+            );
+        }
+
+        if self.config.ha {
+            println!("HA mode, the data directory can be shared with other backends\n");
+        }
+
+        if self.config.standalone {
+            println!("Running in standalone mode, backend is not listening on the network\n");
+        }
+        if let Some(ref address) = self.config.grpc.backend_address {
+            println!(
+                "{} {}, this one listening on {}\n",
+                if listen::unix_path(address).is_some() {
+                    "Calling the backend at"
+                } else {
+                    "Balancing calls across the backends at"
+                },
+                address,
+                self.config.grpc.listen
+            );
+        }
+
+        let capabilities = capabilities::Capabilities::new(&self.config);
+        println!(
+            "Capabilities, also at GET /trow/v1/capabilities: {}\n",
+            serde_json::to_string(&capabilities)?
+        );
+
         if self.config.dry_run {
             println!("Dry run, exiting.");
             std::process::exit(0);
         }
-        let s = format!("https://{}", self.config.grpc.listen);
-        let ci: ClientInterface = build_handlers(s)?;
-
-        let cors = rocket_cors::CorsOptions {
-            allowed_origins: AllowedOrigins::all(),
-            allowed_methods: vec![Method::Get, Method::Post, Method::Options]
-                .into_iter()
-                .map(From::from)
-                .collect(),
-            allowed_headers: AllowedHeaders::some(&["Authorization", "Content-Type"]),
-            allow_credentials: true,
-            ..Default::default()
+
+        let rt = rocket::tokio::runtime::Builder::new_multi_thread()
+            // NOTE: graceful shutdown depends on the "rocket-worker" prefix.
+            .thread_name("rocket-worker-thread")
+            .enable_all()
+            .build()?;
+        // The clients need a runtime to create their channels
+        let _guard = rt.enter();
+
+        if let Some(ref tracing) = self.config.tracing {
+            telemetry::init(tracing)
+                .map_err(|e| anyhow!("Failed to start trace exporter: {}", e))?;
+        }
+        if let Some(ref path) = self.config.audit_log {
+            audit::init(path).map_err(|e| anyhow!("Failed to open audit log {}: {}", path, e))?;
         }
-        .to_cors()?;
 
-        let f = rocket::custom(rocket_config.clone())
+        // Start GRPC Backend thread.
+        // It's stopped after Rocket, so requests draining on shutdown can still use it
+        let (stop_backend, backend_stop) = rocket::tokio::sync::oneshot::channel::<()>();
+        let backend_stop = async move {
+            _ = backend_stop.await;
+        };
+        // Standalone backends only listen on a socket in a directory no other user can read
+        let mut config = self.config.clone();
+        let standalone_dir = if config.standalone {
+            let dir = listen::private_dir()?;
+            config.grpc.listen =
+                format!("{}{}", UNIX_SCHEME, dir.join(STANDALONE_SOCKET).display());
+            config.grpc.tls = None;
+            config.grpc.require_tls = false;
+            if let Some(ref mut spiffe) = config.spiffe {
+                spiffe.svid = None;
+            }
+            Some(dir)
+        } else {
+            None
+        };
+        let ts = init_trow_server(config.clone())?;
+        let backend;
+        let ci = {
+            let connect_address = listen::grpc_connect_address(&config.grpc.listen);
+            let s = format!("https://{}", connect_address);
+            let svid = match config.spiffe {
+                Some(SpiffeConfig {
+                    ref bundle,
+                    svid: Some((ref cert, ref key)),
+                    ..
+                }) => Some(SvidSource::new(cert, key, bundle)?),
+                _ => None,
+            };
+            let client_tls = match svid {
+                Some(svid) => {
+                    backend = rt.spawn(
+                        ts.add_spiffe(svid.clone())
+                            .get_server_future(backend_stop)?,
+                    );
+                    Some(trow_server::spiffe::client_tls_config(svid))
+                }
+                None => {
+                    backend = rt.spawn(ts.get_server_future(backend_stop)?);
+                    match config.grpc.tls {
+                        Some(ref tls) => Some(trow_server::grpc_tls::client_tls_config(
+                            &fs::read(&tls.ca_file)?,
+                            &fs::read(&tls.cert_file)?,
+                            &fs::read(&tls.key_file)?,
+                            &tls.server_name,
+                        )?),
+                        None => None,
+                    }
+                }
+            };
+            let address = config
+                .grpc
+                .backend_address
+                .as_deref()
+                .unwrap_or(connect_address);
+            match listen::unix_path(address) {
+                Some(path) => ClientInterface::unix(path, client_tls)?,
+                None if config.grpc.backend_address.is_some() => {
+                    ClientInterface::balanced(address, client_tls)?
+                }
+                None => match client_tls {
+                    Some(tls) => ClientInterface::new_with_tls(s, tls)?,
+                    None => build_handlers(s)?,
+                },
+            }
+        };
+        let ci = match self.config.blob_redirect {
+            Some(ref redirect) => ci.with_blob_redirect(redirect.clone()),
+            None => ci,
+        };
+        let ci = if self.config.manifest_cache_ttl.is_zero() {
+            ci
+        } else {
+            ci.with_manifest_cache(self.config.manifest_cache_ttl)
+        };
+        let ci = if self.config.admission_cache_ttl.is_zero() {
+            ci
+        } else {
+            ci.with_admission_cache(self.config.admission_cache_ttl)
+        };
+        let ci = ci
+            .with_transfer_limits(self.config.transfer_limits)
+            .with_upload_buffer_size(self.config.upload_buffer_size);
+
+        let config_watcher = self.config.config_reload.as_ref().map(|reloader| {
+            let rules = PolicyRules {
+                allow_prefixes: self.config.allow_prefixes.clone(),
+                allow_images: self.config.allow_images.clone(),
+                deny_prefixes: self.config.deny_prefixes.clone(),
+                deny_images: self.config.deny_images.clone(),
+                quotas: self.config.quotas.clone(),
+                immutable_tags: self.config.immutable_tags.clone(),
+                freeze_windows: self.config.freeze_windows.clone(),
+            };
+            rt.spawn(reloader.clone().watch(rules, ci.clone()))
+        });
+
+        let reloader = match self.config.tls {
+            // Client certificates are optional, as clients without an SVID can still log in
+            Some(ref tls) => Some(tls::CertReloader::new(
+                &tls.cert_file,
+                &tls.key_file,
+                self.config.spiffe.as_ref().map(|s| s.bundle.as_str()),
+            )?),
+            None => None,
+        };
+
+        // Shared by the Rockets for each address, so retries still get the original response
+        let idempotency = Arc::new(idempotency::IdempotencyCache::new(
+            self.config.idempotency_keys,
+        ));
+        rt.spawn(idempotency.clone().expire_keys());
+        let transfers = Arc::new(transfer::TransferLedger::load(
+            Path::new(&self.config.data_dir),
+            self.config.ha,
+        ));
+
+        match reloader {
+            // One Rocket on a loopback port, behind the HTTPS proxy on each address (see tls.rs)
+            Some(reloader) => {
+                let mut https = vec![];
+                for addr in &http_addrs {
+                    https.push(
+                        std::net::TcpListener::bind(addr)
+                            .map_err(|e| anyhow!("Failed to listen on {}: {}", addr, e))?,
+                    );
+                }
+                let port = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+                    .local_addr()?
+                    .port();
+                let mut config = rocket_config.clone();
+                config.address = Ipv4Addr::LOCALHOST.into();
+                config.port = port;
+                let rocket = self
+                    .build_rocket(
+                        config,
+                        &http_addrs,
+                        ci.clone(),
+                        idempotency.clone(),
+                        transfers.clone(),
+                    )?
+                    .manage(tls::ProxySecret::default())
+                    .attach(reloader.fairing(https, port));
+                _ = rt.block_on(rocket.launch())?;
+            }
+            // Otherwise rocket, one for each address
+            None => {
+                let listeners = listen::Listeners::default();
+                let mut launches = vec![];
+                for addr in &http_addrs {
+                    let mut config = rocket_config.clone();
+                    config.address = addr.ip();
+                    config.port = addr.port();
+                    let rocket = self
+                        .build_rocket(
+                            config,
+                            &[*addr],
+                            ci.clone(),
+                            idempotency.clone(),
+                            transfers.clone(),
+                        )?
+                        .attach(listeners.fairing());
+                    let listeners = listeners.clone();
+                    launches.push(async move {
+                        let launched = rocket.launch().await;
+                        listeners.stop();
+                        launched
+                    });
+                }
+                _ = rt.block_on(futures::future::try_join_all(launches))?;
+            }
+        }
+
+        if let Err(e) = transfers.save() {
+            warn!("Failed to save transfer totals: {}", e);
+        }
+        // Closes the channel to the backend, then waits for it to save uploads in progress
+        if let Some(watcher) = config_watcher {
+            watcher.abort();
+        }
+        drop(ci);
+        _ = stop_backend.send(());
+        match rt.block_on(rocket::tokio::time::timeout(
+            BACKEND_SHUTDOWN_TIMEOUT,
+            backend,
+        )) {
+            Ok(Ok(Ok(()))) => info!("Backend shut down"),
+            Ok(Ok(Err(e))) => warn!("Backend failed while shutting down: {}", e),
+            Ok(Err(e)) => warn!("Backend failed while shutting down: {}", e),
+            Err(_) => warn!("Timed out waiting for the backend to shut down"),
+        }
+        if let Some(dir) = standalone_dir {
+            _ = fs::remove_dir_all(dir);
+        }
+        telemetry::shutdown();
+
+        Ok(())
+    }
+
+    fn build_rocket(
+        &self,
+        rocket_config: rocket::Config,
+        // Where clients reach it, for the launch message
+        addrs: &[SocketAddr],
+        ci: ClientInterface,
+        idempotency: Arc<idempotency::IdempotencyCache>,
+        transfers: Arc<transfer::TransferLedger>,
+    ) -> Result<rocket::Rocket<rocket::Build>> {
+        let cors = self.config.cors_config.to_cors()?;
+
+        let addrs = addrs
+            .iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let rocket = rocket::custom(rocket_config)
             .manage(self.config.clone())
-            .manage(ci)
+            .manage(Box::new(ci) as Box<dyn RegistryInterface>)
+            .manage(transfers.clone())
+            .manage(schema2::Renditions::new())
+            .manage(tenants::TenantCache::default())
+            .attach(transfer::TransferAccounting(transfers))
             .attach(fairing::AdHoc::on_response(
                 "Set API Version Header",
                 |_, resp| {
@@ -322,28 +1809,37 @@ impl TrowBuilder {
                     })
                 },
             ))
-            .attach(fairing::AdHoc::on_liftoff("Launch Message", |_| {
+            .attach(fairing::AdHoc::on_response(
+                "Set Request ID Header",
+                |req, resp| {
+                    Box::pin(async move {
+                        resp.set_header(request_id::response_header(req));
+                    })
+                },
+            ))
+            .attach(fairing::AdHoc::on_liftoff("Launch Message", move |_| {
                 Box::pin(async move {
-                    println!("Trow is up and running!");
+                    println!("Trow is up and running on {}!", addrs);
                 })
             }))
             .attach_if(self.config.cors, cors)
-            .mount("/", routes::routes())
-            .register("/", routes::catchers())
-            .launch();
-
-        let rt = rocket::tokio::runtime::Builder::new_multi_thread()
-            // NOTE: graceful shutdown depends on the "rocket-worker" prefix.
-            .thread_name("rocket-worker-thread")
-            .enable_all()
-            .build()?;
-
-        // Start GRPC Backend thread.
-        rt.spawn(init_trow_server(self.config.clone())?);
-        //And now rocket
-        _ = rt.block_on(f)?;
-
-        Ok(())
+            .mount(
+                "/",
+                request_id::with_request_ids(telemetry::with_spans(
+                    request_limits::with_header_limit(
+                        rate_limit::with_rate_limits(
+                            idempotency::with_idempotency_keys(
+                                names::with_name_checks(routes::routes()),
+                                idempotency,
+                            ),
+                            self.config.rate_limits.clone(),
+                        ),
+                        self.config.max_header_size,
+                    ),
+                )),
+            )
+            .register("/", routes::catchers());
+        Ok(rocket)
     }
 }
 
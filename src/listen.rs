@@ -0,0 +1,279 @@
+use std::collections::HashSet;
+use std::fs::DirBuilder;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use log::info;
+use rocket::fairing::AdHoc;
+use rocket::Shutdown;
+use trow_server::{ListenAddr, UNIX_SCHEME};
+use uuid::Uuid;
+
+/*
+ * The addresses Trow listens on, for HTTP (--host) and for the backend's gRPC (--grpc-listen).
+ *
+ * Both take several addresses, IPv4 or IPv6, and host names, which are listened on at every
+ * address they resolve to. This is so Trow can be reached over both IPv4 and IPv6 in dual-stack
+ * clusters, where a pod has an address of each.
+ *
+ * On most Linux systems a socket listening on [::] accepts IPv4 connections too, and listening on
+ * 0.0.0.0 at the same port then fails as the address is in use. So when both are given on a
+ * dual-stack system, the IPv4 addresses on that port are left to the IPv6 socket. When IPv6 is
+ * disabled, IPv6 addresses are skipped as long as there's something else to listen on, so that
+ * listening on both by default works everywhere.
+ *
+ * The backend can also listen on Unix sockets, given as unix:///path/to/socket, for a frontend
+ * on the same host or in the same pod.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Ipv6Support {
+    Unavailable,
+    // IPv4 and IPv6 need their own sockets, e.g. with net.ipv6.bindv6only set
+    Separate,
+    // [::] accepts IPv4 connections as well
+    DualStack,
+}
+
+// Found by listening on [::], then seeing if 0.0.0.0 is free at the same port
+fn ipv6_support() -> Ipv6Support {
+    let v6 = match TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)) {
+        Ok(l) => l,
+        Err(_) => return Ipv6Support::Unavailable,
+    };
+    match v6
+        .local_addr()
+        .and_then(|a| TcpListener::bind(("0.0.0.0", a.port())))
+    {
+        Ok(_) => Ipv6Support::Separate,
+        Err(_) => Ipv6Support::DualStack,
+    }
+}
+
+fn entries(list: &str) -> Vec<&str> {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|e| !e.is_empty())
+        .collect()
+}
+
+fn select(addrs: Vec<SocketAddr>, ipv6: Ipv6Support) -> Result<Vec<SocketAddr>> {
+    let mut seen = HashSet::new();
+    let mut addrs: Vec<SocketAddr> = addrs.into_iter().filter(|a| seen.insert(*a)).collect();
+    match ipv6 {
+        Ipv6Support::Unavailable if addrs.iter().any(SocketAddr::is_ipv4) => {
+            addrs.retain(|a| {
+                if a.is_ipv6() {
+                    info!("Not listening on {} as IPv6 is unavailable", a);
+                }
+                a.is_ipv4()
+            });
+        }
+        Ipv6Support::DualStack => {
+            let wildcard_ports: HashSet<u16> = addrs
+                .iter()
+                .filter(|a| a.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+                .map(|a| a.port())
+                .collect();
+            addrs.retain(|a| {
+                let covered = a.is_ipv4() && wildcard_ports.contains(&a.port());
+                if covered {
+                    info!("Listening on [::]:{} covers {}", a.port(), a);
+                }
+                !covered
+            });
+        }
+        _ => {}
+    }
+    if addrs.is_empty() {
+        return Err(anyhow!("No addresses to listen on"));
+    }
+    Ok(addrs)
+}
+
+/// Where to serve HTTP, given hosts separated by commas, e.g. "::,0.0.0.0"
+pub fn http_addresses(hosts: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let mut addrs = vec![];
+    for host in entries(hosts) {
+        // IPv6 addresses can be given with or without brackets
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        match host.parse::<IpAddr>() {
+            Ok(ip) => addrs.push(SocketAddr::new(ip, port)),
+            Err(_) => addrs.extend(
+                (host, port)
+                    .to_socket_addrs()
+                    .map_err(|e| anyhow!("Failed to resolve host {}: {}", host, e))?,
+            ),
+        }
+    }
+    select(addrs, ipv6_support())
+}
+
+/// A new directory only this user can read, for sockets other users mustn't connect to
+pub fn private_dir() -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("trow-{}", Uuid::new_v4()));
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| anyhow!("Failed to create {}: {}", dir.display(), e))?;
+    Ok(dir)
+}
+
+/// The path of a unix:// address
+pub fn unix_path(address: &str) -> Option<&str> {
+    address.strip_prefix(UNIX_SCHEME)
+}
+
+/// Where to serve gRPC, given HOST:PORT and unix:// addresses separated by commas
+pub fn grpc_addresses(listen: &str) -> Result<Vec<ListenAddr>> {
+    let mut sockets = vec![];
+    let mut addrs = vec![];
+    for address in entries(listen) {
+        match unix_path(address) {
+            Some("") => return Err(anyhow!("Missing path in gRPC address {}", address)),
+            Some(path) => sockets.push(ListenAddr::Unix(PathBuf::from(path))),
+            None => addrs.extend(
+                address
+                    .to_socket_addrs()
+                    .map_err(|e| anyhow!("Failed to resolve gRPC address {}: {}", address, e))?,
+            ),
+        }
+    }
+    if addrs.is_empty() && !sockets.is_empty() {
+        return Ok(sockets);
+    }
+    let mut listen_addrs: Vec<ListenAddr> = select(addrs, ipv6_support())?
+        .into_iter()
+        .map(ListenAddr::Tcp)
+        .collect();
+    listen_addrs.extend(sockets);
+    Ok(listen_addrs)
+}
+
+/*
+ * Where the frontend calls the backend in its own process: a Unix socket if it listens on one, as
+ * that avoids the network, otherwise the first of the addresses it listens on. Names are resolved
+ * when connecting, trying each address in turn.
+ */
+pub fn grpc_connect_address(listen: &str) -> &str {
+    let entries = entries(listen);
+    entries
+        .iter()
+        .find(|e| unix_path(e).is_some())
+        .or_else(|| entries.first())
+        .copied()
+        .unwrap_or(listen)
+}
+
+/*
+ * Without TLS, each HTTP address is served by its own Rocket, as a Rocket only listens on one.
+ * Once one of them stops, e.g. to shut down, the others are stopped too.
+ */
+#[derive(Clone)]
+pub struct Listeners {
+    // None once stopping
+    running: Arc<Mutex<Option<Vec<Shutdown>>>>,
+}
+
+impl Default for Listeners {
+    fn default() -> Listeners {
+        Listeners {
+            running: Arc::new(Mutex::new(Some(vec![]))),
+        }
+    }
+}
+
+impl Listeners {
+    pub fn fairing(&self) -> AdHoc {
+        let listeners = self.clone();
+        AdHoc::on_liftoff("Listeners", move |rocket| {
+            let shutdown = rocket.shutdown();
+            Box::pin(async move {
+                match listeners.running.lock().unwrap().as_mut() {
+                    Some(running) => running.push(shutdown),
+                    None => shutdown.notify(),
+                }
+            })
+        })
+    }
+
+    pub fn stop(&self) {
+        if let Some(running) = self.running.lock().unwrap().take() {
+            for shutdown in running {
+                shutdown.notify();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{entries, grpc_addresses, grpc_connect_address, select, Ipv6Support};
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use trow_server::ListenAddr;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn splits_entries() {
+        assert_eq!(entries("::, 0.0.0.0"), vec!["::", "0.0.0.0"]);
+        assert_eq!(
+            grpc_connect_address("[::1]:51000,127.0.0.1:51000"),
+            "[::1]:51000"
+        );
+        assert_eq!(grpc_connect_address("127.0.0.1:51000"), "127.0.0.1:51000");
+        assert_eq!(
+            grpc_connect_address("0.0.0.0:51000,unix:///var/run/trow.sock"),
+            "unix:///var/run/trow.sock"
+        );
+    }
+
+    #[test]
+    fn reads_unix_addresses() {
+        assert_eq!(
+            grpc_addresses("unix:///var/run/trow.sock").unwrap(),
+            vec![ListenAddr::Unix(PathBuf::from("/var/run/trow.sock"))]
+        );
+        assert_eq!(
+            grpc_addresses("127.0.0.1:51000,unix:///var/run/trow.sock").unwrap(),
+            vec![
+                ListenAddr::Tcp("127.0.0.1:51000".parse().unwrap()),
+                ListenAddr::Unix(PathBuf::from("/var/run/trow.sock"))
+            ]
+        );
+        assert!(grpc_addresses("unix://").is_err());
+    }
+
+    #[test]
+    fn selects_addresses() {
+        let both = addrs(&["[::]:8443", "0.0.0.0:8443", "[::]:8443"]);
+        assert_eq!(
+            select(both.clone(), Ipv6Support::DualStack).unwrap(),
+            addrs(&["[::]:8443"])
+        );
+        assert_eq!(
+            select(both.clone(), Ipv6Support::Separate).unwrap(),
+            addrs(&["[::]:8443", "0.0.0.0:8443"])
+        );
+        assert_eq!(
+            select(both, Ipv6Support::Unavailable).unwrap(),
+            addrs(&["0.0.0.0:8443"])
+        );
+
+        // Only the wildcard covers IPv4, and only on its own port
+        let other = addrs(&["[::1]:8443", "[::]:8000", "127.0.0.1:8443"]);
+        assert_eq!(
+            select(other.clone(), Ipv6Support::DualStack).unwrap(),
+            other
+        );
+        // Nothing else to listen on, so binding reports the problem
+        let v6 = addrs(&["[::1]:8443"]);
+        assert_eq!(select(v6.clone(), Ipv6Support::Unavailable).unwrap(), v6);
+        assert!(select(vec![], Ipv6Support::DualStack).is_err());
+    }
+}
@@ -2,6 +2,9 @@ use clap::{Arg, ArgMatches};
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
+use trow::config_file::ConfigFile;
+use trow::config_reload;
+use trow::cors::CorsConfig;
 use trow::{NetAddr, TrowBuilder};
 
 const PROGRAM_NAME: &str = "Trow";
@@ -21,11 +24,18 @@ fn parse_args() -> ArgMatches {
         .version("0.1")
         .author("From Container Solutions")
         .about(PROGRAM_DESC)
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("config")
+                .help("YAML file of settings, e.g. trow.yaml. Flags and TROW_* environment variables override it. Defaults to $TROW_CONFIG.")
+                .takes_value(true),
+        )
         .arg(
             Arg::new("host")
                 .long("host")
                 .value_name("host")
-                .help("Sets the name of the host or interface to start Trow on. Defaults to 0.0.0.0")
+                .help("Host names or IP addresses to start Trow on, separated by commas, e.g. 10.0.0.5,fd00::5. Names are served at every address they resolve to. Defaults to ::,0.0.0.0 for IPv6 and IPv4, with only :: used where it accepts IPv4 too.")
                 .takes_value(true),
         )
         .arg(
@@ -56,6 +66,13 @@ fn parse_args() -> ArgMatches {
                 .help(format!("Path to TLS private key. Defaults to {}.", DEFAULT_KEY_PATH).as_str())
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("tls-secret-dir")
+                .long("tls-secret-dir")
+                .value_name("tls-secret-dir")
+                .help("Directory where a Kubernetes TLS secret (e.g. from cert-manager) is mounted. Uses its tls.crt and tls.key instead of --cert and --key, waiting for them to be provisioned if needed.")
+                .takes_value(true),
+        )
         .arg(
             Arg::new("data-dir")
                 .short('d')
@@ -156,6 +173,87 @@ Must be used with --user")
 Must be used with --user")
             .takes_value(true)
         )
+        .arg(
+            Arg::new("htpasswd")
+            .long("htpasswd")
+            .value_name("htpasswd")
+            .help("htpasswd file of users with bcrypt passwords, who can log in to push. Changes to the file are picked up while running. Manage it with `trow htpasswd`.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("htpasswd-pull")
+            .long("htpasswd-pull")
+            .help("Require users from --htpasswd to log in to pull as well as push")
+            .requires("htpasswd")
+        )
+        .arg(
+            Arg::new("first-run-setup")
+            .long("first-run-setup")
+            .help("If the --htpasswd file has no users, create an admin user with a random password, shown once, to be replaced through /trow/v1/setup")
+            .requires("htpasswd")
+        )
+        .arg(
+            Arg::new("setup-secret")
+            .long("setup-secret")
+            .value_name("setup-secret")
+            .help("Write the first run admin password to this Kubernetes Secret in Trow's namespace rather than printing it")
+            .requires("first-run-setup")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("delete-users")
+            .long("delete-users")
+            .value_name("delete-users")
+            .help("Comma separated list of users who can delete manifests, blobs and repositories. Other users can push but not delete, except the --user.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("k8s-auth-rules")
+            .long("k8s-auth-rules")
+            .value_name("k8s-auth-rules")
+            .help("Comma separated list of NAMESPACE/NAME=pull|push|delete rules for Kubernetes ServiceAccount tokens, which are then accepted and checked with the TokenReview API. Either part can be *. The first matching rule applies.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("k8s-token-audience")
+            .long("k8s-token-audience")
+            .value_name("k8s-token-audience")
+            .help("Audience that ServiceAccount tokens must be issued for, e.g. trow")
+            .requires("k8s-auth-rules")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("oidc-issuer")
+            .long("oidc-issuer")
+            .value_name("oidc-issuer")
+            .help("URL of an OpenID Connect provider, e.g. Keycloak or Dex, whose ID tokens are accepted in place of a password or Trow token. Requires --oidc-audience and --oidc-roles.")
+            .requires_all(&["oidc-audience", "oidc-roles"])
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("oidc-audience")
+            .long("oidc-audience")
+            .value_name("oidc-audience")
+            .help("Client ID that OIDC tokens must be issued for")
+            .requires("oidc-issuer")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("oidc-roles")
+            .long("oidc-roles")
+            .value_name("oidc-roles")
+            .help("Comma separated list of GROUP=pull|push|delete rules giving OIDC users in a group a permission. * matches any user. The most permissive matching rule applies.")
+            .requires("oidc-issuer")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("oidc-groups-claim")
+            .long("oidc-groups-claim")
+            .value_name("oidc-groups-claim")
+            .help("Claim in OIDC tokens listing the user's groups. Defaults to groups.")
+            .requires("oidc-issuer")
+            .takes_value(true)
+        )
         .arg(
             Arg::new("version")
             .long("version")
@@ -193,11 +291,80 @@ Must be used with --hub-token or --hub-token-file")
             .help("Location of file with token that can be used for accessing the Docker Hub, used when proxying Docker Hub images")
             .takes_value(true)
         )
+        .arg(
+            Arg::new("proxy-check-interval")
+                .long("proxy-check-interval")
+                .value_name("proxy-check-interval")
+                .help("How often to compare a random sample of cached proxied tags with upstream and report any that have drifted, e.g. 6h. Defaults to 0, only comparing them when a proxy-check job is started.")
+                .requires("proxy-docker-hub")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("proxy-check-sample")
+                .long("proxy-check-sample")
+                .value_name("proxy-check-sample")
+                .help("How many cached proxied tags each proxy check compares with upstream. Defaults to 20.")
+                .requires("proxy-docker-hub")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("mirror-on-admission")
+                .long("mirror-on-admission")
+                .help("Fetch Docker Hub images into the proxy cache when pods using them are admitted by the validation webhook, so the cache is warm before nodes pull through Trow.")
+                .requires("proxy-docker-hub")
+        )
+        .arg(
+            Arg::new("mirror-workers")
+                .long("mirror-workers")
+                .value_name("mirror-workers")
+                .help("How many images --mirror-on-admission fetches at once. Defaults to 4.")
+                .requires("mirror-on-admission")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("mirror-queue-size")
+                .long("mirror-queue-size")
+                .value_name("mirror-queue-size")
+                .help("How many images --mirror-on-admission queues before dropping new ones. Images not cached yet are fetched before cached ones are refreshed. Defaults to 1000.")
+                .requires("mirror-on-admission")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("upstream-proxies")
+                .long("upstream-proxies")
+                .value_name("upstream-proxies")
+                .help("Comma separated list of proxies for outbound calls to particular hosts, as HOSTS=PROXY_URL or HOSTS=direct where HOSTS can use *, e.g. registry-1.docker.io=http://proxy:3128 or *.internal=direct. Other hosts use HTTP_PROXY, HTTPS_PROXY and NO_PROXY.")
+                .takes_value(true)
+        )
         .arg(
             Arg::new("enable-cors")
                 .long("enable-cors")
                 .help("Enable Cross-Origin Resource Sharing(CORS) requests. Used to allow access from web apps (e.g. GUIs).")
         )
+        .arg(
+            Arg::new("cors-origins")
+                .long("cors-origins")
+                .value_name("cors-origins")
+                .help("Comma separated list of origins allowed to make CORS requests, e.g. https://ui.example.com. Defaults to any origin.")
+                .requires("enable-cors")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("cors-methods")
+                .long("cors-methods")
+                .value_name("cors-methods")
+                .help("Comma separated list of methods allowed in CORS requests. Defaults to GET,POST,OPTIONS.")
+                .requires("enable-cors")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("cors-headers")
+                .long("cors-headers")
+                .value_name("cors-headers")
+                .help("Comma separated list of headers allowed in CORS requests, or * for any. Defaults to Authorization,Content-Type.")
+                .requires("enable-cors")
+                .takes_value(true)
+        )
         .arg(
             Arg::new("max-manifest-size")
             .long("max-manifest-size")
@@ -209,7 +376,122 @@ Must be used with --hub-token or --hub-token-file")
             Arg::new("max-blob-size")
             .long("max-blob-size")
             .value_name("max-blob-size")
-            .help("Maximum size in mebibytes of \"blob\" that can be uploaded (a single layer of an image). This can be very large in some images (GBs).")
+            .help("Maximum size in mebibytes of \"blob\" that can be uploaded (a single layer of an image), across all the chunks it's sent in. This can be very large in some images (GBs).")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("upload-buffer-size")
+            .long("upload-buffer-size")
+            .value_name("upload-buffer-size")
+            .help("Size in kibibytes of the buffer each upload is written to storage through, which is as much of it as is held in memory at once. Between 4 and 2048, defaults to 256.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("idempotency-keys")
+            .long("idempotency-keys")
+            .value_name("idempotency-keys")
+            .help("Most Idempotency-Key values to remember the response for, dropping the least recently used past that. Defaults to 10000.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("max-layers")
+            .long("max-layers")
+            .value_name("max-layers")
+            .help("Maximum number of layers in a pushed image. Defaults to 0, no limit.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("blob-redirect-url")
+            .long("blob-redirect-url")
+            .value_name("blob-redirect-url")
+            .help("Redirect blob downloads to a server with the blobs directory of the data dir under this URL, e.g. nginx with secure_link. Requires --blob-redirect-secret-file.")
+            .requires("blob-redirect-secret-file")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("blob-redirect-secret-file")
+            .long("blob-redirect-secret-file")
+            .value_name("blob-redirect-secret-file")
+            .help("File with the secret for signing blob redirect URLs, shared with the server they go to")
+            .requires("blob-redirect-url")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("blob-redirect-expiry")
+            .long("blob-redirect-expiry")
+            .value_name("blob-redirect-expiry")
+            .help("How long blob redirect URLs are valid for, e.g. 5m. Defaults to 5m.")
+            .requires("blob-redirect-url")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("manifest-cache-ttl")
+            .long("manifest-cache-ttl")
+            .value_name("manifest-cache-ttl")
+            .help("How long the frontend caches manifests and the digests tags point at in memory, e.g. 30s. Pushes and deletes through the same frontend update the cache straight away. Defaults to 10s, use 0 to not cache them.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("admission-cache-ttl")
+            .long("admission-cache-ttl")
+            .value_name("admission-cache-ttl")
+            .help("How long the frontend caches the validation webhook's decision for a pod's images in a namespace, e.g. 10s. Reloading the policy drops the cached decisions straight away. Defaults to 5s, use 0 to not cache them.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("shutdown-timeout")
+            .long("shutdown-timeout")
+            .value_name("shutdown-timeout")
+            .help("How long uploads and downloads in progress get to finish when Trow is stopped with SIGTERM or Ctrl-C, e.g. 60s. New connections are refused straight away. Keep it below the pod's terminationGracePeriodSeconds. Defaults to 25s.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("rate-limit")
+            .long("rate-limit")
+            .value_name("rate-limit")
+            .help("Requests per second allowed from each client, by logged in user or else IP address. Clients over the limit get 429 Too Many Requests. Defaults to 0, no limit. Can be changed at runtime through /api/v1/rate-limits.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("upload-limit")
+            .long("upload-limit")
+            .value_name("upload-limit")
+            .help("Blob upload requests each client can have in progress at once. Defaults to 0, no limit.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("client-rate-limits")
+            .long("client-rate-limits")
+            .value_name("client-rate-limits")
+            .help("Comma separated list of limits for particular users or IP addresses, overriding --rate-limit and --upload-limit, as CLIENT=REQUESTS_PER_SEC/UPLOADS where 0 is no limit, e.g. ci-bot=100/8,10.0.3.7=5/1.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("max-request-size")
+            .long("max-request-size")
+            .value_name("max-request-size")
+            .help("Maximum size in mebibytes of request bodies other than blobs and manifests, such as JSON sent to the admin API. Defaults to 1.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("max-header-size")
+            .long("max-header-size")
+            .value_name("max-header-size")
+            .help("Maximum size in kibibytes of a request's headers. Requests with more get 431 Request Header Fields Too Large. Defaults to 32, use 0 for no limit.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("idle-timeout")
+            .long("idle-timeout")
+            .value_name("idle-timeout")
+            .help("How long an upload or download can go without any data being sent before it's cut off with 408 Request Timeout, e.g. 2m. Defaults to 60s, use 0 for no limit.")
+            .takes_value(true)
+        )
+        .arg(
+            Arg::new("transfer-timeout")
+            .long("transfer-timeout")
+            .value_name("transfer-timeout")
+            .help("How long an upload or download can take in all before it's cut off with 408 Request Timeout, e.g. 1h. Defaults to 0, no limit.")
             .takes_value(true)
         )
         .arg(
@@ -219,28 +501,443 @@ Must be used with --hub-token or --hub-token-file")
             .help("The log level at which to output to stdout, valid values are OFF, ERROR, WARN, INFO, DEBUG and TRACE")
             .takes_value(true)
         )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .value_name("log-format")
+                .help("Format of log lines, text (the default) or json for one JSON object per line. Both include the ID of the request being handled, also returned in the X-Request-Id response header.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("web-ui")
+                .long("web-ui")
+                .help("Serve a web UI at /ui for browsing repositories, tags and images.")
+        )
+        .arg(
+            Arg::new("watch-data-dir")
+                .long("watch-data-dir")
+                .help("Watch the data directory for repositories and tags added or removed outside of Trow (e.g. restored from backup) and pick them up without a restart.")
+        )
+        .arg(
+            Arg::new("standalone")
+                .long("standalone")
+                .help("Run the backend inside the frontend, reached through a private Unix socket rather than the network. Suitable for most deployments.")
+        )
+        .arg(
+            Arg::new("ha")
+                .long("ha")
+                .help("Allow several Trow backends to share the data directory, e.g. on a ReadWriteMany volume. By default the data directory is locked and a second backend using it fails to start.")
+        )
+        .arg(
+            Arg::new("read-only")
+                .long("read-only")
+                .help("Start in read-only maintenance mode, where uploads, manifest writes and deletes fail with 503 Service Unavailable while pulls carry on. Admins can switch it on and off through PUT /api/v1/read-only.")
+        )
+        .arg(
+            Arg::new("audit-log")
+                .long("audit-log")
+                .value_name("audit-log")
+                .help("File to write an audit record of every push, pull, delete and admission decision to, as lines of JSON. Use - for stdout.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("trace-exporter")
+                .long("trace-exporter")
+                .value_name("trace-exporter")
+                .help("Export OpenTelemetry traces of each request and backend call, to an OTLP collector (otlp) or Jaeger agent (jaeger).")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("trace-endpoint")
+                .long("trace-endpoint")
+                .value_name("trace-endpoint")
+                .help("Where to export traces to. Defaults to http://localhost:4317 for otlp and localhost:6831 for jaeger.")
+                .takes_value(true)
+                .requires("trace-exporter")
+        )
+        .arg(
+            Arg::new("event-sinks")
+                .long("event-sinks")
+                .value_name("event-sinks")
+                .help("Comma separated list of URLs to publish push and delete events to. Supports http(s)://host/path webhooks, and nats://host:port/subject or kafka://host:port/topic if built with the nats or kafka features.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("event-format")
+                .long("event-format")
+                .value_name("event-format")
+                .help("Format of published events, either json (default) or cloudevents.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("quotas")
+                .long("quotas")
+                .value_name("quotas")
+                .help("Comma separated list of storage quotas for repositories or namespaces, as NAME=BYTES[:IMAGES] e.g. myorg=10GiB:100 or myorg/app=:5. Pushes that would go over quota are rejected.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("metadata-db")
+                .long("metadata-db")
+                .value_name("metadata-db")
+                .help("Path of a SQLite database to keep tag and manifest metadata in, e.g. /data/metadata.db. It's created if missing and synced with the data directory at startup. Speeds up the catalog, tag lists and garbage collection on large registries.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("transcode-layers")
+                .long("transcode-layers")
+                .value_name("min-pulls")
+                .help("Keep both gzip and zstd copies of layers once they've been pulled this many times, so clients sending Prefer: layer-compression=zstd or gzip can pull the one they prefer. Off by default.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("transcode-interval")
+                .long("transcode-interval")
+                .value_name("transcode-interval")
+                .help("How often to transcode layers that have been pulled enough times, e.g. 30m. Defaults to 1h, or 0 to only transcode them when a transcode job is started.")
+                .requires("transcode-layers")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("transcode-on-demand")
+                .long("transcode-on-demand")
+                .help("Also transcode a layer in the background as soon as a client asks for it in the compression it doesn't have, rather than waiting until it's been pulled enough times.")
+                .requires("transcode-layers")
+        )
+        .arg(
+            Arg::new("estargz")
+                .long("estargz")
+                .help("Convert pushed layers to eStargz, so clusters running stargz-snapshotter can pull images lazily by sending Prefer: layer-compression=estargz. Off by default.")
+        )
+        .arg(
+            Arg::new("estargz-interval")
+                .long("estargz-interval")
+                .value_name("estargz-interval")
+                .help("How often to convert the layers pushed since the last run to eStargz, e.g. 10m. Defaults to 1h, or 0 to only convert them when an estargz job is started.")
+                .requires("estargz")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("immutable-tags")
+                .long("immutable-tags")
+                .value_name("immutable-tags")
+                .help("Comma separated list of tags that can't be overwritten once pushed, as REPOS[:TAGS] where patterns can use *, e.g. myorg/*:v* or prod/app. Leaving out the tags makes every tag in the repositories immutable.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("freeze-windows")
+                .long("freeze-windows")
+                .value_name("freeze-windows")
+                .help("Comma separated list of change freezes, as NAMESPACES=DAYS[/HH:MM-HH:MM] in UTC e.g. prod=Sat-Sun or *=Mon-Fri/18:00-08:00. During a freeze only images already running in the namespace are admitted, unless the pod has a trow.io/break-glass annotation.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("require-existing-images")
+                .long("require-existing-images")
+                .help("Deny pods using images from this registry that haven't been pushed to it, by tag or digest, even if they're on an allow list. Catches typos in image names and only lets through what was built and pushed.")
+        )
+        .arg(
+            Arg::new("policy-crd")
+                .long("policy-crd")
+                .help("Also admit images according to TrowPolicy resources, which can allow registries, restrict what's admitted to namespaces and require images to be signed. The backend watches the Kubernetes API for changes to them, so needs the CRD installed and permission to list and watch trowpolicies.")
+        )
+        .arg(
+            Arg::new("retention")
+                .long("retention")
+                .value_name("retention")
+                .help("Comma separated list of tag retention rules, as REPOS[:TAGS]=keep-last:N or REPOS=untagged-older-than:AGE where patterns can use *, e.g. myorg/*:release-*=keep-last:10 or *=untagged-older-than:30d. Check what would be deleted with GET /trow/v1/retention.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("retention-interval")
+                .long("retention-interval")
+                .value_name("retention-interval")
+                .help("How often to apply the retention rules and trow.io/retention or trow.io/expires-after annotations on manifests, then garbage collect, e.g. 12h or 7d. Defaults to 24h if --retention is set. Setting this without --retention applies just the annotations. Use 0 to only apply them when a retention job is started.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("backup-dir")
+                .long("backup-dir")
+                .value_name("backup-dir")
+                .help("Directory to back up the registry to, e.g. a mounted volume. Only blobs that aren't already in the backup are copied.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("backup-interval")
+                .long("backup-interval")
+                .value_name("backup-interval")
+                .help("How often to back up to --backup-dir, e.g. 24h. Defaults to 24h. Use 0 to only back up when a backup job is started.")
+                .requires("backup-dir")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("scrub-interval")
+                .long("scrub-interval")
+                .value_name("scrub-interval")
+                .help("How often to re-hash every stored blob and check the blobs each tag uses are all stored, e.g. 7d. Results are in the scrub_* metrics and at GET /api/v1/scrub. Defaults to 0, only checking when a scrub job is started.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("scrub-quarantine")
+                .long("scrub-quarantine")
+                .help("Move corrupt blobs found by scrub jobs to the quarantine directory in the data dir, so they fail to pull as missing and are uploaded again by the next push, rather than leaving them in place.")
+        )
+        .arg(
+            Arg::new("usage-interval")
+                .long("usage-interval")
+                .value_name("usage-interval")
+                .help("How often to record which images are running in the cluster, e.g. 1h, for the report of unused images at GET /trow/v1/usage. Needs permission to list pods in all namespaces. Defaults to 0, only recording them when a usage job is started.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("upload-ttl")
+                .long("upload-ttl")
+                .value_name("upload-ttl")
+                .help("Remove blob uploads nothing has been written to for this long, e.g. 12h, along with any other files that old in the scratch directory. Defaults to 24h, 0 keeps them forever.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("trash-retention")
+                .long("trash-retention")
+                .value_name("trash-retention")
+                .help("Keep deleted manifests and repositories in the trash for this long, e.g. 7d, during which an admin can restore them through POST /api/v1/trash/<id>/restore. Garbage collection keeps their blobs until then. Defaults to 0, deleting them at once.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("scratch-dir")
+                .long("scratch-dir")
+                .value_name("scratch-dir")
+                .help("Write blob uploads in progress to this directory instead of the data directory, e.g. a local SSD or emptyDir when the data directory is on slower network storage. Completed uploads are copied into the data directory. Backends sharing a data directory with --ha need to share this too.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("storage-reserve")
+                .long("storage-reserve")
+                .value_name("storage-reserve")
+                .help("Space to keep free in the data directory and wherever uploads are written, as a size such as 10G or a percentage of the filesystem such as 5%. New uploads are refused with 507 Insufficient Storage while there's less, and those in progress can use the reserve to finish.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("storage")
+                .long("storage")
+                .value_name("storage")
+                .help("Keep the registry in Azure Blob Storage, azure://<account>/<container>[/<prefix>], or Google Cloud Storage, gs://<bucket>[/<prefix>]. The data dir becomes a working copy, brought up to date from the bucket at startup, and every change is written through. Credentials come from workload identity or the node's managed identity or service account.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("spiffe-bundle")
+                .long("spiffe-bundle")
+                .value_name("spiffe-bundle")
+                .help("PEM file with the CA certificates of the SPIFFE trust domain. Needed for --spiffe-rules and --spiffe-svid.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("spiffe-rules")
+                .long("spiffe-rules")
+                .value_name("spiffe-rules")
+                .help("Comma separated list of rules as SPIFFE_ID=pull|push, e.g. spiffe://example.org/ns/ci/*=push. Clients presenting an SVID matching a rule get that access without logging in. The first matching rule applies.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("spiffe-svid")
+                .long("spiffe-svid")
+                .value_name("spiffe-svid")
+                .help("PEM file with Trow's own SVID, used for mutual TLS between the frontend and backend. Reloaded when it changes.")
+                .takes_value(true)
+                .requires("spiffe-svid-key")
+        )
+        .arg(
+            Arg::new("spiffe-svid-key")
+                .long("spiffe-svid-key")
+                .value_name("spiffe-svid-key")
+                .help("PEM file with the private key for --spiffe-svid.")
+                .takes_value(true)
+                .requires("spiffe-svid")
+        )
+        .arg(
+            Arg::new("grpc-tls-ca")
+                .long("grpc-tls-ca")
+                .value_name("grpc-tls-ca")
+                .help("PEM file with the CA certificate for TLS between the frontend and backend. Both sides check the other's certificate against it.")
+                .takes_value(true)
+                .requires_all(&["grpc-tls-cert", "grpc-tls-key"])
+        )
+        .arg(
+            Arg::new("grpc-tls-cert")
+                .long("grpc-tls-cert")
+                .value_name("grpc-tls-cert")
+                .help("PEM file with the certificate presented by both the frontend and backend, issued by --grpc-tls-ca.")
+                .takes_value(true)
+                .requires_all(&["grpc-tls-ca", "grpc-tls-key"])
+        )
+        .arg(
+            Arg::new("grpc-tls-key")
+                .long("grpc-tls-key")
+                .value_name("grpc-tls-key")
+                .help("PEM file with the private key for --grpc-tls-cert.")
+                .takes_value(true)
+                .requires_all(&["grpc-tls-ca", "grpc-tls-cert"])
+        )
+        .arg(
+            Arg::new("grpc-tls-server-name")
+                .long("grpc-tls-server-name")
+                .value_name("grpc-tls-server-name")
+                .help("Name the frontend expects in the backend's certificate. Defaults to localhost.")
+                .takes_value(true)
+                .requires("grpc-tls-cert")
+        )
+        .arg(
+            Arg::new("grpc-listen")
+                .long("grpc-listen")
+                .value_name("grpc-listen")
+                .help("Addresses the backend listens on for gRPC, as HOST:PORT separated by commas. Names are listened on at every address they resolve to. Defaults to 127.0.0.1:51000, use e.g. [::]:51000,0.0.0.0:51000 for frontends in other pods to reach it with --backend-address. A Unix socket such as unix:///var/run/trow.sock avoids the network, and the frontend uses it if given.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("grpc-socket-mode")
+                .long("grpc-socket-mode")
+                .value_name("grpc-socket-mode")
+                .help("Permissions of the backend's Unix sockets in octal. Defaults to 600, only the user Trow runs as, use e.g. 660 for its group as well.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("backend-address")
+                .long("backend-address")
+                .value_name("backend-address")
+                .help("Call the backends this HOST:PORT resolves to instead of only the one in this process, balancing calls across them, e.g. a headless Kubernetes Service with a record for each Trow pod. The name is resolved again every 10 seconds. The backends must share the data directory, see --ha. Or call the one backend listening on a Unix socket, e.g. unix:///var/run/trow.sock.")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::new("migrate")
+                .long("migrate")
+                .help("Upgrade the data directory to the layout this version of Trow needs, then exit. Trow refuses to start on a data directory from an older version until it's been migrated. An interrupted migration carries on where it stopped when run again, and the data directory is verified afterwards.")
+        )
+        .arg(
+            Arg::new("verify-layout")
+                .long("verify-layout")
+                .help("Check the data directory matches its layout version without changing it, then exit. Exits with an error if anything is wrong.")
+        )
+        .arg(
+            Arg::new("grpc-require-tls")
+                .long("grpc-require-tls")
+                .help("Refuse backend clients without a certificate, and refuse to start without --grpc-tls-cert or --spiffe-svid.")
+        )
+        .subcommand(
+            clap::Command::new("htpasswd")
+                .about("Manage the users in an htpasswd file for --htpasswd")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("add")
+                        .about("Add a user or change their password, read from stdin")
+                        .arg(Arg::new("file").required(true))
+                        .arg(Arg::new("user").required(true)),
+                )
+                .subcommand(
+                    clap::Command::new("remove")
+                        .about("Remove a user")
+                        .arg(Arg::new("file").required(true))
+                        .arg(Arg::new("user").required(true)),
+                ),
+        )
         .get_matches()
 }
 
+/*
+ * Runs `trow htpasswd add|remove FILE USER`, exiting with an error if it fails.
+ */
+fn run_htpasswd(matches: &ArgMatches) {
+    let (cmd, args) = matches.subcommand().expect("Subcommand is required");
+    let file = args.value_of("file").expect("File is required");
+    let user = args.value_of("user").expect("User is required");
+    let result = match cmd {
+        "add" => {
+            let mut pass = String::new();
+            std::io::stdin()
+                .read_line(&mut pass)
+                .expect("Failed to read password");
+            let pass = pass.trim_end_matches(&['\r', '\n'][..]);
+            if pass.is_empty() {
+                eprintln!("No password given on stdin");
+                std::process::exit(1);
+            }
+            trow::htpasswd::add_user(file, user, pass).map(|_| println!("Saved {}", user))
+        }
+        "remove" => trow::htpasswd::remove_user(file, user).map(|removed| {
+            if removed {
+                println!("Removed {}", user)
+            } else {
+                println!("{} wasn't in {}", user, file)
+            }
+        }),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to update {}: {}", file, e);
+        std::process::exit(1);
+    }
+}
+
 fn parse_list(names: &str) -> Vec<String> {
     //split on , or whitespace
     let ret_str = names.replace(",", " ");
     ret_str.split_whitespace().map(|x| x.to_owned()).collect()
 }
 
+/*
+ * Flags from the command line, falling back to the config file and environment.
+ */
+struct Settings<'a> {
+    args: &'a ArgMatches,
+    config: ConfigFile,
+}
+
+impl Settings<'_> {
+    fn value_of(&self, name: &str) -> Option<&str> {
+        self.args
+            .value_of(name)
+            .or_else(|| self.config.value_of(name))
+    }
+
+    fn is_present(&self, name: &str) -> bool {
+        self.args.is_present(name) || self.config.is_present(name)
+    }
+}
+
 fn main() {
-    let matches = parse_args();
+    let args = parse_args();
+    if let Some(("htpasswd", sub)) = args.subcommand() {
+        run_htpasswd(sub);
+        return;
+    }
 
-    if matches.is_present("version") {
+    if args.is_present("version") {
         let vcs_ref = env::var("VCS_REF").unwrap_or_default();
         println!("Trow version {} {}", env!("CARGO_PKG_VERSION"), vcs_ref);
         std::process::exit(0);
     }
 
+    let config_path = args
+        .value_of("config")
+        .map(|p| p.to_string())
+        .or_else(|| env::var("TROW_CONFIG").ok());
+    let config = ConfigFile::load(config_path.as_deref())
+        .and_then(|config| {
+            config.check_requires(|flag| args.is_present(flag))?;
+            Ok(config)
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+    let matches = Settings {
+        args: &args,
+        config,
+    };
+
     let fallback_log_level = env::var("RUST_LOG").unwrap_or_else(|_| "error".to_string());
     let log_level = matches.value_of("log-level").unwrap_or(&fallback_log_level);
     let no_tls = matches.is_present("no-tls");
-    let host = matches.value_of("host").unwrap_or("0.0.0.0");
+    let host = matches.value_of("host").unwrap_or("::,0.0.0.0");
     let default_port = if no_tls { 8000 } else { 8443 };
     let port: u16 = matches.value_of("port").map_or(default_port, |x| {
         x.parse().expect("Failed to parse port number")
@@ -266,14 +963,11 @@ fn main() {
             x.parse().expect("Failed to parse max blob size")
         });
 
-    let mut allow_prefixes = parse_list(matches.value_of("allow-prefixes").unwrap_or(""));
-    if matches.is_present("allow-docker-official") {
-        allow_prefixes.push("docker.io/".to_owned());
-    }
-    if !matches.is_present("deny-k8s-images") {
-        allow_prefixes.push("k8s.gcr.io/".to_owned());
-        allow_prefixes.push("docker.io/containersol/trow".to_owned());
-    }
+    let allow_prefixes = config_reload::allow_prefixes(
+        parse_list(matches.value_of("allow-prefixes").unwrap_or("")),
+        matches.is_present("allow-docker-official"),
+        matches.is_present("deny-k8s-images"),
+    );
     let allow_images = parse_list(matches.value_of("allow-images").unwrap_or(""));
     let deny_prefixes = parse_list(matches.value_of("disallow-local-prefixes").unwrap_or(""));
     let deny_images = parse_list(matches.value_of("disallow-local-images").unwrap_or(""));
@@ -300,8 +994,29 @@ fn main() {
         max_blob_size,
         log_level.to_string(),
     );
+    if let Some(format) = matches.value_of("log-format") {
+        builder.with_log_format(format).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+    }
+    if cors {
+        let cors_config = CorsConfig::new(
+            parse_list(matches.value_of("cors-origins").unwrap_or("")),
+            parse_list(matches.value_of("cors-methods").unwrap_or("")),
+            parse_list(matches.value_of("cors-headers").unwrap_or("")),
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        builder.with_cors(cors_config);
+    }
     if !no_tls {
-        builder.with_tls(cert_path.to_string(), key_path.to_string());
+        match matches.value_of("tls-secret-dir") {
+            Some(dir) => builder.with_tls_secret(dir),
+            None => builder.with_tls(cert_path.to_string(), key_path.to_string()),
+        };
     }
     if matches.is_present("user") {
         let user = matches.value_of("user").expect("Failed to read user name");
@@ -335,6 +1050,66 @@ fn main() {
             std::process::exit(1);
         }
     }
+    if let Some(path) = matches.value_of("htpasswd") {
+        if matches.is_present("first-run-setup") {
+            let secret = matches.value_of("setup-secret").map(|s| s.to_string());
+            builder
+                .with_first_run_setup(path, secret)
+                .unwrap_or_else(|e| {
+                    eprintln!("Failed to run first run setup: {}", e);
+                    std::process::exit(1);
+                });
+        }
+        builder
+            .with_htpasswd(path, matches.is_present("htpasswd-pull"))
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to read htpasswd file {}: {}", path, e);
+                std::process::exit(1);
+            });
+    }
+    if let Some(users) = matches.value_of("delete-users") {
+        builder.with_delete_users(parse_list(users));
+    }
+    if let Some(ref path) = config_path {
+        let pinned = config_reload::RELOADABLE
+            .iter()
+            .filter(|flag| args.is_present(flag))
+            .map(|flag| flag.to_string())
+            .collect();
+        builder
+            .with_config_reload(path, pinned)
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+    }
+    if let Some(rules) = matches.value_of("k8s-auth-rules") {
+        let audience = matches
+            .value_of("k8s-token-audience")
+            .map(|a| a.to_string());
+        builder
+            .with_service_account_auth(parse_list(rules), audience)
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to set up ServiceAccount authentication: {}", e);
+                std::process::exit(1);
+            });
+    }
+    if let Some(issuer) = matches.value_of("oidc-issuer") {
+        let audience = matches.value_of("oidc-audience").unwrap();
+        let roles = parse_list(matches.value_of("oidc-roles").unwrap());
+        let groups_claim = matches.value_of("oidc-groups-claim").unwrap_or("groups");
+        builder
+            .with_oidc(
+                issuer.to_string(),
+                audience.to_string(),
+                groups_claim.to_string(),
+                roles,
+            )
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid OIDC configuration: {}", e);
+                std::process::exit(1);
+            });
+    }
     if matches.is_present("proxy-docker-hub") && matches.is_present("hub-user") {
         let hub_user = matches
             .value_of("hub-user")
@@ -369,6 +1144,286 @@ fn main() {
             std::process::exit(1);
         }
     }
+    if matches.is_present("proxy-check-interval") || matches.is_present("proxy-check-sample") {
+        let interval = matches.value_of("proxy-check-interval").unwrap_or("0");
+        let sample = matches
+            .value_of("proxy-check-sample")
+            .unwrap_or("20")
+            .parse()
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid --proxy-check-sample: {}", e);
+                std::process::exit(1);
+            });
+        builder.with_proxy_check(interval.to_string(), sample);
+    }
+    if matches.is_present("mirror-on-admission") {
+        let parse = |name: &str, default: &str| -> usize {
+            matches
+                .value_of(name)
+                .unwrap_or(default)
+                .parse()
+                .unwrap_or_else(|e| {
+                    eprintln!("Invalid --{}: {}", name, e);
+                    std::process::exit(1);
+                })
+        };
+        let workers = parse("mirror-workers", "4");
+        let queue_size = parse("mirror-queue-size", "1000");
+        builder.with_admission_mirroring(workers, queue_size);
+    }
+    if let Some(url) = matches.value_of("blob-redirect-url") {
+        let secret_file = matches.value_of("blob-redirect-secret-file").unwrap();
+        let expiry = matches.value_of("blob-redirect-expiry").unwrap_or("5m");
+        builder
+            .with_blob_redirect(url, secret_file, expiry)
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid blob redirect configuration: {}", e);
+                std::process::exit(1);
+            });
+    }
+    if let Some(ttl) = matches.value_of("manifest-cache-ttl") {
+        builder.with_manifest_cache_ttl(ttl).unwrap_or_else(|e| {
+            eprintln!("Invalid --manifest-cache-ttl: {}", e);
+            std::process::exit(1);
+        });
+    }
+    if let Some(ttl) = matches.value_of("admission-cache-ttl") {
+        builder.with_admission_cache_ttl(ttl).unwrap_or_else(|e| {
+            eprintln!("Invalid --admission-cache-ttl: {}", e);
+            std::process::exit(1);
+        });
+    }
+    if let Some(timeout) = matches.value_of("shutdown-timeout") {
+        builder.with_shutdown_timeout(timeout).unwrap_or_else(|e| {
+            eprintln!("Invalid --shutdown-timeout: {}", e);
+            std::process::exit(1);
+        });
+    }
+    if matches.is_present("rate-limit")
+        || matches.is_present("upload-limit")
+        || matches.is_present("client-rate-limits")
+    {
+        let parse = |name: &str| -> u32 {
+            matches
+                .value_of(name)
+                .unwrap_or("0")
+                .parse()
+                .unwrap_or_else(|e| {
+                    eprintln!("Invalid --{}: {}", name, e);
+                    std::process::exit(1);
+                })
+        };
+        let clients = parse_list(matches.value_of("client-rate-limits").unwrap_or(""));
+        builder
+            .with_rate_limits(parse("rate-limit"), parse("upload-limit"), clients)
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid --client-rate-limits: {}", e);
+                std::process::exit(1);
+            });
+    }
+    if matches.is_present("max-request-size")
+        || matches.is_present("max-header-size")
+        || matches.is_present("idle-timeout")
+        || matches.is_present("transfer-timeout")
+    {
+        let parse = |name: &str, default: u32| -> u32 {
+            matches.value_of(name).map_or(default, |x| {
+                x.parse().unwrap_or_else(|e| {
+                    eprintln!("Invalid --{}: {}", name, e);
+                    std::process::exit(1);
+                })
+            })
+        };
+        builder
+            .with_request_limits(
+                parse("max-request-size", 1),
+                parse("max-header-size", 32),
+                matches.value_of("idle-timeout").unwrap_or("60s"),
+                matches.value_of("transfer-timeout").unwrap_or("0"),
+            )
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid --idle-timeout or --transfer-timeout: {}", e);
+                std::process::exit(1);
+            });
+    }
+    if let Some(size) = matches.value_of("upload-buffer-size") {
+        let size = size.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid --upload-buffer-size: {}", e);
+            std::process::exit(1);
+        });
+        builder.with_upload_buffer_size(size);
+    }
+    if let Some(max_keys) = matches.value_of("idempotency-keys") {
+        let max_keys = max_keys.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid --idempotency-keys: {}", e);
+            std::process::exit(1);
+        });
+        builder.with_idempotency_keys(max_keys);
+    }
+    if matches.is_present("watch-data-dir") {
+        builder.with_data_dir_watch();
+    }
+    if matches.is_present("web-ui") {
+        builder.with_web_ui();
+    }
+    if matches.is_present("event-sinks") {
+        let sinks = parse_list(matches.value_of("event-sinks").unwrap_or(""));
+        let format = matches.value_of("event-format").unwrap_or("json");
+        builder.with_event_sinks(sinks, format.to_string());
+    }
+    if matches.is_present("quotas") {
+        builder.with_quotas(parse_list(matches.value_of("quotas").unwrap_or("")));
+    }
+    if let Some(db_path) = matches.value_of("metadata-db") {
+        builder.with_metadata_db(db_path.to_string());
+    }
+    if let Some(min_pulls) = matches.value_of("transcode-layers") {
+        let min_pulls = min_pulls.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid --transcode-layers: {}", e);
+            std::process::exit(1);
+        });
+        let interval = matches.value_of("transcode-interval").unwrap_or("1h");
+        builder.with_transcoding(
+            min_pulls,
+            interval.to_string(),
+            matches.is_present("transcode-on-demand"),
+        );
+    }
+    if matches.is_present("estargz") {
+        let interval = matches.value_of("estargz-interval").unwrap_or("1h");
+        builder.with_estargz(interval.to_string());
+    }
+    if matches.is_present("immutable-tags") {
+        builder.with_immutable_tags(parse_list(matches.value_of("immutable-tags").unwrap_or("")));
+    }
+    if matches.is_present("upstream-proxies") {
+        builder.with_upstream_proxies(parse_list(
+            matches.value_of("upstream-proxies").unwrap_or(""),
+        ));
+    }
+    if matches.is_present("freeze-windows") {
+        builder.with_freeze_windows(parse_list(matches.value_of("freeze-windows").unwrap_or("")));
+    }
+    if matches.is_present("policy-crd") {
+        builder.with_policy_crd();
+    }
+    if matches.is_present("require-existing-images") {
+        builder.with_existing_images_required();
+    }
+    if matches.is_present("retention") || matches.is_present("retention-interval") {
+        let rules = parse_list(matches.value_of("retention").unwrap_or(""));
+        let interval = matches.value_of("retention-interval").unwrap_or("24h");
+        builder.with_retention(rules, interval.to_string());
+    }
+    if let Some(dir) = matches.value_of("backup-dir") {
+        let interval = matches.value_of("backup-interval").unwrap_or("24h");
+        builder.with_backup(dir.to_string(), interval.to_string());
+    }
+    if matches.is_present("scrub-interval") || matches.is_present("scrub-quarantine") {
+        let interval = matches.value_of("scrub-interval").unwrap_or("0");
+        builder.with_scrub(interval.to_string(), matches.is_present("scrub-quarantine"));
+    }
+    if let Some(interval) = matches.value_of("usage-interval") {
+        builder.with_usage_interval(interval.to_string());
+    }
+    if let Some(ttl) = matches.value_of("upload-ttl") {
+        builder.with_upload_ttl(ttl.to_string());
+    }
+    if let Some(window) = matches.value_of("trash-retention") {
+        builder.with_trash_retention(window.to_string());
+    }
+    if let Some(url) = matches.value_of("storage") {
+        builder.with_storage(url.to_string());
+    }
+    if let Some(dir) = matches.value_of("scratch-dir") {
+        builder.with_scratch_dir(dir.to_string());
+    }
+    if let Some(reserve) = matches.value_of("storage-reserve") {
+        builder.with_storage_reserve(reserve.to_string());
+    }
+    if let Some(max_layers) = matches.value_of("max-layers") {
+        let max_layers = max_layers.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid --max-layers: {}", e);
+            std::process::exit(1);
+        });
+        builder.with_max_layers(max_layers);
+    }
+    if matches.is_present("spiffe-rules") || matches.is_present("spiffe-svid") {
+        let bundle = matches.value_of("spiffe-bundle").unwrap_or_else(|| {
+            eprintln!("--spiffe-bundle must be set to use SPIFFE");
+            std::process::exit(1);
+        });
+        let rules = parse_list(matches.value_of("spiffe-rules").unwrap_or(""));
+        let svid = matches.value_of("spiffe-svid").map(|cert| {
+            let key = matches.value_of("spiffe-svid-key").unwrap_or("");
+            (cert.to_string(), key.to_string())
+        });
+        builder
+            .with_spiffe(bundle.to_string(), rules, svid)
+            .unwrap_or_else(|e| {
+                eprintln!("Invalid SPIFFE configuration: {}", e);
+                std::process::exit(1);
+            });
+    }
+    if let (Some(ca), Some(cert), Some(key)) = (
+        matches.value_of("grpc-tls-ca"),
+        matches.value_of("grpc-tls-cert"),
+        matches.value_of("grpc-tls-key"),
+    ) {
+        builder.with_grpc_tls(
+            ca.to_string(),
+            cert.to_string(),
+            key.to_string(),
+            matches
+                .value_of("grpc-tls-server-name")
+                .map(|s| s.to_string()),
+        );
+    }
+    if matches.is_present("grpc-require-tls") {
+        builder.with_grpc_tls_required();
+    }
+    if let Some(listen) = matches.value_of("grpc-listen") {
+        builder.with_grpc_listen(listen.to_string());
+    }
+    if let Some(mode) = matches.value_of("grpc-socket-mode") {
+        builder.with_grpc_socket_mode(mode).unwrap_or_else(|e| {
+            eprintln!("Invalid --grpc-socket-mode: {}", e);
+            std::process::exit(1);
+        });
+    }
+    if let Some(address) = matches.value_of("backend-address") {
+        builder.with_backend_address(address.to_string());
+    }
+    if matches.is_present("standalone") {
+        builder.with_standalone_backend();
+    }
+    if matches.is_present("ha") {
+        builder.with_ha();
+    }
+    if matches.is_present("read-only") {
+        builder.with_read_only();
+    }
+    if let Some(path) = matches.value_of("audit-log") {
+        builder.with_audit_log(path.to_string());
+    }
+    if let Some(exporter) = matches.value_of("trace-exporter") {
+        let endpoint = matches.value_of("trace-endpoint").map(|e| e.to_string());
+        builder
+            .with_tracing(exporter, endpoint)
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+    }
+    if args.is_present("migrate") || args.is_present("verify-layout") {
+        builder
+            .migrate(!args.is_present("migrate"))
+            .unwrap_or_else(|e| {
+                eprintln!("Error migrating the data directory:\n\n{}", e);
+                std::process::exit(1);
+            });
+        return;
+    }
     builder.start().unwrap_or_else(|e| {
         eprintln!("Error launching Trow:\n\n{}", e);
         std::process::exit(1);
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::registry_interface::{Digest, ManifestReader};
+
+/*
+ * Read-through cache of manifests and the digests tags point at, so a pull storm, such as a
+ * deployment scaling up across many nodes, doesn't make a backend call and read a file for every
+ * manifest request.
+ *
+ * Entries are dropped when pushes and deletes through this frontend change them, and expire after
+ * the TTL to pick up changes made elsewhere, e.g. by another replica or the retention job. Tags in
+ * proxied repositories aren't cached, as pulling them checks upstream for a new version.
+ */
+
+const PROXY_PREFIX: &str = "f/";
+// Dropped when the cache gets this big, rather than tracking which entries are least used
+const MAX_ENTRIES: usize = 10_000;
+
+struct Entry<T> {
+    added: Instant,
+    value: T,
+}
+
+#[derive(Clone)]
+struct CachedManifest {
+    content_type: String,
+    digest: Digest,
+    bytes: Arc<[u8]>,
+}
+
+impl CachedManifest {
+    fn reader(&self) -> ManifestReader {
+        ManifestReader {
+            content_type: self.content_type.clone(),
+            digest: self.digest.clone(),
+            reader: Box::pin(Cursor::new(self.bytes.clone())),
+        }
+    }
+}
+
+// Repository name and tag or digest
+type Key = (String, String);
+
+fn key(repo_name: &str, reference: &str) -> Key {
+    (repo_name.to_string(), reference.to_string())
+}
+
+fn is_digest(reference: &str) -> bool {
+    reference.contains(':')
+}
+
+pub struct ManifestCache {
+    ttl: Duration,
+    // Digest each tag points at
+    tags: Mutex<HashMap<Key, Entry<String>>>,
+    manifests: Mutex<HashMap<Key, Entry<CachedManifest>>>,
+}
+
+fn get<T: Clone>(map: &Mutex<HashMap<Key, Entry<T>>>, key: &Key, ttl: Duration) -> Option<T> {
+    map.lock()
+        .unwrap()
+        .get(key)
+        .filter(|e| e.added.elapsed() < ttl)
+        .map(|e| e.value.clone())
+}
+
+fn put<T>(map: &Mutex<HashMap<Key, Entry<T>>>, key: Key, value: T, ttl: Duration) {
+    let mut map = map.lock().unwrap();
+    if map.len() >= MAX_ENTRIES {
+        map.retain(|_, e| e.added.elapsed() < ttl);
+        if map.len() >= MAX_ENTRIES {
+            map.clear();
+        }
+    }
+    map.insert(
+        key,
+        Entry {
+            added: Instant::now(),
+            value,
+        },
+    );
+}
+
+impl ManifestCache {
+    pub fn new(ttl: Duration) -> ManifestCache {
+        ManifestCache {
+            ttl,
+            tags: Mutex::new(HashMap::new()),
+            manifests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, repo_name: &str, reference: &str) -> Option<ManifestReader> {
+        let digest = if is_digest(reference) {
+            reference.to_string()
+        } else {
+            get(&self.tags, &key(repo_name, reference), self.ttl)?
+        };
+        get(&self.manifests, &key(repo_name, &digest), self.ttl).map(|m| m.reader())
+    }
+
+    /// Adds the manifest read from the backend, returning a reader for it
+    pub fn insert(
+        &self,
+        repo_name: &str,
+        reference: &str,
+        content_type: String,
+        digest: Digest,
+        bytes: Vec<u8>,
+    ) -> ManifestReader {
+        let manifest = CachedManifest {
+            content_type,
+            digest,
+            bytes: bytes.into(),
+        };
+        let digest = manifest.digest.to_string();
+        if !is_digest(reference) && !repo_name.starts_with(PROXY_PREFIX) {
+            put(
+                &self.tags,
+                key(repo_name, reference),
+                digest.clone(),
+                self.ttl,
+            );
+        }
+        let reader = manifest.reader();
+        put(&self.manifests, key(repo_name, &digest), manifest, self.ttl);
+        reader
+    }
+
+    /// After a push, which can change a tag but not what a digest refers to
+    pub fn invalidate_tag(&self, repo_name: &str, reference: &str) {
+        self.tags.lock().unwrap().remove(&key(repo_name, reference));
+    }
+
+    /// After deleting a manifest, along with the tags pointing at it
+    pub fn invalidate_manifest(&self, repo_name: &str, digest: &Digest) {
+        let digest = digest.to_string();
+        self.tags
+            .lock()
+            .unwrap()
+            .retain(|(repo, _), e| repo != repo_name || e.value != digest);
+        self.manifests
+            .lock()
+            .unwrap()
+            .remove(&key(repo_name, &digest));
+    }
+
+    pub fn invalidate_repo(&self, repo_name: &str) {
+        self.tags
+            .lock()
+            .unwrap()
+            .retain(|(repo, _), _| repo != repo_name);
+        self.manifests
+            .lock()
+            .unwrap()
+            .retain(|(repo, _), _| repo != repo_name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ManifestCache;
+    use crate::registry_interface::digest;
+    use std::time::Duration;
+
+    const DIGEST: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn cache_manifest(cache: &ManifestCache, repo_name: &str, tag: &str) {
+        cache.insert(
+            repo_name,
+            tag,
+            "application/vnd.oci.image.manifest.v1+json".to_string(),
+            digest::parse(DIGEST).unwrap(),
+            b"{}".to_vec(),
+        );
+    }
+
+    #[test]
+    fn read_through() {
+        let cache = ManifestCache::new(Duration::from_secs(60));
+        assert!(cache.get("myorg/app", "v1").is_none());
+
+        cache_manifest(&cache, "myorg/app", "v1");
+        let mr = cache.get("myorg/app", "v1").unwrap();
+        assert_eq!(mr.digest().to_string(), DIGEST);
+        assert!(cache.get("myorg/app", DIGEST).is_some());
+        assert!(cache.get("myorg/other", DIGEST).is_none());
+
+        // Pulls of proxied tags check upstream
+        cache_manifest(&cache, "f/docker/nginx", "latest");
+        assert!(cache.get("f/docker/nginx", "latest").is_none());
+        assert!(cache.get("f/docker/nginx", DIGEST).is_some());
+    }
+
+    #[test]
+    fn invalidation() {
+        let cache = ManifestCache::new(Duration::from_secs(60));
+        cache_manifest(&cache, "myorg/app", "v1");
+        cache.invalidate_tag("myorg/app", "v1");
+        assert!(cache.get("myorg/app", "v1").is_none());
+        assert!(cache.get("myorg/app", DIGEST).is_some());
+
+        cache_manifest(&cache, "myorg/app", "v1");
+        cache.invalidate_manifest("myorg/app", &digest::parse(DIGEST).unwrap());
+        assert!(cache.get("myorg/app", "v1").is_none());
+        assert!(cache.get("myorg/app", DIGEST).is_none());
+
+        cache_manifest(&cache, "myorg/app", "v1");
+        cache.invalidate_repo("myorg/app");
+        assert!(cache.get("myorg/app", "v1").is_none());
+
+        let cache = ManifestCache::new(Duration::ZERO);
+        cache_manifest(&cache, "myorg/app", "v1");
+        assert!(cache.get("myorg/app", "v1").is_none());
+    }
+}
@@ -0,0 +1,175 @@
+use log::warn;
+use rocket::data::Data;
+use rocket::http::Method;
+use rocket::request::Request;
+use rocket::route::{Handler, Outcome, Route};
+
+use crate::registry_interface::Reference;
+use crate::response::errors::Error;
+use trow_server::names::{is_valid_repo_name, is_valid_upload_id};
+
+/*
+ * Checks the repository names, tags, digests and upload ids in a request against the grammar
+ * the distribution spec gives them, before a route gets to use them.
+ *
+ * Path segments are percent-decoded, so one segment can hold a `/` and `..` (e.g. `..%2F..`).
+ * Names built from segments that pass can't, as each component is lowercase letters and digits
+ * with single separators between them. The grammar is the backend's, see
+ * trow-server/src/names.rs, which checks everything again before building a path from it.
+ *
+ * Under /v2/ the name is everything before blobs/<digest>, blobs/uploads[/<uuid>],
+ * manifests/<reference>, referrers/<digest> or tags/list. Elsewhere only the repo, reference and
+ * tag query parameters are checked, and routes taking a repository as the rest of the path check
+ * it themselves. Digests of blobs are left to the routes, which treat an invalid one as unknown.
+ */
+
+fn check_name(segments: &[&str]) -> Result<(), Error> {
+    let name = segments.join("/");
+    if is_valid_repo_name(&name) {
+        Ok(())
+    } else {
+        Err(Error::NameInvalid(name))
+    }
+}
+
+fn check_v2(method: Method, segments: &[&str]) -> Result<(), Error> {
+    match segments {
+        [name @ .., "blobs", "uploads"] => check_name(name),
+        [name @ .., "blobs", "uploads", uuid] => {
+            check_name(name)?;
+            if is_valid_upload_id(uuid) {
+                Ok(())
+            } else {
+                Err(Error::BlobUploadUnknown)
+            }
+        }
+        [name @ .., "manifests", reference] => {
+            check_name(name)?;
+            match reference.parse::<Reference>() {
+                Ok(_) => Ok(()),
+                Err(e) if method == Method::Put => Err(Error::TagInvalid(e.to_string())),
+                Err(_) => Err(Error::ManifestUnknown(reference.to_string())),
+            }
+        }
+        [name @ .., "blobs" | "referrers", _] | [name @ .., "tags", "list"] => check_name(name),
+        _ => Ok(()),
+    }
+}
+
+fn check_query(req: &Request<'_>) -> Result<(), Error> {
+    let value = |field: &str| req.query_value::<&str>(field).and_then(|v| v.ok());
+    if let Some(repo) = value("repo") {
+        if !is_valid_repo_name(repo) {
+            return Err(Error::NameInvalid(repo.to_string()));
+        }
+    }
+    if let Some(reference) = value("reference") {
+        reference
+            .parse::<Reference>()
+            .map_err(|e| Error::TagInvalid(e.to_string()))?;
+    }
+    if let Some(tag) = value("tag") {
+        match tag.parse::<Reference>() {
+            Ok(Reference::Tag(_)) => {}
+            _ => return Err(Error::TagInvalid(format!("`{}` is not a valid tag", tag))),
+        }
+    }
+    Ok(())
+}
+
+fn check_request(req: &Request<'_>) -> Result<(), Error> {
+    check_query(req)?;
+    let segments: Vec<&str> = req.uri().path().segments().collect();
+    match segments.split_first() {
+        Some((&"v2", rest)) => check_v2(req.method(), rest),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Clone)]
+struct WithNameChecks {
+    handler: Box<dyn Handler>,
+}
+
+#[rocket::async_trait]
+impl Handler for WithNameChecks {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        match check_request(req) {
+            Ok(()) => self.handler.handle(req, data).await,
+            Err(e) => {
+                warn!(
+                    "Refused {} {} with an invalid name: {}",
+                    req.method(),
+                    req.uri().path(),
+                    e
+                );
+                Outcome::from(req, e)
+            }
+        }
+    }
+}
+
+/// Refuses requests to the routes with invalid repository names, tags, digests or upload ids
+pub fn with_name_checks(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(WithNameChecks {
+                handler: route.handler,
+            });
+            route
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::check_v2;
+    use crate::response::errors::Error;
+    use rocket::http::Method;
+
+    const DIGEST: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn check(method: Method, path: &str) -> Result<(), Error> {
+        let segments: Vec<&str> = path.split('/').collect();
+        check_v2(method, &segments)
+    }
+
+    #[test]
+    fn checks_v2_paths() {
+        let blob = format!("org/app/blobs/{}", DIGEST);
+        assert!(check(Method::Get, &blob).is_ok());
+        assert!(check(Method::Get, "org/app/tags/list").is_ok());
+        assert!(check(Method::Put, "org/app/manifests/v1.0").is_ok());
+        assert!(check(Method::Post, "org/app/blobs/uploads").is_ok());
+        assert!(check(
+            Method::Patch,
+            "org/app/blobs/uploads/7f4ad8e1-26c5-4d2b-9a0b-3c4e8b1f2a60"
+        )
+        .is_ok());
+
+        // A decoded %2F puts a slash inside a segment
+        assert!(matches!(
+            check(Method::Get, "../../etc/blobs/sha256:abc"),
+            Err(Error::NameInvalid(_))
+        ));
+        let segments = ["..%2F..".replace("%2F", "/"), "tags".into(), "list".into()];
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        assert!(matches!(
+            check_v2(Method::Get, &segments),
+            Err(Error::NameInvalid(_))
+        ));
+        assert!(matches!(
+            check(Method::Patch, "org/app/blobs/uploads/.."),
+            Err(Error::BlobUploadUnknown)
+        ));
+        assert!(matches!(
+            check(Method::Get, "org/app/manifests/.."),
+            Err(Error::ManifestUnknown(_))
+        ));
+        assert!(matches!(
+            check(Method::Put, "org/app/manifests/-latest"),
+            Err(Error::TagInvalid(_))
+        ));
+    }
+}
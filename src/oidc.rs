@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use rocket::tokio::sync::RwLock;
+use serde::Deserialize;
+use serde_json::Value;
+use trow_server::egress::EgressProxies;
+
+use crate::spiffe::Permission;
+
+/*
+ * Accepting ID tokens from an external OpenID Connect provider, such as Keycloak or Dex, so
+ * users don't need a separate Trow password.
+ *
+ * Tokens are checked against the provider's signing keys (its JWKS), found through the issuer's
+ * discovery document. The keys are cached and fetched again every hour, or sooner when a token is
+ * signed with a key we haven't seen, as happens when the provider rotates keys.
+ *
+ * What a user can do comes from the groups in their token, mapped by rules of the form
+ * GROUP=pull|push|delete. * matches any user with a valid token. The most permissive matching
+ * rule applies, and users matching none are refused.
+ *
+ * Users are known as oidc:<sub>. Claims such as preferred_username can often be set by the users
+ * themselves, and the prefix keeps them from ever matching a local user such as the --user admin.
+ */
+
+const JWKS_REFRESH: Duration = Duration::from_secs(60 * 60);
+// Limits refetching for unknown keys, so bad tokens can't be used to hammer the provider
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(60);
+// Allowed clock difference with the provider
+const LEEWAY_SECS: u64 = 60;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoleRule {
+    group: String,
+    permission: Permission,
+}
+
+impl FromStr for RoleRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (group, permission) = s.rsplit_once('=').ok_or_else(|| {
+            anyhow!(
+                "OIDC role {} should be of the form GROUP=pull|push|delete",
+                s
+            )
+        })?;
+        let permission = permission
+            .parse()
+            .map_err(|e| anyhow!("{} in OIDC role {}", e, s))?;
+        if group.is_empty() {
+            return Err(anyhow!("OIDC role {} has no group", s));
+        }
+        Ok(RoleRule {
+            group: group.to_string(),
+            permission,
+        })
+    }
+}
+
+impl fmt::Display for RoleRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.group, self.permission)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    pub issuer: String,
+    // Client ID the tokens must be issued for
+    pub audience: String,
+    // Claim listing the user's groups
+    pub groups_claim: String,
+    pub roles: Vec<RoleRule>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OidcIdentity {
+    pub user: String,
+    pub permission: Permission,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+fn b64(s: &str) -> Result<Vec<u8>> {
+    base64::decode_config(s.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|e| anyhow!("Invalid base64url: {}", e))
+}
+
+fn bignum(field: &Option<String>) -> Result<BigNum> {
+    let bytes = b64(field
+        .as_deref()
+        .ok_or_else(|| anyhow!("Missing JWK field"))?)?;
+    Ok(BigNum::from_slice(&bytes)?)
+}
+
+impl Jwk {
+    fn public_key(&self) -> Result<PKey<Public>> {
+        match self.kty.as_str() {
+            "RSA" => Ok(PKey::from_rsa(Rsa::from_public_components(
+                bignum(&self.n)?,
+                bignum(&self.e)?,
+            )?)?),
+            "EC" => {
+                let nid = match self.crv.as_deref() {
+                    Some("P-256") => Nid::X9_62_PRIME256V1,
+                    Some("P-384") => Nid::SECP384R1,
+                    crv => return Err(anyhow!("Unsupported EC curve {:?}", crv)),
+                };
+                let group = EcGroup::from_curve_name(nid)?;
+                let key = EcKey::from_public_key_affine_coordinates(
+                    &group,
+                    &bignum(&self.x)?,
+                    &bignum(&self.y)?,
+                )?;
+                Ok(PKey::from_ec_key(key)?)
+            }
+            kty => Err(anyhow!("Unsupported key type {}", kty)),
+        }
+    }
+}
+
+struct Jwks {
+    keys: HashMap<String, PKey<Public>>,
+    fetched: Instant,
+}
+
+// Keys without an ID are stored under "", used for tokens without a kid
+fn parse_jwks(set: JwkSet) -> HashMap<String, PKey<Public>> {
+    set.keys
+        .iter()
+        .filter(|k| k.key_use.as_deref().unwrap_or("sig") == "sig")
+        .filter_map(|k| match k.public_key() {
+            Ok(key) => Some((k.kid.clone().unwrap_or_default(), key)),
+            Err(e) => {
+                warn!("Skipping OIDC signing key {:?}: {}", k.kid, e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn verify_signature(alg: &str, key: &PKey<Public>, signed: &[u8], sig: &[u8]) -> Result<()> {
+    let (digest, ec_len) = match alg {
+        "RS256" => (MessageDigest::sha256(), None),
+        "RS384" => (MessageDigest::sha384(), None),
+        "RS512" => (MessageDigest::sha512(), None),
+        "ES256" => (MessageDigest::sha256(), Some(32)),
+        "ES384" => (MessageDigest::sha384(), Some(48)),
+        _ => return Err(anyhow!("Unsupported token algorithm {}", alg)),
+    };
+    // JWS ECDSA signatures are r and s concatenated, OpenSSL wants DER
+    let der;
+    let sig = match ec_len {
+        Some(len) => {
+            if sig.len() != len * 2 {
+                return Err(anyhow!("Invalid {} signature length", alg));
+            }
+            let r = BigNum::from_slice(&sig[..len])?;
+            let s = BigNum::from_slice(&sig[len..])?;
+            der = EcdsaSig::from_private_components(r, s)?.to_der()?;
+            &der[..]
+        }
+        None => sig,
+    };
+    let mut verifier = Verifier::new(digest, key)?;
+    verifier.update(signed)?;
+    if verifier.verify(sig)? {
+        Ok(())
+    } else {
+        Err(anyhow!("Invalid token signature"))
+    }
+}
+
+fn check_claims(claims: &Value, issuer: &str, audience: &str, now: u64) -> Result<()> {
+    let iss = claims["iss"].as_str().unwrap_or_default();
+    if iss.trim_end_matches('/') != issuer.trim_end_matches('/') {
+        return Err(anyhow!("Token issued by {}, expected {}", iss, issuer));
+    }
+    let aud_ok = match &claims["aud"] {
+        Value::String(aud) => aud == audience,
+        Value::Array(auds) => auds.iter().any(|a| a.as_str() == Some(audience)),
+        _ => false,
+    };
+    if !aud_ok {
+        return Err(anyhow!("Token not issued for {}", audience));
+    }
+    let exp = claims["exp"]
+        .as_u64()
+        .ok_or_else(|| anyhow!("Token has no expiry"))?;
+    if exp + LEEWAY_SECS < now {
+        return Err(anyhow!("Token expired"));
+    }
+    if let Some(nbf) = claims["nbf"].as_u64() {
+        if nbf > now + LEEWAY_SECS {
+            return Err(anyhow!("Token not yet valid"));
+        }
+    }
+    Ok(())
+}
+
+fn user_name(claims: &Value) -> Result<String> {
+    let sub = claims["sub"]
+        .as_str()
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| anyhow!("Token has no subject"))?;
+    Ok(format!("oidc:{}", sub))
+}
+
+fn groups(claims: &Value, groups_claim: &str) -> Vec<String> {
+    match &claims[groups_claim] {
+        Value::Array(groups) => groups
+            .iter()
+            .filter_map(|g| g.as_str().map(|g| g.to_string()))
+            .collect(),
+        Value::String(group) => vec![group.clone()],
+        _ => vec![],
+    }
+}
+
+fn permission(groups: &[String], roles: &[RoleRule]) -> Option<Permission> {
+    roles
+        .iter()
+        .filter(|r| r.group == "*" || groups.contains(&r.group))
+        .map(|r| r.permission)
+        .max()
+}
+
+pub struct OidcVerifier {
+    config: OidcConfig,
+    client: reqwest::Client,
+    jwks: RwLock<Option<Jwks>>,
+}
+
+impl fmt::Debug for OidcVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OidcVerifier")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl OidcVerifier {
+    pub fn new(config: OidcConfig) -> Result<OidcVerifier> {
+        // Honours the proxy environment variables
+        let client = reqwest::Client::builder()
+            .proxy(EgressProxies::new(vec![]).proxy())
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(OidcVerifier {
+            config,
+            client,
+            jwks: RwLock::new(None),
+        })
+    }
+
+    pub fn config(&self) -> &OidcConfig {
+        &self.config
+    }
+
+    async fn fetch_jwks(&self) -> Result<Jwks> {
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches('/')
+        );
+        let discovery: Discovery = self
+            .client
+            .get(&discovery_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let set: JwkSet = self
+            .client
+            .get(&discovery.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let keys = parse_jwks(set);
+        info!(
+            "Fetched {} OIDC signing keys from {}",
+            keys.len(),
+            discovery.jwks_uri
+        );
+        Ok(Jwks {
+            keys,
+            fetched: Instant::now(),
+        })
+    }
+
+    async fn key(&self, kid: &str) -> Result<PKey<Public>> {
+        {
+            let jwks = self.jwks.read().await;
+            if let Some(ref jwks) = *jwks {
+                let fresh = jwks.fetched.elapsed() < JWKS_REFRESH;
+                let recent = jwks.fetched.elapsed() < JWKS_MIN_REFRESH;
+                match jwks.keys.get(kid) {
+                    Some(key) if fresh => return Ok(key.clone()),
+                    None if recent => return Err(anyhow!("Unknown signing key {}", kid)),
+                    _ => (),
+                }
+            }
+        }
+
+        let mut jwks = self.jwks.write().await;
+        // Another request may have refreshed the keys while we waited
+        let stale = match *jwks {
+            Some(ref j) => j.fetched.elapsed() >= JWKS_MIN_REFRESH,
+            None => true,
+        };
+        if stale {
+            match self.fetch_jwks().await {
+                Ok(fetched) => *jwks = Some(fetched),
+                // Keep using the keys we have if the provider is down
+                Err(e) => warn!("Failed to fetch OIDC signing keys: {}", e),
+            }
+        }
+        jwks.as_ref()
+            .and_then(|j| j.keys.get(kid).cloned())
+            .ok_or_else(|| anyhow!("Unknown signing key {}", kid))
+    }
+
+    /// Checks the token and returns who it's for and what they can do
+    pub async fn verify(&self, token: &str) -> Result<OidcIdentity> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(anyhow!("Not a JWT"));
+        }
+        let header: Value = serde_json::from_slice(&b64(parts[0])?)?;
+        let alg = header["alg"].as_str().unwrap_or_default();
+        let kid = header["kid"].as_str().unwrap_or_default();
+
+        let key = self.key(kid).await?;
+        let signed = format!("{}.{}", parts[0], parts[1]);
+        verify_signature(alg, &key, signed.as_bytes(), &b64(parts[2])?)?;
+
+        let claims: Value = serde_json::from_slice(&b64(parts[1])?)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        check_claims(&claims, &self.config.issuer, &self.config.audience, now)?;
+
+        let user = user_name(&claims)?;
+        let groups = groups(&claims, &self.config.groups_claim);
+        let permission = permission(&groups, &self.config.roles)
+            .ok_or_else(|| anyhow!("No OIDC role for {} with groups {:?}", user, groups))?;
+        Ok(OidcIdentity { user, permission })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_claims, groups, permission, user_name, verify_signature, RoleRule};
+    use crate::spiffe::Permission;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::hash::{hash, MessageDigest};
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use serde_json::json;
+
+    #[test]
+    fn parse_roles() {
+        let rule: RoleRule = "registry-admins=delete".parse().unwrap();
+        assert_eq!(rule.to_string(), "registry-admins=delete");
+        assert!("ci=admin".parse::<RoleRule>().is_err());
+        assert!("=pull".parse::<RoleRule>().is_err());
+        assert!("ci".parse::<RoleRule>().is_err());
+    }
+
+    #[test]
+    fn most_permissive_role_applies() {
+        let roles: Vec<RoleRule> = ["*=pull", "ci=push", "admins=delete"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        let claims = json!({"groups": ["ci", "developers"]});
+        assert_eq!(
+            permission(&groups(&claims, "groups"), &roles),
+            Some(Permission::Push)
+        );
+        assert_eq!(permission(&[], &roles), Some(Permission::Pull));
+        assert_eq!(
+            permission(&["ci".to_string()], &roles[1..]),
+            Some(Permission::Push)
+        );
+        assert_eq!(permission(&[], &roles[1..]), None);
+    }
+
+    #[test]
+    fn names_users_by_subject() {
+        let claims = json!({"sub": "8a1f3c", "preferred_username": "admin"});
+        assert_eq!(user_name(&claims).unwrap(), "oidc:8a1f3c");
+        assert!(user_name(&json!({"preferred_username": "admin"})).is_err());
+        assert!(user_name(&json!({"sub": ""})).is_err());
+    }
+
+    #[test]
+    fn checks_claims() {
+        let claims = json!({
+            "iss": "https://dex.example.com/",
+            "aud": ["trow", "other"],
+            "exp": 1000,
+        });
+        assert!(check_claims(&claims, "https://dex.example.com", "trow", 900).is_ok());
+        assert!(check_claims(&claims, "https://dex.example.com", "nope", 900).is_err());
+        assert!(check_claims(&claims, "https://evil.example.com", "trow", 900).is_err());
+        assert!(check_claims(&claims, "https://dex.example.com", "trow", 2000).is_err());
+    }
+
+    #[test]
+    fn verifies_es256() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let private = EcKey::generate(&group).unwrap();
+        let public =
+            PKey::from_ec_key(EcKey::from_public_key(&group, private.public_key()).unwrap())
+                .unwrap();
+
+        let signed = b"header.payload";
+        let digest = hash(MessageDigest::sha256(), signed).unwrap();
+        let sig = EcdsaSig::sign(&digest, &private).unwrap();
+        let mut jws = sig.r().to_vec_padded(32).unwrap();
+        jws.extend(sig.s().to_vec_padded(32).unwrap());
+
+        assert!(verify_signature("ES256", &public, signed, &jws).is_ok());
+        assert!(verify_signature("ES256", &public, b"header.tampered", &jws).is_err());
+        assert!(verify_signature("HS256", &public, signed, &jws).is_err());
+    }
+}
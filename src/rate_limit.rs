@@ -0,0 +1,394 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::warn;
+use prometheus::{opts, register_int_counter_vec, IntCounterVec};
+use rocket::data::Data;
+use rocket::http::Method;
+use rocket::outcome::Outcome as RocketOutcome;
+use rocket::request::Request;
+use rocket::route::{Handler, Outcome, Route};
+use serde::{Deserialize, Serialize};
+
+use crate::response::errors::Error;
+use crate::response::trow_token::TrowToken;
+
+/*
+ * Per client rate limits, so a CI farm hammering pushes can't starve everyone else of the backend.
+ *
+ * Each client gets a number of requests per second, with bursts of up to a second's worth, and a
+ * number of blob uploads in progress at once. Clients are told by the user they logged in as, or
+ * their IP address if they didn't. Limits can be set for particular users or IP addresses, with
+ * the default limits applying to the rest. Requests over a limit get a 429 with a Retry-After
+ * header, which Docker and containerd honour.
+ *
+ * The limits can be changed through the admin API without a restart. Health, readiness and
+ * metrics requests aren't limited, so probes and scrapes keep working. Counts are held in
+ * memory, so each replica limits separately.
+ */
+
+// Forget clients that have been idle this long once there are too many to track
+const MAX_CLIENTS: usize = 10_000;
+const IDLE_CLIENT: Duration = Duration::from_secs(60);
+const UNLIMITED_PATHS: [&str; 3] = ["/healthz", "/readiness", "/metrics"];
+
+lazy_static! {
+    pub static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "rate_limited_requests_total",
+            "total number of requests refused with 429, by the limit they went over"
+        ),
+        &["limit"]
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    // 0 for no limit
+    #[serde(default)]
+    pub requests_per_sec: u32,
+    // Blob upload requests in progress at once, 0 for no limit
+    #[serde(default)]
+    pub concurrent_uploads: u32,
+}
+
+impl Limits {
+    fn is_unlimited(&self) -> bool {
+        self.requests_per_sec == 0 && self.concurrent_uploads == 0
+    }
+}
+
+/*
+ * The limits as set with --rate-limit, --upload-limit and --client-rate-limits, and read and
+ * replaced through /api/v1/rate-limits.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    // Applied to each client not listed in clients
+    #[serde(default)]
+    pub default: Limits,
+    // By user name or IP address
+    #[serde(default)]
+    pub clients: BTreeMap<String, Limits>,
+}
+
+impl RateLimitConfig {
+    fn is_unlimited(&self) -> bool {
+        self.default.is_unlimited() && self.clients.values().all(|l| l.is_unlimited())
+    }
+}
+
+/*
+ * Parses limits for a client, as CLIENT=REQUESTS_PER_SEC/CONCURRENT_UPLOADS where either can be
+ * 0 for no limit, e.g. ci-bot=100/8 or 10.0.3.7=5/1.
+ */
+pub fn parse_client_limits(s: &str) -> Result<(String, Limits)> {
+    let (client, limits) = s.split_once('=').ok_or_else(|| {
+        anyhow!(
+            "Expected CLIENT=REQUESTS_PER_SEC/CONCURRENT_UPLOADS in {}",
+            s
+        )
+    })?;
+    let (rps, uploads) = limits
+        .split_once('/')
+        .ok_or_else(|| anyhow!("Expected REQUESTS_PER_SEC/CONCURRENT_UPLOADS in {}", s))?;
+    if client.is_empty() {
+        return Err(anyhow!("No client in {}", s));
+    }
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<u32>()
+            .map_err(|e| anyhow!("Invalid limit {} in {}: {}", n, s, e))
+    };
+    Ok((
+        client.trim().to_string(),
+        Limits {
+            requests_per_sec: parse(rps)?,
+            concurrent_uploads: parse(uploads)?,
+        },
+    ))
+}
+
+struct ClientState {
+    // Requests that can be made now, topped up at requests_per_sec
+    tokens: f64,
+    updated: Instant,
+    uploads: u32,
+}
+
+#[derive(Debug, PartialEq)]
+enum Refusal {
+    // Seconds until the client can try again
+    Rate(u64),
+    Uploads,
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    config: RwLock<RateLimitConfig>,
+    clients: Mutex<HashMap<String, ClientState>>,
+}
+
+impl std::fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("config", &self.config())
+            .finish()
+    }
+}
+
+// Released when the upload request finishes
+pub struct UploadPermit {
+    limiter: Arc<RateLimiter>,
+    client: String,
+}
+
+impl Drop for UploadPermit {
+    fn drop(&mut self) {
+        if let Some(state) = self.limiter.clients.lock().unwrap().get_mut(&self.client) {
+            state.uploads = state.uploads.saturating_sub(1);
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config: RwLock::new(config),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> RateLimitConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Replaces the limits, which apply from the next request
+    pub fn set_config(&self, config: RateLimitConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.config.read().unwrap().is_unlimited()
+    }
+
+    fn limits_for(&self, user: Option<&str>, ip: Option<&str>) -> Limits {
+        let config = self.config.read().unwrap();
+        user.and_then(|u| config.clients.get(u))
+            .or_else(|| ip.and_then(|i| config.clients.get(i)))
+            .copied()
+            .unwrap_or(config.default)
+    }
+
+    /*
+     * Counts a request from the client against its limits, returning a permit to hold until the
+     * request finishes if it's an upload.
+     */
+    fn check(
+        self: &Arc<Self>,
+        client: &str,
+        limits: Limits,
+        is_upload: bool,
+        now: Instant,
+    ) -> std::result::Result<Option<UploadPermit>, Refusal> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_CLIENTS {
+            clients.retain(|_, s| s.uploads > 0 || now.duration_since(s.updated) < IDLE_CLIENT);
+        }
+        let rate = limits.requests_per_sec as f64;
+        let state = clients
+            .entry(client.to_string())
+            .or_insert_with(|| ClientState {
+                tokens: rate,
+                updated: now,
+                uploads: 0,
+            });
+
+        if limits.requests_per_sec > 0 {
+            let elapsed = now.duration_since(state.updated).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * rate).min(rate);
+            state.updated = now;
+            if state.tokens < 1.0 {
+                let wait = ((1.0 - state.tokens) / rate).ceil().max(1.0);
+                return Err(Refusal::Rate(wait as u64));
+            }
+        }
+        if is_upload && limits.concurrent_uploads > 0 && state.uploads >= limits.concurrent_uploads
+        {
+            return Err(Refusal::Uploads);
+        }
+        if limits.requests_per_sec > 0 {
+            state.tokens -= 1.0;
+        }
+        if !is_upload {
+            return Ok(None);
+        }
+        state.uploads += 1;
+        Ok(Some(UploadPermit {
+            limiter: self.clone(),
+            client: client.to_string(),
+        }))
+    }
+}
+
+fn is_upload(req: &Request<'_>) -> bool {
+    matches!(req.method(), Method::Post | Method::Patch | Method::Put)
+        && req.uri().path().as_str().contains("/blobs/uploads")
+}
+
+#[derive(Clone)]
+struct WithRateLimit {
+    handler: Box<dyn Handler>,
+    limiter: Arc<RateLimiter>,
+}
+
+#[rocket::async_trait]
+impl Handler for WithRateLimit {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        if self.limiter.is_unlimited() {
+            return self.handler.handle(req, data).await;
+        }
+
+        // Authentication is cached for the request, so the route doesn't repeat it
+        let user = match req.guard::<TrowToken>().await {
+            RocketOutcome::Success(t) if t.user != "none" && t.user != "anonymous" => Some(t.user),
+            _ => None,
+        };
+        let ip = req.client_ip().map(|ip| ip.to_string());
+        let client = match (&user, &ip) {
+            (Some(user), _) => format!("user:{}", user),
+            (None, Some(ip)) => format!("ip:{}", ip),
+            (None, None) => "unknown".to_string(),
+        };
+        let limits = self.limiter.limits_for(user.as_deref(), ip.as_deref());
+
+        match self
+            .limiter
+            .check(&client, limits, is_upload(req), Instant::now())
+        {
+            Ok(_permit) => self.handler.handle(req, data).await,
+            Err(refusal) => {
+                let (limit, retry_after) = match refusal {
+                    Refusal::Rate(secs) => ("requests", secs),
+                    Refusal::Uploads => ("uploads", 1),
+                };
+                warn!(
+                    "Rate limited {} {} from {}, over the {} limit",
+                    req.method(),
+                    req.uri().path(),
+                    client,
+                    limit
+                );
+                RATE_LIMITED.with_label_values(&[limit]).inc();
+                Outcome::from(req, Error::TooManyRequests(retry_after))
+            }
+        }
+    }
+}
+
+/// Applies the limiter's rate limits to the routes, apart from probes and metrics
+pub fn with_rate_limits(routes: Vec<Route>, limiter: Arc<RateLimiter>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            if !UNLIMITED_PATHS.contains(&route.uri.path().as_str()) {
+                route.handler = Box::new(WithRateLimit {
+                    handler: route.handler,
+                    limiter: limiter.clone(),
+                });
+            }
+            route
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_client_limits, Limits, RateLimitConfig, RateLimiter, Refusal};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn limits(requests_per_sec: u32, concurrent_uploads: u32) -> Limits {
+        Limits {
+            requests_per_sec,
+            concurrent_uploads,
+        }
+    }
+
+    #[test]
+    fn parses_client_limits() {
+        assert_eq!(
+            parse_client_limits("ci-bot=100/8").unwrap(),
+            ("ci-bot".to_string(), limits(100, 8))
+        );
+        assert_eq!(
+            parse_client_limits("10.0.3.7=0/1").unwrap(),
+            ("10.0.3.7".to_string(), limits(0, 1))
+        );
+        assert!(parse_client_limits("ci-bot=100").is_err());
+        assert!(parse_client_limits("=1/1").is_err());
+        assert!(parse_client_limits("ci-bot=fast/1").is_err());
+    }
+
+    #[test]
+    fn client_limits_override_default() {
+        let mut config = RateLimitConfig {
+            default: limits(10, 2),
+            ..Default::default()
+        };
+        config.clients.insert("ci-bot".to_string(), limits(100, 8));
+        config.clients.insert("10.0.3.7".to_string(), limits(1, 1));
+        let limiter = RateLimiter::new(config);
+
+        assert_eq!(limiter.limits_for(Some("ci-bot"), None), limits(100, 8));
+        assert_eq!(
+            limiter.limits_for(Some("ci-bot"), Some("10.0.3.7")),
+            limits(100, 8)
+        );
+        assert_eq!(
+            limiter.limits_for(Some("alice"), Some("10.0.3.7")),
+            limits(1, 1)
+        );
+        assert_eq!(limiter.limits_for(None, Some("10.0.0.1")), limits(10, 2));
+    }
+
+    #[test]
+    fn limits_request_rate() {
+        let limiter = Arc::new(RateLimiter::default());
+        let start = Instant::now();
+        for _ in 0..2 {
+            assert!(limiter.check("ip:a", limits(2, 0), false, start).is_ok());
+        }
+        assert_eq!(
+            limiter.check("ip:a", limits(2, 0), false, start).err(),
+            Some(Refusal::Rate(1))
+        );
+        // Other clients have their own allowance
+        assert!(limiter.check("ip:b", limits(2, 0), false, start).is_ok());
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check("ip:a", limits(2, 0), false, later).is_ok());
+        assert!(limiter.check("ip:a", limits(2, 0), false, later).is_err());
+    }
+
+    #[test]
+    fn limits_concurrent_uploads() {
+        let limiter = Arc::new(RateLimiter::default());
+        let now = Instant::now();
+        let first = limiter.check("user:ci", limits(0, 1), true, now).unwrap();
+        assert!(first.is_some());
+        assert_eq!(
+            limiter.check("user:ci", limits(0, 1), true, now).err(),
+            Some(Refusal::Uploads)
+        );
+        // Other requests aren't held up by uploads
+        assert!(limiter.check("user:ci", limits(0, 1), false, now).is_ok());
+
+        drop(first);
+        assert!(limiter.check("user:ci", limits(0, 1), true, now).is_ok());
+    }
+}
@@ -0,0 +1,154 @@
+use super::StorageDriverError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RepositoryInfo {
+    pub name: String,
+    // Not counting manifests pushed by digest
+    pub tags: u64,
+    // Includes blobs shared with other repositories
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RepositoryList {
+    pub repositories: Vec<RepositoryInfo>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RepositoryDeleted {
+    pub name: String,
+    // Tags and manifests pushed by digest that were removed
+    pub manifests: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct UploadSession {
+    pub repo_name: String,
+    pub uuid: String,
+    // Received so far
+    pub bytes: u64,
+    // None if nothing has been received yet
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct UploadList {
+    pub uploads: Vec<UploadSession>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferDirection {
+    Upload,
+    Download,
+}
+
+// A request pushing or pulling through this frontend
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ActiveTransfer {
+    // The upload's for blob uploads, otherwise made up when the transfer started
+    pub uuid: String,
+    pub direction: TransferDirection,
+    pub repo_name: String,
+    // The blob being downloaded
+    pub digest: Option<String>,
+    // Written to or read from storage so far
+    pub bytes: u64,
+    pub started: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ActiveTransferList {
+    // Oldest first
+    pub transfers: Vec<ActiveTransfer>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TransferCancelled {
+    pub uuid: String,
+    // Requests in flight through this frontend that were cut off
+    pub transfers: u64,
+    // Space freed by removing the upload's session and data, None if it wasn't an upload
+    pub freed_bytes: Option<u64>,
+}
+
+// Pulls of a repository by one tag or digest
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ReferencePulls {
+    pub reference: String,
+    pub pulls: u64,
+    pub last_pulled: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RepositoryPulls {
+    pub name: String,
+    pub pulls: u64,
+    pub last_pulled: Option<DateTime<Utc>>,
+    // Most pulled first
+    pub references: Vec<ReferencePulls>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PullStats {
+    // Most pulled first
+    pub repositories: Vec<RepositoryPulls>,
+}
+
+// Space used by a repository's manifests and blobs, including those of earlier tag versions
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RepositoryStorage {
+    pub name: String,
+    // Not counting manifests pushed by digest
+    pub tags: u64,
+    pub blobs: u64,
+    // Used by no other repository, so freed by deleting it and running garbage collection
+    pub unique_bytes: u64,
+    // Also used by other repositories
+    pub shared_bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct StorageReport {
+    pub repositories: u64,
+    pub tags: u64,
+    // Referenced by any repository, each blob counted once
+    pub blobs: u64,
+    pub bytes: u64,
+    // Everything stored, including blobs garbage collection would remove
+    pub stored_blobs: u64,
+    pub stored_bytes: u64,
+    // Largest first
+    pub repositories_by_size: Vec<RepositoryStorage>,
+}
+
+/*
+ * Managing the registry as a whole, rather than individual images.
+ */
+#[rocket::async_trait]
+pub trait Admin {
+    async fn list_repositories(&self) -> Result<RepositoryList, StorageDriverError>;
+
+    /// Removes every tag in the repository. Blobs are freed by the next garbage collection.
+    async fn delete_repository(&self, name: &str) -> Result<RepositoryDeleted, StorageDriverError>;
+
+    /// Uploads that have been started but not completed
+    async fn list_uploads(&self) -> Result<UploadList, StorageDriverError>;
+
+    /// Uploads and downloads in flight through this frontend, with the bytes moved so far
+    async fn active_transfers(&self) -> Result<ActiveTransferList, StorageDriverError>;
+
+    /// Cuts off transfers with the uuid, and if it's an upload's removes its session and data
+    async fn cancel_transfer(&self, uuid: &str) -> Result<TransferCancelled, StorageDriverError>;
+
+    /// How often each repository, or just the one given, has been pulled
+    async fn pull_stats(&self, repo_name: Option<&str>) -> Result<PullStats, StorageDriverError>;
+
+    /// Space used by one repository
+    async fn repo_storage(&self, name: &str) -> Result<RepositoryStorage, StorageDriverError>;
+
+    /// Space used by the registry, with a breakdown by repository
+    async fn storage_report(&self) -> Result<StorageReport, StorageDriverError>;
+}
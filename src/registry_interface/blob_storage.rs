@@ -1,8 +1,11 @@
 use rocket::data::DataStream;
+use rocket::tokio::io::{AsyncReadExt, AsyncSeekExt};
+use sha2::{Digest as _, Sha256};
 
-use super::digest::Digest;
+use super::digest::{Digest, DigestAlgorithm};
 use super::AsyncSeekRead;
 use super::StorageDriverError;
+use std::io::{self, SeekFrom};
 use std::pin::Pin;
 
 pub struct ContentInfo {
@@ -10,17 +13,70 @@ pub struct ContentInfo {
     pub range: (u64, u64),
 }
 
-#[allow(dead_code)]
 pub struct UploadInfo {
-    name: String,
-    session_id: String,
-    uploaded: u32,
-    size: u32,
+    pub name: String,
+    pub session_id: String,
+    // Bytes stored so far
+    pub uploaded: u64,
+}
+
+/// What a HEAD request needs to know about a blob
+pub struct BlobMetadata {
+    pub digest: Digest,
+    pub size: u64,
 }
 
 pub struct BlobReader {
     pub digest: Digest,
     pub reader: Pin<Box<dyn AsyncSeekRead>>,
+    pub size: u64,
+    pub range: ReadRange,
+    // Sent to the client instead of the blob, see blob_redirect.rs
+    pub redirect: Option<String>,
+    // Value of the Content-Digest header, if the client asked for one
+    pub content_digest: Option<String>,
+}
+
+/// A single range from a Range header, e.g. bytes=100-199, bytes=100- or bytes=-100
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    // Start and optional end, which is inclusive like in the header
+    From(u64, Option<u64>),
+    // The last n bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// First and last byte in a blob of the given size, or None if it's outside the blob
+    pub fn resolve(&self, size: u64) -> Option<(u64, u64)> {
+        match *self {
+            ByteRange::From(start, end) => {
+                let last = end.unwrap_or(u64::MAX).min(size.checked_sub(1)?);
+                if start > last {
+                    None
+                } else {
+                    Some((start, last))
+                }
+            }
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(n) => Some((size.saturating_sub(n), size.checked_sub(1)?)),
+        }
+    }
+}
+
+/// The ranges asked for in a Range header, in the order given
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ByteRanges(pub Vec<ByteRange>);
+
+/// How much of the blob is sent
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReadRange {
+    Whole,
+    // First and last byte
+    Part(u64, u64),
+    // First and last byte of each range, sent as multipart/byteranges
+    Parts(Vec<(u64, u64)>),
+    Unsatisfiable,
 }
 pub struct Stored {
     pub total_stored: u64,
@@ -36,12 +92,77 @@ impl BlobReader {
     pub fn digest(&self) -> &Digest {
         &self.digest
     }
+
+    /*
+     * Only send the requested part of the blob. The reader is moved to the start of the range
+     * straight away, so resumed downloads don't re-read what the client already has.
+     */
+    pub async fn seek_to(&mut self, range: ByteRange) -> io::Result<()> {
+        match range.resolve(self.size) {
+            Some((start, end)) => {
+                self.reader.seek(SeekFrom::Start(start)).await?;
+                self.range = ReadRange::Part(start, end);
+            }
+            None => self.range = ReadRange::Unsatisfiable,
+        }
+        Ok(())
+    }
+
+    /*
+     * As seek_to, for every range asked for. Those outside the blob are left out, and if only one
+     * is left it's sent on its own. Used by lazy pulling clients, e.g. stargz-snapshotter, to
+     * fetch several chunks of a layer in one request.
+     */
+    pub async fn seek_to_ranges(&mut self, ranges: &[ByteRange]) -> io::Result<()> {
+        if let [range] = ranges {
+            return self.seek_to(*range).await;
+        }
+        let parts: Vec<(u64, u64)> = ranges.iter().filter_map(|r| r.resolve(self.size)).collect();
+        match parts.as_slice() {
+            [] => self.range = ReadRange::Unsatisfiable,
+            [(start, end)] => return self.seek_to(ByteRange::From(*start, Some(*end))).await,
+            _ => self.range = ReadRange::Parts(parts),
+        }
+        Ok(())
+    }
+
+    /*
+     * Works out the RFC 9530 Content-Digest of exactly the bytes that will be sent, so clients
+     * can check a part of the blob on its own. For the whole blob it's the blob's digest, but a
+     * range has to be read and hashed first, then the reader is moved back to its start.
+     */
+    pub async fn add_content_digest(&mut self) -> io::Result<()> {
+        let hash = match self.range.clone() {
+            ReadRange::Whole if matches!(self.digest.algo, DigestAlgorithm::Sha256) => {
+                hex::decode(&self.digest.hash)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            }
+            ReadRange::Part(start, end) => {
+                let mut hasher = Sha256::new();
+                let mut buf = vec![0u8; 64 * 1024];
+                let mut left = end - start + 1;
+                while left > 0 {
+                    let want = left.min(buf.len() as u64) as usize;
+                    let n = self.reader.read(&mut buf[..want]).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    hasher.update(&buf[..n]);
+                    left -= n as u64;
+                }
+                self.reader.seek(SeekFrom::Start(start)).await?;
+                hasher.finalize().to_vec()
+            }
+            _ => return Ok(()),
+        };
+        self.content_digest = Some(format!("sha-256=:{}:", base64::encode(hash)));
+        Ok(())
+    }
 }
 
 #[rocket::async_trait]
 pub trait BlobStorage {
     /// Retrieve the blob from the registry identified by digest.
-    /// A HEAD request can also be issued to this endpoint to obtain resource information without receiving all data.
     /// GET: /v2/<name>/blobs/<digest>
     async fn get_blob(&self, name: &str, digest: &Digest)
         -> Result<BlobReader, StorageDriverError>;
@@ -57,7 +178,11 @@ pub trait BlobStorage {
     /// Retrieve status of upload identified by session_id.
     /// The primary purpose of this endpoint is to resolve the current status of a resumable upload.
     /// GET: /v2/<name>/blobs/uploads/<session_id>
-    async fn status_blob_upload(&self, name: &str, session_id: &str) -> UploadInfo;
+    async fn status_blob_upload(
+        &self,
+        name: &str,
+        session_id: &str,
+    ) -> Result<UploadInfo, StorageDriverError>;
 
     /// Upload a chunk of data for the specified upload.
     /// PATCH: /v2/<name>/blobs/uploads/<session_id>
@@ -78,6 +203,17 @@ pub trait BlobStorage {
         data: DataStream<'a>,
     ) -> Result<Stored, StorageDriverError>;
 
+    /// Uploads a whole blob in one go and completes it, verifying it matches digest.
+    /// POST: /v2/<name>/blobs/uploads/?digest=<digest>
+    /// Saves the separate calls to start, store and complete an upload for small blobs such as
+    /// configs. There's no progress to resume, so the client sends the whole blob again on failure.
+    async fn upload_blob_complete<'a>(
+        &self,
+        name: &str,
+        digest: &Digest,
+        data: DataStream<'a>,
+    ) -> Result<UploadInfo, StorageDriverError>;
+
     /// Finalises the upload of the given session_id.
     /// Also verfies uploaded blob matches user digest
     async fn complete_and_verify_blob_upload(
@@ -97,7 +233,11 @@ pub trait BlobStorage {
         session_id: &str,
     ) -> Result<(), StorageDriverError>;
 
-    /// Whether the specific blob exists
-    /// AM: Assume this is for HEAD requests?
-    async fn has_blob(&self, name: &str, digest: &Digest) -> bool;
+    /// Size and digest of the blob if it exists, without reading it.
+    /// HEAD: /v2/<name>/blobs/<digest>
+    async fn blob_exists(
+        &self,
+        name: &str,
+        digest: &Digest,
+    ) -> Result<BlobMetadata, StorageDriverError>;
 }
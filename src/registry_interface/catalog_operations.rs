@@ -64,14 +64,46 @@ impl ManifestHistory {
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TagHistoryEntry {
+    pub digest: String,
+    // When the tag was pointed at the digest, None if not recorded by this version of Trow
+    pub pushed: Option<DateTime<Utc>>,
+}
+
+/*
+ * Every digest a tag has pointed to, newest first, so the first is the current one. Digests
+ * removed by retention rules are dropped from the history, and it's deleted with the tag.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TagHistory {
+    pub repository: String,
+    pub tag: String,
+    pub history: Vec<TagHistoryEntry>,
+}
+
+impl TagHistory {
+    /// What the tag pointed to at the time, None if it wasn't pushed until later
+    pub fn at(&self, time: DateTime<Utc>) -> Option<&TagHistoryEntry> {
+        self.history
+            .iter()
+            .filter(|e| e.pushed.map_or(false, |p| p <= time))
+            .max_by_key(|e| e.pushed)
+    }
+}
+
 #[rocket::async_trait]
 pub trait CatalogOperations {
     /// Returns a vec of all repository names in the registry
-    /// Can optionally be given a start value and maximum number of results to return.
+    /// Can optionally be given a start value and maximum number of results to return, and a
+    /// prefix the names must start with, such as a namespace.
+    /// Given a user, only the repositories they can see are returned.
     async fn get_catalog(
         &self,
         start_value: Option<&str>,
         num_results: Option<u32>,
+        prefix: Option<&str>,
+        user: Option<&str>,
     ) -> Result<Vec<String>, StorageDriverError>;
 
     /// Returns a vec of all tags under the given repository
@@ -92,4 +124,45 @@ pub trait CatalogOperations {
         start_value: Option<&str>,
         num_results: Option<u32>,
     ) -> Result<ManifestHistory, StorageDriverError>;
+
+    /// Every digest the tag has pointed to, see TagHistory
+    async fn get_tag_history(
+        &self,
+        repo: &str,
+        tag: &str,
+    ) -> Result<TagHistory, StorageDriverError>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TagHistory, TagHistoryEntry};
+    use chrono::{DateTime, Utc};
+
+    fn time(t: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(t).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn finds_digest_at_time() {
+        let entry = |digest: &str, pushed: Option<&str>| TagHistoryEntry {
+            digest: digest.to_string(),
+            pushed: pushed.map(time),
+        };
+        let history = TagHistory {
+            repository: "org/app".to_string(),
+            tag: "latest".to_string(),
+            history: vec![
+                entry("sha256:c", Some("2022-03-08T09:00:00Z")),
+                entry("sha256:b", Some("2022-03-01T09:00:00Z")),
+                entry("sha256:undated", None),
+                entry("sha256:a", Some("2022-02-01T09:00:00Z")),
+            ],
+        };
+        let at = |t: &str| history.at(time(t)).map(|e| e.digest.as_str());
+        assert_eq!(at("2022-03-10T00:00:00Z"), Some("sha256:c"));
+        assert_eq!(at("2022-03-08T09:00:00Z"), Some("sha256:c"));
+        assert_eq!(at("2022-03-02T00:00:00Z"), Some("sha256:b"));
+        assert_eq!(at("2022-02-15T00:00:00Z"), Some("sha256:a"));
+        assert_eq!(at("2022-01-01T00:00:00Z"), None);
+    }
 }
@@ -19,7 +19,7 @@ const BUFFER_SIZE: usize = 1024;
 // These regex are used to do a simple validation of the tag fields
 lazy_static! {
     static ref REGEX_ALGO: Regex = Regex::new(r"^[A-Za-z0-9_+.-]+$").unwrap();
-    static ref REGEX_DIGEST: Regex = Regex::new(r"^[A-Fa-f0-9]+$").unwrap();
+    static ref REGEX_DIGEST: Regex = Regex::new(r"^[a-f0-9]+$").unwrap();
 }
 
 #[derive(Error, Debug)]
@@ -134,8 +134,8 @@ pub fn parse(component: &str) -> Result<Digest, DigestError> {
         .map(String::from)
         .collect::<Vec<String>>();
 
-    // check that we have both parts: algo and digest
-    if algo_digest.len() < 2 {
+    // check that we have both parts: algo and digest, and nothing after them
+    if algo_digest.len() != 2 {
         return Err(DigestError::InvalidDigest(format!(
             "Component cannot be parsed into a digest: {}",
             &component
@@ -162,6 +162,18 @@ pub fn parse(component: &str) -> Result<Digest, DigestError> {
 
     let algo_enum = DigestAlgorithm::from_str(algo.as_str()).map_err(DigestError::InvalidDigest)?;
 
+    // The length of the algorithm's hex encoded hash, as the spec requires
+    let hex_len = match algo_enum {
+        DigestAlgorithm::Sha256 => 64,
+        DigestAlgorithm::Sha512 => 128,
+    };
+    if digest.len() != hex_len {
+        return Err(DigestError::InvalidDigest(format!(
+            "Component cannot be parsed into a TAG wrong digest length: {} - {}",
+            &component, &digest
+        )));
+    }
+
     Ok(Digest {
         algo: algo_enum,
         hash: digest,
@@ -171,7 +183,7 @@ pub fn parse(component: &str) -> Result<Digest, DigestError> {
 #[cfg(test)]
 mod test {
     use crate::registry_interface::digest::{
-        sha256_digest, sha256_tag_digest, Digest, DigestAlgorithm,
+        parse, sha256_digest, sha256_tag_digest, Digest, DigestAlgorithm,
     };
     use std::io::BufReader;
 
@@ -247,4 +259,16 @@ mod test {
             "sha256:05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec"
         );
     }
+
+    #[test]
+    fn rejects_digests_outside_the_spec() {
+        let hex = "05c6e08f1d9fdafa03147fcb8f82f124c76d2f70e3d989dc8aadb5e7d7450bec";
+        assert_eq!(parse(&format!("sha256:{}", hex)).unwrap().hash, hex);
+        assert!(parse(&format!("sha256:{}", hex.to_uppercase())).is_err());
+        assert!(parse(&format!("sha256:{}:../../etc", hex)).is_err());
+        assert!(parse(&format!("sha512:{}", hex)).is_err());
+        assert!(parse("sha256:05c6e08f").is_err());
+        assert!(parse("sha256:../../etc/passwd").is_err());
+        assert!(parse(hex).is_err());
+    }
 }
@@ -0,0 +1,34 @@
+use super::StorageDriverError;
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+
+/*
+ * Pushes, pulls, deletes and blobs freed by garbage collection, as they happen, so e.g. a CD
+ * pipeline can deploy a new tag as soon as it's pushed rather than polling for it.
+ */
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RegistryEvent {
+    pub id: String,
+    // push, pull, delete or gc
+    pub action: String,
+    // Blank for gc
+    pub repository: String,
+    // Not set for deletes, gc or pulls by digest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    pub digest: String,
+    // RFC 3339
+    pub timestamp: String,
+}
+
+#[rocket::async_trait]
+pub trait Events {
+    /// Events from now on, for repositories starting with prefix and, if there's a user, only
+    /// those they can see. Events are missed if the stream isn't read fast enough.
+    async fn watch_events(
+        &self,
+        prefix: Option<&str>,
+        user: Option<&str>,
+    ) -> Result<BoxStream<'static, RegistryEvent>, StorageDriverError>;
+}
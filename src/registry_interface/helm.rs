@@ -0,0 +1,136 @@
+use super::StorageDriverError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+/*
+ * Helm charts pushed as OCI artifacts, listed as a classic chart repository for clients that
+ * can't pull from OCI registries. Helm is pointed at /helm, reads /helm/index.yaml and downloads
+ * charts from the URLs in it, relative to /helm.
+ */
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChartVersion {
+    pub repo_name: String,
+    pub tag: String,
+    // Chart.yaml, as pushed
+    pub metadata: Map<String, Value>,
+    // Digest of the packaged chart
+    pub digest: String,
+    pub size: u64,
+    pub pushed: DateTime<Utc>,
+}
+
+impl ChartVersion {
+    // Where the packaged chart is downloaded from, relative to /helm
+    pub fn url(&self, name: &str, version: &str) -> String {
+        format!(
+            "charts/{}/{}/{}-{}.tgz",
+            self.repo_name,
+            self.digest.trim_start_matches("sha256:"),
+            name,
+            version
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChartIndex {
+    pub api_version: String,
+    // Chart name to its versions, newest first
+    pub entries: BTreeMap<String, Vec<Map<String, Value>>>,
+    pub generated: DateTime<Utc>,
+}
+
+impl ChartIndex {
+    /*
+     * The index.yaml Helm reads, with each chart's Chart.yaml plus where to download it.
+     *
+     * Charts are listed by the name in their Chart.yaml, so the same chart pushed to several
+     * repositories has a version from each. Charts without a name or version are left out, as
+     * Helm would refuse the whole index.
+     */
+    pub fn new(mut charts: Vec<ChartVersion>, generated: DateTime<Utc>) -> ChartIndex {
+        charts.sort_by(|a, b| b.pushed.cmp(&a.pushed));
+        let mut entries: BTreeMap<String, Vec<Map<String, Value>>> = BTreeMap::new();
+        for chart in charts {
+            let field = |key: &str| chart.metadata.get(key).and_then(Value::as_str);
+            let (name, version) = match (field("name"), field("version")) {
+                (Some(name), Some(version)) => (name.to_string(), version.to_string()),
+                _ => continue,
+            };
+            let mut entry = chart.metadata.clone();
+            entry.insert("urls".to_string(), json!([chart.url(&name, &version)]));
+            entry.insert(
+                "digest".to_string(),
+                json!(chart.digest.trim_start_matches("sha256:")),
+            );
+            entry.insert("created".to_string(), json!(chart.pushed.to_rfc3339()));
+            entries.entry(name).or_default().push(entry);
+        }
+        ChartIndex {
+            api_version: "v1".to_string(),
+            entries,
+            generated,
+        }
+    }
+}
+
+#[rocket::async_trait]
+pub trait Charts {
+    /// Every tagged Helm chart pushed as an OCI artifact
+    async fn list_charts(&self) -> Result<Vec<ChartVersion>, StorageDriverError>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ChartIndex, ChartVersion};
+    use chrono::{TimeZone, Utc};
+    use serde_json::{json, Value};
+
+    fn chart(repo_name: &str, metadata: Value, day: u32) -> ChartVersion {
+        ChartVersion {
+            repo_name: repo_name.to_string(),
+            tag: metadata["version"].as_str().unwrap_or("latest").to_string(),
+            metadata: metadata.as_object().unwrap().clone(),
+            digest: format!("sha256:{:064}", day),
+            size: 1024,
+            pushed: Utc.ymd(2022, 3, day).and_hms(0, 0, 0),
+        }
+    }
+
+    #[test]
+    fn indexes_charts_by_name() {
+        let charts = vec![
+            chart(
+                "charts/mychart",
+                json!({"apiVersion": "v2", "name": "mychart", "version": "1.0.0"}),
+                1,
+            ),
+            chart(
+                "charts/mychart",
+                json!({"apiVersion": "v2", "name": "mychart", "version": "1.1.0", "appVersion": "2.0"}),
+                2,
+            ),
+            chart("charts/broken", json!({"name": "broken"}), 3),
+        ];
+        let index = ChartIndex::new(charts, Utc.ymd(2022, 3, 4).and_hms(0, 0, 0));
+        assert_eq!(index.entries.keys().collect::<Vec<_>>(), vec!["mychart"]);
+
+        let versions = &index.entries["mychart"];
+        assert_eq!(versions[0]["version"], "1.1.0");
+        assert_eq!(versions[0]["appVersion"], "2.0");
+        assert_eq!(versions[1]["version"], "1.0.0");
+        assert_eq!(
+            versions[1]["urls"],
+            json!([format!("charts/charts/mychart/{:064}/mychart-1.0.0.tgz", 1)])
+        );
+        assert_eq!(versions[1]["digest"], format!("{:064}", 1));
+        assert_eq!(versions[1]["created"], "2022-03-01T00:00:00+00:00");
+
+        let yaml = serde_yaml::to_string(&index).unwrap();
+        assert!(yaml.contains("apiVersion: v1"));
+    }
+}
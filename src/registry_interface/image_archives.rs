@@ -0,0 +1,77 @@
+use super::StorageDriverError;
+use futures::stream::BoxStream;
+use rocket::data::DataStream;
+use serde::{Deserialize, Serialize};
+
+/*
+ * An image being exported as an OCI image layout tarball, sent on to the client as it's read from
+ * the backend.
+ */
+pub struct ImageArchive {
+    // For the name of the file it's saved to
+    pub repo_name: String,
+    pub reference: String,
+    pub chunks: BoxStream<'static, Vec<u8>>,
+}
+
+impl ImageArchive {
+    pub fn file_name(&self) -> String {
+        format!("{}-{}.tar", self.repo_name, self.reference).replace(
+            |c: char| !c.is_ascii_alphanumeric() && !matches!(c, '.' | '_' | '-'),
+            "_",
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ImportedManifest {
+    pub digest: String,
+    // None if the image was imported by digest only
+    pub tag: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ImageImported {
+    pub repo_name: String,
+    pub manifests: Vec<ImportedManifest>,
+    pub blobs: u64,
+    pub bytes: u64,
+}
+
+/*
+ * Moving images between registries without a Docker daemon, e.g. into an air-gapped cluster.
+ */
+#[rocket::async_trait]
+pub trait ImageArchives {
+    /// The image and everything it references, as an OCI image layout tarball
+    async fn export_image(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<ImageArchive, StorageDriverError>;
+
+    /// Stores the images in an OCI image layout tarball, the same as pushing them. They're
+    /// tagged as in the tarball, or with tag if it has a single image.
+    async fn import_image<'a>(
+        &self,
+        name: &str,
+        tag: Option<&str>,
+        data: DataStream<'a>,
+    ) -> Result<ImageImported, StorageDriverError>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::ImageArchive;
+    use futures::stream::{self, StreamExt};
+
+    #[test]
+    fn file_name_is_safe() {
+        let archive = ImageArchive {
+            repo_name: "org/app".to_string(),
+            reference: "sha256:abc".to_string(),
+            chunks: stream::empty().boxed(),
+        };
+        assert_eq!(archive.file_name(), "org_app-sha256_abc.tar");
+    }
+}
@@ -0,0 +1,56 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum JobError {
+    #[error("Invalid job request: {0}")]
+    Invalid(String),
+    #[error("Job {0} not found")]
+    NotFound(String),
+    #[error("Internal job error")]
+    Internal,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct JobStatus {
+    pub id: String,
+    // e.g. "gc" or "scrub"
+    pub kind: String,
+    // One of running, completed, failed or cancelled
+    pub state: String,
+    // Percentage complete
+    pub progress: u32,
+    pub message: String,
+    pub created: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct JobList {
+    pub jobs: Vec<JobStatus>,
+}
+
+/*
+ * Long running operations run in the background and are tracked by id.
+ */
+#[rocket::async_trait]
+pub trait Jobs {
+    /// Starts a job of the given kind, returning as soon as it's running
+    async fn start_job(&self, kind: &str) -> Result<JobStatus, JobError>;
+
+    async fn get_job(&self, id: &str) -> Result<JobStatus, JobError>;
+
+    async fn list_jobs(&self) -> Result<JobList, JobError>;
+
+    /// Asks the job to stop, returns the status at the time of asking
+    async fn cancel_job(&self, id: &str) -> Result<JobStatus, JobError>;
+
+    /// Starts a job making sure every blob of the image is in the registry
+    async fn prewarm(
+        &self,
+        repo_name: &str,
+        reference: &str,
+        notify: bool,
+    ) -> Result<JobStatus, JobError>;
+}
@@ -0,0 +1,55 @@
+use super::StorageDriverError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/*
+ * Read-only maintenance mode, in which every write to the registry is refused with a 503 while
+ * reads carry on as normal, e.g. while storage is migrated or backed up.
+ *
+ * Also the result of the last scrub job, which checks stored blobs still match their digests and
+ * that every tag's blobs are stored.
+ */
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ReadOnlyStatus {
+    pub read_only: bool,
+    // Why writes are refused, shown to clients. Defaulted if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MissingBlob {
+    pub digest: String,
+    // The first tag found referring to it, as repo:tag
+    pub referenced_by: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ScrubReport {
+    // Blobs re-hashed
+    pub checked: u64,
+    // Blobs that didn't match their digest
+    pub corrupt: Vec<String>,
+    pub missing: Vec<MissingBlob>,
+    // Corrupt blobs moved to the quarantine dir
+    pub quarantined: Vec<String>,
+    // Stopped part way, so not everything was checked
+    pub cancelled: bool,
+    pub finished: DateTime<Utc>,
+}
+
+#[rocket::async_trait]
+pub trait Maintenance {
+    async fn read_only_status(&self) -> Result<ReadOnlyStatus, StorageDriverError>;
+
+    /// Turns read-only mode on or off until it's changed again or the backend restarts
+    async fn set_read_only(
+        &self,
+        read_only: bool,
+        reason: Option<&str>,
+    ) -> Result<ReadOnlyStatus, StorageDriverError>;
+
+    /// The result of the last scrub job, None if none has finished since the backend started
+    async fn scrub_report(&self) -> Result<Option<ScrubReport>, StorageDriverError>;
+}
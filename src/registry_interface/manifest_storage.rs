@@ -1,8 +1,52 @@
+use super::Digest;
 use super::{AsyncSeekRead, StorageDriverError};
-use super::{Digest, DigestAlgorithm};
 use rocket::data::DataStream;
+use rocket::tokio::io::AsyncSeekExt;
+use serde::{Deserialize, Serialize};
+use std::io::{self, SeekFrom};
 use std::pin::Pin;
 
+/// What a HEAD request needs to know about a manifest
+pub struct ManifestMetadata {
+    pub content_type: String,
+    pub digest: Digest,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PlatformImage {
+    pub os: String,
+    pub architecture: String,
+    pub variant: Option<String>,
+    pub digest: String,
+    pub media_type: String,
+    // Of the platform's manifest itself
+    pub manifest_size: u64,
+    // Config and layers, None if the platform's manifest isn't stored
+    pub image_size: Option<u64>,
+}
+
+impl PlatformImage {
+    /// Whether the platform is os/architecture, or os/architecture/variant
+    pub fn matches(&self, platform: &str) -> bool {
+        let mut parts = platform.split('/');
+        parts.next() == Some(self.os.as_str())
+            && parts.next() == Some(self.architecture.as_str())
+            && match parts.next() {
+                Some(v) => self.variant.as_deref() == Some(v) && parts.next().is_none(),
+                None => true,
+            }
+    }
+}
+
+/// The platforms in an image index, or the platform of a single image
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct IndexSummary {
+    pub digest: String,
+    pub media_type: String,
+    pub platforms: Vec<PlatformImage>,
+}
+
 pub struct ManifestReader {
     pub content_type: String,
     pub digest: Digest,
@@ -21,21 +65,38 @@ impl ManifestReader {
     pub fn digest(&self) -> &Digest {
         &self.digest
     }
+
+    /// For manifests that only exist in memory, finding the size by seeking to the end
+    pub async fn metadata(mut self) -> io::Result<ManifestMetadata> {
+        let size = self.reader.seek(SeekFrom::End(0)).await?;
+        Ok(ManifestMetadata {
+            content_type: self.content_type,
+            digest: self.digest,
+            size,
+        })
+    }
 }
 
 // This trait handles all the necessary Manifest Operations (get, save delete)
 #[rocket::async_trait]
 pub trait ManifestStorage {
     /// Fetch the manifest identified by name and reference where reference can be a tag or digest.
-    /// A HEAD request can also be issued to this endpoint to obtain resource information without receiving all data.
     /// GET: /v2/<name>/manifests/<reference>
-    /// HEAD: /v2/<name>/manifests/<reference>
     async fn get_manifest(
         &self,
         name: &str,
         tag: &str,
     ) -> Result<ManifestReader, StorageDriverError>;
 
+    /// A copy of the manifest using layers transcoded to the compression, "gzip" or "zstd".
+    /// Fails with InvalidManifest if none of its layers have been transcoded.
+    async fn get_transcoded_manifest(
+        &self,
+        name: &str,
+        reference: &str,
+        compression: &str,
+    ) -> Result<ManifestReader, StorageDriverError>;
+
     // Stores should take a reader that has the data, possibly a second method that returns byte array
 
     /// TODO: DataStream is currently tied to Rocket implementation to handle transfers that get capped.
@@ -61,6 +122,42 @@ pub trait ManifestStorage {
     /// DELETE: /v2/<name>/manifests/<reference>
     async fn delete_manifest(&self, name: &str, digest: &Digest) -> Result<(), StorageDriverError>;
 
-    /// Whether the specific manifest exists
-    async fn has_manifest(&self, name: &str, algo: &DigestAlgorithm, reference: &str) -> bool;
+    /// Size, digest and media type of the manifest if it exists, without reading it.
+    /// HEAD: /v2/<name>/manifests/<reference>
+    async fn manifest_metadata(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<ManifestMetadata, StorageDriverError>;
+
+    /// The platforms the manifest identified by name and reference is for, with their digests
+    async fn index_summary(
+        &self,
+        name: &str,
+        reference: &str,
+    ) -> Result<IndexSummary, StorageDriverError>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::PlatformImage;
+
+    #[test]
+    fn matches_platforms() {
+        let arm = PlatformImage {
+            os: "linux".to_string(),
+            architecture: "arm64".to_string(),
+            variant: Some("v8".to_string()),
+            digest: "sha256:abc".to_string(),
+            media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+            manifest_size: 500,
+            image_size: None,
+        };
+        assert!(arm.matches("linux/arm64"));
+        assert!(arm.matches("linux/arm64/v8"));
+        assert!(!arm.matches("linux/arm64/v7"));
+        assert!(!arm.matches("linux/amd64"));
+        assert!(!arm.matches("linux"));
+        assert!(!arm.matches("linux/arm64/v8/extra"));
+    }
 }
@@ -1,19 +1,58 @@
 use rocket::tokio::io::{AsyncRead, AsyncSeek};
 use thiserror::Error;
 
-pub use blob_storage::{BlobReader, BlobStorage, ContentInfo, UploadInfo};
-pub use catalog_operations::{CatalogOperations, ManifestHistory};
+pub use admin::{
+    ActiveTransfer, ActiveTransferList, Admin, PullStats, ReferencePulls, RepositoryDeleted,
+    RepositoryInfo, RepositoryList, RepositoryPulls, RepositoryStorage, StorageReport,
+    TransferCancelled, TransferDirection, UploadList, UploadSession,
+};
+pub use blob_storage::{
+    BlobMetadata, BlobReader, BlobStorage, ByteRange, ByteRanges, ContentInfo, ReadRange,
+    UploadInfo,
+};
+pub use catalog_operations::{CatalogOperations, ManifestHistory, TagHistory, TagHistoryEntry};
 pub use digest::{Digest, DigestAlgorithm};
-pub use manifest_storage::{ManifestReader, ManifestStorage};
+pub use events::{Events, RegistryEvent};
+pub use helm::{ChartIndex, ChartVersion, Charts};
+pub use image_archives::{ImageArchive, ImageArchives, ImageImported, ImportedManifest};
+pub use jobs::{JobError, JobList, JobStatus, Jobs};
+pub use maintenance::{Maintenance, MissingBlob, ReadOnlyStatus, ScrubReport};
+pub use manifest_storage::{
+    IndexSummary, ManifestMetadata, ManifestReader, ManifestStorage, PlatformImage,
+};
 pub use metrics::{Metrics, MetricsError, MetricsResponse};
-pub use validation::{AdmissionRequest, AdmissionResponse, Validation, ValidationError};
+pub use policy::{Policies, PolicyRules};
+pub use quotas::{QuotaUsage, Quotas, UploadCheck};
+pub use reference::{Reference, ReferenceError};
+pub use referrers::{Referrer, ReferrerList, Referrers};
+pub use retention::{Retention, RetentionDeletion, RetentionReport};
+pub use tenants::{Tenancy, Tenant, TenantList, TenantMember};
+pub use trash::{Trash, TrashEntry, TrashList};
+pub use usage::{UnusedImage, Usage, UsageReport};
+pub use validation::{
+    AdmissionRequest, AdmissionResponse, PolicyDecision, PolicyRequest, Validation, ValidationError,
+};
 
+pub mod admin;
 pub mod blob_storage;
 pub mod catalog_operations;
 #[allow(dead_code)]
 pub mod digest;
+pub mod events;
+pub mod helm;
+pub mod image_archives;
+pub mod jobs;
+pub mod maintenance;
 pub mod manifest_storage;
 pub mod metrics;
+pub mod policy;
+pub mod quotas;
+pub mod reference;
+pub mod referrers;
+pub mod retention;
+pub mod tenants;
+pub mod trash;
+pub mod usage;
 pub mod validation;
 
 // Storage Driver Error
@@ -21,14 +60,49 @@ pub mod validation;
 pub enum StorageDriverError {
     #[error("the name `{0}` is not valid")]
     InvalidName(String),
+    #[error("the repository `{0}` is not known")]
+    NameUnknown(String),
     #[error("manifest is not valid")]
     InvalidManifest,
+    // A pushed manifest failed validation, with why
+    #[error("{0}")]
+    ManifestRejected(String),
+    #[error("blob `{0}` is not known")]
+    BlobUnknown(String),
     #[error("Digest did not match content")]
     InvalidDigest,
     #[error("Unsupported Operation")]
     Unsupported,
     #[error("Requested index does not match actual")]
     InvalidContentRange,
+    // A chunk that doesn't start where the stored data ends, with how many bytes are stored
+    #[error("chunk sent out of order, {0} bytes are stored")]
+    OutOfOrder(u64),
+    #[error("Content over data limit")]
+    TooLarge,
+    #[error("{0}")]
+    QuotaExceeded(String),
+    #[error("{0}")]
+    TagImmutable(String),
+    #[error("{0}")]
+    InvalidPolicy(String),
+    #[error("{0}")]
+    InvalidArchive(String),
+    // A tenant can't be deleted while it has repositories
+    #[error("{0}")]
+    TenantInUse(String),
+    // Writes are refused during read-only maintenance, with why
+    #[error("{0}")]
+    ReadOnly(String),
+    // The client stalled or took too long sending, with which
+    #[error("{0}")]
+    TimedOut(String),
+    // Storage is full or nearly so, with how full
+    #[error("{0}")]
+    InsufficientStorage(String),
+    // The upload was cancelled or never started
+    #[error("upload `{0}` is not known")]
+    UploadUnknown(String),
     #[error("Internal storage error")]
     Internal,
 }
@@ -37,6 +111,60 @@ pub enum StorageDriverError {
 //I'd much rather not have to write an impl for every class :(
 pub trait AsyncSeekRead: AsyncRead + AsyncSeek + Send {}
 impl AsyncSeekRead for rocket::tokio::fs::File {}
+impl<T: AsRef<[u8]> + Unpin + Send> AsyncSeekRead for std::io::Cursor<T> {}
+
+/*
+ * Everything the frontend needs from a registry, so the routes don't care how it's implemented.
+ *
+ * Anything implementing the individual traits gets this for free.
+ */
+pub trait RegistryInterface:
+    ManifestStorage
+    + BlobStorage
+    + CatalogOperations
+    + Validation
+    + Metrics
+    + Jobs
+    + Quotas
+    + Retention
+    + Admin
+    + Usage
+    + Policies
+    + ImageArchives
+    + Charts
+    + Referrers
+    + Tenancy
+    + Maintenance
+    + Events
+    + Trash
+    + Send
+    + Sync
+{
+}
+
+impl<T> RegistryInterface for T where
+    T: ManifestStorage
+        + BlobStorage
+        + CatalogOperations
+        + Validation
+        + Metrics
+        + Jobs
+        + Quotas
+        + Retention
+        + Admin
+        + Usage
+        + Policies
+        + ImageArchives
+        + Charts
+        + Referrers
+        + Tenancy
+        + Maintenance
+        + Events
+        + Trash
+        + Send
+        + Sync
+{
+}
 
 // Super trait
 pub trait RegistryStorage: ManifestStorage + BlobStorage + CatalogOperations {
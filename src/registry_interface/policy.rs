@@ -0,0 +1,28 @@
+use super::StorageDriverError;
+use serde::{Deserialize, Serialize};
+
+/// Admission and push rules the backend can replace while running, as given to the flags
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct PolicyRules {
+    pub allow_prefixes: Vec<String>,
+    pub allow_images: Vec<String>,
+    pub deny_prefixes: Vec<String>,
+    pub deny_images: Vec<String>,
+    pub quotas: Vec<String>,
+    pub immutable_tags: Vec<String>,
+    pub freeze_windows: Vec<String>,
+}
+
+#[rocket::async_trait]
+pub trait Policies {
+    /// Replaces the rules, numbered as the given generation. If any of them are invalid, fails
+    /// with InvalidPolicy and the old rules are kept.
+    async fn update_policy(
+        &self,
+        generation: u64,
+        rules: &PolicyRules,
+    ) -> Result<(), StorageDriverError>;
+
+    /// The generation of the rules in use, 0 for those the backend started with
+    async fn policy_generation(&self) -> Result<u64, StorageDriverError>;
+}
@@ -0,0 +1,39 @@
+use super::StorageDriverError;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct QuotaUsage {
+    // Repository or namespace the quota is set on, or the repository if there's no quota
+    pub name: String,
+    pub bytes: u64,
+    pub images: u64,
+    // None means unlimited
+    pub max_bytes: Option<u64>,
+    pub max_images: Option<u64>,
+}
+
+/// Whether a push would be accepted, checked before uploading anything
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct UploadCheck {
+    pub allowed: bool,
+    // Why the push would be refused
+    pub reason: Option<String>,
+    // Bytes free on the disk uploads are stored on
+    pub disk_available: u64,
+    pub quota: QuotaUsage,
+}
+
+#[rocket::async_trait]
+pub trait Quotas {
+    /// Usage of the quota covering the given repository
+    async fn get_quota_usage(&self, name: &str) -> Result<QuotaUsage, StorageDriverError>;
+
+    /// Whether pushing _size_ bytes to the repository, optionally to the given tag, would be
+    /// allowed by its quota and the free disk space
+    async fn check_upload(
+        &self,
+        name: &str,
+        size: u64,
+        tag: Option<&str>,
+    ) -> Result<UploadCheck, StorageDriverError>;
+}
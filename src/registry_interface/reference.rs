@@ -0,0 +1,105 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+use super::digest::{self, Digest};
+
+// Tag names as given in the distribution spec
+lazy_static! {
+    static ref REGEX_TAG: Regex = Regex::new(r"^[A-Za-z0-9_][A-Za-z0-9._-]{0,127}$").unwrap();
+}
+
+#[derive(Error, Debug)]
+pub enum ReferenceError {
+    #[error("`{0}` is not a valid tag or digest")]
+    InvalidReference(String),
+}
+
+/*
+ * What a manifest is asked for by: a tag, which can be moved to other manifests, or a digest,
+ * which always refers to the same content.
+ *
+ * Anything with a ':' has to be a digest, as tags can't contain one, so a reference is never
+ * mistaken for the other kind.
+ */
+#[derive(Debug, PartialEq, Clone)]
+pub enum Reference {
+    Tag(String),
+    Digest(Digest),
+}
+
+impl Reference {
+    pub fn tag(&self) -> Option<&str> {
+        match self {
+            Reference::Tag(tag) => Some(tag),
+            Reference::Digest(_) => None,
+        }
+    }
+}
+
+impl FromStr for Reference {
+    type Err = ReferenceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(':') {
+            digest::parse(s)
+                .map(Reference::Digest)
+                .map_err(|_| ReferenceError::InvalidReference(s.to_string()))
+        } else if REGEX_TAG.is_match(s) {
+            Ok(Reference::Tag(s.to_string()))
+        } else {
+            Err(ReferenceError::InvalidReference(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reference::Tag(tag) => write!(f, "{}", tag),
+            Reference::Digest(digest) => write!(f, "{}", digest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Reference;
+    use crate::registry_interface::DigestAlgorithm;
+
+    #[test]
+    fn parses_tags_and_digests() {
+        assert_eq!(
+            "v1.2_rc-3".parse::<Reference>().unwrap(),
+            Reference::Tag("v1.2_rc-3".to_string())
+        );
+        let digest = "sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        match digest.parse::<Reference>().unwrap() {
+            Reference::Digest(d) => {
+                assert_eq!(d.algo, DigestAlgorithm::Sha256);
+                assert_eq!(d.to_string(), digest);
+            }
+            r => panic!("Expected a digest, got {:?}", r),
+        }
+        assert_eq!(digest.parse::<Reference>().unwrap().to_string(), digest);
+        // Cosign signatures are stored under a tag named after the digest
+        assert!("sha256-b94d27b9.sig"
+            .parse::<Reference>()
+            .unwrap()
+            .tag()
+            .is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_references() {
+        assert!("".parse::<Reference>().is_err());
+        assert!(".hidden".parse::<Reference>().is_err());
+        assert!("-v1".parse::<Reference>().is_err());
+        assert!("v1/latest".parse::<Reference>().is_err());
+        assert!("md5:abcdef".parse::<Reference>().is_err());
+        assert!("sha256:xyz".parse::<Reference>().is_err());
+        assert!("a".repeat(129).parse::<Reference>().is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use super::StorageDriverError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/*
+ * The referrers API, which lists the manifests whose subject is a given manifest, such as the
+ * SBOMs, signatures and policy bundles ORAS pushes for an image. The list is an OCI image index,
+ * optionally filtered by artifactType.
+ */
+
+pub const REFERRERS_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Referrer {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    pub artifact_type: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReferrerList {
+    pub schema_version: u8,
+    pub media_type: String,
+    pub manifests: Vec<Referrer>,
+    // Whether only one artifactType is listed, which the client is told in a header
+    #[serde(skip)]
+    pub filtered: bool,
+}
+
+impl ReferrerList {
+    pub fn new(mut manifests: Vec<Referrer>, artifact_type: Option<&str>) -> ReferrerList {
+        if let Some(artifact_type) = artifact_type {
+            manifests.retain(|r| r.artifact_type == artifact_type);
+        }
+        ReferrerList {
+            schema_version: 2,
+            media_type: REFERRERS_MEDIA_TYPE.to_string(),
+            manifests,
+            filtered: artifact_type.is_some(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+pub trait Referrers {
+    /// Manifests in the repository whose subject is the digest
+    async fn list_referrers(
+        &self,
+        repo_name: &str,
+        digest: &str,
+    ) -> Result<Vec<Referrer>, StorageDriverError>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Referrer, ReferrerList};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn referrer(artifact_type: &str, digest: char) -> Referrer {
+        Referrer {
+            media_type: "application/vnd.oci.artifact.manifest.v1+json".to_string(),
+            digest: format!("sha256:{}", digest.to_string().repeat(64)),
+            size: 100,
+            artifact_type: artifact_type.to_string(),
+            annotations: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn filters_by_artifact_type() {
+        let referrers = vec![
+            referrer("application/spdx+json", 'a'),
+            referrer("application/vnd.cncf.notary.signature", 'b'),
+        ];
+        let all = ReferrerList::new(referrers.clone(), None);
+        assert_eq!(all.manifests.len(), 2);
+        assert!(!all.filtered);
+
+        let sboms = ReferrerList::new(referrers, Some("application/spdx+json"));
+        assert!(sboms.filtered);
+        assert_eq!(
+            serde_json::to_value(&sboms).unwrap(),
+            json!({
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.index.v1+json",
+                "manifests": [{
+                    "mediaType": "application/vnd.oci.artifact.manifest.v1+json",
+                    "digest": format!("sha256:{}", "a".repeat(64)),
+                    "size": 100,
+                    "artifactType": "application/spdx+json"
+                }]
+            })
+        );
+    }
+}
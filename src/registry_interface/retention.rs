@@ -0,0 +1,25 @@
+use super::StorageDriverError;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RetentionDeletion {
+    pub repo_name: String,
+    // None for manifests pushed by digest
+    pub tag: Option<String>,
+    pub digest: String,
+    // Only the digest's entry in the tag history is removed, the tag stays
+    pub history_only: bool,
+    // The rule causing the deletion
+    pub rule: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RetentionReport {
+    pub deletions: Vec<RetentionDeletion>,
+}
+
+#[rocket::async_trait]
+pub trait Retention {
+    /// What the retention rules would delete if run now, without deleting anything
+    async fn dry_run_retention(&self) -> Result<RetentionReport, StorageDriverError>;
+}
@@ -0,0 +1,47 @@
+use super::StorageDriverError;
+use serde::{Deserialize, Serialize};
+
+/*
+ * Tenants, each owning a top-level namespace, e.g. team-a and everything under team-a/. Only
+ * admins and the tenant's members can see or use its repositories, and the tenant can have its own
+ * quota and retention rules.
+ */
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TenantMember {
+    pub user: String,
+    // pull, push or delete
+    pub permission: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Tenant {
+    pub name: String,
+    #[serde(default)]
+    pub members: Vec<TenantMember>,
+    // BYTES[:IMAGES], as for --quota
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<String>,
+    // As for --retention, only selecting repositories under the tenant's namespace
+    #[serde(default)]
+    pub retention: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TenantList {
+    pub tenants: Vec<Tenant>,
+}
+
+#[rocket::async_trait]
+pub trait Tenancy {
+    /// Every tenant, by name
+    async fn list_tenants(&self) -> Result<Vec<Tenant>, StorageDriverError>;
+
+    /// Creates the tenant, or replaces the one with the same name. Fails with InvalidPolicy if
+    /// it's invalid.
+    async fn put_tenant(&self, tenant: &Tenant) -> Result<Tenant, StorageDriverError>;
+
+    /// Fails with NameUnknown if there's no such tenant, or TenantInUse if there are still
+    /// repositories in its namespace
+    async fn delete_tenant(&self, name: &str) -> Result<(), StorageDriverError>;
+}
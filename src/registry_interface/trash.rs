@@ -0,0 +1,35 @@
+use super::StorageDriverError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/*
+ * Manifests and repositories deleted while Trow runs with --trash-retention, which can be
+ * restored until the window has passed and garbage collection removes them for good.
+ */
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TrashEntry {
+    pub id: String,
+    pub repo_name: String,
+    // The manifest deleted, not set if the whole repository was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    // Tags, and digests, the manifest or repository was stored under
+    pub tags: Vec<String>,
+    pub deleted: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TrashList {
+    pub entries: Vec<TrashEntry>,
+}
+
+#[rocket::async_trait]
+pub trait Trash {
+    /// Oldest first, empty if Trow isn't keeping deleted manifests
+    async fn list_trash(&self) -> Result<Vec<TrashEntry>, StorageDriverError>;
+
+    /// Puts the tags back as they were deleted. Fails if any has been pushed since.
+    async fn restore_trash(&self, id: &str) -> Result<TrashEntry, StorageDriverError>;
+}
@@ -0,0 +1,25 @@
+use super::StorageDriverError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct UnusedImage {
+    pub repo_name: String,
+    pub tag: String,
+    pub digest: String,
+    pub pushed: DateTime<Utc>,
+    // None if never seen running
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct UsageReport {
+    pub unused_for_days: u64,
+    pub images: Vec<UnusedImage>,
+}
+
+#[rocket::async_trait]
+pub trait Usage {
+    /// Tags the usage job hasn't seen running in the cluster for at least the given number of days
+    async fn usage_report(&self, unused_for_days: u64) -> Result<UsageReport, StorageDriverError>;
+}
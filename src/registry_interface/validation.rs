@@ -1,13 +1,176 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 use thiserror::Error;
 
+// Set on pods to admit them during a change freeze, with the reason as the value
+pub const BREAK_GLASS_ANNOTATION: &str = "trow.io/break-glass";
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AdmissionReviewVersion {
+    #[serde(rename = "admission.k8s.io/v1")]
+    V1,
+    #[serde(rename = "admission.k8s.io/v1beta1")]
+    V1Beta1,
+}
+
+/*
+ * Sent to the validation webhook with the request, and sent back with the response, as the same
+ * version. Kubernetes sends v1 or v1beta1 depending on the admissionReviewVersions of the webhook.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AdmissionReview {
+    pub api_version: AdmissionReviewVersion,
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<AdmissionRequest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<AdmissionResponse>,
+}
+
+impl AdmissionReview {
+    // The review to send back, without the request, which Kubernetes doesn't need again
+    pub fn respond(&self, response: AdmissionResponse) -> AdmissionReview {
+        AdmissionReview {
+            api_version: self.api_version,
+            kind: "AdmissionReview".to_string(),
+            request: None,
+            response: Some(response),
+        }
+    }
+}
+
+/*
+ * The request in a Kubernetes AdmissionReview, which is the same in admission.k8s.io/v1 and
+ * v1beta1. Only the fields Trow uses are kept.
+ */
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
 pub struct AdmissionRequest {
     pub uid: String,
-    pub object: serde_json::Value,
+    // Of the object, e.g. apps/v1 Deployment
+    #[serde(default)]
+    pub kind: GroupVersionKind,
+    #[serde(default)]
+    pub resource: GroupVersionResource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
     pub namespace: String,
-    pub operation: String, //CREATE, UPDATE, DELETE, CONNECT
-                           //probably want user info as well, but normally it's the service account :(
+    pub operation: Operation,
+    // Normally a controller's service account, rather than whoever made the change
+    #[serde(default)]
+    pub user_info: UserInfo,
+    #[serde(default)]
+    pub object: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GroupVersionKind {
+    // Blank for the core group, e.g. for pods
+    #[serde(default)]
+    pub group: String,
+    pub version: String,
+    pub kind: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct GroupVersionResource {
+    #[serde(default)]
+    pub group: String,
+    pub version: String,
+    pub resource: String,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Operation {
+    Create,
+    Update,
+    Delete,
+    Connect,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Create => "CREATE",
+            Operation::Update => "UPDATE",
+            Operation::Delete => "DELETE",
+            Operation::Connect => "CONNECT",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UserInfo {
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+// The parts of a pod spec naming images
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct PodSpec {
+    #[serde(default)]
+    containers: Vec<Container>,
+    #[serde(default)]
+    init_containers: Vec<Container>,
+    #[serde(default)]
+    ephemeral_containers: Vec<Container>,
+}
+
+#[derive(Deserialize)]
+struct Container {
+    image: Option<String>,
+}
+
+// Where the pod spec is in each kind of object that creates pods
+fn pod_spec_path(kind: &str) -> Option<&'static str> {
+    match kind {
+        "Pod" => Some("/spec"),
+        "Deployment"
+        | "StatefulSet"
+        | "DaemonSet"
+        | "ReplicaSet"
+        | "ReplicationController"
+        | "Job" => Some("/spec/template/spec"),
+        "CronJob" => Some("/spec/jobTemplate/spec/template/spec"),
+        "PodTemplate" => Some("/template/spec"),
+        _ => None,
+    }
+}
+
+/*
+ * Every image field anywhere in the object, for kinds without a known pod spec.
+ *
+ * The major problem is Rust doesn't have TCO so we could be DOS'd by a malicious request.
+ */
+fn find_images(blob: &Value, images: &mut Vec<String>) {
+    match blob {
+        Value::Array(vals) => {
+            for v in vals {
+                find_images(v, images);
+            }
+        }
+        Value::Object(m) => {
+            for (k, v) in m {
+                if k == "image" {
+                    if let Value::String(image) = v {
+                        images.push(image.to_owned())
+                    }
+                } else {
+                    find_images(v, images);
+                }
+            }
+        }
+        _ => (),
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -15,7 +178,62 @@ pub struct AdmissionResponse {
     pub uid: String,
     pub allowed: bool,
     pub status: Option<Status>,
-    /* Not yet implemented, Patch, PatchType & AuditAnnotations. */
+    // Added to the Kubernetes audit log entry for the request
+    #[serde(
+        rename = "auditAnnotations",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub audit_annotations: Option<HashMap<String, String>>,
+    /* Not yet implemented, Patch & PatchType. */
+}
+
+impl AdmissionRequest {
+    // The images the object's pods would run, including init and ephemeral containers
+    pub fn images(&self) -> Vec<String> {
+        match pod_spec_path(&self.kind.kind) {
+            Some(path) => {
+                let spec: PodSpec = self
+                    .object
+                    .pointer(path)
+                    .and_then(|s| PodSpec::deserialize(s).ok())
+                    .unwrap_or_default();
+                spec.containers
+                    .into_iter()
+                    .chain(spec.init_containers)
+                    .chain(spec.ephemeral_containers)
+                    .filter_map(|c| c.image)
+                    .collect()
+            }
+            None => {
+                let mut images = Vec::new();
+                find_images(&self.object, &mut images);
+                images
+            }
+        }
+    }
+
+    pub fn break_glass(&self) -> Option<&str> {
+        self.object["metadata"]["annotations"][BREAK_GLASS_ANNOTATION]
+            .as_str()
+            .filter(|r| !r.is_empty())
+    }
+}
+
+impl PolicyRequest {
+    pub fn all_images(&self) -> Vec<String> {
+        self.image.iter().chain(&self.images).cloned().collect()
+    }
+}
+
+impl AdmissionResponse {
+    // The reason given if a change freeze was overridden
+    pub fn break_glass(&self) -> Option<&str> {
+        self.audit_annotations
+            .as_ref()?
+            .get(BREAK_GLASS_ANNOTATION)
+            .map(String::as_str)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -29,6 +247,42 @@ pub struct Status {
     pub code: Option<i32>, // Suggested http return code, 0 if not set
 }
 
+/*
+ * A hypothetical admission of an image, or a pod's images, to a namespace, for testing policies.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PolicyRequest {
+    #[serde(default)]
+    pub image: Option<String>,
+    #[serde(default)]
+    pub images: Vec<String>,
+    pub namespace: String,
+    // As if the pod had a trow.io/break-glass annotation with this reason
+    #[serde(default)]
+    pub break_glass: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ImageDecision {
+    pub image: String,
+    pub allowed: bool,
+    pub reason: String,
+    // The allow or deny list entry, or default behaviour, deciding the image
+    pub rule: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PolicyDecision {
+    pub allowed: bool,
+    // Why each image was denied
+    pub reason: Option<String>,
+    pub images: Vec<ImageDecision>,
+    // The change freeze in force in the namespace
+    pub freeze_window: Option<String>,
+    // Allowed only because break_glass overrode a change freeze
+    pub break_glass: bool,
+}
+
 #[derive(Error, Debug)]
 pub enum ValidationError {
     #[error("Internal validation error")]
@@ -44,4 +298,99 @@ pub trait Validation {
         admission_req: &AdmissionRequest,
         host_names: &[String],
     ) -> Result<AdmissionResponse, ValidationError>;
+
+    /// What the admission webhook would decide, without recording the image as admitted
+    async fn evaluate_policy(
+        &self,
+        policy_req: &PolicyRequest,
+        host_names: &[String],
+    ) -> Result<PolicyDecision, ValidationError>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AdmissionResponse, AdmissionReview, AdmissionReviewVersion, Operation};
+    use serde_json::json;
+
+    fn review(api_version: &str, kind: &str, object: serde_json::Value) -> AdmissionReview {
+        serde_json::from_value(json!({
+            "apiVersion": api_version,
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "kind": {"group": "apps", "version": "v1", "kind": kind},
+                "resource": {"group": "apps", "version": "v1", "resource": "things"},
+                "namespace": "prod",
+                "operation": "CREATE",
+                "userInfo": {"username": "alice", "groups": ["system:authenticated"]},
+                "object": object
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn finds_images_in_workloads() {
+        let pod_spec = json!({
+            "initContainers": [{"name": "migrate", "image": "trow.test/migrate:v1"}],
+            "containers": [
+                {"name": "app", "image": "trow.test/app:v1"},
+                {"name": "proxy", "image": "quay.io/envoy:v1.2"}
+            ]
+        });
+        let images = vec![
+            "trow.test/app:v1",
+            "quay.io/envoy:v1.2",
+            "trow.test/migrate:v1",
+        ];
+
+        let pod = review("admission.k8s.io/v1", "Pod", json!({ "spec": pod_spec }));
+        assert_eq!(pod.request.as_ref().unwrap().images(), images);
+        let template = json!({ "spec": { "template": { "spec": pod_spec } } });
+        for kind in ["Deployment", "StatefulSet", "DaemonSet", "Job"] {
+            let r = review("admission.k8s.io/v1", kind, template.clone());
+            assert_eq!(r.request.unwrap().images(), images);
+        }
+        let cron_job = json!({
+            "spec": { "jobTemplate": { "spec": { "template": { "spec": pod_spec } } } }
+        });
+        let r = review("admission.k8s.io/v1beta1", "CronJob", cron_job);
+        assert_eq!(r.api_version, AdmissionReviewVersion::V1Beta1);
+        let req = r.request.unwrap();
+        assert_eq!(req.operation, Operation::Create);
+        assert_eq!(req.user_info.username, "alice");
+        assert_eq!(req.images(), images);
+
+        // Anything else is searched for images
+        let r = review("admission.k8s.io/v1", "Rollout", template);
+        assert_eq!(r.request.unwrap().images().len(), 3);
+    }
+
+    #[test]
+    fn responds_with_same_version() {
+        let r = review("admission.k8s.io/v1", "Pod", json!({}));
+        let resp = r.respond(AdmissionResponse {
+            uid: "705ab4f5-6393-11e8-b7cc-42010a800002".to_string(),
+            allowed: true,
+            status: None,
+            audit_annotations: None,
+        });
+        assert_eq!(
+            serde_json::to_value(resp).unwrap(),
+            json!({
+                "apiVersion": "admission.k8s.io/v1",
+                "kind": "AdmissionReview",
+                "response": {
+                    "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                    "allowed": true,
+                    "status": null
+                }
+            })
+        );
+        assert!(serde_json::from_value::<AdmissionReview>(json!({
+            "apiVersion": "admission.k8s.io/v2",
+            "kind": "AdmissionReview"
+        }))
+        .is_err());
+    }
 }
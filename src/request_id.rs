@@ -0,0 +1,65 @@
+use rocket::data::Data;
+use rocket::http::Header;
+use rocket::request::Request;
+use rocket::route::{Handler, Outcome, Route};
+use tonic::metadata::MetadataValue;
+use tonic::Status;
+use trow_server::request_id::{self, REQUEST_ID_HEADER};
+
+/*
+ * Gives each HTTP request an ID, which is logged with everything done for the request in both
+ * the frontend and backend (see trow_server::request_id), and returned in the X-Request-Id
+ * response header. Clients can set their own ID with an X-Request-Id request header.
+ */
+
+struct RequestId(String);
+
+/// The ID of the request, created on first use
+pub fn request_id<'r>(req: &'r Request<'_>) -> &'r str {
+    &req.local_cache(|| {
+        let id = req
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .filter(|id| request_id::is_valid(id))
+            .map(|id| id.to_string())
+            .unwrap_or_else(request_id::new_request_id);
+        RequestId(id)
+    })
+    .0
+}
+
+pub fn response_header(req: &Request<'_>) -> Header<'static> {
+    Header::new("X-Request-Id", request_id(req).to_string())
+}
+
+// Runs the route's handler with the request ID set
+#[derive(Clone)]
+struct WithRequestId(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for WithRequestId {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let id = request_id(req).to_string();
+        request_id::scope(id, self.0.handle(req, data)).await
+    }
+}
+
+pub fn with_request_ids(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(WithRequestId(route.handler));
+            route
+        })
+        .collect()
+}
+
+/// gRPC interceptor passing the current request ID to the backend
+pub fn add_to_grpc_request(mut req: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
+    if let Some(id) = request_id::current() {
+        if let Ok(value) = MetadataValue::from_str(&id) {
+            req.metadata_mut().insert(REQUEST_ID_HEADER, value);
+        }
+    }
+    Ok(req)
+}
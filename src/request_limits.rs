@@ -0,0 +1,370 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use log::warn;
+use prometheus::{opts, register_int_counter_vec, IntCounterVec};
+use rocket::data::{Data, DataStream, Limits, ToByteUnit, N};
+use rocket::request::Request;
+use rocket::route::{Handler, Outcome, Route};
+use rocket::tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use rocket::tokio::time::{sleep_until, Instant};
+
+use crate::registry_interface::AsyncSeekRead;
+use crate::response::errors::Error;
+
+/*
+ * Hard limits on requests, so a handful of stalled or deliberately slow clients can't tie up the
+ * registry.
+ *
+ * Blobs and manifests have their own size limits. Every other body, e.g. the JSON sent to the
+ * admin API, is limited to --max-request-size, and requests with more than --max-header-size of
+ * headers get a 431 before reaching a route.
+ *
+ * Uploads that send nothing for --idle-timeout, or are still going after --transfer-timeout, are
+ * cut off with a 408. Downloads are held to the same timeouts, checked each time more of the blob
+ * is read, so one the client has stopped reading is cut off as soon as it reads again. Either
+ * timeout can be turned off.
+ */
+
+lazy_static! {
+    pub static ref REQUESTS_CUT_OFF: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "requests_cut_off_total",
+            "total number of requests cut off for going over a request limit, by the limit"
+        ),
+        &["limit"]
+    )
+    .unwrap();
+}
+
+/// The limits for bodies other than blobs and manifests, for Rocket's config
+pub fn body_limits(max_request_size: u32) -> Limits {
+    let size = max_request_size.mebibytes();
+    Limits::default()
+        .limit("json", size)
+        .limit("form", size)
+        .limit("data-form", size)
+        .limit("string", size)
+        .limit("bytes", size)
+        .limit("file", size)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransferLimits {
+    // Longest without any data being sent, None for no limit
+    pub idle_timeout: Option<Duration>,
+    // Longest the whole transfer can take, None for no limit
+    pub transfer_timeout: Option<Duration>,
+}
+
+impl TransferLimits {
+    /// Timeouts of zero are no limit
+    pub fn new(idle_timeout: Duration, transfer_timeout: Duration) -> TransferLimits {
+        TransferLimits {
+            idle_timeout: Some(idle_timeout).filter(|t| !t.is_zero()),
+            transfer_timeout: Some(transfer_timeout).filter(|t| !t.is_zero()),
+        }
+    }
+
+    // When a transfer started then times out, unless there's progress after last_progress
+    fn deadline(
+        &self,
+        started: Instant,
+        last_progress: Instant,
+    ) -> Option<(Instant, &'static str)> {
+        let idle = self.idle_timeout.map(|t| (last_progress + t, "idle"));
+        let transfer = self.transfer_timeout.map(|t| (started + t, "transfer"));
+        match (idle, transfer) {
+            (Some(i), Some(t)) => Some(if i.0 <= t.0 { i } else { t }),
+            (i, t) => i.or(t),
+        }
+    }
+
+    // The error for the limit that was hit, counted in the metrics
+    fn timed_out(&self, limit: &'static str) -> io::Error {
+        REQUESTS_CUT_OFF.with_label_values(&[limit]).inc();
+        let message = match limit {
+            "idle" => format!(
+                "Nothing was sent for {}s",
+                self.idle_timeout.unwrap_or_default().as_secs()
+            ),
+            _ => format!(
+                "Transfer took longer than {}s",
+                self.transfer_timeout.unwrap_or_default().as_secs()
+            ),
+        };
+        io::Error::new(io::ErrorKind::TimedOut, message)
+    }
+
+    /*
+     * Streams the body to the sink as DataStream::stream_to does, failing with a TimedOut error
+     * if the client stalls or takes too long.
+     */
+    pub async fn stream_to<W: AsyncWrite + Unpin>(
+        &self,
+        data: DataStream<'_>,
+        sink: &mut W,
+    ) -> io::Result<N> {
+        let progress = Mutex::new(Instant::now());
+        let mut writer = ProgressWriter {
+            inner: sink,
+            progress: &progress,
+        };
+        self.timed(&progress, data.stream_to(&mut writer)).await
+    }
+
+    // Runs the transfer until it finishes or goes over a limit, going by the progress it records
+    async fn timed<T>(
+        &self,
+        progress: &Mutex<Instant>,
+        transfer: impl Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        let started = Instant::now();
+        tokio::pin!(transfer);
+        loop {
+            let deadline = match self.deadline(started, *progress.lock().unwrap()) {
+                Some((deadline, _)) => deadline,
+                None => return transfer.await,
+            };
+            tokio::select! {
+                res = &mut transfer => return res,
+                _ = sleep_until(deadline) => {
+                    // Data that came in meanwhile moves the deadline on
+                    if let Some((deadline, limit)) = self.deadline(started, *progress.lock().unwrap()) {
+                        if deadline <= Instant::now() {
+                            return Err(self.timed_out(limit));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wraps a blob's reader so reading fails once the download has stalled or taken too long
+    pub fn reader(&self, inner: Pin<Box<dyn AsyncSeekRead>>) -> Pin<Box<dyn AsyncSeekRead>> {
+        if *self == TransferLimits::default() {
+            return inner;
+        }
+        let now = Instant::now();
+        Box::pin(TimedReader {
+            inner,
+            limits: *self,
+            started: now,
+            last_read: now,
+        })
+    }
+}
+
+// Records when data was last written through it
+struct ProgressWriter<'a, W> {
+    inner: &'a mut W,
+    progress: &'a Mutex<Instant>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ProgressWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                *self.progress.lock().unwrap() = Instant::now();
+            }
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+struct TimedReader {
+    inner: Pin<Box<dyn AsyncSeekRead>>,
+    limits: TransferLimits,
+    started: Instant,
+    last_read: Instant,
+}
+
+impl AsyncRead for TimedReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some((deadline, limit)) = self.limits.deadline(self.started, self.last_read) {
+            if deadline <= Instant::now() {
+                return Poll::Ready(Err(self.limits.timed_out(limit)));
+            }
+        }
+        let res = self.inner.as_mut().poll_read(cx, buf);
+        if res.is_ready() {
+            self.last_read = Instant::now();
+        }
+        res
+    }
+}
+
+impl AsyncSeek for TimedReader {
+    fn start_seek(mut self: Pin<&mut Self>, pos: io::SeekFrom) -> io::Result<()> {
+        self.inner.as_mut().start_seek(pos)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        self.inner.as_mut().poll_complete(cx)
+    }
+}
+
+impl AsyncSeekRead for TimedReader {}
+
+// The bytes the request's headers take up, as sent
+fn header_size(req: &Request<'_>) -> usize {
+    req.headers()
+        .iter()
+        // Each is "name: value\r\n"
+        .map(|h| h.name().as_str().len() + h.value().len() + 4)
+        .sum()
+}
+
+#[derive(Clone)]
+struct WithHeaderLimit {
+    handler: Box<dyn Handler>,
+    max_bytes: usize,
+}
+
+#[rocket::async_trait]
+impl Handler for WithHeaderLimit {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let size = header_size(req);
+        if size <= self.max_bytes {
+            return self.handler.handle(req, data).await;
+        }
+        warn!(
+            "Refused {} {} with {} bytes of headers, over the limit of {}",
+            req.method(),
+            req.uri().path(),
+            size,
+            self.max_bytes
+        );
+        REQUESTS_CUT_OFF.with_label_values(&["headers"]).inc();
+        Outcome::from(req, Error::HeadersTooLarge(self.max_bytes))
+    }
+}
+
+/// Refuses requests to the routes with more than max_bytes of headers, or none if it's 0
+pub fn with_header_limit(routes: Vec<Route>, max_bytes: usize) -> Vec<Route> {
+    if max_bytes == 0 {
+        return routes;
+    }
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(WithHeaderLimit {
+                handler: route.handler,
+                max_bytes,
+            });
+            route
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ProgressWriter, TransferLimits};
+    use rocket::tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+    use rocket::tokio::time::{sleep, Instant};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn limits(idle_ms: u64, transfer_ms: u64) -> TransferLimits {
+        TransferLimits::new(
+            Duration::from_millis(idle_ms),
+            Duration::from_millis(transfer_ms),
+        )
+    }
+
+    // Copies from the client end of a pipe through a ProgressWriter, as stream_to does
+    async fn transfer(
+        limits: TransferLimits,
+        client: impl FnOnce(io::DuplexStream),
+    ) -> io::Result<u64> {
+        let (mut server, client_end) = io::duplex(64);
+        client(client_end);
+        let progress = Mutex::new(Instant::now());
+        let mut sink = vec![];
+        let mut writer = ProgressWriter {
+            inner: &mut sink,
+            progress: &progress,
+        };
+        limits
+            .timed(&progress, io::copy(&mut server, &mut writer))
+            .await
+    }
+
+    #[rocket::async_test]
+    async fn cuts_off_stalled_transfers() {
+        // Keeps sending, so only the transfer timeout applies
+        let trickle = |mut c: io::DuplexStream| {
+            rocket::tokio::spawn(async move {
+                loop {
+                    if c.write_all(b"x").await.is_err() {
+                        return;
+                    }
+                    sleep(Duration::from_millis(10)).await;
+                }
+            });
+        };
+        let err = transfer(limits(100, 300), trickle).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(err.to_string().contains("longer than"));
+
+        // Sends a little, then stalls with the connection open
+        let stall = |mut c: io::DuplexStream| {
+            rocket::tokio::spawn(async move {
+                c.write_all(b"some").await.unwrap();
+                sleep(Duration::from_secs(60)).await;
+                drop(c);
+            });
+        };
+        let err = transfer(limits(100, 0), stall).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(err.to_string().contains("Nothing was sent"));
+
+        // Finishes in time
+        let quick = |mut c: io::DuplexStream| {
+            rocket::tokio::spawn(async move {
+                c.write_all(b"done").await.unwrap();
+            });
+        };
+        assert_eq!(transfer(limits(100, 300), quick).await.unwrap(), 4);
+        assert_eq!(transfer(TransferLimits::default(), quick).await.unwrap(), 4);
+    }
+
+    #[rocket::async_test]
+    async fn cuts_off_slow_downloads() {
+        let blob = std::io::Cursor::new(vec![7u8; 16]);
+        let mut reader = limits(50, 0).reader(Box::pin(blob));
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        let err = reader.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        let blob = std::io::Cursor::new(vec![7u8; 16]);
+        let mut reader = limits(50, 0).reader(Box::pin(blob));
+        let mut all = vec![];
+        reader.read_to_end(&mut all).await.unwrap();
+        assert_eq!(all.len(), 16);
+    }
+}
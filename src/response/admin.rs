@@ -0,0 +1,270 @@
+use std::io::Cursor;
+
+use crate::config_reload::ConfigStatus;
+use crate::image_details::ImageDetails;
+use crate::rate_limit::RateLimitConfig;
+use crate::registry_interface::{
+    ActiveTransferList, ImageArchive, ImageImported, PullStats, ReadOnlyStatus, RepositoryDeleted,
+    RepositoryList, RepositoryStorage, ScrubReport, StorageReport, TagHistory, Tenant, TenantList,
+    TransferCancelled, TrashEntry, TrashList, UploadList,
+};
+use crate::transfer::TransferReport;
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::stream::ByteStream;
+use rocket::response::{self, Responder, Response};
+
+impl<'r> Responder<'r, 'static> for RepositoryList {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for RepositoryDeleted {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for UploadList {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ActiveTransferList {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TransferCancelled {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for PullStats {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for RepositoryStorage {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for StorageReport {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ImageImported {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+// Sent as it's read from the backend, so the length isn't known
+impl<'r> Responder<'r, 'r> for ImageArchive {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'r> {
+        let disposition = format!("attachment; filename=\"{}\"", self.file_name());
+        Response::build_from(ByteStream(self.chunks).respond_to(req)?)
+            .header(ContentType::new("application", "x-tar"))
+            .raw_header("Content-Disposition", disposition)
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for RateLimitConfig {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TenantList {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Tenant {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TrashList {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TrashEntry {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ReadOnlyStatus {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ScrubReport {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TagHistory {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ImageDetails {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TransferReport {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ConfigStatus {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::registry_interface::{RepositoryInfo, RepositoryList};
+    use crate::response::test_helper::test_client;
+    use rocket::http::{ContentType, Status};
+    use rocket::response::Responder;
+
+    #[test]
+    fn repository_list_ok() {
+        let cl = test_client();
+        let req = cl.get("/");
+        let list = RepositoryList {
+            repositories: vec![RepositoryInfo {
+                name: "org/app".to_string(),
+                tags: 2,
+                bytes: 1024,
+            }],
+        };
+        let response = list.respond_to(req.inner()).unwrap();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+    }
+}
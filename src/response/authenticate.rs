@@ -1,9 +1,11 @@
+use crate::response::errors::Error;
 use crate::response::get_base_url;
 use rocket::http::ContentType;
 use rocket::http::{Header, Status};
 use rocket::request::Request;
 use rocket::response::{self, Responder, Response};
 use serde::Serialize;
+use std::io::Cursor;
 
 /*
  * Generate a WWW-Authenticate header
@@ -25,6 +27,7 @@ impl<'r> Responder<'r, 'static> for Authenticate {
             .status(Status::Unauthorized)
             .header(authenticate_header)
             .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(Error::Unauthorized.to_string()))
             .ok()
     }
 }
@@ -0,0 +1,72 @@
+use crate::registry_interface::BlobMetadata;
+use crate::response::etag::{self, digest_etag};
+use rocket::http::{Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use std::io::Cursor;
+
+/*
+ * Answers a HEAD request for a blob with the same headers a GET would get, without the blob.
+ *
+ * Rocket drops the body of responses to HEAD requests but keeps its size, which is how the
+ * Content-Length is set here.
+ */
+impl<'r> Responder<'r, 'static> for BlobMetadata {
+    fn respond_to(self, req: &Request) -> response::Result<'static> {
+        let digest = Header::new("Docker-Content-Digest", self.digest.to_string());
+        let tag = digest_etag(&self.digest.to_string());
+
+        if etag::not_modified(req, &tag) {
+            return Response::build()
+                .status(Status::NotModified)
+                .header(Header::new("ETag", tag))
+                .header(digest)
+                .ok();
+        }
+
+        Response::build()
+            .header(Header::new("Content-Type", "application/octet-stream"))
+            .header(Header::new("Accept-Ranges", "bytes"))
+            .header(Header::new("ETag", tag))
+            .header(digest)
+            .sized_body(self.size as usize, Cursor::new(vec![]))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::registry_interface::{digest, BlobMetadata};
+    use crate::response::etag::digest_etag;
+    use crate::response::test_helper::test_client;
+    use rocket::http::{Header, Status};
+    use rocket::response::Responder;
+
+    const DIGEST: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn metadata() -> BlobMetadata {
+        BlobMetadata {
+            digest: digest::parse(DIGEST).unwrap(),
+            size: 100,
+        }
+    }
+
+    #[test]
+    fn headers() {
+        let cl = test_client();
+        let req = cl.head("/");
+        let resp = metadata().respond_to(req.inner()).unwrap();
+        assert_eq!(resp.status(), Status::Ok);
+        assert_eq!(resp.body().preset_size(), Some(100));
+        assert_eq!(
+            resp.headers().get_one("Docker-Content-Digest"),
+            Some(DIGEST)
+        );
+
+        let req = cl
+            .head("/")
+            .header(Header::new("If-None-Match", digest_etag(DIGEST)));
+        let resp = metadata().respond_to(req.inner()).unwrap();
+        assert_eq!(resp.status(), Status::NotModified);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::registry_interface::{ByteRange, ByteRanges};
+use crate::response::etag::digest_etag;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+
+/*
+ * Parses the Range header of blob GETs.
+ *
+ * Several ranges can be asked for at once, up to MAX_RANGES, and are sent as a
+ * multipart/byteranges response. Anything else, including a header with more ranges than that,
+ * is ignored and the whole blob is sent, which RFC 7233 allows. Should be wrapped in an Option in
+ * routes.
+ *
+ * An If-Range header must be the blob's ETag for the range to be used. Dates are never
+ * matched, so clients resuming with one get the whole blob.
+ */
+const MAX_RANGES: usize = 100;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ByteRanges {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        if let Some(if_range) = request.headers().get_one("If-Range") {
+            // The digest is the last part of the blob path
+            let path = request.uri().path().to_string();
+            let digest = path.rsplit('/').next().unwrap_or_default();
+            if if_range.trim() != digest_etag(digest) {
+                return Outcome::Forward(());
+            }
+        }
+        match request.headers().get_one("Range").and_then(parse_ranges) {
+            Some(r) => Outcome::Success(r),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+fn parse_ranges(header: &str) -> Option<ByteRanges> {
+    let specs: Vec<&str> = header.trim().strip_prefix("bytes=")?.split(',').collect();
+    if specs.len() > MAX_RANGES {
+        return None;
+    }
+    let ranges = specs
+        .into_iter()
+        .map(parse_range)
+        .collect::<Option<Vec<ByteRange>>>()?;
+    Some(ByteRanges(ranges))
+}
+
+fn parse_range(spec: &str) -> Option<ByteRange> {
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        return end.parse().ok().map(ByteRange::Suffix);
+    }
+    let start = start.parse().ok()?;
+    let end = if end.is_empty() {
+        None
+    } else {
+        let end = end.parse().ok()?;
+        if end < start {
+            return None;
+        }
+        Some(end)
+    };
+    Some(ByteRange::From(start, end))
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_ranges;
+    use crate::registry_interface::{ByteRange, ByteRanges};
+
+    #[test]
+    fn parse_ranges_header() {
+        let one = |r| Some(ByteRanges(vec![r]));
+        assert_eq!(
+            parse_ranges("bytes=100-199"),
+            one(ByteRange::From(100, Some(199)))
+        );
+        assert_eq!(parse_ranges("bytes=100-"), one(ByteRange::From(100, None)));
+        assert_eq!(parse_ranges("bytes=-50"), one(ByteRange::Suffix(50)));
+        assert_eq!(
+            parse_ranges("bytes=0-1, 5-6,-2"),
+            Some(ByteRanges(vec![
+                ByteRange::From(0, Some(1)),
+                ByteRange::From(5, Some(6)),
+                ByteRange::Suffix(2)
+            ]))
+        );
+        assert_eq!(parse_ranges("bytes=0-1,6-5"), None);
+        assert_eq!(parse_ranges("bytes=10-5"), None);
+        assert_eq!(parse_ranges("items=0-5"), None);
+        let many: Vec<String> = (0..101).map(|i| format!("{}-{}", i, i)).collect();
+        assert_eq!(parse_ranges(&format!("bytes={}", many.join(","))), None);
+
+        assert_eq!(
+            ByteRange::From(100, Some(199)).resolve(150),
+            Some((100, 149))
+        );
+        assert_eq!(ByteRange::From(150, None).resolve(150), None);
+        assert_eq!(ByteRange::Suffix(500).resolve(150), Some((0, 149)));
+        assert_eq!(ByteRange::Suffix(5).resolve(0), None);
+    }
+}
@@ -0,0 +1,50 @@
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+
+/*
+ * A blob GET asking for a Content-Digest with the response, as in RFC 9530, e.g.
+ * "Want-Content-Digest: sha-256". Clients downloading a blob in parts use it to check each part,
+ * as the blob's digest only covers the whole thing.
+ *
+ * Only sha-256 is supported, and only if it's not given a preference of 0, which means it's not
+ * wanted. Should be wrapped in an Option in routes.
+ */
+pub struct WantContentDigest;
+
+fn wants_sha256(header: &str) -> bool {
+    header.split(',').any(|alg| {
+        let (name, pref) = alg.split_once('=').unwrap_or((alg, "1"));
+        name.trim().eq_ignore_ascii_case("sha-256") && pref.trim() != "0"
+    })
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WantContentDigest {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        if request
+            .headers()
+            .get("Want-Content-Digest")
+            .any(wants_sha256)
+        {
+            Outcome::Success(WantContentDigest)
+        } else {
+            Outcome::Forward(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::wants_sha256;
+
+    #[test]
+    fn parses_preferences() {
+        assert!(wants_sha256("sha-256"));
+        assert!(wants_sha256("sha-512=3, sha-256=10"));
+        assert!(wants_sha256("SHA-256=1"));
+        assert!(!wants_sha256("sha-512"));
+        assert!(!wants_sha256("sha-256=0"));
+    }
+}
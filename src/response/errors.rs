@@ -1,3 +1,4 @@
+use crate::registry_interface::StorageDriverError;
 use rocket::http::{ContentType, Status};
 use rocket::request::Request;
 use rocket::response;
@@ -10,32 +11,63 @@ use std::error;
 use std::fmt;
 use std::io::Cursor;
 
+/*
+ * Errors returned to clients, each with one of the distribution spec's error codes and the status
+ * the spec gives for it, in the spec's JSON body:
+ *
+ * {"errors": [{"code": "MANIFEST_UNKNOWN", "message": "Manifest unknown", "detail": ...}]}
+ *
+ * Failures from the backend come in as StorageDriverError. Routes match the ones whose meaning
+ * depends on the request, e.g. an invalid manifest is unknown when pulling but invalid when
+ * pushing, and convert the rest with From.
+ */
 #[derive(Debug)]
 pub enum Error {
-    /*
-    BLOB_UNKNOWN,
-
-    BLOB_UPLOAD_UNKNOWN,
-    DIGEST_INVALID,
-    MANIFEST_BLOB_UNKNOWN,
-    ,
-    MANIFEST_UNVERIFIED,
-    NAME_UNKNOWN,
-    SIZE_INVALID,
-    TAG_INVALID,
-    UNAUTHORIZED,
-    DENIED,
-    */
     NameInvalid(String),
+    NameUnknown(String),
     BlobUploadInvalid(String),
     ManifestUnknown(String),
     ManifestInvalid(String),
+    // Digest of a blob the pushed manifest refers to
+    ManifestBlobUnknown(String),
+    // Over the size limit for manifests or blobs
+    SizeInvalid(String),
     Unauthorized,
+    Denied(String),
     BlobUnknown,
     BlobUploadUnknown,
     Unsupported,
     InternalError,
     DigestInvalid,
+    // Not part of the distribution spec, the Range asked for is outside the blob
+    RangeInvalid(String),
+    // Not part of the distribution spec, used by the Trow job API
+    JobUnknown(String),
+    JobInvalid(String),
+    // Reported with the DENIED code, as clients show its message
+    QuotaExceeded(String),
+    TagInvalid(String),
+    // Used by the first run setup API
+    SetupUnavailable,
+    SetupDenied(String),
+    // Seconds until the client can retry
+    TooManyRequests(u64),
+    // Bad parameters for the transfer report
+    TransferInvalid(String),
+    // Bad time to look up a tag's history at
+    HistoryInvalid(String),
+    // A tenant that can't be stored or deleted, with why
+    TenantInvalid(String),
+    // The registry is in read-only maintenance mode, with why
+    ReadOnly(String),
+    // Not part of the distribution spec, the client stalled or took too long, with which
+    RequestTimeout(String),
+    // Not part of the distribution spec, with the most bytes of headers allowed
+    HeadersTooLarge(usize),
+    // Reported with the DENIED code, as clients show its message
+    InsufficientStorage(String),
+    // Reported with the BLOB_UPLOAD_INVALID code, with the bytes stored, sent as the Range header
+    UploadOutOfOrder(u64),
 }
 
 // Create ErrorMsg struct that serializes to json of appropriate type
@@ -54,7 +86,9 @@ impl fmt::Display for Error {
                 format_error_json(f, "UNAUTHORIZED", "Authorization required", None)
             }
             Error::BlobUnknown => format_error_json(f, "BLOB_UNKNOWN", "Blob Unknown", None),
-            Error::BlobUploadUnknown => write!(f, "Blob Upload Unknown"),
+            Error::BlobUploadUnknown => {
+                format_error_json(f, "BLOB_UPLOAD_UNKNOWN", "Blob upload unknown", None)
+            }
             Error::BlobUploadInvalid(ref detail) => format_error_json(
                 f,
                 "BLOB_UPLOAD_INVALID",
@@ -77,6 +111,25 @@ impl fmt::Display for Error {
                 "Manifest invalid",
                 Some(json!({ "detail": detail })),
             ),
+            Error::ManifestBlobUnknown(ref digest) => format_error_json(
+                f,
+                "MANIFEST_BLOB_UNKNOWN",
+                "Manifest references a blob unknown to the registry",
+                Some(json!({ "digest": digest })),
+            ),
+            Error::SizeInvalid(ref detail) => format_error_json(
+                f,
+                "SIZE_INVALID",
+                "Content is over the size limit",
+                Some(json!({ "Reason": detail })),
+            ),
+            Error::Denied(ref reason) => format_error_json(f, "DENIED", reason, None),
+            Error::RangeInvalid(ref range) => format_error_json(
+                f,
+                "RANGE_INVALID",
+                "Range not satisfiable",
+                Some(json!({ "Range": range })),
+            ),
             Error::ManifestUnknown(ref tag) => format_error_json(
                 f,
                 "MANIFEST_UNKNOWN",
@@ -89,6 +142,71 @@ impl fmt::Display for Error {
                 "Invalid repository name",
                 Some(json!({ "Repository": name })),
             ),
+            Error::NameUnknown(ref name) => format_error_json(
+                f,
+                "NAME_UNKNOWN",
+                "Repository name not known to registry",
+                Some(json!({ "Repository": name })),
+            ),
+            Error::JobUnknown(ref id) => {
+                format_error_json(f, "JOB_UNKNOWN", "Job unknown", Some(json!({ "Job": id })))
+            }
+            Error::JobInvalid(ref detail) => format_error_json(
+                f,
+                "JOB_INVALID",
+                "Invalid job request",
+                Some(json!({ "Reason": detail })),
+            ),
+            Error::QuotaExceeded(ref reason) => format_error_json(f, "DENIED", reason, None),
+            Error::TagInvalid(ref reason) => format_error_json(f, "TAG_INVALID", reason, None),
+            Error::SetupUnavailable => {
+                format_error_json(f, "UNSUPPORTED", "First run setup isn't enabled", None)
+            }
+            Error::SetupDenied(ref reason) => format_error_json(f, "DENIED", reason, None),
+            Error::TooManyRequests(retry_after) => format_error_json(
+                f,
+                "TOOMANYREQUESTS",
+                "Too many requests",
+                Some(json!({ "RetryAfter": retry_after })),
+            ),
+            Error::TransferInvalid(ref reason) => format_error_json(
+                f,
+                "TRANSFER_INVALID",
+                "Invalid transfer report request",
+                Some(json!({ "Reason": reason })),
+            ),
+            Error::HistoryInvalid(ref reason) => format_error_json(
+                f,
+                "HISTORY_INVALID",
+                "Invalid tag history request",
+                Some(json!({ "Reason": reason })),
+            ),
+            Error::TenantInvalid(ref reason) => format_error_json(
+                f,
+                "TENANT_INVALID",
+                "Invalid tenant request",
+                Some(json!({ "Reason": reason })),
+            ),
+            Error::ReadOnly(ref reason) => format_error_json(f, "UNAVAILABLE", reason, None),
+            Error::RequestTimeout(ref reason) => format_error_json(
+                f,
+                "REQUEST_TIMEOUT",
+                "Request timed out",
+                Some(json!({ "Reason": reason })),
+            ),
+            Error::InsufficientStorage(ref reason) => format_error_json(f, "DENIED", reason, None),
+            Error::HeadersTooLarge(max) => format_error_json(
+                f,
+                "HEADERS_TOO_LARGE",
+                "Request headers are over the size limit",
+                Some(json!({ "MaxBytes": max })),
+            ),
+            Error::UploadOutOfOrder(stored) => format_error_json(
+                f,
+                "BLOB_UPLOAD_INVALID",
+                "Chunk doesn't start where the upload's stored data ends",
+                Some(json!({ "Stored": stored })),
+            ),
         }
     }
 }
@@ -112,6 +230,33 @@ fn format_error_json(
     )
 }
 
+impl From<StorageDriverError> for Error {
+    fn from(e: StorageDriverError) -> Error {
+        match e {
+            StorageDriverError::InvalidName(name) => Error::NameInvalid(name),
+            StorageDriverError::NameUnknown(name) => Error::NameUnknown(name),
+            StorageDriverError::InvalidManifest => Error::ManifestInvalid(String::new()),
+            StorageDriverError::ManifestRejected(reason) => Error::ManifestInvalid(reason),
+            StorageDriverError::BlobUnknown(_) => Error::BlobUnknown,
+            StorageDriverError::InvalidDigest => Error::DigestInvalid,
+            StorageDriverError::Unsupported => Error::Unsupported,
+            StorageDriverError::InvalidContentRange => Error::BlobUploadInvalid(e.to_string()),
+            StorageDriverError::OutOfOrder(stored) => Error::UploadOutOfOrder(stored),
+            StorageDriverError::TooLarge => Error::SizeInvalid(e.to_string()),
+            StorageDriverError::QuotaExceeded(reason) => Error::QuotaExceeded(reason),
+            StorageDriverError::TagImmutable(reason) => Error::TagInvalid(reason),
+            StorageDriverError::InvalidArchive(reason) => Error::ManifestInvalid(reason),
+            StorageDriverError::InvalidPolicy(_) => Error::Unsupported,
+            StorageDriverError::TenantInUse(reason) => Error::TenantInvalid(reason),
+            StorageDriverError::ReadOnly(reason) => Error::ReadOnly(reason),
+            StorageDriverError::TimedOut(reason) => Error::RequestTimeout(reason),
+            StorageDriverError::InsufficientStorage(reason) => Error::InsufficientStorage(reason),
+            StorageDriverError::UploadUnknown(_) => Error::BlobUploadUnknown,
+            StorageDriverError::Internal => Error::InternalError,
+        }
+    }
+}
+
 impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
@@ -122,10 +267,29 @@ impl error::Error for Error {
             Error::BlobUploadInvalid(_) => "The blob upload encountered an error and can no longer proceed.",
             Error::InternalError => "An internal error occured, please consult the logs for more details.",
             Error::DigestInvalid => "When a blob is uploaded, the registry will check that the content matches the digest provided by the client. The error may include a detail structure with the key \"digest\", including the invalid digest string. This error may also be returned when a manifest includes an invalid layer digest.",
+            Error::ManifestBlobUnknown(_) => "This error may be returned when a manifest blob is unknown to the registry.",
+            Error::SizeInvalid(_) => "The content is larger than the registry allows.",
+            Error::Denied(_) => "The access controller denied access for the operation on a resource.",
+            Error::RangeInvalid(_) => "The Range header asks for bytes outside the blob.",
             Error::ManifestInvalid(_) => "During upload, manifests undergo several checks ensuring validity. If those checks fail, this error may be returned, unless a more specific error is included. The detail will contain information the failed validation.",
             Error::ManifestUnknown(_) => "This error is returned when the manifest, identified by name and tag is unknown to the repository.",
-            Error::NameInvalid(_) => "Invalid repository name encountered either during manifest validation or any API operation."
-
+            Error::NameInvalid(_) => "Invalid repository name encountered either during manifest validation or any API operation.",
+            Error::NameUnknown(_) => "This is returned if the name used during an operation is unknown to the registry.",
+            Error::JobUnknown(_) => "The job id is unknown. Jobs are only kept until Trow restarts.",
+            Error::JobInvalid(_) => "The job could not be started, most likely because the type of job is not supported.",
+            Error::QuotaExceeded(_) => "The push would take the repository over its storage quota.",
+            Error::TagInvalid(_) => "The tag can't be written to, most likely because it's immutable and has already been pushed.",
+            Error::SetupUnavailable => "Trow wasn't started with --first-run-setup.",
+            Error::SetupDenied(_) => "Setup can only be completed once, by the bootstrap admin, with a valid user and password.",
+            Error::TooManyRequests(_) => "The client made too many requests or uploads at once and should retry after the Retry-After header's number of seconds.",
+            Error::TransferInvalid(_) => "The transfer report was asked for with an invalid day or grouping.",
+            Error::HistoryInvalid(_) => "The tag history was asked for at a time that isn't an RFC 3339 timestamp.",
+            Error::TenantInvalid(_) => "The tenant is invalid, or can't be deleted as it still has repositories.",
+            Error::ReadOnly(_) => "The registry is read-only for maintenance, writes will be accepted again once it's over.",
+            Error::RequestTimeout(_) => "The client stopped sending for longer than --idle-timeout, or took longer than --transfer-timeout.",
+            Error::HeadersTooLarge(_) => "The request's headers are larger than --max-header-size allows.",
+            Error::InsufficientStorage(_) => "The registry's storage is below its --storage-reserve, or full, so uploads are refused until space is freed.",
+            Error::UploadOutOfOrder(_) => "The chunk overlaps or doesn't follow on from the data stored so far, which the Range header gives."
         }
     }
 }
@@ -133,22 +297,139 @@ impl error::Error for Error {
 impl<'r> Responder<'r, 'static> for Error {
     fn respond_to(self, _req: &Request) -> response::Result<'static> {
         let json = format!("{}", self);
+        let retry_after = match self {
+            Error::TooManyRequests(secs) => Some(secs),
+            _ => None,
+        };
+        // As given for uploads in progress, where the next chunk has to start
+        let range = match self {
+            Error::UploadOutOfOrder(stored) => Some(format!("0-{}", stored.saturating_sub(1))),
+            _ => None,
+        };
 
         let status = match self {
             Error::Unsupported => Status::MethodNotAllowed,
             Error::Unauthorized => Status::Unauthorized,
-            Error::BlobUploadUnknown | Error::ManifestUnknown(_) => Status::NotFound,
+            Error::QuotaExceeded(_) | Error::SetupDenied(_) | Error::Denied(_) => Status::Forbidden,
+            Error::BlobUnknown
+            | Error::BlobUploadUnknown
+            | Error::SetupUnavailable
+            | Error::ManifestUnknown(_)
+            | Error::NameUnknown(_)
+            | Error::JobUnknown(_) => Status::NotFound,
             Error::InternalError => Status::InternalServerError,
-            Error::BlobUploadInvalid(_) => Status::RangeNotSatisfiable,
+            Error::BlobUploadInvalid(_) | Error::RangeInvalid(_) | Error::UploadOutOfOrder(_) => {
+                Status::RangeNotSatisfiable
+            }
+            Error::SizeInvalid(_) => Status::PayloadTooLarge,
             Error::DigestInvalid
             | Error::ManifestInvalid(_)
-            | Error::BlobUnknown
-            | Error::NameInvalid(_) => Status::BadRequest,
+            | Error::ManifestBlobUnknown(_)
+            | Error::NameInvalid(_)
+            | Error::JobInvalid(_)
+            | Error::TagInvalid(_)
+            | Error::TransferInvalid(_)
+            | Error::HistoryInvalid(_)
+            | Error::TenantInvalid(_) => Status::BadRequest,
+            Error::TooManyRequests(_) => Status::TooManyRequests,
+            Error::ReadOnly(_) => Status::ServiceUnavailable,
+            Error::RequestTimeout(_) => Status::RequestTimeout,
+            Error::HeadersTooLarge(_) => Status::RequestHeaderFieldsTooLarge,
+            Error::InsufficientStorage(_) => Status::InsufficientStorage,
+        };
+        let mut resp = Response::build();
+        resp.header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .status(status);
+        if let Some(secs) = retry_after {
+            resp.raw_header("Retry-After", secs.to_string());
+        }
+        if let Some(range) = range {
+            resp.raw_header("Range", range);
+        }
+        resp.ok()
+    }
+}
+
+/*
+ * Failures that don't come from a route returning an Error, e.g. a request guard refusing a push,
+ * sent in the same format from the default catcher.
+ */
+pub struct StatusError(pub Status);
+
+impl<'r> Responder<'r, 'static> for StatusError {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let code = match self.0.code {
+            401 => "UNAUTHORIZED",
+            403 => "DENIED",
+            413 => "SIZE_INVALID",
+            429 => "TOOMANYREQUESTS",
+            500..=599 => "INTERNAL_ERROR",
+            _ => "UNSUPPORTED",
         };
+        let emsg = ErrorMsg {
+            code: code.to_string(),
+            message: self.0.reason().unwrap_or("Request failed").to_string(),
+            detail: None,
+        };
+        let json = format!("{{\"errors\":[{}]}}", serde_json::to_string(&emsg).unwrap());
         Response::build()
             .header(ContentType::JSON)
             .sized_body(None, Cursor::new(json))
-            .status(status)
+            .status(self.0)
             .ok()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Error;
+    use crate::registry_interface::StorageDriverError;
+    use serde_json::Value;
+
+    fn code(e: &Error) -> String {
+        let body: Value = serde_json::from_str(&e.to_string()).unwrap();
+        body["errors"][0]["code"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn errors_have_spec_codes() {
+        assert_eq!(code(&Error::BlobUploadUnknown), "BLOB_UPLOAD_UNKNOWN");
+        assert_eq!(
+            code(&Error::ManifestBlobUnknown("sha256:abc".to_string())),
+            "MANIFEST_BLOB_UNKNOWN"
+        );
+        assert_eq!(
+            code(&Error::from(StorageDriverError::NameUnknown(
+                "a".to_string()
+            ))),
+            "NAME_UNKNOWN"
+        );
+        assert_eq!(
+            code(&Error::from(StorageDriverError::TagImmutable(
+                "a".to_string()
+            ))),
+            "TAG_INVALID"
+        );
+        assert_eq!(
+            code(&Error::from(StorageDriverError::BlobUnknown(
+                "sha256:abc".to_string()
+            ))),
+            "BLOB_UNKNOWN"
+        );
+        assert_eq!(
+            code(&Error::from(StorageDriverError::TooLarge)),
+            "SIZE_INVALID"
+        );
+        assert_eq!(
+            code(&Error::from(StorageDriverError::OutOfOrder(10))),
+            "BLOB_UPLOAD_INVALID"
+        );
+        assert_eq!(
+            code(&Error::from(StorageDriverError::ManifestRejected(
+                "Invalid manifest: size of sha256:abc is 2, not 3".to_string()
+            ))),
+            "MANIFEST_INVALID"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::io::Cursor;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Response};
+use sha2::{Digest, Sha256};
+
+/*
+ * Weak ETags for list responses such as the catalog and tag lists, so clients polling them get
+ * a 304 with no body when nothing has changed.
+ *
+ * The tag is a hash of the JSON body, so it's computed after the list is read; what's saved is
+ * sending the list. It's weak as the same list could be serialised differently.
+ *
+ * Blobs and manifests get a strong ETag of their digest instead, which is known before anything
+ * is read. A manifest pulled by tag gets the digest the tag is on now, so a client revalidating
+ * gets the new manifest once the tag has moved.
+ */
+
+/// Content named by its digest can't change, so the digest makes a strong ETag
+pub fn digest_etag(digest: &str) -> String {
+    format!("\"{}\"", digest)
+}
+
+fn etag(body: &str) -> String {
+    let hash = Sha256::digest(body.as_bytes());
+    format!("W/\"{}\"", hex::encode(&hash[..16]))
+}
+
+// If-None-Match uses weak comparison, so W/ is ignored on both sides
+fn matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |t: &str| t.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|t| opaque(t) == opaque(etag))
+}
+
+/// Whether the request's If-None-Match has the ETag, so the client already has the response
+pub(crate) fn not_modified(req: &Request, etag: &str) -> bool {
+    req.headers().get("If-None-Match").any(|v| matches(v, etag))
+}
+
+/// Responds with the JSON body and its ETag, or 304 if the client already has it
+pub fn json_with_etag(req: &Request, json: String) -> response::Result<'static> {
+    let tag = etag(&json);
+    let cached = not_modified(req, &tag);
+
+    let mut resp = Response::build();
+    resp.header(Header::new("ETag", tag))
+        // Clients can keep the response, but must check it's current
+        .header(Header::new("Cache-Control", "no-cache"));
+    if cached {
+        resp.status(Status::NotModified);
+    } else {
+        resp.header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json));
+    }
+    resp.ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{etag, json_with_etag, matches};
+    use crate::response::test_helper::test_client;
+    use rocket::http::{Header, Status};
+
+    #[test]
+    fn weak_comparison() {
+        let tag = etag("{}");
+        assert!(tag.starts_with("W/\""));
+        assert!(matches(&tag, &tag));
+        assert!(matches(tag.trim_start_matches("W/"), &tag));
+        assert!(matches(&format!("\"other\", {}", tag), &tag));
+        assert!(matches("*", &tag));
+        assert!(!matches("W/\"other\"", &tag));
+    }
+
+    #[test]
+    fn not_modified() {
+        let cl = test_client();
+        let body = "{\"tags\":[]}".to_string();
+
+        let req = cl.get("/");
+        let resp = json_with_etag(req.inner(), body.clone()).unwrap();
+        assert_eq!(resp.status(), Status::Ok);
+        let tag = resp.headers().get_one("ETag").unwrap().to_string();
+
+        let req = cl.get("/").header(Header::new("If-None-Match", tag));
+        let resp = json_with_etag(req.inner(), body).unwrap();
+        assert_eq!(resp.status(), Status::NotModified);
+    }
+}
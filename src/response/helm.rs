@@ -0,0 +1,17 @@
+use std::io::Cursor;
+
+use crate::registry_interface::ChartIndex;
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+
+impl<'r> Responder<'r, 'static> for ChartIndex {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let yaml = serde_yaml::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::new("application", "x-yaml"))
+            .sized_body(None, Cursor::new(yaml))
+            .ok()
+    }
+}
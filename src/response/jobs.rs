@@ -0,0 +1,84 @@
+use std::io::Cursor;
+
+use crate::registry_interface::{JobList, JobStatus};
+use crate::response::get_base_url;
+use crate::types::StartedJob;
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+
+impl<'r> Responder<'r, 'static> for JobStatus {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for JobList {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for StartedJob {
+    fn respond_to(self, req: &Request) -> response::Result<'static> {
+        let location = format!("{}/trow/v1/jobs/{}", get_base_url(req), self.0.id);
+        let json = serde_json::to_string(&self.0).unwrap();
+
+        Response::build()
+            .status(Status::Accepted)
+            .header(ContentType::JSON)
+            .header(Header::new("Location", location))
+            .sized_body(None, Cursor::new(json))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::registry_interface::JobStatus;
+    use crate::response::test_helper::test_client;
+    use crate::types::StartedJob;
+    use chrono::Utc;
+    use rocket::http::Status;
+    use rocket::response::Responder;
+
+    fn build_job() -> JobStatus {
+        JobStatus {
+            id: "1234".to_string(),
+            kind: "gc".to_string(),
+            state: "running".to_string(),
+            progress: 0,
+            message: "".to_string(),
+            created: Utc::now(),
+            finished: None,
+        }
+    }
+
+    #[test]
+    fn started_job_accepted() {
+        let cl = test_client();
+        let req = cl.get("/");
+        let response = StartedJob(build_job()).respond_to(req.inner()).unwrap();
+        assert_eq!(response.status(), Status::Accepted);
+        let location = response.headers().get_one("Location").unwrap();
+        assert!(location.ends_with("/trow/v1/jobs/1234"));
+    }
+
+    #[test]
+    fn job_status_ok() {
+        let cl = test_client();
+        let req = cl.get("/");
+        let response = build_job().respond_to(req.inner()).unwrap();
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
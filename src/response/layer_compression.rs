@@ -0,0 +1,57 @@
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+
+/*
+ * A manifest request preferring layers compressed a particular way, given as an RFC 7240
+ * preference, e.g. "Prefer: layer-compression=zstd". Trow serves a copy of the manifest using
+ * transcoded layers if it has them (see trow-server's transcode.rs), or "estargz" for layers
+ * converted for lazy pulling (see trow-server's estargz.rs).
+ *
+ * Only "gzip", "zstd" and "estargz" are understood. Should be wrapped in an Option in routes.
+ */
+pub struct PreferLayerCompression(pub &'static str);
+
+fn preferred(header: &str) -> Option<&'static str> {
+    header.split(',').find_map(|pref| {
+        let pref = pref.split(';').next()?;
+        let (name, value) = pref.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("layer-compression") {
+            return None;
+        }
+        match value.trim().trim_matches('"').to_ascii_lowercase().as_str() {
+            "gzip" => Some("gzip"),
+            "zstd" => Some("zstd"),
+            "estargz" => Some("estargz"),
+            _ => None,
+        }
+    })
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PreferLayerCompression {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        match request.headers().get("Prefer").find_map(preferred) {
+            Some(c) => Outcome::Success(PreferLayerCompression(c)),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::preferred;
+
+    #[test]
+    fn parses_preferences() {
+        assert_eq!(preferred("layer-compression=zstd"), Some("zstd"));
+        assert_eq!(
+            preferred("respond-async, Layer-Compression=\"GZIP\"; strict"),
+            Some("gzip")
+        );
+        assert_eq!(preferred("layer-compression=eStargz"), Some("estargz"));
+        assert_eq!(preferred("layer-compression=brotli"), None);
+        assert_eq!(preferred("return=minimal"), None);
+    }
+}
@@ -0,0 +1,32 @@
+use crate::registry_interface::ManifestMetadata;
+use crate::response::etag::{self, digest_etag};
+use rocket::http::{Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use std::io::Cursor;
+
+/*
+ * Answers a HEAD request for a manifest without reading it. As with blobs, Rocket drops the body
+ * but keeps its size for the Content-Length.
+ */
+impl<'r> Responder<'r, 'static> for ManifestMetadata {
+    fn respond_to(self, req: &Request) -> response::Result<'static> {
+        let digest = Header::new("Docker-Content-Digest", self.digest.to_string());
+        let tag = digest_etag(&self.digest.to_string());
+
+        if etag::not_modified(req, &tag) {
+            return Response::build()
+                .status(Status::NotModified)
+                .header(Header::new("ETag", tag))
+                .header(digest)
+                .ok();
+        }
+
+        Response::build()
+            .header(Header::new("Content-Type", self.content_type))
+            .header(Header::new("ETag", tag))
+            .header(digest)
+            .sized_body(self.size as usize, Cursor::new(vec![]))
+            .ok()
+    }
+}
@@ -1,18 +1,79 @@
 use crate::registry_interface::ManifestReader;
-use rocket::http::Header;
+use crate::response::etag::{self, digest_etag};
+use rocket::http::{Header, Status};
 use rocket::request::Request;
 use rocket::response::{self, Responder, Response};
 
 impl<'r> Responder<'r, 'static> for ManifestReader {
-    fn respond_to(self, _: &Request) -> response::Result<'static> {
+    fn respond_to(self, req: &Request) -> response::Result<'static> {
         let ct = Header::new("Content-Type", self.content_type().to_string());
         let digest = Header::new("Docker-Content-Digest", self.digest().to_string());
+        let tag = digest_etag(&self.digest().to_string());
+
+        // The client already has the manifest the reference is on
+        if etag::not_modified(req, &tag) {
+            return Response::build()
+                .status(Status::NotModified)
+                .header(Header::new("ETag", tag))
+                .header(digest)
+                .ok();
+        }
 
         // Important to used sized_body in order to have content length set correctly
         let mut resp = Response::build().sized_body(None, self.get_reader()).ok()?;
         resp.set_header(ct);
         resp.set_header(digest);
+        resp.set_header(Header::new("ETag", tag));
 
         Ok(resp)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::registry_interface::{digest, ManifestReader};
+    use crate::response::etag::digest_etag;
+    use crate::response::test_helper::test_client;
+    use rocket::http::{Header, Status};
+    use rocket::response::Responder;
+    use std::io::Cursor;
+
+    const DIGEST: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn reader() -> ManifestReader {
+        ManifestReader {
+            content_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+            digest: digest::parse(DIGEST).unwrap(),
+            reader: Box::pin(Cursor::new(b"{}".to_vec())),
+        }
+    }
+
+    #[test]
+    fn revalidates_with_etag() {
+        let cl = test_client();
+        let req = cl.get("/");
+        let resp = reader().respond_to(req.inner()).unwrap();
+        assert_eq!(resp.status(), Status::Ok);
+        assert_eq!(
+            resp.headers().get_one("ETag"),
+            Some(digest_etag(DIGEST).as_str())
+        );
+
+        let req = cl
+            .get("/")
+            .header(Header::new("If-None-Match", digest_etag(DIGEST)));
+        let resp = reader().respond_to(req.inner()).unwrap();
+        assert_eq!(resp.status(), Status::NotModified);
+        assert_eq!(
+            resp.headers().get_one("Docker-Content-Digest"),
+            Some(DIGEST)
+        );
+
+        // The tag has moved to another manifest
+        let req = cl
+            .get("/")
+            .header(Header::new("If-None-Match", "\"sha256:abc\""));
+        let resp = reader().respond_to(req.inner()).unwrap();
+        assert_eq!(resp.status(), Status::Ok);
+    }
+}
@@ -3,24 +3,40 @@ use log::warn;
 use rocket::request::Request;
 
 pub mod accepted_upload;
+pub mod admin;
 pub mod authenticate;
 pub mod blob_deleted;
+pub mod blob_metadata;
 pub mod blob_reader;
+pub mod byte_range;
+pub mod capabilities;
+pub mod content_digest;
 pub mod content_info;
 pub mod empty;
 pub mod errors;
+pub mod etag;
 pub mod health;
+pub mod helm;
 pub mod html;
+pub mod index_summary;
+pub mod jobs;
+pub mod layer_compression;
 pub mod manifest_deleted;
 pub mod manifest_history;
+pub mod manifest_metadata;
 pub mod manifest_reader;
 pub mod metrics;
+pub mod quotas;
 pub mod readiness;
+pub mod referrers;
 pub mod repo_catalog;
+pub mod retention;
+pub mod setup;
 pub mod tag_list;
-mod test_helper;
+pub(crate) mod test_helper;
 pub mod trow_token;
 pub mod upload_info;
+pub mod usage;
 pub mod verified_manifest;
 
 /// Gets the base URL e.g. <http://registry:8000> using the HOST value from the request header.
@@ -0,0 +1,20 @@
+use std::io::Cursor;
+
+use crate::registry_interface::referrers::REFERRERS_MEDIA_TYPE;
+use crate::registry_interface::ReferrerList;
+use rocket::http::Header;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+
+impl<'r> Responder<'r, 'static> for ReferrerList {
+    fn respond_to(self, _req: &Request) -> response::Result<'static> {
+        let json = serde_json::to_string(&self).unwrap();
+
+        let mut resp = Response::build();
+        resp.header(Header::new("Content-Type", REFERRERS_MEDIA_TYPE));
+        if self.filtered {
+            resp.header(Header::new("OCI-Filters-Applied", "artifactType"));
+        }
+        resp.sized_body(None, Cursor::new(json)).ok()
+    }
+}
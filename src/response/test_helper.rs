@@ -1,16 +1,20 @@
 #[cfg(test)]
 use crate::GrpcConfig;
 #[cfg(test)]
+use crate::LogFormat;
+#[cfg(test)]
 use crate::NetAddr;
 #[cfg(test)]
 use crate::TrowConfig;
 #[cfg(test)]
 #[cfg(test)]
 use rocket::local::blocking::Client;
+#[cfg(test)]
+use std::time::Duration;
 
 #[cfg(test)]
-pub fn test_client() -> Client {
-    let trow_config = TrowConfig {
+pub fn test_config() -> TrowConfig {
+    TrowConfig {
         data_dir: "".to_string(),
         addr: NetAddr {
             host: "trow".to_string(),
@@ -19,6 +23,10 @@ pub fn test_client() -> Client {
         tls: None,
         grpc: GrpcConfig {
             listen: "trow:51000".to_owned(),
+            socket_mode: 0o600,
+            tls: None,
+            require_tls: false,
+            backend_address: None,
         },
         proxy_hub: true,
         hub_user: None,
@@ -31,13 +39,74 @@ pub fn test_client() -> Client {
         dry_run: false,
         max_manifest_size: 1,
         max_blob_size: 100,
+        max_layers: 0,
+        blob_redirect: None,
+        manifest_cache_ttl: Duration::ZERO,
+        admission_cache_ttl: Duration::ZERO,
+        rate_limits: Default::default(),
+        shutdown_timeout: Duration::from_secs(25),
+        max_request_size: 1,
+        max_header_size: 32 * 1024,
+        transfer_limits: Default::default(),
+        upload_buffer_size: 64 * 1024,
+        idempotency_keys: 100,
         token_secret: "secret".to_string(),
         user: None,
+        htpasswd: None,
+        htpasswd_pull: false,
+        delete_users: Default::default(),
+        oidc: None,
+        service_accounts: None,
+        setup: None,
         cors: false,
+        cors_config: Default::default(),
         log_level: "error".to_string(),
-    };
+        log_format: LogFormat::Text,
+        watch_data_dir: false,
+        web_ui: false,
+        standalone: false,
+        event_sinks: vec![],
+        event_format: "json".to_string(),
+        quotas: vec![],
+        retention: vec![],
+        retention_interval: "0".to_string(),
+        immutable_tags: vec![],
+        freeze_windows: vec![],
+        policy_crd: false,
+        require_existing_images: false,
+        upstream_proxies: vec![],
+        usage_interval: "0".to_string(),
+        upload_ttl: "24h".to_string(),
+        trash_retention: "0".to_string(),
+        storage: None,
+        scratch_dir: None,
+        storage_reserve: None,
+        proxy_check_interval: "0".to_string(),
+        proxy_check_sample: 20,
+        mirror_workers: 0,
+        mirror_queue_size: 1000,
+        backup_dir: None,
+        backup_interval: "0".to_string(),
+        scrub_interval: "0".to_string(),
+        scrub_quarantine: false,
+        metadata_db: None,
+        transcode_min_pulls: None,
+        transcode_interval: "1h".to_string(),
+        transcode_on_demand: false,
+        estargz_interval: None,
+        ha: false,
+        read_only: false,
+        audit_log: None,
+        tracing: None,
+        spiffe: None,
+        config_reload: None,
+    }
+}
+
+#[cfg(test)]
+pub fn test_client() -> Client {
     let rocket = rocket::Rocket::build()
-        .manage(trow_config)
+        .manage(test_config())
         .mount("/", vec![]);
     Client::tracked(rocket).expect("valid rocket instance")
 }
@@ -1,7 +1,10 @@
+use crate::registry_interface::RegistryInterface;
+use crate::spiffe::{self, Permission};
+use crate::tenants::{self, Access, TenantCache};
 use crate::TrowConfig;
 use crate::UserConfig;
 use frank_jwt::{decode, encode, Algorithm, ValidationOptions};
-use log::warn;
+use log::{debug, warn};
 use rocket::http::ContentType;
 use rocket::http::Status;
 use rocket::request::{self, FromRequest, Request};
@@ -10,6 +13,7 @@ use rocket::{outcome::Outcome, State};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::io::Cursor;
+use std::net::IpAddr;
 use std::ops::Add;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
@@ -19,6 +23,7 @@ const AUTHORIZATION: &str = "authorization";
 
 pub struct ValidBasicToken {
     user: String,
+    permission: Permission,
 }
 
 #[rocket::async_trait]
@@ -30,13 +35,14 @@ impl<'r> FromRequest<'r> for ValidBasicToken {
             .await
             .expect("TrowConfig not present!");
 
-        let user_cfg = match config.user {
-            Some(ref user_cfg) => user_cfg,
-            None => {
-                warn!("Attempted login, but no users are configured");
-                return Outcome::Failure((Status::Unauthorized, ()));
-            }
-        };
+        if config.user.is_none()
+            && config.htpasswd.is_none()
+            && config.oidc.is_none()
+            && config.service_accounts.is_none()
+        {
+            warn!("Attempted login, but no users are configured");
+            return Outcome::Failure((Status::Unauthorized, ()));
+        }
 
         // As Authorization is a standard header
         let auth_val = match req.headers().get_one(AUTHORIZATION) {
@@ -57,17 +63,9 @@ impl<'r> FromRequest<'r> for ValidBasicToken {
             return Outcome::Failure((Status::Unauthorized, ()));
         }
 
-        match base64::decode(&auth_strings[1]) {
-            Ok(user_pass) => {
-                if verify_user(user_pass, user_cfg) {
-                    Outcome::Success(ValidBasicToken {
-                        user: user_cfg.user.clone(),
-                    })
-                } else {
-                    Outcome::Failure((Status::Unauthorized, ()))
-                }
-            }
-            Err(_) => Outcome::Failure((Status::Unauthorized, ())),
+        match basic_auth(&auth_strings[1], config).await {
+            Some((user, permission)) => Outcome::Success(ValidBasicToken { user, permission }),
+            None => Outcome::Failure((Status::Unauthorized, ())),
         }
     }
 }
@@ -75,24 +73,83 @@ impl<'r> FromRequest<'r> for ValidBasicToken {
 /**
  * Sod the errors, just fail verification if there's an encoding problem.
  */
-fn verify_user(user_pass: Vec<u8>, user_cfg: &UserConfig) -> bool {
-    let mut user_pass = user_pass.split(|b| b == &b':');
-    if let Some(user) = user_pass.next() {
-        if let Some(pass) = user_pass.next() {
-            if user_cfg.user.as_bytes() == user {
-                if let Ok(v) = argon2::verify_encoded(&user_cfg.hash_encoded, pass) {
-                    return v;
-                }
-            }
+fn verify_user(user: &[u8], pass: &[u8], user_cfg: &UserConfig) -> bool {
+    if user_cfg.user.as_bytes() == user {
+        if let Ok(v) = argon2::verify_encoded(&user_cfg.hash_encoded, pass) {
+            return v;
         }
     }
     false
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/*
+ * Checks base64 encoded user:password credentials against the --user and the htpasswd file,
+ * returning the user name if they're valid.
+ */
+fn basic_auth_user(user: &[u8], pass: &[u8], config: &TrowConfig) -> Option<String> {
+    if matches!(config.user, Some(ref user_cfg) if verify_user(user, pass, user_cfg)) {
+        return String::from_utf8(user.to_vec()).ok();
+    }
+    let htpasswd = config.htpasswd.as_ref()?;
+    let (user, pass) = (
+        std::str::from_utf8(user).ok()?,
+        std::str::from_utf8(pass).ok()?,
+    );
+    if htpasswd.verify(user, pass) {
+        Some(user.to_string())
+    } else {
+        None
+    }
+}
+
+/*
+ * Checks a token issued outside Trow, by the OIDC provider or Kubernetes, returning who it's for
+ * and what they can do.
+ */
+async fn external_token(token: &str, config: &TrowConfig) -> Option<(String, Permission)> {
+    if let Some(ref oidc) = config.oidc {
+        match oidc.verify(token).await {
+            Ok(id) => return Some((id.user, id.permission)),
+            Err(e) => debug!("Not a valid OIDC token: {}", e),
+        }
+    }
+    if let Some(ref reviewer) = config.service_accounts {
+        match reviewer.verify(token).await {
+            Ok(account) => return Some(account),
+            Err(e) => debug!("Not a valid ServiceAccount token: {}", e),
+        }
+    }
+    warn!("Rejected token");
+    None
+}
+
+/*
+ * Checks base64 encoded user:password credentials, returning who the user is and what they can
+ * do. With OIDC or ServiceAccount tokens, the password can be a token and the user name is
+ * ignored, so `docker login` and kubelets work with tokens.
+ */
+async fn basic_auth(encoded: &str, config: &TrowConfig) -> Option<(String, Permission)> {
+    let user_pass = base64::decode(encoded).ok()?;
+    let (user, pass) = match user_pass.iter().position(|b| b == &b':') {
+        Some(i) => (&user_pass[..i], &user_pass[i + 1..]),
+        None => return None,
+    };
+    if let Some(user) = basic_auth_user(user, pass, config) {
+        let permission = config.user_permission(&user);
+        return Some((user, permission));
+    }
+    if config.oidc.is_none() && config.service_accounts.is_none() {
+        return None;
+    }
+    external_token(std::str::from_utf8(pass).ok()?, config).await
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TrowToken {
     pub user: String,
     pub token: String,
+    // Where the request came from, for the audit log
+    pub client_ip: Option<IpAddr>,
 }
 
 // Just using the default token claim stuff
@@ -123,6 +180,9 @@ struct TokenClaim {
     // (JWT ID) A unique identifier for this token.
     // Can be used by the intended audience to prevent replays of the token.
     jti: String,
+
+    // What the subject can do, as pull, push or delete
+    perm: String,
 }
 /*
  * Create new jsonwebtoken.
@@ -142,6 +202,7 @@ pub fn new(vbt: ValidBasicToken, tc: &State<TrowConfig>) -> Result<TrowToken, fr
         nbf: current_time.as_secs(),
         iat: current_time.as_secs(),
         jti: Uuid::new_v4().to_string(),
+        perm: vbt.permission.to_string(),
     };
 
     let header = json!({});
@@ -153,6 +214,7 @@ pub fn new(vbt: ValidBasicToken, tc: &State<TrowConfig>) -> Result<TrowToken, fr
     Ok(TrowToken {
         user: vbt.user,
         token,
+        client_ip: None,
     })
 }
 /*
@@ -174,65 +236,236 @@ impl<'r> Responder<'r, 'static> for TrowToken {
 impl<'r> FromRequest<'r> for TrowToken {
     type Error = ();
     async fn from_request(req: &'r Request<'_>) -> request::Outcome<TrowToken, ()> {
-        let config = req
-            .guard::<&rocket::State<TrowConfig>>()
+        // Cached, as the rate limiter also needs to know who the client is
+        req.local_cache_async(async { CachedAuth(authenticate(req).await) })
             .await
-            .expect("TrowConfig not present!");
+            .0
+            .clone()
+    }
+}
+
+// The outcome of authenticating the request
+struct CachedAuth(request::Outcome<TrowToken, ()>);
+
+async fn authenticate(req: &Request<'_>) -> request::Outcome<TrowToken, ()> {
+    let config = req
+        .guard::<&rocket::State<TrowConfig>>()
+        .await
+        .expect("TrowConfig not present!");
+
+    // Clients with an SVID matching a rule don't need to log in
+    let spiffe_auth = matches!(config.spiffe, Some(ref s) if !s.rules.is_empty());
+    if spiffe_auth {
+        if let Some(id) = spiffe::client_id(req).await {
+            let spiffe = config.spiffe.as_ref().unwrap();
+            match spiffe.permission(&id) {
+                Some(p) => {
+                    let token = TrowToken {
+                        user: id,
+                        token: "spiffe".to_string(),
+                        client_ip: req.client_ip(),
+                    };
+                    return authorize(req, config, p, token).await;
+                }
+                None => warn!("No SPIFFE rule matches {}", id),
+            }
+        }
+    }
 
-        if config.user.is_none() {
-            //Authentication is not configured
-            //TODO: Figure out how to create this only once
-            let no_auth_token = TrowToken {
-                user: "none".to_string(),
-                token: "none".to_string(),
+    if !config.auth_configured() {
+        //Authentication is not configured
+        //TODO: Figure out how to create this only once
+        let no_auth_token = TrowToken {
+            user: "none".to_string(),
+            token: "none".to_string(),
+            client_ip: req.client_ip(),
+        };
+        return Outcome::Success(no_auth_token);
+    }
+    let auth_val = match req.headers().get_one("Authorization") {
+        Some(a) => a,
+        None if config.allows_anonymous_pull() && Permission::Pull.allows(req.method()) => {
+            // Tenants' repositories need a login
+            return match tenant_access(req, config, "anonymous").await {
+                Ok(Access::Open) => Outcome::Success(TrowToken {
+                    user: "anonymous".to_string(),
+                    token: "none".to_string(),
+                    client_ip: req.client_ip(),
+                }),
+                Ok(_) => Outcome::Failure((Status::Unauthorized, ())),
+                Err(status) => Outcome::Failure((status, ())),
             };
-            return Outcome::Success(no_auth_token);
         }
-        let auth_val = match req.headers().get_one("Authorization") {
-            Some(a) => a,
-            None => return Outcome::Failure((Status::Unauthorized, ())),
+        None => return Outcome::Failure((Status::Unauthorized, ())),
+    };
+
+    // Check header handling - isn't there a next?
+    // split the header on white space
+    let auth_strings: Vec<String> = auth_val.split_whitespace().map(String::from).collect();
+    if auth_strings.len() != 2 {
+        return Outcome::Failure((Status::BadRequest, ()));
+    }
+    // Clients such as curl can send credentials directly rather than logging in first
+    if auth_strings[0] == "Basic" {
+        return match basic_auth(&auth_strings[1], config).await {
+            Some((user, permission)) => {
+                let token = TrowToken {
+                    user,
+                    token: "basic".to_string(),
+                    client_ip: req.client_ip(),
+                };
+                authorize(req, config, permission, token).await
+            }
+            None => Outcome::Failure((Status::Unauthorized, ())),
         };
+    }
+    // We're looking for a Bearer token
+    if auth_strings[0] != "Bearer" {
+        return Outcome::Failure((Status::Unauthorized, ()));
+    }
 
-        // Check header handling - isn't there a next?
-        // split the header on white space
-        let auth_strings: Vec<String> = auth_val.split_whitespace().map(String::from).collect();
-        if auth_strings.len() != 2 {
-            return Outcome::Failure((Status::BadRequest, ()));
+    // parse for bearer token
+    // TODO: frank_jwt is meant to verify iat, nbf etc, but doesn't.
+
+    let dec_token = match decode(
+        &auth_strings[1],
+        &config.token_secret,
+        Algorithm::HS256,
+        &ValidationOptions::default(),
+    ) {
+        Ok((_, payload)) => payload,
+        // Not one of ours, but could be from the OIDC provider or Kubernetes
+        Err(_) if config.oidc.is_some() || config.service_accounts.is_some() => {
+            return match external_token(&auth_strings[1], config).await {
+                Some((user, permission)) => {
+                    let token = TrowToken {
+                        user,
+                        token: auth_strings[1].clone(),
+                        client_ip: req.client_ip(),
+                    };
+                    authorize(req, config, permission, token).await
+                }
+                None => Outcome::Failure((Status::Unauthorized, ())),
+            };
         }
-        // We're looking for a Bearer token
-        //TODO: Maybe should forward or something on Basic
-        if auth_strings[0] != "Bearer" {
+        Err(_) => {
+            warn!("Failed to decode user token");
             return Outcome::Failure((Status::Unauthorized, ()));
         }
+    };
 
-        // parse for bearer token
-        // TODO: frank_jwt is meant to verify iat, nbf etc, but doesn't.
-
-        let dec_token = match decode(
-            &auth_strings[1],
-            &config.token_secret,
-            Algorithm::HS256,
-            &ValidationOptions::default(),
-        ) {
-            Ok((_, payload)) => payload,
-            Err(_) => {
-                warn!("Failed to decode user token");
-                return Outcome::Failure((Status::Unauthorized, ()));
-            }
-        };
+    let user = dec_token["sub"].as_str().unwrap_or_default().to_string();
+    // Decided at login, as OIDC users' permissions come from their ID token
+    let permission = dec_token["perm"]
+        .as_str()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or_else(|| config.user_permission(&user));
+    let trow_token = TrowToken {
+        user,
+        token: auth_strings[1].clone(),
+        client_ip: req.client_ip(),
+    };
 
-        let trow_token = TrowToken {
-            user: dec_token["sub"].to_string(),
-            token: auth_strings[1].clone(),
-        };
+    authorize(req, config, permission, trow_token).await
+}
 
-        Outcome::Success(trow_token)
+/*
+ * What the user can do in the repository the request is for, if it's in a tenant's namespace.
+ * Admins can use every tenant's repositories.
+ */
+async fn tenant_access(
+    req: &Request<'_>,
+    config: &TrowConfig,
+    user: &str,
+) -> Result<Access, Status> {
+    let namespace = match tenants::request_namespace(req) {
+        Some(ns) => ns,
+        None => return Ok(Access::Open),
+    };
+    if config.is_admin(user) {
+        return Ok(Access::Open);
+    }
+    let rocket = req.rocket();
+    let (ci, cache) = match (
+        rocket.state::<Box<dyn RegistryInterface>>(),
+        rocket.state::<TenantCache>(),
+    ) {
+        (Some(ci), Some(cache)) => (ci, cache),
+        _ => return Ok(Access::Open),
+    };
+    match cache.get(ci.as_ref()).await {
+        Some(all) => Ok(tenants::access(&all, user, &namespace)),
+        None => Err(Status::ServiceUnavailable),
+    }
+}
+
+/*
+ * Checks a logged in user is allowed to make the request, e.g. only some users can delete. In a
+ * tenant's namespace, members can do what the tenant allows them and no one else can do anything.
+ */
+async fn authorize(
+    req: &Request<'_>,
+    config: &TrowConfig,
+    permission: Permission,
+    token: TrowToken,
+) -> request::Outcome<TrowToken, ()> {
+    let permission = match tenant_access(req, config, &token.user).await {
+        Ok(Access::Open) => permission,
+        Ok(Access::Member(p)) => p,
+        Ok(Access::Denied) => {
+            warn!(
+                "{} isn't a member of the tenant owning {}",
+                token.user,
+                req.uri().path()
+            );
+            return Outcome::Failure((Status::Forbidden, ()));
+        }
+        Err(status) => return Outcome::Failure((status, ())),
+    };
+    if permission.allows(req.method()) {
+        Outcome::Success(token)
+    } else {
+        warn!(
+            "{} has {:?} permission, denied {}",
+            token.user,
+            permission,
+            req.method()
+        );
+        Outcome::Failure((Status::Forbidden, ()))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::basic_auth;
+    use crate::response::test_helper::test_config;
+    use crate::spiffe::Permission;
+    use crate::UserConfig;
 
     #[tokio::test]
-    async fn test() {}
+    async fn checks_basic_auth() {
+        let mut config = test_config();
+        let hash_config = argon2::Config::default();
+        config.user = Some(UserConfig {
+            user: "admin".to_string(),
+            hash_encoded: argon2::hash_encoded(b"secret", b"some salt", &hash_config).unwrap(),
+        });
+
+        // The --user can do anything
+        assert_eq!(
+            basic_auth(&base64::encode("admin:secret"), &config).await,
+            Some(("admin".to_string(), Permission::Delete))
+        );
+        assert_eq!(
+            basic_auth(&base64::encode("admin:wrong"), &config).await,
+            None
+        );
+        assert_eq!(
+            basic_auth(&base64::encode("other:secret"), &config).await,
+            None
+        );
+        // Needs a user and a password
+        assert_eq!(basic_auth(&base64::encode("admin"), &config).await, None);
+        assert_eq!(basic_auth("not base64!", &config).await, None);
+    }
 }
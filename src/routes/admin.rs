@@ -0,0 +1,644 @@
+use crate::audit::{self, AuditAction, AuditRecord};
+use crate::config_reload::ConfigStatus;
+use crate::image_details::{image_details, ImageDetails};
+use crate::rate_limit::RateLimitConfig;
+use crate::registry_interface::{
+    ActiveTransferList, ImageArchive, ImageImported, PullStats, ReadOnlyStatus, RegistryInterface,
+    RepositoryDeleted, RepositoryList, RepositoryStorage, ScrubReport, StorageDriverError,
+    StorageReport, TagHistory, Tenant, TenantList, TransferCancelled, TrashEntry, TrashList,
+    UploadList,
+};
+use crate::response::errors::Error;
+use crate::response::trow_token::TrowToken;
+use crate::tenants::{self, TenantCache};
+use crate::transfer::{self, GroupBy, TransferLedger, TransferReport};
+use crate::types::StartedJob;
+use crate::TrowConfig;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use log::info;
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::Json;
+use rocket::{delete, get, post, put};
+use std::path::PathBuf;
+use std::sync::Arc;
+use trow_server::names;
+
+/*
+ * Admin API for managing the registry.
+ *
+ * GET /api/v1/repositories lists repositories with their tag counts and sizes
+ * DELETE /api/v1/repositories/<repo> removes every tag in a repository
+ * POST /api/v1/gc starts garbage collection, returning the job as /trow/v1/jobs does
+ * GET /api/v1/uploads lists uploads in progress
+ * GET /api/v1/active-transfers lists uploads and downloads in flight through this frontend, with
+ * the bytes moved so far
+ * DELETE /api/v1/active-transfers/<uuid> cuts them off, and for an upload removes its session and
+ * data, see active_transfers.rs
+ * GET /api/v1/pulls?repo=<repo> shows how often repositories and their tags have been pulled
+ * GET /api/v1/storage shows the space used by the registry and each repository
+ * GET /api/v1/storage/repositories/<repo> shows the space used by one repository
+ * GET /api/v1/tag-history?repo=<repo>&tag=<tag>&at=<time> shows every digest the tag has pointed
+ * to, or only the one it pointed to at the time
+ * GET /api/v1/images?repo=<repo>&reference=<tag or digest> shows the layers of an image and when
+ * and for which platform it was built, or the platforms of an index, see image_details.rs
+ * GET /api/v1/export?repo=<repo>&reference=<tag or digest> downloads an image as an OCI image
+ * layout tarball
+ * POST /api/v1/import?repo=<repo>&tag=<tag> stores the images in an OCI image layout tarball
+ * GET /api/v1/rate-limits shows the per client rate limits
+ * PUT /api/v1/rate-limits replaces them, taking the same JSON as GET returns
+ * GET /api/v1/transfer?from=<day>&to=<day>&by=<fields> adds up bytes pushed and pulled, see
+ * transfer.rs
+ * GET /api/v1/config shows which version of the config file is in use, see config_reload.rs
+ * GET /api/v1/tenants lists the tenants, which own top-level namespaces
+ * PUT /api/v1/tenants/<name> creates or replaces a tenant, taking the JSON GET lists it as
+ * DELETE /api/v1/tenants/<name> removes a tenant, once its repositories are deleted
+ * GET /api/v1/scrub shows the result of the last scrub job, see trow-server/src/scrub.rs
+ * GET /api/v1/read-only shows whether the registry is in read-only maintenance mode
+ * PUT /api/v1/read-only turns it on or off, taking the JSON GET returns
+ * GET /api/v1/events?prefix=<prefix> streams pushes, pulls, deletes and garbage collected blobs
+ * as server-sent events, as they happen
+ * GET /api/v1/trash lists deleted manifests and repositories that can still be restored
+ * POST /api/v1/trash/<id>/restore puts one back
+ *
+ * Only admins can manage tenants, change read-only mode, restore from the trash, change rate
+ * limits, start garbage collection or import images, and
+ * repositories of tenants the caller isn't a member of are left out of the repository list,
+ * trash and events. Only those who see every repository see garbage
+ * collection events.
+ */
+
+#[get("/api/v1/repositories")]
+pub async fn list_repositories(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    tenant_cache: &rocket::State<TenantCache>,
+) -> Result<RepositoryList, Error> {
+    let hidden =
+        tenants::hiding_from(tc, tenant_cache, ci.inner().as_ref(), &auth_user.user).await?;
+    let mut list = ci
+        .list_repositories()
+        .await
+        .map_err(|_| Error::InternalError)?;
+    if let Some(hidden) = hidden {
+        list.repositories
+            .retain(|r| tenants::can_see(&hidden, &auth_user.user, &r.name));
+    }
+    Ok(list)
+}
+
+#[delete("/api/v1/repositories/<repo..>")]
+pub async fn delete_repository(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    repo: PathBuf,
+) -> Result<RepositoryDeleted, Error> {
+    require_admin(tc, &auth_user, "delete repositories")?;
+    let repo = repo.to_string_lossy();
+    if !names::is_valid_repo_name(&repo) {
+        return Err(Error::NameInvalid(repo.to_string()));
+    }
+    let res = ci.delete_repository(&repo).await;
+    audit::record(
+        AuditRecord::for_caller(AuditAction::Delete, &auth_user)
+            .repository(&repo)
+            .outcome(&res),
+    );
+    res.map_err(|e| match e {
+        StorageDriverError::NameUnknown(name) => Error::NameUnknown(name),
+        StorageDriverError::InvalidName(name) => Error::NameInvalid(name),
+        StorageDriverError::ReadOnly(reason) => Error::ReadOnly(reason),
+        _ => Error::InternalError,
+    })
+}
+
+#[post("/api/v1/gc")]
+pub async fn start_gc(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+) -> Result<StartedJob, Error> {
+    require_admin(tc, &auth_user, "start garbage collection")?;
+    ci.start_job("gc")
+        .await
+        .map(StartedJob)
+        .map_err(|_| Error::InternalError)
+}
+
+#[get("/api/v1/uploads")]
+pub async fn list_uploads(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+) -> Result<UploadList, Error> {
+    ci.list_uploads().await.map_err(|_| Error::InternalError)
+}
+
+#[get("/api/v1/active-transfers")]
+pub async fn active_transfers(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+) -> Result<ActiveTransferList, Error> {
+    ci.active_transfers()
+        .await
+        .map_err(|_| Error::InternalError)
+}
+
+#[delete("/api/v1/active-transfers/<uuid>")]
+pub async fn cancel_transfer(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    uuid: String,
+) -> Result<TransferCancelled, Error> {
+    let cancelled = ci.cancel_transfer(&uuid).await?;
+    info!(
+        "{} cancelled transfer {}, cutting off {} requests",
+        auth_user.user, uuid, cancelled.transfers
+    );
+    Ok(cancelled)
+}
+
+/*
+ * Only available with the metadata database, which keeps the counts.
+ */
+#[get("/api/v1/pulls?<repo>")]
+pub async fn pull_stats(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    repo: Option<String>,
+) -> Result<PullStats, Error> {
+    ci.pull_stats(repo.as_deref()).await.map_err(|e| match e {
+        StorageDriverError::Unsupported => Error::Unsupported,
+        _ => Error::InternalError,
+    })
+}
+
+#[get("/api/v1/storage")]
+pub async fn storage_report(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+) -> Result<StorageReport, Error> {
+    ci.storage_report().await.map_err(|_| Error::InternalError)
+}
+
+#[get("/api/v1/storage/repositories/<repo..>")]
+pub async fn repo_storage(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    repo: PathBuf,
+) -> Result<RepositoryStorage, Error> {
+    let repo = repo.to_string_lossy();
+    if !names::is_valid_repo_name(&repo) {
+        return Err(Error::NameInvalid(repo.to_string()));
+    }
+    ci.repo_storage(&repo).await.map_err(|e| match e {
+        StorageDriverError::NameUnknown(name) => Error::NameUnknown(name),
+        _ => Error::InternalError,
+    })
+}
+
+/*
+ * With at, only the digest the tag pointed to at that time is listed, and it's unknown if the tag
+ * hadn't been pushed by then.
+ */
+#[get("/api/v1/tag-history?<repo>&<tag>&<at>")]
+pub async fn tag_history(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    repo: String,
+    tag: String,
+    at: Option<String>,
+) -> Result<TagHistory, Error> {
+    let at = at
+        .map(|t| {
+            DateTime::parse_from_rfc3339(&t)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(|e| Error::HistoryInvalid(format!("{}: {}", t, e)))
+        })
+        .transpose()?;
+    let mut history = ci.get_tag_history(&repo, &tag).await.map_err(|e| match e {
+        StorageDriverError::NameUnknown(_) | StorageDriverError::InvalidName(_) => {
+            Error::ManifestUnknown(format!("{}:{}", repo, tag))
+        }
+        _ => Error::InternalError,
+    })?;
+    if let Some(at) = at {
+        let entry = history
+            .at(at)
+            .cloned()
+            .ok_or_else(|| Error::ManifestUnknown(format!("{}:{}", repo, tag)))?;
+        history.history = vec![entry];
+    }
+    Ok(history)
+}
+
+#[get("/api/v1/images?<repo>&<reference>")]
+pub async fn get_image_details(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    repo: String,
+    reference: String,
+) -> Result<ImageDetails, Error> {
+    image_details(ci.inner().as_ref(), &repo, &reference)
+        .await
+        .map_err(|e| match e {
+            StorageDriverError::InvalidName(_) | StorageDriverError::InvalidManifest => {
+                Error::ManifestUnknown(format!("{}:{}", repo, reference))
+            }
+            _ => Error::InternalError,
+        })
+}
+
+#[get("/api/v1/export?<repo>&<reference>")]
+pub async fn export_image(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    repo: String,
+    reference: String,
+) -> Result<ImageArchive, Error> {
+    let res = ci.export_image(&repo, &reference).await;
+    audit::record(
+        AuditRecord::for_caller(AuditAction::Pull, &auth_user)
+            .repository(&repo)
+            .reference(&reference)
+            .outcome(&res),
+    );
+    res.map_err(|e| match e {
+        StorageDriverError::InvalidName(name) => Error::NameInvalid(name),
+        StorageDriverError::InvalidManifest => Error::ManifestUnknown(reference),
+        StorageDriverError::InvalidArchive(reason) => Error::ManifestInvalid(reason),
+        _ => Error::InternalError,
+    })
+}
+
+/*
+ * The tarball is limited to --max-blob-size. Images are tagged as in the tarball, or with tag if
+ * it has a single image.
+ */
+#[post("/api/v1/import?<repo>&<tag>", data = "<archive>")]
+pub async fn import_image(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    repo: String,
+    tag: Option<String>,
+    archive: rocket::data::Data<'_>,
+) -> Result<ImageImported, Error> {
+    require_admin(tc, &auth_user, "import images")?;
+    let data = archive.open(tc.max_blob_size.mebibytes());
+    let res = ci.import_image(&repo, tag.as_deref(), data).await;
+    let rec = AuditRecord::for_caller(AuditAction::Push, &auth_user).repository(&repo);
+    match &res {
+        Ok(imported) => {
+            for m in &imported.manifests {
+                let mut rec = rec.clone().digest(&m.digest);
+                if let Some(tag) = &m.tag {
+                    rec = rec.reference(tag);
+                }
+                audit::record(rec);
+            }
+        }
+        Err(e) => audit::record(rec.failed(&e.to_string())),
+    }
+    res.map_err(|e| match e {
+        StorageDriverError::InvalidArchive(reason) => Error::ManifestInvalid(reason),
+        StorageDriverError::QuotaExceeded(reason) => Error::QuotaExceeded(reason),
+        StorageDriverError::TagImmutable(reason) => Error::TagInvalid(reason),
+        StorageDriverError::TooLarge => Error::ManifestInvalid(format!(
+            "Archive is over the {} MiB limit",
+            tc.max_blob_size
+        )),
+        StorageDriverError::ReadOnly(reason) => Error::ReadOnly(reason),
+        _ => Error::InternalError,
+    })
+}
+
+#[get("/api/v1/rate-limits")]
+pub fn get_rate_limits(_auth_user: TrowToken, tc: &rocket::State<TrowConfig>) -> RateLimitConfig {
+    tc.rate_limits.config()
+}
+
+#[put("/api/v1/rate-limits", data = "<limits>")]
+pub fn set_rate_limits(
+    auth_user: TrowToken,
+    tc: &rocket::State<TrowConfig>,
+    limits: Json<RateLimitConfig>,
+) -> Result<RateLimitConfig, Error> {
+    require_admin(tc, &auth_user, "change rate limits")?;
+    info!("Rate limits set by {}: {:?}", auth_user.user, limits.0);
+    tc.rate_limits.set_config(limits.into_inner());
+    Ok(tc.rate_limits.config())
+}
+
+/*
+ * Days are YYYY-MM-DD, defaulting to today. Totals are grouped by a comma separated list of day,
+ * user and namespace, defaulting to user and namespace.
+ */
+#[get("/api/v1/transfer?<from>&<to>&<by>")]
+pub fn transfer_report(
+    _auth_user: TrowToken,
+    transfers: &rocket::State<Arc<TransferLedger>>,
+    from: Option<String>,
+    to: Option<String>,
+    by: Option<String>,
+) -> Result<TransferReport, Error> {
+    let day = |d: Option<String>| match d {
+        Some(d) => transfer::parse_day(&d).map_err(|e| Error::TransferInvalid(e.to_string())),
+        None => Ok(transfer::today()),
+    };
+    let from = day(from)?;
+    let to = day(to)?;
+    let by = by.as_deref().unwrap_or("user,namespace");
+    let by: Vec<GroupBy> = by
+        .split(',')
+        .filter(|b| !b.is_empty())
+        .map(|b| b.parse())
+        .collect::<anyhow::Result<_>>()
+        .map_err(|e| Error::TransferInvalid(e.to_string()))?;
+    Ok(transfers.report(&from, &to, &by))
+}
+
+/*
+ * The generation is 0 until the config file changes, and stays 0 if Trow wasn't started with one.
+ */
+#[get("/api/v1/config")]
+pub async fn config_status(
+    _auth_user: TrowToken,
+    tc: &rocket::State<TrowConfig>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+) -> ConfigStatus {
+    let status = tc
+        .config_reload
+        .as_ref()
+        .map(|r| r.status())
+        .unwrap_or_default();
+    ConfigStatus {
+        backend_generation: ci.policy_generation().await.ok(),
+        ..status
+    }
+}
+
+// Without authentication there are no admins, and anyone can use admin only routes
+pub(super) fn require_admin(
+    tc: &TrowConfig,
+    auth_user: &TrowToken,
+    what: &str,
+) -> Result<(), Error> {
+    if tc.auth_configured() && !tc.is_admin(&auth_user.user) {
+        return Err(Error::Denied(format!(
+            "{} isn't an admin, only admins can {}",
+            auth_user.user, what
+        )));
+    }
+    Ok(())
+}
+
+#[get("/api/v1/tenants")]
+pub async fn list_tenants(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+) -> Result<TenantList, Error> {
+    require_admin(tc, &auth_user, "manage tenants")?;
+    let tenants = ci.list_tenants().await.map_err(|_| Error::InternalError)?;
+    Ok(TenantList { tenants })
+}
+
+#[put("/api/v1/tenants/<name>", data = "<tenant>")]
+pub async fn put_tenant(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    tenant_cache: &rocket::State<TenantCache>,
+    name: String,
+    tenant: Json<Tenant>,
+) -> Result<Tenant, Error> {
+    require_admin(tc, &auth_user, "manage tenants")?;
+    let tenant = Tenant {
+        name,
+        ..tenant.into_inner()
+    };
+    let stored = ci.put_tenant(&tenant).await.map_err(|e| match e {
+        StorageDriverError::InvalidPolicy(reason) => Error::TenantInvalid(reason),
+        _ => Error::InternalError,
+    })?;
+    tenant_cache.invalidate();
+    info!("Tenant {} stored by {}", stored.name, auth_user.user);
+    Ok(stored)
+}
+
+#[delete("/api/v1/tenants/<name>")]
+pub async fn delete_tenant(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    tenant_cache: &rocket::State<TenantCache>,
+    name: String,
+) -> Result<(), Error> {
+    require_admin(tc, &auth_user, "manage tenants")?;
+    ci.delete_tenant(&name).await.map_err(|e| match e {
+        StorageDriverError::NameUnknown(name) => Error::NameUnknown(name),
+        StorageDriverError::TenantInUse(reason) => Error::TenantInvalid(reason),
+        _ => Error::InternalError,
+    })?;
+    tenant_cache.invalidate();
+    info!("Tenant {} deleted by {}", name, auth_user.user);
+    Ok(())
+}
+
+#[get("/api/v1/scrub")]
+pub async fn scrub_report(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+) -> Result<ScrubReport, Error> {
+    ci.scrub_report()
+        .await
+        .map_err(|_| Error::InternalError)?
+        .ok_or_else(|| Error::JobUnknown("scrub".to_string()))
+}
+
+#[get("/api/v1/events?<prefix>")]
+pub async fn watch_events(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    prefix: Option<String>,
+) -> Result<EventStream<BoxStream<'static, Event>>, Error> {
+    let user = Some(auth_user.user.as_str()).filter(|u| !tenants::sees_everything(tc, u));
+    let events = ci
+        .watch_events(prefix.as_deref(), user)
+        .await
+        .map_err(|_| Error::InternalError)?;
+    // Named after the action, so clients can listen for e.g. only pushes
+    let events = events.map(|e| Event::json(&e).event(e.action.clone()).id(e.id.clone()));
+    Ok(EventStream::from(events.boxed()))
+}
+
+#[get("/api/v1/read-only")]
+pub async fn get_read_only(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+) -> Result<ReadOnlyStatus, Error> {
+    ci.read_only_status()
+        .await
+        .map_err(|_| Error::InternalError)
+}
+
+#[get("/api/v1/trash")]
+pub async fn list_trash(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    tenant_cache: &rocket::State<TenantCache>,
+) -> Result<TrashList, Error> {
+    let hidden =
+        tenants::hiding_from(tc, tenant_cache, ci.inner().as_ref(), &auth_user.user).await?;
+    let mut entries = ci.list_trash().await.map_err(|_| Error::InternalError)?;
+    if let Some(hidden) = hidden {
+        entries.retain(|e| tenants::can_see(&hidden, &auth_user.user, &e.repo_name));
+    }
+    Ok(TrashList { entries })
+}
+
+#[post("/api/v1/trash/<id>/restore")]
+pub async fn restore_trash(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    id: String,
+) -> Result<TrashEntry, Error> {
+    require_admin(tc, &auth_user, "restore from the trash")?;
+    let entry = ci.restore_trash(&id).await.map_err(|e| match e {
+        StorageDriverError::NameUnknown(id) => Error::NameUnknown(id),
+        StorageDriverError::TagImmutable(reason) => Error::TagInvalid(reason),
+        StorageDriverError::ReadOnly(reason) => Error::ReadOnly(reason),
+        _ => Error::InternalError,
+    })?;
+    info!(
+        "{}@{} restored from the trash by {}",
+        entry.repo_name,
+        entry.digest.as_deref().unwrap_or("*"),
+        auth_user.user
+    );
+    Ok(entry)
+}
+
+#[put("/api/v1/read-only", data = "<status>")]
+pub async fn set_read_only(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    status: Json<ReadOnlyStatus>,
+) -> Result<ReadOnlyStatus, Error> {
+    require_admin(tc, &auth_user, "change read-only mode")?;
+    let stored = ci
+        .set_read_only(status.read_only, status.reason.as_deref())
+        .await
+        .map_err(|_| Error::InternalError)?;
+    info!(
+        "Read-only mode turned {} by {}",
+        if stored.read_only { "on" } else { "off" },
+        auth_user.user
+    );
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod test {
+    use super::require_admin;
+    use crate::response::test_helper::{test_client, test_config};
+    use crate::response::trow_token::TrowToken;
+    use crate::routes::jobs::check_can_start;
+    use crate::UserConfig;
+    use rocket::http::Status;
+    use rocket::response::Responder;
+
+    fn caller(user: &str) -> TrowToken {
+        TrowToken {
+            user: user.to_string(),
+            token: "token".to_string(),
+            client_ip: None,
+        }
+    }
+
+    #[test]
+    fn refuses_users_who_arent_admins() {
+        let mut config = test_config();
+        // Without authentication everyone is let in
+        assert!(require_admin(&config, &caller("none"), "change rate limits").is_ok());
+
+        config.user = Some(UserConfig {
+            user: "admin".to_string(),
+            hash_encoded: String::new(),
+        });
+        assert!(require_admin(&config, &caller("admin"), "change rate limits").is_ok());
+
+        let err = require_admin(&config, &caller("pusher"), "change rate limits").unwrap_err();
+        let cl = test_client();
+        let req = cl.put("/api/v1/rate-limits");
+        let resp = err.respond_to(req.inner()).unwrap();
+        assert_eq!(resp.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn only_admins_delete_repositories() {
+        let mut config = test_config();
+        config.user = Some(UserConfig {
+            user: "admin".to_string(),
+            hash_encoded: String::new(),
+        });
+        assert!(require_admin(&config, &caller("admin"), "delete repositories").is_ok());
+
+        // Even a user allowed to delete manifests
+        *config.delete_users.write().unwrap() = vec!["pusher".to_string()];
+        let err = require_admin(&config, &caller("pusher"), "delete repositories").unwrap_err();
+        let cl = test_client();
+        let req = cl.delete("/api/v1/repositories/myorg/app");
+        let resp = err.respond_to(req.inner()).unwrap();
+        assert_eq!(resp.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn only_admins_restore_backups() {
+        let mut config = test_config();
+        config.user = Some(UserConfig {
+            user: "admin".to_string(),
+            hash_encoded: String::new(),
+        });
+        assert!(check_can_start(&config, &caller("admin"), "restore").is_ok());
+
+        let err = check_can_start(&config, &caller("pusher"), "restore").unwrap_err();
+        let cl = test_client();
+        let req = cl.post("/trow/v1/jobs");
+        let resp = err.respond_to(req.inner()).unwrap();
+        assert_eq!(resp.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn only_admins_start_or_cancel_jobs() {
+        let mut config = test_config();
+        config.user = Some(UserConfig {
+            user: "admin".to_string(),
+            hash_encoded: String::new(),
+        });
+        let cl = test_client();
+        let req = cl.post("/trow/v1/jobs");
+        for kind in ["retention", "backup", "archive", "scrub"] {
+            assert!(check_can_start(&config, &caller("admin"), kind).is_ok());
+            let err = check_can_start(&config, &caller("pusher"), kind).unwrap_err();
+            let resp = err.respond_to(req.inner()).unwrap();
+            assert_eq!(resp.status(), Status::Forbidden);
+        }
+        // Only report on the registry
+        assert!(check_can_start(&config, &caller("pusher"), "usage").is_ok());
+        assert!(check_can_start(&config, &caller("pusher"), "proxy-check").is_ok());
+
+        // As cancel_job checks
+        let err = require_admin(&config, &caller("pusher"), "cancel jobs").unwrap_err();
+        let req = cl.delete("/trow/v1/jobs/1234");
+        let resp = err.respond_to(req.inner()).unwrap();
+        assert_eq!(resp.status(), Status::Forbidden);
+    }
+}
@@ -1,5 +1,9 @@
-use crate::client_interface::ClientInterface;
-use crate::registry_interface::{digest, BlobReader, BlobStorage, ContentInfo, StorageDriverError};
+use crate::audit::{self, AuditAction, AuditRecord};
+use crate::registry_interface::{
+    digest, BlobMetadata, BlobReader, ByteRanges, ContentInfo, RegistryInterface,
+    StorageDriverError,
+};
+use crate::response::content_digest::WantContentDigest;
 use crate::response::errors::Error;
 use crate::response::trow_token::TrowToken;
 use crate::response::upload_info::UploadInfo;
@@ -8,9 +12,10 @@ use crate::types::{
 };
 use crate::TrowConfig;
 use anyhow::Result;
-use rocket::data::ToByteUnit;
-use rocket::http::uri::Origin;
-use rocket::{delete, get, patch, post, put};
+use rocket::data::{ByteUnit, ToByteUnit};
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::{delete, get, head, patch, post, put};
 
 /*
 ---
@@ -21,21 +26,47 @@ digest - unique identifier for the blob to be downoaded
 
 # Responses
 200 - blob is downloaded
+206 - the part of the blob asked for with a Range header is downloaded, as multipart/byteranges
+      if several ranges were asked for
 307 - redirect to another service for downloading[1]
+416 - the Range is outside the blob
  */
 
 #[get("/v2/<name_repo>/blobs/<digest>")]
 pub async fn get_blob(
-    _auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    range: Option<ByteRanges>,
+    want_digest: Option<WantContentDigest>,
     name_repo: String,
     digest: String,
-) -> Option<BlobReader> {
-    let digest = digest::parse(&digest);
-    match digest {
-        Ok(d) => ci.get_blob(&name_repo, &d).await.ok(),
-        Err(_) => None,
+) -> Result<BlobReader, Error> {
+    let rec = AuditRecord::for_caller(AuditAction::Pull, &auth_user)
+        .repository(&name_repo)
+        .digest(&digest);
+    // A blob can't be stored under an invalid digest, so it's unknown rather than invalid
+    let digest = digest::parse(&digest).map_err(|_| Error::BlobUnknown)?;
+    let res = ci.get_blob(&name_repo, &digest).await;
+    audit::record(rec.outcome(&res));
+    let mut reader = res.map_err(|e| match e {
+        StorageDriverError::InvalidName(name) => Error::NameInvalid(name),
+        StorageDriverError::Internal => Error::InternalError,
+        _ => Error::BlobUnknown,
+    })?;
+    // Lets clients resume interrupted downloads, and lazy pulling clients fetch parts of layers
+    if let Some(range) = range {
+        reader
+            .seek_to_ranges(&range.0)
+            .await
+            .map_err(|_| Error::InternalError)?;
+    }
+    if want_digest.is_some() && reader.redirect.is_none() {
+        reader
+            .add_content_digest()
+            .await
+            .map_err(|_| Error::InternalError)?;
     }
+    Ok(reader)
 }
 
 /*
@@ -45,12 +76,22 @@ pub async fn get_blob(
 #[get("/v2/<name>/<repo>/blobs/<digest>")]
 pub async fn get_blob_2level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    range: Option<ByteRanges>,
+    want_digest: Option<WantContentDigest>,
     name: String,
     repo: String,
     digest: String,
-) -> Option<BlobReader> {
-    get_blob(auth_user, ci, format!("{}/{}", name, repo), digest).await
+) -> Result<BlobReader, Error> {
+    get_blob(
+        auth_user,
+        ci,
+        range,
+        want_digest,
+        format!("{}/{}", name, repo),
+        digest,
+    )
+    .await
 }
 
 /*
@@ -59,13 +100,23 @@ pub async fn get_blob_2level(
 #[get("/v2/<org>/<name>/<repo>/blobs/<digest>")]
 pub async fn get_blob_3level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    range: Option<ByteRanges>,
+    want_digest: Option<WantContentDigest>,
     org: String,
     name: String,
     repo: String,
     digest: String,
-) -> Option<BlobReader> {
-    get_blob(auth_user, ci, format!("{}/{}/{}", org, name, repo), digest).await
+) -> Result<BlobReader, Error> {
+    get_blob(
+        auth_user,
+        ci,
+        range,
+        want_digest,
+        format!("{}/{}/{}", org, name, repo),
+        digest,
+    )
+    .await
 }
 
 /*
@@ -74,16 +125,20 @@ pub async fn get_blob_3level(
 #[get("/v2/<fourth>/<org>/<name>/<repo>/blobs/<digest>")]
 pub async fn get_blob_4level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    range: Option<ByteRanges>,
+    want_digest: Option<WantContentDigest>,
     fourth: String,
     org: String,
     name: String,
     repo: String,
     digest: String,
-) -> Option<BlobReader> {
+) -> Result<BlobReader, Error> {
     get_blob(
         auth_user,
         ci,
+        range,
+        want_digest,
         format!("{}/{}/{}/{}", fourth, org, name, repo),
         digest,
     )
@@ -96,15 +151,104 @@ pub async fn get_blob_4level(
 #[get("/v2/<fifth>/<fourth>/<org>/<name>/<repo>/blobs/<digest>")]
 pub async fn get_blob_5level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    range: Option<ByteRanges>,
+    want_digest: Option<WantContentDigest>,
     fifth: String,
     fourth: String,
     org: String,
     name: String,
     repo: String,
     digest: String,
-) -> Option<BlobReader> {
+) -> Result<BlobReader, Error> {
     get_blob(
+        auth_user,
+        ci,
+        range,
+        want_digest,
+        format!("{}/{}/{}/{}/{}", fifth, fourth, org, name, repo),
+        digest,
+    )
+    .await
+}
+
+/*
+---
+Checking a Layer Exists
+HEAD /v2/<name>/blobs/<digest>
+
+# Responses
+200 - blob exists, with its size in Content-Length and its digest in Docker-Content-Digest
+404 - blob not known to the registry
+
+Answered from the blob's metadata, so clients can cheaply check which layers need pushing.
+ */
+
+#[head("/v2/<name_repo>/blobs/<digest>")]
+pub async fn head_blob(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    name_repo: String,
+    digest: String,
+) -> Option<BlobMetadata> {
+    let digest = digest::parse(&digest).ok()?;
+    ci.blob_exists(&name_repo, &digest).await.ok()
+}
+
+#[head("/v2/<name>/<repo>/blobs/<digest>")]
+pub async fn head_blob_2level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    name: String,
+    repo: String,
+    digest: String,
+) -> Option<BlobMetadata> {
+    head_blob(auth_user, ci, format!("{}/{}", name, repo), digest).await
+}
+
+#[head("/v2/<org>/<name>/<repo>/blobs/<digest>")]
+pub async fn head_blob_3level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    org: String,
+    name: String,
+    repo: String,
+    digest: String,
+) -> Option<BlobMetadata> {
+    head_blob(auth_user, ci, format!("{}/{}/{}", org, name, repo), digest).await
+}
+
+#[head("/v2/<fourth>/<org>/<name>/<repo>/blobs/<digest>")]
+pub async fn head_blob_4level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    fourth: String,
+    org: String,
+    name: String,
+    repo: String,
+    digest: String,
+) -> Option<BlobMetadata> {
+    head_blob(
+        auth_user,
+        ci,
+        format!("{}/{}/{}/{}", fourth, org, name, repo),
+        digest,
+    )
+    .await
+}
+
+#[head("/v2/<fifth>/<fourth>/<org>/<name>/<repo>/blobs/<digest>")]
+pub async fn head_blob_5level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    fifth: String,
+    fourth: String,
+    org: String,
+    name: String,
+    repo: String,
+    digest: String,
+) -> Option<BlobMetadata> {
+    head_blob(
         auth_user,
         ci,
         format!("{}/{}/{}/{}/{}", fifth, fourth, org, name, repo),
@@ -123,28 +267,51 @@ Content-Type: application/octet-stream
 <Layer Binary Data>
  */
 
+/*
+ * What's left of --max-blob-size for an upload, so a blob can't go over it by being sent in many
+ * chunks.
+ */
+async fn remaining_blob_size(
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    repo_name: &str,
+    uuid: &str,
+) -> Result<ByteUnit, Error> {
+    let uploaded = match ci.status_blob_upload(repo_name, uuid).await {
+        Ok(status) => status.uploaded,
+        Err(StorageDriverError::InvalidName(_)) => return Err(Error::BlobUploadUnknown),
+        Err(_) => return Err(Error::InternalError),
+    };
+    let max = tc.max_blob_size.mebibytes().as_u64();
+    Ok(max.saturating_sub(uploaded).bytes())
+}
+
+fn blob_too_large(tc: &TrowConfig) -> Error {
+    Error::SizeInvalid(format!(
+        "Blob over data limit {} mebibytes",
+        tc.max_blob_size
+    ))
+}
+
 /**
  * Completes the upload.
  */
 #[put("/v2/<repo_name>/blobs/uploads/<uuid>?<digest>", data = "<chunk>")]
 pub async fn put_blob(
-    _auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     repo_name: String,
     uuid: String,
     digest: String,
     chunk: rocket::data::Data<'_>,
 ) -> Result<AcceptedUpload, Error> {
-    let ds = chunk.open(tc.max_blob_size.mebibytes());
+    let ds = chunk.open(remaining_blob_size(ci, tc, &repo_name, &uuid).await?);
 
     let size = match ci.store_blob_chunk(&repo_name, &uuid, None, ds).await {
         Ok(stored) => {
             if !stored.complete {
-                return Err(Error::BlobUploadInvalid(format!(
-                    "Content over data limit {} mebibytes",
-                    tc.max_blob_size
-                )));
+                return Err(blob_too_large(tc));
             } else {
                 stored.total_stored
             }
@@ -155,16 +322,30 @@ pub async fn put_blob(
                 "Invalid Content Range".to_string(),
             ))
         }
+        Err(StorageDriverError::OutOfOrder(stored)) => return Err(Error::UploadOutOfOrder(stored)),
+        Err(StorageDriverError::ReadOnly(reason)) => return Err(Error::ReadOnly(reason)),
         Err(_) => return Err(Error::InternalError),
     };
 
     let digest_obj = digest::parse(&digest).map_err(|_| Error::DigestInvalid)?;
-    ci.complete_and_verify_blob_upload(&repo_name, &uuid, &digest_obj)
-        .await
-        .map_err(|e| match e {
-            StorageDriverError::InvalidDigest => Error::DigestInvalid,
-            _ => Error::InternalError,
-        })?;
+    let res = ci
+        .complete_and_verify_blob_upload(&repo_name, &uuid, &digest_obj)
+        .await;
+    audit::record(
+        AuditRecord::for_caller(AuditAction::Push, &auth_user)
+            .repository(&repo_name)
+            .digest(&digest_obj)
+            .outcome(&res),
+    );
+    res.map_err(|e| match e {
+        StorageDriverError::InvalidDigest => Error::DigestInvalid,
+        StorageDriverError::InvalidContentRange => {
+            Error::BlobUploadInvalid("Upload has ranges missing or sent twice".to_string())
+        }
+        StorageDriverError::QuotaExceeded(reason) => Error::QuotaExceeded(reason),
+        StorageDriverError::ReadOnly(reason) => Error::ReadOnly(reason),
+        _ => Error::InternalError,
+    })?;
 
     Ok(create_accepted_upload(
         digest_obj,
@@ -180,7 +361,7 @@ pub async fn put_blob(
 #[put("/v2/<repo>/<name>/blobs/uploads/<uuid>?<digest>", data = "<chunk>")]
 pub async fn put_blob_2level(
     auth_user: TrowToken,
-    config: &rocket::State<ClientInterface>,
+    config: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     repo: String,
     name: String,
@@ -209,7 +390,7 @@ pub async fn put_blob_2level(
 )]
 pub async fn put_blob_3level(
     auth_user: TrowToken,
-    config: &rocket::State<ClientInterface>,
+    config: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     org: String,
     repo: String,
@@ -239,7 +420,7 @@ pub async fn put_blob_3level(
 )]
 pub async fn put_blob_4level(
     auth_user: TrowToken,
-    config: &rocket::State<ClientInterface>,
+    config: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     fourth: String,
     org: String,
@@ -270,7 +451,7 @@ pub async fn put_blob_4level(
 )]
 pub async fn put_blob_5level(
     auth_user: TrowToken,
-    config: &rocket::State<ClientInterface>,
+    config: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     fifth: String,
     fourth: String,
@@ -310,28 +491,35 @@ Uploads a blob or chunk of a blob.
 
 Checks UUID. Returns UploadInfo with range set to correct position.
 
+A chunk has to start where the data stored so far ends, and can't be sent while another is still
+being written, or it gets a 416 with the Range stored so far, as the spec requires. A chunk that's
+cut off isn't stored, so it can be sent again from the same place.
+
 */
 #[patch("/v2/<repo_name>/blobs/uploads/<uuid>", data = "<chunk>")]
 pub async fn patch_blob(
     _auth_user: TrowToken,
     info: Option<ContentInfo>,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     repo_name: String,
     uuid: String,
     chunk: rocket::data::Data<'_>,
 ) -> Result<UploadInfo, Error> {
-    let data = chunk.open(tc.max_blob_size.mebibytes());
+    // Ranges can be sent ahead of the data so far, so the limit is on where they end too
+    if let Some(range) = info.as_ref().map(|i| i.range) {
+        if range.1 >= tc.max_blob_size.mebibytes().as_u64() {
+            return Err(blob_too_large(tc));
+        }
+    }
+    let data = chunk.open(remaining_blob_size(ci, tc, &repo_name, &uuid).await?);
 
     match ci.store_blob_chunk(&repo_name, &uuid, info, data).await {
         Ok(stored) => {
             let repo_name = RepoName(repo_name);
             let uuid = Uuid(uuid);
             if !stored.complete {
-                Err(Error::BlobUploadInvalid(format!(
-                    "Content over data limit {} mebibytes",
-                    tc.max_blob_size
-                )))
+                Err(blob_too_large(tc))
             } else {
                 Ok(create_upload_info(
                     uuid,
@@ -344,6 +532,8 @@ pub async fn patch_blob(
         Err(StorageDriverError::InvalidContentRange) => Err(Error::BlobUploadInvalid(
             "Invalid Content Range".to_string(),
         )),
+        Err(StorageDriverError::OutOfOrder(stored)) => Err(Error::UploadOutOfOrder(stored)),
+        Err(StorageDriverError::ReadOnly(reason)) => Err(Error::ReadOnly(reason)),
         Err(_) => Err(Error::InternalError),
     }
 }
@@ -355,7 +545,7 @@ pub async fn patch_blob(
 pub async fn patch_blob_2level(
     auth_user: TrowToken,
     info: Option<ContentInfo>,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     repo: String,
     name: String,
@@ -381,7 +571,7 @@ pub async fn patch_blob_2level(
 pub async fn patch_blob_3level(
     auth_user: TrowToken,
     info: Option<ContentInfo>,
-    handler: &rocket::State<ClientInterface>,
+    handler: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     org: String,
     repo: String,
@@ -411,7 +601,7 @@ pub async fn patch_blob_3level(
 pub async fn patch_blob_4level(
     auth_user: TrowToken,
     info: Option<ContentInfo>,
-    handler: &rocket::State<ClientInterface>,
+    handler: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     fourth: String,
     org: String,
@@ -442,7 +632,7 @@ pub async fn patch_blob_4level(
 pub async fn patch_blob_5level(
     auth_user: TrowToken,
     info: Option<ContentInfo>,
-    handler: &rocket::State<ClientInterface>,
+    handler: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     fifth: String,
     fourth: String,
@@ -464,23 +654,124 @@ pub async fn patch_blob_5level(
     .await
 }
 
+/*
+---
+Upload progress
+GET /v2/<name>/blobs/uploads/<uuid>
+
+Returns the range stored so far in the Range header, so the client can carry on from there with
+PATCH, e.g. after a dropped connection or a restart of Trow.
+
+# Returns
+204 - the upload is in progress
+404 - no such upload
+*/
+#[get("/v2/<repo_name>/blobs/uploads/<uuid>")]
+pub async fn get_upload_status(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    repo_name: String,
+    uuid: String,
+) -> Result<Custom<UploadInfo>, Error> {
+    match ci.status_blob_upload(&repo_name, &uuid).await {
+        Ok(status) => Ok(Custom(
+            Status::NoContent,
+            create_upload_info(
+                Uuid(status.session_id),
+                RepoName(status.name),
+                (0, (status.uploaded as u32).checked_sub(1).unwrap_or(0)), // First byte is 0
+            ),
+        )),
+        Err(StorageDriverError::InvalidName(_)) => Err(Error::BlobUploadUnknown),
+        Err(_) => Err(Error::InternalError),
+    }
+}
+
+#[get("/v2/<repo>/<name>/blobs/uploads/<uuid>")]
+pub async fn get_upload_status_2level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    repo: String,
+    name: String,
+    uuid: String,
+) -> Result<Custom<UploadInfo>, Error> {
+    get_upload_status(auth_user, ci, format!("{}/{}", repo, name), uuid).await
+}
+
+#[get("/v2/<org>/<repo>/<name>/blobs/uploads/<uuid>")]
+pub async fn get_upload_status_3level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    org: String,
+    repo: String,
+    name: String,
+    uuid: String,
+) -> Result<Custom<UploadInfo>, Error> {
+    get_upload_status(auth_user, ci, format!("{}/{}/{}", org, repo, name), uuid).await
+}
+
+#[get("/v2/<fourth>/<org>/<repo>/<name>/blobs/uploads/<uuid>")]
+pub async fn get_upload_status_4level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    fourth: String,
+    org: String,
+    repo: String,
+    name: String,
+    uuid: String,
+) -> Result<Custom<UploadInfo>, Error> {
+    get_upload_status(
+        auth_user,
+        ci,
+        format!("{}/{}/{}/{}", fourth, org, repo, name),
+        uuid,
+    )
+    .await
+}
+
+#[get("/v2/<fifth>/<fourth>/<org>/<repo>/<name>/blobs/uploads/<uuid>")]
+pub async fn get_upload_status_5level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    fifth: String,
+    fourth: String,
+    org: String,
+    repo: String,
+    name: String,
+    uuid: String,
+) -> Result<Custom<UploadInfo>, Error> {
+    get_upload_status(
+        auth_user,
+        ci,
+        format!("{}/{}/{}/{}/{}", fifth, fourth, org, repo, name),
+        uuid,
+    )
+    .await
+}
+
 /*
  Starting point for an uploading a new image or new version of an image.
 
  We respond with details of location and UUID to upload to with patch/put.
 
  No data is being transferred _unless_ the request ends with "?digest".
- In this case the whole blob is attached.
+ In this case the whole blob is attached, and stored and verified in one go.
 */
-#[post("/v2/<repo_name>/blobs/uploads", data = "<data>")]
+#[post("/v2/<repo_name>/blobs/uploads?<digest>", data = "<data>")]
 pub async fn post_blob_upload(
-    uri: &Origin<'_>, // This is a mess, but needed to check for ?digest
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     repo_name: String,
+    digest: Option<String>,
     data: rocket::data::Data<'_>,
 ) -> Result<Upload, Error> {
+    if let Some(digest) = digest {
+        return upload_blob(auth_user, ci, tc, repo_name, digest, data)
+            .await
+            .map(Upload::Accepted);
+    }
+
     /*
     Ask the backend for a UUID.
 
@@ -497,32 +788,11 @@ pub async fn post_blob_upload(
         .await
         .map_err(|e| match e {
             StorageDriverError::InvalidName(n) => Error::NameInvalid(n),
+            StorageDriverError::QuotaExceeded(reason) => Error::QuotaExceeded(reason),
+            StorageDriverError::ReadOnly(reason) => Error::ReadOnly(reason),
             _ => Error::InternalError,
         })?;
 
-    if let Some(digest) = uri.query() {
-        if digest.starts_with("digest=") {
-            //Have a monolithic upload with data
-
-            //Unwrap must be safe given above statement
-            let digest = digest
-                .strip_prefix("digest=")
-                .unwrap()
-                .percent_decode_lossy();
-            return put_blob(
-                auth_user,
-                ci,
-                tc,
-                repo_name.to_string(),
-                uuid,
-                digest.to_string(),
-                data,
-            )
-            .await
-            .map(Upload::Accepted);
-        }
-    }
-
     Ok(Upload::Info(create_upload_info(
         Uuid(uuid),
         RepoName(repo_name.clone()),
@@ -530,44 +800,87 @@ pub async fn post_blob_upload(
     )))
 }
 
+/*
+ * Monolithic upload in a single POST
+ */
+async fn upload_blob(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    repo_name: String,
+    digest: String,
+    data: rocket::data::Data<'_>,
+) -> Result<AcceptedUpload, Error> {
+    let digest = digest::parse(&digest).map_err(|_| Error::DigestInvalid)?;
+    let ds = data.open(tc.max_blob_size.mebibytes());
+    let res = ci.upload_blob_complete(&repo_name, &digest, ds).await;
+    audit::record(
+        AuditRecord::for_caller(AuditAction::Push, &auth_user)
+            .repository(&repo_name)
+            .digest(&digest)
+            .outcome(&res),
+    );
+    let info = res.map_err(|e| match e {
+        StorageDriverError::InvalidName(n) => Error::NameInvalid(n),
+        StorageDriverError::InvalidDigest => Error::DigestInvalid,
+        StorageDriverError::QuotaExceeded(reason) => Error::QuotaExceeded(reason),
+        StorageDriverError::TooLarge => blob_too_large(tc),
+        StorageDriverError::ReadOnly(reason) => Error::ReadOnly(reason),
+        _ => Error::InternalError,
+    })?;
+
+    Ok(create_accepted_upload(
+        digest,
+        RepoName(repo_name),
+        Uuid(info.session_id),
+        (0, (info.uploaded as u32).checked_sub(1).unwrap_or(0)), // Note first byte is 0
+    ))
+}
+
 /*
  * Parse 2 level <repo>/<name> style path and pass it to put_blob_upload_onename
  */
-#[post("/v2/<repo>/<name>/blobs/uploads", data = "<data>")]
+#[post("/v2/<repo>/<name>/blobs/uploads?<digest>", data = "<data>")]
 pub async fn post_blob_upload_2level(
-    //digest: PossibleDigest, //create requestguard to handle /?digest
-    uri: &Origin<'_>,
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     repo: String,
     name: String,
+    digest: Option<String>,
     data: rocket::data::Data<'_>,
 ) -> Result<Upload, Error> {
-    post_blob_upload(uri, auth_user, ci, tc, format!("{}/{}", repo, name), data).await
+    post_blob_upload(
+        auth_user,
+        ci,
+        tc,
+        format!("{}/{}", repo, name),
+        digest,
+        data,
+    )
+    .await
 }
 
 /*
  * Parse 3 level <org>/<repo>/<name> style path and pass it to put_blob_upload_onename
  */
-#[post("/v2/<org>/<repo>/<name>/blobs/uploads", data = "<data>")]
+#[post("/v2/<org>/<repo>/<name>/blobs/uploads?<digest>", data = "<data>")]
 pub async fn post_blob_upload_3level(
-    //digest: PossibleDigest, //create requestguard to handle /?digest
-    uri: &Origin<'_>,
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     org: String,
     repo: String,
     name: String,
+    digest: Option<String>,
     data: rocket::data::Data<'_>,
 ) -> Result<Upload, Error> {
     post_blob_upload(
-        uri,
         auth_user,
         ci,
         tc,
         format!("{}/{}/{}", org, repo, name),
+        digest,
         data,
     )
     .await
@@ -576,25 +889,27 @@ pub async fn post_blob_upload_3level(
 /*
  * Parse 4 level <fourth>/<org>/<repo>/<name> style path
  */
-#[post("/v2/<fourth>/<org>/<repo>/<name>/blobs/uploads", data = "<data>")]
+#[post(
+    "/v2/<fourth>/<org>/<repo>/<name>/blobs/uploads?<digest>",
+    data = "<data>"
+)]
 pub async fn post_blob_upload_4level(
-    //digest: PossibleDigest, //create requestguard to handle /?digest
-    uri: &Origin<'_>,
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     fourth: String,
     org: String,
     repo: String,
     name: String,
+    digest: Option<String>,
     data: rocket::data::Data<'_>,
 ) -> Result<Upload, Error> {
     post_blob_upload(
-        uri,
         auth_user,
         ci,
         tc,
         format!("{}/{}/{}/{}", fourth, org, repo, name),
+        digest,
         data,
     )
     .await
@@ -604,28 +919,27 @@ pub async fn post_blob_upload_4level(
  * Parse 5 level <fith>/<fourth>/<org>/<repo>/<name> style path
  */
 #[post(
-    "/v2/<fifth>/<fourth>/<org>/<repo>/<name>/blobs/uploads",
+    "/v2/<fifth>/<fourth>/<org>/<repo>/<name>/blobs/uploads?<digest>",
     data = "<data>"
 )]
 pub async fn post_blob_upload_5level(
-    //digest: PossibleDigest, //create requestguard to handle /?digest
-    uri: &Origin<'_>,
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     fifth: String,
     fourth: String,
     org: String,
     repo: String,
     name: String,
+    digest: Option<String>,
     data: rocket::data::Data<'_>,
 ) -> Result<Upload, Error> {
     post_blob_upload(
-        uri,
         auth_user,
         ci,
         tc,
         format!("{}/{}/{}/{}/{}", fifth, fourth, org, repo, name),
+        digest,
         data,
     )
     .await
@@ -668,22 +982,30 @@ pub fn post_blob_upload_6level(
  */
 #[delete("/v2/<repo>/blobs/<digest>")]
 pub async fn delete_blob(
-    _auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     repo: String,
     digest: String,
 ) -> Result<BlobDeleted, Error> {
     let digest = digest::parse(&digest).map_err(|_| Error::DigestInvalid)?;
-    ci.delete_blob(&repo, &digest)
-        .await
-        .map_err(|_| Error::BlobUnknown)?;
+    let res = ci.delete_blob(&repo, &digest).await;
+    audit::record(
+        AuditRecord::for_caller(AuditAction::Delete, &auth_user)
+            .repository(&repo)
+            .digest(&digest)
+            .outcome(&res),
+    );
+    res.map_err(|e| match e {
+        StorageDriverError::ReadOnly(reason) => Error::ReadOnly(reason),
+        _ => Error::BlobUnknown,
+    })?;
     Ok(BlobDeleted {})
 }
 
 #[delete("/v2/<user>/<repo>/blobs/<digest>")]
 pub async fn delete_blob_2level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     user: String,
     repo: String,
     digest: String,
@@ -694,7 +1016,7 @@ pub async fn delete_blob_2level(
 #[delete("/v2/<org>/<user>/<repo>/blobs/<digest>")]
 pub async fn delete_blob_3level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     org: String,
     user: String,
     repo: String,
@@ -706,7 +1028,7 @@ pub async fn delete_blob_3level(
 #[delete("/v2/<fourth>/<org>/<user>/<repo>/blobs/<digest>")]
 pub async fn delete_blob_4level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     fourth: String,
     org: String,
     user: String,
@@ -725,7 +1047,7 @@ pub async fn delete_blob_4level(
 #[delete("/v2/<fifth>/<fourth>/<org>/<user>/<repo>/blobs/<digest>")]
 pub async fn delete_blob_5level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     fifth: String,
     fourth: String,
     org: String,
@@ -0,0 +1,16 @@
+use crate::capabilities::Capabilities;
+use crate::TrowConfig;
+
+use rocket::get;
+use rocket::State;
+
+/*
+* What this deployment supports, see capabilities.rs. No login needed, so clients can check before
+* they log in.
+* GET /trow/v1/capabilities
+*/
+
+#[get("/trow/v1/capabilities")]
+pub fn get_capabilities(tc: &State<TrowConfig>) -> Capabilities {
+    Capabilities::new(tc)
+}
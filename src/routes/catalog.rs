@@ -1,23 +1,34 @@
-use crate::client_interface::ClientInterface;
-use crate::registry_interface::{CatalogOperations, ManifestHistory};
+use crate::registry_interface::{ManifestHistory, RegistryInterface};
 use crate::response::errors::Error;
 use crate::response::trow_token::TrowToken;
+use crate::tenants;
 use crate::types::{RepoCatalog, TagList};
+use crate::TrowConfig;
 use anyhow::Result;
 use rocket::get;
 
-#[get("/v2/_catalog?<n>&<last>")]
+/*
+ * Lists repositories, optionally only those starting with prefix, e.g. a team's namespace
+ * with prefix=myteam/
+ *
+ * Repositories of tenants the caller isn't a member of are left out by the backend, which keeps
+ * the tenants.
+ */
+#[get("/v2/_catalog?<n>&<last>&<prefix>")]
 pub async fn get_catalog(
-    _auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
     n: Option<u32>,
     last: Option<String>,
+    prefix: Option<String>,
 ) -> Result<RepoCatalog, Error> {
     let limit = n.unwrap_or(std::u32::MAX);
     let last_repo = last.unwrap_or_default();
+    let user = Some(auth_user.user.as_str()).filter(|u| !tenants::sees_everything(tc, u));
 
     let cat = ci
-        .get_catalog(Some(&last_repo), Some(limit))
+        .get_catalog(Some(&last_repo), Some(limit), prefix.as_deref(), user)
         .await
         .map_err(|_| Error::InternalError)?;
 
@@ -27,7 +38,7 @@ pub async fn get_catalog(
 #[get("/v2/<repo_name>/tags/list?<last>&<n>")]
 pub async fn list_tags(
     _auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     repo_name: String,
     last: Option<String>,
     n: Option<u32>,
@@ -37,15 +48,14 @@ pub async fn list_tags(
 
     let tags = ci
         .get_tags(&repo_name, Some(&last_tag), Some(limit))
-        .await
-        .map_err(|_| Error::InternalError)?;
+        .await?;
     Ok(TagList::new_filled(repo_name, tags))
 }
 
 #[get("/v2/<user>/<repo>/tags/list?<last>&<n>")]
 pub async fn list_tags_2level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     user: String,
     repo: String,
     last: Option<String>,
@@ -57,7 +67,7 @@ pub async fn list_tags_2level(
 #[get("/v2/<org>/<user>/<repo>/tags/list?<last>&<n>")]
 pub async fn list_tags_3level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     org: String,
     user: String,
     repo: String,
@@ -70,7 +80,7 @@ pub async fn list_tags_3level(
 #[get("/v2/<fourth>/<org>/<user>/<repo>/tags/list?<last>&<n>")]
 pub async fn list_tags_4level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     fourth: String,
     org: String,
     user: String,
@@ -91,7 +101,7 @@ pub async fn list_tags_4level(
 #[get("/v2/<fifth>/<fourth>/<org>/<user>/<repo>/tags/list?<last>&<n>")]
 pub async fn list_tags_5level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     fifth: String,
     fourth: String,
     org: String,
@@ -114,7 +124,7 @@ pub async fn list_tags_5level(
 #[get("/<onename>/manifest_history/<reference>?<last>&<n>")]
 pub async fn get_manifest_history(
     _auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     onename: String,
     reference: String,
     last: Option<String>,
@@ -133,7 +143,7 @@ pub async fn get_manifest_history(
 #[get("/<user>/<repo>/manifest_history/<reference>?<last>&<n>")]
 pub async fn get_manifest_history_2level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     user: String,
     repo: String,
     reference: String,
@@ -154,7 +164,7 @@ pub async fn get_manifest_history_2level(
 #[get("/<org>/<user>/<repo>/manifest_history/<reference>?<last>&<n>")]
 pub async fn get_manifest_history_3level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     org: String,
     user: String,
     repo: String,
@@ -176,7 +186,7 @@ pub async fn get_manifest_history_3level(
 #[get("/<fourth>/<org>/<user>/<repo>/manifest_history/<reference>?<last>&<n>")]
 pub async fn get_manifest_history_4level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     fourth: String,
     org: String,
     user: String,
@@ -199,7 +209,7 @@ pub async fn get_manifest_history_4level(
 #[get("/<fifth>/<fourth>/<org>/<user>/<repo>/manifest_history/<reference>?<last>&<n>")]
 pub async fn get_manifest_history_5level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     fifth: String,
     fourth: String,
     org: String,
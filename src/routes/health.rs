@@ -1,5 +1,4 @@
-use crate::client_interface::ClientInterface;
-use crate::registry_interface::metrics::Metrics;
+use crate::registry_interface::RegistryInterface;
 use crate::types::HealthResponse;
 
 use rocket::get;
@@ -11,7 +10,7 @@ use rocket::State;
 */
 
 #[get("/healthz")]
-pub async fn healthz(ci: &State<ClientInterface>) -> HealthResponse {
+pub async fn healthz(ci: &State<Box<dyn RegistryInterface>>) -> HealthResponse {
     HealthResponse {
         message: "".to_string(),
         is_healthy: ci.is_healthy().await,
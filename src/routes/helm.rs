@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+
+use super::blob;
+use crate::registry_interface::{BlobReader, ByteRanges, ChartIndex, RegistryInterface};
+use crate::response::errors::Error;
+use crate::response::trow_token::TrowToken;
+use crate::tenants::{self, TenantCache};
+use crate::TrowConfig;
+use chrono::Utc;
+use rocket::get;
+
+/*
+ * A classic Helm chart repository of the charts pushed as OCI artifacts, for clients that can't
+ * pull from OCI registries:
+ *
+ * helm repo add trow https://trow.example.com/helm
+ *
+ * The index is built from what's stored each time it's asked for, so charts show up as soon as
+ * they're pushed. Charts of tenants the caller isn't a member of are left out.
+ */
+#[get("/helm/index.yaml")]
+pub async fn get_chart_index(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    tenant_cache: &rocket::State<TenantCache>,
+) -> Result<ChartIndex, Error> {
+    let hidden =
+        tenants::hiding_from(tc, tenant_cache, ci.inner().as_ref(), &auth_user.user).await?;
+    let mut charts = ci.list_charts().await.map_err(|_| Error::InternalError)?;
+    if let Some(hidden) = hidden {
+        charts.retain(|c| tenants::can_see(&hidden, &auth_user.user, &c.repo_name));
+    }
+    Ok(ChartIndex::new(charts, Utc::now()))
+}
+
+/*
+ * A packaged chart, at the URL given in the index:
+ *
+ * GET /helm/charts/<repo>/<sha256 hex>/<name>-<version>.tgz
+ *
+ * The file name is only there so Helm saves the chart under it, the blob is found by digest.
+ */
+#[get("/helm/charts/<path..>")]
+pub async fn get_chart(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    range: Option<ByteRanges>,
+    path: PathBuf,
+) -> Result<BlobReader, Error> {
+    let segments: Vec<String> = path
+        .iter()
+        .map(|s| s.to_string_lossy().to_string())
+        .collect();
+    if segments.len() < 3 {
+        return Err(Error::BlobUnknown);
+    }
+    let hex = &segments[segments.len() - 2];
+    let repo_name = segments[..segments.len() - 2].join("/");
+    blob::get_blob(
+        auth_user,
+        ci,
+        range,
+        None,
+        repo_name,
+        format!("sha256:{}", hex),
+    )
+    .await
+}
@@ -0,0 +1,116 @@
+use crate::registry_interface::{JobError, JobList, JobStatus, Reference, RegistryInterface};
+use crate::response::errors::Error;
+use crate::response::trow_token::TrowToken;
+use crate::types::{JobRequest, PrewarmJobRequest, StartedJob};
+use crate::TrowConfig;
+use rocket::serde::json::Json;
+use rocket::{delete, get, post};
+
+/*
+ * Long running jobs such as garbage collection.
+ *
+ * POST /trow/v1/jobs with {"kind": "gc"} starts a job and returns 202 with its status.
+ * Poll GET /trow/v1/jobs/<id> for progress, DELETE it to cancel. Only admins can start jobs other
+ * than usage and proxy-check, or cancel jobs.
+ *
+ * POST /trow/v1/prewarm with {"image": "f/docker/library/nginx:1.21", "notify": true} starts a
+ * prewarm job for the image, which makes sure all its blobs are in the registry, fetching them
+ * from upstream for a proxied image, then tells node agents to pull it if notify is set.
+ */
+
+// Jobs that only report on the registry, which anyone who can log in can start
+const READ_ONLY_JOBS: &[&str] = &["usage", "proxy-check"];
+
+fn to_error(e: JobError) -> Error {
+    match e {
+        JobError::NotFound(id) => Error::JobUnknown(id),
+        JobError::Invalid(reason) => Error::JobInvalid(reason),
+        JobError::Internal => Error::InternalError,
+    }
+}
+
+#[get("/trow/v1/jobs")]
+pub async fn list_jobs(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+) -> Result<JobList, Error> {
+    ci.list_jobs().await.map_err(to_error)
+}
+
+#[post("/trow/v1/jobs", data = "<job>")]
+pub async fn start_job(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    job: Json<JobRequest>,
+) -> Result<StartedJob, Error> {
+    check_can_start(tc, &auth_user, &job.kind)?;
+    ci.start_job(&job.kind)
+        .await
+        .map(StartedJob)
+        .map_err(to_error)
+}
+
+/*
+ * Other jobs delete or replace tags across repositories, e.g. retention and restore, or read the
+ * whole registry, e.g. backups, so only admins can start them.
+ */
+pub(super) fn check_can_start(
+    tc: &TrowConfig,
+    auth_user: &TrowToken,
+    kind: &str,
+) -> Result<(), Error> {
+    if READ_ONLY_JOBS.contains(&kind) {
+        return Ok(());
+    }
+    super::admin::require_admin(tc, auth_user, &format!("start {} jobs", kind))
+}
+
+#[post("/trow/v1/prewarm", data = "<req>")]
+pub async fn prewarm(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    req: Json<PrewarmJobRequest>,
+) -> Result<StartedJob, Error> {
+    let (repo_name, reference) = split_image(&req.image)
+        .ok_or_else(|| Error::JobInvalid(format!("{} is not a valid image", req.image)))?;
+    ci.prewarm(repo_name, &reference.to_string(), req.notify)
+        .await
+        .map(StartedJob)
+        .map_err(to_error)
+}
+
+// The repository and reference of repo:tag or repo@digest, with the tag "latest" if there's neither
+fn split_image(image: &str) -> Option<(&str, Reference)> {
+    let (repo_name, reference) = match image.split_once('@') {
+        Some(split) => split,
+        None => match image.rsplit_once(':') {
+            Some((repo, tag)) if !tag.contains('/') => (repo, tag),
+            _ => (image, "latest"),
+        },
+    };
+    if repo_name.is_empty() {
+        return None;
+    }
+    Some((repo_name, reference.parse().ok()?))
+}
+
+#[get("/trow/v1/jobs/<id>")]
+pub async fn get_job(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    id: String,
+) -> Result<JobStatus, Error> {
+    ci.get_job(&id).await.map_err(to_error)
+}
+
+#[delete("/trow/v1/jobs/<id>")]
+pub async fn cancel_job(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    id: String,
+) -> Result<JobStatus, Error> {
+    super::admin::require_admin(tc, &auth_user, "cancel jobs")?;
+    ci.cancel_job(&id).await.map_err(to_error)
+}
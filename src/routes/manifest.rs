@@ -1,10 +1,14 @@
 use rocket::data::ToByteUnit;
-use rocket::{delete, get, put};
+use rocket::{delete, get, head, put};
 
-use crate::client_interface::ClientInterface;
-use crate::registry_interface::{digest, ManifestReader, ManifestStorage, StorageDriverError};
+use crate::audit::{self, AuditAction, AuditRecord};
+use crate::registry_interface::{
+    digest, ManifestMetadata, ManifestReader, RegistryInterface, StorageDriverError,
+};
 use crate::response::errors::Error;
+use crate::response::layer_compression::PreferLayerCompression;
 use crate::response::trow_token::TrowToken;
+use crate::schema2::{ManifestAccept, Renditions};
 use crate::types::{create_verified_manifest, ManifestDeleted, RepoName, VerifiedManifest};
 use crate::TrowConfig;
 
@@ -27,28 +31,91 @@ Accept: manifest-version
 # Returns
 200 - return the manifest
 404 - manifest not known to the registry
+
+OCI manifests are served as Docker schema2 to clients that only accept the Docker media types.
+Pulls by tag preferring a layer compression get a copy using transcoded layers, if there is one.
  */
 #[get("/v2/<onename>/manifests/<reference>")]
 pub async fn get_manifest(
-    _auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    renditions: &rocket::State<Renditions>,
+    accept: ManifestAccept,
+    prefer: Option<PreferLayerCompression>,
     onename: String,
     reference: String,
 ) -> Result<ManifestReader, Error> {
-    ci.get_manifest(&onename, &reference)
+    let ci = ci.inner().as_ref();
+    let res = match renditions.original(&onename, &reference) {
+        // A schema2 rendition handed out earlier
+        Some(original) => match ci.get_manifest(&onename, &original).await {
+            Ok(mr) => renditions.convert(ci, &onename, mr).await,
+            Err(e) => Err(e),
+        },
+        None => match ci.get_manifest(&onename, &reference).await {
+            Ok(mr) if accept.wants_schema2(mr.content_type()) => {
+                renditions.convert(ci, &onename, mr).await
+            }
+            Ok(mr) => Ok(with_preferred_layers(ci, &onename, &reference, prefer, mr).await),
+            res => res,
+        },
+    };
+    let rec = AuditRecord::for_caller(AuditAction::Pull, &auth_user)
+        .repository(&onename)
+        .reference(&reference);
+    audit::record(match res {
+        Ok(ref mr) => rec.digest(mr.digest()),
+        Err(ref e) => rec.failed(&e.to_string()),
+    });
+    res.map_err(|_| Error::ManifestUnknown(reference))
+}
+
+/*
+ * The copy of the manifest using layers in the preferred compression, if there is one. Only for
+ * pulls by tag, as a pull by digest has to get exactly the manifest asked for.
+ */
+async fn with_preferred_layers(
+    ci: &dyn RegistryInterface,
+    repo_name: &str,
+    reference: &str,
+    prefer: Option<PreferLayerCompression>,
+    mr: ManifestReader,
+) -> ManifestReader {
+    let compression = match prefer {
+        Some(PreferLayerCompression(c)) if digest::parse(reference).is_err() => c,
+        _ => return mr,
+    };
+    let digest = mr.digest().to_string();
+    match ci
+        .get_transcoded_manifest(repo_name, &digest, compression)
         .await
-        .map_err(|_| Error::ManifestUnknown(reference))
+    {
+        Ok(transcoded) => transcoded,
+        Err(_) => mr,
+    }
 }
 
 #[get("/v2/<user>/<repo>/manifests/<reference>")]
 pub async fn get_manifest_2level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    renditions: &rocket::State<Renditions>,
+    accept: ManifestAccept,
+    prefer: Option<PreferLayerCompression>,
     user: String,
     repo: String,
     reference: String,
 ) -> Result<ManifestReader, Error> {
-    get_manifest(auth_user, ci, format!("{}/{}", user, repo), reference).await
+    get_manifest(
+        auth_user,
+        ci,
+        renditions,
+        accept,
+        prefer,
+        format!("{}/{}", user, repo),
+        reference,
+    )
+    .await
 }
 
 /*
@@ -57,7 +124,10 @@ pub async fn get_manifest_2level(
 #[get("/v2/<org>/<user>/<repo>/manifests/<reference>")]
 pub async fn get_manifest_3level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    renditions: &rocket::State<Renditions>,
+    accept: ManifestAccept,
+    prefer: Option<PreferLayerCompression>,
     org: String,
     user: String,
     repo: String,
@@ -66,6 +136,9 @@ pub async fn get_manifest_3level(
     get_manifest(
         auth_user,
         ci,
+        renditions,
+        accept,
+        prefer,
         format!("{}/{}/{}", org, user, repo),
         reference,
     )
@@ -78,7 +151,10 @@ pub async fn get_manifest_3level(
 #[get("/v2/<fourth>/<org>/<user>/<repo>/manifests/<reference>")]
 pub async fn get_manifest_4level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    renditions: &rocket::State<Renditions>,
+    accept: ManifestAccept,
+    prefer: Option<PreferLayerCompression>,
     fourth: String,
     org: String,
     user: String,
@@ -88,6 +164,9 @@ pub async fn get_manifest_4level(
     get_manifest(
         auth_user,
         ci,
+        renditions,
+        accept,
+        prefer,
         format!("{}/{}/{}/{}", fourth, org, user, repo),
         reference,
     )
@@ -100,7 +179,10 @@ pub async fn get_manifest_4level(
 #[get("/v2/<fifth>/<fourth>/<org>/<user>/<repo>/manifests/<reference>")]
 pub async fn get_manifest_5level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    renditions: &rocket::State<Renditions>,
+    accept: ManifestAccept,
+    prefer: Option<PreferLayerCompression>,
     fifth: String,
     fourth: String,
     org: String,
@@ -111,12 +193,160 @@ pub async fn get_manifest_5level(
     get_manifest(
         auth_user,
         ci,
+        renditions,
+        accept,
+        prefer,
         format!("{}/{}/{}/{}/{} ", fifth, fourth, org, user, repo),
         reference,
     )
     .await
 }
 
+/*
+---
+Checking a manifest exists
+HEAD /v2/<name>/manifests/<reference>
+
+# Returns
+200 - with the Content-Type, Content-Length and Docker-Content-Digest a GET would return
+404 - manifest not known to the registry
+
+Answered without reading the manifest, except for the schema2 renditions of OCI manifests, which
+are only made when asked for, and copies using transcoded layers.
+ */
+#[head("/v2/<onename>/manifests/<reference>")]
+pub async fn head_manifest(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    renditions: &rocket::State<Renditions>,
+    accept: ManifestAccept,
+    prefer: Option<PreferLayerCompression>,
+    onename: String,
+    reference: String,
+) -> Result<ManifestMetadata, Error> {
+    let ci = ci.inner().as_ref();
+    let original = match renditions.original(&onename, &reference) {
+        Some(original) => original,
+        None => match ci.manifest_metadata(&onename, &reference).await {
+            Ok(md) if accept.wants_schema2(&md.content_type) => reference.clone(),
+            Ok(md) if prefer.is_some() && digest::parse(&reference).is_err() => {
+                let mr = match ci.get_manifest(&onename, &reference).await {
+                    Ok(mr) => with_preferred_layers(ci, &onename, &reference, prefer, mr).await,
+                    Err(_) => return Ok(md),
+                };
+                return mr.metadata().await.map_err(|_| Error::InternalError);
+            }
+            res => return res.map_err(|_| Error::ManifestUnknown(reference)),
+        },
+    };
+    let res = match ci.get_manifest(&onename, &original).await {
+        Ok(mr) => renditions.convert(ci, &onename, mr).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(mr) => mr.metadata().await.map_err(|_| Error::InternalError),
+        Err(_) => Err(Error::ManifestUnknown(reference)),
+    }
+}
+
+#[head("/v2/<user>/<repo>/manifests/<reference>")]
+pub async fn head_manifest_2level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    renditions: &rocket::State<Renditions>,
+    accept: ManifestAccept,
+    prefer: Option<PreferLayerCompression>,
+    user: String,
+    repo: String,
+    reference: String,
+) -> Result<ManifestMetadata, Error> {
+    head_manifest(
+        auth_user,
+        ci,
+        renditions,
+        accept,
+        prefer,
+        format!("{}/{}", user, repo),
+        reference,
+    )
+    .await
+}
+
+#[head("/v2/<org>/<user>/<repo>/manifests/<reference>")]
+pub async fn head_manifest_3level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    renditions: &rocket::State<Renditions>,
+    accept: ManifestAccept,
+    prefer: Option<PreferLayerCompression>,
+    org: String,
+    user: String,
+    repo: String,
+    reference: String,
+) -> Result<ManifestMetadata, Error> {
+    head_manifest(
+        auth_user,
+        ci,
+        renditions,
+        accept,
+        prefer,
+        format!("{}/{}/{}", org, user, repo),
+        reference,
+    )
+    .await
+}
+
+#[head("/v2/<fourth>/<org>/<user>/<repo>/manifests/<reference>")]
+pub async fn head_manifest_4level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    renditions: &rocket::State<Renditions>,
+    accept: ManifestAccept,
+    prefer: Option<PreferLayerCompression>,
+    fourth: String,
+    org: String,
+    user: String,
+    repo: String,
+    reference: String,
+) -> Result<ManifestMetadata, Error> {
+    head_manifest(
+        auth_user,
+        ci,
+        renditions,
+        accept,
+        prefer,
+        format!("{}/{}/{}/{}", fourth, org, user, repo),
+        reference,
+    )
+    .await
+}
+
+#[head("/v2/<fifth>/<fourth>/<org>/<user>/<repo>/manifests/<reference>")]
+pub async fn head_manifest_5level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    renditions: &rocket::State<Renditions>,
+    accept: ManifestAccept,
+    prefer: Option<PreferLayerCompression>,
+    fifth: String,
+    fourth: String,
+    org: String,
+    user: String,
+    repo: String,
+    reference: String,
+) -> Result<ManifestMetadata, Error> {
+    head_manifest(
+        auth_user,
+        ci,
+        renditions,
+        accept,
+        prefer,
+        format!("{}/{}/{}/{}/{}", fifth, fourth, org, user, repo),
+        reference,
+    )
+    .await
+}
+
 /*
 
 ---
@@ -127,8 +357,8 @@ Content-Type: <manifest media type>
  */
 #[put("/v2/<repo_name>/manifests/<reference>", data = "<chunk>")]
 pub async fn put_image_manifest(
-    _auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     repo_name: String,
     reference: String,
@@ -136,19 +366,28 @@ pub async fn put_image_manifest(
 ) -> Result<VerifiedManifest, Error> {
     let data = chunk.open(tc.max_manifest_size.mebibytes());
 
-    match ci.store_manifest(&repo_name, &reference, data).await {
+    let res = ci.store_manifest(&repo_name, &reference, data).await;
+    let rec = AuditRecord::for_caller(AuditAction::Push, &auth_user)
+        .repository(&repo_name)
+        .reference(&reference);
+    audit::record(match res {
+        Ok(ref digest) => rec.digest(digest),
+        Err(ref e) => rec.failed(&e.to_string()),
+    });
+
+    match res {
         Ok(digest) => Ok(create_verified_manifest(
             RepoName(repo_name),
             digest,
             reference,
         )),
         Err(StorageDriverError::InvalidName(name)) => Err(Error::NameInvalid(name)),
-        Err(StorageDriverError::InvalidManifest) => Err(Error::ManifestInvalid("".to_string())),
-        Err(StorageDriverError::InvalidContentRange) => Err(Error::ManifestInvalid(format!(
-            "Content over data limit {} mebibytes",
-            tc.max_blob_size
+        Err(StorageDriverError::BlobUnknown(digest)) => Err(Error::ManifestBlobUnknown(digest)),
+        Err(StorageDriverError::InvalidContentRange) => Err(Error::SizeInvalid(format!(
+            "Manifest over data limit {} mebibytes",
+            tc.max_manifest_size
         ))),
-        Err(_) => Err(Error::InternalError),
+        Err(e) => Err(e.into()),
     }
 }
 
@@ -158,7 +397,7 @@ pub async fn put_image_manifest(
 #[put("/v2/<user>/<repo>/manifests/<reference>", data = "<chunk>")]
 pub async fn put_image_manifest_2level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     user: String,
     repo: String,
@@ -182,7 +421,7 @@ pub async fn put_image_manifest_2level(
 #[put("/v2/<org>/<user>/<repo>/manifests/<reference>", data = "<chunk>")]
 pub async fn put_image_manifest_3level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     org: String,
     user: String,
@@ -210,7 +449,7 @@ pub async fn put_image_manifest_3level(
 )]
 pub async fn put_image_manifest_4level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     fourth: String,
     org: String,
@@ -239,7 +478,7 @@ pub async fn put_image_manifest_4level(
 )]
 pub async fn put_image_manifest_5level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
     fifth: String,
     fourth: String,
@@ -268,24 +507,38 @@ DELETE /v2/<name>/manifests/<reference>
 
 #[delete("/v2/<repo>/manifests/<digest>")]
 pub async fn delete_image_manifest(
-    _auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     repo: String,
     digest: String,
 ) -> Result<ManifestDeleted, Error> {
-    let digest = digest::parse(&digest).map_err(|_| Error::Unsupported)?;
-    match ci.delete_manifest(&repo, &digest).await {
+    // Only digests can be deleted, tags can't contain a ':'
+    let digest = digest::parse(&digest).map_err(|_| {
+        if digest.contains(':') {
+            Error::DigestInvalid
+        } else {
+            Error::Unsupported
+        }
+    })?;
+    let res = ci.delete_manifest(&repo, &digest).await;
+    audit::record(
+        AuditRecord::for_caller(AuditAction::Delete, &auth_user)
+            .repository(&repo)
+            .digest(&digest)
+            .outcome(&res),
+    );
+    match res {
         Ok(_) => Ok(ManifestDeleted {}),
         Err(StorageDriverError::Unsupported) => Err(Error::Unsupported),
         Err(StorageDriverError::InvalidManifest) => Err(Error::ManifestUnknown(repo)),
-        Err(_) => Err(Error::InternalError),
+        Err(e) => Err(e.into()),
     }
 }
 
 #[delete("/v2/<user>/<repo>/manifests/<digest>")]
 pub async fn delete_image_manifest_2level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     user: String,
     repo: String,
     digest: String,
@@ -296,7 +549,7 @@ pub async fn delete_image_manifest_2level(
 #[delete("/v2/<org>/<user>/<repo>/manifests/<digest>")]
 pub async fn delete_image_manifest_3level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     org: String,
     user: String,
     repo: String,
@@ -308,7 +561,7 @@ pub async fn delete_image_manifest_3level(
 #[delete("/v2/<fourth>/<org>/<user>/<repo>/manifests/<digest>")]
 pub async fn delete_image_manifest_4level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     fourth: String,
     org: String,
     user: String,
@@ -327,7 +580,7 @@ pub async fn delete_image_manifest_4level(
 #[delete("/v2/<fifth>/<fourth>/<org>/<user>/<repo>/manifests/<digest>")]
 pub async fn delete_image_manifest_5level(
     auth_user: TrowToken,
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     fifth: String,
     fourth: String,
     org: String,
@@ -1,6 +1,4 @@
-use crate::client_interface::ClientInterface;
-
-use crate::registry_interface::{Metrics, MetricsResponse};
+use crate::registry_interface::{MetricsResponse, RegistryInterface};
 use crate::response::errors::Error;
 
 use anyhow::Result;
@@ -13,6 +11,6 @@ use rocket::State;
 */
 
 #[get("/metrics")]
-pub async fn metrics(ci: &State<ClientInterface>) -> Result<MetricsResponse, Error> {
+pub async fn metrics(ci: &State<Box<dyn RegistryInterface>>) -> Result<MetricsResponse, Error> {
     ci.get_metrics().await.map_err(|_| Error::InternalError)
 }
@@ -1,21 +1,33 @@
 use crate::response::authenticate::Authenticate;
-use crate::response::errors::Error;
+use crate::response::errors::{Error, StatusError};
 use crate::response::html::HTML;
 use crate::response::trow_token::ValidBasicToken;
 use crate::response::trow_token::{self, TrowToken};
 use crate::TrowConfig;
+use rocket::http::Status;
 use rocket::request::Request;
 use rocket::serde::json::{json, Json, Value};
 use rocket::State;
 use rocket::{catch, catchers, get, routes};
 use std::str;
 
+mod admin;
 mod blob;
+mod capabilities;
 mod catalog;
 mod health;
+mod helm;
+mod jobs;
 mod manifest;
 mod metrics;
+mod platforms;
+mod quotas;
 mod readiness;
+mod referrers;
+mod retention;
+mod setup;
+mod ui;
+mod usage;
 mod validation;
 
 pub fn routes() -> Vec<rocket::Route> {
@@ -28,6 +40,11 @@ pub fn routes() -> Vec<rocket::Route> {
         manifest::get_manifest_3level,
         manifest::get_manifest_4level,
         manifest::get_manifest_5level,
+        manifest::head_manifest,
+        manifest::head_manifest_2level,
+        manifest::head_manifest_3level,
+        manifest::head_manifest_4level,
+        manifest::head_manifest_5level,
         manifest::put_image_manifest,
         manifest::put_image_manifest_2level,
         manifest::put_image_manifest_3level,
@@ -43,6 +60,11 @@ pub fn routes() -> Vec<rocket::Route> {
         blob::get_blob_3level,
         blob::get_blob_4level,
         blob::get_blob_5level,
+        blob::head_blob,
+        blob::head_blob_2level,
+        blob::head_blob_3level,
+        blob::head_blob_4level,
+        blob::head_blob_5level,
         blob::put_blob,
         blob::put_blob_2level,
         blob::put_blob_3level,
@@ -53,6 +75,11 @@ pub fn routes() -> Vec<rocket::Route> {
         blob::patch_blob_3level,
         blob::patch_blob_4level,
         blob::patch_blob_5level,
+        blob::get_upload_status,
+        blob::get_upload_status_2level,
+        blob::get_upload_status_3level,
+        blob::get_upload_status_4level,
+        blob::get_upload_status_5level,
         blob::post_blob_upload,
         blob::post_blob_upload_2level,
         blob::post_blob_upload_3level,
@@ -75,15 +102,63 @@ pub fn routes() -> Vec<rocket::Route> {
         catalog::get_manifest_history_3level,
         catalog::get_manifest_history_4level,
         catalog::get_manifest_history_5level,
+        referrers::get_referrers,
+        referrers::get_referrers_2level,
+        referrers::get_referrers_3level,
+        referrers::get_referrers_4level,
+        referrers::get_referrers_5level,
         validation::validate_image,
+        validation::evaluate_policy,
         health::healthz,
+        capabilities::get_capabilities,
         readiness::readiness,
-        metrics::metrics
+        metrics::metrics,
+        jobs::list_jobs,
+        jobs::start_job,
+        jobs::get_job,
+        jobs::cancel_job,
+        jobs::prewarm,
+        quotas::get_quota_usage,
+        quotas::check_upload,
+        retention::dry_run_retention,
+        admin::list_repositories,
+        admin::delete_repository,
+        admin::start_gc,
+        admin::list_uploads,
+        admin::active_transfers,
+        admin::cancel_transfer,
+        admin::pull_stats,
+        admin::storage_report,
+        admin::repo_storage,
+        admin::tag_history,
+        admin::get_image_details,
+        admin::export_image,
+        admin::import_image,
+        admin::get_rate_limits,
+        admin::set_rate_limits,
+        admin::transfer_report,
+        admin::config_status,
+        admin::list_tenants,
+        admin::put_tenant,
+        admin::delete_tenant,
+        admin::scrub_report,
+        admin::get_read_only,
+        admin::set_read_only,
+        admin::watch_events,
+        admin::list_trash,
+        admin::restore_trash,
+        usage::usage_report,
+        platforms::get_platforms,
+        helm::get_chart_index,
+        helm::get_chart,
+        setup::get_setup,
+        setup::complete_setup,
+        ui::get_ui
     ]
 }
 
 pub fn catchers() -> Vec<rocket::Catcher> {
-    catchers![not_found, no_auth]
+    catchers![not_found, no_auth, default_error]
 }
 
 /*
@@ -116,6 +191,12 @@ fn no_auth(_req: &Request) -> Authenticate {
     Authenticate {}
 }
 
+// e.g. a request guard refusing a push, in the spec's error format
+#[catch(default)]
+fn default_error(status: Status, _req: &Request) -> StatusError {
+    StatusError(status)
+}
+
 /* login should it be /v2/login?
  * this is where client will attempt to login
  *
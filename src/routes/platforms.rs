@@ -0,0 +1,39 @@
+use crate::registry_interface::{IndexSummary, RegistryInterface, StorageDriverError};
+use crate::response::errors::Error;
+use crate::response::trow_token::TrowToken;
+use rocket::get;
+
+/*
+ * The platforms an image is built for, with the digest and size of each platform's image, e.g.
+ * to check an image covers the nodes it'll run on.
+ *
+ * Given platform=os/architecture or os/architecture/variant, only the matching platforms are
+ * listed, which gives the digest a node on that platform would pull. It's a 404 if none match.
+ */
+#[get("/trow/v1/platforms?<repo>&<reference>&<platform>")]
+pub async fn get_platforms(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    repo: String,
+    reference: String,
+    platform: Option<String>,
+) -> Result<IndexSummary, Error> {
+    let mut summary = ci
+        .index_summary(&repo, &reference)
+        .await
+        .map_err(|e| match e {
+            StorageDriverError::InvalidName(name) => Error::NameInvalid(name),
+            StorageDriverError::InvalidManifest => Error::ManifestUnknown(reference.clone()),
+            _ => Error::InternalError,
+        })?;
+    if let Some(platform) = platform {
+        summary.platforms.retain(|p| p.matches(&platform));
+        if summary.platforms.is_empty() {
+            return Err(Error::ManifestUnknown(format!(
+                "{} for {}",
+                reference, platform
+            )));
+        }
+    }
+    Ok(summary)
+}
@@ -0,0 +1,50 @@
+use crate::registry_interface::{QuotaUsage, RegistryInterface, UploadCheck};
+use crate::response::errors::Error;
+use crate::response::trow_token::TrowToken;
+use rocket::get;
+use std::path::PathBuf;
+use trow_server::names;
+
+/*
+ * Usage against the quota covering a repository.
+ *
+ * GET /trow/v1/quotas/<repo> e.g. /trow/v1/quotas/myorg/app
+ */
+#[get("/trow/v1/quotas/<repo..>")]
+pub async fn get_quota_usage(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    repo: PathBuf,
+) -> Result<QuotaUsage, Error> {
+    let repo = repo.to_string_lossy();
+    if !names::is_valid_repo_name(&repo) {
+        return Err(Error::NameInvalid(repo.to_string()));
+    }
+    ci.get_quota_usage(&repo)
+        .await
+        .map_err(|_| Error::InternalError)
+}
+
+/*
+ * Whether a push of _size_ bytes, optionally to _tag_, would be accepted, so CI can find out
+ * before uploading anything. Blobs the repository already has can't be left out, so the push
+ * itself may fit when this says it won't.
+ *
+ * GET /trow/v1/push-check/<repo>?size=<bytes>&tag=<tag>
+ */
+#[get("/trow/v1/push-check/<repo..>?<size>&<tag>")]
+pub async fn check_upload(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    repo: PathBuf,
+    size: u64,
+    tag: Option<String>,
+) -> Result<UploadCheck, Error> {
+    let repo = repo.to_string_lossy();
+    if !names::is_valid_repo_name(&repo) {
+        return Err(Error::NameInvalid(repo.to_string()));
+    }
+    ci.check_upload(&repo, size, tag.as_deref())
+        .await
+        .map_err(|_| Error::InternalError)
+}
@@ -1,5 +1,4 @@
-use crate::client_interface::ClientInterface;
-use crate::registry_interface::Metrics;
+use crate::registry_interface::RegistryInterface;
 use crate::types::ReadinessResponse;
 use rocket::get;
 use rocket::State;
@@ -10,7 +9,7 @@ use rocket::State;
 */
 
 #[get("/readiness")]
-pub async fn readiness(ci: &State<ClientInterface>) -> ReadinessResponse {
+pub async fn readiness(ci: &State<Box<dyn RegistryInterface>>) -> ReadinessResponse {
     ReadinessResponse {
         message: "".to_string(),
         is_ready: ci.is_ready().await,
@@ -0,0 +1,114 @@
+use crate::registry_interface::{digest, ReferrerList, RegistryInterface, StorageDriverError};
+use crate::response::errors::Error;
+use crate::response::trow_token::TrowToken;
+use rocket::{get, FromForm};
+
+#[derive(FromForm)]
+pub struct ReferrersFilter {
+    #[field(name = "artifactType")]
+    artifact_type: Option<String>,
+}
+
+/*
+ * Manifests whose subject is the digest, such as SBOMs and signatures, as an OCI image index.
+ *
+ * Given artifactType, only referrers of that type are listed. A subject with no referrers, or
+ * that isn't stored, gets an empty index rather than a 404, as artifacts can be pushed before
+ * the image they're about.
+ */
+#[get("/v2/<repo_name>/referrers/<digest>?<filter..>")]
+pub async fn get_referrers(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    repo_name: String,
+    digest: String,
+    filter: ReferrersFilter,
+) -> Result<ReferrerList, Error> {
+    digest::parse(&digest).map_err(|_| Error::DigestInvalid)?;
+    let referrers = ci
+        .list_referrers(&repo_name, &digest)
+        .await
+        .map_err(|e| match e {
+            StorageDriverError::InvalidName(name) => Error::NameInvalid(name),
+            _ => Error::InternalError,
+        })?;
+    Ok(ReferrerList::new(
+        referrers,
+        filter.artifact_type.as_deref(),
+    ))
+}
+
+#[get("/v2/<user>/<repo>/referrers/<digest>?<filter..>")]
+pub async fn get_referrers_2level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    user: String,
+    repo: String,
+    digest: String,
+    filter: ReferrersFilter,
+) -> Result<ReferrerList, Error> {
+    get_referrers(auth_user, ci, format!("{}/{}", user, repo), digest, filter).await
+}
+
+#[get("/v2/<org>/<user>/<repo>/referrers/<digest>?<filter..>")]
+pub async fn get_referrers_3level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    org: String,
+    user: String,
+    repo: String,
+    digest: String,
+    filter: ReferrersFilter,
+) -> Result<ReferrerList, Error> {
+    get_referrers(
+        auth_user,
+        ci,
+        format!("{}/{}/{}", org, user, repo),
+        digest,
+        filter,
+    )
+    .await
+}
+
+#[get("/v2/<fourth>/<org>/<user>/<repo>/referrers/<digest>?<filter..>")]
+pub async fn get_referrers_4level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    fourth: String,
+    org: String,
+    user: String,
+    repo: String,
+    digest: String,
+    filter: ReferrersFilter,
+) -> Result<ReferrerList, Error> {
+    get_referrers(
+        auth_user,
+        ci,
+        format!("{}/{}/{}/{}", fourth, org, user, repo),
+        digest,
+        filter,
+    )
+    .await
+}
+
+#[get("/v2/<fifth>/<fourth>/<org>/<user>/<repo>/referrers/<digest>?<filter..>")]
+pub async fn get_referrers_5level(
+    auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    fifth: String,
+    fourth: String,
+    org: String,
+    user: String,
+    repo: String,
+    digest: String,
+    filter: ReferrersFilter,
+) -> Result<ReferrerList, Error> {
+    get_referrers(
+        auth_user,
+        ci,
+        format!("{}/{}/{}/{}/{}", fifth, fourth, org, user, repo),
+        digest,
+        filter,
+    )
+    .await
+}
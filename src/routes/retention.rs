@@ -0,0 +1,19 @@
+use crate::registry_interface::{RegistryInterface, RetentionReport};
+use crate::response::errors::Error;
+use crate::response::trow_token::TrowToken;
+use rocket::get;
+
+/*
+ * Dry run of the retention rules, listing what would be deleted.
+ *
+ * Start a "retention" job to actually delete them.
+ */
+#[get("/trow/v1/retention")]
+pub async fn dry_run_retention(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+) -> Result<RetentionReport, Error> {
+    ci.dry_run_retention()
+        .await
+        .map_err(|_| Error::InternalError)
+}
@@ -0,0 +1,39 @@
+use crate::response::errors::Error;
+use crate::response::trow_token::TrowToken;
+use crate::types::{SetupRequest, SetupStatus};
+use crate::TrowConfig;
+use log::warn;
+use rocket::serde::json::Json;
+use rocket::{get, post};
+
+/*
+ * First run setup, see setup.rs.
+ *
+ * GET /trow/v1/setup shows whether setup is complete and what to do next
+ * POST /trow/v1/setup with {"user": ..., "password": ...} creates the first admin, as the
+ * bootstrap admin
+ */
+
+#[get("/trow/v1/setup")]
+pub fn get_setup(
+    _auth_user: TrowToken,
+    tc: &rocket::State<TrowConfig>,
+) -> Result<SetupStatus, Error> {
+    let setup = tc.setup.as_ref().ok_or(Error::SetupUnavailable)?;
+    Ok(setup.status())
+}
+
+#[post("/trow/v1/setup", data = "<req>")]
+pub fn complete_setup(
+    auth_user: TrowToken,
+    tc: &rocket::State<TrowConfig>,
+    req: Json<SetupRequest>,
+) -> Result<SetupStatus, Error> {
+    let setup = tc.setup.as_ref().ok_or(Error::SetupUnavailable)?;
+    setup
+        .complete(&auth_user.user, &req.user, &req.password)
+        .map_err(|e| {
+            warn!("Setup by {} failed: {}", auth_user.user, e);
+            Error::SetupDenied(e.to_string())
+        })
+}
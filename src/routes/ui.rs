@@ -0,0 +1,21 @@
+use crate::response::html::HTML;
+use crate::TrowConfig;
+use rocket::get;
+
+/*
+ * A minimal web UI for browsing repositories, tags and images, turned on with --web-ui.
+ *
+ * The page is embedded in the binary and does everything from the browser, with the admin and
+ * registry APIs, so it shows what the logged in user can see. It's a 404 when turned off.
+ */
+
+const UI_PAGE: &str = include_str!("../ui.html");
+
+#[get("/ui")]
+pub fn get_ui(tc: &rocket::State<TrowConfig>) -> Option<HTML<'static>> {
+    if tc.web_ui {
+        Some(HTML(UI_PAGE))
+    } else {
+        None
+    }
+}
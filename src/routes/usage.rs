@@ -0,0 +1,23 @@
+use crate::registry_interface::{RegistryInterface, UsageReport};
+use crate::response::errors::Error;
+use crate::response::trow_token::TrowToken;
+use rocket::get;
+
+// Default for how long an image must not have been running to be reported
+const DEFAULT_UNUSED_DAYS: u64 = 30;
+
+/*
+ * Images that haven't been seen running in the cluster for the given number of days.
+ *
+ * Only as up to date as the last "usage" job.
+ */
+#[get("/trow/v1/usage?<days>")]
+pub async fn usage_report(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    days: Option<u64>,
+) -> Result<UsageReport, Error> {
+    ci.usage_report(days.unwrap_or(DEFAULT_UNUSED_DAYS))
+        .await
+        .map_err(|_| Error::InternalError)
+}
@@ -1,36 +1,35 @@
-use crate::client_interface::ClientInterface;
-use crate::registry_interface::validation::{self, Validation};
+use crate::audit::{self, AuditAction, AuditRecord};
+use crate::registry_interface::validation::{self, AdmissionReview};
+use crate::registry_interface::{PolicyDecision, PolicyRequest, RegistryInterface};
+use crate::response::errors::Error;
+use crate::response::trow_token::TrowToken;
 
-use crate::types::AdmissionReview;
 use crate::TrowConfig;
 use rocket::post;
 use rocket::serde::json::Json;
+use std::net::IpAddr;
 
 //Kubernetes webhooks for admitting images
 //Update to use rocket_contrib::Json
 //Just using String for debugging
 #[post("/validate-image", data = "<image_data>")]
 pub async fn validate_image(
-    ci: &rocket::State<ClientInterface>,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
     tc: &rocket::State<TrowConfig>,
+    client_ip: Option<IpAddr>,
     image_data: Json<AdmissionReview>,
 ) -> Json<AdmissionReview> {
     /*
      * The return type is a little complicated. Always return a 200 including for disallowed images. The JSON is an
-     * AdmissionReview object with an AdmissionResponse entry. The object sent to this endpoint can be reused, or
-     * a new created with the same UID.
+     * AdmissionReview object of the same version, with an AdmissionResponse entry for the request's UID.
      *
      * The docs on this stuff is a bit lacking, it's easiest to refer to the Go code in kubernetes/api.
      */
-    let mut resp_data = image_data.clone();
-    match image_data.0.request {
-        Some(req) => match ci.validate_admission(&req, &tc.host_names).await {
-            Ok(res) => {
-                resp_data.response = Some(res);
-                resp_data
-            }
-            Err(e) => {
-                resp_data.response = Some(validation::AdmissionResponse {
+    match &image_data.request {
+        Some(req) => {
+            let res = match ci.validate_admission(req, &tc.host_names).await {
+                Ok(res) => res,
+                Err(e) => validation::AdmissionResponse {
                     uid: req.uid.clone(),
                     allowed: false,
                     status: Some(validation::Status {
@@ -38,23 +37,54 @@ pub async fn validate_image(
                         message: Some(format!("Internal Error {:?}", e)),
                         code: None,
                     }),
-                });
-                resp_data
-            }
-        },
+                    audit_annotations: None,
+                },
+            };
 
-        None => {
-            resp_data.response = Some(validation::AdmissionResponse {
-                uid: "UNKNOWN".to_string(),
-                allowed: false,
-                status: Some(validation::Status {
-                    status: "Failure".to_owned(),
-                    message: Some("No request found in review object".to_owned()),
-                    code: None,
-                }),
-            });
+            // The webhook is called by the Kubernetes API server, which doesn't say who for
+            let images = req.images();
+            let reason = match res.break_glass() {
+                Some(r) => Some(format!("Break-glass override of change freeze: {}", r)),
+                None => res.status.as_ref().and_then(|s| s.message.clone()),
+            };
+            audit::record(
+                AuditRecord::new(AuditAction::Admit, "kubernetes", client_ip).admission(
+                    &req.namespace,
+                    images,
+                    res.allowed,
+                    reason,
+                ),
+            );
 
-            resp_data
+            Json(image_data.respond(res))
         }
+
+        None => Json(image_data.respond(validation::AdmissionResponse {
+            uid: "UNKNOWN".to_string(),
+            allowed: false,
+            status: Some(validation::Status {
+                status: "Failure".to_owned(),
+                message: Some("No request found in review object".to_owned()),
+                code: None,
+            }),
+            audit_annotations: None,
+        })),
     }
 }
+
+/*
+ * Tries out the admission policy: what the webhook would decide if a pod with the image was
+ * created in the namespace, and which rules decided it. Nothing is recorded as admitted.
+ */
+#[post("/trow/v1/policy/evaluate", data = "<policy_req>")]
+pub async fn evaluate_policy(
+    _auth_user: TrowToken,
+    ci: &rocket::State<Box<dyn RegistryInterface>>,
+    tc: &rocket::State<TrowConfig>,
+    policy_req: Json<PolicyRequest>,
+) -> Result<Json<PolicyDecision>, Error> {
+    ci.evaluate_policy(&policy_req, &tc.host_names)
+        .await
+        .map(Json)
+        .map_err(|_| Error::InternalError)
+}
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Mutex;
+
+use log::{debug, warn};
+use rocket::request::{self, FromRequest, Request};
+use rocket::tokio::io::AsyncReadExt;
+use serde_json::Value;
+
+use crate::registry_interface::{
+    digest, DigestAlgorithm, ManifestReader, RegistryInterface, StorageDriverError,
+};
+
+/*
+ * Docker schema2 renditions of OCI manifests, for clients too old to understand OCI media types.
+ *
+ * A client that doesn't accept the OCI media type of a manifest but does accept the Docker
+ * equivalent gets a copy with the media types rewritten, generated when it's pulled. Layers and
+ * configs are the same blobs either way, so only the manifest changes. Indexes become manifest
+ * lists, with each OCI image manifest in them converted too. Manifests using anything without a
+ * Docker equivalent, such as zstd layers or artifact configs, are served unchanged.
+ *
+ * A rendition has a different digest from the original, so the digests handed out are remembered
+ * in order to serve them when the client pulls by digest, as it does for the entries of a manifest
+ * list. They're only kept in memory and on this replica.
+ */
+
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+const DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+
+// Dropped when the map gets this big, the same as the manifest cache
+const MAX_RENDITIONS: usize = 10_000;
+
+fn docker_media_type(oci: &str) -> Option<&'static str> {
+    match oci {
+        OCI_MANIFEST => Some(DOCKER_MANIFEST),
+        OCI_INDEX => Some(DOCKER_LIST),
+        "application/vnd.oci.image.config.v1+json" => {
+            Some("application/vnd.docker.container.image.v1+json")
+        }
+        "application/vnd.oci.image.layer.v1.tar+gzip" => {
+            Some("application/vnd.docker.image.rootfs.diff.tar.gzip")
+        }
+        "application/vnd.oci.image.layer.v1.tar" => {
+            Some("application/vnd.docker.image.rootfs.diff.tar")
+        }
+        "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip" => {
+            Some("application/vnd.docker.image.rootfs.foreign.diff.tar.gzip")
+        }
+        _ => None,
+    }
+}
+
+/**
+ * The manifest media types the client accepts, from all its Accept headers. Never fails.
+ */
+pub struct ManifestAccept(Vec<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ManifestAccept {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        // Docker sends each type in a separate header
+        let types = req
+            .headers()
+            .get("Accept")
+            .flat_map(|h| h.split(','))
+            .filter_map(|t| t.split(';').next())
+            .map(|t| t.trim().to_ascii_lowercase())
+            .filter(|t| !t.is_empty())
+            .collect();
+        request::Outcome::Success(ManifestAccept(types))
+    }
+}
+
+impl ManifestAccept {
+    fn accepts(&self, media_type: &str) -> bool {
+        self.0
+            .iter()
+            .any(|t| t == media_type || t == "*/*" || t == "application/*")
+    }
+
+    /// Whether the client needs the Docker rendition of a manifest of this type
+    pub fn wants_schema2(&self, content_type: &str) -> bool {
+        let docker = match content_type {
+            OCI_MANIFEST => DOCKER_MANIFEST,
+            OCI_INDEX => DOCKER_LIST,
+            _ => return false,
+        };
+        !self.accepts(content_type) && self.accepts(docker)
+    }
+}
+
+fn convert_descriptor(desc: &mut Value) -> Option<()> {
+    let media_type = docker_media_type(desc.get("mediaType")?.as_str()?)?;
+    let desc = desc.as_object_mut()?;
+    desc.insert("mediaType".to_string(), media_type.into());
+    desc.remove("annotations");
+    Some(())
+}
+
+/**
+ * The Docker rendition of an OCI image manifest, or None if anything in it has no Docker
+ * equivalent.
+ */
+pub fn convert_manifest(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut manifest: Value = serde_json::from_slice(bytes).ok()?;
+    convert_descriptor(manifest.get_mut("config")?)?;
+    for layer in manifest.get_mut("layers")?.as_array_mut()? {
+        convert_descriptor(layer)?;
+    }
+    let fields = manifest.as_object_mut()?;
+    fields.insert("mediaType".to_string(), DOCKER_MANIFEST.into());
+    for field in ["annotations", "artifactType", "subject"] {
+        fields.remove(field);
+    }
+    serde_json::to_vec_pretty(&manifest).ok()
+}
+
+async fn read_manifest(mr: ManifestReader) -> Result<(String, Vec<u8>), StorageDriverError> {
+    let content_type = mr.content_type().to_string();
+    let mut bytes = vec![];
+    mr.get_reader()
+        .read_to_end(&mut bytes)
+        .await
+        .map_err(|_| StorageDriverError::Internal)?;
+    Ok((content_type, bytes))
+}
+
+fn reader(content_type: &str, bytes: Vec<u8>) -> Result<ManifestReader, StorageDriverError> {
+    let digest = digest::hash_tag(&DigestAlgorithm::Sha256, bytes.as_slice())
+        .ok()
+        .and_then(|d| digest::parse(&d).ok())
+        .ok_or(StorageDriverError::Internal)?;
+    Ok(ManifestReader {
+        content_type: content_type.to_string(),
+        digest,
+        reader: Box::pin(Cursor::new(bytes)),
+    })
+}
+
+// Repository name and digest of the rendition
+type Key = (String, String);
+
+#[derive(Default)]
+pub struct Renditions {
+    // Digest of the original manifest for each rendition handed out
+    originals: Mutex<HashMap<Key, String>>,
+}
+
+impl Renditions {
+    pub fn new() -> Renditions {
+        Renditions::default()
+    }
+
+    /// The digest of the manifest the reference is a rendition of, if it is one
+    pub fn original(&self, repo_name: &str, reference: &str) -> Option<String> {
+        self.originals
+            .lock()
+            .unwrap()
+            .get(&(repo_name.to_string(), reference.to_string()))
+            .cloned()
+    }
+
+    fn remember(&self, repo_name: &str, rendition: &str, original: &str) {
+        let mut originals = self.originals.lock().unwrap();
+        if originals.len() >= MAX_RENDITIONS {
+            originals.clear();
+        }
+        originals.insert(
+            (repo_name.to_string(), rendition.to_string()),
+            original.to_string(),
+        );
+    }
+
+    /*
+     * Converts an index into a manifest list, converting the image manifests it refers to as
+     * well. Manifests that can't be converted are left as they are, as they may be for platforms
+     * the client doesn't use.
+     */
+    async fn convert_index(
+        &self,
+        ci: &dyn RegistryInterface,
+        repo_name: &str,
+        bytes: &[u8],
+    ) -> Option<Vec<u8>> {
+        let mut index: Value = serde_json::from_slice(bytes).ok()?;
+        for entry in index.get_mut("manifests")?.as_array_mut()? {
+            if entry.get("mediaType").and_then(Value::as_str) != Some(OCI_MANIFEST) {
+                continue;
+            }
+            let original = entry.get("digest")?.as_str()?.to_string();
+            let child = match ci.get_manifest(repo_name, &original).await {
+                Ok(mr) => read_manifest(mr).await.ok()?.1,
+                Err(e) => {
+                    warn!(
+                        "Failed to read {}@{} to convert: {}",
+                        repo_name, original, e
+                    );
+                    continue;
+                }
+            };
+            let converted = match convert_manifest(&child) {
+                Some(c) => c,
+                None => continue,
+            };
+            let size = converted.len() as u64;
+            let digest = reader(DOCKER_MANIFEST, converted)
+                .ok()?
+                .digest()
+                .to_string();
+            self.remember(repo_name, &digest, &original);
+
+            let entry = entry.as_object_mut()?;
+            entry.insert("mediaType".to_string(), DOCKER_MANIFEST.into());
+            entry.insert("digest".to_string(), digest.into());
+            entry.insert("size".to_string(), size.into());
+            entry.remove("annotations");
+        }
+        let fields = index.as_object_mut()?;
+        fields.insert("mediaType".to_string(), DOCKER_LIST.into());
+        for field in ["annotations", "artifactType", "subject"] {
+            fields.remove(field);
+        }
+        serde_json::to_vec_pretty(&index).ok()
+    }
+
+    /*
+     * The Docker rendition of the manifest, or the manifest unchanged if it can't be converted.
+     * The rendition's digest is remembered so it can be pulled by digest.
+     */
+    pub async fn convert(
+        &self,
+        ci: &dyn RegistryInterface,
+        repo_name: &str,
+        mr: ManifestReader,
+    ) -> Result<ManifestReader, StorageDriverError> {
+        let original = mr.digest().to_string();
+        let (content_type, bytes) = read_manifest(mr).await?;
+        let converted = match content_type.as_str() {
+            OCI_MANIFEST => convert_manifest(&bytes),
+            OCI_INDEX => self.convert_index(ci, repo_name, &bytes).await,
+            _ => None,
+        };
+        match converted {
+            Some(c) => {
+                let mr = reader(docker_media_type(&content_type).unwrap_or_default(), c)?;
+                self.remember(repo_name, &mr.digest().to_string(), &original);
+                debug!("Serving {} as {} for {}", original, mr.digest(), repo_name);
+                Ok(mr)
+            }
+            None => {
+                debug!("Can't convert {}@{} to schema2", repo_name, original);
+                reader(&content_type, bytes)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{convert_manifest, ManifestAccept};
+    use serde_json::Value;
+
+    fn accept(types: &[&str]) -> ManifestAccept {
+        ManifestAccept(types.iter().map(|t| t.to_string()).collect())
+    }
+
+    #[test]
+    fn old_clients_want_schema2() {
+        let docker = accept(&[
+            "application/vnd.docker.distribution.manifest.v2+json",
+            "application/vnd.docker.distribution.manifest.list.v2+json",
+            "application/vnd.docker.distribution.manifest.v1+prettyjws",
+        ]);
+        assert!(docker.wants_schema2("application/vnd.oci.image.manifest.v1+json"));
+        assert!(docker.wants_schema2("application/vnd.oci.image.index.v1+json"));
+        assert!(!docker.wants_schema2("application/vnd.docker.distribution.manifest.v2+json"));
+
+        let current = accept(&[
+            "application/vnd.docker.distribution.manifest.v2+json",
+            "application/vnd.oci.image.manifest.v1+json",
+        ]);
+        assert!(!current.wants_schema2("application/vnd.oci.image.manifest.v1+json"));
+        assert!(!accept(&["*/*"]).wants_schema2("application/vnd.oci.image.manifest.v1+json"));
+        // No Accept header, so nothing to go on
+        assert!(!accept(&[]).wants_schema2("application/vnd.oci.image.manifest.v1+json"));
+    }
+
+    #[test]
+    fn converts_media_types() {
+        let oci = br#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": { "mediaType": "application/vnd.oci.image.config.v1+json", "size": 1, "digest": "sha256:config" },
+            "layers": [ { "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "size": 2, "digest": "sha256:layer", "annotations": { "a": "b" } } ],
+            "annotations": { "org.opencontainers.image.created": "2022-01-01T00:00:00Z" }
+        }"#;
+        let converted: Value = serde_json::from_slice(&convert_manifest(oci).unwrap()).unwrap();
+        assert_eq!(
+            converted["mediaType"],
+            "application/vnd.docker.distribution.manifest.v2+json"
+        );
+        assert_eq!(
+            converted["config"]["mediaType"],
+            "application/vnd.docker.container.image.v1+json"
+        );
+        assert_eq!(converted["config"]["digest"], "sha256:config");
+        assert_eq!(
+            converted["layers"][0]["mediaType"],
+            "application/vnd.docker.image.rootfs.diff.tar.gzip"
+        );
+        assert_eq!(converted["layers"][0]["size"], 2);
+        assert!(converted.get("annotations").is_none());
+        assert!(converted["layers"][0].get("annotations").is_none());
+
+        let zstd = br#"{
+            "schemaVersion": 2,
+            "config": { "mediaType": "application/vnd.oci.image.config.v1+json", "size": 1, "digest": "sha256:config" },
+            "layers": [ { "mediaType": "application/vnd.oci.image.layer.v1.tar+zstd", "size": 2, "digest": "sha256:layer" } ]
+        }"#;
+        assert!(convert_manifest(zstd).is_none());
+    }
+}
@@ -0,0 +1,267 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::info;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::htpasswd;
+use crate::kube::InCluster;
+use crate::types::SetupStatus;
+
+/*
+ * First run setup, so Trow can be started securely without working out users and passwords
+ * beforehand.
+ *
+ * If the htpasswd file has no users when Trow first starts, a bootstrap "admin" user is added to
+ * it with a random password. The password is printed once, or written to a Kubernetes Secret in
+ * Trow's namespace, and never stored in plain text by Trow.
+ *
+ * The bootstrap admin can do anything, and is meant to be replaced through the setup API:
+ *
+ * GET /trow/v1/setup shows whether setup is complete and what to do next
+ * POST /trow/v1/setup with {"user": ..., "password": ...} creates the first real admin and
+ * removes the bootstrap admin
+ *
+ * Setup is recorded as complete in setup.json in the data dir, along with the admins it created,
+ * so the bootstrap admin is never made again.
+ */
+
+pub const BOOTSTRAP_USER: &str = "admin";
+static SETUP_FILE: &str = "setup.json";
+const PASSWORD_LEN: usize = 32;
+const MIN_PASSWORD_LEN: usize = 12;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct SetupState {
+    completed: Option<DateTime<Utc>>,
+    // Admins created through the setup API, who can delete
+    admins: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Setup {
+    state_path: PathBuf,
+    htpasswd_path: String,
+    state: Mutex<SetupState>,
+}
+
+fn load_state(path: &Path) -> Result<SetupState> {
+    match fs::read(path) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(SetupState::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn has_users(htpasswd_path: &str) -> bool {
+    fs::read_to_string(htpasswd_path)
+        .map(|c| {
+            c.lines()
+                .any(|l| !l.trim().is_empty() && !l.trim().starts_with('#'))
+        })
+        .unwrap_or(false)
+}
+
+fn generate_password() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(PASSWORD_LEN)
+        .map(char::from)
+        .collect()
+}
+
+/*
+ * Saves the bootstrap credentials to a Secret in Trow's namespace, using the pod's service
+ * account, which needs permission to create and update Secrets.
+ */
+fn write_secret(name: &str, user: &str, pass: &str) -> Result<()> {
+    let cluster = InCluster::new()?;
+    let token = cluster.token()?;
+    let client = reqwest::blocking::Client::builder()
+        .add_root_certificate(cluster.ca()?)
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    let secrets = format!(
+        "{}/api/v1/namespaces/{}/secrets",
+        cluster.base_url, cluster.namespace
+    );
+    let secret = json!({
+        "apiVersion": "v1",
+        "kind": "Secret",
+        "metadata": {"name": name},
+        "type": "kubernetes.io/basic-auth",
+        "stringData": {"username": user, "password": pass},
+    });
+    let resp = client
+        .post(&secrets)
+        .bearer_auth(&token)
+        .json(&secret)
+        .send()?;
+    // Left over from an earlier install, so replace it
+    if resp.status() == reqwest::StatusCode::CONFLICT {
+        client
+            .put(&format!("{}/{}", secrets, name))
+            .bearer_auth(&token)
+            .json(&secret)
+            .send()?
+            .error_for_status()?;
+    } else {
+        resp.error_for_status()?;
+    }
+    Ok(())
+}
+
+impl Setup {
+    /*
+     * Loads the setup state, creating the bootstrap admin if setup hasn't been done and there
+     * are no users. The password goes to the named Secret if given, otherwise it's printed.
+     */
+    pub fn bootstrap(data_dir: &str, htpasswd_path: &str, secret: Option<&str>) -> Result<Setup> {
+        let state_path = Path::new(data_dir).join(SETUP_FILE);
+        let state = load_state(&state_path)?;
+
+        if state.completed.is_none() && !has_users(htpasswd_path) {
+            let pass = generate_password();
+            htpasswd::add_user(htpasswd_path, BOOTSTRAP_USER, &pass)?;
+            match secret {
+                Some(name) => {
+                    write_secret(name, BOOTSTRAP_USER, &pass)?;
+                    println!(
+                        "Created bootstrap user {}, with the password in Secret {}\n",
+                        BOOTSTRAP_USER, name
+                    );
+                }
+                None => {
+                    println!("**********************************************************");
+                    println!("Created bootstrap user {}", BOOTSTRAP_USER);
+                    println!("Password: {}", pass);
+                    println!("This is the only time the password is shown. Finish setup");
+                    println!("with POST /trow/v1/setup to replace this user.");
+                    println!("**********************************************************\n");
+                }
+            }
+        }
+
+        Ok(Setup {
+            state_path,
+            htpasswd_path: htpasswd_path.to_string(),
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.state.lock().unwrap().completed.is_some()
+    }
+
+    /// Whether the user can do anything, as the bootstrap admin or an admin made during setup
+    pub fn is_admin(&self, user: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.completed {
+            None => user == BOOTSTRAP_USER,
+            Some(_) => state.admins.iter().any(|a| a == user),
+        }
+    }
+
+    pub fn status(&self) -> SetupStatus {
+        let state = self.state.lock().unwrap();
+        let next = match state.completed {
+            None => Some(format!(
+                "Log in as {} and POST {{\"user\": ..., \"password\": ...}} to /trow/v1/setup \
+                 to create an admin and remove the bootstrap user",
+                BOOTSTRAP_USER
+            )),
+            Some(_) => None,
+        };
+        SetupStatus {
+            complete: state.completed.is_some(),
+            completed: state.completed,
+            admins: state.admins.clone(),
+            next,
+        }
+    }
+
+    /*
+     * Creates the first admin and removes the bootstrap admin. Only the bootstrap admin can do
+     * this, once.
+     */
+    pub fn complete(&self, caller: &str, user: &str, pass: &str) -> Result<SetupStatus> {
+        let mut state = self.state.lock().unwrap();
+        if state.completed.is_some() {
+            return Err(anyhow!("Setup has already been completed"));
+        }
+        if caller != BOOTSTRAP_USER {
+            return Err(anyhow!("Only {} can complete setup", BOOTSTRAP_USER));
+        }
+        if user == BOOTSTRAP_USER {
+            return Err(anyhow!("Choose a user name other than {}", BOOTSTRAP_USER));
+        }
+        if pass.len() < MIN_PASSWORD_LEN {
+            return Err(anyhow!(
+                "Passwords must be at least {} characters",
+                MIN_PASSWORD_LEN
+            ));
+        }
+
+        htpasswd::add_user(&self.htpasswd_path, user, pass)?;
+        let mut new_state = state.clone();
+        new_state.completed = Some(Utc::now());
+        new_state.admins.push(user.to_string());
+        fs::write(&self.state_path, serde_json::to_vec_pretty(&new_state)?)?;
+        *state = new_state;
+        htpasswd::remove_user(&self.htpasswd_path, BOOTSTRAP_USER)?;
+        info!("Setup completed, {} is now an admin", user);
+        drop(state);
+        Ok(self.status())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Setup, BOOTSTRAP_USER};
+    use crate::htpasswd::Htpasswd;
+    use std::fs;
+
+    #[test]
+    fn bootstrap_and_complete() {
+        let dir = std::env::temp_dir().join(format!("trow-setup-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let data_dir = dir.to_str().unwrap();
+        let htpasswd_path = dir.join("users.htpasswd");
+        let htpasswd_path = htpasswd_path.to_str().unwrap();
+
+        let setup = Setup::bootstrap(data_dir, htpasswd_path, None).unwrap();
+        assert!(!setup.is_complete());
+        assert!(setup.is_admin(BOOTSTRAP_USER));
+        assert!(setup.status().next.is_some());
+        let htpasswd = Htpasswd::load(htpasswd_path).unwrap();
+        assert!(!htpasswd.verify(BOOTSTRAP_USER, ""));
+
+        assert!(setup
+            .complete("alice", "alice", "long enough password")
+            .is_err());
+        assert!(setup.complete(BOOTSTRAP_USER, "alice", "short").is_err());
+        let status = setup
+            .complete(BOOTSTRAP_USER, "alice", "long enough password")
+            .unwrap();
+        assert!(status.complete);
+        assert_eq!(status.admins, vec!["alice".to_string()]);
+        assert!(!setup.is_admin(BOOTSTRAP_USER));
+        assert!(setup.is_admin("alice"));
+        assert!(htpasswd.verify("alice", "long enough password"));
+        assert!(setup
+            .complete(BOOTSTRAP_USER, "bob", "long enough password")
+            .is_err());
+
+        // Completed setup is remembered, so the bootstrap admin isn't made again
+        let setup = Setup::bootstrap(data_dir, htpasswd_path, None).unwrap();
+        assert!(setup.is_complete());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
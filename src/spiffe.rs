@@ -0,0 +1,181 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use rocket::http::Method;
+use rocket::mtls::x509::{parse_x509_certificate, GeneralName, ParsedExtension};
+use rocket::request::Request;
+use trow_server::spiffe::IdPattern;
+
+/*
+ * Authorising registry clients by SPIFFE ID.
+ *
+ * Clients present an X.509 SVID as a TLS client certificate, which the HTTPS proxy checks against
+ * the trust bundle (see tls.rs). The SPIFFE ID is then matched against the rules in order, the first match
+ * deciding what the client can do.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Pull,
+    // Includes pull, but not deleting
+    Push,
+    // Includes push and pull
+    Delete,
+}
+
+impl Permission {
+    pub fn allows(&self, method: Method) -> bool {
+        match self {
+            Permission::Pull => matches!(method, Method::Get | Method::Head),
+            Permission::Push => method != Method::Delete,
+            Permission::Delete => true,
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pull" => Ok(Permission::Pull),
+            "push" => Ok(Permission::Push),
+            "delete" => Ok(Permission::Delete),
+            _ => Err(anyhow!(
+                "Unknown permission {}, expected pull, push or delete",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let permission = match self {
+            Permission::Pull => "pull",
+            Permission::Push => "push",
+            Permission::Delete => "delete",
+        };
+        write!(f, "{}", permission)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpiffeRule {
+    pattern: IdPattern,
+    permission: Permission,
+}
+
+// e.g. spiffe://example.org/ns/ci/*=push
+impl FromStr for SpiffeRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (pattern, permission) = s.rsplit_once('=').ok_or_else(|| {
+            anyhow!(
+                "SPIFFE rule {} should be of the form ID=pull|push|delete",
+                s
+            )
+        })?;
+        let permission = permission
+            .parse()
+            .map_err(|e| anyhow!("{} in SPIFFE rule {}", e, s))?;
+        Ok(SpiffeRule {
+            pattern: pattern.parse()?,
+            permission,
+        })
+    }
+}
+
+impl fmt::Display for SpiffeRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.pattern, self.permission)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SpiffeConfig {
+    // PEM file of CAs trusted to issue SVIDs
+    pub bundle: String,
+    pub rules: Vec<SpiffeRule>,
+    // Trow's own SVID certificate and key, used between the frontend and backend
+    pub svid: Option<(String, String)>,
+}
+
+impl SpiffeConfig {
+    pub fn permission(&self, id: &str) -> Option<Permission> {
+        self.rules
+            .iter()
+            .find(|r| r.pattern.matches(id))
+            .map(|r| r.permission)
+    }
+}
+
+/// The SPIFFE ID of the client certificate, if the client sent a valid SVID
+pub async fn client_id(req: &Request<'_>) -> Option<String> {
+    let der = crate::tls::client_cert(req)?;
+    let (_, cert) = parse_x509_certificate(&der).ok()?;
+    cert.extensions()
+        .iter()
+        .filter_map(|ext| match ext.parsed_extension() {
+            ParsedExtension::SubjectAlternativeName(san) => Some(san),
+            _ => None,
+        })
+        .flat_map(|san| san.general_names.iter())
+        .find_map(|name| match name {
+            GeneralName::URI(uri) if uri.starts_with("spiffe://") => Some(uri.to_string()),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Permission, SpiffeConfig, SpiffeRule};
+    use rocket::http::Method;
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules: Vec<SpiffeRule> = [
+            "spiffe://example.org/ns/ci/sa/cleaner=delete",
+            "spiffe://example.org/ns/ci/sa/deployer=push",
+            "spiffe://example.org/ns/ci/*=pull",
+        ]
+        .iter()
+        .map(|r| r.parse().unwrap())
+        .collect();
+        let config = SpiffeConfig {
+            bundle: "bundle.pem".to_string(),
+            rules,
+            svid: None,
+        };
+
+        assert_eq!(
+            config.permission("spiffe://example.org/ns/ci/sa/deployer"),
+            Some(Permission::Push)
+        );
+        assert_eq!(
+            config.permission("spiffe://example.org/ns/ci/sa/cleaner"),
+            Some(Permission::Delete)
+        );
+        assert_eq!(
+            config.permission("spiffe://example.org/ns/ci/sa/builder"),
+            Some(Permission::Pull)
+        );
+        assert_eq!(
+            config.permission("spiffe://example.org/ns/prod/sa/app"),
+            None
+        );
+
+        assert!(Permission::Pull.allows(Method::Head));
+        assert!(!Permission::Pull.allows(Method::Put));
+        assert!(Permission::Push.allows(Method::Put));
+        assert!(!Permission::Push.allows(Method::Delete));
+        assert!(Permission::Delete.allows(Method::Delete));
+
+        assert!("spiffe://example.org/ns/ci=admin"
+            .parse::<SpiffeRule>()
+            .is_err());
+        assert!("spiffe://example.org/ns/ci".parse::<SpiffeRule>().is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::{anyhow, Result};
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::trace::{FutureExt, SpanKind, StatusCode, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use rocket::data::Data;
+use rocket::http::HeaderMap;
+use rocket::outcome::Outcome as RocketOutcome;
+use rocket::request::Request;
+use rocket::route::{Handler, Outcome, Route};
+use tonic::codegen::{http, Service};
+use trow_server::request_id;
+use trow_server::telemetry::{record_grpc_response, HeaderInjector, TRACER_NAME};
+
+/*
+ * Distributed tracing with OpenTelemetry. Each HTTP route and each call to the backend gets a
+ * span, and the backend continues the trace (see trow_server::telemetry), so a slow push shows
+ * where the time went.
+ *
+ * Spans are exported to an OTLP collector or a Jaeger agent. Clients can send a W3C traceparent
+ * header to make Trow's spans part of their own trace.
+ */
+
+const SERVICE_NAME: &str = "trow";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceExporter {
+    // gRPC to an OpenTelemetry collector
+    Otlp,
+    // UDP to a Jaeger agent
+    Jaeger,
+}
+
+impl FromStr for TraceExporter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "otlp" => Ok(TraceExporter::Otlp),
+            "jaeger" => Ok(TraceExporter::Jaeger),
+            _ => Err(anyhow!(
+                "Unknown trace exporter {}, expected otlp or jaeger",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TraceExporter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceExporter::Otlp => write!(f, "otlp"),
+            TraceExporter::Jaeger => write!(f, "jaeger"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TracingConfig {
+    pub exporter: TraceExporter,
+    pub endpoint: String,
+}
+
+impl TracingConfig {
+    pub fn new(exporter: &str, endpoint: Option<String>) -> Result<TracingConfig> {
+        let exporter: TraceExporter = exporter.parse()?;
+        let endpoint = endpoint.unwrap_or_else(|| match exporter {
+            TraceExporter::Otlp => "http://localhost:4317".to_string(),
+            TraceExporter::Jaeger => "localhost:6831".to_string(),
+        });
+        Ok(TracingConfig { exporter, endpoint })
+    }
+}
+
+/*
+ * Installs the exporter as the global tracer. Must be called from within a Tokio runtime, which
+ * runs the batch exporter.
+ */
+pub fn init(config: &TracingConfig) -> Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    match config.exporter {
+        TraceExporter::Otlp => {
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(&config.endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", SERVICE_NAME),
+                ])))
+                .install_batch(opentelemetry::runtime::Tokio)?;
+        }
+        TraceExporter::Jaeger => {
+            opentelemetry_jaeger::new_pipeline()
+                .with_service_name(SERVICE_NAME)
+                .with_agent_endpoint(&config.endpoint)
+                .install_batch(opentelemetry::runtime::Tokio)?;
+        }
+    }
+    Ok(())
+}
+
+/// Flushes any spans not yet exported
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct RocketHeaderExtractor<'a, 'h>(&'a HeaderMap<'h>);
+
+impl<'a, 'h> Extractor for RocketHeaderExtractor<'a, 'h> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_one(key)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|h| h.name.as_str()).collect()
+    }
+}
+
+// Runs the route's handler in a span
+#[derive(Clone)]
+struct WithSpan {
+    name: String,
+    handler: Box<dyn Handler>,
+}
+
+#[rocket::async_trait]
+impl Handler for WithSpan {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let parent =
+            global::get_text_map_propagator(|p| p.extract(&RocketHeaderExtractor(req.headers())));
+        let tracer = global::tracer(TRACER_NAME);
+        let mut attributes = vec![
+            KeyValue::new("http.method", req.method().as_str()),
+            KeyValue::new("http.target", req.uri().to_string()),
+        ];
+        if let Some(id) = request_id::current() {
+            attributes.push(KeyValue::new("trow.request_id", id));
+        }
+        let span = tracer
+            .span_builder(self.name.clone())
+            .with_kind(SpanKind::Server)
+            .with_attributes(attributes)
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+
+        let outcome = self
+            .handler
+            .handle(req, data)
+            .with_context(cx.clone())
+            .await;
+        let span = cx.span();
+        match &outcome {
+            RocketOutcome::Success(resp) => {
+                let code = resp.status().code;
+                span.set_attribute(KeyValue::new("http.status_code", code as i64));
+                if code >= 500 {
+                    span.set_status(StatusCode::Error, resp.status().to_string());
+                }
+            }
+            RocketOutcome::Failure(status) => {
+                span.set_attribute(KeyValue::new("http.status_code", status.code as i64));
+                span.set_status(StatusCode::Error, status.to_string());
+            }
+            // Another route will handle it, which has its own span
+            RocketOutcome::Forward(_) => span.set_attribute(KeyValue::new("http.forwarded", true)),
+        }
+        span.end();
+        outcome
+    }
+}
+
+pub fn with_spans(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            let name = format!("{} {}", route.method, route.uri);
+            route.handler = Box::new(WithSpan {
+                name,
+                handler: route.handler,
+            });
+            route
+        })
+        .collect()
+}
+
+/*
+ * Wraps the channel to the backend so each call gets a client span, with the trace context
+ * passed on in the call's metadata.
+ */
+#[derive(Clone)]
+pub struct Traced<S>(pub S);
+
+impl<S, B, R> Service<http::Request<B>> for Traced<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<R>>,
+    S::Error: fmt::Display,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        let tracer = global::tracer(TRACER_NAME);
+        let span = tracer
+            .span_builder(req.uri().path().to_string())
+            .with_kind(SpanKind::Client)
+            .with_attributes(vec![KeyValue::new("rpc.system", "grpc")])
+            .start(&tracer);
+        let cx = Context::current_with_span(span);
+        global::get_text_map_propagator(|p| {
+            p.inject_context(&cx, &mut HeaderInjector(req.headers_mut()))
+        });
+
+        let fut = self.0.call(req);
+        Box::pin(async move {
+            let res = fut.await;
+            record_grpc_response(&cx.span(), &res);
+            cx.span().end();
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TraceExporter, TracingConfig};
+
+    #[test]
+    fn parse_config() {
+        let config = TracingConfig::new("OTLP", None).unwrap();
+        assert_eq!(config.exporter, TraceExporter::Otlp);
+        assert_eq!(config.endpoint, "http://localhost:4317");
+
+        let config = TracingConfig::new("jaeger", Some("jaeger:6831".to_string())).unwrap();
+        assert_eq!(config.exporter, TraceExporter::Jaeger);
+        assert_eq!(config.endpoint, "jaeger:6831");
+
+        assert!(TracingConfig::new("zipkin", None).is_err());
+    }
+}
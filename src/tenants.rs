@@ -0,0 +1,191 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use rocket::request::Request;
+
+use crate::registry_interface::{RegistryInterface, Tenant};
+use crate::response::errors::Error;
+use crate::spiffe::Permission;
+use crate::TrowConfig;
+
+/*
+ * Which tenant, if any, owns the repository a request is for, and what the caller can do there.
+ *
+ * The tenants are read from the backend and cached for a few seconds, so most requests don't
+ * need a backend call, while changes made through another frontend are soon seen. If the backend
+ * can't be reached, the tenants last read are used.
+ */
+
+const TTL: Duration = Duration::from_secs(10);
+
+// Paths where the repository is followed by one of these, e.g. /v2/<repo>/manifests/<reference>
+const V2_MARKERS: [&str; 4] = ["manifests", "blobs", "tags", "referrers"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    // Not in a tenant's namespace
+    Open,
+    // A member of the tenant, with what they can do there
+    Member(Permission),
+    Denied,
+}
+
+#[derive(Default)]
+pub struct TenantCache {
+    // When the tenants were read, None once they're known to have changed
+    tenants: Mutex<Option<(Option<Instant>, Arc<Vec<Tenant>>)>>,
+}
+
+impl TenantCache {
+    /// The tenants, or None if they've never been read and can't be now
+    pub async fn get(&self, ci: &dyn RegistryInterface) -> Option<Arc<Vec<Tenant>>> {
+        let cached = self.tenants.lock().unwrap().clone();
+        if let Some((Some(read), tenants)) = &cached {
+            if read.elapsed() < TTL {
+                return Some(tenants.clone());
+            }
+        }
+        match ci.list_tenants().await {
+            Ok(tenants) => {
+                let tenants = Arc::new(tenants);
+                *self.tenants.lock().unwrap() = Some((Some(Instant::now()), tenants.clone()));
+                Some(tenants)
+            }
+            Err(e) => {
+                warn!("Failed to read tenants, using those read before: {}", e);
+                cached.map(|(_, tenants)| tenants)
+            }
+        }
+    }
+
+    /// Makes the next get read the tenants again, after they're changed through this frontend
+    pub fn invalidate(&self) {
+        // The tenants are kept in case the backend can't be reached
+        if let Some((read, _)) = self.tenants.lock().unwrap().as_mut() {
+            *read = None;
+        }
+    }
+}
+
+/*
+ * What the user can do in the repository. Tenants' repositories are only open to their members,
+ * with the permission they're given as a member.
+ */
+pub fn access(tenants: &[Tenant], user: &str, repo_name: &str) -> Access {
+    let namespace = repo_name.split('/').next().unwrap_or_default();
+    let tenant = match tenants.iter().find(|t| t.name == namespace) {
+        Some(t) => t,
+        None => return Access::Open,
+    };
+    tenant
+        .members
+        .iter()
+        .find(|m| m.user == user)
+        .and_then(|m| m.permission.parse().ok())
+        .map(Access::Member)
+        .unwrap_or(Access::Denied)
+}
+
+// Whether the user may see the repository at all, e.g. in the catalog
+pub fn can_see(tenants: &[Tenant], user: &str, repo_name: &str) -> bool {
+    access(tenants, user, repo_name) != Access::Denied
+}
+
+// Admins can see every repository, and so can everyone if there's no authentication
+pub fn sees_everything(tc: &TrowConfig, user: &str) -> bool {
+    !tc.auth_configured() || tc.is_admin(user)
+}
+
+/*
+ * The tenants whose repositories need hiding from the user in listings. None if the user can see
+ * everything.
+ */
+pub async fn hiding_from(
+    tc: &TrowConfig,
+    cache: &TenantCache,
+    ci: &dyn RegistryInterface,
+    user: &str,
+) -> Result<Option<Arc<Vec<Tenant>>>, Error> {
+    if sees_everything(tc, user) {
+        return Ok(None);
+    }
+    match cache.get(ci).await {
+        Some(tenants) if tenants.is_empty() => Ok(None),
+        Some(tenants) => Ok(Some(tenants)),
+        None => Err(Error::InternalError),
+    }
+}
+
+/*
+ * The top-level namespace of the repository the request is for, if it's for one. The name is
+ * taken from the path of registry, chart and admin API requests, or the repo query parameter.
+ */
+pub fn request_namespace(req: &Request<'_>) -> Option<String> {
+    let repo_param = req.query_value::<&str>("repo").and_then(|r| r.ok());
+    path_namespace(&req.uri().path().to_string(), repo_param)
+}
+
+fn path_namespace(path: &str, repo_param: Option<&str>) -> Option<String> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let namespace = match segments.as_slice() {
+        ["v2", ns, rest @ ..] if rest.iter().any(|s| V2_MARKERS.contains(s)) => Some(*ns),
+        ["helm", "charts", ns, ..]
+        | ["api", "v1", "repositories", ns, ..]
+        | ["api", "v1", "storage", "repositories", ns, ..]
+        | ["trow", "v1", "push-check", ns, ..]
+        | ["trow", "v1", "quotas", ns, ..] => Some(*ns),
+        [ns, rest @ ..] if rest.contains(&"manifest_history") => Some(*ns),
+        _ => repo_param.and_then(|r| r.split('/').next()),
+    };
+    namespace.map(str::to_string)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{access, path_namespace, Access};
+    use crate::registry_interface::{Tenant, TenantMember};
+    use crate::spiffe::Permission;
+
+    #[test]
+    fn finds_namespace() {
+        let ns = |path: &str| path_namespace(path, None);
+        assert_eq!(ns("/v2/team-a/app/manifests/latest").unwrap(), "team-a");
+        assert_eq!(ns("/v2/app/blobs/uploads/1234").unwrap(), "app");
+        assert_eq!(ns("/v2/team-a/app/tags/list").unwrap(), "team-a");
+        assert_eq!(
+            ns("/helm/charts/team-a/chart/abc/c-1.0.tgz").unwrap(),
+            "team-a"
+        );
+        assert_eq!(ns("/api/v1/repositories/team-a/app").unwrap(), "team-a");
+        assert_eq!(ns("/team-a/app/manifest_history/latest").unwrap(), "team-a");
+        assert_eq!(ns("/v2/_catalog"), None);
+        assert_eq!(ns("/v2/"), None);
+        assert_eq!(ns("/api/v1/repositories"), None);
+        assert_eq!(
+            path_namespace("/api/v1/export", Some("team-a/app")).unwrap(),
+            "team-a"
+        );
+    }
+
+    #[test]
+    fn checks_membership() {
+        let tenants = vec![Tenant {
+            name: "team-a".to_string(),
+            members: vec![TenantMember {
+                user: "alice".to_string(),
+                permission: "pull".to_string(),
+            }],
+            quota: None,
+            retention: vec![],
+        }];
+        assert_eq!(
+            access(&tenants, "alice", "team-a/app"),
+            Access::Member(Permission::Pull)
+        );
+        assert_eq!(access(&tenants, "bob", "team-a/app"), Access::Denied);
+        assert_eq!(access(&tenants, "bob", "team-a"), Access::Denied);
+        assert_eq!(access(&tenants, "bob", "team-ab/app"), Access::Open);
+        assert_eq!(access(&tenants, "bob", "app"), Access::Open);
+    }
+}
@@ -0,0 +1,494 @@
+use std::convert::Infallible;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, HOST};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Client, StatusCode, Uri, Version};
+use log::{debug, info, warn};
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use rocket::fairing::AdHoc;
+use rocket::request::Request;
+use rocket::tokio::net::TcpListener;
+use rocket::Shutdown;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use uuid::Uuid;
+
+/*
+ * Serving HTTPS, and reloading the certificate without restarting anything.
+ *
+ * Rocket reads its certificate once, when it launches, so TLS is terminated here instead. Each
+ * HTTPS address is served by a proxy that passes requests on to Rocket, which listens for plain
+ * HTTP on a loopback port. The client's address is passed on in X-Real-IP, which Rocket takes as
+ * the client IP, and its certificate, if it sent one the bundle vouches for, in
+ * X-Trow-Client-Cert.
+ *
+ * The proxy gets the certificate for each handshake from a resolver. The files are checked for
+ * changes and, once the new key matches the new certificate, the resolver is updated in place, so
+ * new connections get the new certificate while open ones carry on and the listeners are never
+ * restarted. If the new files can't be used, e.g. only one of them has been replaced so far, the
+ * old certificate stays in use.
+ *
+ * The files can come from a Kubernetes TLS secret mounted as a directory, which is how
+ * cert-manager provides certificates. The secret holds tls.crt and tls.key.
+ */
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+const REAL_IP_HEADER: &str = "x-real-ip";
+const CLIENT_CERT_HEADER: &str = "x-trow-client-cert";
+// Holds the ProxySecret, so Rocket only believes the client certificate header from the proxy
+const PROXY_HEADER: &str = "x-trow-proxy";
+
+const SECRET_CERT_FILE: &str = "tls.crt";
+const SECRET_KEY_FILE: &str = "tls.key";
+
+/// The certificate and key paths in a mounted TLS secret
+pub fn secret_paths(dir: &str) -> (String, String) {
+    let dir = Path::new(dir);
+    (
+        dir.join(SECRET_CERT_FILE).to_string_lossy().to_string(),
+        dir.join(SECRET_KEY_FILE).to_string_lossy().to_string(),
+    )
+}
+
+/*
+ * Blocks until the certificate and key exist. Used with mounted secrets, which may be optional
+ * and only filled in once the certificate has been issued.
+ */
+pub fn wait_for(cert: &str, key: &str) {
+    if Path::new(cert).is_file() && Path::new(key).is_file() {
+        return;
+    }
+    println!(
+        "Waiting for TLS certificate {} and key {} to be provisioned",
+        cert, key
+    );
+    while !(Path::new(cert).is_file() && Path::new(key).is_file()) {
+        std::thread::sleep(CHECK_INTERVAL);
+    }
+}
+
+/// Checks the key is the private key for the certificate
+pub fn check_pair(cert: &Path, key: &Path) -> Result<()> {
+    let cert = X509::from_pem(&fs::read(cert)?)
+        .map_err(|e| anyhow!("Invalid certificate {}: {}", cert.display(), e))?;
+    let key = PKey::private_key_from_pem(&fs::read(key)?)
+        .map_err(|e| anyhow!("Invalid private key {}: {}", key.display(), e))?;
+    if !cert.public_key()?.public_eq(&key) {
+        return Err(anyhow!("TLS key doesn't match the certificate"));
+    }
+    Ok(())
+}
+
+fn modified(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+// Serves the certificate the reloader last loaded to each new connection
+struct Resolver(RwLock<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap().clone())
+    }
+}
+
+fn pem_certs(path: &Path) -> Result<Vec<Certificate>> {
+    X509::stack_from_pem(&fs::read(path)?)
+        .map_err(|e| anyhow!("Invalid certificate {}: {}", path.display(), e))?
+        .iter()
+        .map(|c| -> Result<Certificate> { Ok(Certificate(c.to_der()?)) })
+        .collect()
+}
+
+// The certificate chain and key, once the key is known to match
+fn load_key(cert: &Path, key: &Path) -> Result<CertifiedKey> {
+    check_pair(cert, key)?;
+    // rustls only reads EC keys as PKCS #8
+    let pem = PKey::private_key_from_pem(&fs::read(key)?)?.private_key_to_pem_pkcs8()?;
+    let der: String = String::from_utf8(pem)?
+        .lines()
+        .filter(|l| !l.starts_with("-----"))
+        .collect();
+    let signing_key = any_supported_type(&PrivateKey(base64::decode(der)?))
+        .map_err(|_| anyhow!("Unsupported private key type in {}", key.display()))?;
+    Ok(CertifiedKey::new(pem_certs(cert)?, signing_key))
+}
+
+// Trusted to issue client certificates, which are optional
+fn client_roots(bundle: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for ca in pem_certs(bundle)? {
+        roots
+            .add(&ca)
+            .map_err(|e| anyhow!("Invalid CA in {}: {:?}", bundle.display(), e))?;
+    }
+    Ok(roots)
+}
+
+#[derive(Clone)]
+pub struct CertReloader {
+    cert: PathBuf,
+    key: PathBuf,
+    // CAs for client certificates, such as the SPIFFE bundle
+    bundle: Option<PathBuf>,
+    resolver: Arc<Resolver>,
+    // Replaced when the bundle changes, the certificate comes from the resolver
+    server_config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl CertReloader {
+    pub fn new(cert: &str, key: &str, bundle: Option<&str>) -> Result<CertReloader> {
+        let cert = PathBuf::from(cert);
+        let key = PathBuf::from(key);
+        let resolver = Arc::new(Resolver(RwLock::new(Arc::new(load_key(&cert, &key)?))));
+        let bundle = bundle.map(PathBuf::from);
+        let server_config = server_config(&resolver, bundle.as_deref())?;
+        Ok(CertReloader {
+            cert,
+            key,
+            bundle,
+            resolver,
+            server_config: Arc::new(RwLock::new(Arc::new(server_config))),
+        })
+    }
+
+    fn paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.cert.clone(), self.key.clone()];
+        paths.extend(self.bundle.iter().cloned());
+        paths
+    }
+
+    // Switches to the files as they are now, or fails and keeps the old ones
+    fn reload(&self) -> Result<()> {
+        let key = load_key(&self.cert, &self.key)?;
+        let server_config = server_config(&self.resolver, self.bundle.as_deref())?;
+        *self.resolver.0.write().unwrap() = Arc::new(key);
+        *self.server_config.write().unwrap() = Arc::new(server_config);
+        Ok(())
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.server_config.read().unwrap().clone())
+    }
+
+    async fn watch(self) {
+        let paths = self.paths();
+        let mut last = modified(&paths);
+        loop {
+            rocket::tokio::time::sleep(CHECK_INTERVAL).await;
+            let current = modified(&paths);
+            if current == last {
+                continue;
+            }
+            last = current;
+            match self.reload() {
+                Ok(()) => info!("TLS certificate changed, using it for new connections"),
+                Err(e) => warn!(
+                    "TLS files changed but can't be used, keeping the old ones: {}",
+                    e
+                ),
+            }
+        }
+    }
+
+    /*
+     * Serves HTTPS on the listener, passing requests on to Rocket at the loopback port, until
+     * Rocket shuts down.
+     */
+    async fn serve(
+        self,
+        listener: TcpListener,
+        port: u16,
+        secret: Arc<String>,
+        shutdown: Shutdown,
+    ) {
+        let client = Client::new();
+        loop {
+            let (stream, remote) = rocket::tokio::select! {
+                _ = shutdown.clone() => return,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept HTTPS connection: {}", e);
+                        continue;
+                    }
+                },
+            };
+            let acceptor = self.acceptor();
+            let client = client.clone();
+            let secret = secret.clone();
+            rocket::tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        debug!("TLS handshake with {} failed: {}", remote, e);
+                        return;
+                    }
+                };
+                let client_cert = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .map(|cert| base64::encode(&cert.0));
+                let service = service_fn(move |req| {
+                    forward(
+                        client.clone(),
+                        req,
+                        port,
+                        remote.ip(),
+                        client_cert.clone(),
+                        secret.clone(),
+                    )
+                });
+                if let Err(e) = Http::new().serve_connection(stream, service).await {
+                    debug!("HTTPS connection from {} failed: {}", remote, e);
+                }
+            });
+        }
+    }
+
+    /*
+     * Serves HTTPS on the listeners for the Rocket at the loopback port, and starts watching the
+     * files, once Rocket is up.
+     */
+    pub fn fairing(&self, listeners: Vec<std::net::TcpListener>, port: u16) -> AdHoc {
+        let reloader = self.clone();
+        AdHoc::on_liftoff("HTTPS", move |rocket| {
+            let shutdown = rocket.shutdown();
+            let secret = rocket
+                .state::<ProxySecret>()
+                .map(|s| Arc::new(s.0.clone()))
+                .unwrap_or_default();
+            Box::pin(async move {
+                for listener in listeners {
+                    let listener = listener
+                        .set_nonblocking(true)
+                        .and_then(|()| TcpListener::from_std(listener));
+                    match listener {
+                        Ok(listener) => {
+                            rocket::tokio::spawn(reloader.clone().serve(
+                                listener,
+                                port,
+                                secret.clone(),
+                                shutdown.clone(),
+                            ));
+                        }
+                        Err(e) => {
+                            warn!("Failed to serve HTTPS: {}", e);
+                            shutdown.clone().notify();
+                        }
+                    }
+                }
+                rocket::tokio::spawn(reloader.watch());
+            })
+        })
+    }
+}
+
+fn server_config(resolver: &Arc<Resolver>, bundle: Option<&Path>) -> Result<ServerConfig> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let mut config = match bundle {
+        Some(bundle) => builder
+            .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(client_roots(
+                bundle,
+            )?))
+            .with_cert_resolver(resolver.clone()),
+        None => builder
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone()),
+    };
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/*
+ * Passes the request on to Rocket, saying who sent it. Headers clients send claiming to be from
+ * the proxy are removed.
+ */
+async fn forward(
+    client: Client<HttpConnector>,
+    mut req: hyper::Request<Body>,
+    port: u16,
+    client_ip: IpAddr,
+    client_cert: Option<String>,
+    secret: Arc<String>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let authority = req.uri().authority().map(|a| a.to_string());
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let uri = format!("http://{}:{}{}", Ipv4Addr::LOCALHOST, port, path).parse::<Uri>();
+    *req.uri_mut() = match uri {
+        Ok(uri) => uri,
+        Err(_) => return Ok(status(StatusCode::BAD_REQUEST)),
+    };
+    // HTTP/2 clients give the host in the URI
+    *req.version_mut() = Version::HTTP_11;
+    let headers = req.headers_mut();
+    if let Some(Ok(authority)) = authority.map(|a| HeaderValue::from_str(&a)) {
+        headers.entry(HOST).or_insert(authority);
+    }
+    headers.remove(CLIENT_CERT_HEADER);
+    headers.insert(
+        REAL_IP_HEADER,
+        HeaderValue::from_str(&client_ip.to_string()).expect("IP addresses are valid headers"),
+    );
+    if let Ok(secret) = HeaderValue::from_str(&secret) {
+        headers.insert(PROXY_HEADER, secret);
+    }
+    if let Some(Ok(cert)) = client_cert.map(|c| HeaderValue::from_str(&c)) {
+        headers.insert(CLIENT_CERT_HEADER, cert);
+    }
+
+    match client.request(req).await {
+        Ok(resp) => Ok(resp),
+        Err(e) => {
+            warn!("Failed to pass HTTPS request on: {}", e);
+            Ok(status(StatusCode::BAD_GATEWAY))
+        }
+    }
+}
+
+fn status(code: StatusCode) -> hyper::Response<Body> {
+    let mut resp = hyper::Response::new(Body::empty());
+    *resp.status_mut() = code;
+    resp
+}
+
+/// Proves requests came through the HTTPS proxy, managed by the Rocket behind it
+pub struct ProxySecret(pub String);
+
+impl Default for ProxySecret {
+    fn default() -> ProxySecret {
+        ProxySecret(Uuid::new_v4().to_string())
+    }
+}
+
+/// The DER client certificate the HTTPS proxy checked, if the client sent one
+pub fn client_cert(req: &Request<'_>) -> Option<Vec<u8>> {
+    let secret = req.rocket().state::<ProxySecret>()?;
+    if req.headers().get_one(PROXY_HEADER) != Some(secret.0.as_str()) {
+        return None;
+    }
+    base64::decode(req.headers().get_one(CLIENT_CERT_HEADER)?).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_pair, secret_paths, CertReloader};
+    use openssl::asn1::Asn1Time;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    fn cert(key: &PKey<Private>) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "trow.test").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .sign(key, openssl::hash::MessageDigest::sha256())
+            .unwrap();
+        builder.build()
+    }
+
+    fn write(dir: &Path, name: &str, pem: Vec<u8>) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, pem).unwrap();
+        path
+    }
+
+    #[test]
+    fn secret_file_names() {
+        let (cert, key) = secret_paths("/etc/trow/tls");
+        assert_eq!(cert, "/etc/trow/tls/tls.crt");
+        assert_eq!(key, "/etc/trow/tls/tls.key");
+    }
+
+    #[test]
+    fn checks_key_matches_cert() {
+        let dir = std::env::temp_dir().join(format!("trow-tls-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let key1 = key();
+        let cert_path = write(&dir, "tls.crt", cert(&key1).to_pem().unwrap());
+        let key1_path = write(&dir, "tls.key", key1.private_key_to_pem_pkcs8().unwrap());
+        let key2_path = write(&dir, "other.key", key().private_key_to_pem_pkcs8().unwrap());
+        let junk_path = write(&dir, "junk.crt", b"not a cert".to_vec());
+
+        assert!(check_pair(&cert_path, &key1_path).is_ok());
+        assert!(check_pair(&cert_path, &key2_path).is_err());
+        assert!(check_pair(&junk_path, &key1_path).is_err());
+        assert!(check_pair(&dir.join("missing.crt"), &key1_path).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reloads_cert_in_place() {
+        let dir = std::env::temp_dir().join(format!("trow-tls-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("tls.crt");
+        let key_path = dir.join("tls.key");
+        let write_pair = |key: &PKey<Private>| {
+            let cert = cert(key);
+            write(&dir, "tls.crt", cert.to_pem().unwrap());
+            write(&dir, "tls.key", key.private_key_to_pem_pkcs8().unwrap());
+            cert.to_der().unwrap()
+        };
+
+        let first = write_pair(&key());
+        let reloader = CertReloader::new(
+            cert_path.to_str().unwrap(),
+            key_path.to_str().unwrap(),
+            None,
+        )
+        .unwrap();
+        let serving = || reloader.resolver.0.read().unwrap().cert[0].0.clone();
+        assert_eq!(serving(), first);
+
+        let second = write_pair(&key());
+        reloader.reload().unwrap();
+        assert_eq!(serving(), second);
+
+        // Only the key replaced so far
+        write(&dir, "tls.key", key().private_key_to_pem_pkcs8().unwrap());
+        assert!(reloader.reload().is_err());
+        assert_eq!(serving(), second);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDate, Utc};
+use log::warn;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::request::Request;
+use rocket::response::Response;
+use rocket::{Orbit, Rocket};
+use serde::{Deserialize, Serialize};
+
+use crate::response::trow_token::TrowToken;
+
+/*
+ * Accounting of the bytes each user pushes and pulls to each namespace, for chargeback or
+ * showback of registry use.
+ *
+ * Totals are kept per day, user and namespace (the first part of the repository name, e.g. myorg
+ * for myorg/app). Pulls count the bytes of each manifest and blob served, or the part asked for
+ * with a Range header, but not HEAD requests or blobs redirected elsewhere. Pushes count each blob
+ * when its upload completes, so abandoned uploads aren't charged, and each manifest.
+ *
+ * The totals are saved to transfer.json in the data dir every minute and on shutdown, and kept for
+ * KEEP_DAYS. Replicas sharing the data dir each save to their own file, named after the host. GET /api/v1/transfer adds them up over a range of days.
+ */
+
+const KEEP_DAYS: i64 = 400;
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+const DAY_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Totals {
+    pub pushed_bytes: u64,
+    pub pulled_bytes: u64,
+    pub pushes: u64,
+    pub pulls: u64,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.pushed_bytes += other.pushed_bytes;
+        self.pulled_bytes += other.pulled_bytes;
+        self.pushes += other.pushes;
+        self.pulls += other.pulls;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Push,
+    Pull,
+}
+
+// Day as YYYY-MM-DD, user and namespace
+type Key = (String, String, String);
+
+// As saved in the file
+#[derive(Serialize, Deserialize)]
+struct SavedTotals {
+    day: String,
+    user: String,
+    namespace: String,
+    #[serde(flatten)]
+    totals: Totals,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupBy {
+    Day,
+    User,
+    Namespace,
+}
+
+impl FromStr for GroupBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<GroupBy> {
+        match s {
+            "day" => Ok(GroupBy::Day),
+            "user" => Ok(GroupBy::User),
+            "namespace" => Ok(GroupBy::Namespace),
+            _ => Err(anyhow!(
+                "Can't group by {}, expected day, user or namespace",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TransferRow {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub day: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TransferReport {
+    pub from: String,
+    pub to: String,
+    pub rows: Vec<TransferRow>,
+}
+
+pub struct TransferLedger {
+    path: PathBuf,
+    totals: Mutex<BTreeMap<Key, Totals>>,
+    // Whether there's anything to save
+    changed: AtomicBool,
+}
+
+fn file_name(shared: bool) -> String {
+    let host = std::env::var("HOSTNAME").ok().filter(|h| !h.is_empty());
+    match host {
+        Some(host) if shared => format!("transfer-{}.json", host),
+        _ => "transfer.json".to_string(),
+    }
+}
+
+pub fn today() -> String {
+    Utc::now().format(DAY_FORMAT).to_string()
+}
+
+/// Checks a day given in a request is YYYY-MM-DD
+pub fn parse_day(day: &str) -> Result<String> {
+    NaiveDate::parse_from_str(day, DAY_FORMAT)
+        .map(|d| d.format(DAY_FORMAT).to_string())
+        .map_err(|e| anyhow!("Invalid day {}, expected YYYY-MM-DD: {}", day, e))
+}
+
+impl TransferLedger {
+    /// Loads the totals saved in the data dir, if any
+    pub fn load(data_dir: &Path, shared: bool) -> TransferLedger {
+        let path = data_dir.join(file_name(shared));
+        let mut totals = BTreeMap::new();
+        if path.exists() {
+            let saved: Result<Vec<SavedTotals>> = fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(serde_json::from_slice(&bytes)?));
+            match saved {
+                Ok(saved) => {
+                    for s in saved {
+                        totals.insert((s.day, s.user, s.namespace), s.totals);
+                    }
+                }
+                Err(e) => warn!("Ignoring unreadable transfer totals in {:?}: {}", path, e),
+            }
+        }
+        TransferLedger {
+            path,
+            totals: Mutex::new(totals),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn record(&self, day: &str, user: &str, namespace: &str, direction: Direction, bytes: u64) {
+        let mut totals = self.totals.lock().unwrap();
+        let t = totals
+            .entry((day.to_string(), user.to_string(), namespace.to_string()))
+            .or_default();
+        match direction {
+            Direction::Push => {
+                t.pushes += 1;
+                t.pushed_bytes += bytes;
+            }
+            Direction::Pull => {
+                t.pulls += 1;
+                t.pulled_bytes += bytes;
+            }
+        }
+        self.changed.store(true, Ordering::SeqCst);
+    }
+
+    /// Adds up the totals for the days from and to, inclusive, grouped by the given fields
+    pub fn report(&self, from: &str, to: &str, by: &[GroupBy]) -> TransferReport {
+        let mut rows: BTreeMap<(Option<String>, Option<String>, Option<String>), Totals> =
+            BTreeMap::new();
+        for ((day, user, namespace), totals) in self.totals.lock().unwrap().iter() {
+            if day.as_str() < from || day.as_str() > to {
+                continue;
+            }
+            let pick = |g: GroupBy, v: &String| by.contains(&g).then(|| v.clone());
+            rows.entry((
+                pick(GroupBy::Day, day),
+                pick(GroupBy::User, user),
+                pick(GroupBy::Namespace, namespace),
+            ))
+            .or_default()
+            .add(totals);
+        }
+        TransferReport {
+            from: from.to_string(),
+            to: to.to_string(),
+            rows: rows
+                .into_iter()
+                .map(|((day, user, namespace), totals)| TransferRow {
+                    day,
+                    user,
+                    namespace,
+                    totals,
+                })
+                .collect(),
+        }
+    }
+
+    /// Writes the totals to the data dir if they've changed, dropping days older than KEEP_DAYS
+    pub fn save(&self) -> Result<()> {
+        if !self.changed.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let oldest = (Utc::now() - chrono::Duration::days(KEEP_DAYS))
+            .format(DAY_FORMAT)
+            .to_string();
+        let saved: Vec<SavedTotals> = {
+            let mut totals = self.totals.lock().unwrap();
+            totals.retain(|(day, _, _), _| *day >= oldest);
+            totals
+                .iter()
+                .map(|((day, user, namespace), totals)| SavedTotals {
+                    day: day.clone(),
+                    user: user.clone(),
+                    namespace: namespace.clone(),
+                    totals: *totals,
+                })
+                .collect()
+        };
+        let tmp_path = self.path.with_extension("json.tmp");
+        let res = fs::write(&tmp_path, serde_json::to_vec(&saved)?)
+            .and_then(|_| fs::rename(&tmp_path, &self.path));
+        if res.is_err() {
+            // Try again next time
+            self.changed.store(true, Ordering::SeqCst);
+        }
+        Ok(res?)
+    }
+}
+
+enum Target {
+    Manifest,
+    Blob,
+    Upload,
+}
+
+// The repository and what's being transferred, from a path like /v2/myorg/app/blobs/<digest>
+fn parse_path(path: &str) -> Option<(&str, Target)> {
+    let path = path.strip_prefix("/v2/")?;
+    if let Some(i) = path.rfind("/manifests/") {
+        return Some((&path[..i], Target::Manifest));
+    }
+    let i = path.rfind("/blobs/")?;
+    let target = if path[i..].starts_with("/blobs/uploads") {
+        Target::Upload
+    } else {
+        Target::Blob
+    };
+    Some((&path[..i], target))
+}
+
+// The size of the blob from the Range header of a completed upload, e.g. 0-1023
+fn upload_size(resp: &Response<'_>) -> Option<u64> {
+    let range = resp.headers().get_one("Range")?;
+    let end: u64 = range.split_once('-')?.1.parse().ok()?;
+    Some(end + 1)
+}
+
+/*
+ * Records transfers as responses are sent, and saves the totals every SAVE_INTERVAL.
+ */
+pub struct TransferAccounting(pub Arc<TransferLedger>);
+
+#[rocket::async_trait]
+impl Fairing for TransferAccounting {
+    fn info(&self) -> Info {
+        Info {
+            name: "Transfer Accounting",
+            kind: Kind::Liftoff | Kind::Response,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let ledger = self.0.clone();
+        let shutdown = rocket.shutdown();
+        rocket::tokio::spawn(async move {
+            loop {
+                rocket::tokio::select! {
+                    _ = rocket::tokio::time::sleep(SAVE_INTERVAL) => {}
+                    // Saved once requests have drained instead
+                    _ = shutdown.clone() => return,
+                }
+                if let Err(e) = ledger.save() {
+                    warn!("Failed to save transfer totals: {}", e);
+                }
+            }
+        });
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, resp: &mut Response<'r>) {
+        if !resp.status().class().is_success() {
+            return;
+        }
+        let (repo, target) = match parse_path(req.uri().path().as_str()) {
+            Some(t) => t,
+            None => return,
+        };
+        let transfer = match (req.method(), target) {
+            (Method::Get, Target::Manifest | Target::Blob) => resp
+                .body_mut()
+                .size()
+                .await
+                .map(|s| (Direction::Pull, s as u64)),
+            (Method::Put, Target::Manifest) => req
+                .headers()
+                .get_one("Content-Length")
+                .and_then(|l| l.parse().ok())
+                .map(|s| (Direction::Push, s)),
+            // Completing an upload, with PUT or a single POST
+            (Method::Put | Method::Post, Target::Upload) if resp.status() == Status::Created => {
+                upload_size(resp).map(|s| (Direction::Push, s))
+            }
+            _ => None,
+        };
+        let (direction, bytes) = match transfer {
+            Some(t) => t,
+            None => return,
+        };
+        let user = match req.guard::<TrowToken>().await {
+            rocket::outcome::Outcome::Success(t) => t.user,
+            _ => return,
+        };
+        let namespace = repo.split('/').next().unwrap_or(repo);
+        self.0.record(&today(), &user, namespace, direction, bytes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_day, Direction, GroupBy, Totals, TransferLedger};
+    use tempfile::tempdir;
+
+    #[test]
+    fn reports_by_group() {
+        let dir = tempdir().unwrap();
+        let ledger = TransferLedger::load(dir.path(), false);
+        ledger.record("2022-03-01", "alice", "myorg", Direction::Push, 100);
+        ledger.record("2022-03-01", "alice", "myorg", Direction::Pull, 10);
+        ledger.record("2022-03-02", "bob", "myorg", Direction::Pull, 20);
+        ledger.record("2022-03-02", "bob", "other", Direction::Pull, 5);
+        ledger.record("2022-04-01", "bob", "myorg", Direction::Pull, 1000);
+
+        let report = ledger.report("2022-03-01", "2022-03-31", &[GroupBy::Namespace]);
+        assert_eq!(report.rows.len(), 2);
+        assert_eq!(report.rows[0].namespace.as_deref(), Some("myorg"));
+        assert_eq!(report.rows[0].user, None);
+        assert_eq!(
+            report.rows[0].totals,
+            Totals {
+                pushed_bytes: 100,
+                pulled_bytes: 30,
+                pushes: 1,
+                pulls: 2,
+            }
+        );
+
+        let report = ledger.report("2022-03-02", "2022-04-01", &[GroupBy::User]);
+        assert_eq!(report.rows.len(), 1);
+        assert_eq!(report.rows[0].totals.pulled_bytes, 1025);
+
+        let report = ledger.report("2022-03-01", "2022-04-01", &[]);
+        assert_eq!(report.rows.len(), 1);
+        assert_eq!(report.rows[0].totals.pulls, 4);
+    }
+
+    #[test]
+    fn saves_totals() {
+        let dir = tempdir().unwrap();
+        let ledger = TransferLedger::load(dir.path(), false);
+        let today = super::today();
+        ledger.record(&today, "alice", "myorg", Direction::Push, 100);
+        // Too old to keep
+        ledger.record("2000-01-01", "alice", "myorg", Direction::Push, 100);
+        ledger.save().unwrap();
+
+        let loaded = TransferLedger::load(dir.path(), false);
+        let report = loaded.report("1970-01-01", "9999-12-31", &[GroupBy::Day]);
+        assert_eq!(report.rows.len(), 1);
+        assert_eq!(report.rows[0].day.as_deref(), Some(today.as_str()));
+    }
+
+    #[test]
+    fn parses_days() {
+        assert_eq!(parse_day("2022-03-01").unwrap(), "2022-03-01");
+        assert!(parse_day("2022-3-1x").is_err());
+        assert!(parse_day("yesterday").is_err());
+    }
+}
@@ -1,5 +1,6 @@
-use crate::registry_interface::{validation, Digest};
+use crate::registry_interface::{Digest, JobStatus};
 
+use chrono::{DateTime, Utc};
 use derive_more::Display;
 use rocket::Responder;
 use serde::{Deserialize, Serialize};
@@ -192,16 +193,6 @@ impl TagList {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "camelCase")]
-pub struct AdmissionReview {
-    //TODO: Get rid of stringly typing
-    pub api_version: String,
-    pub kind: String,
-    pub request: Option<validation::AdmissionRequest>,
-    pub response: Option<validation::AdmissionResponse>,
-}
-
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct HealthResponse {
     pub message: String,
@@ -213,3 +204,39 @@ pub struct ReadinessResponse {
     pub message: String,
     pub is_ready: bool,
 }
+
+// Body of a request to start a job e.g. {"kind": "gc"}
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct JobRequest {
+    pub kind: String,
+}
+
+// Body of a request to prewarm an image e.g. {"image": "f/docker/library/nginx:1.21"}
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PrewarmJobRequest {
+    // A repository in this registry, with a tag or digest
+    pub image: String,
+    // Publish a prewarm event once the image is resident, for node agents to pull it
+    #[serde(default)]
+    pub notify: bool,
+}
+
+// Returned when a job is started; it's likely still running
+#[derive(Debug)]
+pub struct StartedJob(pub JobStatus);
+
+// Body of a request to complete first run setup
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SetupRequest {
+    pub user: String,
+    pub password: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SetupStatus {
+    pub complete: bool,
+    pub completed: Option<DateTime<Utc>>,
+    pub admins: Vec<String>,
+    // What to do to finish setup
+    pub next: Option<String>,
+}
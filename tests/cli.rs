@@ -144,4 +144,23 @@ mod cli {
             .assert()
             .success();
     }
+
+    #[test]
+    fn ctl_needs_a_command() {
+        let ctl = || assert_cmd::Command::cargo_bin("trow-ctl").unwrap();
+        ctl()
+            .arg("--help")
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("delete-repo"));
+
+        ctl().assert().failure();
+
+        // Nothing is listening
+        ctl()
+            .args(&["repos", "--url", "http://127.0.0.1:9"])
+            .assert()
+            .failure()
+            .stderr(predicate::str::is_empty().not());
+    }
 }
@@ -112,6 +112,9 @@ mod interface_tests {
             media_type: Some("application/vnd.docker.distribution.manifest.v2+json".to_owned()),
             config,
             layers,
+            artifact_type: None,
+            subject: None,
+            annotations: None,
         };
         let manifest_addr = format!("{}/v2/{}/manifests/{}", TROW_ADDRESS, name, "tag");
         let resp = cl.put(&manifest_addr).json(&mani).send().await.unwrap();
@@ -143,6 +143,16 @@ mod interface_tests {
         assert_eq!(rc, &rc_resp);
     }
 
+    async fn check_repo_catalog_prefix(cl: &reqwest::Client, prefix: &str, rc: &RepoCatalog) {
+        let resp = cl
+            .get(&format!("{}/v2/_catalog?prefix={}", TROW_ADDRESS, prefix))
+            .send()
+            .await
+            .unwrap();
+        let rc_resp: RepoCatalog = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+        assert_eq!(rc, &rc_resp);
+    }
+
     async fn check_tag_list(cl: &reqwest::Client, tl: &TagList) {
         let resp = cl
             .get(&format!("{}/v2/{}/tags/list", TROW_ADDRESS, tl.repo_name()))
@@ -260,6 +270,7 @@ mod interface_tests {
         digest
     }
 
+    // digest is the manifest pushed by push_oci_manifest, which is 354 bytes
     async fn push_manifest_list(
         cl: &reqwest::Client,
         digest: &str,
@@ -273,7 +284,7 @@ mod interface_tests {
                 "manifests": [
                   {{
                     "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
-                    "size": 7143,
+                    "size": 354,
                     "digest": "{}",
                     "platform": {{
                       "architecture": "ppc64le",
@@ -563,6 +574,11 @@ mod interface_tests {
         println!("Running check_repo_catalog");
         check_repo_catalog(&client, &rc).await;
 
+        let mut rc = RepoCatalog::new();
+        rc.insert("repo/image/test".to_string());
+        println!("Running check_repo_catalog_prefix");
+        check_repo_catalog_prefix(&client, "repo/", &rc).await;
+
         let mut tl = TagList::new("repo/image/test".to_string());
         tl.insert("tag".to_string());
         println!("Running check_tag_list 1");
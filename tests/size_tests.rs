@@ -73,6 +73,12 @@ mod interface_tests {
 
     #[cfg(test)]
     pub async fn put_sized_blob(cl: &reqwest::Client, size: usize) -> StatusCode {
+        *put_sized_chunks(cl, &[size]).await.last().unwrap()
+    }
+
+    // Sends each chunk in its own PATCH to the same upload
+    #[cfg(test)]
+    pub async fn put_sized_chunks(cl: &reqwest::Client, sizes: &[usize]) -> Vec<StatusCode> {
         let resp = cl
             .post(&format!("{}/v2/{}/blobs/uploads/", TROW_ADDRESS, "sized"))
             .send()
@@ -84,17 +90,21 @@ mod interface_tests {
             .get(common::LOCATION_HEADER)
             .unwrap()
             .to_str()
-            .unwrap();
-
-        let blob = common::gen_rand_blob(size);
-        let resp = cl
-            .patch(location)
-            .body(blob)
-            .send()
-            .await
-            .expect("Failed to send patch request");
-
-        resp.status()
+            .unwrap()
+            .to_string();
+
+        let mut statuses = vec![];
+        for size in sizes {
+            let blob = common::gen_rand_blob(*size);
+            let resp = cl
+                .patch(&location)
+                .body(blob)
+                .send()
+                .await
+                .expect("Failed to send patch request");
+            statuses.push(resp.status());
+        }
+        statuses
     }
 
     #[tokio::test]
@@ -121,8 +131,13 @@ mod interface_tests {
             put_sized_blob(&client, 3 * 1024 * 1024 - 1).await
         );
         assert_eq!(
-            StatusCode::RANGE_NOT_SATISFIABLE,
+            StatusCode::PAYLOAD_TOO_LARGE,
             put_sized_blob(&client, 3 * 1024 * 1024 + 1).await
         );
+        // The limit is for the whole blob, not each chunk
+        assert_eq!(
+            vec![StatusCode::ACCEPTED, StatusCode::PAYLOAD_TOO_LARGE],
+            put_sized_chunks(&client, &[2 * 1024 * 1024, 2 * 1024 * 1024]).await
+        );
     }
 }
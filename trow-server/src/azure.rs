@@ -0,0 +1,328 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::{StatusCode, Url};
+use serde_json::Value;
+
+use crate::egress::EgressProxies;
+use crate::storage::{expires_in, object_name, CachedToken, StorageDriver};
+
+/*
+ * Azure Blob Storage, for --storage azure://<account>/<container>[/<prefix>].
+ *
+ * Credentials come from AKS workload identity if the pod has it (AZURE_FEDERATED_TOKEN_FILE,
+ * AZURE_CLIENT_ID and AZURE_TENANT_ID are set by its webhook), and otherwise from the managed
+ * identity of the VM or node, through the instance metadata service. Set AZURE_CLIENT_ID to pick
+ * a user-assigned managed identity. The identity needs the Storage Blob Data Contributor role on
+ * the container.
+ *
+ * Files larger than BLOCK_SIZE are uploaded in blocks, as a single upload is limited in size.
+ */
+
+const API_VERSION: &str = "2021-08-06";
+const SCOPE: &str = "https://storage.azure.com/.default";
+const RESOURCE: &str = "https://storage.azure.com/";
+const IMDS_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+const DEFAULT_AUTHORITY: &str = "https://login.microsoftonline.com/";
+const BLOCK_SIZE: u64 = 100 * 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+enum Credential {
+    WorkloadIdentity {
+        authority: String,
+        tenant_id: String,
+        client_id: String,
+        token_file: String,
+    },
+    ManagedIdentity {
+        client_id: Option<String>,
+    },
+}
+
+pub struct AzureBlob {
+    account: String,
+    container: String,
+    prefix: Option<String>,
+    credential: Credential,
+    token: CachedToken,
+    client: Client,
+    // For the instance metadata service, which must never go through a proxy
+    imds_client: Client,
+}
+
+impl AzureBlob {
+    pub fn new(
+        account: &str,
+        container: &str,
+        prefix: Option<String>,
+        egress: &EgressProxies,
+    ) -> Result<AzureBlob> {
+        let client_id = env::var("AZURE_CLIENT_ID").ok();
+        let credential = match (
+            env::var("AZURE_FEDERATED_TOKEN_FILE"),
+            env::var("AZURE_TENANT_ID"),
+            &client_id,
+        ) {
+            (Ok(token_file), Ok(tenant_id), Some(client_id)) => Credential::WorkloadIdentity {
+                authority: env::var("AZURE_AUTHORITY_HOST")
+                    .unwrap_or_else(|_| DEFAULT_AUTHORITY.to_string()),
+                tenant_id,
+                client_id: client_id.clone(),
+                token_file,
+            },
+            _ => Credential::ManagedIdentity { client_id },
+        };
+        Ok(AzureBlob {
+            account: account.to_string(),
+            container: container.to_string(),
+            prefix,
+            credential,
+            token: CachedToken::default(),
+            client: Client::builder()
+                .timeout(TIMEOUT)
+                .proxy(egress.proxy())
+                .build()?,
+            imds_client: Client::builder()
+                .timeout(Duration::from_secs(30))
+                .no_proxy()
+                .build()?,
+        })
+    }
+
+    fn fetch_token(&self) -> Result<(String, Duration)> {
+        let resp: Value = match &self.credential {
+            Credential::WorkloadIdentity {
+                authority,
+                tenant_id,
+                client_id,
+                token_file,
+            } => {
+                // Read each time, as the token in it is rotated
+                let assertion = fs::read_to_string(token_file)?;
+                let url = format!(
+                    "{}/{}/oauth2/v2.0/token",
+                    authority.trim_end_matches('/'),
+                    tenant_id
+                );
+                self.client
+                    .post(url)
+                    .form(&[
+                        ("client_id", client_id.as_str()),
+                        ("scope", SCOPE),
+                        ("grant_type", "client_credentials"),
+                        (
+                            "client_assertion_type",
+                            "urn:ietf:params:oauth:client-assertion-type:jwt-bearer",
+                        ),
+                        ("client_assertion", assertion.trim()),
+                    ])
+                    .send()?
+                    .error_for_status()?
+                    .json()?
+            }
+            Credential::ManagedIdentity { client_id } => {
+                let mut req = self
+                    .imds_client
+                    .get(IMDS_URL)
+                    .header("Metadata", "true")
+                    .query(&[("api-version", "2018-02-01"), ("resource", RESOURCE)]);
+                if let Some(id) = client_id {
+                    req = req.query(&[("client_id", id)]);
+                }
+                req.send()?.error_for_status()?.json()?
+            }
+        };
+        let token = resp["access_token"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No access token from Azure AD"))?;
+        Ok((token.to_string(), expires_in(&resp)))
+    }
+
+    fn url(&self, path: Option<&str>) -> Result<Url> {
+        let mut url = Url::parse(&format!("https://{}.blob.core.windows.net/", self.account))?;
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| anyhow!("Invalid account {}", self.account))?;
+            segments.push(&self.container);
+            if let Some(path) = path {
+                segments.extend(object_name(&self.prefix, path).split('/'));
+            }
+        }
+        Ok(url)
+    }
+
+    fn send(&self, req: RequestBuilder) -> Result<Response> {
+        let token = self.token.get(|| self.fetch_token())?;
+        Ok(req
+            .bearer_auth(token)
+            .header("x-ms-version", API_VERSION)
+            .header(
+                "x-ms-date",
+                Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            )
+            .send()?)
+    }
+
+    fn put_blocks(&self, url: Url, local_path: &Path) -> Result<()> {
+        let mut file = File::open(local_path)?;
+        let mut ids = vec![];
+        loop {
+            let mut block = vec![];
+            (&mut file).take(BLOCK_SIZE).read_to_end(&mut block)?;
+            if block.is_empty() {
+                break;
+            }
+            // Ids have to be base64 and the same length, which numbers padded to 8 digits are
+            let id = format!("{:08}", ids.len());
+            let mut block_url = url.clone();
+            block_url
+                .query_pairs_mut()
+                .append_pair("comp", "block")
+                .append_pair("blockid", &id);
+            self.send(self.client.put(block_url).body(block))?
+                .error_for_status()?;
+            ids.push(id);
+        }
+        let list: String = ids
+            .iter()
+            .map(|id| format!("<Latest>{}</Latest>", id))
+            .collect();
+        let mut list_url = url;
+        list_url.query_pairs_mut().append_pair("comp", "blocklist");
+        self.send(self.client.put(list_url).body(format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>{}</BlockList>",
+            list
+        )))?
+        .error_for_status()?;
+        Ok(())
+    }
+}
+
+impl StorageDriver for AzureBlob {
+    fn describe(&self) -> String {
+        format!("azure://{}/{}", self.account, self.container)
+    }
+
+    fn get(&self, path: &str, local_path: &Path) -> Result<bool> {
+        let mut resp = self.send(self.client.get(self.url(Some(path))?))?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        resp.error_for_status_ref()?;
+        resp.copy_to(&mut File::create(local_path)?)?;
+        Ok(true)
+    }
+
+    fn put(&self, path: &str, local_path: &Path) -> Result<()> {
+        let url = self.url(Some(path))?;
+        if fs::metadata(local_path)?.len() > BLOCK_SIZE {
+            return self.put_blocks(url, local_path);
+        }
+        self.send(
+            self.client
+                .put(url)
+                .header("x-ms-blob-type", "BlockBlob")
+                .body(File::open(local_path)?),
+        )?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        let resp = self.send(self.client.delete(self.url(Some(path))?))?;
+        if resp.status() != StatusCode::NOT_FOUND {
+            resp.error_for_status()?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<(String, u64)>> {
+        let prefix = object_name(&self.prefix, &format!("{}/", dir));
+        let strip = object_name(&self.prefix, "");
+        let mut objects = vec![];
+        let mut marker = String::new();
+        loop {
+            let mut url = self.url(None)?;
+            url.query_pairs_mut()
+                .append_pair("restype", "container")
+                .append_pair("comp", "list")
+                .append_pair("prefix", &prefix);
+            if !marker.is_empty() {
+                url.query_pairs_mut().append_pair("marker", &marker);
+            }
+            let body = self
+                .send(self.client.get(url))?
+                .error_for_status()?
+                .text()?;
+            for blob in xml_elements(&body, "Blob") {
+                let name = xml_elements(&blob, "Name").pop().unwrap_or_default();
+                let size = xml_elements(&blob, "Content-Length")
+                    .pop()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(0);
+                if let Some(path) = name.strip_prefix(&strip) {
+                    objects.push((path.to_string(), size));
+                }
+            }
+            marker = xml_elements(&body, "NextMarker").pop().unwrap_or_default();
+            if marker.is_empty() {
+                return Ok(objects);
+            }
+        }
+    }
+}
+
+/*
+ * The contents of every <tag> element, unescaped. Enough for the listings Azure returns, without
+ * a full XML parser.
+ */
+fn xml_elements(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut found = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                found.push(
+                    rest[..end]
+                        .replace("&lt;", "<")
+                        .replace("&gt;", ">")
+                        .replace("&quot;", "\"")
+                        .replace("&apos;", "'")
+                        .replace("&amp;", "&"),
+                );
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod test {
+    use super::xml_elements;
+
+    #[test]
+    fn reads_blob_listing() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?><EnumerationResults ServiceEndpoint="https://trow.blob.core.windows.net/" ContainerName="registry"><Prefix>manifests/</Prefix><Blobs><Blob><Name>manifests/app/latest</Name><Properties><Content-Length>160</Content-Length></Properties></Blob><Blob><Name>manifests/a&amp;b/v1</Name><Properties><Content-Length>0</Content-Length></Properties></Blob></Blobs><NextMarker /></EnumerationResults>"#;
+        let blobs = xml_elements(xml, "Blob");
+        assert_eq!(blobs.len(), 2);
+        assert_eq!(
+            xml_elements(&blobs[0], "Name"),
+            vec!["manifests/app/latest"]
+        );
+        assert_eq!(xml_elements(&blobs[0], "Content-Length"), vec!["160"]);
+        assert_eq!(xml_elements(&blobs[1], "Name"), vec!["manifests/a&b/v1"]);
+        assert!(xml_elements(xml, "NextMarker").is_empty());
+    }
+}
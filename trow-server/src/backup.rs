@@ -0,0 +1,733 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::{info, warn};
+use uuid::Uuid;
+
+use crate::digest::sha256_tag_digest;
+use crate::jobs::JobHandle;
+use crate::maintenance::{blob_path, follow_manifests, walk_files};
+use crate::metadata::MetadataStore;
+
+/*
+ * Incremental backups of the registry.
+ *
+ * The "backup" job copies the tag files, repository links and every blob the tags refer to into a
+ * backup target, laid out like the data dir so restoring is copying it back. Blobs are named by
+ * their digest and never change, so only blobs the target doesn't already have are copied, which
+ * makes backing up a mostly static registry quick. Each blob is checked against its digest as it's
+ * copied, so a corrupt blob doesn't end up in the backup.
+ *
+ * Tag files and links are written after all the blobs, and only if they all copied, so a failed
+ * backup leaves the previous one usable. Tags and links deleted from the registry are removed from
+ * the backup, but blobs are left, as the backup is never garbage collected.
+ *
+ * Targets implement BackupTarget. The only one so far is a directory, which can be a mounted
+ * volume, NFS share or bucket.
+ *
+ * The "archive" job instead writes everything, blobs included, into a single tarball in the
+ * target's archives dir, for keeping copies offsite or at particular points in time. It's laid out
+ * like the data dir too.
+ *
+ * The "restore" job copies tags back from the target, with the blobs they need, and then rebuilds
+ * the metadata database from the data dir. Only tags missing from the data dir are restored, so
+ * it can be used to bring back deleted repositories or fill an empty data dir without touching
+ * anything pushed since the backup.
+ */
+
+static MANIFESTS_DIR: &str = "manifests";
+static BLOBS_DIR: &str = "blobs";
+static LINKS_DIR: &str = "links";
+static ARCHIVES_DIR: &str = "archives";
+
+/*
+ * Somewhere to back up to. Paths are relative to the data dir and always use /.
+ */
+pub trait BackupTarget: Send + Sync {
+    // For logs and the job message
+    fn describe(&self) -> String;
+
+    fn has_blob(&self, digest: &str) -> Result<bool>;
+
+    /// Copies the local blob into the backup, failing if it doesn't match its digest
+    fn put_blob(&self, digest: &str, local_path: &Path) -> Result<()>;
+
+    fn put_file(&self, path: &str, contents: &[u8]) -> Result<()>;
+
+    /// Every file under the directory, e.g. "manifests"
+    fn list_files(&self, dir: &str) -> Result<Vec<String>>;
+
+    fn remove_file(&self, path: &str) -> Result<()>;
+
+    /// Copies the blob out of the backup, failing if it doesn't match its digest
+    fn get_blob(&self, digest: &str, local_path: &Path) -> Result<()>;
+
+    fn get_file(&self, path: &str) -> Result<Vec<u8>>;
+
+    /// Stores a tarball written by the archive job under the given name
+    fn put_archive(&self, name: &str, local_path: &Path) -> Result<()>;
+}
+
+pub struct DirTarget {
+    root: PathBuf,
+}
+
+impl DirTarget {
+    pub fn new(root: &Path) -> Result<DirTarget> {
+        fs::create_dir_all(root)
+            .map_err(|e| anyhow!("Failed to create backup dir {:?}: {}", root, e))?;
+        Ok(DirTarget {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn write_atomic(&self, path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+        let tmp_dir = self.root.join(".tmp");
+        fs::create_dir_all(&tmp_dir)?;
+        let tmp_path = tmp_dir.join(Uuid::new_v4().to_string());
+        let res = write(&tmp_path).and_then(|_| {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            Ok(fs::rename(&tmp_path, path)?)
+        });
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        res
+    }
+}
+
+impl BackupTarget for DirTarget {
+    fn describe(&self) -> String {
+        self.root.to_string_lossy().to_string()
+    }
+
+    fn has_blob(&self, digest: &str) -> Result<bool> {
+        let path = blob_path(&self.root.join(BLOBS_DIR), digest)
+            .ok_or_else(|| anyhow!("Invalid digest {}", digest))?;
+        Ok(path.exists())
+    }
+
+    fn put_blob(&self, digest: &str, local_path: &Path) -> Result<()> {
+        let path = blob_path(&self.root.join(BLOBS_DIR), digest)
+            .ok_or_else(|| anyhow!("Invalid digest {}", digest))?;
+        self.write_atomic(&path, |tmp_path| {
+            fs::copy(local_path, tmp_path)?;
+            // Checks the copy, which also catches the local blob being corrupt
+            let actual = sha256_tag_digest(BufReader::new(File::open(tmp_path)?))?;
+            if actual != digest {
+                return Err(anyhow!("Blob {} has digest {}", digest, actual));
+            }
+            Ok(())
+        })
+    }
+
+    fn put_file(&self, path: &str, contents: &[u8]) -> Result<()> {
+        let path = self.root.join(path);
+        // Most tag files are unchanged since the last backup
+        if fs::read(&path).map_or(false, |c| c == contents) {
+            return Ok(());
+        }
+        self.write_atomic(&path, |tmp_path| Ok(fs::write(tmp_path, contents)?))
+    }
+
+    fn list_files(&self, dir: &str) -> Result<Vec<String>> {
+        Ok(walk_files(&self.root.join(dir))?
+            .iter()
+            .filter_map(|p| p.strip_prefix(&self.root).ok())
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+            .collect())
+    }
+
+    fn remove_file(&self, path: &str) -> Result<()> {
+        Ok(fs::remove_file(self.root.join(path))?)
+    }
+
+    fn get_blob(&self, digest: &str, local_path: &Path) -> Result<()> {
+        let path = blob_path(&self.root.join(BLOBS_DIR), digest)
+            .ok_or_else(|| anyhow!("Invalid digest {}", digest))?;
+        fs::copy(&path, local_path)?;
+        let actual = sha256_tag_digest(BufReader::new(File::open(local_path)?))?;
+        if actual != digest {
+            let _ = fs::remove_file(local_path);
+            return Err(anyhow!("Backed up blob {} has digest {}", digest, actual));
+        }
+        Ok(())
+    }
+
+    fn get_file(&self, path: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(self.root.join(path))?)
+    }
+
+    fn put_archive(&self, name: &str, local_path: &Path) -> Result<()> {
+        let path = self.root.join(ARCHIVES_DIR).join(name);
+        self.write_atomic(&path, |tmp_path| {
+            fs::copy(local_path, tmp_path)?;
+            Ok(())
+        })
+    }
+}
+
+/*
+ * The tag files and links at one point in time, with the blobs they need.
+ */
+struct Snapshot {
+    // Path relative to the data dir and contents
+    files: Vec<(String, Vec<u8>)>,
+    tags: usize,
+    digests: Vec<String>,
+}
+
+fn relative(base: &Path, dir: &str, path: &Path) -> Option<String> {
+    let rel = path.strip_prefix(base.join(dir)).ok()?;
+    Some(format!(
+        "{}/{}",
+        dir,
+        rel.to_string_lossy().replace('\\', "/")
+    ))
+}
+
+// Every digest in a tag file's history
+fn tag_file_digests(contents: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(contents)
+        .lines()
+        .filter_map(|l| l.split(' ').next())
+        .filter(|d| !d.is_empty())
+        .map(|d| d.to_string())
+        .collect()
+}
+
+fn snapshot(data_path: &Path, tags_lock: &RwLock<()>) -> Result<Snapshot> {
+    let mut files = vec![];
+    let mut tag_digests = vec![];
+    {
+        let _guard = tags_lock.read().unwrap();
+        for path in walk_files(&data_path.join(MANIFESTS_DIR))? {
+            let contents = fs::read(&path)?;
+            tag_digests.extend(tag_file_digests(&contents));
+            if let Some(rel) = relative(data_path, MANIFESTS_DIR, &path) {
+                files.push((rel, contents));
+            }
+        }
+    }
+    let tags = files.len();
+    // Links are empty files
+    for path in walk_files(&data_path.join(LINKS_DIR))? {
+        if let Some(rel) = relative(data_path, LINKS_DIR, &path) {
+            files.push((rel, vec![]));
+        }
+    }
+
+    let mut digests: Vec<String> = follow_manifests(tag_digests, &data_path.join(BLOBS_DIR))
+        .into_iter()
+        .collect();
+    digests.sort();
+    Ok(Snapshot {
+        files,
+        tags,
+        digests,
+    })
+}
+
+/**
+ * Backs up the registry in data_path to the target, copying only the blobs it doesn't have.
+ */
+pub fn run_job(
+    data_path: &Path,
+    tags_lock: &RwLock<()>,
+    target: &dyn BackupTarget,
+    handle: &JobHandle,
+) -> Result<String> {
+    info!("Backing up to {}", target.describe());
+    let snapshot = snapshot(data_path, tags_lock)?;
+    let blobs_path = data_path.join(BLOBS_DIR);
+
+    let (mut copied, mut bytes, mut present, mut failed) = (0, 0, 0, 0);
+    for (i, digest) in snapshot.digests.iter().enumerate() {
+        if handle.is_cancelled() {
+            return Ok(format!(
+                "Cancelled after copying {} blobs ({} bytes), tags not backed up",
+                copied, bytes
+            ));
+        }
+        handle.set_progress(i, snapshot.digests.len());
+
+        let local_path = match blob_path(&blobs_path, digest) {
+            Some(p) if p.exists() => p,
+            // Tags can refer to manifests that were never pulled, e.g. in a proxied list
+            _ => continue,
+        };
+        if target.has_blob(digest)? {
+            present += 1;
+            continue;
+        }
+        match target.put_blob(digest, &local_path) {
+            Ok(()) => {
+                copied += 1;
+                bytes += fs::metadata(&local_path)?.len();
+            }
+            Err(e) => {
+                warn!("Failed to back up blob {}: {:?}", digest, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!(
+            "Failed to back up {} blobs, see the logs. Copied {} blobs ({} bytes), tags not backed up",
+            failed,
+            copied,
+            bytes
+        ));
+    }
+
+    let mut kept = HashSet::new();
+    for (path, contents) in &snapshot.files {
+        target.put_file(path, contents)?;
+        kept.insert(path.as_str());
+    }
+    let mut removed = 0;
+    for dir in [MANIFESTS_DIR, LINKS_DIR] {
+        for path in target.list_files(dir)? {
+            if !kept.contains(path.as_str()) {
+                target.remove_file(&path)?;
+                removed += 1;
+            }
+        }
+    }
+
+    Ok(format!(
+        "Backed up {} tags to {}: copied {} new blobs ({} bytes), {} already backed up, removed {} deleted tags and links",
+        snapshot.tags,
+        target.describe(),
+        copied,
+        bytes,
+        present,
+        removed
+    ))
+}
+
+/*
+ * Writes the snapshot's files and the blobs it needs as a tarball. keep_going is called before
+ * each blob with the progress so far, and stops the archive if it returns false.
+ *
+ * Returns the number of blobs and their total size, or None if stopped.
+ */
+fn write_archive<W: Write>(
+    snapshot: &Snapshot,
+    blobs_path: &Path,
+    out: W,
+    keep_going: &dyn Fn(usize, usize) -> bool,
+) -> Result<Option<(usize, u64)>> {
+    let mut builder = tar::Builder::new(out);
+    let mtime = Utc::now().timestamp() as u64;
+    for (path, contents) in &snapshot.files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        builder.append_data(&mut header, path, contents.as_slice())?;
+    }
+
+    let (mut blobs, mut bytes) = (0, 0);
+    for (i, digest) in snapshot.digests.iter().enumerate() {
+        if !keep_going(i, snapshot.digests.len()) {
+            return Ok(None);
+        }
+        let local_path = match blob_path(blobs_path, digest) {
+            Some(p) if p.exists() => p,
+            // Tags can refer to manifests that were never pulled, e.g. in a proxied list
+            _ => continue,
+        };
+        let name = format!("{}/{}", BLOBS_DIR, digest.replacen(':', "/", 1));
+        builder.append_path_with_name(&local_path, name)?;
+        blobs += 1;
+        bytes += fs::metadata(&local_path)?.len();
+    }
+    builder.into_inner()?.flush()?;
+    Ok(Some((blobs, bytes)))
+}
+
+/**
+ * Writes a tarball of the registry in data_path to the target's archives dir.
+ */
+pub fn run_archive_job(
+    data_path: &Path,
+    scratch_path: &Path,
+    tags_lock: &RwLock<()>,
+    target: &dyn BackupTarget,
+    handle: &JobHandle,
+) -> Result<String> {
+    info!("Archiving to {}", target.describe());
+    let snapshot = snapshot(data_path, tags_lock)?;
+    let name = format!("trow-{}.tar", Utc::now().format("%Y%m%dT%H%M%SZ"));
+    let tmp_path = scratch_path.join(Uuid::new_v4().to_string());
+
+    let keep_going = |i: usize, total: usize| {
+        handle.set_progress(i, total);
+        !handle.is_cancelled()
+    };
+    let res = File::create(&tmp_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|f| write_archive(&snapshot, &data_path.join(BLOBS_DIR), f, &keep_going))
+        .and_then(|written| {
+            if written.is_some() {
+                target.put_archive(&name, &tmp_path)?;
+            }
+            Ok(written)
+        });
+    let _ = fs::remove_file(&tmp_path);
+
+    match res? {
+        Some((blobs, bytes)) => Ok(format!(
+            "Archived {} tags and {} blobs ({} bytes) to {}/{}/{}",
+            snapshot.tags,
+            blobs,
+            bytes,
+            target.describe(),
+            ARCHIVES_DIR,
+            name
+        )),
+        None => Ok("Cancelled, no archive written".to_string()),
+    }
+}
+
+/*
+ * Restores tags missing from data_path, with their blobs and links, from the target. keep_going
+ * is as for write_archive.
+ */
+fn restore(
+    data_path: &Path,
+    scratch_path: &Path,
+    tags_lock: &RwLock<()>,
+    metadata: Option<&MetadataStore>,
+    target: &dyn BackupTarget,
+    keep_going: &dyn Fn(usize, usize) -> bool,
+) -> Result<String> {
+    let blobs_path = data_path.join(BLOBS_DIR);
+    let mut tags = vec![];
+    let mut skipped = 0;
+    for path in target.list_files(MANIFESTS_DIR)? {
+        if data_path.join(&path).exists() {
+            skipped += 1;
+            continue;
+        }
+        let contents = target.get_file(&path)?;
+        tags.push((path, contents));
+    }
+    let tag_digests: Vec<String> = tags.iter().flat_map(|(_, c)| tag_file_digests(c)).collect();
+
+    // The blobs a manifest refers to are only known once it's restored, so keep following the
+    // manifests until there's nothing new
+    let mut tried = HashSet::new();
+    let (mut copied, mut bytes, mut failed) = (0, 0, 0);
+    loop {
+        let mut needed: Vec<String> = follow_manifests(tag_digests.clone(), &blobs_path)
+            .into_iter()
+            .filter(|d| !tried.contains(d))
+            .collect();
+        if needed.is_empty() {
+            break;
+        }
+        needed.sort();
+        for (i, digest) in needed.iter().enumerate() {
+            if !keep_going(i, needed.len()) {
+                return Ok(format!(
+                    "Cancelled after restoring {} blobs ({} bytes), tags not restored",
+                    copied, bytes
+                ));
+            }
+            tried.insert(digest.clone());
+            let local_path = blob_path(&blobs_path, digest)
+                .ok_or_else(|| anyhow!("Invalid digest {}", digest))?;
+            // Not in the backup if it was never pulled through a proxy
+            if local_path.exists() || !target.has_blob(digest)? {
+                continue;
+            }
+            let tmp_path = scratch_path.join(Uuid::new_v4().to_string());
+            let res = target.get_blob(digest, &tmp_path).and_then(|_| {
+                if let Some(dir) = local_path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                Ok(fs::rename(&tmp_path, &local_path)?)
+            });
+            match res {
+                Ok(()) => {
+                    copied += 1;
+                    bytes += fs::metadata(&local_path)?.len();
+                }
+                Err(e) => {
+                    let _ = fs::remove_file(&tmp_path);
+                    warn!("Failed to restore blob {}: {:?}", digest, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!(
+            "Failed to restore {} blobs, see the logs. Restored {} blobs ({} bytes), tags not restored",
+            failed,
+            copied,
+            bytes
+        ));
+    }
+
+    for path in target.list_files(LINKS_DIR)? {
+        let local_path = data_path.join(&path);
+        if !local_path.exists() {
+            if let Some(dir) = local_path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(&local_path, "")?;
+        }
+    }
+
+    let mut restored = 0;
+    {
+        let _guard = tags_lock.write().unwrap();
+        for (path, contents) in &tags {
+            let local_path = data_path.join(path);
+            // Pushed while the blobs were being restored
+            if local_path.exists() {
+                skipped += 1;
+                continue;
+            }
+            if let Some(dir) = local_path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(&local_path, contents)?;
+            restored += 1;
+        }
+        if let Some(m) = metadata {
+            m.sync(&data_path.join(MANIFESTS_DIR), &blobs_path)?;
+        }
+    }
+
+    Ok(format!(
+        "Restored {} tags from {}: copied {} blobs ({} bytes), skipped {} tags already present",
+        restored,
+        target.describe(),
+        copied,
+        bytes,
+        skipped
+    ))
+}
+
+/**
+ * Restores tags missing from the registry in data_path from the target, then rebuilds the
+ * metadata database if there is one.
+ */
+pub fn run_restore_job(
+    data_path: &Path,
+    scratch_path: &Path,
+    tags_lock: &RwLock<()>,
+    metadata: Option<&MetadataStore>,
+    target: &dyn BackupTarget,
+    handle: &JobHandle,
+) -> Result<String> {
+    info!("Restoring from {}", target.describe());
+    let keep_going = |i: usize, total: usize| {
+        handle.set_progress(i, total);
+        !handle.is_cancelled()
+    };
+    restore(
+        data_path,
+        scratch_path,
+        tags_lock,
+        metadata,
+        target,
+        &keep_going,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{restore, snapshot, write_archive, BackupTarget, DirTarget};
+    use crate::digest::sha256_tag_digest;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::RwLock;
+    use tempfile::tempdir;
+
+    // sha256 of "hello world"
+    const LAYER: &str = "sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    #[test]
+    fn snapshot_finds_blobs() {
+        let dir = tempdir().unwrap();
+        let data = dir.path();
+        fs::create_dir_all(data.join("manifests/repo")).unwrap();
+        fs::create_dir_all(data.join("blobs/sha256")).unwrap();
+        fs::create_dir_all(data.join("links/repo/_blobs/sha256")).unwrap();
+        let manifest = format!(
+            r#"{{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": {{ "mediaType": "application/vnd.docker.container.image.v1+json", "size": 1, "digest": "sha256:config" }},
+            "layers": [ {{ "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "size": 11, "digest": "{}" }} ]
+        }}"#,
+            LAYER
+        );
+        fs::write(data.join("blobs/sha256/manifest"), manifest).unwrap();
+        fs::write(
+            data.join("manifests/repo/latest"),
+            "sha256:manifest 2022-01-01T00:00:00Z\n",
+        )
+        .unwrap();
+        fs::write(data.join("links/repo/_blobs/sha256/manifest"), "").unwrap();
+
+        let snap = snapshot(data, &RwLock::new(())).unwrap();
+        assert_eq!(snap.tags, 1);
+        assert_eq!(
+            snap.digests,
+            vec![LAYER, "sha256:config", "sha256:manifest"]
+        );
+        let paths: Vec<&str> = snap.files.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec!["manifests/repo/latest", "links/repo/_blobs/sha256/manifest"]
+        );
+    }
+
+    #[test]
+    fn dir_target_checks_digests() {
+        let dir = tempdir().unwrap();
+        let blob = dir.path().join("blob");
+        fs::write(&blob, "hello world").unwrap();
+        let target = DirTarget::new(&dir.path().join("backup")).unwrap();
+
+        assert!(!target.has_blob(LAYER).unwrap());
+        target.put_blob(LAYER, &blob).unwrap();
+        assert!(target.has_blob(LAYER).unwrap());
+
+        fs::write(&blob, "corrupted").unwrap();
+        let other = "sha256:0000000000000000000000000000000000000000000000000000000000000000";
+        assert!(target.put_blob(other, &blob).is_err());
+        assert!(!target.has_blob(other).unwrap());
+
+        target.put_file("manifests/repo/v1", b"digest").unwrap();
+        assert_eq!(
+            target.list_files("manifests").unwrap(),
+            vec!["manifests/repo/v1"]
+        );
+        target.remove_file("manifests/repo/v1").unwrap();
+        assert!(target.list_files("manifests").unwrap().is_empty());
+    }
+
+    // Stores an image with one layer, also used as its config, returning the manifest digest
+    fn write_image(blobs_path: &Path) -> String {
+        let manifest = format!(
+            r#"{{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": {{ "mediaType": "application/vnd.docker.container.image.v1+json", "size": 11, "digest": "{}" }},
+            "layers": [ {{ "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "size": 11, "digest": "{}" }} ]
+        }}"#,
+            LAYER, LAYER
+        );
+        let digest = sha256_tag_digest(manifest.as_bytes()).unwrap();
+        fs::create_dir_all(blobs_path.join("sha256")).unwrap();
+        fs::write(blobs_path.join("sha256").join(&LAYER[7..]), "hello world").unwrap();
+        fs::write(blobs_path.join("sha256").join(&digest[7..]), manifest).unwrap();
+        digest
+    }
+
+    #[test]
+    fn restores_missing_tags() {
+        let dir = tempdir().unwrap();
+        let backup = dir.path().join("backup");
+        let digest = write_image(&backup.join("blobs"));
+        let target = DirTarget::new(&backup).unwrap();
+        let tag_line = format!("{} 2022-01-01T00:00:00Z\n", digest);
+        target
+            .put_file("manifests/repo/v1", tag_line.as_bytes())
+            .unwrap();
+        target
+            .put_file("manifests/repo/v2", tag_line.as_bytes())
+            .unwrap();
+        let link = format!("links/repo/_blobs/sha256/{}", &digest[7..]);
+        target.put_file(&link, b"").unwrap();
+
+        let data = dir.path().join("data");
+        let scratch = dir.path().join("scratch");
+        fs::create_dir_all(data.join("manifests/repo")).unwrap();
+        fs::create_dir_all(&scratch).unwrap();
+        // Pushed since the backup
+        fs::write(data.join("manifests/repo/v2"), "newer").unwrap();
+
+        let msg = restore(&data, &scratch, &RwLock::new(()), None, &target, &|_, _| {
+            true
+        })
+        .unwrap();
+        assert!(msg.starts_with("Restored 1 tags"), "{}", msg);
+        assert_eq!(
+            fs::read_to_string(data.join("manifests/repo/v1")).unwrap(),
+            tag_line
+        );
+        assert_eq!(
+            fs::read_to_string(data.join("manifests/repo/v2")).unwrap(),
+            "newer"
+        );
+        // The layer is only found by reading the restored manifest
+        assert!(data.join("blobs/sha256").join(&digest[7..]).exists());
+        assert!(data.join("blobs/sha256").join(&LAYER[7..]).exists());
+        assert!(data.join(&link).exists());
+        assert!(fs::read_dir(&scratch).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn archives_snapshot() {
+        let dir = tempdir().unwrap();
+        let data = dir.path();
+        let digest = write_image(&data.join("blobs"));
+        fs::create_dir_all(data.join("manifests/repo")).unwrap();
+        fs::write(
+            data.join("manifests/repo/latest"),
+            format!("{} 2022-01-01T00:00:00Z\n", digest),
+        )
+        .unwrap();
+
+        let snap = snapshot(data, &RwLock::new(())).unwrap();
+        let mut tarball = vec![];
+        let written = write_archive(&snap, &data.join("blobs"), &mut tarball, &|_, _| true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            written,
+            (
+                2,
+                11 + fs::metadata(data.join("blobs/sha256").join(&digest[7..]))
+                    .unwrap()
+                    .len()
+            )
+        );
+
+        let mut archive = tar::Archive::new(tarball.as_slice());
+        let mut paths: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        paths.sort();
+        let mut expected = vec![
+            format!("blobs/sha256/{}", &digest[7..]),
+            format!("blobs/sha256/{}", &LAYER[7..]),
+            "manifests/repo/latest".to_string(),
+        ];
+        expected.sort();
+        assert_eq!(paths, expected);
+
+        assert!(
+            write_archive(&snap, &data.join("blobs"), vec![], &|_, _| false)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{
+    opts, register_int_counter, register_int_gauge, register_int_gauge_vec, IntCounter, IntGauge,
+    IntGaugeVec,
+};
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::{Code, Status};
+
+use crate::quota::parse_size;
+
+/*
+ * Free space monitoring, so pushes are refused with a clear error while storage is nearly full
+ * rather than failing part way through with an io error once it fills.
+ *
+ * The space available in the data dir and the dir uploads are written to is read every few
+ * seconds. While either has less than the reserve, new uploads and imports are refused with
+ * ResourceExhausted, marked as being for storage rather than a quota by the INSUFFICIENT_STORAGE
+ * metadata key, which the frontend turns into a 507 Insufficient Storage. Uploads already in
+ * progress carry on, so they can use the reserve to finish.
+ */
+
+/// Set on errors refusing an upload because storage is below the reserve
+pub const INSUFFICIENT_STORAGE: &str = "trow-insufficient-storage";
+
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    pub static ref STORAGE_AVAILABLE: IntGaugeVec = register_int_gauge_vec!(
+        opts!(
+            "storage_available_bytes",
+            "space available in bytes for the registry's directories, by directory"
+        ),
+        &["dir"]
+    )
+    .unwrap();
+    pub static ref STORAGE_RESERVE: IntGaugeVec = register_int_gauge_vec!(
+        opts!(
+            "storage_reserve_bytes",
+            "space in bytes kept free, below which uploads are refused, by directory"
+        ),
+        &["dir"]
+    )
+    .unwrap();
+    pub static ref BELOW_RESERVE: IntGauge = register_int_gauge!(
+        "storage_below_reserve",
+        "1 while storage is below the reserve and uploads are refused"
+    )
+    .unwrap();
+    pub static ref UPLOADS_REFUSED: IntCounter = register_int_counter!(
+        "uploads_refused_insufficient_storage_total",
+        "total number of uploads refused as storage was below the reserve"
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reserve {
+    Bytes(u64),
+    // Of the filesystem's total size
+    Percent(u64),
+}
+
+impl Reserve {
+    fn bytes(&self, total: u64) -> u64 {
+        match *self {
+            Reserve::Bytes(b) => b,
+            Reserve::Percent(p) => total / 100 * p,
+        }
+    }
+}
+
+impl FromStr for Reserve {
+    type Err = anyhow::Error;
+
+    // A size such as 10G, or a percentage of the filesystem such as 5%
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().strip_suffix('%') {
+            Some(p) => {
+                let p: u64 = p
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid percentage {}", s))?;
+                if p > 100 {
+                    return Err(anyhow!("Reserve {} is over 100%", s));
+                }
+                Ok(Reserve::Percent(p))
+            }
+            None => Ok(Reserve::Bytes(parse_size(s)?)),
+        }
+    }
+}
+
+pub struct Capacity {
+    reserve: Reserve,
+    // Each with its name for metrics and errors
+    dirs: Vec<(&'static str, PathBuf)>,
+    // Why uploads are refused, while below the reserve
+    low: RwLock<Option<String>>,
+}
+
+impl Capacity {
+    pub fn new(reserve: Reserve, dirs: Vec<(&'static str, PathBuf)>) -> Capacity {
+        Capacity {
+            reserve,
+            dirs,
+            low: RwLock::new(None),
+        }
+    }
+
+    /*
+     * Reads the space available again, updating the metrics and whether uploads are refused.
+     * Returns why they're refused, if they are.
+     */
+    pub fn check(&self) -> Option<String> {
+        let mut low = None;
+        for (name, path) in &self.dirs {
+            let space = fs3::available_space(path).and_then(|a| Ok((a, fs3::total_space(path)?)));
+            let (available, total) = match space {
+                Ok(space) => space,
+                Err(e) => {
+                    warn!("Failed to find free space in {:?}: {:?}", path, e);
+                    continue;
+                }
+            };
+            let reserve = self.reserve.bytes(total);
+            STORAGE_AVAILABLE
+                .with_label_values(&[name])
+                .set(available as i64);
+            STORAGE_RESERVE
+                .with_label_values(&[name])
+                .set(reserve as i64);
+            if available < reserve && low.is_none() {
+                low = Some(format!(
+                    "Registry storage is nearly full: {} bytes available for {}, below the reserve of {} bytes",
+                    available, name, reserve
+                ));
+            }
+        }
+        BELOW_RESERVE.set(low.is_some() as i64);
+
+        let mut current = self.low.write().unwrap();
+        match (&*current, &low) {
+            (None, Some(reason)) => warn!("{}, refusing uploads until space is freed", reason),
+            (Some(_), None) => info!("Registry storage is above the reserve again"),
+            _ => {}
+        }
+        *current = low.clone();
+        low
+    }
+
+    /// Fails with an insufficient storage error while below the reserve
+    pub fn check_upload(&self) -> Result<(), Status> {
+        match &*self.low.read().unwrap() {
+            Some(reason) => {
+                UPLOADS_REFUSED.inc();
+                Err(insufficient_storage(reason))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn insufficient_storage(reason: &str) -> Status {
+    let mut metadata = MetadataMap::new();
+    metadata.insert(INSUFFICIENT_STORAGE, MetadataValue::from_static("true"));
+    Status::with_metadata(Code::ResourceExhausted, reason, metadata)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Capacity, Reserve, INSUFFICIENT_STORAGE};
+    use tempfile::tempdir;
+
+    #[test]
+    fn parses_reserves() {
+        assert_eq!("10G".parse::<Reserve>().unwrap(), Reserve::Bytes(10 << 30));
+        assert_eq!("5%".parse::<Reserve>().unwrap(), Reserve::Percent(5));
+        assert!("101%".parse::<Reserve>().is_err());
+        assert!("lots".parse::<Reserve>().is_err());
+        assert_eq!(Reserve::Percent(5).bytes(1000), 50);
+    }
+
+    #[test]
+    fn refuses_uploads_below_reserve() {
+        let dir = tempdir().unwrap();
+        let capacity = Capacity::new(Reserve::Bytes(0), vec![("data", dir.path().to_path_buf())]);
+        assert!(capacity.check().is_none());
+        assert!(capacity.check_upload().is_ok());
+
+        // No filesystem has all of itself free
+        let capacity = Capacity::new(
+            Reserve::Percent(100),
+            vec![("data", dir.path().to_path_buf())],
+        );
+        assert!(capacity.check().unwrap().contains("for data"));
+        let status = capacity.check_upload().unwrap_err();
+        assert!(status.metadata().contains_key(INSUFFICIENT_STORAGE));
+
+        // Only refused once checked
+        let capacity = Capacity::new(
+            Reserve::Percent(100),
+            vec![("data", dir.path().to_path_buf())],
+        );
+        assert!(capacity.check_upload().is_ok());
+    }
+}
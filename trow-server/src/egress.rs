@@ -0,0 +1,233 @@
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use reqwest::{Proxy, Url};
+
+use crate::selector::glob_match;
+
+/*
+ * Proxies for outbound HTTP calls, such as to proxied registries and event webhooks.
+ *
+ * The standard HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY environment variables (or their
+ * lower case versions) are honoured. Rules can override them for particular upstream hosts,
+ * given as HOSTS=PROXY_URL or HOSTS=direct, where HOSTS can use *:
+ *
+ *   registry-1.docker.io=http://hub-proxy:3128   send Docker Hub traffic through its own proxy
+ *   *.internal.example.com=direct                 never use a proxy for internal hosts
+ *
+ * The first matching rule applies. Hosts without a rule use the environment.
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Route {
+    Direct,
+    Via(Url),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyRule {
+    hosts: String,
+    route: Route,
+}
+
+impl FromStr for ProxyRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (hosts, target) = s.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "Proxy rule {} should be of the form HOSTS=PROXY_URL or HOSTS=direct",
+                s
+            )
+        })?;
+        let hosts = hosts.trim();
+        if hosts.is_empty() {
+            return Err(anyhow!("Proxy rule {} has no host pattern", s));
+        }
+        let target = target.trim();
+        let route = if target == "direct" {
+            Route::Direct
+        } else {
+            Route::Via(
+                Url::parse(target)
+                    .map_err(|e| anyhow!("Invalid proxy URL {} in {}: {}", target, s, e))?,
+            )
+        };
+        Ok(ProxyRule {
+            hosts: hosts.to_lowercase(),
+            route,
+        })
+    }
+}
+
+impl fmt::Display for ProxyRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.route {
+            Route::Direct => write!(f, "{}=direct", self.hosts),
+            Route::Via(url) => write!(f, "{}={}", self.hosts, url),
+        }
+    }
+}
+
+// Proxies from the environment
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct EnvProxies {
+    http: Option<Url>,
+    https: Option<Url>,
+    no_proxy: Vec<String>,
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name)
+        .or_else(|_| env::var(name.to_lowercase()))
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+}
+
+impl EnvProxies {
+    fn from_vars(get: impl Fn(&str) -> Option<String>) -> EnvProxies {
+        let url = |name: &str| get(name).and_then(|v| Url::parse(v.trim()).ok());
+        let all = url("ALL_PROXY");
+        EnvProxies {
+            http: url("HTTP_PROXY").or_else(|| all.clone()),
+            https: url("HTTPS_PROXY").or(all),
+            no_proxy: get("NO_PROXY")
+                .map(|v| {
+                    v.split(',')
+                        .map(|h| h.trim().to_lowercase())
+                        .filter(|h| !h.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    // NO_PROXY entries match the host and its subdomains, * matches everything
+    fn excluded(&self, host: &str) -> bool {
+        self.no_proxy.iter().any(|entry| {
+            let domain = entry.trim_start_matches('.');
+            entry == "*" || host == domain || host.ends_with(&format!(".{}", domain))
+        })
+    }
+
+    fn proxy_for(&self, url: &Url) -> Option<Url> {
+        if self.excluded(url.host_str().unwrap_or_default()) {
+            return None;
+        }
+        match url.scheme() {
+            "https" => self.https.clone(),
+            "http" => self.http.clone(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EgressProxies {
+    rules: Arc<Vec<ProxyRule>>,
+    env: Arc<EnvProxies>,
+}
+
+impl EgressProxies {
+    pub fn new(rules: Vec<ProxyRule>) -> EgressProxies {
+        EgressProxies {
+            rules: Arc::new(rules),
+            env: Arc::new(EnvProxies::from_vars(env_var)),
+        }
+    }
+
+    /// The proxy to reach the URL through, None to connect directly
+    pub fn proxy_for(&self, url: &Url) -> Option<Url> {
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+        match self.rules.iter().find(|r| glob_match(&r.hosts, &host)) {
+            Some(ProxyRule {
+                route: Route::Via(proxy),
+                ..
+            }) => Some(proxy.clone()),
+            Some(ProxyRule {
+                route: Route::Direct,
+                ..
+            }) => None,
+            None => self.env.proxy_for(url),
+        }
+    }
+
+    /*
+     * For adding to HTTP clients, with ClientBuilder::proxy. This replaces reqwest's own use of
+     * the environment.
+     */
+    pub fn proxy(&self) -> Proxy {
+        let proxies = self.clone();
+        Proxy::custom(move |url| proxies.proxy_for(url))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EgressProxies, EnvProxies, ProxyRule};
+    use reqwest::Url;
+    use std::sync::Arc;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rule: ProxyRule = "registry-1.docker.io=http://proxy:3128".parse().unwrap();
+        assert_eq!(rule.to_string(), "registry-1.docker.io=http://proxy:3128/");
+        assert!("*.internal=direct".parse::<ProxyRule>().is_ok());
+        assert!("quay.io".parse::<ProxyRule>().is_err());
+        assert!("=direct".parse::<ProxyRule>().is_err());
+        assert!("quay.io=not a url".parse::<ProxyRule>().is_err());
+    }
+
+    #[test]
+    fn env_proxies() {
+        let env = EnvProxies::from_vars(|name| match name {
+            "HTTPS_PROXY" => Some("http://corp-proxy:8080".to_string()),
+            "NO_PROXY" => Some("localhost, .svc.cluster.local,example.com".to_string()),
+            _ => None,
+        });
+        assert_eq!(
+            env.proxy_for(&url("https://quay.io/v2/")),
+            Some(url("http://corp-proxy:8080"))
+        );
+        assert_eq!(env.proxy_for(&url("http://quay.io/v2/")), None);
+        assert_eq!(env.proxy_for(&url("https://localhost/")), None);
+        assert_eq!(
+            env.proxy_for(&url("https://trow.kube-public.svc.cluster.local/")),
+            None
+        );
+        assert_eq!(env.proxy_for(&url("https://api.example.com/")), None);
+        assert!(env.proxy_for(&url("https://notexample.com/")).is_some());
+    }
+
+    #[test]
+    fn rules_override_env() {
+        let proxies = EgressProxies {
+            rules: Arc::new(vec![
+                "registry-1.docker.io=http://hub-proxy:3128"
+                    .parse()
+                    .unwrap(),
+                "*.internal=direct".parse().unwrap(),
+            ]),
+            env: Arc::new(EnvProxies::from_vars(|name| match name {
+                "ALL_PROXY" => Some("http://corp-proxy:8080".to_string()),
+                _ => None,
+            })),
+        };
+        assert_eq!(
+            proxies.proxy_for(&url("https://registry-1.docker.io/v2/")),
+            Some(url("http://hub-proxy:3128"))
+        );
+        assert_eq!(proxies.proxy_for(&url("https://hooks.internal/")), None);
+        assert_eq!(
+            proxies.proxy_for(&url("https://quay.io/v2/")),
+            Some(url("http://corp-proxy:8080"))
+        );
+    }
+}
@@ -0,0 +1,1174 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, Result};
+use chrono::{TimeZone, Utc};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tar::{EntryType, Header};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::digest::sha256_tag_digest;
+use crate::jobs::JobHandle;
+use crate::maintenance::blob_path;
+use crate::transcode::{oci_media_type, Compression, Variant};
+
+/*
+ * eStargz versions of pushed layers, so clusters running stargz-snapshotter can start containers
+ * before their images are pulled, fetching files as they're read.
+ *
+ * An eStargz layer is still a gzipped tar, but each file's contents start a new gzip member, and
+ * a table of contents (the TOC) at the end records where. A client reads the TOC using the
+ * footer, the last FOOTER_SIZE bytes, then fetches the files it needs with Range requests. Large
+ * files are split into chunks of CHUNK_SIZE so they can be fetched a part at a time. See
+ * https://github.com/containerd/stargz-snapshotter/blob/main/docs/estargz.md.
+ *
+ * Layers are recorded as manifests using them are pushed, and the "estargz" job converts them.
+ * Converting changes the uncompressed tar, as it gains the TOC and a landmark file, so the image
+ * config is rewritten with the new diff IDs. A client asking for eStargz is given a copy of the
+ * manifest pointing at the converted layers and config, with the annotations stargz-snapshotter
+ * needs, stored with the digest of the original as for transcoded manifests. Layers already in
+ * eStargz, and those with extended attributes the TOC would lose, are left alone.
+ */
+
+static INDEX_FILE: &str = "estargz.json";
+static MANIFESTS_DIR: &str = "manifests";
+static TOC_NAME: &str = "stargz.index.json";
+static PREFETCH_LANDMARK: &str = ".prefetch.landmark";
+static NO_PREFETCH_LANDMARK: &str = ".no.prefetch.landmark";
+
+pub const TOC_DIGEST_ANNOTATION: &str = "containerd.io/snapshot/stargz/toc.digest";
+pub const UNCOMPRESSED_SIZE_ANNOTATION: &str = "io.containers.estargz.uncompressed-size";
+
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const OCI_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const OCI_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+const DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const DOCKER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+const CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+const FOOTER_SIZE: usize = 51;
+const BLOCK_SIZE: u64 = 512;
+// GNU long names and PAX headers, read whole
+const MAX_EXTENSION_SIZE: u64 = 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Converted {
+    pub digest: String,
+    pub size: u64,
+    // Digest of the uncompressed tar, for the image config
+    pub diff_id: String,
+    pub toc_digest: String,
+    pub uncompressed_size: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    // The converted version of each layer, by the digest of the original
+    layers: HashMap<String, Converted>,
+    // Layers pushed but not converted yet
+    pending: HashSet<String>,
+    // Layers that can't be converted, so aren't tried again
+    skipped: HashSet<String>,
+    // The digest of the original of each rewritten config, by the rewritten one's digest
+    configs: HashMap<String, String>,
+    // The digest of the original of each rewritten manifest, by the rewritten one's digest
+    manifests: HashMap<String, String>,
+}
+
+// Why a layer can't be converted
+#[derive(Error, Debug)]
+#[error("{0}")]
+struct Unconvertible(String);
+
+#[derive(Clone)]
+pub struct Estargz {
+    dir: PathBuf,
+    blobs_path: PathBuf,
+    index: Arc<RwLock<Index>>,
+}
+
+impl Estargz {
+    pub fn new(dir: &Path, blobs_path: &Path) -> Result<Estargz> {
+        fs::create_dir_all(dir.join(MANIFESTS_DIR))?;
+        let index = match fs::read(dir.join(INDEX_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Index::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Estargz {
+            dir: dir.to_path_buf(),
+            blobs_path: blobs_path.to_path_buf(),
+            index: Arc::new(RwLock::new(index)),
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        let bytes = serde_json::to_vec(&*self.index.read().unwrap())?;
+        let tmp_path = self.dir.join(Uuid::new_v4().to_string());
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, self.dir.join(INDEX_FILE))?;
+        Ok(())
+    }
+
+    /// Queues the layers of a pushed image manifest for the next estargz job
+    pub fn record_layers(&self, manifest: &Value) -> Result<()> {
+        let mut index = self.index.write().unwrap();
+        let mut added = false;
+        for layer in manifest
+            .get("layers")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let media_type = layer.get("mediaType").and_then(Value::as_str);
+            let digest = layer.get("digest").and_then(Value::as_str);
+            let already_estargz = layer
+                .get("annotations")
+                .and_then(|a| a.get(TOC_DIGEST_ANNOTATION))
+                .is_some();
+            let digest = match (media_type, digest) {
+                (Some(OCI_GZIP | DOCKER_GZIP | OCI_ZSTD), Some(d)) if !already_estargz => d,
+                _ => continue,
+            };
+            if index.layers.contains_key(digest) || index.skipped.contains(digest) {
+                continue;
+            }
+            added |= index.pending.insert(digest.to_string());
+        }
+        drop(index);
+        if added {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Where the converted layer or rewritten config with this digest is stored, if there is one
+    pub fn blob_path(&self, digest: &str) -> Option<PathBuf> {
+        let index = self.index.read().unwrap();
+        let known =
+            index.configs.contains_key(digest) || index.layers.values().any(|c| c.digest == digest);
+        if !known {
+            return None;
+        }
+        blob_path(&self.dir, digest)
+    }
+
+    /// Where the rewritten manifest with this digest is stored, with the original's digest
+    pub fn manifest_path(&self, digest: &str) -> Option<(PathBuf, String)> {
+        let original = self.index.read().unwrap().manifests.get(digest)?.clone();
+        let (_, hex) = digest.split_once(':')?;
+        Some((self.dir.join(MANIFESTS_DIR).join(hex), original))
+    }
+
+    // Stores the rewritten bytes under their digest, recording the original they came from
+    fn store(&self, bytes: &[u8], path_of: impl Fn(&str) -> Option<PathBuf>) -> Result<String> {
+        let digest = sha256_tag_digest(bytes)?;
+        let path = path_of(&digest).ok_or_else(|| anyhow!("Invalid digest {}", digest))?;
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let tmp_path = self.dir.join(Uuid::new_v4().to_string());
+            fs::write(&tmp_path, bytes)?;
+            fs::rename(&tmp_path, &path)?;
+        }
+        Ok(digest)
+    }
+
+    /*
+     * Points the layers of an image manifest at their converted versions, and the config at a
+     * copy with their diff IDs. None if no layer has been converted, or the manifest has
+     * something OCI has no media type for.
+     */
+    fn rewrite_manifest(&self, bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut manifest: Value = serde_json::from_slice(bytes)?;
+        let config_digest = match manifest
+            .get("config")
+            .and_then(|c| c.get("digest"))
+            .and_then(Value::as_str)
+        {
+            Some(d) => d.to_string(),
+            None => return Ok(None),
+        };
+        let layers = match manifest.get_mut("layers").and_then(Value::as_array_mut) {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+
+        let mut diff_ids = HashMap::new();
+        {
+            let index = self.index.read().unwrap();
+            for (i, layer) in layers.iter_mut().enumerate() {
+                let converted = layer
+                    .get("digest")
+                    .and_then(Value::as_str)
+                    .and_then(|d| index.layers.get(d));
+                let media_type = layer
+                    .get("mediaType")
+                    .and_then(Value::as_str)
+                    .map(str::to_string);
+                let layer = match layer.as_object_mut() {
+                    Some(l) => l,
+                    None => return Ok(None),
+                };
+                match (converted, media_type) {
+                    (Some(c), _) => {
+                        layer.insert("mediaType".to_string(), OCI_GZIP.into());
+                        layer.insert("digest".to_string(), c.digest.clone().into());
+                        layer.insert("size".to_string(), c.size.into());
+                        let mut annotations = serde_json::Map::new();
+                        annotations.insert(
+                            TOC_DIGEST_ANNOTATION.to_string(),
+                            c.toc_digest.clone().into(),
+                        );
+                        annotations.insert(
+                            UNCOMPRESSED_SIZE_ANNOTATION.to_string(),
+                            c.uncompressed_size.to_string().into(),
+                        );
+                        layer.insert("annotations".to_string(), annotations.into());
+                        diff_ids.insert(i, c.diff_id.clone());
+                    }
+                    (None, Some(t)) => match oci_media_type(&t) {
+                        Some(t) => {
+                            let t = t.to_string();
+                            layer.insert("mediaType".to_string(), t.into());
+                        }
+                        None => return Ok(None),
+                    },
+                    (None, None) => return Ok(None),
+                }
+            }
+        }
+        if diff_ids.is_empty() {
+            return Ok(None);
+        }
+        let layer_count = layers.len();
+
+        // The diff IDs are in the same order as the layers
+        let config_path = blob_path(&self.blobs_path, &config_digest)
+            .ok_or_else(|| anyhow!("Invalid digest {}", config_digest))?;
+        let mut config: Value = serde_json::from_slice(&fs::read(config_path)?)?;
+        let config_diff_ids = match config
+            .get_mut("rootfs")
+            .and_then(|r| r.get_mut("diff_ids"))
+            .and_then(Value::as_array_mut)
+        {
+            Some(d) if d.len() == layer_count => d,
+            _ => return Ok(None),
+        };
+        for (i, diff_id) in diff_ids {
+            config_diff_ids[i] = diff_id.into();
+        }
+        let config_bytes = serde_json::to_vec(&config)?;
+        let new_config = self.store(&config_bytes, |d| blob_path(&self.dir, d))?;
+        self.index
+            .write()
+            .unwrap()
+            .configs
+            .insert(new_config.clone(), config_digest);
+
+        let config = match manifest.get_mut("config").and_then(Value::as_object_mut) {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let media_type = match config
+            .get("mediaType")
+            .and_then(Value::as_str)
+            .and_then(oci_media_type)
+        {
+            Some(t) => t.to_string(),
+            None => return Ok(None),
+        };
+        config.insert("mediaType".to_string(), media_type.into());
+        config.insert("digest".to_string(), new_config.into());
+        config.insert("size".to_string(), config_bytes.len().into());
+        if let Some(fields) = manifest.as_object_mut() {
+            fields.insert("mediaType".to_string(), OCI_MANIFEST.into());
+        }
+        Ok(Some(serde_json::to_vec_pretty(&manifest)?))
+    }
+
+    /*
+     * Points the entries of an index at rewritten copies of their manifests. None if none of them
+     * could be rewritten.
+     */
+    fn rewrite_index(&self, bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut index: Value = serde_json::from_slice(bytes)?;
+        let entries = match index.get_mut("manifests").and_then(Value::as_array_mut) {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let mut changed = false;
+        for entry in entries {
+            let media_type = entry.get("mediaType").and_then(Value::as_str);
+            let digest = entry.get("digest").and_then(Value::as_str);
+            let (media_type, variant) = match (media_type, digest) {
+                (Some(t @ (OCI_MANIFEST | DOCKER_MANIFEST)), Some(d)) => {
+                    (t.to_string(), self.variant(d, t)?)
+                }
+                (Some(t), _) => (t.to_string(), None),
+                _ => return Ok(None),
+            };
+            let entry = match entry.as_object_mut() {
+                Some(e) => e,
+                None => return Ok(None),
+            };
+            match variant {
+                Some(v) => {
+                    entry.insert("mediaType".to_string(), OCI_MANIFEST.into());
+                    entry.insert("digest".to_string(), v.digest.into());
+                    entry.insert("size".to_string(), fs::metadata(&v.path)?.len().into());
+                    changed = true;
+                }
+                None => match oci_media_type(&media_type) {
+                    Some(t) => {
+                        let t = t.to_string();
+                        entry.insert("mediaType".to_string(), t.into());
+                    }
+                    None => return Ok(None),
+                },
+            }
+        }
+        if !changed {
+            return Ok(None);
+        }
+        if let Some(fields) = index.as_object_mut() {
+            fields.insert("mediaType".to_string(), OCI_INDEX.into());
+        }
+        Ok(Some(serde_json::to_vec_pretty(&index)?))
+    }
+
+    /*
+     * A copy of the stored manifest using the eStargz versions of its layers, or None if none of
+     * them have been converted. The copy is stored so it can be pulled by digest.
+     */
+    pub fn variant(&self, digest: &str, content_type: &str) -> Result<Option<Variant>> {
+        let path = blob_path(&self.blobs_path, digest)
+            .ok_or_else(|| anyhow!("Invalid digest {}", digest))?;
+        let bytes = fs::read(&path)?;
+        let rewritten = match content_type {
+            OCI_MANIFEST | DOCKER_MANIFEST => self.rewrite_manifest(&bytes)?,
+            OCI_INDEX | DOCKER_LIST => self.rewrite_index(&bytes)?,
+            _ => None,
+        };
+        let rewritten = match rewritten {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let content_type = match content_type {
+            OCI_INDEX | DOCKER_LIST => OCI_INDEX,
+            _ => OCI_MANIFEST,
+        };
+
+        let manifests_path = self.dir.join(MANIFESTS_DIR);
+        let variant_digest = self.store(&rewritten, |d| {
+            Some(manifests_path.join(d.split_once(':')?.1))
+        })?;
+        let added = self
+            .index
+            .write()
+            .unwrap()
+            .manifests
+            .insert(variant_digest.clone(), digest.to_string())
+            .is_none();
+        if added {
+            debug!("Rewrote {} as {} for eStargz", digest, variant_digest);
+            self.save()?;
+        }
+        let (_, hex) = variant_digest
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid digest {}", variant_digest))?;
+        Ok(Some(Variant {
+            path: manifests_path.join(hex),
+            digest: variant_digest,
+            content_type: content_type.to_string(),
+        }))
+    }
+
+    /*
+     * Converts the layers pushed since the last run, after removing the conversions of layers
+     * that have been deleted.
+     */
+    pub fn run_job(&self, handle: &JobHandle) -> Result<String> {
+        let removed = self.remove_deleted()?;
+
+        let pending: Vec<String> = self.index.read().unwrap().pending.iter().cloned().collect();
+        let (mut converted, mut skipped, mut failed) = (0, 0, 0);
+        for (i, digest) in pending.iter().enumerate() {
+            if handle.is_cancelled() {
+                break;
+            }
+            handle.set_progress(i, pending.len());
+            let path = match blob_path(&self.blobs_path, digest) {
+                Some(p) if p.exists() => p,
+                // Deleted before it was converted
+                _ => {
+                    self.index.write().unwrap().pending.remove(digest);
+                    continue;
+                }
+            };
+            match convert(&path, &self.dir) {
+                Ok(c) => {
+                    debug!("Converted {} to eStargz {}", digest, c.digest);
+                    let mut index = self.index.write().unwrap();
+                    index.pending.remove(digest);
+                    index.layers.insert(digest.clone(), c);
+                    converted += 1;
+                }
+                Err(e) => match e.downcast::<Unconvertible>() {
+                    Ok(reason) => {
+                        debug!("Not converting {} to eStargz: {}", digest, reason);
+                        let mut index = self.index.write().unwrap();
+                        index.pending.remove(digest);
+                        index.skipped.insert(digest.clone());
+                        skipped += 1;
+                    }
+                    // Left pending, to try again next time
+                    Err(e) => {
+                        warn!("Failed to convert layer {} to eStargz: {:?}", digest, e);
+                        failed += 1;
+                    }
+                },
+            }
+        }
+        self.save()?;
+
+        let summary = format!(
+            "Converted {} layers to eStargz, skipped {}, removed {} conversions of deleted layers",
+            converted, skipped, removed
+        );
+        info!("{}", summary);
+        if failed > 0 {
+            return Err(anyhow!(
+                "Failed to convert {} layers, see the logs. {}",
+                failed,
+                summary
+            ));
+        }
+        Ok(summary)
+    }
+
+    /// Removes conversions, configs and manifests whose originals no longer exist
+    fn remove_deleted(&self) -> Result<usize> {
+        let exists =
+            |digest: &str| blob_path(&self.blobs_path, digest).map_or(false, |p| p.exists());
+        let remove = |path: Option<PathBuf>| {
+            if let Some(p) = path {
+                let _ = fs::remove_file(p);
+            }
+        };
+        let mut index = self.index.write().unwrap();
+
+        let deleted: Vec<String> = index
+            .layers
+            .keys()
+            .filter(|d| !exists(d))
+            .cloned()
+            .collect();
+        let mut removed = vec![];
+        for digest in &deleted {
+            if let Some(c) = index.layers.remove(digest) {
+                remove(blob_path(&self.dir, &c.digest));
+                removed.push(c.digest);
+            }
+        }
+        index.skipped.retain(|d| exists(d));
+
+        let manifests_path = self.dir.join(MANIFESTS_DIR);
+        let path_of = |digest: &str| Some(manifests_path.join(digest.split_once(':')?.1));
+        // Including those using a conversion that's just been removed
+        let gone: Vec<String> = index
+            .manifests
+            .iter()
+            .filter(|(variant, original)| {
+                let uses_removed = path_of(variant)
+                    .and_then(|p| fs::read_to_string(p).ok())
+                    .map_or(true, |m| removed.iter().any(|r| m.contains(r.as_str())));
+                !exists(original) || uses_removed
+            })
+            .map(|(variant, _)| variant.clone())
+            .collect();
+        for digest in gone {
+            index.manifests.remove(&digest);
+            remove(path_of(&digest));
+        }
+        let configs: Vec<String> = index
+            .configs
+            .iter()
+            .filter(|(_, original)| !exists(original))
+            .map(|(config, _)| config.clone())
+            .collect();
+        for digest in configs {
+            index.configs.remove(&digest);
+            remove(blob_path(&self.dir, &digest));
+        }
+        Ok(deleted.len())
+    }
+}
+
+// An entry in the TOC, as stargz-snapshotter reads it
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TocEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "is_zero")]
+    size: u64,
+    #[serde(rename = "modtime", skip_serializing_if = "String::is_empty")]
+    mod_time: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    link_name: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    mode: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    uid: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    gid: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    user_name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    group_name: String,
+    // Where the gzip member with the chunk starts
+    #[serde(skip_serializing_if = "is_zero")]
+    offset: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    dev_major: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    dev_minor: u64,
+    // Of the whole file, on its first chunk
+    #[serde(skip_serializing_if = "String::is_empty")]
+    digest: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    chunk_offset: u64,
+    // Left out for the last chunk, which is the rest of the file
+    #[serde(skip_serializing_if = "is_zero")]
+    chunk_size: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    chunk_digest: String,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+#[derive(Serialize)]
+struct Toc {
+    version: u32,
+    entries: Vec<TocEntry>,
+}
+
+struct Counter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/*
+ * Writes the layer a gzip member at a time. The uncompressed tar is hashed as it's written, for
+ * the diff ID.
+ */
+struct LayerWriter {
+    out: Option<Counter<BufWriter<File>>>,
+    gz: Option<GzEncoder<Counter<BufWriter<File>>>>,
+    diff: Sha256,
+    uncompressed: u64,
+}
+
+impl LayerWriter {
+    // Ends the current gzip member, so the next write starts a new one
+    fn end_member(&mut self) -> io::Result<()> {
+        if let Some(gz) = self.gz.take() {
+            self.out = Some(gz.finish()?);
+        }
+        Ok(())
+    }
+
+    // Where the next gzip member will start, once the current one has ended
+    fn offset(&self) -> u64 {
+        self.out.as_ref().map_or(0, |o| o.written)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.gz.is_none() {
+            let out = self.out.take().expect("Layer writer has no output");
+            self.gz = Some(GzEncoder::new(out, flate2::Compression::default()));
+        }
+        if let Some(gz) = &mut self.gz {
+            gz.write_all(bytes)?;
+        }
+        self.diff.update(bytes);
+        self.uncompressed += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn pad(&mut self, size: u64) -> io::Result<()> {
+        let padding = (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE;
+        self.write(&vec![0; padding as usize])
+    }
+
+    // Writes a regular file's contents a chunk at a time, adding its entries to the TOC
+    fn write_file(
+        &mut self,
+        mut entry: TocEntry,
+        contents: &mut impl Read,
+        toc: &mut Vec<TocEntry>,
+    ) -> io::Result<()> {
+        let size = entry.size;
+        let mut whole = Sha256::new();
+        let mut written = 0;
+        let mut chunk = vec![];
+        while written < size {
+            let len = CHUNK_SIZE.min(size - written);
+            chunk.clear();
+            contents.by_ref().take(len).read_to_end(&mut chunk)?;
+            if chunk.len() as u64 != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.end_member()?;
+            entry.offset = self.offset();
+            entry.chunk_offset = written;
+            if len == CHUNK_SIZE && size > CHUNK_SIZE {
+                entry.chunk_size = len;
+            }
+            entry.chunk_digest = format!("sha256:{}", hex::encode(Sha256::digest(&chunk)));
+            self.write(&chunk)?;
+            whole.update(&chunk);
+            toc.push(entry);
+            written += len;
+            entry = TocEntry {
+                name: toc[toc.len() - 1].name.clone(),
+                kind: "chunk",
+                ..TocEntry::default()
+            };
+        }
+        // The first chunk has the whole file's digest
+        if let Some(first) = toc.iter_mut().rev().find(|e| e.kind == "reg") {
+            first.digest = format!("sha256:{}", hex::encode(whole.finalize()));
+        }
+        self.pad(size)
+    }
+
+    // The file's tar header and contents
+    fn append_regular(
+        &mut self,
+        name: &str,
+        contents: &[u8],
+        toc: &mut Vec<TocEntry>,
+    ) -> Result<()> {
+        let mut header = Header::new_ustar();
+        header.set_path(name)?;
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_entry_type(EntryType::Regular);
+        header.set_cksum();
+        self.write(header.as_bytes())?;
+        let entry = TocEntry {
+            name: name.to_string(),
+            kind: "reg",
+            size: contents.len() as u64,
+            mode: 0o644,
+            ..TocEntry::default()
+        };
+        self.write_file(entry, &mut &contents[..], toc)?;
+        Ok(())
+    }
+}
+
+/*
+ * The last gzip member, empty but with the offset of the TOC in an extra field, so it's always
+ * FOOTER_SIZE bytes and can be found from the end of the layer.
+ */
+fn footer(toc_offset: u64) -> Vec<u8> {
+    let subfield = format!("{:016x}STARGZ", toc_offset);
+    let mut extra = vec![b'S', b'G'];
+    extra.extend((subfield.len() as u16).to_le_bytes());
+    extra.extend(subfield.as_bytes());
+
+    // Header with the extra flag, no mtime and an unknown OS
+    let mut footer = vec![0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff];
+    footer.extend((extra.len() as u16).to_le_bytes());
+    footer.extend(extra);
+    // A final, empty stored block, then the CRC and size of no data
+    footer.extend([1, 0, 0, 0xff, 0xff]);
+    footer.extend([0; 8]);
+    footer
+}
+
+// The key=value records of a PAX header
+fn pax_records(data: &[u8]) -> Vec<(String, String)> {
+    let mut records = vec![];
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|b| *b == b' ') {
+        let len: usize = match std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|l| l.parse().ok())
+        {
+            Some(l) if l > space && l <= rest.len() => l,
+            _ => break,
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..len]);
+        if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+            records.push((key.to_string(), value.to_string()));
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+// As stargz-snapshotter names entries, without a leading / or ./ or a trailing /
+fn clean_name(name: &str) -> String {
+    name.split('/')
+        .filter(|p| !p.is_empty() && *p != ".")
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+fn null_terminated(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+/*
+ * Converts a gzip or zstd layer to eStargz, storing it in dir by its digest. Fails with
+ * Unconvertible if it's neither, or has something the TOC can't describe.
+ */
+fn convert(src: &Path, dir: &Path) -> Result<Converted> {
+    let input = BufReader::new(File::open(src)?);
+    let decoder: Box<dyn Read> = match Compression::sniff(src)? {
+        Some(Compression::Gzip) => Box::new(MultiGzDecoder::new(input)),
+        Some(Compression::Zstd) => Box::new(zstd::stream::read::Decoder::new(input)?),
+        None => return Err(Unconvertible("not a gzip or zstd layer".to_string()).into()),
+    };
+
+    let tmp_path = dir.join(Uuid::new_v4().to_string());
+    let res =
+        write_estargz(decoder, &tmp_path).and_then(|(toc_digest, diff_id, uncompressed_size)| {
+            let digest = sha256_tag_digest(BufReader::new(File::open(&tmp_path)?))?;
+            let size = fs::metadata(&tmp_path)?.len();
+            let path =
+                blob_path(dir, &digest).ok_or_else(|| anyhow!("Invalid digest {}", digest))?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&tmp_path, &path)?;
+            Ok(Converted {
+                digest,
+                size,
+                diff_id,
+                toc_digest,
+                uncompressed_size,
+            })
+        });
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+// Returns the TOC digest, diff ID and uncompressed size
+fn write_estargz(tar: impl Read, path: &Path) -> Result<(String, String, u64)> {
+    let mut writer = LayerWriter {
+        out: Some(Counter {
+            inner: BufWriter::new(File::create(path)?),
+            written: 0,
+        }),
+        gz: None,
+        diff: Sha256::new(),
+        uncompressed: 0,
+    };
+    let mut toc = vec![];
+    // Nothing is prefetched
+    writer.append_regular(NO_PREFETCH_LANDMARK, &[0xf], &mut toc)?;
+
+    let mut archive = tar::Archive::new(tar);
+    // Long names and PAX headers set the name of the entry after them
+    let (mut long_name, mut long_link) = (None, None);
+    let mut pax: Vec<(String, String)> = vec![];
+    for entry in archive.entries()?.raw(true) {
+        let mut entry = entry?;
+        let header = entry.header().clone();
+        let size = entry.size();
+        let kind = header.entry_type();
+
+        if matches!(
+            kind,
+            EntryType::GNULongName
+                | EntryType::GNULongLink
+                | EntryType::XHeader
+                | EntryType::XGlobalHeader
+        ) {
+            if size > MAX_EXTENSION_SIZE {
+                return Err(Unconvertible(format!("has a {} byte tar extension", size)).into());
+            }
+            let mut data = vec![];
+            (&mut entry).take(size).read_to_end(&mut data)?;
+            match kind {
+                EntryType::GNULongName => long_name = Some(null_terminated(&data)),
+                EntryType::GNULongLink => long_link = Some(null_terminated(&data)),
+                EntryType::XHeader => pax.extend(pax_records(&data)),
+                _ => {}
+            }
+            writer.write(header.as_bytes())?;
+            writer.write(&data)?;
+            writer.pad(size)?;
+            continue;
+        }
+
+        if let Some((key, _)) = pax.iter().find(|(k, _)| {
+            k.starts_with("SCHILY.xattr.") || k == "size" || k.starts_with("GNU.sparse")
+        }) {
+            return Err(Unconvertible(format!("has a {} PAX record", key)).into());
+        }
+        let pax_value = |key: &str| {
+            pax.iter()
+                .rev()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.clone())
+        };
+        let name = pax_value("path")
+            .or_else(|| long_name.take())
+            .unwrap_or_else(|| String::from_utf8_lossy(&header.path_bytes()).to_string());
+        let link_name = pax_value("linkpath")
+            .or_else(|| long_link.take())
+            .or_else(|| {
+                header
+                    .link_name_bytes()
+                    .map(|l| String::from_utf8_lossy(&l).to_string())
+            })
+            .unwrap_or_default();
+        pax.clear();
+        let name = clean_name(&name);
+
+        let toc_kind = match kind {
+            EntryType::Regular | EntryType::Continuous => "reg",
+            EntryType::Directory => "dir",
+            EntryType::Symlink => "symlink",
+            EntryType::Link => "hardlink",
+            EntryType::Char => "char",
+            EntryType::Block => "block",
+            EntryType::Fifo => "fifo",
+            other => return Err(Unconvertible(format!("has a {:?} tar entry", other)).into()),
+        };
+        // From an eStargz layer converted before
+        if name == TOC_NAME || name == PREFETCH_LANDMARK || name == NO_PREFETCH_LANDMARK {
+            continue;
+        }
+
+        let toc_entry = TocEntry {
+            name,
+            kind: toc_kind,
+            size: if toc_kind == "reg" { size } else { 0 },
+            mod_time: header
+                .mtime()
+                .ok()
+                .and_then(|t| Utc.timestamp_opt(t as i64, 0).single())
+                .map(|t| t.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+                .unwrap_or_default(),
+            link_name: if matches!(toc_kind, "symlink" | "hardlink") {
+                clean_link(toc_kind, &link_name)
+            } else {
+                String::new()
+            },
+            mode: header.mode().unwrap_or(0) as u64,
+            uid: header.uid().unwrap_or(0),
+            gid: header.gid().unwrap_or(0),
+            user_name: header
+                .username()
+                .ok()
+                .flatten()
+                .unwrap_or_default()
+                .to_string(),
+            group_name: header
+                .groupname()
+                .ok()
+                .flatten()
+                .unwrap_or_default()
+                .to_string(),
+            dev_major: header.device_major().ok().flatten().unwrap_or(0) as u64,
+            dev_minor: header.device_minor().ok().flatten().unwrap_or(0) as u64,
+            ..TocEntry::default()
+        };
+        writer.write(header.as_bytes())?;
+        if toc_kind == "reg" && size > 0 {
+            writer.write_file(toc_entry, &mut entry, &mut toc)?;
+        } else {
+            if size > 0 {
+                return Err(Unconvertible(format!("has a {} with contents", toc_kind)).into());
+            }
+            toc.push(toc_entry);
+        }
+    }
+
+    // The TOC, in a tar of its own, ending the archive
+    writer.end_member()?;
+    let toc_offset = writer.offset();
+    let toc_json = serde_json::to_vec_pretty(&Toc {
+        version: 1,
+        entries: toc,
+    })?;
+    let mut header = Header::new_ustar();
+    header.set_path(TOC_NAME)?;
+    header.set_size(toc_json.len() as u64);
+    header.set_mode(0o444);
+    header.set_entry_type(EntryType::Regular);
+    header.set_cksum();
+    writer.write(header.as_bytes())?;
+    writer.write(&toc_json)?;
+    writer.pad(toc_json.len() as u64)?;
+    writer.write(&[0; 2 * BLOCK_SIZE as usize])?;
+    writer.end_member()?;
+
+    let mut out = writer.out.take().expect("Layer writer has no output");
+    out.write_all(&footer(toc_offset))?;
+    let file = out.inner.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+
+    let toc_digest = format!("sha256:{}", hex::encode(Sha256::digest(&toc_json)));
+    let diff_id = format!("sha256:{}", hex::encode(writer.diff.finalize()));
+    Ok((toc_digest, diff_id, writer.uncompressed))
+}
+
+// Hard links are to other entries, so are named the same way. Symlinks are left as they are.
+fn clean_link(kind: &str, link_name: &str) -> String {
+    if kind == "hardlink" {
+        clean_name(link_name)
+    } else {
+        link_name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        footer, Estargz, FOOTER_SIZE, TOC_DIGEST_ANNOTATION, UNCOMPRESSED_SIZE_ANNOTATION,
+    };
+    use crate::digest::sha256_tag_digest;
+    use crate::jobs::{Job, JobKind, Jobs};
+    use crate::maintenance::blob_path;
+    use flate2::read::{GzDecoder, MultiGzDecoder};
+    use flate2::write::GzEncoder;
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::path::Path;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn store(blobs: &Path, bytes: &[u8]) -> String {
+        let digest = sha256_tag_digest(bytes).unwrap();
+        let path = blob_path(blobs, &digest).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, bytes).unwrap();
+        digest
+    }
+
+    async fn run(jobs: &Jobs, estargz: &Estargz) -> Job {
+        let e = estargz.clone();
+        let job = jobs.start(JobKind::Estargz, move |h| e.run_job(h));
+        loop {
+            let job = jobs.get(&job.id).unwrap();
+            if job.finished.is_some() {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn layer() -> Vec<u8> {
+        let mut tar = tar::Builder::new(vec![]);
+        let mut dir = tar::Header::new_gnu();
+        dir.set_entry_type(tar::EntryType::Directory);
+        dir.set_mode(0o755);
+        dir.set_size(0);
+        tar.append_data(&mut dir, "etc/", &[][..]).unwrap();
+        let mut file = tar::Header::new_gnu();
+        file.set_mode(0o644);
+        file.set_size(11);
+        tar.append_data(&mut file, "etc/hostname", &b"trow-server"[..])
+            .unwrap();
+        let mut big = tar::Header::new_gnu();
+        let contents = vec![7u8; 5 * 1024 * 1024];
+        big.set_mode(0o644);
+        big.set_size(contents.len() as u64);
+        tar.append_data(&mut big, "usr/lib/a-library-with-a-name-well-over-one-hundred-characters-long-so-it-needs-a-gnu-long-name-entry.so", &contents[..])
+            .unwrap();
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        std::io::copy(&mut &tar.into_inner().unwrap()[..], &mut encoder).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn footer_is_fixed_size() {
+        let footer = footer(0x1234);
+        assert_eq!(footer.len(), FOOTER_SIZE);
+        // A valid, empty gzip member
+        let mut contents = vec![];
+        GzDecoder::new(&footer[..])
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert!(contents.is_empty());
+        assert_eq!(&footer[16..38], b"0000000000001234STARGZ");
+    }
+
+    #[tokio::test]
+    async fn converts_pushed_layers() {
+        let dir = tempdir().unwrap();
+        let blobs = dir.path().join("blobs");
+        let layer_bytes = layer();
+        let layer = store(&blobs, &layer_bytes);
+        let original_diff_id = {
+            let mut tar = vec![];
+            MultiGzDecoder::new(&layer_bytes[..])
+                .read_to_end(&mut tar)
+                .unwrap();
+            format!("sha256:{}", hex::encode(Sha256::digest(&tar)))
+        };
+        let config = store(
+            &blobs,
+            json!({"architecture": "amd64", "os": "linux", "rootfs": {"type": "layers", "diff_ids": [original_diff_id]}})
+                .to_string()
+                .as_bytes(),
+        );
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": {"mediaType": "application/vnd.docker.container.image.v1+json", "size": 1, "digest": config},
+            "layers": [{"mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "size": layer_bytes.len(), "digest": layer}]
+        });
+        let manifest_digest = store(&blobs, manifest.to_string().as_bytes());
+        let docker_manifest = "application/vnd.docker.distribution.manifest.v2+json";
+
+        let estargz = Estargz::new(&dir.path().join("estargz"), &blobs).unwrap();
+        assert!(estargz
+            .variant(&manifest_digest, docker_manifest)
+            .unwrap()
+            .is_none());
+        estargz.record_layers(&manifest).unwrap();
+        let jobs = Jobs::new();
+        let job = run(&jobs, &estargz).await;
+        assert!(
+            job.message.starts_with("Converted 1 layers"),
+            "{}",
+            job.message
+        );
+
+        let variant = estargz
+            .variant(&manifest_digest, docker_manifest)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            variant.content_type,
+            "application/vnd.oci.image.manifest.v1+json"
+        );
+        let rewritten: Value = serde_json::from_slice(&fs::read(&variant.path).unwrap()).unwrap();
+        let converted = rewritten["layers"][0]["digest"].as_str().unwrap();
+        let annotations = &rewritten["layers"][0]["annotations"];
+        let layer_path = estargz.blob_path(converted).unwrap();
+        let converted_bytes = fs::read(&layer_path).unwrap();
+        assert_eq!(rewritten["layers"][0]["size"], converted_bytes.len());
+
+        // Still a gzipped tar of the same files, with the TOC and landmark added
+        let mut tar = vec![];
+        MultiGzDecoder::new(&converted_bytes[..])
+            .read_to_end(&mut tar)
+            .unwrap();
+        assert_eq!(
+            annotations[UNCOMPRESSED_SIZE_ANNOTATION],
+            tar.len().to_string()
+        );
+        let mut names = vec![];
+        for entry in tar::Archive::new(&tar[..]).entries().unwrap() {
+            names.push(entry.unwrap().path().unwrap().to_string_lossy().to_string());
+        }
+        assert_eq!(names[0], ".no.prefetch.landmark");
+        assert_eq!(names[2], "etc/hostname");
+        assert!(names[3].ends_with("gnu-long-name-entry.so"));
+        assert_eq!(names[4], "stargz.index.json");
+
+        // The rewritten config has the new diff ID
+        let config_digest = rewritten["config"]["digest"].as_str().unwrap();
+        let config: Value =
+            serde_json::from_slice(&fs::read(estargz.blob_path(config_digest).unwrap()).unwrap())
+                .unwrap();
+        assert_eq!(
+            config["rootfs"]["diff_ids"][0],
+            format!("sha256:{}", hex::encode(Sha256::digest(&tar)))
+        );
+
+        // The footer leads to the TOC, which leads to each file's contents
+        let footer = &converted_bytes[converted_bytes.len() - 51..];
+        let toc_offset =
+            u64::from_str_radix(std::str::from_utf8(&footer[16..32]).unwrap(), 16).unwrap();
+        let mut toc_tar = vec![];
+        GzDecoder::new(&converted_bytes[toc_offset as usize..])
+            .read_to_end(&mut toc_tar)
+            .unwrap();
+        let mut toc_entry = tar::Archive::new(&toc_tar[..]);
+        let mut toc_json = vec![];
+        toc_entry
+            .entries()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut toc_json)
+            .unwrap();
+        assert_eq!(
+            annotations[TOC_DIGEST_ANNOTATION],
+            format!("sha256:{}", hex::encode(Sha256::digest(&toc_json)))
+        );
+        let toc: Value = serde_json::from_slice(&toc_json).unwrap();
+        let entries = toc["entries"].as_array().unwrap();
+        let hostname = entries
+            .iter()
+            .find(|e| e["name"] == "etc/hostname")
+            .unwrap();
+        let mut file = fs::File::open(&layer_path).unwrap();
+        file.seek(SeekFrom::Start(hostname["offset"].as_u64().unwrap()))
+            .unwrap();
+        let mut contents = vec![0; 11];
+        GzDecoder::new(file).read_exact(&mut contents).unwrap();
+        assert_eq!(contents, b"trow-server");
+        // The library is in two chunks
+        let chunks: Vec<&Value> = entries
+            .iter()
+            .filter(|e| e["name"].as_str().unwrap().ends_with(".so"))
+            .collect();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0]["type"], "reg");
+        assert_eq!(chunks[0]["chunkSize"], 4 * 1024 * 1024);
+        assert_eq!(chunks[1]["type"], "chunk");
+        assert_eq!(chunks[1]["chunkOffset"], 4 * 1024 * 1024);
+        assert!(chunks[1].get("chunkSize").is_none());
+
+        // Garbage collected
+        fs::remove_file(blob_path(&blobs, &layer).unwrap()).unwrap();
+        assert_eq!(estargz.remove_deleted().unwrap(), 1);
+        assert!(estargz.blob_path(converted).is_none());
+        assert!(estargz.manifest_path(&variant.digest).is_none());
+    }
+}
@@ -0,0 +1,455 @@
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{SecondsFormat, Utc};
+use log::{debug, info, warn};
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+use crate::egress::EgressProxies;
+
+/*
+ * Registry events (pushes, deletes and prewarms) published to external systems.
+ *
+ * Sinks are given as URLs:
+ *
+ *  - http(s)://host/path - POSTs each event to the URL
+ *  - nats://host:port/subject - publishes to the NATS subject (needs the "nats" feature)
+ *  - kafka://host:port/topic - produces to the Kafka topic (needs the "kafka" feature)
+ *
+ * Events are delivered on a background thread in the order they happened. Delivery is best
+ * effort; failures are logged and the event dropped, so a slow or broken sink never holds up a
+ * push.
+ *
+ * Pulls and blobs freed by garbage collection are events too, but only for those watching the
+ * registry live through WatchEvents, as there would be far too many for the sinks. Watchers that
+ * fall more than LIVE_BUFFER events behind miss some.
+ *
+ * Prewarms are asked for through the prewarm API once an image is in the registry, so agents on
+ * the nodes listening for them can pull it before a rollout needs it.
+ */
+
+const SINK_TIMEOUT: Duration = Duration::from_secs(5);
+const LIVE_BUFFER: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventAction {
+    Push,
+    Pull,
+    Delete,
+    Gc,
+    Prewarm,
+}
+
+impl EventAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventAction::Push => "push",
+            EventAction::Pull => "pull",
+            EventAction::Delete => "delete",
+            EventAction::Gc => "gc",
+            EventAction::Prewarm => "prewarm",
+        }
+    }
+
+    // Whether the event goes to the sinks as well as watchers
+    fn is_published(&self) -> bool {
+        matches!(
+            self,
+            EventAction::Push | EventAction::Delete | EventAction::Prewarm
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    pub id: String,
+    pub action: EventAction,
+    // Blank for gc, as blobs aren't in a repository
+    pub repository: String,
+    // Not set for deletes, which are always by digest, or pulls by digest
+    pub tag: Option<String>,
+    pub digest: String,
+    pub timestamp: String,
+}
+
+impl Event {
+    pub fn new(action: EventAction, repository: &str, tag: Option<&str>, digest: &str) -> Event {
+        Event {
+            id: Uuid::new_v4().to_string(),
+            action,
+            repository: repository.to_string(),
+            tag: tag.map(|t| t.to_string()),
+            digest: digest.to_string(),
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventFormat {
+    // The Event struct as JSON
+    Json,
+    // CloudEvents 1.0 structured mode, with the Event struct as the data
+    CloudEvents,
+}
+
+impl FromStr for EventFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(EventFormat::Json),
+            "cloudevents" => Ok(EventFormat::CloudEvents),
+            _ => Err(anyhow!(
+                "Unknown event format {}, expected json or cloudevents",
+                s
+            )),
+        }
+    }
+}
+
+impl EventFormat {
+    pub fn serialize(&self, event: &Event) -> Vec<u8> {
+        let value = match self {
+            EventFormat::Json => json!(event),
+            EventFormat::CloudEvents => {
+                json!({
+                    "specversion": "1.0",
+                    "type": format!("io.trow.manifest.{}", event.action.as_str()),
+                    "source": format!("/trow/{}", event.repository),
+                    "id": event.id,
+                    "time": event.timestamp,
+                    "datacontenttype": "application/json",
+                    "data": event,
+                })
+            }
+        };
+        value.to_string().into_bytes()
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            EventFormat::Json => "application/json",
+            EventFormat::CloudEvents => "application/cloudevents+json",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SinkConfig {
+    Webhook(Url),
+    Nats { server: String, subject: String },
+    Kafka { hosts: Vec<String>, topic: String },
+}
+
+impl FromStr for SinkConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let url = Url::parse(s).map_err(|e| anyhow!("Invalid event sink {}: {}", s, e))?;
+        let host_port = || -> Result<String> {
+            let host = url
+                .host_str()
+                .ok_or_else(|| anyhow!("Event sink {} has no host", s))?;
+            Ok(match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            })
+        };
+        let name = url.path().trim_start_matches('/').to_string();
+
+        match url.scheme() {
+            "http" | "https" => Ok(SinkConfig::Webhook(url.clone())),
+            "nats" if cfg!(feature = "nats") => {
+                if name.is_empty() {
+                    return Err(anyhow!("NATS event sink {} needs a subject", s));
+                }
+                Ok(SinkConfig::Nats {
+                    server: format!("nats://{}", host_port()?),
+                    subject: name,
+                })
+            }
+            "kafka" if cfg!(feature = "kafka") => {
+                if name.is_empty() {
+                    return Err(anyhow!("Kafka event sink {} needs a topic", s));
+                }
+                Ok(SinkConfig::Kafka {
+                    hosts: vec![host_port()?],
+                    topic: name,
+                })
+            }
+            "nats" | "kafka" => Err(anyhow!(
+                "Trow was built without {} support, rebuild with --features {}",
+                url.scheme(),
+                url.scheme()
+            )),
+            other => Err(anyhow!("Unsupported event sink type {}", other)),
+        }
+    }
+}
+
+trait EventSink {
+    // Key is used for partitioning where the sink supports it
+    fn publish(&mut self, key: &str, payload: &[u8]) -> Result<()>;
+}
+
+struct Webhook {
+    url: Url,
+    content_type: &'static str,
+    client: reqwest::blocking::Client,
+}
+
+impl EventSink for Webhook {
+    fn publish(&mut self, _key: &str, payload: &[u8]) -> Result<()> {
+        self.client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, self.content_type)
+            .body(payload.to_vec())
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+// Connections are made on first use and dropped on error, so a sink that is down at startup
+// or restarts later gets picked up again.
+
+#[cfg(feature = "nats")]
+struct Nats {
+    server: String,
+    subject: String,
+    conn: Option<nats::Connection>,
+}
+
+#[cfg(feature = "nats")]
+impl EventSink for Nats {
+    fn publish(&mut self, _key: &str, payload: &[u8]) -> Result<()> {
+        if self.conn.is_none() {
+            self.conn = Some(nats::connect(self.server.as_str())?);
+        }
+        let res = self.conn.as_ref().unwrap().publish(&self.subject, payload);
+        if res.is_err() {
+            self.conn = None;
+        }
+        Ok(res?)
+    }
+}
+
+#[cfg(feature = "kafka")]
+struct Kafka {
+    hosts: Vec<String>,
+    topic: String,
+    producer: Option<kafka::producer::Producer>,
+}
+
+#[cfg(feature = "kafka")]
+impl EventSink for Kafka {
+    fn publish(&mut self, key: &str, payload: &[u8]) -> Result<()> {
+        use kafka::producer::{Producer, Record, RequiredAcks};
+
+        if self.producer.is_none() {
+            let producer = Producer::from_hosts(self.hosts.clone())
+                .with_ack_timeout(SINK_TIMEOUT)
+                .with_required_acks(RequiredAcks::One)
+                .create()?;
+            self.producer = Some(producer);
+        }
+        let record = Record::from_key_value(&self.topic, key.as_bytes(), payload);
+        let res = self.producer.as_mut().unwrap().send(&record);
+        if res.is_err() {
+            self.producer = None;
+        }
+        Ok(res?)
+    }
+}
+
+fn create_sink(
+    config: &SinkConfig,
+    format: EventFormat,
+    egress: &EgressProxies,
+) -> Result<Box<dyn EventSink>> {
+    match config {
+        SinkConfig::Webhook(url) => Ok(Box::new(Webhook {
+            url: url.clone(),
+            content_type: format.content_type(),
+            client: reqwest::blocking::Client::builder()
+                .timeout(SINK_TIMEOUT)
+                .proxy(egress.proxy())
+                .build()?,
+        })),
+        #[cfg(feature = "nats")]
+        SinkConfig::Nats { server, subject } => Ok(Box::new(Nats {
+            server: server.clone(),
+            subject: subject.clone(),
+            conn: None,
+        })),
+        #[cfg(feature = "kafka")]
+        SinkConfig::Kafka { hosts, topic } => Ok(Box::new(Kafka {
+            hosts: hosts.clone(),
+            topic: topic.clone(),
+            producer: None,
+        })),
+        #[allow(unreachable_patterns)]
+        _ => Err(anyhow!("Unsupported event sink {:?}", config)),
+    }
+}
+
+/*
+ * Handle for publishing events, cheap to clone.
+ *
+ * Only sends events to watchers if no sinks are configured.
+ */
+#[derive(Clone)]
+pub struct EventPublisher {
+    tx: Option<mpsc::UnboundedSender<Event>>,
+    live: broadcast::Sender<Event>,
+}
+
+impl Default for EventPublisher {
+    fn default() -> Self {
+        EventPublisher {
+            tx: None,
+            live: broadcast::channel(LIVE_BUFFER).0,
+        }
+    }
+}
+
+impl EventPublisher {
+    pub fn new(
+        sinks: Vec<SinkConfig>,
+        format: EventFormat,
+        egress: EgressProxies,
+    ) -> Result<EventPublisher> {
+        if sinks.is_empty() {
+            return Ok(EventPublisher::default());
+        }
+        let (tx, mut rx) = mpsc::unbounded_channel::<Event>();
+
+        thread::Builder::new()
+            .name("trow-events".to_string())
+            .spawn(move || {
+                // Created here as the blocking HTTP client can't live on the async runtime
+                let mut sinks: Vec<(SinkConfig, Box<dyn EventSink>)> = sinks
+                    .into_iter()
+                    .filter_map(|c| match create_sink(&c, format, &egress) {
+                        Ok(s) => Some((c, s)),
+                        Err(e) => {
+                            warn!("Failed to create event sink {:?}: {:?}", c, e);
+                            None
+                        }
+                    })
+                    .collect();
+                info!("Publishing registry events to {} sinks", sinks.len());
+
+                while let Some(event) = rx.blocking_recv() {
+                    let payload = format.serialize(&event);
+                    for (config, sink) in sinks.iter_mut() {
+                        match sink.publish(&event.repository, &payload) {
+                            Ok(_) => debug!("Sent event {} to {:?}", event.id, config),
+                            Err(e) => {
+                                warn!("Failed to send event {} to {:?}: {:?}", event.id, config, e)
+                            }
+                        }
+                    }
+                }
+            })?;
+        Ok(EventPublisher {
+            tx: Some(tx),
+            ..EventPublisher::default()
+        })
+    }
+
+    pub fn publish(&self, event: Event) {
+        if self.is_watched() {
+            // Only fails if the last watcher has just gone
+            self.live.send(event.clone()).ok();
+        }
+        if let Some(tx) = &self.tx {
+            if event.action.is_published() && tx.send(event).is_err() {
+                warn!("Event publisher has stopped, dropping event");
+            }
+        }
+    }
+
+    // Events from now on, as they happen
+    pub fn watch(&self) -> broadcast::Receiver<Event> {
+        self.live.subscribe()
+    }
+
+    // Whether anyone is watching, so events only they get can be skipped if not
+    pub fn is_watched(&self) -> bool {
+        self.live.receiver_count() > 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Event, EventAction, EventFormat, EventPublisher, SinkConfig};
+
+    #[test]
+    fn parse_sinks() {
+        assert!(matches!(
+            "https://example.com/hook".parse::<SinkConfig>().unwrap(),
+            SinkConfig::Webhook(_)
+        ));
+        assert!("ftp://example.com".parse::<SinkConfig>().is_err());
+        assert!("not a url".parse::<SinkConfig>().is_err());
+
+        let nats = "nats://localhost:4222/registry.events".parse::<SinkConfig>();
+        if cfg!(feature = "nats") {
+            assert_eq!(
+                nats.unwrap(),
+                SinkConfig::Nats {
+                    server: "nats://localhost:4222".to_string(),
+                    subject: "registry.events".to_string()
+                }
+            );
+        } else {
+            assert!(nats.is_err());
+        }
+    }
+
+    #[test]
+    fn sends_events_to_watchers() {
+        let events = EventPublisher::default();
+        assert!(!events.is_watched());
+        let mut watcher = events.watch();
+        assert!(events.is_watched());
+
+        events.publish(Event::new(
+            EventAction::Pull,
+            "org/app",
+            Some("v1"),
+            "sha256:abc",
+        ));
+        events.publish(Event::new(EventAction::Gc, "", None, "sha256:def"));
+        let pull = watcher.try_recv().unwrap();
+        assert_eq!(pull.action, EventAction::Pull);
+        assert_eq!(pull.repository, "org/app");
+        assert_eq!(watcher.try_recv().unwrap().digest, "sha256:def");
+        assert!(watcher.try_recv().is_err());
+    }
+
+    #[test]
+    fn serialize_cloudevents() {
+        let event = Event::new(EventAction::Push, "org/app", Some("v1"), "sha256:abc");
+        let json: serde_json::Value =
+            serde_json::from_slice(&EventFormat::CloudEvents.serialize(&event)).unwrap();
+        assert_eq!(json["specversion"], "1.0");
+        assert_eq!(json["type"], "io.trow.manifest.push");
+        assert_eq!(json["data"]["repository"], "org/app");
+        assert_eq!(json["data"]["tag"], "v1");
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&EventFormat::Json.serialize(&event)).unwrap();
+        assert_eq!(json["action"], "push");
+        assert_eq!(json["digest"], "sha256:abc");
+    }
+}
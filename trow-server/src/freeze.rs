@@ -0,0 +1,275 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, Timelike, Utc, Weekday};
+use log::warn;
+
+use crate::selector::glob_match;
+
+/*
+ * Change freezes: during a freeze window, only images already running in a namespace are
+ * admitted, so pods can restart and scale but nothing new is rolled out.
+ *
+ * Windows are given as NAMESPACES=DAYS[/HH:MM-HH:MM], in UTC. The namespace pattern can use *,
+ * days are a day or range of days, and the times can run past midnight:
+ *
+ *   prod=Sat-Sun                    all weekend in the prod namespace
+ *   *=Mon-Fri/18:00-08:00           weekday evenings, until 8am the next morning, everywhere
+ *
+ * Trow doesn't ask Kubernetes what's running. Instead it remembers every image it has admitted
+ * to each namespace (in admitted-images.json in the data dir), and treats those as running.
+ *
+ * Pods annotated with trow.io/break-glass (giving a reason) are admitted during a freeze anyway.
+ */
+
+static ADMITTED_FILE: &str = "admitted-images.json";
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FreezeWindow {
+    namespaces: String,
+    // Indexed by days from Monday
+    days: [bool; 7],
+    // Start and end as minutes from midnight, None for the whole day
+    minutes: Option<(u32, u32)>,
+    spec: String,
+}
+
+fn parse_day(s: &str) -> Result<Weekday> {
+    s.trim()
+        .parse()
+        .map_err(|_| anyhow!("Invalid day {}, expected e.g. Mon or Sat", s))
+}
+
+// Accepts HH:MM, including 24:00 for the end of the day
+fn parse_minutes(s: &str) -> Result<u32> {
+    let err = || anyhow!("Invalid time {}, expected HH:MM", s);
+    let (h, m) = s.trim().split_once(':').ok_or_else(err)?;
+    let h: u32 = h.parse().map_err(|_| err())?;
+    let m: u32 = m.parse().map_err(|_| err())?;
+    if m >= 60 || h * 60 + m > MINUTES_PER_DAY {
+        return Err(err());
+    }
+    Ok(h * 60 + m)
+}
+
+impl FromStr for FreezeWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (namespaces, window) = s.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "Freeze window {} should be of the form NAMESPACES=DAYS[/HH:MM-HH:MM]",
+                s
+            )
+        })?;
+        let namespaces = namespaces.trim();
+        if namespaces.is_empty() {
+            return Err(anyhow!("Freeze window {} has no namespace pattern", s));
+        }
+
+        let (days_spec, times) = match window.split_once('/') {
+            Some((d, t)) => (d, Some(t)),
+            None => (window, None),
+        };
+        let (first, last) = match days_spec.split_once('-') {
+            Some((f, l)) => (parse_day(f)?, parse_day(l)?),
+            None => {
+                let d = parse_day(days_spec)?;
+                (d, d)
+            }
+        };
+        // Ranges can wrap round the end of the week, e.g. Fri-Mon
+        let mut days = [false; 7];
+        let mut day = first;
+        loop {
+            days[day.num_days_from_monday() as usize] = true;
+            if day == last {
+                break;
+            }
+            day = day.succ();
+        }
+
+        let minutes = match times {
+            None => None,
+            Some(t) => {
+                let (start, end) = t
+                    .split_once('-')
+                    .ok_or_else(|| anyhow!("Invalid times {}, expected HH:MM-HH:MM", t))?;
+                let (start, end) = (parse_minutes(start)?, parse_minutes(end)?);
+                if start == end {
+                    return Err(anyhow!("Freeze window {} is empty", s));
+                }
+                Some((start, end))
+            }
+        };
+
+        Ok(FreezeWindow {
+            namespaces: namespaces.to_string(),
+            days,
+            minutes,
+            spec: s.trim().to_string(),
+        })
+    }
+}
+
+impl fmt::Display for FreezeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.spec)
+    }
+}
+
+impl FreezeWindow {
+    pub fn is_active(&self, namespace: &str, now: DateTime<Utc>) -> bool {
+        if !glob_match(&self.namespaces, namespace) {
+            return false;
+        }
+        let today = now.weekday().num_days_from_monday() as usize;
+        let minute = now.hour() * 60 + now.minute();
+        match self.minutes {
+            None => self.days[today],
+            Some((start, end)) if start < end => {
+                self.days[today] && start <= minute && minute < end
+            }
+            // Runs past midnight, so the early hours belong to the previous day's window
+            Some((start, end)) => {
+                let yesterday = (today + 6) % 7;
+                (self.days[today] && minute >= start) || (self.days[yesterday] && minute < end)
+            }
+        }
+    }
+}
+
+pub fn active_window<'a>(
+    windows: &'a [FreezeWindow],
+    namespace: &str,
+    now: DateTime<Utc>,
+) -> Option<&'a FreezeWindow> {
+    windows.iter().find(|w| w.is_active(namespace, now))
+}
+
+/*
+ * Images admitted to each namespace, kept so they can still be admitted during a freeze.
+ *
+ * Entries are never removed, so an image that was once running counts as running.
+ */
+#[derive(Clone)]
+pub struct AdmittedImages {
+    path: PathBuf,
+    images: Arc<RwLock<HashMap<String, HashSet<String>>>>,
+}
+
+impl AdmittedImages {
+    pub fn load(data_path: &Path) -> Result<AdmittedImages> {
+        let path = data_path.join(ADMITTED_FILE);
+        let images = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(AdmittedImages {
+            path,
+            images: Arc::new(RwLock::new(images)),
+        })
+    }
+
+    pub fn contains(&self, namespace: &str, image: &str) -> bool {
+        self.images
+            .read()
+            .unwrap()
+            .get(namespace)
+            .map_or(false, |imgs| imgs.contains(image))
+    }
+
+    /*
+     * Adds the images to the namespace, saving them if any are new.
+     *
+     * Failing to save is only logged, as the admission has already been decided.
+     */
+    pub fn record(&self, namespace: &str, images: &[String]) {
+        let mut all = self.images.write().unwrap();
+        let ns = all.entry(namespace.to_string()).or_default();
+        let mut changed = false;
+        for image in images {
+            changed |= ns.insert(image.clone());
+        }
+        if changed {
+            if let Err(e) = self.save(&all) {
+                warn!("Failed to save admitted images: {}", e);
+            }
+        }
+    }
+
+    // Renamed into place so a crash can't leave a partly written file
+    fn save(&self, all: &HashMap<String, HashSet<String>>) -> Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(all)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AdmittedImages, FreezeWindow};
+    use chrono::{TimeZone, Utc};
+    use tempfile::tempdir;
+
+    #[test]
+    fn parse_windows() {
+        assert!("prod=Sat-Sun".parse::<FreezeWindow>().is_ok());
+        assert!("*=Mon-Fri/18:00-08:00".parse::<FreezeWindow>().is_ok());
+        assert!("prod=Fri/17:00-24:00".parse::<FreezeWindow>().is_ok());
+        assert!("prod".parse::<FreezeWindow>().is_err());
+        assert!("=Sat".parse::<FreezeWindow>().is_err());
+        assert!("prod=Someday".parse::<FreezeWindow>().is_err());
+        assert!("prod=Sat/25:00-26:00".parse::<FreezeWindow>().is_err());
+        assert!("prod=Sat/10:00-10:00".parse::<FreezeWindow>().is_err());
+    }
+
+    #[test]
+    fn active_windows() {
+        // 2022-06-11 is a Saturday
+        let sat_noon = Utc.ymd(2022, 6, 11).and_hms(12, 0, 0);
+        let mon_noon = Utc.ymd(2022, 6, 13).and_hms(12, 0, 0);
+
+        let weekend: FreezeWindow = "prod*=Sat-Sun".parse().unwrap();
+        assert!(weekend.is_active("prod", sat_noon));
+        assert!(weekend.is_active("prod-eu", sat_noon));
+        assert!(!weekend.is_active("dev", sat_noon));
+        assert!(!weekend.is_active("prod", mon_noon));
+
+        // Wraps round the end of the week
+        let long_weekend: FreezeWindow = "prod=Fri-Mon".parse().unwrap();
+        assert!(long_weekend.is_active("prod", mon_noon));
+
+        let evenings: FreezeWindow = "*=Mon-Fri/18:00-08:00".parse().unwrap();
+        assert!(evenings.is_active("prod", Utc.ymd(2022, 6, 13).and_hms(19, 0, 0)));
+        assert!(!evenings.is_active("prod", mon_noon));
+        // Tuesday morning is the end of Monday evening, but Monday morning follows Sunday
+        assert!(evenings.is_active("prod", Utc.ymd(2022, 6, 14).and_hms(7, 59, 0)));
+        assert!(!evenings.is_active("prod", Utc.ymd(2022, 6, 13).and_hms(7, 0, 0)));
+        // Saturday morning is the end of Friday evening
+        assert!(evenings.is_active("prod", Utc.ymd(2022, 6, 11).and_hms(7, 0, 0)));
+        assert!(!evenings.is_active("prod", sat_noon));
+    }
+
+    #[test]
+    fn admitted_images_are_saved() {
+        let dir = tempdir().unwrap();
+        let admitted = AdmittedImages::load(dir.path()).unwrap();
+        admitted.record("prod", &["trow/app:v1".to_string()]);
+        assert!(admitted.contains("prod", "trow/app:v1"));
+        assert!(!admitted.contains("dev", "trow/app:v1"));
+
+        let reloaded = AdmittedImages::load(dir.path()).unwrap();
+        assert!(reloaded.contains("prod", "trow/app:v1"));
+        assert!(!reloaded.contains("prod", "trow/app:v2"));
+    }
+}
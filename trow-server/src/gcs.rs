@@ -0,0 +1,202 @@
+use std::env;
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::{StatusCode, Url};
+use serde_json::Value;
+
+use crate::egress::EgressProxies;
+use crate::storage::{expires_in, object_name, CachedToken, StorageDriver};
+
+/*
+ * Google Cloud Storage, for --storage gs://<bucket>[/<prefix>].
+ *
+ * Credentials come from the metadata server, which on GKE with Workload Identity gives the token
+ * of the Google service account the Kubernetes one is bound to, and otherwise the node's. The
+ * service account needs the Storage Object Admin role on the bucket. GCE_METADATA_HOST overrides
+ * where the metadata server is, as for Google's own libraries.
+ */
+
+const API_URL: &str = "https://storage.googleapis.com/storage/v1/b/";
+const UPLOAD_URL: &str = "https://storage.googleapis.com/upload/storage/v1/b/";
+const METADATA_HOST: &str = "metadata.google.internal";
+const TOKEN_PATH: &str = "/computeMetadata/v1/instance/service-accounts/default/token";
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+pub struct Gcs {
+    bucket: String,
+    prefix: Option<String>,
+    token: CachedToken,
+    client: Client,
+    // For the metadata server, which must never go through a proxy
+    metadata_client: Client,
+}
+
+impl Gcs {
+    pub fn new(bucket: &str, prefix: Option<String>, egress: &EgressProxies) -> Result<Gcs> {
+        Ok(Gcs {
+            bucket: bucket.to_string(),
+            prefix,
+            token: CachedToken::default(),
+            client: Client::builder()
+                .timeout(TIMEOUT)
+                .proxy(egress.proxy())
+                .build()?,
+            metadata_client: Client::builder()
+                .timeout(Duration::from_secs(30))
+                .no_proxy()
+                .build()?,
+        })
+    }
+
+    fn fetch_token(&self) -> Result<(String, Duration)> {
+        let host = env::var("GCE_METADATA_HOST").unwrap_or_else(|_| METADATA_HOST.to_string());
+        let resp: Value = self
+            .metadata_client
+            .get(format!("http://{}{}", host, TOKEN_PATH))
+            .header("Metadata-Flavor", "Google")
+            .send()?
+            .error_for_status()?
+            .json()?;
+        let token = resp["access_token"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No access token from the metadata server"))?;
+        Ok((token.to_string(), expires_in(&resp)))
+    }
+
+    // The object's URL, with its name as a single path segment, / and all
+    fn object_url(&self, path: &str) -> Result<Url> {
+        let mut url = Url::parse(API_URL)?.join(&format!("{}/o/", self.bucket))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid bucket {}", self.bucket))?
+            .pop_if_empty()
+            .push(&object_name(&self.prefix, path));
+        Ok(url)
+    }
+
+    fn send(&self, req: RequestBuilder) -> Result<Response> {
+        let token = self.token.get(|| self.fetch_token())?;
+        Ok(req.bearer_auth(token).send()?)
+    }
+}
+
+impl StorageDriver for Gcs {
+    fn describe(&self) -> String {
+        format!("gs://{}", self.bucket)
+    }
+
+    fn get(&self, path: &str, local_path: &Path) -> Result<bool> {
+        let mut url = self.object_url(path)?;
+        url.query_pairs_mut().append_pair("alt", "media");
+        let mut resp = self.send(self.client.get(url))?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        resp.error_for_status_ref()?;
+        resp.copy_to(&mut File::create(local_path)?)?;
+        Ok(true)
+    }
+
+    fn put(&self, path: &str, local_path: &Path) -> Result<()> {
+        let mut url = Url::parse(UPLOAD_URL)?.join(&format!("{}/o", self.bucket))?;
+        url.query_pairs_mut()
+            .append_pair("uploadType", "media")
+            .append_pair("name", &object_name(&self.prefix, path));
+        self.send(
+            self.client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+                .body(File::open(local_path)?),
+        )?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        let resp = self.send(self.client.delete(self.object_url(path)?))?;
+        if resp.status() != StatusCode::NOT_FOUND {
+            resp.error_for_status()?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<(String, u64)>> {
+        let prefix = object_name(&self.prefix, &format!("{}/", dir));
+        let strip = object_name(&self.prefix, "");
+        let mut objects = vec![];
+        let mut page_token = String::new();
+        loop {
+            let mut url = Url::parse(API_URL)?.join(&format!("{}/o", self.bucket))?;
+            url.query_pairs_mut()
+                .append_pair("prefix", &prefix)
+                .append_pair("fields", "items(name,size),nextPageToken");
+            if !page_token.is_empty() {
+                url.query_pairs_mut().append_pair("pageToken", &page_token);
+            }
+            let page: Value = self
+                .send(self.client.get(url))?
+                .error_for_status()?
+                .json()?;
+            objects.extend(list_page(&page, &strip));
+            page_token = page["nextPageToken"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            if page_token.is_empty() {
+                return Ok(objects);
+            }
+        }
+    }
+}
+
+// The objects in a page of a listing, with the prefix taken off their names
+fn list_page(page: &Value, strip: &str) -> Vec<(String, u64)> {
+    page["items"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|item| {
+            let name = item["name"].as_str()?.strip_prefix(strip)?;
+            // Sizes are strings, as they're 64 bit
+            let size = item["size"].as_str().and_then(|s| s.parse().ok());
+            Some((name.to_string(), size.unwrap_or(0)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{list_page, Gcs};
+    use crate::egress::EgressProxies;
+    use serde_json::json;
+
+    #[test]
+    fn names_objects() {
+        let gcs = Gcs::new(
+            "trow",
+            Some("prod".to_string()),
+            &EgressProxies::new(vec![]),
+        )
+        .unwrap();
+        assert_eq!(
+            gcs.object_url("manifests/app/latest").unwrap().as_str(),
+            "https://storage.googleapis.com/storage/v1/b/trow/o/prod%2Fmanifests%2Fapp%2Flatest"
+        );
+
+        let page = json!({"items": [
+            {"name": "prod/links/app/_blobs/sha256/abc", "size": "0"},
+            {"name": "prod/blobs/sha256/abc", "size": "1024"},
+            {"name": "other/blobs/sha256/abc", "size": "1024"}
+        ]});
+        assert_eq!(
+            list_page(&page, "prod/"),
+            vec![
+                ("links/app/_blobs/sha256/abc".to_string(), 0),
+                ("blobs/sha256/abc".to_string(), 1024)
+            ]
+        );
+    }
+}
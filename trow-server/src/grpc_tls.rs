@@ -0,0 +1,104 @@
+use anyhow::{anyhow, Result};
+use rustls::internal::pemfile;
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, NoClientAuth,
+    PrivateKey, RootCertStore, ServerConfig,
+};
+use tonic::transport::{self, ClientTlsConfig, Identity, ServerTlsConfig};
+
+/*
+ * TLS for the gRPC channel between the frontend and backend, with certificates from PEM files
+ * (see spiffe.rs for using SVIDs instead).
+ *
+ * The backend presents its certificate and checks any client certificate against the CA. Client
+ * certificates are optional unless required, which allows frontends to be given certificates
+ * before the backend insists on them. The frontend checks the backend's certificate is issued by
+ * the CA for the expected name.
+ */
+
+static ALPN_H2: &[u8] = b"h2";
+
+fn parse_certs(pem: &[u8]) -> Result<Vec<Certificate>> {
+    let certs = pemfile::certs(&mut &pem[..]).map_err(|_| anyhow!("Failed to parse PEM"))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in PEM"));
+    }
+    Ok(certs)
+}
+
+fn parse_key(pem: &[u8]) -> Result<PrivateKey> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut &pem[..]).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut &pem[..]).unwrap_or_default();
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No private key found in PEM"))
+}
+
+/// TLS config for the backend's gRPC listener
+pub fn server_tls_config(
+    cert: &[u8],
+    key: &[u8],
+    client_ca: Option<&[u8]>,
+    require_client_cert: bool,
+) -> Result<ServerTlsConfig> {
+    let verifier = match client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in parse_certs(ca)? {
+                roots.add(&cert)?;
+            }
+            if require_client_cert {
+                AllowAnyAuthenticatedClient::new(roots)
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            }
+        }
+        None if require_client_cert => {
+            return Err(anyhow!(
+                "Requiring client certificates needs a CA to check them against"
+            ))
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let mut config = ServerConfig::new(verifier);
+    config
+        .set_single_cert(parse_certs(cert)?, parse_key(key)?)
+        .map_err(|e| anyhow!("Invalid gRPC certificate or key: {}", e))?;
+    config.set_protocols(&[ALPN_H2.to_vec()]);
+    let mut tls = ServerTlsConfig::new();
+    tls.rustls_server_config(config);
+    Ok(tls)
+}
+
+/// TLS config for the frontend, presenting its certificate and checking the backend's
+pub fn client_tls_config(
+    ca: &[u8],
+    cert: &[u8],
+    key: &[u8],
+    server_name: &str,
+) -> Result<ClientTlsConfig> {
+    // Parsed here so bad files are reported at startup rather than on the first call
+    parse_certs(ca)?;
+    parse_certs(cert)?;
+    parse_key(key)?;
+    Ok(ClientTlsConfig::new()
+        .ca_certificate(transport::Certificate::from_pem(ca))
+        .identity(Identity::from_pem(cert, key))
+        .domain_name(server_name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{client_tls_config, parse_key, server_tls_config};
+
+    #[test]
+    fn rejects_bad_config() {
+        assert!(parse_key(b"not a key").is_err());
+        assert!(server_tls_config(b"not a cert", b"not a key", None, false).is_err());
+        assert!(server_tls_config(b"", b"", None, true).is_err());
+        assert!(client_tls_config(b"", b"", b"", "localhost").is_err());
+    }
+}
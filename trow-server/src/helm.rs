@@ -0,0 +1,164 @@
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::maintenance::blob_path;
+use crate::manifest::{FromJson, Manifest};
+use crate::retention::{is_digest, read_repos};
+
+/*
+ * Helm charts pushed as OCI artifacts, e.g. with `helm push mychart-1.0.0.tgz oci://trow/charts`.
+ *
+ * Helm stores a chart in a repository named after it, e.g. charts/mychart, tagged with its
+ * version. The manifest's config is the chart's Chart.yaml as JSON, and its one layer is the
+ * packaged chart. Nothing special is needed to store them, but clients older than Helm 3.8 can't
+ * pull them, so the frontend also serves a classic chart repository index listing every chart
+ * found here, with the packaged chart downloaded as a blob.
+ */
+
+pub const HELM_CONFIG: &str = "application/vnd.cncf.helm.config.v1+json";
+pub const HELM_CHART: &str = "application/vnd.cncf.helm.chart.content.v1.tar+gzip";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChartVersion {
+    pub repo_name: String,
+    pub tag: String,
+    // Chart.yaml as JSON, as it was pushed
+    pub metadata: String,
+    // The packaged chart
+    pub digest: String,
+    pub size: u64,
+    pub pushed: SystemTime,
+}
+
+// The config and packaged chart of a chart's manifest, None if it's not a chart
+fn chart_blobs(manifest: &Manifest) -> Option<(&str, &str, Option<u64>)> {
+    let image = match manifest {
+        Manifest::V2(image) => image,
+        Manifest::List(_) | Manifest::Artifact(_) => return None,
+    };
+    if image.config.media_type != HELM_CONFIG {
+        return None;
+    }
+    let chart = image.layers.iter().find(|l| l.media_type == HELM_CHART)?;
+    Some((&image.config.digest, &chart.digest, chart.size))
+}
+
+fn read_chart(
+    blobs_path: &Path,
+    repo_name: &str,
+    tag: &str,
+    digest: &str,
+    pushed: SystemTime,
+) -> Option<ChartVersion> {
+    let read_json = |digest: &str| -> Option<Value> {
+        let bytes = fs::read(blob_path(blobs_path, digest)?).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    };
+    let manifest = Manifest::from_json(&read_json(digest)?).ok()?;
+    let (config, chart, size) = chart_blobs(&manifest)?;
+    let metadata = read_json(config).filter(Value::is_object)?;
+    Some(ChartVersion {
+        repo_name: repo_name.to_string(),
+        tag: tag.to_string(),
+        metadata: metadata.to_string(),
+        digest: chart.to_string(),
+        size: size.unwrap_or(0),
+        pushed,
+    })
+}
+
+/*
+ * Every tagged chart, by repository and tag.
+ */
+pub fn list_charts(manifests_path: &Path, blobs_path: &Path) -> Result<Vec<ChartVersion>> {
+    let mut charts = vec![];
+    for (repo_name, files) in read_repos(manifests_path)? {
+        for (tag, tf) in files {
+            if is_digest(&tag) {
+                continue;
+            }
+            let current = match tf.history.first() {
+                Some(entry) => &entry.digest,
+                None => continue,
+            };
+            if let Some(chart) = read_chart(blobs_path, &repo_name, &tag, current, tf.pushed) {
+                charts.push(chart);
+            }
+        }
+    }
+    charts.sort_by(|a, b| (&a.repo_name, &a.tag).cmp(&(&b.repo_name, &b.tag)));
+    Ok(charts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{list_charts, HELM_CHART, HELM_CONFIG};
+    use crate::digest::sha256_tag_digest;
+    use std::fs;
+    use std::io::BufReader;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    // Stores the blob, returning its digest
+    fn write_blob(blobs: &Path, content: &str) -> String {
+        let digest = sha256_tag_digest(BufReader::new(content.as_bytes())).unwrap();
+        let dir = blobs.join("sha256");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(digest.trim_start_matches("sha256:")), content).unwrap();
+        digest
+    }
+
+    fn write_tag(manifests: &Path, repo: &str, tag: &str, digest: &str) {
+        let dir = manifests.join(repo);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(tag), format!("{} 2022-03-01T00:00:00Z\n", digest)).unwrap();
+    }
+
+    fn manifest(config_type: &str, config: &str, layer_type: &str, layer: &str) -> String {
+        format!(
+            r#"{{"schemaVersion": 2,
+            "config": {{"mediaType": "{}", "size": 10, "digest": "{}"}},
+            "layers": [{{"mediaType": "{}", "size": 90, "digest": "{}"}}],
+            "annotations": {{"org.opencontainers.image.title": "mychart"}}}}"#,
+            config_type, config, layer_type, layer
+        )
+    }
+
+    #[test]
+    fn lists_tagged_charts() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        let blobs = dir.path().join("blobs");
+
+        let config = write_blob(&blobs, r#"{"name": "mychart", "version": "1.0.0"}"#);
+        let layer = write_blob(&blobs, "chart");
+        let chart = write_blob(&blobs, &manifest(HELM_CONFIG, &config, HELM_CHART, &layer));
+        write_tag(&manifests, "charts/mychart", "1.0.0", &chart);
+        write_tag(&manifests, "charts/mychart", &chart, &chart);
+
+        let image_config = write_blob(&blobs, r#"{"architecture": "amd64"}"#);
+        let image = write_blob(
+            &blobs,
+            &manifest(
+                "application/vnd.oci.image.config.v1+json",
+                &image_config,
+                "application/vnd.oci.image.layer.v1.tar+gzip",
+                &layer,
+            ),
+        );
+        write_tag(&manifests, "app", "latest", &image);
+
+        let charts = list_charts(&manifests, &blobs).unwrap();
+        assert_eq!(charts.len(), 1);
+        assert_eq!(charts[0].repo_name, "charts/mychart");
+        assert_eq!(charts[0].tag, "1.0.0");
+        assert_eq!(charts[0].digest, layer);
+        assert_eq!(charts[0].size, 90);
+        let metadata: serde_json::Value = serde_json::from_str(&charts[0].metadata).unwrap();
+        assert_eq!(metadata["name"], "mychart");
+    }
+}
@@ -0,0 +1,187 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::maintenance::blob_path;
+use crate::manifest::{FromJson, Manifest};
+
+/*
+ * Summaries of the platforms an image is built for, so clients can see what's in an image index
+ * (manifest list) and pick a platform without fetching and parsing each manifest themselves.
+ *
+ * For an index, there's an entry for each manifest it lists. A single platform image has one
+ * entry, with the platform read from its config. Image sizes add up the config and layers listed
+ * in each platform's manifest, so they're known even for layers that haven't been pulled through
+ * a proxy yet, but not for platforms whose manifest isn't stored.
+ *
+ * Manifests never change, so once every platform's manifest is stored the summary can be kept
+ * (see MetadataStore::cache_summary) rather than worked out on every request.
+ */
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PlatformImage {
+    pub os: String,
+    pub architecture: String,
+    pub variant: Option<String>,
+    pub digest: String,
+    pub media_type: String,
+    // Of the platform's manifest itself
+    pub manifest_size: u64,
+    // Config and layers, None if the platform's manifest isn't stored
+    pub image_size: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IndexSummary {
+    pub digest: String,
+    pub media_type: String,
+    pub platforms: Vec<PlatformImage>,
+}
+
+impl IndexSummary {
+    // Whether anything in the summary could still change, as manifests are pulled through a proxy
+    pub fn is_complete(&self) -> bool {
+        self.platforms.iter().all(|p| p.image_size.is_some())
+    }
+}
+
+fn read_manifest(blobs_path: &Path, digest: &str) -> Result<Option<(Manifest, u64)>> {
+    let bytes = match blob_path(blobs_path, digest).and_then(|p| fs::read(p).ok()) {
+        Some(b) => b,
+        None => return Ok(None),
+    };
+    let manifest = Manifest::from_json(&serde_json::from_slice(&bytes)?)?;
+    Ok(Some((manifest, bytes.len() as u64)))
+}
+
+fn image_size(manifest: &Manifest) -> Option<u64> {
+    match manifest {
+        Manifest::V2(m) => {
+            Some(m.config.size.unwrap_or(0) + m.layers.iter().filter_map(|l| l.size).sum::<u64>())
+        }
+        Manifest::List(_) | Manifest::Artifact(_) => None,
+    }
+}
+
+pub fn summarize(blobs_path: &Path, digest: &str) -> Result<IndexSummary> {
+    let (manifest, manifest_size) = read_manifest(blobs_path, digest)?
+        .ok_or_else(|| anyhow!("Manifest {} is not stored", digest))?;
+    let platforms = match &manifest {
+        Manifest::List(list) => list
+            .manifests
+            .iter()
+            .map(|entry| {
+                let image = read_manifest(blobs_path, &entry.digest)?;
+                Ok(PlatformImage {
+                    os: entry.platform.os.clone(),
+                    architecture: entry.platform.architecture.clone(),
+                    variant: entry.platform.variant.clone(),
+                    digest: entry.digest.clone(),
+                    media_type: entry.media_type.clone(),
+                    manifest_size: entry.size as u64,
+                    image_size: image.and_then(|(m, _)| image_size(&m)),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Manifest::V2(m) => {
+            let config: Value = blob_path(blobs_path, &m.config.digest)
+                .and_then(|p| fs::read(p).ok())
+                .and_then(|b| serde_json::from_slice(&b).ok())
+                .unwrap_or(Value::Null);
+            let field = |name: &str| config[name].as_str().map(str::to_string);
+            vec![PlatformImage {
+                os: field("os").unwrap_or_default(),
+                architecture: field("architecture").unwrap_or_default(),
+                variant: field("variant"),
+                digest: digest.to_string(),
+                media_type: manifest.get_media_type(),
+                manifest_size,
+                image_size: image_size(&manifest),
+            }]
+        }
+        // Not built for any platform
+        Manifest::Artifact(_) => vec![],
+    };
+    Ok(IndexSummary {
+        digest: digest.to_string(),
+        media_type: manifest.get_media_type(),
+        platforms,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::summarize;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn image_manifest(config: &str) -> String {
+        format!(
+            r#"{{"schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {{"mediaType": "application/vnd.oci.image.config.v1+json", "size": 10, "digest": "{}"}},
+            "layers": [{{"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "size": 90, "digest": "sha256:{}"}}]}}"#,
+            config,
+            "d".repeat(64)
+        )
+    }
+
+    #[test]
+    fn summarizes_indexes_and_images() {
+        let dir = tempdir().unwrap();
+        let blobs = dir.path().join("blobs");
+        fs::create_dir_all(blobs.join("sha256")).unwrap();
+        let write = |hex: &str, contents: &str| {
+            fs::write(blobs.join("sha256").join(hex), contents).unwrap();
+        };
+
+        let config = format!("sha256:{}", "c".repeat(64));
+        write(
+            &"c".repeat(64),
+            r#"{"os": "linux", "architecture": "amd64"}"#,
+        );
+        let amd64 = image_manifest(&config);
+        write(&"a".repeat(64), &amd64);
+        let index = format!(
+            r#"{{"schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+                {{"mediaType": "application/vnd.oci.image.manifest.v1+json", "size": {}, "digest": "sha256:{}",
+                  "platform": {{"architecture": "amd64", "os": "linux"}}}},
+                {{"mediaType": "application/vnd.oci.image.manifest.v1+json", "size": 500, "digest": "sha256:{}",
+                  "platform": {{"architecture": "arm64", "os": "linux", "variant": "v8"}}}}
+            ]}}"#,
+            amd64.len(),
+            "a".repeat(64),
+            "b".repeat(64)
+        );
+        write(&"e".repeat(64), &index);
+
+        let summary = summarize(&blobs, &format!("sha256:{}", "e".repeat(64))).unwrap();
+        assert_eq!(
+            summary.media_type,
+            "application/vnd.oci.image.index.v1+json"
+        );
+        assert_eq!(summary.platforms.len(), 2);
+        assert_eq!(summary.platforms[0].architecture, "amd64");
+        assert_eq!(summary.platforms[0].manifest_size, amd64.len() as u64);
+        assert_eq!(summary.platforms[0].image_size, Some(100));
+        // Not pulled yet
+        assert_eq!(summary.platforms[1].variant, Some("v8".to_string()));
+        assert_eq!(summary.platforms[1].image_size, None);
+        assert!(!summary.is_complete());
+
+        let single = summarize(&blobs, &format!("sha256:{}", "a".repeat(64))).unwrap();
+        assert_eq!(single.platforms.len(), 1);
+        assert_eq!(single.platforms[0].os, "linux");
+        assert_eq!(single.platforms[0].architecture, "amd64");
+        assert_eq!(single.platforms[0].variant, None);
+        assert_eq!(single.platforms[0].digest, single.digest);
+        assert!(single.is_complete());
+
+        assert!(summarize(&blobs, &format!("sha256:{}", "f".repeat(64))).is_err());
+    }
+}
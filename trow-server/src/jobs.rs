@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use uuid::Uuid;
+
+/*
+ * Long running operations, such as garbage collection, run in the background as jobs.
+ *
+ * Starting a job returns immediately with an id that can be used to check progress or cancel it.
+ * Jobs are kept in memory, so the list is lost on restart.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobKind {
+    // Delete blobs that aren't referenced by any tagged manifest
+    GarbageCollect,
+    // Check every blob still matches its digest
+    Scrub,
+    // Delete tags and manifests according to the retention rules
+    Retention,
+    // Record which tags are running in the cluster
+    Usage,
+    // Compare a sample of proxied tags with upstream
+    ProxyCheck,
+    // Copy new blobs and the current tags to the backup target
+    Backup,
+    // Write a tarball of the tags and blobs to the backup target
+    Archive,
+    // Copy tags missing from the data dir back from the backup target
+    Restore,
+    // Recompress frequently pulled layers, see transcode.rs
+    Transcode,
+    // Convert pushed layers to eStargz for lazy pulling, see estargz.rs
+    Estargz,
+    // Make sure an image is in the registry before it's pulled, see the prewarm API
+    Prewarm,
+}
+
+impl fmt::Display for JobKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobKind::GarbageCollect => write!(f, "gc"),
+            JobKind::Scrub => write!(f, "scrub"),
+            JobKind::Retention => write!(f, "retention"),
+            JobKind::Usage => write!(f, "usage"),
+            JobKind::ProxyCheck => write!(f, "proxy-check"),
+            JobKind::Backup => write!(f, "backup"),
+            JobKind::Archive => write!(f, "archive"),
+            JobKind::Restore => write!(f, "restore"),
+            JobKind::Transcode => write!(f, "transcode"),
+            JobKind::Estargz => write!(f, "estargz"),
+            JobKind::Prewarm => write!(f, "prewarm"),
+        }
+    }
+}
+
+impl FromStr for JobKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gc" => Ok(JobKind::GarbageCollect),
+            "scrub" => Ok(JobKind::Scrub),
+            "retention" => Ok(JobKind::Retention),
+            "usage" => Ok(JobKind::Usage),
+            "proxy-check" => Ok(JobKind::ProxyCheck),
+            "backup" => Ok(JobKind::Backup),
+            "archive" => Ok(JobKind::Archive),
+            "restore" => Ok(JobKind::Restore),
+            "transcode" => Ok(JobKind::Transcode),
+            "estargz" => Ok(JobKind::Estargz),
+            "prewarm" => Ok(JobKind::Prewarm),
+            _ => Err(anyhow!("Unknown job type {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobState::Running => write!(f, "running"),
+            JobState::Completed => write!(f, "completed"),
+            JobState::Failed => write!(f, "failed"),
+            JobState::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub id: String,
+    pub kind: JobKind,
+    pub state: JobState,
+    // Percentage complete, 0 to 100
+    pub progress: u32,
+    // Summary of the result, or the error if the job failed
+    pub message: String,
+    pub created: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+/*
+ * Given to the function doing the work so it can report progress and check for cancellation.
+ */
+pub struct JobHandle {
+    id: String,
+    jobs: Jobs,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobHandle {
+    pub fn set_progress(&self, done: usize, total: usize) {
+        let progress = if total == 0 {
+            100
+        } else {
+            (done * 100 / total) as u32
+        };
+        if let Some(job) = self.jobs.jobs.write().unwrap().get_mut(&self.id) {
+            job.progress = progress.min(100);
+        }
+    }
+
+    /// Long running jobs should check this regularly and stop early if it's true
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Jobs {
+    jobs: Arc<RwLock<HashMap<String, Job>>>,
+}
+
+impl Jobs {
+    pub fn new() -> Jobs {
+        Jobs::default()
+    }
+
+    /**
+     * Runs the given function on the blocking thread pool and returns the new job.
+     *
+     * The function returns a summary message on success.
+     */
+    pub fn start<F>(&self, kind: JobKind, work: F) -> Job
+    where
+        F: FnOnce(&JobHandle) -> Result<String> + Send + 'static,
+    {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let job = Job {
+            id: Uuid::new_v4().to_string(),
+            kind,
+            state: JobState::Running,
+            progress: 0,
+            message: String::new(),
+            created: Utc::now(),
+            finished: None,
+            cancelled: cancelled.clone(),
+        };
+        self.jobs
+            .write()
+            .unwrap()
+            .insert(job.id.clone(), job.clone());
+        info!("Starting {} job {}", kind, job.id);
+
+        let handle = JobHandle {
+            id: job.id.clone(),
+            jobs: self.clone(),
+            cancelled,
+        };
+        tokio::task::spawn_blocking(move || {
+            let res = work(&handle);
+            handle.jobs.finish(&handle.id, handle.is_cancelled(), res);
+        });
+        job
+    }
+
+    fn finish(&self, id: &str, cancelled: bool, res: Result<String>) {
+        if let Some(job) = self.jobs.write().unwrap().get_mut(id) {
+            job.finished = Some(Utc::now());
+            match res {
+                Ok(msg) => {
+                    job.state = if cancelled {
+                        JobState::Cancelled
+                    } else {
+                        job.progress = 100;
+                        JobState::Completed
+                    };
+                    job.message = msg;
+                }
+                Err(e) => {
+                    error!("Job {} failed: {:?}", id, e);
+                    job.state = JobState::Failed;
+                    job.message = e.to_string();
+                }
+            }
+            info!("{} job {} {}: {}", job.kind, id, job.state, job.message);
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.read().unwrap().get(id).cloned()
+    }
+
+    /// Oldest first
+    pub fn list(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self.jobs.read().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|j| j.created);
+        jobs
+    }
+
+    /// Asks a running job to stop. Has no effect on jobs that have finished.
+    pub fn cancel(&self, id: &str) -> Option<Job> {
+        let job = self.get(id)?;
+        if job.state == JobState::Running {
+            info!("Cancelling {} job {}", job.kind, id);
+            job.cancelled.store(true, Ordering::Relaxed);
+        }
+        Some(job)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{JobKind, JobState, Jobs};
+    use std::time::Duration;
+
+    async fn wait_for_finish(jobs: &Jobs, id: &str) -> JobState {
+        loop {
+            let state = jobs.get(id).unwrap().state;
+            if state != JobState::Running {
+                return state;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn job_completes() {
+        let jobs = Jobs::new();
+        let job = jobs.start(JobKind::Scrub, |h| {
+            h.set_progress(1, 2);
+            Ok("done".to_string())
+        });
+        assert_eq!(job.state, JobState::Running);
+        assert_eq!(wait_for_finish(&jobs, &job.id).await, JobState::Completed);
+
+        let job = jobs.get(&job.id).unwrap();
+        assert_eq!(job.progress, 100);
+        assert_eq!(job.message, "done");
+        assert!(job.finished.is_some());
+        assert_eq!(jobs.list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn job_cancelled() {
+        let jobs = Jobs::new();
+        let job = jobs.start(JobKind::GarbageCollect, |h| {
+            while !h.is_cancelled() {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            Ok("stopped".to_string())
+        });
+        jobs.cancel(&job.id).unwrap();
+        assert_eq!(wait_for_finish(&jobs, &job.id).await, JobState::Cancelled);
+        assert!(jobs.cancel("no-such-job").is_none());
+    }
+
+    #[test]
+    fn job_kind_round_trips() {
+        for kind in [
+            JobKind::GarbageCollect,
+            JobKind::Scrub,
+            JobKind::Retention,
+            JobKind::Usage,
+            JobKind::ProxyCheck,
+            JobKind::Backup,
+            JobKind::Archive,
+            JobKind::Restore,
+            JobKind::Transcode,
+            JobKind::Estargz,
+            JobKind::Prewarm,
+        ] {
+            assert_eq!(kind.to_string().parse::<JobKind>().unwrap(), kind);
+        }
+        assert!("defrag".parse::<JobKind>().is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::links;
+use crate::maintenance::{blob_path, referenced_by_tags, walk_files};
+use crate::trash::Trash;
+
+/*
+ * The version of the data dir's layout, and the migrations between versions.
+ *
+ * layout.json in the data dir records the version. A new data dir is given the current version,
+ * while one without the file but with tags in it is from before the file existed, version 0. The
+ * backend refuses to start on a data dir from an older version, and `trow --migrate` upgrades it
+ * first.
+ *
+ * Versions so far:
+ *
+ *   0  Blobs shared by all repositories, with nothing recording which repositories pushed them
+ *   1  links/<repo>/_blobs/ for every blob a repository's tags refer to (see links.rs)
+ *
+ * Migrations go a repository at a time and must be safe to repeat. The repositories done are
+ * checkpointed in layout.json, so an interrupted migration picks up where it stopped. Each
+ * migration can also check a repository, which `trow --migrate` does for every version once it's
+ * finished, and `trow --verify-layout` does without changing anything.
+ */
+
+pub const CURRENT_VERSION: u32 = 1;
+
+pub(crate) static LAYOUT_FILE: &str = "layout.json";
+static MANIFESTS_DIR: &str = "manifests";
+static BLOBS_DIR: &str = "blobs";
+static LINKS_DIR: &str = "links";
+static TRASH_DIR: &str = "trash";
+
+// Repositories migrated between writes of the checkpoint
+const CHECKPOINT_EVERY: usize = 100;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Layout {
+    version: u32,
+    // Set while migrating to the next version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    migrating: Option<Progress>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Progress {
+    to: u32,
+    // Repositories already migrated
+    done: BTreeSet<String>,
+}
+
+// A repository's tag files, including those of tags in the trash
+struct Repository {
+    name: String,
+    tag_files: Vec<PathBuf>,
+}
+
+struct Migration {
+    to: u32,
+    description: &'static str,
+    migrate: fn(&Path, &Repository) -> Result<()>,
+    // Problems with the repository, empty if it's as the version expects
+    verify: fn(&Path, &Repository) -> Result<Vec<String>>,
+}
+
+static MIGRATIONS: [Migration; 1] = [Migration {
+    to: 1,
+    description: "linking repositories to the blobs they use",
+    migrate: link_blobs,
+    verify: verify_links,
+}];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub from: u32,
+    pub to: u32,
+    // Empty if the data dir verified
+    pub problems: Vec<String>,
+}
+
+pub fn path(data_path: &Path) -> PathBuf {
+    data_path.join(LAYOUT_FILE)
+}
+
+fn read(data_path: &Path) -> Result<Layout> {
+    match fs::read(path(data_path)) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let version = if walk_files(&data_path.join(MANIFESTS_DIR))?.is_empty() {
+                CURRENT_VERSION
+            } else {
+                0
+            };
+            Ok(Layout {
+                version,
+                migrating: None,
+            })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Written alongside and moved into place, so it's never seen half written
+fn write(data_path: &Path, layout: &Layout) -> Result<()> {
+    let tmp_path = data_path.join(format!(".{}", Uuid::new_v4()));
+    fs::write(&tmp_path, serde_json::to_vec(layout)?)?;
+    fs::rename(&tmp_path, path(data_path))?;
+    Ok(())
+}
+
+/*
+ * Fails unless the data dir has the current layout, recording it if the data dir is new. Called
+ * before the backend starts.
+ */
+pub fn check(data_path: &Path) -> Result<()> {
+    let layout = read(data_path)?;
+    if layout.version > CURRENT_VERSION {
+        return Err(anyhow!(
+            "Data directory {:?} has layout {}, from a newer version of Trow than this one (layout {})",
+            data_path,
+            layout.version,
+            CURRENT_VERSION
+        ));
+    }
+    if layout.version < CURRENT_VERSION || layout.migrating.is_some() {
+        return Err(anyhow!(
+            "Data directory {:?} has layout {} and this version of Trow needs layout {}. Run trow with --migrate to upgrade it.",
+            data_path,
+            layout.version,
+            CURRENT_VERSION
+        ));
+    }
+    if !path(data_path).exists() {
+        write(data_path, &layout)?;
+    }
+    Ok(())
+}
+
+// Every repository with tags, in the manifests dir or the trash
+fn repositories(data_path: &Path) -> Result<Vec<Repository>> {
+    let manifests_path = data_path.join(MANIFESTS_DIR);
+    let mut repos: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for file in walk_files(&manifests_path)? {
+        let name = file
+            .parent()
+            .and_then(|p| p.strip_prefix(&manifests_path).ok())
+            .map(|p| p.to_string_lossy().to_string())
+            .filter(|n| !n.is_empty());
+        if let Some(name) = name {
+            repos.entry(name).or_default().push(file);
+        }
+    }
+    if data_path.join(TRASH_DIR).exists() {
+        // Only read, so how long entries are kept for doesn't matter
+        let trash = Trash::new(data_path, Duration::ZERO)?;
+        for (name, files) in trash.tag_files()? {
+            repos.entry(name).or_default().extend(files);
+        }
+    }
+    Ok(repos
+        .into_iter()
+        .map(|(name, tag_files)| Repository { name, tag_files })
+        .collect())
+}
+
+/*
+ * Upgrades the data dir to the current layout, resuming a migration that was interrupted, then
+ * verifies it.
+ */
+pub fn migrate(data_path: &Path) -> Result<MigrationReport> {
+    let mut layout = read(data_path)?;
+    if layout.version > CURRENT_VERSION {
+        return Err(anyhow!(
+            "Data directory {:?} has layout {}, from a newer version of Trow",
+            data_path,
+            layout.version
+        ));
+    }
+    let from = layout.version;
+    for m in MIGRATIONS.iter().filter(|m| m.to > from) {
+        info!("Migrating to layout {}, {}", m.to, m.description);
+        let mut progress = match layout.migrating.take() {
+            Some(p) if p.to == m.to => p,
+            _ => Progress {
+                to: m.to,
+                done: BTreeSet::new(),
+            },
+        };
+        let repos = repositories(data_path)?;
+        let mut since_checkpoint = 0;
+        for (i, repo) in repos.iter().enumerate() {
+            if progress.done.contains(&repo.name) {
+                continue;
+            }
+            (m.migrate)(data_path, repo)?;
+            progress.done.insert(repo.name.clone());
+            since_checkpoint += 1;
+            if since_checkpoint == CHECKPOINT_EVERY {
+                info!("Migrated {} of {} repositories", i + 1, repos.len());
+                layout.migrating = Some(progress.clone());
+                write(data_path, &layout)?;
+                since_checkpoint = 0;
+            }
+        }
+        layout = Layout {
+            version: m.to,
+            migrating: None,
+        };
+        write(data_path, &layout)?;
+        info!("Migrated to layout {}", m.to);
+    }
+    // Also records the version of a new data dir
+    write(data_path, &layout)?;
+
+    let mut report = verify(data_path)?;
+    report.from = from;
+    Ok(report)
+}
+
+/*
+ * Checks the data dir against every version up to the one it has, without changing anything.
+ */
+pub fn verify(data_path: &Path) -> Result<MigrationReport> {
+    let layout = read(data_path)?;
+    let mut problems = vec![];
+    if layout.version < CURRENT_VERSION {
+        problems.push(format!(
+            "Layout is {}, this version of Trow needs {}",
+            layout.version, CURRENT_VERSION
+        ));
+    }
+    if let Some(p) = &layout.migrating {
+        problems.push(format!("Migration to layout {} didn't finish", p.to));
+    }
+    let repos = repositories(data_path)?;
+    for m in MIGRATIONS.iter().filter(|m| m.to <= layout.version) {
+        for repo in &repos {
+            problems.extend((m.verify)(data_path, repo)?);
+        }
+    }
+    Ok(MigrationReport {
+        from: layout.version,
+        to: layout.version,
+        problems,
+    })
+}
+
+// The blobs the repository's tags refer to that the data dir has
+fn stored_blobs(data_path: &Path, repo: &Repository) -> Result<Vec<String>> {
+    let blobs_path = data_path.join(BLOBS_DIR);
+    let mut digests: Vec<String> = referenced_by_tags(&repo.tag_files, &blobs_path)?
+        .into_iter()
+        .filter(|d| blob_path(&blobs_path, d).map_or(false, |p| p.exists()))
+        .collect();
+    digests.sort();
+    Ok(digests)
+}
+
+// Version 1
+fn link_blobs(data_path: &Path, repo: &Repository) -> Result<()> {
+    let links_path = data_path.join(LINKS_DIR);
+    for digest in stored_blobs(data_path, repo)? {
+        let linked =
+            links::link_path(&links_path, &repo.name, &digest).map_or(false, |p| p.exists());
+        if !linked {
+            links::link(&links_path, &repo.name, &digest)?;
+        }
+    }
+    Ok(())
+}
+
+fn verify_links(data_path: &Path, repo: &Repository) -> Result<Vec<String>> {
+    let links_path = data_path.join(LINKS_DIR);
+    Ok(stored_blobs(data_path, repo)?
+        .into_iter()
+        .filter(|d| !links::link_path(&links_path, &repo.name, d).map_or(false, |p| p.exists()))
+        .map(|d| format!("{} doesn't link to {}", repo.name, d))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, migrate, verify, CURRENT_VERSION};
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn migrates_data_dir_from_before_links() {
+        let dir = tempdir().unwrap();
+        let data = dir.path();
+        let manifest = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": { "mediaType": "application/vnd.docker.container.image.v1+json", "size": 1, "digest": "sha256:config" },
+            "layers": [ { "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "size": 1, "digest": "sha256:layer" } ]
+        }"#;
+        fs::create_dir_all(data.join("blobs/sha256")).unwrap();
+        fs::write(data.join("blobs/sha256/manifest"), manifest).unwrap();
+        fs::write(data.join("blobs/sha256/config"), "{}").unwrap();
+        fs::write(data.join("blobs/sha256/layer"), "layer").unwrap();
+        fs::create_dir_all(data.join("manifests/org/app")).unwrap();
+        fs::write(
+            data.join("manifests/org/app/latest"),
+            "sha256:manifest 2022-01-01T00:00:00Z\n",
+        )
+        .unwrap();
+
+        assert!(check(data).is_err());
+        let report = verify(data).unwrap();
+        assert_eq!(report.to, 0);
+        assert!(report.problems[0].contains("Layout is 0"));
+
+        let report = migrate(data).unwrap();
+        assert_eq!((report.from, report.to), (0, CURRENT_VERSION));
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert!(data.join("links/org/app/_blobs/sha256/layer").exists());
+        check(data).unwrap();
+
+        // Picked up by verifying, but left alone by migrating again
+        fs::remove_file(data.join("links/org/app/_blobs/sha256/config")).unwrap();
+        assert_eq!(
+            verify(data).unwrap().problems,
+            vec!["org/app doesn't link to sha256:config"]
+        );
+        assert_eq!(migrate(data).unwrap().problems.len(), 1);
+    }
+
+    #[test]
+    fn new_data_dir_has_current_layout() {
+        let dir = tempdir().unwrap();
+        check(dir.path()).unwrap();
+        let report = verify(dir.path()).unwrap();
+        assert_eq!(report.to, CURRENT_VERSION);
+        assert!(report.problems.is_empty());
+    }
+}
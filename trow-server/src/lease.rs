@@ -0,0 +1,212 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/*
+ * A lease on the data directory, so two backends can't write to the same volume by accident
+ * (easily done with a ReadWriteMany volume and more than one replica).
+ *
+ * The holder writes .trow.lock in the data dir and renews it every third of the lease period.
+ * File locks aren't used as they're unreliable on network filesystems, which is exactly where a
+ * shared volume is likely to be.
+ *
+ * A backend that finds someone else's lease watches it for a whole lease period, in case the
+ * previous backend was only just stopped. If it's renewed in that time the backend is still
+ * running and we refuse to start. Nothing depends on the clocks of the two hosts agreeing.
+ *
+ * A lease left by the same host and pid can be taken straight away, as it must be from an earlier
+ * run of the same container.
+ */
+
+static LEASE_FILE: &str = ".trow.lock";
+
+// Long enough that a busy backend doesn't miss renewing
+pub const LEASE_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct LeaseRecord {
+    id: String,
+    host: String,
+    pid: u32,
+    renewed: DateTime<Utc>,
+}
+
+pub struct DataDirLease {
+    path: PathBuf,
+    id: String,
+    host: String,
+    pid: u32,
+    ttl: Duration,
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn read_record(path: &Path) -> Result<Option<LeaseRecord>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl DataDirLease {
+    /*
+     * Takes the lease on the data directory, creating the directory if needed.
+     *
+     * Can block for the lease period if another backend's lease is found. Fails if that backend
+     * is still renewing it.
+     */
+    pub fn acquire(data_path: &Path, ttl: Duration) -> Result<Arc<DataDirLease>> {
+        fs::create_dir_all(data_path)?;
+        let lease = DataDirLease {
+            path: data_path.join(LEASE_FILE),
+            id: Uuid::new_v4().to_string(),
+            host: hostname(),
+            pid: std::process::id(),
+            ttl,
+        };
+
+        match read_record(&lease.path)? {
+            None => lease.create()?,
+            Some(current) => {
+                if current.host != lease.host || current.pid != lease.pid {
+                    info!(
+                        "Data directory was leased by {} (pid {}), waiting {:?} to check it has stopped",
+                        current.host, current.pid, ttl
+                    );
+                    thread::sleep(ttl);
+                    if read_record(&lease.path)?.as_ref() != Some(&current) {
+                        return Err(lease.held_error(&current));
+                    }
+                }
+                lease.write()?;
+            }
+        }
+
+        // Catches another backend starting at the same time
+        match read_record(&lease.path)? {
+            Some(current) if current.id == lease.id => Ok(Arc::new(lease)),
+            Some(current) => Err(lease.held_error(&current)),
+            None => Err(anyhow!("Lease file {} disappeared", lease.path.display())),
+        }
+    }
+
+    /*
+     * Renews the lease in a background thread for as long as the process runs.
+     *
+     * Failures are logged rather than stopping the backend, as a full or flaky volume is more
+     * likely than another backend taking over.
+     */
+    pub fn keep_renewed(self: Arc<Self>) {
+        thread::spawn(move || loop {
+            thread::sleep(self.ttl / 3);
+            match self.renew() {
+                Ok(()) => debug!("Renewed lease on data directory"),
+                Err(e) => error!("Failed to renew lease on data directory: {}", e),
+            }
+        });
+    }
+
+    fn renew(&self) -> Result<()> {
+        match read_record(&self.path)? {
+            Some(current) if current.id != self.id => Err(anyhow!(
+                "Lease was taken by another backend on {} (pid {})",
+                current.host,
+                current.pid
+            )),
+            _ => self.write(),
+        }
+    }
+
+    fn record(&self) -> LeaseRecord {
+        LeaseRecord {
+            id: self.id.clone(),
+            host: self.host.clone(),
+            pid: self.pid,
+            renewed: Utc::now(),
+        }
+    }
+
+    fn held_error(&self, current: &LeaseRecord) -> anyhow::Error {
+        anyhow!(
+            "The data directory is in use by another Trow backend on {} (pid {}), last renewed at {}. Remove {} if that backend has definitely stopped.",
+            current.host,
+            current.pid,
+            current.renewed.to_rfc3339(),
+            self.path.display()
+        )
+    }
+
+    // Fails if the file already exists, so only one of two new backends creates it
+    fn create(&self) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.path)?;
+        file.write_all(&serde_json::to_vec(&self.record())?)?;
+        Ok(())
+    }
+
+    // Renamed into place so a reader never sees a partly written lease
+    fn write(&self) -> Result<()> {
+        let tmp_path = self
+            .path
+            .with_file_name(format!("{}.{}", LEASE_FILE, self.id));
+        fs::write(&tmp_path, serde_json::to_vec(&self.record())?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DataDirLease, LEASE_FILE};
+    use std::fs;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    const TTL: Duration = Duration::from_millis(300);
+
+    #[test]
+    fn lease_is_exclusive() {
+        let dir = tempdir().unwrap();
+        let data = dir.path().join("data");
+
+        let first = DataDirLease::acquire(&data, TTL).unwrap();
+        // The same process can pick up its own lease, so pretend to be another host
+        let mut record = super::read_record(&data.join(LEASE_FILE)).unwrap().unwrap();
+        record.host = "other-host".to_string();
+        fs::write(data.join(LEASE_FILE), serde_json::to_vec(&record).unwrap()).unwrap();
+
+        // Renewed while we're waiting, so it's still in use
+        let renewer = std::thread::spawn(move || {
+            std::thread::sleep(TTL / 3);
+            record.renewed = chrono::Utc::now();
+            fs::write(data.join(LEASE_FILE), serde_json::to_vec(&record).unwrap()).unwrap();
+        });
+        assert!(DataDirLease::acquire(&dir.path().join("data"), TTL).is_err());
+        renewer.join().unwrap();
+
+        // Not renewed any more, so it can be taken over
+        let second = DataDirLease::acquire(&dir.path().join("data"), TTL).unwrap();
+        assert_ne!(first.id, second.id);
+        assert!(first.renew().is_err());
+        assert!(second.renew().is_ok());
+    }
+}
@@ -1,22 +1,85 @@
 pub mod digest;
 
 use tonic::transport::Server;
+mod azure;
+mod backup;
+mod capacity;
+mod estargz;
+mod events;
+mod freeze;
+mod gcs;
+pub mod grpc_tls;
+mod helm;
+mod index_summary;
+mod jobs;
+pub mod layout;
+mod lease;
+mod links;
+mod listener;
+mod maintenance;
+mod metadata;
 mod metrics;
+mod mirror;
+pub mod names;
+mod oci_layout;
+mod policy;
+mod proxy_check;
+mod quota;
+mod referrers;
+mod retention;
+mod scrub;
+mod selector;
 mod server;
+mod storage;
+mod storage_usage;
 mod temporary_file;
+mod tenants;
+mod transcode;
+mod trash;
+mod trow_policy;
+mod uploads;
+mod usage;
 mod validate;
+mod watcher;
+mod write_locks;
+use backup::DirTarget;
+use capacity::Reserve;
+use egress::{EgressProxies, ProxyRule};
+use events::{EventFormat, EventPublisher, SinkConfig};
+use freeze::FreezeWindow;
+use layout::MigrationReport;
+use lease::DataDirLease;
 use log::{debug, warn};
+use quota::Quota;
+use request_id::WithRequestId;
+use retention::RetentionRule;
+use selector::TagSelector;
 use server::trow_server::admission_controller_server::AdmissionControllerServer;
 use server::trow_server::registry_server::RegistryServer;
 use server::TrowServer;
+use spiffe::SvidSource;
 use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use storage::Storage;
+use telemetry::Traced;
 use tokio::runtime::Runtime;
 
+pub mod egress;
 pub mod manifest;
+pub mod request_id;
+pub mod spiffe;
+pub mod telemetry;
+
+pub use capacity::INSUFFICIENT_STORAGE;
+pub use listener::{ListenAddr, UNIX_SCHEME};
+pub use retention::parse_duration;
 
 pub struct TrowServerBuilder {
     data_path: String,
-    listen_addr: std::net::SocketAddr,
+    listen_addrs: Vec<ListenAddr>,
+    // Permissions of the Unix sockets listened on
+    socket_mode: u32,
     proxy_hub: bool,
     hub_user: Option<String>,
     hub_pass: Option<String>,
@@ -27,11 +90,53 @@ pub struct TrowServerBuilder {
     tls_cert: Option<Vec<u8>>,
     tls_key: Option<Vec<u8>>,
     root_key: Option<Vec<u8>>,
+    require_client_cert: bool,
+    watch_data_dir: bool,
+    event_sinks: Vec<SinkConfig>,
+    event_format: EventFormat,
+    quotas: Vec<Quota>,
+    retention: Vec<RetentionRule>,
+    retention_interval: Duration,
+    immutable_tags: Vec<TagSelector>,
+    freeze_windows: Vec<FreezeWindow>,
+    usage_interval: Duration,
+    proxy_check_interval: Duration,
+    proxy_check_sample: usize,
+    // Most layers a pushed image can have, 0 for no limit
+    max_layers: usize,
+    backup_dir: Option<String>,
+    backup_interval: Duration,
+    scrub_interval: Duration,
+    scrub_quarantine: bool,
+    // Layers are transcoded once pulled this many times, if set
+    transcode_min_pulls: Option<u64>,
+    transcode_interval: Duration,
+    transcode_on_demand: bool,
+    // Layers are converted to eStargz every interval, if set
+    estargz_interval: Option<Duration>,
+    mirror_workers: usize,
+    mirror_queue_size: usize,
+    upload_ttl: Duration,
+    // Deleted manifests can be restored for this long, zero to delete them at once
+    trash_window: Duration,
+    // Bucket the registry is kept in, with the data dir as a working copy
+    storage_url: Option<String>,
+    // Where uploads in progress are written, if not the data dir
+    scratch_dir: Option<String>,
+    // Space to keep free, refusing uploads below it
+    storage_reserve: Option<Reserve>,
+    upstream_proxies: Vec<ProxyRule>,
+    metadata_db: Option<String>,
+    watch_policies: bool,
+    require_existing_images: bool,
+    spiffe: Option<Arc<SvidSource>>,
+    // Refuse writes until switched off through SetReadOnly
+    read_only: bool,
 }
 
 pub fn build_server(
     data_path: &str,
-    listen_addr: std::net::SocketAddr,
+    listen_addrs: Vec<ListenAddr>,
     proxy_hub: bool,
     hub_user: Option<String>,
     hub_pass: Option<String>,
@@ -42,7 +147,8 @@ pub fn build_server(
 ) -> TrowServerBuilder {
     TrowServerBuilder {
         data_path: data_path.to_string(),
-        listen_addr,
+        listen_addrs,
+        socket_mode: 0o600,
         proxy_hub,
         hub_user,
         hub_pass,
@@ -53,24 +159,411 @@ pub fn build_server(
         tls_cert: None,
         tls_key: None,
         root_key: None,
+        require_client_cert: false,
+        watch_data_dir: false,
+        event_sinks: vec![],
+        event_format: EventFormat::Json,
+        quotas: vec![],
+        retention: vec![],
+        retention_interval: Duration::ZERO,
+        immutable_tags: vec![],
+        freeze_windows: vec![],
+        usage_interval: Duration::ZERO,
+        proxy_check_interval: Duration::ZERO,
+        proxy_check_sample: 20,
+        max_layers: 0,
+        backup_dir: None,
+        backup_interval: Duration::ZERO,
+        scrub_interval: Duration::ZERO,
+        scrub_quarantine: false,
+        transcode_min_pulls: None,
+        transcode_interval: Duration::ZERO,
+        transcode_on_demand: false,
+        estargz_interval: None,
+        mirror_workers: 0,
+        mirror_queue_size: 0,
+        upload_ttl: Duration::ZERO,
+        trash_window: Duration::ZERO,
+        storage_url: None,
+        scratch_dir: None,
+        storage_reserve: None,
+        upstream_proxies: vec![],
+        metadata_db: None,
+        watch_policies: false,
+        require_existing_images: false,
+        spiffe: None,
+        read_only: false,
     }
 }
 
 impl TrowServerBuilder {
+    /*
+     * Serve gRPC over TLS with the given PEM certificate and key (see grpc_tls.rs).
+     */
     pub fn add_tls(mut self, tls_cert: Vec<u8>, tls_key: Vec<u8>) -> TrowServerBuilder {
         self.tls_cert = Some(tls_cert);
         self.tls_key = Some(tls_key);
         self
     }
 
+    /// CA certificates to check client certificates against
     pub fn add_root_cert(mut self, root_key: Vec<u8>) -> TrowServerBuilder {
         self.root_key = Some(root_key);
         self
     }
 
+    /// Who can connect through Unix sockets, e.g. 0o660 for the group as well as the owner
+    pub fn add_socket_mode(mut self, mode: u32) -> TrowServerBuilder {
+        self.socket_mode = mode;
+        self
+    }
+
+    /// Refuse gRPC clients without a certificate issued by the root cert
+    pub fn require_client_cert(mut self) -> TrowServerBuilder {
+        self.require_client_cert = true;
+        self
+    }
+
+    pub fn watch_data_dir(mut self) -> TrowServerBuilder {
+        self.watch_data_dir = true;
+        self
+    }
+
+    /*
+     * Publish registry events to the given sinks (URLs, see events.rs).
+     *
+     * Fails if any of the sinks or the format are invalid.
+     */
+    pub fn add_event_sinks(
+        mut self,
+        sinks: Vec<String>,
+        format: &str,
+    ) -> anyhow::Result<TrowServerBuilder> {
+        self.event_sinks = sinks
+            .iter()
+            .map(|s| s.parse())
+            .collect::<anyhow::Result<Vec<SinkConfig>>>()?;
+        self.event_format = format.parse()?;
+        Ok(self)
+    }
+
+    /*
+     * Limit the storage used by repositories or namespaces (see quota.rs for the format).
+     *
+     * Fails if any of the quotas are invalid.
+     */
+    pub fn add_quotas(mut self, quotas: Vec<String>) -> anyhow::Result<TrowServerBuilder> {
+        self.quotas = quotas
+            .iter()
+            .map(|q| q.parse())
+            .collect::<anyhow::Result<Vec<Quota>>>()?;
+        Ok(self)
+    }
+
+    /*
+     * Delete old tags and manifests according to the rules (see retention.rs for the format),
+     * checked every interval e.g. "24h". An interval of "0" only runs them when a retention job
+     * is started.
+     *
+     * Fails if any of the rules or the interval are invalid.
+     */
+    pub fn add_retention(
+        mut self,
+        rules: Vec<String>,
+        interval: &str,
+    ) -> anyhow::Result<TrowServerBuilder> {
+        self.retention = rules
+            .iter()
+            .map(|r| r.parse())
+            .collect::<anyhow::Result<Vec<RetentionRule>>>()?;
+        self.retention_interval = retention::parse_duration(interval)?;
+        Ok(self)
+    }
+
+    /*
+     * Reject pushes to existing tags matching any of the selectors, see selector.rs for the
+     * format.
+     *
+     * Fails if any of the selectors are invalid.
+     */
+    pub fn add_immutable_tags(
+        mut self,
+        selectors: Vec<String>,
+    ) -> anyhow::Result<TrowServerBuilder> {
+        self.immutable_tags = selectors
+            .iter()
+            .map(|s| s.parse())
+            .collect::<anyhow::Result<Vec<TagSelector>>>()?;
+        Ok(self)
+    }
+
+    /*
+     * Only admit images already running in a namespace during the given windows, see freeze.rs
+     * for the format.
+     *
+     * Fails if any of the windows are invalid.
+     */
+    pub fn add_freeze_windows(mut self, windows: Vec<String>) -> anyhow::Result<TrowServerBuilder> {
+        self.freeze_windows = windows
+            .iter()
+            .map(|w| w.parse())
+            .collect::<anyhow::Result<Vec<FreezeWindow>>>()?;
+        Ok(self)
+    }
+
+    /*
+     * Record which tags are running in the cluster every interval e.g. "1h", for the usage
+     * report (see usage.rs). An interval of "0" only records them when a usage job is started.
+     */
+    pub fn add_usage_interval(mut self, interval: &str) -> anyhow::Result<TrowServerBuilder> {
+        self.usage_interval = retention::parse_duration(interval)?;
+        Ok(self)
+    }
+
+    /*
+     * Compare sample_size proxied tags, picked at random, with upstream every interval e.g. "6h"
+     * and report any drift (see proxy_check.rs). An interval of "0" only compares them when a
+     * proxy-check job is started.
+     */
+    pub fn add_proxy_check(
+        mut self,
+        interval: &str,
+        sample_size: usize,
+    ) -> anyhow::Result<TrowServerBuilder> {
+        self.proxy_check_interval = retention::parse_duration(interval)?;
+        self.proxy_check_sample = sample_size;
+        Ok(self)
+    }
+
+    /*
+     * Back up the registry to backup_dir every interval e.g. "24h", copying only new blobs (see
+     * backup.rs). An interval of "0" only backs up when a backup job is started.
+     */
+    pub fn add_backup(
+        mut self,
+        backup_dir: &str,
+        interval: &str,
+    ) -> anyhow::Result<TrowServerBuilder> {
+        self.backup_interval = retention::parse_duration(interval)?;
+        self.backup_dir = Some(backup_dir.to_string());
+        Ok(self)
+    }
+
+    /*
+     * Check every blob still matches its digest and every tag's blobs are stored, every interval
+     * e.g. "7d" (see scrub.rs). An interval of "0" only checks when a scrub job is started.
+     * Corrupt blobs are moved to the quarantine dir if quarantine is set.
+     */
+    pub fn add_scrub(
+        mut self,
+        interval: &str,
+        quarantine: bool,
+    ) -> anyhow::Result<TrowServerBuilder> {
+        self.scrub_interval = retention::parse_duration(interval)?;
+        self.scrub_quarantine = quarantine;
+        Ok(self)
+    }
+
+    /*
+     * Make gzip and zstd renditions of layers pulled at least min_pulls times, every interval e.g.
+     * "1h" (see transcode.rs). An interval of "0" only transcodes when a transcode job is started.
+     * With on_demand, layers are also transcoded as soon as a client asks for the other
+     * compression.
+     */
+    pub fn add_transcoding(
+        mut self,
+        min_pulls: u64,
+        interval: &str,
+        on_demand: bool,
+    ) -> anyhow::Result<TrowServerBuilder> {
+        self.transcode_interval = retention::parse_duration(interval)?;
+        self.transcode_min_pulls = Some(min_pulls);
+        self.transcode_on_demand = on_demand;
+        Ok(self)
+    }
+
+    /*
+     * Convert pushed layers to eStargz every interval e.g. "1h" (see estargz.rs). An interval of
+     * "0" only converts them when an estargz job is started.
+     */
+    pub fn add_estargz(mut self, interval: &str) -> anyhow::Result<TrowServerBuilder> {
+        self.estargz_interval = Some(retention::parse_duration(interval)?);
+        Ok(self)
+    }
+
+    /*
+     * Refuse new uploads while the data dir or uploads dir has less than reserve available,
+     * either a size such as "10G" or a percentage such as "5%" (see capacity.rs).
+     */
+    pub fn add_storage_reserve(mut self, reserve: &str) -> anyhow::Result<TrowServerBuilder> {
+        self.storage_reserve = Some(reserve.parse()?);
+        Ok(self)
+    }
+
+    /// Reject pushed images with more than max_layers layers, or 0 for no limit
+    pub fn add_max_layers(mut self, max_layers: usize) -> TrowServerBuilder {
+        self.max_layers = max_layers;
+        self
+    }
+
+    /*
+     * Remove uploads nothing has been written to for longer than ttl e.g. "24h", along with
+     * anything else that old in the scratch dir (see uploads.rs). A ttl of "0" keeps them forever.
+     */
+    pub fn add_upload_ttl(mut self, ttl: &str) -> anyhow::Result<TrowServerBuilder> {
+        self.upload_ttl = retention::parse_duration(ttl)?;
+        Ok(self)
+    }
+
+    /*
+     * Keep deleted manifests and repositories for window e.g. "7d", during which they can be
+     * restored, before garbage collection removes them (see trash.rs). A window of "0" deletes
+     * them at once.
+     */
+    pub fn add_trash_retention(mut self, window: &str) -> anyhow::Result<TrowServerBuilder> {
+        self.trash_window = retention::parse_duration(window)?;
+        Ok(self)
+    }
+
+    /*
+     * Write uploads in progress to dir rather than the data dir, e.g. a local SSD when the data
+     * dir is on network storage (see uploads.rs).
+     */
+    pub fn add_scratch_dir(mut self, dir: &str) -> TrowServerBuilder {
+        self.scratch_dir = Some(dir.to_string());
+        self
+    }
+
+    /*
+     * Keep the registry in the bucket at url e.g. "gs://my-bucket", writing every change through
+     * and bringing the data dir up to date from it at startup (see storage.rs).
+     */
+    pub fn add_storage(mut self, url: &str) -> TrowServerBuilder {
+        self.storage_url = Some(url.to_string());
+        self
+    }
+
+    /*
+     * Mirror Docker Hub images into the proxy cache when pods using them are admitted, fetching
+     * up to workers at once and queueing up to queue_size more (see mirror.rs).
+     */
+    pub fn add_admission_mirroring(
+        mut self,
+        workers: usize,
+        queue_size: usize,
+    ) -> TrowServerBuilder {
+        self.mirror_workers = workers;
+        self.mirror_queue_size = queue_size;
+        self
+    }
+
+    /*
+     * Proxies for particular upstream hosts, overriding HTTP_PROXY etc. from the environment,
+     * see egress.rs for the format.
+     *
+     * Fails if any of the rules are invalid.
+     */
+    pub fn add_upstream_proxies(mut self, rules: Vec<String>) -> anyhow::Result<TrowServerBuilder> {
+        self.upstream_proxies = rules
+            .iter()
+            .map(|r| r.parse())
+            .collect::<anyhow::Result<Vec<ProxyRule>>>()?;
+        Ok(self)
+    }
+
+    /*
+     * Keep tag and manifest metadata in a SQLite database at the given path, which is used for
+     * the catalog, tag lists and garbage collection instead of reading the data dir.
+     */
+    pub fn add_metadata_db(mut self, db_path: &str) -> TrowServerBuilder {
+        self.metadata_db = Some(db_path.to_string());
+        self
+    }
+
+    /*
+     * Admit images according to TrowPolicy resources as well, watching the Kubernetes API for
+     * changes to them (see trow_policy.rs).
+     */
+    pub fn watch_policies(mut self) -> TrowServerBuilder {
+        self.watch_policies = true;
+        self
+    }
+
+    /*
+     * Deny pods using images from this registry that haven't been pushed to it, even if they're
+     * on an allow list, e.g. to only deploy what was built.
+     */
+    pub fn require_existing_images(mut self) -> TrowServerBuilder {
+        self.require_existing_images = true;
+        self
+    }
+
+    /*
+     * Take a lease on the data directory, renewed for as long as the process runs, so a second
+     * backend pointed at the same volume fails to start (see lease.rs).
+     *
+     * Can take 30 seconds if an old lease is found. Fails if it's still in use.
+     */
+    pub fn lock_data_dir(self) -> anyhow::Result<TrowServerBuilder> {
+        let lease = DataDirLease::acquire(std::path::Path::new(&self.data_path), lease::LEASE_TTL)?;
+        lease.keep_renewed();
+        Ok(self)
+    }
+
+    /*
+     * Use mutual TLS with the given SVID for the gRPC listener. Only clients presenting an SVID
+     * with the same SPIFFE ID are accepted.
+     */
+    pub fn add_spiffe(mut self, svid: Arc<SvidSource>) -> TrowServerBuilder {
+        self.spiffe = Some(svid);
+        self
+    }
+
+    /// Start in read-only maintenance mode, refusing uploads, manifest writes and deletes
+    pub fn read_only(mut self) -> TrowServerBuilder {
+        self.read_only = true;
+        self
+    }
+
+    /*
+     * Upgrades the data dir to the current layout instead of starting, resuming a migration that
+     * was interrupted, then verifies it (see layout.rs). Only verifies it if verify_only.
+     *
+     * With storage, the data dir is brought up to date from the bucket first and the changes are
+     * written back after.
+     */
+    pub fn migrate(self, verify_only: bool) -> anyhow::Result<MigrationReport> {
+        let data_path = std::path::Path::new(&self.data_path);
+        let storage = match &self.storage_url {
+            Some(url) => {
+                let storage =
+                    Storage::open(url, data_path, &EgressProxies::new(self.upstream_proxies))?;
+                storage.restore()?;
+                Some(storage)
+            }
+            None => None,
+        };
+        if verify_only {
+            return layout::verify(data_path);
+        }
+        let report = layout::migrate(data_path)?;
+        if let Some(s) = &storage {
+            s.sync_migrated()?;
+        }
+        Ok(report)
+    }
+
     pub fn start_trow_sync(self) {
-        let server = self.get_server_future();
         let rt = Runtime::new().expect("Failed to start Tokio runtime");
+        // The listeners are registered with the runtime
+        let _guard = rt.enter();
+        let server = match self.get_server_future(std::future::pending()) {
+            Ok(server) => server,
+            Err(e) => {
+                eprintln!("Failure in Trow server: {}", e);
+                std::process::exit(1);
+            }
+        };
 
         debug!("Trow backend service running");
 
@@ -85,7 +578,70 @@ impl TrowServerBuilder {
         }
     }
 
-    pub fn get_server_future(self) -> impl Future<Output = Result<(), tonic::transport::Error>> {
+    /*
+     * Runs the gRPC server until shutdown completes, then finishes the calls in flight.
+     *
+     * Connections are accepted on all the listen addresses, which are bound before returning so
+     * an address in use is reported straight away. Must be called within a Tokio runtime.
+     */
+    pub fn get_server_future(
+        self,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> std::io::Result<impl Future<Output = Result<(), tonic::transport::Error>>> {
+        let (incoming, socket_files) = listener::bind(&self.listen_addrs, self.socket_mode)?;
+        let svid = self.spiffe.clone();
+        let tls = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(
+                grpc_tls::server_tls_config(
+                    cert,
+                    key,
+                    self.root_key.as_deref(),
+                    self.require_client_cert,
+                )
+                .expect("Failure configuring gRPC TLS"),
+            ),
+            _ => None,
+        };
+        let ts = self.build_trow_server();
+
+        let mut server = Server::builder();
+        if let Some(svid) = svid {
+            server = server
+                .tls_config(spiffe::server_tls_config(svid))
+                .expect("Failure configuring SPIFFE TLS");
+        } else if let Some(tls) = tls {
+            server = server
+                .tls_config(tls)
+                .expect("Failure configuring gRPC TLS");
+        }
+        let future = server
+            .add_service(WithRequestId(Traced(RegistryServer::new(ts.clone()))))
+            .add_service(WithRequestId(Traced(AdmissionControllerServer::new(ts))))
+            .serve_with_incoming_shutdown(incoming, shutdown);
+        Ok(async move {
+            let served = future.await;
+            drop(socket_files);
+            served
+        })
+    }
+
+    fn build_trow_server(self) -> TrowServer {
+        let egress = EgressProxies::new(self.upstream_proxies);
+        let data_path = std::path::Path::new(&self.data_path);
+        // Before anything reads the data dir
+        let storage = self.storage_url.as_ref().map(|url| {
+            let storage =
+                Storage::open(url, data_path, &egress).expect("Failure configuring storage");
+            storage
+                .restore()
+                .expect("Failure reading the registry from storage");
+            storage
+        });
+        layout::check(data_path).expect("Failure checking the data directory");
+        if let Some(s) = &storage {
+            s.sync(&layout::path(data_path))
+                .expect("Failure writing the layout version to storage");
+        }
         let ts = TrowServer::new(
             &self.data_path,
             self.proxy_hub,
@@ -96,12 +652,146 @@ impl TrowServerBuilder {
             self.deny_prefixes,
             self.deny_images,
         )
-        .expect("Failure configuring Trow Server");
+        .expect("Failure configuring Trow Server")
+        .with_events(
+            EventPublisher::new(self.event_sinks, self.event_format, egress.clone())
+                .expect("Failure starting event publisher"),
+        )
+        .with_egress(&egress)
+        .expect("Failure configuring HTTP client")
+        .with_quotas(self.quotas)
+        .with_retention(self.retention.clone())
+        .with_proxy_check_sample(self.proxy_check_sample)
+        .with_max_layers(self.max_layers)
+        .with_immutable_tags(self.immutable_tags);
+        let ts = match &self.scratch_dir {
+            Some(dir) => ts
+                .with_uploads_dir(dir)
+                .expect("Failure creating scratch dir"),
+            None => ts,
+        };
+        let ts = match self.storage_reserve {
+            Some(reserve) => ts
+                .with_capacity_reserve(reserve)
+                .schedule_capacity_check(capacity::CHECK_INTERVAL),
+            None => ts,
+        };
+
+        let ts = if self.freeze_windows.is_empty() {
+            ts
+        } else {
+            ts.with_freeze_windows(self.freeze_windows, std::path::Path::new(&self.data_path))
+                .expect("Failure loading admitted images")
+        };
 
-        let future = Server::builder()
-            .add_service(RegistryServer::new(ts.clone()))
-            .add_service(AdmissionControllerServer::new(ts))
-            .serve(self.listen_addr);
-        future
+        let ts = match storage {
+            Some(storage) => ts.with_storage(storage),
+            None => ts,
+        };
+
+        let ts = match &self.metadata_db {
+            Some(db_path) => ts
+                .with_metadata(std::path::Path::new(db_path))
+                .expect("Failure opening metadata database"),
+            None => ts,
+        };
+
+        let ts = if self.watch_data_dir {
+            ts.watch_data_dir()
+                .expect("Failure watching data directory for changes")
+        } else {
+            ts
+        };
+        let ts = if self.watch_policies {
+            ts.watch_policies()
+                .expect("Failure watching TrowPolicy resources")
+        } else {
+            ts
+        };
+        let ts = if !self.trash_window.is_zero() {
+            ts.with_trash(self.trash_window)
+                .expect("Failure creating trash dir")
+        } else {
+            ts
+        };
+        let ts = if self.require_existing_images {
+            ts.with_existing_images_required()
+        } else {
+            ts
+        };
+        let ts = if self.read_only {
+            ts.with_read_only()
+        } else {
+            ts
+        };
+        let ts = if !self.usage_interval.is_zero() {
+            ts.schedule_usage(self.usage_interval)
+        } else {
+            ts
+        };
+        let ts = if self.proxy_hub && self.mirror_workers > 0 {
+            ts.with_admission_mirroring(self.mirror_workers, self.mirror_queue_size)
+        } else {
+            ts
+        };
+        let ts = match &self.backup_dir {
+            Some(dir) => ts.with_backup(Arc::new(
+                DirTarget::new(std::path::Path::new(dir)).expect("Failure creating backup dir"),
+            )),
+            None => ts,
+        };
+        let ts = match &self.backup_dir {
+            Some(_) if !self.backup_interval.is_zero() => ts.schedule_backup(self.backup_interval),
+            _ => ts,
+        };
+        let ts = if self.scrub_quarantine {
+            ts.with_scrub_quarantine()
+        } else {
+            ts
+        };
+        let ts = if !self.scrub_interval.is_zero() {
+            ts.schedule_scrub(self.scrub_interval)
+        } else {
+            ts
+        };
+        let ts = if !self.proxy_check_interval.is_zero() {
+            ts.schedule_proxy_check(self.proxy_check_interval)
+        } else {
+            ts
+        };
+        let ts = match self.transcode_min_pulls {
+            Some(min_pulls) => ts
+                .with_transcoding(min_pulls, self.transcode_on_demand)
+                .expect("Failure loading transcoded layers"),
+            None => ts,
+        };
+        let ts = match self.transcode_min_pulls {
+            Some(_) if !self.transcode_interval.is_zero() => {
+                ts.schedule_transcoding(self.transcode_interval)
+            }
+            _ => ts,
+        };
+        let ts = match self.estargz_interval {
+            Some(interval) => {
+                let ts = ts.with_estargz().expect("Failure loading eStargz layers");
+                if interval.is_zero() {
+                    ts
+                } else {
+                    ts.schedule_estargz(interval)
+                }
+            }
+            None => ts,
+        };
+        let ts = if !self.upload_ttl.is_zero() {
+            ts.schedule_upload_expiry(self.upload_ttl)
+        } else {
+            ts
+        };
+        // Annotations on manifests can expire them even without any rules
+        if !self.retention_interval.is_zero() {
+            ts.schedule_retention(self.retention_interval)
+        } else {
+            ts
+        }
     }
 }
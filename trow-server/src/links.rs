@@ -0,0 +1,130 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::maintenance::walk_files;
+
+/*
+ * Per-repository links to blobs in the shared store.
+ *
+ * Blobs are stored once under blobs/ no matter how many repositories push them. Each repository
+ * that uploads a blob gets an empty file at links/<repo>/_blobs/<alg>/<hex>, so deleting a blob
+ * from one repository doesn't take it away from the others.
+ *
+ * Repository path components can't start with _, so _blobs never clashes with a repository name.
+ * The link's mtime is when it was last uploaded, which garbage collection uses for its grace
+ * period.
+ */
+
+static LINKS_MARKER: &str = "_blobs";
+
+pub(crate) fn link_path(links_path: &Path, repo_name: &str, digest: &str) -> Option<PathBuf> {
+    let (alg, val) = digest.split_once(':')?;
+    Some(
+        links_path
+            .join(repo_name)
+            .join(LINKS_MARKER)
+            .join(alg)
+            .join(val),
+    )
+}
+
+/// Links the blob into the repository, or refreshes the link if it's already there
+pub fn link(links_path: &Path, repo_name: &str, digest: &str) -> Result<PathBuf> {
+    let path = link_path(links_path, repo_name, digest)
+        .ok_or_else(|| anyhow!("Invalid digest {}", digest))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, b"")?;
+    Ok(path)
+}
+
+/// Returns false if the repository didn't link to the blob
+pub fn unlink(links_path: &Path, repo_name: &str, digest: &str) -> Result<bool> {
+    let path = match link_path(links_path, repo_name, digest) {
+        Some(p) => p,
+        None => return Ok(false),
+    };
+    match fs::remove_file(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Removes all of the repository's links, e.g. when it's deleted
+pub fn unlink_repo(links_path: &Path, repo_name: &str) -> Result<()> {
+    match fs::remove_dir_all(links_path.join(repo_name).join(LINKS_MARKER)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+pub struct Link {
+    pub repo_name: String,
+    pub digest: String,
+    pub path: PathBuf,
+}
+
+pub fn all_links(links_path: &Path) -> Result<Vec<Link>> {
+    let mut links = vec![];
+    for path in walk_files(links_path)? {
+        let val = path.file_name().map(|v| v.to_string_lossy().to_string());
+        let alg_dir = path.parent();
+        let alg = alg_dir
+            .and_then(|a| a.file_name())
+            .map(|a| a.to_string_lossy().to_string());
+        let marker_dir = alg_dir.and_then(|a| a.parent());
+        let repo = marker_dir
+            .filter(|m| m.file_name().map_or(false, |n| n == LINKS_MARKER))
+            .and_then(|m| m.parent())
+            .and_then(|r| r.strip_prefix(links_path).ok())
+            .map(|r| r.to_string_lossy().to_string());
+        if let (Some(repo_name), Some(alg), Some(val)) = (repo, alg, val) {
+            links.push(Link {
+                repo_name,
+                digest: format!("{}:{}", alg, val),
+                path,
+            });
+        }
+    }
+    Ok(links)
+}
+
+/// Every repository linking to the blob
+pub fn linked_repos(links_path: &Path, digest: &str) -> Result<Vec<String>> {
+    Ok(all_links(links_path)?
+        .into_iter()
+        .filter(|l| l.digest == digest)
+        .map(|l| l.repo_name)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{all_links, link, linked_repos, unlink};
+    use tempfile::tempdir;
+
+    #[test]
+    fn links_are_per_repo() {
+        let dir = tempdir().unwrap();
+        let digest = format!("sha256:{}", "a".repeat(64));
+
+        link(dir.path(), "org/app", &digest).unwrap();
+        link(dir.path(), "other", &digest).unwrap();
+        // Linking again is fine
+        link(dir.path(), "other", &digest).unwrap();
+
+        let mut repos = linked_repos(dir.path(), &digest).unwrap();
+        repos.sort();
+        assert_eq!(repos, vec!["org/app", "other"]);
+        assert_eq!(all_links(dir.path()).unwrap().len(), 2);
+
+        assert!(unlink(dir.path(), "org/app", &digest).unwrap());
+        assert!(!unlink(dir.path(), "org/app", &digest).unwrap());
+        assert_eq!(linked_repos(dir.path(), &digest).unwrap(), vec!["other"]);
+    }
+}
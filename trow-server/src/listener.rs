@@ -0,0 +1,195 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::stream::{BoxStream, StreamExt};
+use log::warn;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+use tonic::transport::server::Connected;
+
+/*
+ * Where the backend listens for gRPC.
+ *
+ * As well as TCP addresses, the backend can listen on a Unix socket for a frontend on the same
+ * host or in the same pod. That skips the network stack, and only processes able to open the
+ * socket file can connect, rather than anything that can reach the port. The socket file is
+ * created with the permissions given to the builder, replacing one left behind by a backend that
+ * didn't shut down cleanly, and removed once the server stops.
+ */
+
+pub const UNIX_SCHEME: &str = "unix://";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_SCHEME, path.display()),
+        }
+    }
+}
+
+pub(crate) enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connected for Connection {
+    type ConnectInfo = ();
+
+    fn connect_info(&self) -> Self::ConnectInfo {}
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Connection::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Connection::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Connection::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Connection::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+// Removes the socket files when dropped, once the server has stopped
+pub(crate) struct SocketFiles(Vec<PathBuf>);
+
+impl Drop for SocketFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Failed to remove socket {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn with_address(e: io::Error, addr: &ListenAddr) -> io::Error {
+    io::Error::new(e.kind(), format!("Failed to listen on {}: {}", addr, e))
+}
+
+fn bind_unix(path: &Path, mode: u32) -> io::Result<std::os::unix::net::UnixListener> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file exists and isn't a socket",
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another backend is listening on the socket",
+            ));
+        }
+        // Nothing listening, so it's left from a backend that didn't shut down cleanly
+        fs::remove_file(path)?;
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/*
+ * Binds all the addresses, then accepts connections on any of them. Must be called within a Tokio
+ * runtime.
+ */
+pub(crate) fn bind(
+    addrs: &[ListenAddr],
+    socket_mode: u32,
+) -> io::Result<(BoxStream<'static, io::Result<Connection>>, SocketFiles)> {
+    let mut streams = vec![];
+    let mut files = SocketFiles(vec![]);
+    for addr in addrs {
+        match addr {
+            ListenAddr::Tcp(socket) => {
+                let listener =
+                    std::net::TcpListener::bind(socket).map_err(|e| with_address(e, addr))?;
+                listener.set_nonblocking(true)?;
+                let stream = TcpListenerStream::new(TcpListener::from_std(listener)?);
+                streams.push(stream.map(|s| s.map(Connection::Tcp)).boxed());
+            }
+            ListenAddr::Unix(path) => {
+                let listener = bind_unix(path, socket_mode).map_err(|e| with_address(e, addr))?;
+                files.0.push(path.clone());
+                listener.set_nonblocking(true)?;
+                let stream = UnixListenerStream::new(UnixListener::from_std(listener)?);
+                streams.push(stream.map(|s| s.map(Connection::Unix)).boxed());
+            }
+        }
+    }
+    Ok((futures::stream::select_all(streams).boxed(), files))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bind, ListenAddr};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn binds_unix_sockets() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("trow.sock");
+        let addrs = vec![ListenAddr::Unix(path.clone())];
+
+        // Left behind by a backend that stopped without removing it
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let (incoming, files) = bind(&addrs, 0o660).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        // In use while the first is listening
+        assert!(bind(&addrs, 0o660).is_err());
+        drop(incoming);
+        drop(files);
+        assert!(!path.exists());
+
+        // Not a socket, so not replaced
+        fs::write(&path, "data").unwrap();
+        assert!(bind(&addrs, 0o660).is_err());
+        assert!(path.exists());
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::events::{Event, EventAction, EventPublisher};
+use crate::jobs::JobHandle;
+use crate::links;
+use crate::manifest::{FromJson, Manifest};
+use crate::metadata::MetadataStore;
+use crate::trash::Trash;
+
+// Blobs newer than this are never collected, as they may belong to a push that hasn't
+// uploaded its manifest yet.
+const GC_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+// Returns every file under the given directory
+pub(crate) fn walk_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    if !dir.exists() {
+        return Ok(files);
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            files.extend(walk_files(&entry.path())?);
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+pub(crate) fn blob_path(blobs_path: &Path, digest: &str) -> Option<PathBuf> {
+    let (alg, val) = digest.split_once(':')?;
+    Some(blobs_path.join(alg).join(val))
+}
+
+/*
+ * Finds every blob reachable from a tag.
+ *
+ * All digests in a tag's history are included, so old versions of a tag can still be pulled by
+ * digest. Manifest lists are followed to the manifests they point to.
+ */
+pub(crate) fn referenced_digests(
+    manifests_path: &Path,
+    blobs_path: &Path,
+) -> Result<HashSet<String>> {
+    referenced_by_tags(&walk_files(manifests_path)?, blobs_path)
+}
+
+// As referenced_digests, for just the given tag files
+pub(crate) fn referenced_by_tags(tags: &[PathBuf], blobs_path: &Path) -> Result<HashSet<String>> {
+    let mut to_visit = vec![];
+    for tag in tags {
+        let file = BufReader::new(File::open(tag)?);
+        for line in file.lines() {
+            if let Some(digest) = line?.split(' ').next() {
+                if !digest.is_empty() {
+                    to_visit.push(digest.to_string());
+                }
+            }
+        }
+    }
+    Ok(follow_manifests(to_visit, blobs_path))
+}
+
+// The given digests, and everything the manifests among them refer to
+pub(crate) fn follow_manifests(mut to_visit: Vec<String>, blobs_path: &Path) -> HashSet<String> {
+    let mut referenced = HashSet::new();
+    while let Some(digest) = to_visit.pop() {
+        if !referenced.insert(digest.clone()) {
+            continue;
+        }
+        // Only manifests are JSON with a schemaVersion, layers just fail to parse
+        let manifest = blob_path(blobs_path, &digest)
+            .and_then(|p| fs::read(p).ok())
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .and_then(|v| Manifest::from_json(&v).ok());
+        if let Some(manifest) = manifest {
+            to_visit.extend(
+                manifest
+                    .get_local_asset_digests()
+                    .into_iter()
+                    .map(|d| d.to_string()),
+            );
+        }
+    }
+    referenced
+}
+
+pub(crate) fn digest_for_blob(blobs_path: &Path, blob: &Path) -> Option<String> {
+    let rel = blob.strip_prefix(blobs_path).ok()?;
+    let alg = rel.parent()?.to_str()?;
+    let val = rel.file_name()?.to_str()?;
+    Some(format!("{}:{}", alg, val))
+}
+
+/*
+ * Removes links to blobs the repository's tags, including those in the trash, no longer use,
+ * returning the digests that are still linked from somewhere.
+ *
+ * Links newer than the cutoff are kept, as the push they belong to may not have uploaded its
+ * manifest yet.
+ */
+fn prune_links(
+    manifests_path: &Path,
+    blobs_path: &Path,
+    links_path: &Path,
+    trashed: &HashMap<String, Vec<PathBuf>>,
+    cutoff: SystemTime,
+) -> Result<HashSet<String>> {
+    let mut used: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut linked = HashSet::new();
+    for link in links::all_links(links_path)? {
+        if !used.contains_key(&link.repo_name) {
+            let repo_path = manifests_path.join(&link.repo_name);
+            let mut digests = referenced_digests(&repo_path, blobs_path)?;
+            if let Some(files) = trashed.get(&link.repo_name) {
+                digests.extend(referenced_by_tags(files, blobs_path)?);
+            }
+            used.insert(link.repo_name.clone(), digests);
+        }
+        let recent = fs::metadata(&link.path)?.modified()? > cutoff;
+        if recent || used[&link.repo_name].contains(&link.digest) {
+            linked.insert(link.digest);
+            continue;
+        }
+        match fs::remove_file(&link.path) {
+            Ok(_) => info!("Unlinked {} from {}", link.digest, link.repo_name),
+            Err(e) => {
+                warn!("Failed to remove link {:?}: {:?}", link.path, e);
+                linked.insert(link.digest);
+            }
+        }
+    }
+    Ok(linked)
+}
+
+/**
+ * Deletes blobs that aren't referenced by any tag or linked from any repository.
+ *
+ * References are looked up in the metadata database if there is one, rather than parsing every
+ * manifest. Deleted tags still in the trash count as references, and the trash is emptied of
+ * those past the restore window first.
+ */
+pub fn garbage_collect(
+    manifests_path: &Path,
+    blobs_path: &Path,
+    links_path: &Path,
+    metadata: Option<&MetadataStore>,
+    trash: Option<&Trash>,
+    events: &EventPublisher,
+    handle: &JobHandle,
+) -> Result<String> {
+    let now = SystemTime::now();
+    let cutoff = now - GC_GRACE_PERIOD;
+    let trashed = match trash {
+        Some(t) => {
+            t.purge_expired(now)?;
+            t.tag_files()?
+        }
+        None => HashMap::new(),
+    };
+    let linked = prune_links(manifests_path, blobs_path, links_path, &trashed, cutoff)?;
+    let mut referenced = match metadata {
+        Some(m) => m.referenced_digests(blobs_path)?,
+        None => referenced_digests(manifests_path, blobs_path)?,
+    };
+    let trashed: Vec<PathBuf> = trashed.into_values().flatten().collect();
+    referenced.extend(referenced_by_tags(&trashed, blobs_path)?);
+    let blobs = walk_files(blobs_path)?;
+
+    let mut deleted = 0;
+    let mut freed = 0;
+    for (i, blob) in blobs.iter().enumerate() {
+        if handle.is_cancelled() {
+            return Ok(format!(
+                "Cancelled after deleting {} blobs ({} bytes)",
+                deleted, freed
+            ));
+        }
+        handle.set_progress(i, blobs.len());
+
+        let digest = match digest_for_blob(blobs_path, blob) {
+            Some(d) => d,
+            None => continue,
+        };
+        if referenced.contains(&digest) || linked.contains(&digest) {
+            continue;
+        }
+        let metadata = fs::metadata(blob)?;
+        if metadata.modified()? > cutoff {
+            continue;
+        }
+        match fs::remove_file(blob) {
+            Ok(_) => {
+                info!("Garbage collected {}", digest);
+                events.publish(Event::new(EventAction::Gc, "", None, &digest));
+                deleted += 1;
+                freed += metadata.len();
+            }
+            Err(e) => warn!("Failed to delete blob {}: {:?}", digest, e),
+        }
+    }
+    Ok(format!(
+        "Deleted {} unreferenced blobs ({} bytes)",
+        deleted, freed
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::referenced_digests;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn follows_manifests_to_layers() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        let blobs = dir.path().join("blobs");
+        fs::create_dir_all(manifests.join("repo")).unwrap();
+        fs::create_dir_all(blobs.join("sha256")).unwrap();
+
+        let manifest = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": { "mediaType": "application/vnd.docker.container.image.v1+json", "size": 1, "digest": "sha256:config" },
+            "layers": [ { "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "size": 1, "digest": "sha256:layer" } ]
+        }"#;
+        fs::write(blobs.join("sha256").join("manifest"), manifest).unwrap();
+        fs::write(blobs.join("sha256").join("unused"), "junk").unwrap();
+        fs::write(
+            manifests.join("repo").join("latest"),
+            "sha256:manifest 2022-01-01T00:00:00Z\n",
+        )
+        .unwrap();
+
+        let referenced = referenced_digests(&manifests, &blobs).unwrap();
+        assert!(referenced.contains("sha256:manifest"));
+        assert!(referenced.contains("sha256:config"));
+        assert!(referenced.contains("sha256:layer"));
+        assert!(!referenced.contains("sha256:unused"));
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
@@ -27,6 +29,7 @@ pub trait FromJson {
 pub enum Manifest {
     List(ManifestList),
     V2(ManifestV2),
+    Artifact(ArtifactManifest),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -66,6 +69,31 @@ pub struct ManifestV2 {
     pub media_type: Option<String>, //TODO: make enum
     pub config: Object,
     pub layers: Vec<Object>,
+    // Set by artifacts that use an image manifest, taken from the config if not
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Object>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+/*
+ * An OCI artifact manifest, as pushed by ORAS for SBOMs, signatures, WASM modules, policy bundles
+ * and the like. The blobs can be anything, and don't have to include a config. The subject is the
+ * manifest the artifact is about, which doesn't have to be stored for the artifact to be pushed.
+ */
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArtifactManifest {
+    pub media_type: String,
+    pub artifact_type: String,
+    #[serde(default)]
+    pub blobs: Vec<Object>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Object>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -77,9 +105,27 @@ pub struct Object {
 }
 
 #[derive(Error, Debug)]
-#[error("Invalid Manifest: {err:?}")]
+#[error("Invalid manifest: {err}")]
 pub struct InvalidManifest {
-    err: String,
+    pub(crate) err: String,
+}
+
+const FOREIGN_LAYER: &str = "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
+
+// Only sha256 digests are supported, always as lowercase hex
+fn check_digest(digest: &str) -> Result<()> {
+    let valid = match digest.strip_prefix("sha256:") {
+        Some(hex) => hex.len() == 64 && hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')),
+        None => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(InvalidManifest {
+            err: format!("{} is not a valid sha256 digest", digest),
+        }
+        .into())
+    }
 }
 
 // TODO: Consider changing this to enum with as_str() impl?
@@ -89,6 +135,7 @@ pub mod manifest_media_type {
     pub const OCI_V1: &str = "application/vnd.oci.image.manifest.v1+json";
     pub const DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
     pub const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+    pub const OCI_ARTIFACT: &str = "application/vnd.oci.artifact.manifest.v1+json";
 
     // Weirdly the media type is optional in the JSON, so assume OCI_V1.
     // TODO: Check if we should be falling back to mime type
@@ -103,13 +150,19 @@ fn schema_2(raw: &Value) -> Result<Manifest> {
         .unwrap_or(manifest_media_type::DEFAULT);
 
     match mt {
-        manifest_media_type::DOCKER_V2 | manifest_media_type::OCI_V1 => {
-            Ok(Manifest::V2(serde_json::from_value(raw.clone())?))
-        }
+        manifest_media_type::DOCKER_V2 | manifest_media_type::OCI_V1 => Ok(Manifest::V2(
+            serde_json::from_value(raw.clone()).map_err(|e| InvalidManifest {
+                err: format!("{}: {}", mt, e),
+            })?,
+        )),
 
-        manifest_media_type::DOCKER_LIST | manifest_media_type::OCI_INDEX => {
-            Ok(Manifest::List(serde_json::from_value(raw.clone())?))
-        }
+        manifest_media_type::DOCKER_LIST | manifest_media_type::OCI_INDEX => Ok(Manifest::List(
+            serde_json::from_value(raw.clone()).map_err(|e| InvalidManifest {
+                err: format!("{}: {}", mt, e),
+            })?,
+        )),
+
+        manifest_media_type::OCI_ARTIFACT => artifact(raw),
 
         unknown => Err(InvalidManifest {
             err: format!("Media Type {} is not supported.", unknown),
@@ -118,8 +171,20 @@ fn schema_2(raw: &Value) -> Result<Manifest> {
     }
 }
 
+fn artifact(raw: &Value) -> Result<Manifest> {
+    Ok(Manifest::Artifact(
+        serde_json::from_value(raw.clone()).map_err(|e| InvalidManifest {
+            err: format!("{}: {}", manifest_media_type::OCI_ARTIFACT, e),
+        })?,
+    ))
+}
+
 impl FromJson for Manifest {
     fn from_json(raw: &Value) -> Result<Self> {
+        // Artifact manifests are the only kind without a schemaVersion
+        if raw["mediaType"].as_str() == Some(manifest_media_type::OCI_ARTIFACT) {
+            return artifact(raw);
+        }
         let schema_version = raw["schemaVersion"].as_u64().ok_or(InvalidManifest {
             err: "schemaVersion is required".to_owned(),
         })?;
@@ -141,28 +206,78 @@ impl Manifest {
     /// Returns a Vector of the digests of all assets referenced in the Manifest
     /// With the exception of digests for "foreign blobs"
     pub fn get_local_asset_digests(&self) -> Vec<&str> {
+        self.get_local_assets()
+            .into_iter()
+            .map(|(digest, _)| digest)
+            .collect()
+    }
+
+    /// As get_local_asset_digests, with the size each is given in the manifest
+    pub fn get_local_assets(&self) -> Vec<(&str, Option<u64>)> {
         match *self {
             Manifest::V2(ref m2) => {
-                let mut digests: Vec<&str> = m2
+                let mut assets: Vec<(&str, Option<u64>)> = m2
                     .layers
                     .iter()
-                    .filter(|x| {
-                        x.media_type != "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip"
-                    })
-                    .map(|x| x.digest.as_str())
+                    .filter(|x| x.media_type != FOREIGN_LAYER)
+                    .map(|x| (x.digest.as_str(), x.size))
                     .collect();
-                digests.push(&m2.config.digest);
-                digests
+                assets.push((&m2.config.digest, m2.config.size));
+                assets
             }
             Manifest::List(ref list) => {
                 // Just return the manifest digests.
                 // We could recurse into the manifests, but they should have been checked already.
 
-                list.manifests.iter().map(|x| x.digest.as_str()).collect()
+                list.manifests
+                    .iter()
+                    .map(|x| (x.digest.as_str(), Some(x.size as u64)))
+                    .collect()
             }
+            // The subject isn't included, as it's stored separately if at all
+            Manifest::Artifact(ref artifact) => artifact
+                .blobs
+                .iter()
+                .map(|x| (x.digest.as_str(), x.size))
+                .collect(),
         }
     }
 
+    /*
+     * Checks what parsing doesn't for a pushed manifest: every descriptor has a digest Trow can
+     * store, foreign layers included. Whether the blobs exist is up to the caller.
+     */
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            Manifest::V2(ref m2) => {
+                check_digest(&m2.config.digest)?;
+                for layer in &m2.layers {
+                    check_digest(&layer.digest)?;
+                }
+            }
+            Manifest::List(ref list) => {
+                for entry in &list.manifests {
+                    check_digest(&entry.digest)?;
+                }
+            }
+            Manifest::Artifact(ref artifact) => {
+                if artifact.artifact_type.is_empty() {
+                    return Err(InvalidManifest {
+                        err: "artifactType is required".to_owned(),
+                    }
+                    .into());
+                }
+                for blob in &artifact.blobs {
+                    check_digest(&blob.digest)?;
+                }
+            }
+        }
+        if let Some(subject) = self.subject() {
+            check_digest(&subject.digest)?;
+        }
+        Ok(())
+    }
+
     // TODO: use proper enums and return &str
     pub fn get_media_type(&self) -> String {
         match *self {
@@ -172,6 +287,39 @@ impl Manifest {
                 .unwrap_or(&manifest_media_type::DEFAULT.to_string())
                 .to_string(),
             Manifest::List(ref list) => list.media_type.clone(),
+            Manifest::Artifact(ref artifact) => artifact.media_type.clone(),
+        }
+    }
+
+    /// The manifest this one refers to, for manifests listed by the referrers API
+    pub fn subject(&self) -> Option<&Object> {
+        match *self {
+            Manifest::V2(ref m2) => m2.subject.as_ref(),
+            Manifest::List(_) => None,
+            Manifest::Artifact(ref artifact) => artifact.subject.as_ref(),
+        }
+    }
+
+    /*
+     * What kind of artifact this is, as listed by the referrers API. Images fall back to the media
+     * type of their config, as the spec asks, which is how artifacts pushed as images are told
+     * apart. Manifest lists have none.
+     */
+    pub fn artifact_type(&self) -> Option<&str> {
+        match *self {
+            Manifest::V2(ref m2) => {
+                Some(m2.artifact_type.as_deref().unwrap_or(&m2.config.media_type))
+            }
+            Manifest::List(_) => None,
+            Manifest::Artifact(ref artifact) => Some(&artifact.artifact_type),
+        }
+    }
+
+    pub fn annotations(&self) -> Option<&HashMap<String, String>> {
+        match *self {
+            Manifest::V2(ref m2) => m2.annotations.as_ref(),
+            Manifest::List(_) => None,
+            Manifest::Artifact(ref artifact) => artifact.annotations.as_ref(),
         }
     }
 }
@@ -223,7 +371,7 @@ mod test {
         // There's probably an easier way to do this
         let m_v2 = match mani {
             Manifest::V2(ref m2) => m2,
-            Manifest::List(_) => panic!(),
+            _ => panic!(),
         };
 
         assert_eq!(
@@ -283,7 +431,7 @@ mod test {
         // There's probably an easier way to do this
         let m_v2 = match mani {
             Manifest::V2(ref m2) => m2,
-            Manifest::List(_) => panic!(),
+            _ => panic!(),
         };
 
         assert_eq!(
@@ -371,4 +519,91 @@ mod test {
         let v: Value = serde_json::from_str(&data).unwrap();
         assert!(Manifest::from_json(&v).is_ok());
     }
+
+    #[test]
+    fn validates_descriptors() {
+        let manifest = |digest: &str| {
+            format!(
+                r#"{{ "schemaVersion": 2,
+                     "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                     "config": {{ "mediaType": "application/vnd.oci.image.config.v1+json",
+                                  "size": 3, "digest": "{}" }},
+                     "layers": [] }}"#,
+                digest
+            )
+        };
+        let parse = |data: &str| Manifest::from_json(&serde_json::from_str(data).unwrap());
+
+        let valid = parse(&manifest(&format!("sha256:{}", "a".repeat(64)))).unwrap();
+        assert!(valid.validate().is_ok());
+        assert_eq!(valid.get_local_assets()[0].1, Some(3));
+        for digest in [
+            "sha256:../../etc/passwd",
+            "sha256:abc",
+            &format!("sha256:{}", "A".repeat(64)),
+            &format!("md5:{}", "a".repeat(64)),
+        ] {
+            assert!(parse(&manifest(digest)).unwrap().validate().is_err());
+        }
+
+        // Missing the config
+        let err = parse(r#"{"schemaVersion": 2, "layers": []}"#)
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("Invalid manifest"));
+    }
+
+    #[test]
+    fn valid_artifact() {
+        let blob = format!("sha256:{}", "a".repeat(64));
+        let subject = format!("sha256:{}", "b".repeat(64));
+        let data = format!(
+            r#"{{ "mediaType": "application/vnd.oci.artifact.manifest.v1+json",
+                 "artifactType": "application/spdx+json",
+                 "blobs": [{{ "mediaType": "application/spdx+json", "size": 12, "digest": "{}" }}],
+                 "subject": {{ "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                              "size": 100, "digest": "{}" }},
+                 "annotations": {{ "org.opencontainers.artifact.created": "2022-03-01T00:00:00Z" }} }}"#,
+            blob, subject
+        );
+        let v: Value = serde_json::from_str(&data).unwrap();
+        let mani = Manifest::from_json(&v).unwrap();
+        assert!(mani.validate().is_ok());
+        assert_eq!(
+            mani.get_media_type(),
+            "application/vnd.oci.artifact.manifest.v1+json"
+        );
+        assert_eq!(mani.artifact_type(), Some("application/spdx+json"));
+        assert_eq!(mani.subject().unwrap().digest, subject);
+        assert_eq!(mani.get_local_assets(), vec![(blob.as_str(), Some(12))]);
+        assert_eq!(mani.annotations().unwrap().len(), 1);
+
+        // Required, as it's what the referrers API filters by
+        let untyped = data.replace("\"artifactType\": \"application/spdx+json\",", "");
+        let v: Value = serde_json::from_str(&untyped).unwrap();
+        assert!(Manifest::from_json(&v).is_err());
+    }
+
+    #[test]
+    fn image_artifact_type() {
+        let data = format!(
+            r#"{{ "schemaVersion": 2,
+                 "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                 "config": {{ "mediaType": "application/vnd.cncf.notary.signature",
+                              "size": 2, "digest": "sha256:{}" }},
+                 "layers": [],
+                 "subject": {{ "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                              "size": 100, "digest": "sha256:{}" }} }}"#,
+            "a".repeat(64),
+            "b".repeat(64)
+        );
+        let v: Value = serde_json::from_str(&data).unwrap();
+        let mani = Manifest::from_json(&v).unwrap();
+        assert_eq!(
+            mani.artifact_type(),
+            Some("application/vnd.cncf.notary.signature")
+        );
+        assert!(mani.subject().is_some());
+        assert!(mani.annotations().is_none());
+    }
 }
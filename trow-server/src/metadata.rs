@@ -0,0 +1,618 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use crate::index_summary::IndexSummary;
+use crate::maintenance::{blob_path, walk_files};
+use crate::manifest::{FromJson, Manifest};
+use crate::quota::blob_size;
+use crate::referrers::{as_referrer, Referrer};
+
+/*
+ * SQLite database of repositories, tags, manifests and the blobs they reference.
+ *
+ * The files in the data directory are still the source of truth. The database is rebuilt from
+ * them at startup and kept up to date by Trow's own writes, so listing tags or working out what
+ * is referenced doesn't mean walking and parsing every file.
+ *
+ * Tag rows mirror the lines of the tag files, position 0 being the first line. Manifests with a
+ * subject are kept as referrers of it, whichever repositories they're in.
+ *
+ * Pull counts are the exception, as they aren't in the data directory. They're only kept here, so
+ * they survive syncs but are lost if the database is deleted. They're kept after the tag or
+ * digest they were pulled by is deleted, as the history of what was pulled.
+ */
+pub struct MetadataStore {
+    conn: Mutex<Connection>,
+}
+
+static SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tags (
+    repo TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    digest TEXT NOT NULL,
+    pushed TEXT NOT NULL,
+    PRIMARY KEY (repo, tag, position)
+);
+CREATE INDEX IF NOT EXISTS tags_by_digest ON tags (repo, digest);
+CREATE TABLE IF NOT EXISTS manifests (
+    digest TEXT PRIMARY KEY,
+    size INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS blob_refs (
+    manifest TEXT NOT NULL,
+    blob TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    PRIMARY KEY (manifest, blob)
+);
+CREATE TABLE IF NOT EXISTS pulls (
+    repo TEXT NOT NULL,
+    reference TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    last_pulled TEXT NOT NULL,
+    PRIMARY KEY (repo, reference)
+);
+CREATE TABLE IF NOT EXISTS index_summaries (
+    digest TEXT PRIMARY KEY,
+    summary TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS referrers (
+    digest TEXT PRIMARY KEY,
+    subject TEXT NOT NULL,
+    media_type TEXT NOT NULL,
+    artifact_type TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    annotations TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS referrers_by_subject ON referrers (subject);
+";
+
+// Pulls of a repository by one tag or digest
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReferencePulls {
+    pub reference: String,
+    pub pulls: u64,
+    pub last_pulled: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RepoPulls {
+    pub repo_name: String,
+    pub pulls: u64,
+    pub last_pulled: String,
+    // Most pulled first
+    pub references: Vec<ReferencePulls>,
+}
+
+// Digest and push time for each line of a tag file
+fn read_tag_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let file = match File::open(path) {
+        Ok(f) => BufReader::new(f),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = vec![];
+    for line in file.lines() {
+        let line = line?;
+        let mut parts = line.splitn(2, ' ');
+        let digest = parts.next().unwrap_or("").trim();
+        if digest.is_empty() {
+            continue;
+        }
+        let pushed = parts.next().unwrap_or("").trim();
+        lines.push((digest.to_string(), pushed.to_string()));
+    }
+    Ok(lines)
+}
+
+/*
+ * Records the manifest and what it references, if it's not already known. Manifest lists are
+ * followed to the manifests they point to, as long as they've been downloaded.
+ *
+ * Anything that isn't a manifest in the blobs dir is skipped, so this is safe to call on any
+ * digest.
+ */
+fn record_manifest(tx: &Transaction, blobs_path: &Path, digest: &str) -> Result<()> {
+    let mut to_visit = vec![digest.to_string()];
+    while let Some(digest) = to_visit.pop() {
+        let known: Option<i64> = tx
+            .query_row(
+                "SELECT size FROM manifests WHERE digest = ?1",
+                params![digest],
+                |r| r.get(0),
+            )
+            .optional()?;
+        if known.is_some() {
+            continue;
+        }
+        let bytes = match blob_path(blobs_path, &digest).and_then(|p| fs::read(p).ok()) {
+            Some(b) => b,
+            None => continue,
+        };
+        let manifest = match serde_json::from_slice(&bytes)
+            .ok()
+            .and_then(|v| Manifest::from_json(&v).ok())
+        {
+            Some(m) => m,
+            None => continue,
+        };
+
+        tx.execute(
+            "INSERT INTO manifests (digest, size) VALUES (?1, ?2)",
+            params![digest, bytes.len() as i64],
+        )?;
+        if let Some((subject, r)) = as_referrer(&manifest, &digest, bytes.len() as u64) {
+            tx.execute(
+                "INSERT OR REPLACE INTO referrers
+                 (digest, subject, media_type, artifact_type, size, annotations)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    digest,
+                    subject,
+                    r.media_type,
+                    r.artifact_type,
+                    r.size as i64,
+                    serde_json::to_string(&r.annotations)?
+                ],
+            )?;
+        }
+        for asset in manifest.get_local_asset_digests() {
+            tx.execute(
+                "INSERT OR IGNORE INTO blob_refs (manifest, blob, size) VALUES (?1, ?2, ?3)",
+                params![digest, asset, blob_size(blobs_path, asset) as i64],
+            )?;
+            to_visit.push(asset.to_string());
+        }
+    }
+    Ok(())
+}
+
+fn replace_tag(
+    tx: &Transaction,
+    blobs_path: &Path,
+    repo_name: &str,
+    tag: &str,
+    lines: &[(String, String)],
+) -> Result<()> {
+    tx.execute(
+        "DELETE FROM tags WHERE repo = ?1 AND tag = ?2",
+        params![repo_name, tag],
+    )?;
+    for (position, (digest, pushed)) in lines.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO tags (repo, tag, position, digest, pushed) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![repo_name, tag, position as i64, digest, pushed],
+        )?;
+        record_manifest(tx, blobs_path, digest)?;
+    }
+    Ok(())
+}
+
+impl MetadataStore {
+    pub fn open(db_path: &Path) -> Result<MetadataStore> {
+        let conn = Connection::open(db_path)?;
+        let had_referrers: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'referrers'",
+            [],
+            |r| r.get(0),
+        )?;
+        conn.execute_batch(SCHEMA)?;
+        if had_referrers == 0 {
+            // Recorded before referrers were, so recorded again by the next sync
+            conn.execute_batch("DELETE FROM manifests; DELETE FROM blob_refs;")?;
+        }
+        Ok(MetadataStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Throws away the tags and reads them from the manifests directory again.
+    pub fn sync(&self, manifests_path: &Path, blobs_path: &Path) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM tags", [])?;
+
+        let mut count = 0;
+        for path in walk_files(manifests_path)? {
+            let rel = path.strip_prefix(manifests_path)?;
+            let (repo, tag) = match (rel.parent(), rel.file_name()) {
+                (Some(r), Some(t)) if !r.as_os_str().is_empty() => {
+                    (r.to_string_lossy(), t.to_string_lossy())
+                }
+                // Files directly under the manifests dir aren't tags
+                _ => continue,
+            };
+            let lines = read_tag_file(&path)?;
+            replace_tag(&tx, blobs_path, &repo, &tag, &lines)?;
+            count += 1;
+        }
+        tx.commit()?;
+        info!("Metadata database synced with {} tags", count);
+        Ok(())
+    }
+
+    /// Adds a line to the start of the tag, matching the tag file, so it's the current digest.
+    pub fn add_tag(
+        &self,
+        blobs_path: &Path,
+        repo_name: &str,
+        tag: &str,
+        digest: &str,
+        pushed: &str,
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        // Via negative positions, as shifting up in place can collide part way
+        tx.execute(
+            "UPDATE tags SET position = -position - 1 WHERE repo = ?1 AND tag = ?2",
+            params![repo_name, tag],
+        )?;
+        tx.execute(
+            "UPDATE tags SET position = -position WHERE repo = ?1 AND tag = ?2",
+            params![repo_name, tag],
+        )?;
+        tx.execute(
+            "INSERT INTO tags (repo, tag, position, digest, pushed) VALUES (?1, ?2, 0, ?3, ?4)",
+            params![repo_name, tag, digest, pushed],
+        )?;
+        record_manifest(&tx, blobs_path, digest)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Re-reads a tag file after it was changed or deleted outside of add_tag.
+    pub fn reload_tag(
+        &self,
+        manifests_path: &Path,
+        blobs_path: &Path,
+        repo_name: &str,
+        tag: &str,
+    ) -> Result<()> {
+        let lines = read_tag_file(&manifests_path.join(repo_name).join(tag))?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        replace_tag(&tx, blobs_path, repo_name, tag, &lines)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn catalog(&self) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT DISTINCT repo FROM tags ORDER BY repo")?;
+        let repos = stmt
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(repos)
+    }
+
+    pub fn tags(&self, repo_name: &str) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT DISTINCT tag FROM tags WHERE repo = ?1 ORDER BY tag")?;
+        let tags = stmt
+            .query_map(params![repo_name], |r| r.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(tags)
+    }
+
+    pub fn tag_exists(&self, repo_name: &str, tag: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let found = conn
+            .query_row(
+                "SELECT 1 FROM tags WHERE repo = ?1 AND tag = ?2 LIMIT 1",
+                params![repo_name, tag],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// Whether any tag in the repo currently points at the digest
+    pub fn is_current_in_repo(&self, repo_name: &str, digest: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let found = conn
+            .query_row(
+                "SELECT 1 FROM tags WHERE repo = ?1 AND digest = ?2 AND position = 0 LIMIT 1",
+                params![repo_name, digest],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// Manifests current in the repo whose subject is the digest, by digest
+    pub fn referrers(&self, repo_name: &str, subject: &str) -> Result<Vec<Referrer>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT digest, media_type, artifact_type, size, annotations FROM referrers
+             WHERE subject = ?2
+             AND digest IN (SELECT digest FROM tags WHERE repo = ?1 AND position = 0)
+             ORDER BY digest",
+        )?;
+        let rows = stmt
+            .query_map(params![repo_name, subject], |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    r.get::<_, String>(1)?,
+                    r.get::<_, String>(2)?,
+                    r.get::<_, i64>(3)?,
+                    r.get::<_, String>(4)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut referrers = vec![];
+        for (digest, media_type, artifact_type, size, annotations) in rows {
+            let annotations: HashMap<String, String> = serde_json::from_str(&annotations)?;
+            referrers.push(Referrer {
+                digest,
+                media_type,
+                artifact_type,
+                size: size as u64,
+                annotations,
+            });
+        }
+        Ok(referrers)
+    }
+
+    /// Counts a pull of a manifest in the repo by the tag or digest, at the given time
+    pub fn record_pull(&self, repo_name: &str, reference: &str, pulled: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO pulls (repo, reference, count, last_pulled) VALUES (?1, ?2, 1, ?3)
+             ON CONFLICT (repo, reference)
+             DO UPDATE SET count = count + 1, last_pulled = excluded.last_pulled",
+            params![repo_name, reference, pulled],
+        )?;
+        Ok(())
+    }
+
+    /*
+     * Pull counts for each repository, or just the one given, most pulled first. Times are
+     * compared as strings, so they have to be written in the same format.
+     */
+    pub fn pull_stats(&self, repo_name: Option<&str>) -> Result<Vec<RepoPulls>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT repo, reference, count, last_pulled FROM pulls
+             WHERE ?1 IS NULL OR repo = ?1 ORDER BY repo, count DESC, reference",
+        )?;
+        let rows = stmt
+            .query_map(params![repo_name], |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    ReferencePulls {
+                        reference: r.get(1)?,
+                        pulls: r.get::<_, i64>(2)? as u64,
+                        last_pulled: r.get(3)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut repos: Vec<RepoPulls> = vec![];
+        for (repo_name, pulls) in rows {
+            match repos.last_mut() {
+                Some(r) if r.repo_name == repo_name => {
+                    r.pulls += pulls.pulls;
+                    if pulls.last_pulled > r.last_pulled {
+                        r.last_pulled = pulls.last_pulled.clone();
+                    }
+                    r.references.push(pulls);
+                }
+                _ => repos.push(RepoPulls {
+                    repo_name,
+                    pulls: pulls.pulls,
+                    last_pulled: pulls.last_pulled.clone(),
+                    references: vec![pulls],
+                }),
+            }
+        }
+        repos.sort_by(|a, b| b.pulls.cmp(&a.pulls).then(a.repo_name.cmp(&b.repo_name)));
+        Ok(repos)
+    }
+
+    /// The summary kept for the manifest, see index_summary.rs
+    pub fn summary(&self, digest: &str) -> Result<Option<IndexSummary>> {
+        let conn = self.conn.lock().unwrap();
+        let json: Option<String> = conn
+            .query_row(
+                "SELECT summary FROM index_summaries WHERE digest = ?1",
+                params![digest],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Ok(match json {
+            Some(j) => Some(serde_json::from_str(&j)?),
+            None => None,
+        })
+    }
+
+    /// Keeps a summary that's complete, so it can't change
+    pub fn cache_summary(&self, summary: &IndexSummary) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO index_summaries (digest, summary) VALUES (?1, ?2)",
+            params![summary.digest, serde_json::to_string(summary)?],
+        )?;
+        Ok(())
+    }
+
+    /*
+     * Every digest reachable from a tag, including their history, the same as
+     * maintenance::referenced_digests. Manifests missing from the database are read from the
+     * blobs dir and recorded, so nothing in use is ever missed.
+     */
+    pub fn referenced_digests(&self, blobs_path: &Path) -> Result<HashSet<String>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut to_visit: Vec<String> = vec![];
+        {
+            let mut stmt = tx.prepare("SELECT DISTINCT digest FROM tags")?;
+            for digest in stmt.query_map([], |r| r.get(0))? {
+                to_visit.push(digest?);
+            }
+        }
+
+        let mut referenced = HashSet::new();
+        while let Some(digest) = to_visit.pop() {
+            if !referenced.insert(digest.clone()) {
+                continue;
+            }
+            if let Err(e) = record_manifest(&tx, blobs_path, &digest) {
+                warn!("Failed to record manifest {}: {:?}", digest, e);
+            }
+            let mut stmt = tx.prepare_cached("SELECT blob FROM blob_refs WHERE manifest = ?1")?;
+            let blobs = stmt
+                .query_map(params![digest], |r| r.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            to_visit.extend(blobs);
+        }
+        tx.commit()?;
+        Ok(referenced)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MetadataStore;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn store_follows_tags() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        let blobs = dir.path().join("blobs");
+        fs::create_dir_all(manifests.join("org/app")).unwrap();
+        fs::create_dir_all(blobs.join("sha256")).unwrap();
+
+        let config = format!("sha256:{}", "c".repeat(64));
+        let layer = format!("sha256:{}", "d".repeat(64));
+        let manifest = format!(
+            r#"{{"schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": {{"mediaType": "application/vnd.docker.container.image.v1+json", "size": 2, "digest": "{}"}},
+            "layers": [{{"mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "size": 5, "digest": "{}"}}]}}"#,
+            config, layer
+        );
+        let digest = format!("sha256:{}", "a".repeat(64));
+        fs::write(blobs.join("sha256").join("a".repeat(64)), manifest).unwrap();
+        fs::write(blobs.join("sha256").join("d".repeat(64)), "layer").unwrap();
+        fs::write(
+            manifests.join("org/app/v1"),
+            format!("{} 2022-01-01T00:00:00Z\n", digest),
+        )
+        .unwrap();
+
+        let store = MetadataStore::open(&dir.path().join("metadata.db")).unwrap();
+        store.sync(&manifests, &blobs).unwrap();
+        assert_eq!(store.catalog().unwrap(), vec!["org/app"]);
+        assert_eq!(store.tags("org/app").unwrap(), vec!["v1"]);
+        assert!(store.is_current_in_repo("org/app", &digest).unwrap());
+
+        let referenced = store.referenced_digests(&blobs).unwrap();
+        assert!(referenced.contains(&digest));
+        assert!(referenced.contains(&config));
+        assert!(referenced.contains(&layer));
+
+        store
+            .add_tag(&blobs, "other", "latest", &digest, "2022-01-02T00:00:00Z")
+            .unwrap();
+        assert_eq!(store.catalog().unwrap(), vec!["org/app", "other"]);
+        assert!(store.tag_exists("other", "latest").unwrap());
+        // The latest push is current, the earlier ones only history
+        store
+            .add_tag(
+                &blobs,
+                "other",
+                "latest",
+                "sha256:newer",
+                "2022-01-03T00:00:00Z",
+            )
+            .unwrap();
+        assert!(store.is_current_in_repo("other", "sha256:newer").unwrap());
+        assert!(!store.is_current_in_repo("other", &digest).unwrap());
+
+        fs::remove_file(manifests.join("org/app/v1")).unwrap();
+        store
+            .reload_tag(&manifests, &blobs, "org/app", "v1")
+            .unwrap();
+        assert_eq!(store.catalog().unwrap(), vec!["other"]);
+        assert!(!store.is_current_in_repo("org/app", &digest).unwrap());
+    }
+
+    #[test]
+    fn counts_pulls() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        let blobs = dir.path().join("blobs");
+        fs::create_dir_all(&manifests).unwrap();
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let store = MetadataStore::open(&dir.path().join("metadata.db")).unwrap();
+
+        store
+            .record_pull("org/app", "v1", "2022-01-01T00:00:00Z")
+            .unwrap();
+        store
+            .record_pull("org/app", "v1", "2022-01-03T00:00:00Z")
+            .unwrap();
+        store
+            .record_pull("org/app", &digest, "2022-01-02T00:00:00Z")
+            .unwrap();
+        store
+            .record_pull("other", "latest", "2022-01-04T00:00:00Z")
+            .unwrap();
+
+        let stats = store.pull_stats(None).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].repo_name, "org/app");
+        assert_eq!(stats[0].pulls, 3);
+        assert_eq!(stats[0].last_pulled, "2022-01-03T00:00:00Z");
+        assert_eq!(stats[0].references[0].reference, "v1");
+        assert_eq!(stats[0].references[0].pulls, 2);
+        assert_eq!(stats[0].references[1].reference, digest);
+        assert_eq!(stats[1].repo_name, "other");
+
+        // Not in the data dir, so kept through a sync
+        store.sync(&manifests, &blobs).unwrap();
+        let stats = store.pull_stats(Some("other")).unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].pulls, 1);
+        assert!(store.pull_stats(Some("missing")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn finds_referrers() {
+        let dir = tempdir().unwrap();
+        let blobs = dir.path().join("blobs");
+        fs::create_dir_all(blobs.join("sha256")).unwrap();
+        let image = format!("sha256:{}", "a".repeat(64));
+        let sbom = format!(
+            r#"{{"mediaType": "application/vnd.oci.artifact.manifest.v1+json",
+            "artifactType": "application/spdx+json",
+            "blobs": [],
+            "subject": {{"mediaType": "application/vnd.oci.image.manifest.v1+json", "size": 10, "digest": "{}"}}}}"#,
+            image
+        );
+        let digest = format!("sha256:{}", "e".repeat(64));
+        fs::write(blobs.join("sha256").join("e".repeat(64)), &sbom).unwrap();
+
+        let store = MetadataStore::open(&dir.path().join("metadata.db")).unwrap();
+        store
+            .add_tag(&blobs, "org/app", &digest, &digest, "2022-01-01T00:00:00Z")
+            .unwrap();
+        let referrers = store.referrers("org/app", &image).unwrap();
+        assert_eq!(referrers.len(), 1);
+        assert_eq!(referrers[0].digest, digest);
+        assert_eq!(referrers[0].artifact_type, "application/spdx+json");
+        assert_eq!(referrers[0].size, sbom.len() as u64);
+        assert!(store.referrers("other", &image).unwrap().is_empty());
+    }
+}
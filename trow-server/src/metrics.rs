@@ -1,8 +1,8 @@
 use anyhow::Result;
 use lazy_static::lazy_static;
 use prometheus::{
-    labels, opts, register_int_counter, register_int_gauge, Encoder, IntCounter, IntGauge,
-    TextEncoder,
+    labels, opts, register_int_counter, register_int_counter_vec, register_int_gauge, Encoder,
+    IntCounter, IntCounterVec, IntGauge, TextEncoder,
 };
 use std::path::PathBuf;
 
@@ -34,6 +34,10 @@ lazy_static! {
         "total number of requests for blobs made",
         labels! {"type" => "blobs"}
     )).unwrap();
+    pub static ref MANIFEST_PULLS: IntCounterVec = register_int_counter_vec!(opts!(
+        "manifest_pulls_total",
+        "number of manifests pulled, by repository"
+    ), &["repo"]).unwrap();
 }
 
 // Query disk metrics
@@ -56,6 +60,8 @@ pub fn gather_metrics(blobs_path: &PathBuf) -> Result<String> {
     //      * disk
     //      * total manifest requests
     //      * total blob requests
+    //      * manifest pulls by repository
+    //      * frontend calls to the backend, registered by the frontend
 
     let metric_families = prometheus::gather();
     let mut buffer = vec![];
@@ -0,0 +1,219 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use tokio::sync::Notify;
+
+/*
+ * Mirroring Docker Hub images into the proxy cache when pods using them are admitted, so the
+ * cache is warm before nodes are switched to pull through Trow.
+ *
+ * Admission only queues the images and returns, so decisions never wait on upstream. A fixed
+ * number of workers fetch from the queue, which bounds the load on upstream and the disk.
+ * Images that aren't cached yet are fetched before refreshes of cached ones, and an image already
+ * in the queue isn't added again. When the queue is full new images are dropped, as they'll be
+ * fetched on the next admission or pull anyway.
+ */
+
+lazy_static! {
+    pub static ref QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "mirror_queue_depth",
+        "images waiting to be mirrored into the proxy cache"
+    )
+    .unwrap();
+    pub static ref IN_PROGRESS: IntGauge = register_int_gauge!(
+        "mirror_in_progress",
+        "images being mirrored into the proxy cache"
+    )
+    .unwrap();
+    pub static ref FAILED: IntCounter = register_int_counter!(
+        "mirror_failures_total",
+        "images that failed to mirror into the proxy cache"
+    )
+    .unwrap();
+    pub static ref DROPPED: IntCounter = register_int_counter!(
+        "mirror_dropped_total",
+        "images not mirrored as the queue was full"
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // Check a cached image for a new version
+    Refresh,
+    // Fetch an image that isn't cached
+    Missing,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Task {
+    priority: Priority,
+    // Earlier tasks first within a priority
+    seq: Reverse<u64>,
+    // Local repository name and tag, e.g. f/docker/nginx:1.21
+    repo_name: String,
+    tag: String,
+}
+
+impl Ord for Task {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.priority, self.seq).cmp(&(other.priority, other.seq))
+    }
+}
+
+impl PartialOrd for Task {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    tasks: BinaryHeap<Task>,
+    // Queued or in progress, as repo:tag
+    pending: HashSet<String>,
+    next_seq: u64,
+}
+
+struct Inner {
+    queue: Mutex<Queue>,
+    notify: Notify,
+    max_queued: usize,
+}
+
+#[derive(Clone)]
+pub struct MirrorQueue {
+    inner: Arc<Inner>,
+}
+
+fn key(repo_name: &str, tag: &str) -> String {
+    format!("{}:{}", repo_name, tag)
+}
+
+impl MirrorQueue {
+    pub fn new(max_queued: usize) -> MirrorQueue {
+        MirrorQueue {
+            inner: Arc::new(Inner {
+                queue: Mutex::new(Queue::default()),
+                notify: Notify::new(),
+                max_queued,
+            }),
+        }
+    }
+
+    /// Queues the image, returning false if it was dropped or already queued
+    pub fn push(&self, repo_name: &str, tag: &str, priority: Priority) -> bool {
+        {
+            let mut queue = self.inner.queue.lock().unwrap();
+            if queue.pending.contains(&key(repo_name, tag)) {
+                return false;
+            }
+            if queue.tasks.len() >= self.inner.max_queued {
+                warn!("Mirror queue full, dropping {}:{}", repo_name, tag);
+                DROPPED.inc();
+                return false;
+            }
+            queue.pending.insert(key(repo_name, tag));
+            let seq = Reverse(queue.next_seq);
+            queue.next_seq += 1;
+            queue.tasks.push(Task {
+                priority,
+                seq,
+                repo_name: repo_name.to_string(),
+                tag: tag.to_string(),
+            });
+            QUEUE_DEPTH.set(queue.tasks.len() as i64);
+        }
+        self.inner.notify.notify_one();
+        true
+    }
+
+    fn pop(&self) -> Option<(String, String)> {
+        let mut queue = self.inner.queue.lock().unwrap();
+        let task = queue.tasks.pop()?;
+        QUEUE_DEPTH.set(queue.tasks.len() as i64);
+        Some((task.repo_name, task.tag))
+    }
+
+    fn done(&self, repo_name: &str, tag: &str) {
+        self.inner
+            .queue
+            .lock()
+            .unwrap()
+            .pending
+            .remove(&key(repo_name, tag));
+    }
+
+    /*
+     * Starts the workers, which call fetch for each queued image. Must be called from within the
+     * tokio runtime.
+     */
+    pub fn start<F, Fut>(&self, workers: usize, fetch: F)
+    where
+        F: Fn(String, String) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send,
+    {
+        for _ in 0..workers {
+            let queue = self.clone();
+            let fetch = fetch.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (repo_name, tag) = match queue.pop() {
+                        Some(t) => t,
+                        None => {
+                            queue.inner.notify.notified().await;
+                            continue;
+                        }
+                    };
+                    IN_PROGRESS.inc();
+                    match fetch(repo_name.clone(), tag.clone()).await {
+                        Ok(()) => info!("Mirrored {}:{}", repo_name, tag),
+                        Err(e) => {
+                            warn!("Failed to mirror {}:{}: {}", repo_name, tag, e);
+                            FAILED.inc();
+                        }
+                    }
+                    IN_PROGRESS.dec();
+                    queue.done(&repo_name, &tag);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MirrorQueue, Priority};
+
+    #[test]
+    fn missing_images_first() {
+        let queue = MirrorQueue::new(3);
+        assert!(queue.push("f/docker/nginx", "1.21", Priority::Refresh));
+        assert!(queue.push("f/docker/redis", "6", Priority::Missing));
+        assert!(queue.push("f/docker/postgres", "14", Priority::Missing));
+        // Already queued
+        assert!(!queue.push("f/docker/redis", "6", Priority::Missing));
+        // Full
+        assert!(!queue.push("f/docker/mysql", "8", Priority::Missing));
+
+        let order: Vec<(String, String)> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(
+            order,
+            vec![
+                ("f/docker/redis".to_string(), "6".to_string()),
+                ("f/docker/postgres".to_string(), "14".to_string()),
+                ("f/docker/nginx".to_string(), "1.21".to_string()),
+            ]
+        );
+
+        // Still pending until done
+        assert!(!queue.push("f/docker/redis", "6", Priority::Missing));
+        queue.done("f/docker/redis", "6");
+        assert!(queue.push("f/docker/redis", "6", Priority::Missing));
+    }
+}
@@ -0,0 +1,119 @@
+use uuid::Uuid;
+
+/*
+ * Repository names, tags, digests and upload ids, checked against the grammar the distribution
+ * spec gives them before they're used to build a path.
+ *
+ * The frontend checks them as requests come in, but the backend checks them again rather than
+ * trusting whoever is calling it. A name that passes can't reach outside the directory it's
+ * joined to: components are lowercase letters and digits with single separators between them,
+ * so there's no `..`, no leading `.` and no `/` outside the separators between components.
+ */
+
+// Longest repository name, counting the slashes, as registries commonly limit it to
+const MAX_NAME_LENGTH: usize = 255;
+
+const MAX_TAG_LENGTH: usize = 128;
+
+fn is_lower_alphanumeric(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit()
+}
+
+// [a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*
+fn is_valid_component(component: &str) -> bool {
+    component.starts_with(is_lower_alphanumeric)
+        && component.ends_with(is_lower_alphanumeric)
+        && component
+            .split(is_lower_alphanumeric)
+            .filter(|sep| !sep.is_empty())
+            .all(|sep| matches!(sep, "." | "_" | "__") || sep.chars().all(|c| c == '-'))
+}
+
+pub fn is_valid_repo_name(repo_name: &str) -> bool {
+    repo_name.len() <= MAX_NAME_LENGTH && repo_name.split('/').all(is_valid_component)
+}
+
+// [a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}
+pub fn is_valid_tag(tag: &str) -> bool {
+    let mut chars = tag.chars();
+    let first_ok = matches!(chars.next(), Some(c) if c.is_ascii_alphanumeric() || c == '_');
+    first_ok
+        && tag.len() <= MAX_TAG_LENGTH
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+// Only sha256 is stored, which is 64 lowercase hex digits
+pub fn is_valid_digest(digest: &str) -> bool {
+    digest.strip_prefix("sha256:").map_or(false, |hex| {
+        hex.len() == 64 && hex.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+    })
+}
+
+// As handed out when an upload is started
+pub fn is_valid_upload_id(uuid: &str) -> bool {
+    uuid.len() == 36 && Uuid::parse_str(uuid).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_valid_digest, is_valid_repo_name, is_valid_tag, is_valid_upload_id};
+
+    #[test]
+    fn checks_repo_names() {
+        for name in [
+            "app",
+            "org/app",
+            "f/docker/library/nginx",
+            "my-org/app.v2",
+            "a__b/c--d/e_f",
+            "0/1",
+        ] {
+            assert!(is_valid_repo_name(name), "{} should be valid", name);
+        }
+        for name in [
+            "",
+            "..",
+            "../etc",
+            "org/../../etc",
+            "org/./app",
+            "org//app",
+            "/org/app",
+            "org/app/",
+            ".hidden",
+            "org/-app",
+            "org/app_",
+            "a___b",
+            "a._b",
+            "Org/App",
+            "org/app\\..",
+            "org%2Fapp",
+        ] {
+            assert!(!is_valid_repo_name(name), "{} should be invalid", name);
+        }
+        assert!(is_valid_repo_name(&"a".repeat(255)));
+        assert!(!is_valid_repo_name(&"a".repeat(256)));
+    }
+
+    #[test]
+    fn checks_references_and_upload_ids() {
+        assert!(is_valid_tag("v1.2_rc-3"));
+        assert!(is_valid_tag("_latest"));
+        assert!(!is_valid_tag(".."));
+        assert!(!is_valid_tag("-v1"));
+        assert!(!is_valid_tag("v1/../latest"));
+        assert!(!is_valid_tag(&"a".repeat(129)));
+
+        let hex = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        assert!(is_valid_digest(&format!("sha256:{}", hex)));
+        assert!(!is_valid_digest(&format!("sha256:{}", hex.to_uppercase())));
+        assert!(!is_valid_digest(&format!("sha512:{}", hex)));
+        assert!(!is_valid_digest("sha256:abc"));
+        assert!(!is_valid_digest(&format!("sha256:{}:..", hex)));
+        assert!(!is_valid_digest("sha256:../../../../etc/passwd"));
+
+        assert!(is_valid_upload_id("7f4ad8e1-26c5-4d2b-9a0b-3c4e8b1f2a60"));
+        assert!(!is_valid_upload_id("7f4ad8e126c54d2b9a0b3c4e8b1f2a60"));
+        assert!(!is_valid_upload_id("../7f4ad8e1-26c5-4d2b-9a0b-3c4e8b1f"));
+        assert!(!is_valid_upload_id(""));
+    }
+}
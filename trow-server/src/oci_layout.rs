@@ -0,0 +1,352 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::digest::sha256_tag_digest;
+use crate::maintenance::{blob_path, follow_manifests};
+use crate::manifest::{FromJson, Manifest};
+
+/*
+ * Moving images in and out of the registry as OCI image layout tarballs
+ * (https://github.com/opencontainers/image-spec/blob/main/image-layout.md), e.g. to carry them
+ * into an air-gapped cluster without a Docker daemon.
+ *
+ * An export has the manifest and everything it references, so for an index every platform's
+ * manifest and blobs, along with the oci-layout file and an index.json listing the manifest with
+ * the tag it was exported by. skopeo, crane and podman can all read it.
+ *
+ * An import reads the blobs out of a tarball into the scratch dir, checking each against its
+ * digest, and lists the manifests in its index.json with their tags. Storing them is up to the
+ * server, which treats them the same as a push.
+ */
+
+static LAYOUT_FILE: &str = "oci-layout";
+static INDEX_FILE: &str = "index.json";
+static BLOBS_DIR: &str = "blobs";
+static LAYOUT_VERSION: &str = "1.0.0";
+static INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+static REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+#[derive(Error, Debug)]
+pub enum LayoutError {
+    // e.g. platforms of a proxied image that were never pulled
+    #[error("Blob {0} is not stored, so the image can't be exported")]
+    MissingBlob(String),
+    #[error("Not an OCI image layout: {0}")]
+    InvalidLayout(String),
+    #[error("Blob {0} in the layout doesn't match its digest")]
+    DigestMismatch(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageLayout {
+    image_layout_version: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Index {
+    schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    media_type: Option<String>,
+    manifests: Vec<Descriptor>,
+}
+
+fn append_file<W: Write>(builder: &mut tar::Builder<W>, path: &str, contents: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, path, contents)?;
+    Ok(())
+}
+
+/**
+ * Writes the manifest with the given digest, and everything it references, to out as a tarball.
+ */
+pub fn export<W: Write>(blobs_path: &Path, digest: &str, tag: Option<&str>, out: W) -> Result<()> {
+    let manifest_path =
+        blob_path(blobs_path, digest).ok_or_else(|| anyhow!("Invalid digest {}", digest))?;
+    let manifest_bytes =
+        fs::read(&manifest_path).map_err(|_| LayoutError::MissingBlob(digest.to_string()))?;
+    let manifest = Manifest::from_json(&serde_json::from_slice(&manifest_bytes)?)?;
+
+    let mut blobs: Vec<String> = follow_manifests(vec![digest.to_string()], blobs_path)
+        .into_iter()
+        .collect();
+    blobs.sort();
+    let mut paths = vec![];
+    for blob in &blobs {
+        match blob_path(blobs_path, blob) {
+            Some(p) if p.exists() => paths.push((blob, p)),
+            _ => return Err(LayoutError::MissingBlob(blob.clone()).into()),
+        }
+    }
+
+    let mut annotations = HashMap::new();
+    if let Some(tag) = tag {
+        annotations.insert(REF_NAME_ANNOTATION.to_string(), tag.to_string());
+    }
+    let index = Index {
+        schema_version: 2,
+        media_type: Some(INDEX_MEDIA_TYPE.to_string()),
+        manifests: vec![Descriptor {
+            media_type: manifest.get_media_type(),
+            digest: digest.to_string(),
+            size: manifest_bytes.len() as u64,
+            annotations,
+        }],
+    };
+    let layout = ImageLayout {
+        image_layout_version: LAYOUT_VERSION.to_string(),
+    };
+
+    let mut builder = tar::Builder::new(out);
+    append_file(&mut builder, LAYOUT_FILE, &serde_json::to_vec(&layout)?)?;
+    append_file(&mut builder, INDEX_FILE, &serde_json::to_vec(&index)?)?;
+    for (blob, path) in paths {
+        let name = format!("{}/{}", BLOBS_DIR, blob.replacen(':', "/", 1));
+        builder.append_path_with_name(&path, name)?;
+    }
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/*
+ * What an import found in the tarball.
+ */
+#[derive(Debug)]
+pub struct Layout {
+    // Digest and where the blob was extracted to in the scratch dir
+    pub blobs: Vec<(String, PathBuf)>,
+    // Digest and the tag it was exported by, if any
+    pub manifests: Vec<(String, Option<String>)>,
+}
+
+impl Layout {
+    pub fn remove_blobs(&self) {
+        for (_, path) in &self.blobs {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// The digest of a blob from its path in the layout, blobs/sha256/<hex>
+fn blob_digest(path: &str) -> Option<String> {
+    let rel = path.strip_prefix(BLOBS_DIR)?.strip_prefix('/')?;
+    let (alg, hex) = rel.split_once('/')?;
+    if alg != "sha256" || hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!("{}:{}", alg, hex))
+}
+
+fn extract_blob(entry: &mut impl Read, digest: &str, scratch_path: &Path) -> Result<PathBuf> {
+    let path = scratch_path.join(Uuid::new_v4().to_string());
+    let res = File::create(&path)
+        .and_then(|mut f| io::copy(entry, &mut f))
+        .map_err(anyhow::Error::from)
+        .and_then(|_| sha256_tag_digest(BufReader::new(File::open(&path)?)))
+        .and_then(|actual| {
+            if actual != digest {
+                return Err(LayoutError::DigestMismatch(digest.to_string()).into());
+            }
+            Ok(())
+        });
+    match res {
+        Ok(()) => Ok(path),
+        Err(e) => {
+            let _ = fs::remove_file(&path);
+            Err(e)
+        }
+    }
+}
+
+fn read_layout<R: Read>(tarball: R, scratch_path: &Path, layout: &mut Layout) -> Result<()> {
+    let mut version = None;
+    let mut index = None;
+    let mut seen = HashSet::new();
+
+    let mut archive = tar::Archive::new(tarball);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().to_string();
+        // Tarballs made with tar -C dir . have paths starting ./
+        let path = path.trim_start_matches("./");
+        if path == LAYOUT_FILE {
+            let l: ImageLayout = serde_json::from_reader(&mut entry).map_err(|e| {
+                LayoutError::InvalidLayout(format!("Invalid {}: {}", LAYOUT_FILE, e))
+            })?;
+            version = Some(l.image_layout_version);
+        } else if path == INDEX_FILE {
+            let i: Index = serde_json::from_reader(&mut entry).map_err(|e| {
+                LayoutError::InvalidLayout(format!("Invalid {}: {}", INDEX_FILE, e))
+            })?;
+            index = Some(i);
+        } else if let Some(digest) = blob_digest(path) {
+            if seen.insert(digest.clone()) {
+                let blob = extract_blob(&mut entry, &digest, scratch_path)?;
+                layout.blobs.push((digest, blob));
+            }
+        }
+    }
+
+    match version {
+        Some(v) if v == LAYOUT_VERSION => {}
+        Some(v) => {
+            return Err(
+                LayoutError::InvalidLayout(format!("Unsupported layout version {}", v)).into(),
+            )
+        }
+        None => return Err(LayoutError::InvalidLayout(format!("No {} file", LAYOUT_FILE)).into()),
+    }
+    let index = index.ok_or_else(|| LayoutError::InvalidLayout(format!("No {}", INDEX_FILE)))?;
+    if index.manifests.is_empty() {
+        return Err(LayoutError::InvalidLayout(format!("{} lists no images", INDEX_FILE)).into());
+    }
+    for m in index.manifests {
+        if !seen.contains(&m.digest) {
+            return Err(LayoutError::InvalidLayout(format!(
+                "Manifest {} is listed but not in the tarball",
+                m.digest
+            ))
+            .into());
+        }
+        let tag = m.annotations.get(REF_NAME_ANNOTATION).cloned();
+        layout.manifests.push((m.digest, tag));
+    }
+    Ok(())
+}
+
+/**
+ * Extracts the blobs in the tarball to the scratch dir, checking each against its digest. Nothing
+ * is left in the scratch dir if this fails.
+ */
+pub fn import<R: Read>(tarball: R, scratch_path: &Path) -> Result<Layout> {
+    let mut layout = Layout {
+        blobs: vec![],
+        manifests: vec![],
+    };
+    match read_layout(tarball, scratch_path, &mut layout) {
+        Ok(()) => Ok(layout),
+        Err(e) => {
+            layout.remove_blobs();
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{export, import, LayoutError};
+    use crate::digest::sha256_tag_digest;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    // sha256 of "hello world"
+    const LAYER: &str = "sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    fn write_image(blobs_path: &Path) -> String {
+        let manifest = format!(
+            r#"{{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {{ "mediaType": "application/vnd.oci.image.config.v1+json", "size": 11, "digest": "{}" }},
+            "layers": [ {{ "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "size": 11, "digest": "{}" }} ]
+        }}"#,
+            LAYER, LAYER
+        );
+        let digest = sha256_tag_digest(manifest.as_bytes()).unwrap();
+        fs::create_dir_all(blobs_path.join("sha256")).unwrap();
+        fs::write(blobs_path.join("sha256").join(&LAYER[7..]), "hello world").unwrap();
+        fs::write(blobs_path.join("sha256").join(&digest[7..]), manifest).unwrap();
+        digest
+    }
+
+    #[test]
+    fn exports_and_imports() {
+        let dir = tempdir().unwrap();
+        let blobs = dir.path().join("blobs");
+        let scratch = dir.path().join("scratch");
+        fs::create_dir_all(&scratch).unwrap();
+        let digest = write_image(&blobs);
+
+        let mut tarball = vec![];
+        export(&blobs, &digest, Some("v1"), &mut tarball).unwrap();
+
+        let layout = import(tarball.as_slice(), &scratch).unwrap();
+        assert_eq!(
+            layout.manifests,
+            vec![(digest.clone(), Some("v1".to_string()))]
+        );
+        let mut digests: Vec<&str> = layout.blobs.iter().map(|(d, _)| d.as_str()).collect();
+        digests.sort_unstable();
+        let mut expected = vec![digest.as_str(), LAYER];
+        expected.sort_unstable();
+        assert_eq!(digests, expected);
+        for (d, path) in &layout.blobs {
+            assert_eq!(
+                fs::read(path).unwrap(),
+                fs::read(blobs.join("sha256").join(&d[7..])).unwrap()
+            );
+        }
+        layout.remove_blobs();
+        assert!(fs::read_dir(&scratch).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn needs_every_blob() {
+        let dir = tempdir().unwrap();
+        let blobs = dir.path().join("blobs");
+        let digest = write_image(&blobs);
+        fs::remove_file(blobs.join("sha256").join(&LAYER[7..])).unwrap();
+
+        let err = export(&blobs, &digest, None, vec![]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LayoutError>(),
+            Some(LayoutError::MissingBlob(d)) if d == LAYER
+        ));
+    }
+
+    #[test]
+    fn rejects_corrupt_blobs() {
+        let dir = tempdir().unwrap();
+        let blobs = dir.path().join("blobs");
+        let scratch = dir.path().join("scratch");
+        fs::create_dir_all(&scratch).unwrap();
+        let digest = write_image(&blobs);
+        fs::write(blobs.join("sha256").join(&LAYER[7..]), "hello w0rld").unwrap();
+
+        let mut tarball = vec![];
+        export(&blobs, &digest, None, &mut tarball).unwrap();
+        let err = import(tarball.as_slice(), &scratch).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LayoutError>(),
+            Some(LayoutError::DigestMismatch(_))
+        ));
+        // Including any blobs extracted before the corrupt one
+        assert!(fs::read_dir(&scratch).unwrap().next().is_none());
+
+        assert!(import(&b"not a tarball"[..], &scratch).is_err());
+    }
+}
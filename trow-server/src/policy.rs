@@ -0,0 +1,151 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, Result};
+
+use crate::freeze::{AdmittedImages, FreezeWindow};
+use crate::quota::Quota;
+use crate::selector::TagSelector;
+use crate::trow_policy::ClusterPolicies;
+
+/*
+ * The admission and push rules, which can be replaced while the backend runs, e.g. when the
+ * frontend's config file changes.
+ *
+ * Requests take a snapshot of the rules, so each sees either the old or the new rules throughout.
+ * Each set of rules has a generation, 0 for those the backend started with, so it can be
+ * confirmed which are in use.
+ */
+
+#[derive(Clone, Default)]
+pub struct Policy {
+    pub allow_prefixes: Vec<String>,
+    pub allow_images: Vec<String>,
+    pub deny_local_prefixes: Vec<String>,
+    pub deny_local_images: Vec<String>,
+    // Deny local images that aren't stored, even if they're on an allow list
+    pub require_existing_images: bool,
+    pub quotas: Vec<Quota>,
+    pub immutable_tags: Vec<TagSelector>,
+    pub freeze_windows: Vec<FreezeWindow>,
+    // Images admitted to each namespace, only tracked while there are freeze windows
+    pub admitted: Option<AdmittedImages>,
+    // From TrowPolicy resources, which are kept when the other rules are replaced
+    pub cluster: ClusterPolicies,
+}
+
+fn parse_all<T: FromStr<Err = anyhow::Error>>(rules: &[String], what: &str) -> Result<Vec<T>> {
+    rules
+        .iter()
+        .map(|r| {
+            r.parse()
+                .map_err(|e| anyhow!("Invalid {} {}: {}", what, r, e))
+        })
+        .collect()
+}
+
+impl Policy {
+    /*
+     * Replaces the quotas, immutable tags and freeze windows with those parsed from the rules,
+     * as given on the command line. Starts tracking admitted images if there are now freeze
+     * windows, loading those recorded before from the data dir.
+     *
+     * Fails if any of the rules are invalid.
+     */
+    pub fn with_rules(
+        &self,
+        quotas: &[String],
+        immutable_tags: &[String],
+        freeze_windows: &[String],
+        data_path: &Path,
+    ) -> Result<Policy> {
+        let freeze_windows: Vec<FreezeWindow> = parse_all(freeze_windows, "freeze window")?;
+        let admitted = match &self.admitted {
+            _ if freeze_windows.is_empty() => None,
+            Some(admitted) => Some(admitted.clone()),
+            None => Some(AdmittedImages::load(data_path)?),
+        };
+        Ok(Policy {
+            quotas: parse_all(quotas, "quota")?,
+            immutable_tags: parse_all(immutable_tags, "immutable tag selector")?,
+            freeze_windows,
+            admitted,
+            ..self.clone()
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct SharedPolicy {
+    current: Arc<RwLock<(u64, Arc<Policy>)>>,
+}
+
+impl SharedPolicy {
+    pub fn new(policy: Policy) -> SharedPolicy {
+        SharedPolicy {
+            current: Arc::new(RwLock::new((0, Arc::new(policy)))),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Policy> {
+        self.current.read().unwrap().1.clone()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.current.read().unwrap().0
+    }
+
+    pub fn replace(&self, generation: u64, policy: Policy) {
+        *self.current.write().unwrap() = (generation, Arc::new(policy));
+    }
+
+    /// Changes the current rules without starting a new generation, e.g. while setting up
+    pub fn edit(&self, f: impl FnOnce(&mut Policy)) {
+        let mut current = self.current.write().unwrap();
+        let mut policy = (*current.1).clone();
+        f(&mut policy);
+        current.1 = Arc::new(policy);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Policy, SharedPolicy};
+    use tempfile::tempdir;
+
+    #[test]
+    fn replaces_rules() {
+        let dir = tempdir().unwrap();
+        let shared = SharedPolicy::new(Policy {
+            allow_prefixes: vec!["quay.io/".to_string()],
+            ..Policy::default()
+        });
+        let old = shared.get();
+
+        let policy = old
+            .with_rules(
+                &["myorg=1GiB".to_string()],
+                &[],
+                &["prod=Sat-Sun".to_string()],
+                dir.path(),
+            )
+            .unwrap();
+        shared.replace(1, policy);
+        let new = shared.get();
+        assert_eq!(shared.generation(), 1);
+        assert_eq!(new.allow_prefixes, vec!["quay.io/"]);
+        assert_eq!(new.quotas.len(), 1);
+        assert!(new.admitted.is_some());
+        // Requests already holding the old rules keep them
+        assert!(old.quotas.is_empty());
+
+        let err = new
+            .with_rules(&["myorg=lots".to_string()], &[], &[], dir.path())
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("Invalid quota myorg=lots"));
+        let cleared = new.with_rules(&[], &[], &[], dir.path()).unwrap();
+        assert!(cleared.admitted.is_none());
+    }
+}
@@ -0,0 +1,234 @@
+use std::path::Path;
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use rand::seq::SliceRandom;
+
+use crate::retention::{is_digest, read_repos};
+
+/*
+ * Shadow mode checks of the proxy cache, to build trust in it before cutting off direct access to
+ * upstream registries.
+ *
+ * The "proxy-check" job picks a random sample of cached tags in proxied repositories and asks the
+ * upstream registry which digest each tag points at now, using a HEAD request as pulls do. Tags
+ * where the cached digest differs have drifted: pulling them through Trow would fetch the new
+ * version, but if upstream went away the old one would be served. Nothing in the cache is changed.
+ *
+ * The result is in the job message and the proxy_check_* metrics. Some drift is expected for
+ * moving tags like latest, but tags that are meant to be fixed shouldn't drift.
+ */
+
+lazy_static! {
+    pub static ref CHECKED_TAGS: IntGauge = register_int_gauge!(
+        "proxy_check_tags",
+        "proxied tags compared with upstream in the last proxy check"
+    )
+    .unwrap();
+    pub static ref DRIFTED_TAGS: IntGauge = register_int_gauge!(
+        "proxy_check_drifted_tags",
+        "proxied tags where the cached digest differed from upstream in the last proxy check"
+    )
+    .unwrap();
+    pub static ref FAILED_TAGS: IntGauge = register_int_gauge!(
+        "proxy_check_failed_tags",
+        "proxied tags that couldn't be checked against upstream in the last proxy check"
+    )
+    .unwrap();
+}
+
+// A cached tag in a proxied repository
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedTag {
+    pub repo_name: String,
+    pub tag: String,
+    pub digest: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Drift {
+    pub tag: CachedTag,
+    pub upstream: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub checked: usize,
+    pub drifted: Vec<Drift>,
+    // Repository and tag, with the error
+    pub failed: Vec<(String, String)>,
+}
+
+impl CheckReport {
+    pub fn add(&mut self, tag: CachedTag, upstream: Result<String>) {
+        match upstream {
+            Ok(digest) => {
+                self.checked += 1;
+                if digest != tag.digest {
+                    self.drifted.push(Drift {
+                        tag,
+                        upstream: digest,
+                    });
+                }
+            }
+            Err(e) => self
+                .failed
+                .push((format!("{}:{}", tag.repo_name, tag.tag), e.to_string())),
+        }
+    }
+
+    pub fn record_metrics(&self) {
+        CHECKED_TAGS.set(self.checked as i64);
+        DRIFTED_TAGS.set(self.drifted.len() as i64);
+        FAILED_TAGS.set(self.failed.len() as i64);
+    }
+
+    // For the job message
+    pub fn summary(&self) -> String {
+        let mut msg = format!(
+            "Checked {} proxied tags against upstream, {} drifted",
+            self.checked,
+            self.drifted.len()
+        );
+        if !self.drifted.is_empty() {
+            let drifted: Vec<String> = self
+                .drifted
+                .iter()
+                .map(|d| {
+                    format!(
+                        "{}:{} (cached {}, upstream {})",
+                        d.tag.repo_name, d.tag.tag, d.tag.digest, d.upstream
+                    )
+                })
+                .collect();
+            msg.push_str(&format!(": {}", drifted.join(", ")));
+        }
+        if !self.failed.is_empty() {
+            let failed: Vec<String> = self
+                .failed
+                .iter()
+                .map(|(tag, e)| format!("{} ({})", tag, e))
+                .collect();
+            msg.push_str(&format!(
+                ". Failed to check {}: {}",
+                self.failed.len(),
+                failed.join(", ")
+            ));
+        }
+        msg
+    }
+}
+
+/*
+ * Picks up to sample_size tags at random from the repositories under proxy_dir, e.g. "f/".
+ * References by digest are left out, as they can't change.
+ */
+pub fn sample_tags(
+    manifests_path: &Path,
+    proxy_dir: &str,
+    sample_size: usize,
+) -> Result<Vec<CachedTag>> {
+    let proxy_path = manifests_path.join(proxy_dir);
+    if !proxy_path.exists() {
+        return Ok(vec![]);
+    }
+    let mut tags = vec![];
+    for (repo_name, files) in read_repos(&proxy_path)? {
+        for (tag, tf) in files {
+            if is_digest(&tag) {
+                continue;
+            }
+            if let Some(current) = tf.history.first() {
+                tags.push(CachedTag {
+                    repo_name: format!("{}{}", proxy_dir, repo_name),
+                    tag,
+                    digest: current.digest.clone(),
+                });
+            }
+        }
+    }
+    let mut sample: Vec<CachedTag> = tags
+        .choose_multiple(&mut rand::thread_rng(), sample_size)
+        .cloned()
+        .collect();
+    sample.sort_by(|a, b| (&a.repo_name, &a.tag).cmp(&(&b.repo_name, &b.tag)));
+    Ok(sample)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sample_tags, CachedTag, CheckReport};
+    use anyhow::anyhow;
+    use std::fs;
+
+    const OLD: &str = "sha256:1111111111111111111111111111111111111111111111111111111111111111";
+    const NEW: &str = "sha256:2222222222222222222222222222222222222222222222222222222222222222";
+
+    fn cached(repo_name: &str, tag: &str) -> CachedTag {
+        CachedTag {
+            repo_name: repo_name.to_string(),
+            tag: tag.to_string(),
+            digest: OLD.to_string(),
+        }
+    }
+
+    #[test]
+    fn samples_proxied_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifests = dir.path();
+        let line = format!("{} 2022-01-01T00:00:00.000000000Z\n", OLD);
+        for (repo, tag) in [
+            ("f/docker/library/nginx", "latest"),
+            ("f/docker/library/nginx", "1.21"),
+            ("f/docker/library/nginx", NEW),
+            ("myorg/app", "v1"),
+        ] {
+            fs::create_dir_all(manifests.join(repo)).unwrap();
+            fs::write(manifests.join(repo).join(tag), &line).unwrap();
+        }
+
+        let sample = sample_tags(manifests, "f/", 10).unwrap();
+        assert_eq!(
+            sample,
+            vec![
+                cached("f/docker/library/nginx", "1.21"),
+                cached("f/docker/library/nginx", "latest"),
+            ]
+        );
+        assert_eq!(sample_tags(manifests, "f/", 1).unwrap().len(), 1);
+        assert!(sample_tags(&manifests.join("nothing"), "f/", 10)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn reports_drift() {
+        let mut report = CheckReport::default();
+        report.add(
+            cached("f/docker/library/nginx", "1.21"),
+            Ok(OLD.to_string()),
+        );
+        report.add(
+            cached("f/docker/library/nginx", "latest"),
+            Ok(NEW.to_string()),
+        );
+        report.add(
+            cached("f/docker/library/redis", "6"),
+            Err(anyhow!("timed out")),
+        );
+
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.drifted.len(), 1);
+        assert_eq!(report.drifted[0].tag.tag, "latest");
+        assert_eq!(
+            report.summary(),
+            format!(
+                "Checked 2 proxied tags against upstream, 1 drifted: \
+                 f/docker/library/nginx:latest (cached {}, upstream {}). \
+                 Failed to check 1: f/docker/library/redis:6 (timed out)",
+                OLD, NEW
+            )
+        );
+    }
+}
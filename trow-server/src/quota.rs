@@ -0,0 +1,211 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use crate::maintenance::{blob_path, referenced_digests, walk_files};
+
+/*
+ * Storage quotas for a repository or namespace.
+ *
+ * Given on the command line as NAME=BYTES[:IMAGES], e.g. "myorg=10GiB:100" or "myorg/app=:5".
+ * A quota on "myorg" covers "myorg" and every repository under "myorg/". Where several quotas
+ * match, the most specific (longest) name wins.
+ *
+ * Usage is worked out from the data directory when it's needed rather than tracked, so it's
+ * always correct, but takes a walk of the namespace on each push to it.
+ */
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub name: String,
+    pub max_bytes: Option<u64>,
+    pub max_images: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    // Total size of the blobs referenced by the namespace, each counted once
+    pub bytes: u64,
+    // Number of tags, including manifests pushed by digest
+    pub images: u64,
+    // Digests of all manifests and blobs referenced, used to avoid double counting
+    pub digests: HashSet<String>,
+}
+
+// Accepts a plain number of bytes or a number with a K, M, G or T suffix (powers of 1024),
+// optionally followed by "iB" or "B"
+pub(crate) fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let lower = s.to_ascii_lowercase();
+    let num = lower.trim_end_matches("ib").trim_end_matches('b');
+    let (num, multiplier) = match num.chars().last() {
+        Some('k') => (&num[..num.len() - 1], 1u64 << 10),
+        Some('m') => (&num[..num.len() - 1], 1 << 20),
+        Some('g') => (&num[..num.len() - 1], 1 << 30),
+        Some('t') => (&num[..num.len() - 1], 1 << 40),
+        _ => (num, 1),
+    };
+    let num: u64 = num
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("Invalid size {}", s))?;
+    num.checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("Size {} is too large", s))
+}
+
+impl FromStr for Quota {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, limits) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Quota {} should be of the form NAME=BYTES[:IMAGES]", s))?;
+        let name = name.trim().trim_end_matches('/').to_string();
+        if name.is_empty() {
+            return Err(anyhow!("Quota {} has no repository or namespace", s));
+        }
+        let (bytes, images) = match limits.split_once(':') {
+            Some((b, i)) => (b, Some(i)),
+            None => (limits, None),
+        };
+        let max_bytes = match bytes.trim() {
+            "" => None,
+            b => Some(parse_size(b)?),
+        };
+        let max_images = match images.map(str::trim) {
+            None | Some("") => None,
+            Some(i) => Some(
+                i.parse()
+                    .map_err(|_| anyhow!("Invalid image count {} in quota {}", i, s))?,
+            ),
+        };
+        if max_bytes.is_none() && max_images.is_none() {
+            return Err(anyhow!("Quota {} doesn't set any limits", s));
+        }
+        Ok(Quota {
+            name,
+            max_bytes,
+            max_images,
+        })
+    }
+}
+
+impl Quota {
+    pub fn applies_to(&self, repo_name: &str) -> bool {
+        repo_name == self.name
+            || (repo_name.starts_with(&self.name) && repo_name[self.name.len()..].starts_with('/'))
+    }
+}
+
+pub fn find_quota<'a>(quotas: &'a [Quota], repo_name: &str) -> Option<&'a Quota> {
+    quotas
+        .iter()
+        .filter(|q| q.applies_to(repo_name))
+        .max_by_key(|q| q.name.len())
+}
+
+/*
+ * Works out the usage of a repository or namespace.
+ *
+ * Blobs shared between repositories count fully against each one, as deleting either
+ * repository alone won't free them.
+ */
+pub fn usage(manifests_path: &Path, blobs_path: &Path, name: &str) -> Result<Usage> {
+    let dir = manifests_path.join(name);
+    let images = walk_files(&dir)?.len() as u64;
+    let digests = referenced_digests(&dir, blobs_path)?;
+    let bytes = digests.iter().map(|d| blob_size(blobs_path, d)).sum();
+    Ok(Usage {
+        bytes,
+        images,
+        digests,
+    })
+}
+
+pub fn blob_size(blobs_path: &Path, digest: &str) -> u64 {
+    blob_path(blobs_path, digest)
+        .and_then(|p| fs::metadata(p).ok())
+        .map(|m| m.len())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_quota, parse_size, usage, Quota};
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn parse_quotas() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("10K").unwrap(), 10 * 1024);
+        assert_eq!(parse_size("5GiB").unwrap(), 5 << 30);
+        assert_eq!(parse_size("2mb").unwrap(), 2 << 20);
+        assert!(parse_size("lots").is_err());
+
+        assert_eq!(
+            "myorg/=10G:100".parse::<Quota>().unwrap(),
+            Quota {
+                name: "myorg".to_string(),
+                max_bytes: Some(10 << 30),
+                max_images: Some(100)
+            }
+        );
+        let q: Quota = "myorg/app=:5".parse().unwrap();
+        assert_eq!(q.max_bytes, None);
+        assert_eq!(q.max_images, Some(5));
+
+        assert!("myorg".parse::<Quota>().is_err());
+        assert!("myorg=".parse::<Quota>().is_err());
+        assert!("=10G".parse::<Quota>().is_err());
+    }
+
+    #[test]
+    fn most_specific_quota_wins() {
+        let quotas: Vec<Quota> = vec![
+            "myorg=10G".parse().unwrap(),
+            "myorg/app=1G".parse().unwrap(),
+        ];
+        assert_eq!(find_quota(&quotas, "myorg/app").unwrap().name, "myorg/app");
+        assert_eq!(
+            find_quota(&quotas, "myorg/app/sub").unwrap().name,
+            "myorg/app"
+        );
+        assert_eq!(find_quota(&quotas, "myorg/other").unwrap().name, "myorg");
+        assert!(find_quota(&quotas, "myorganisation/app").is_none());
+        assert!(find_quota(&quotas, "other").is_none());
+    }
+
+    #[test]
+    fn counts_shared_blobs_once() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        let blobs = dir.path().join("blobs");
+        fs::create_dir_all(manifests.join("myorg").join("app")).unwrap();
+        fs::create_dir_all(blobs.join("sha256")).unwrap();
+
+        let manifest = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": { "mediaType": "application/vnd.docker.container.image.v1+json", "size": 1, "digest": "sha256:config" },
+            "layers": [ { "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "size": 1, "digest": "sha256:layer" } ]
+        }"#;
+        fs::write(blobs.join("sha256").join("manifest"), manifest).unwrap();
+        fs::write(blobs.join("sha256").join("config"), "12345").unwrap();
+        fs::write(blobs.join("sha256").join("layer"), "1234567890").unwrap();
+        for tag in ["v1", "latest"] {
+            fs::write(
+                manifests.join("myorg").join("app").join(tag),
+                "sha256:manifest 2022-01-01T00:00:00Z\n",
+            )
+            .unwrap();
+        }
+
+        let usage = usage(&manifests, &blobs, "myorg").unwrap();
+        assert_eq!(usage.images, 2);
+        assert_eq!(usage.bytes, manifest.len() as u64 + 15);
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::maintenance::blob_path;
+use crate::manifest::{FromJson, Manifest};
+use crate::server::get_digest_from_manifest_path;
+
+/*
+ * Manifests that refer to another through their subject, such as SBOMs, signatures and policy
+ * bundles pushed alongside an image by ORAS, cosign or notation. Clients find them with the
+ * referrers API, optionally only those of one artifactType.
+ *
+ * A manifest is listed as a referrer in the repositories it's current in, i.e. where a tag or its
+ * digest points to it. The subject itself doesn't have to be stored, so artifacts can be pushed
+ * before the image they describe.
+ */
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Referrer {
+    pub digest: String,
+    pub media_type: String,
+    pub size: u64,
+    pub artifact_type: String,
+    pub annotations: HashMap<String, String>,
+}
+
+// The manifest's subject, with how it's listed as a referrer of it. None if it has no subject.
+pub(crate) fn as_referrer(
+    manifest: &Manifest,
+    digest: &str,
+    size: u64,
+) -> Option<(String, Referrer)> {
+    let subject = manifest.subject()?.digest.clone();
+    Some((
+        subject,
+        Referrer {
+            digest: digest.to_string(),
+            media_type: manifest.get_media_type(),
+            size,
+            artifact_type: manifest.artifact_type().unwrap_or_default().to_string(),
+            annotations: manifest.annotations().cloned().unwrap_or_default(),
+        },
+    ))
+}
+
+fn read_referrer(blobs_path: &Path, digest: &str) -> Option<(String, Referrer)> {
+    let bytes = fs::read(blob_path(blobs_path, digest)?).ok()?;
+    let manifest = Manifest::from_json(&serde_json::from_slice(&bytes).ok()?).ok()?;
+    as_referrer(&manifest, digest, bytes.len() as u64)
+}
+
+/*
+ * Referrers of the subject in the repository, by digest. Only the repository's own tag files are
+ * read, not those of repositories nested under it.
+ */
+pub fn list_referrers(
+    manifests_path: &Path,
+    blobs_path: &Path,
+    repo_name: &str,
+    subject: &str,
+) -> Result<Vec<Referrer>> {
+    let entries = match fs::read_dir(manifests_path.join(repo_name)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut digests = vec![];
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Ok(digest) = get_digest_from_manifest_path(entry.path()) {
+            digests.push(digest);
+        }
+    }
+    digests.sort();
+    digests.dedup();
+
+    Ok(digests
+        .iter()
+        .filter_map(|d| read_referrer(blobs_path, d))
+        .filter(|(s, _)| s == subject)
+        .map(|(_, referrer)| referrer)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::list_referrers;
+    use crate::digest::sha256_tag_digest;
+    use std::fs;
+    use std::io::BufReader;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn write_blob(blobs: &Path, content: &str) -> String {
+        let digest = sha256_tag_digest(BufReader::new(content.as_bytes())).unwrap();
+        let dir = blobs.join("sha256");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(digest.trim_start_matches("sha256:")), content).unwrap();
+        digest
+    }
+
+    fn write_tag(manifests: &Path, repo: &str, tag: &str, digest: &str) {
+        let dir = manifests.join(repo);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(tag), format!("{} 2022-03-01T00:00:00Z\n", digest)).unwrap();
+    }
+
+    fn artifact(artifact_type: &str, subject: &str) -> String {
+        format!(
+            r#"{{"mediaType": "application/vnd.oci.artifact.manifest.v1+json",
+            "artifactType": "{}",
+            "blobs": [],
+            "subject": {{"mediaType": "application/vnd.oci.image.manifest.v1+json", "size": 10, "digest": "{}"}},
+            "annotations": {{"org.example.kind": "{}"}}}}"#,
+            artifact_type, subject, artifact_type
+        )
+    }
+
+    #[test]
+    fn lists_referrers_in_repo() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        let blobs = dir.path().join("blobs");
+        let image = format!("sha256:{}", "a".repeat(64));
+        let other = format!("sha256:{}", "b".repeat(64));
+
+        let sbom = write_blob(&blobs, &artifact("application/spdx+json", &image));
+        write_tag(&manifests, "app", &sbom, &sbom);
+        let signature = write_blob(
+            &blobs,
+            &artifact("application/vnd.cncf.notary.signature", &image),
+        );
+        write_tag(&manifests, "app", "signature", &signature);
+        let unrelated = write_blob(&blobs, &artifact("application/spdx+json", &other));
+        write_tag(&manifests, "app", &unrelated, &unrelated);
+        // Not in the repository
+        write_tag(&manifests, "app/nested", &sbom, &sbom);
+        let elsewhere = write_blob(&blobs, &artifact("application/vnd.example", &image));
+        write_tag(&manifests, "app/nested", &elsewhere, &elsewhere);
+
+        let referrers = list_referrers(&manifests, &blobs, "app", &image).unwrap();
+        let mut types: Vec<&str> = referrers.iter().map(|r| r.artifact_type.as_str()).collect();
+        types.sort_unstable();
+        assert_eq!(
+            types,
+            vec![
+                "application/spdx+json",
+                "application/vnd.cncf.notary.signature"
+            ]
+        );
+        let sbom_referrer = referrers.iter().find(|r| r.digest == sbom).unwrap();
+        assert_eq!(
+            sbom_referrer.media_type,
+            "application/vnd.oci.artifact.manifest.v1+json"
+        );
+        assert_eq!(
+            sbom_referrer.annotations["org.example.kind"],
+            "application/spdx+json"
+        );
+
+        assert!(list_referrers(&manifests, &blobs, "missing", &image)
+            .unwrap()
+            .is_empty());
+    }
+}
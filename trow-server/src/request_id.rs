@@ -0,0 +1,113 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tonic::codegen::{http, Service};
+use tonic::transport::NamedService;
+use uuid::Uuid;
+
+/*
+ * Correlation IDs, so the log lines from the frontend and backend for one registry request can
+ * be tied together.
+ *
+ * The frontend gives each HTTP request an ID (or uses the client's X-Request-Id) and sends it to
+ * the backend in the x-request-id gRPC metadata. While the request is being handled the ID is
+ * kept in a task-local, which the logger adds to every line. Work spawned onto other tasks, such
+ * as streaming responses and jobs, isn't covered.
+ */
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Longer IDs from clients are replaced rather than logged
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+pub fn new_request_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/*
+ * Whether an ID from a client is safe to log and pass on, i.e. short and printable without
+ * spaces or quotes.
+ */
+pub fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:/+=".contains(c))
+}
+
+/// The ID of the request being handled by this task, if any
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Runs the future with the given request ID
+pub async fn scope<F: Future>(id: String, f: F) -> F::Output {
+    REQUEST_ID.scope(id, f).await
+}
+
+/*
+ * Wraps a gRPC service so each call runs with the request ID from its metadata.
+ */
+#[derive(Clone)]
+pub struct WithRequestId<S>(pub S);
+
+impl<S: NamedService> NamedService for WithRequestId<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for WithRequestId<S>
+where
+    S: Service<http::Request<B>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| is_valid(id))
+            .map(|id| id.to_string());
+        let fut = self.0.call(req);
+        match id {
+            Some(id) => Box::pin(scope(id, fut)),
+            None => Box::pin(fut),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{current, is_valid, new_request_id, scope};
+
+    #[test]
+    fn validates_ids() {
+        assert!(is_valid(&new_request_id()));
+        assert!(is_valid("req-42"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("has spaces"));
+        assert!(!is_valid("quote\"d"));
+        assert!(!is_valid(&"x".repeat(200)));
+    }
+
+    #[tokio::test]
+    async fn id_is_scoped_to_the_future() {
+        assert_eq!(current(), None);
+        let id = scope("abc".to_string(), async { current() }).await;
+        assert_eq!(id.as_deref(), Some("abc"));
+        assert_eq!(current(), None);
+    }
+}
@@ -0,0 +1,724 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+
+use crate::jobs::JobHandle;
+use crate::maintenance::{blob_path, walk_files};
+use crate::selector::TagSelector;
+
+// Tag retention policies.
+//
+// Rules are given as SELECTOR=ACTION, where the selector is a repository pattern optionally
+// followed by a tag pattern. Patterns can use * to match anything, including /.
+//
+//   myorg/*:release-*=keep-last:10  keep the 10 most recently pushed release-* tags in each
+//                                   repository under myorg/
+//   *=untagged-older-than:30d       delete manifests that are no longer tagged after 30 days
+//
+// If several keep-last rules cover a tag, it's kept if any of them keep it. Untagged manifests
+// are those pushed by digest and older versions of tags in the tag history.
+//
+// Manifests can also carry hints in their annotations, which win over the rules:
+//
+//   trow.io/retention: keep         never delete tags or references to this manifest, and don't
+//                                   count its tags towards keep-last
+//   trow.io/expires-after: 7d       delete tags pointing at this manifest 7 days after they were
+//                                   pushed, or the manifest 7 days after it was untagged
+//
+// Only references to manifests are deleted; garbage collection frees the blobs afterwards.
+
+const KEEP_ANNOTATION: &str = "trow.io/retention";
+const EXPIRES_ANNOTATION: &str = "trow.io/expires-after";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuleAction {
+    KeepLast(usize),
+    UntaggedOlderThan(Duration),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetentionRule {
+    selector: TagSelector,
+    action: RuleAction,
+}
+
+/*
+ * A reference to a manifest that a rule wants removed.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Deletion {
+    pub repo_name: String,
+    // None for manifests pushed by digest
+    pub tag: Option<String>,
+    pub digest: String,
+    // Only the digest's entry in the tag history is removed, the tag stays
+    pub history_only: bool,
+    pub rule: String,
+}
+
+// Accepts a number followed by s, m, h or d
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    if s == "0" {
+        return Ok(Duration::ZERO);
+    }
+    let (num, unit) = s.split_at(s.len() - s.chars().last().map_or(0, |c| c.len_utf8()));
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(anyhow!("Duration {} needs a unit of s, m, h or d", s)),
+    };
+    let num: u64 = num.parse().map_err(|_| anyhow!("Invalid duration {}", s))?;
+    Ok(Duration::from_secs(num * multiplier))
+}
+
+fn format_duration(d: &Duration) -> String {
+    let secs = d.as_secs();
+    for (unit, size) in [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60)] {
+        if secs % size == 0 && secs >= size {
+            return format!("{}{}", secs / size, unit);
+        }
+    }
+    format!("{}s", secs)
+}
+
+impl FromStr for RetentionRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (selector, action) = s.rsplit_once('=').ok_or_else(|| {
+            anyhow!(
+                "Retention rule {} should be of the form REPOS[:TAGS]=keep-last:N or REPOS=untagged-older-than:AGE",
+                s
+            )
+        })?;
+        let selector: TagSelector = selector
+            .parse()
+            .map_err(|e| anyhow!("Invalid retention rule: {}", e))?;
+        let action = match action.split_once(':') {
+            Some(("keep-last", n)) => RuleAction::KeepLast(
+                n.trim()
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid tag count {} in retention rule {}", n, s))?,
+            ),
+            Some(("untagged-older-than", age)) => {
+                if selector.tags.is_some() {
+                    return Err(anyhow!(
+                        "Retention rule {} can't use a tag pattern with untagged-older-than",
+                        s
+                    ));
+                }
+                RuleAction::UntaggedOlderThan(parse_duration(age)?)
+            }
+            _ => return Err(anyhow!("Unknown action {} in retention rule {}", action, s)),
+        };
+        Ok(RetentionRule { selector, action })
+    }
+}
+
+impl fmt::Display for RetentionRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.action {
+            RuleAction::KeepLast(n) => write!(f, "{}=keep-last:{}", self.selector, n),
+            RuleAction::UntaggedOlderThan(age) => write!(
+                f,
+                "{}=untagged-older-than:{}",
+                self.selector,
+                format_duration(&age)
+            ),
+        }
+    }
+}
+
+// A line from a tag file
+pub(crate) struct HistoryEntry {
+    pub digest: String,
+    pub date: Option<DateTime<Utc>>,
+}
+
+pub(crate) struct TagFile {
+    // First entry is the current digest, see save_tag
+    pub history: Vec<HistoryEntry>,
+    // Time of the latest push to the tag
+    pub pushed: SystemTime,
+}
+
+fn read_tag_file(path: &Path) -> Result<TagFile> {
+    let mut history = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let mut parts = line.splitn(2, ' ');
+        let digest = match parts.next() {
+            Some(d) if !d.is_empty() => d.to_string(),
+            _ => continue,
+        };
+        let date = parts
+            .next()
+            .and_then(|d| DateTime::parse_from_rfc3339(d.trim()).ok())
+            .map(|d| d.with_timezone(&Utc));
+        history.push(HistoryEntry { digest, date });
+    }
+    let pushed = history
+        .iter()
+        .filter_map(|e| e.date)
+        .max()
+        .map(SystemTime::from)
+        .map_or_else(|| fs::metadata(path)?.modified(), Ok)?;
+    Ok(TagFile { history, pushed })
+}
+
+pub(crate) fn is_digest(reference: &str) -> bool {
+    reference.starts_with("sha256:")
+}
+
+// Repository name to tag name to tag file
+pub(crate) fn read_repos(
+    manifests_path: &Path,
+) -> Result<HashMap<String, HashMap<String, TagFile>>> {
+    let mut repos: HashMap<String, HashMap<String, TagFile>> = HashMap::new();
+    for path in walk_files(manifests_path)? {
+        let rel = match path.strip_prefix(manifests_path) {
+            Ok(rel) => rel,
+            Err(_) => continue,
+        };
+        let (repo, tag) = match (rel.parent(), rel.file_name()) {
+            (Some(repo), Some(tag)) => (repo.to_string_lossy(), tag.to_string_lossy()),
+            _ => continue,
+        };
+        match read_tag_file(&path) {
+            Ok(tf) => {
+                repos
+                    .entry(repo.to_string())
+                    .or_default()
+                    .insert(tag.to_string(), tf);
+            }
+            Err(e) => warn!("Failed to read tag file {:?}: {:?}", path, e),
+        }
+    }
+    Ok(repos)
+}
+
+// Annotations on a manifest that override the rules
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Hints {
+    keep: bool,
+    expires_after: Option<Duration>,
+}
+
+fn read_hints(blobs_path: &Path, digest: &str) -> Hints {
+    let annotations = blob_path(blobs_path, digest)
+        .and_then(|p| fs::read(p).ok())
+        .and_then(|b| serde_json::from_slice::<serde_json::Value>(&b).ok())
+        .and_then(|v| v.get("annotations").cloned());
+    let annotation = |key: &str| {
+        annotations
+            .as_ref()
+            .and_then(|a| a.get(key))
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+    };
+
+    let mut hints = Hints::default();
+    match annotation(KEEP_ANNOTATION).as_deref() {
+        Some("keep") => hints.keep = true,
+        Some(v) => warn!("Ignoring {}: {} on {}", KEEP_ANNOTATION, v, digest),
+        None => (),
+    }
+    if let Some(v) = annotation(EXPIRES_ANNOTATION) {
+        match parse_duration(&v) {
+            Ok(d) => hints.expires_after = Some(d),
+            Err(e) => warn!("Ignoring {} on {}: {}", EXPIRES_ANNOTATION, digest, e),
+        }
+    }
+    hints
+}
+
+/*
+ * Works out what the rules and hints would delete, without changing anything.
+ */
+pub fn plan(
+    manifests_path: &Path,
+    blobs_path: &Path,
+    rules: &[RetentionRule],
+    now: SystemTime,
+) -> Result<Vec<Deletion>> {
+    let mut deletions = vec![];
+    let repos = read_repos(manifests_path)?;
+    let mut repo_names: Vec<&String> = repos.keys().collect();
+    repo_names.sort();
+    let mut hints_by_digest: HashMap<&str, Hints> = HashMap::new();
+    for tags in repos.values() {
+        for (tag, tf) in tags {
+            let digests = tf.history.iter().map(|e| e.digest.as_str());
+            for digest in digests.chain(Some(tag.as_str()).filter(|t| is_digest(t))) {
+                hints_by_digest
+                    .entry(digest)
+                    .or_insert_with(|| read_hints(blobs_path, digest));
+            }
+        }
+    }
+    let hints = |digest: &str| hints_by_digest.get(digest).copied().unwrap_or_default();
+    let expiry_reason = |age: &Duration| format!("{}={}", EXPIRES_ANNOTATION, format_duration(age));
+
+    for repo_name in repo_names {
+        let tags = &repos[repo_name];
+        let mut tag_names: Vec<&String> = tags.keys().collect();
+        tag_names.sort();
+        let rules: Vec<&RetentionRule> = rules
+            .iter()
+            .filter(|r| r.selector.matches_repo(repo_name))
+            .collect();
+        let untagged_rule = rules.iter().find_map(|r| match r.action {
+            RuleAction::UntaggedOlderThan(age) => Some((r.to_string(), age)),
+            _ => None,
+        });
+        let current_digest = |tag: &String| {
+            tags[tag]
+                .history
+                .first()
+                .map(|e| e.digest.clone())
+                .unwrap_or_default()
+        };
+        let pinned: HashSet<&String> = tag_names
+            .iter()
+            .copied()
+            .filter(|t| !is_digest(t) && hints(&current_digest(t)).keep)
+            .collect();
+
+        // Tag name to the first rule that would delete it, unless another rule keeps it.
+        // Pinned tags don't use up a place.
+        let mut expired: HashMap<&String, &RetentionRule> = HashMap::new();
+        let mut kept: HashSet<&String> = HashSet::new();
+        for rule in &rules {
+            if let RuleAction::KeepLast(n) = rule.action {
+                let mut covered: Vec<&String> = tag_names
+                    .iter()
+                    .copied()
+                    .filter(|t| {
+                        !is_digest(t) && !pinned.contains(t) && rule.selector.matches(repo_name, t)
+                    })
+                    .collect();
+                // Most recent first
+                covered.sort_by_key(|t| std::cmp::Reverse(tags[*t].pushed));
+                kept.extend(covered.iter().take(n).copied());
+                for tag in covered.into_iter().skip(n) {
+                    expired.entry(tag).or_insert(rule);
+                }
+            }
+        }
+
+        // Digests the tags point at now
+        let current: HashSet<&str> = tags
+            .iter()
+            .filter(|(t, _)| !is_digest(t))
+            .filter_map(|(_, tf)| tf.history.first())
+            .map(|e| e.digest.as_str())
+            .collect();
+
+        // How long a manifest is kept once untagged, and why. The hint wins over the rule.
+        let untagged_expiry = |digest: &str| -> Option<(String, Duration)> {
+            let h = hints(digest);
+            if h.keep {
+                None
+            } else if let Some(age) = h.expires_after {
+                Some((expiry_reason(&age), age))
+            } else {
+                untagged_rule.clone()
+            }
+        };
+
+        for tag in &tag_names {
+            let tf = &tags[*tag];
+            if is_digest(tag) {
+                // Pushed by digest, untagged unless a tag points at the same manifest
+                if current.contains(tag.as_str()) {
+                    continue;
+                }
+                if let Some((rule, age)) = untagged_expiry(tag) {
+                    if tf.pushed < now - age {
+                        deletions.push(Deletion {
+                            repo_name: repo_name.to_string(),
+                            tag: None,
+                            digest: tag.to_string(),
+                            history_only: false,
+                            rule,
+                        });
+                    }
+                }
+                continue;
+            }
+
+            let digest = current_digest(tag);
+            let reason = if pinned.contains(tag) {
+                None
+            } else if let (Some(rule), false) = (expired.get(tag), kept.contains(tag)) {
+                Some(rule.to_string())
+            } else {
+                hints(&digest)
+                    .expires_after
+                    .filter(|age| tf.pushed < now - *age)
+                    .map(|age| expiry_reason(&age))
+            };
+            if let Some(rule) = reason {
+                deletions.push(Deletion {
+                    repo_name: repo_name.to_string(),
+                    tag: Some(tag.to_string()),
+                    digest,
+                    history_only: false,
+                    rule,
+                });
+                continue;
+            }
+
+            for entry in tf.history.iter().skip(1) {
+                if current.contains(entry.digest.as_str()) {
+                    continue;
+                }
+                if let (Some((rule, age)), Some(date)) =
+                    (untagged_expiry(&entry.digest), entry.date)
+                {
+                    if SystemTime::from(date) < now - age {
+                        deletions.push(Deletion {
+                            repo_name: repo_name.to_string(),
+                            tag: Some(tag.to_string()),
+                            digest: entry.digest.clone(),
+                            history_only: true,
+                            rule,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    Ok(deletions)
+}
+
+// Rewrites the tag file without the given digests. Written to the scratch dir first so the
+// tag file is never partial and the watcher doesn't see a stray file.
+fn prune_history(path: &Path, scratch_path: &Path, digests: &HashSet<&str>) -> Result<()> {
+    let contents = fs::read_to_string(path)?;
+    let tmp = scratch_path.join(format!("retention-{}", uuid::Uuid::new_v4()));
+    let mut out = File::create(&tmp)?;
+    let mut seen_current = false;
+    for line in contents.lines() {
+        let digest = line.split(' ').next().unwrap_or("");
+        // Never remove the current digest, even if it appears again further down
+        if !seen_current || !digests.contains(digest) {
+            writeln!(out, "{}", line)?;
+        }
+        seen_current = true;
+    }
+    out.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/*
+ * Carries out the deletions from plan, returning the ones that succeeded.
+ */
+pub fn apply(
+    manifests_path: &Path,
+    scratch_path: &Path,
+    deletions: Vec<Deletion>,
+    handle: &JobHandle,
+) -> Result<Vec<Deletion>> {
+    let mut done = vec![];
+    let mut history: HashMap<PathBuf, (HashSet<String>, Vec<Deletion>)> = HashMap::new();
+    let total = deletions.len();
+
+    for (i, deletion) in deletions.into_iter().enumerate() {
+        if handle.is_cancelled() {
+            return Ok(done);
+        }
+        handle.set_progress(i, total);
+
+        let reference = deletion.tag.as_deref().unwrap_or(&deletion.digest);
+        let path = manifests_path.join(&deletion.repo_name).join(reference);
+        if deletion.history_only {
+            let entry = history.entry(path).or_default();
+            entry.0.insert(deletion.digest.clone());
+            entry.1.push(deletion);
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(_) => {
+                info!(
+                    "Retention rule {} deleted {}/{}",
+                    deletion.rule, deletion.repo_name, reference
+                );
+                done.push(deletion);
+            }
+            Err(e) => warn!("Failed to delete {:?}: {:?}", path, e),
+        }
+    }
+
+    for (path, (digests, deletions)) in history {
+        // The tag may have been deleted by another rule
+        if !path.exists() {
+            continue;
+        }
+        let digests: HashSet<&str> = digests.iter().map(String::as_str).collect();
+        match prune_history(&path, scratch_path, &digests) {
+            Ok(_) => {
+                info!(
+                    "Retention removed {} old entries from {:?}",
+                    digests.len(),
+                    path
+                );
+                done.extend(deletions);
+            }
+            Err(e) => warn!("Failed to prune history of {:?}: {:?}", path, e),
+        }
+    }
+    Ok(done)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_duration, plan, RetentionRule, RuleAction};
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use tempfile::tempdir;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn write_tag(manifests: &Path, repo: &str, tag: &str, lines: &[(&str, &str)]) {
+        let dir = manifests.join(repo);
+        fs::create_dir_all(&dir).unwrap();
+        let contents: String = lines
+            .iter()
+            .map(|(digest, date)| format!("{} {}\n", digest, date))
+            .collect();
+        fs::write(dir.join(tag), contents).unwrap();
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rule: RetentionRule = "myorg/*:release-*=keep-last:10".parse().unwrap();
+        assert_eq!(rule.action, RuleAction::KeepLast(10));
+        assert_eq!(rule.to_string(), "myorg/*:release-*=keep-last:10");
+
+        let rule: RetentionRule = "*=untagged-older-than:30d".parse().unwrap();
+        assert_eq!(
+            rule.action,
+            RuleAction::UntaggedOlderThan(Duration::from_secs(30 * DAY))
+        );
+        assert_eq!(rule.to_string(), "*=untagged-older-than:30d");
+        assert!("myorg=keep-last:lots".parse::<RetentionRule>().is_err());
+        assert!("myorg:v*=untagged-older-than:1d"
+            .parse::<RetentionRule>()
+            .is_err());
+        assert!("myorg=delete-all:1".parse::<RetentionRule>().is_err());
+        assert!("=keep-last:1".parse::<RetentionRule>().is_err());
+        assert!(parse_duration("30").is_err());
+        assert_eq!(
+            parse_duration("12h").unwrap(),
+            Duration::from_secs(12 * 60 * 60)
+        );
+    }
+
+    #[test]
+    fn keeps_most_recent_tags() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path();
+        // No manifests, so no hints
+        let blobs = dir.path().join("blobs");
+        write_tag(
+            manifests,
+            "app",
+            "release-1",
+            &[("sha256:a", "2022-01-01T00:00:00Z")],
+        );
+        write_tag(
+            manifests,
+            "app",
+            "release-2",
+            &[("sha256:b", "2022-02-01T00:00:00Z")],
+        );
+        write_tag(
+            manifests,
+            "app",
+            "release-3",
+            &[("sha256:c", "2022-03-01T00:00:00Z")],
+        );
+        write_tag(
+            manifests,
+            "app",
+            "latest",
+            &[("sha256:c", "2022-03-01T00:00:00Z")],
+        );
+        write_tag(
+            manifests,
+            "other",
+            "release-1",
+            &[("sha256:a", "2022-01-01T00:00:00Z")],
+        );
+
+        let rules = vec!["app:release-*=keep-last:2".parse().unwrap()];
+        let deletions = plan(manifests, &blobs, &rules, SystemTime::now()).unwrap();
+        assert_eq!(deletions.len(), 1);
+        assert_eq!(deletions[0].repo_name, "app");
+        assert_eq!(deletions[0].tag.as_deref(), Some("release-1"));
+        assert_eq!(deletions[0].digest, "sha256:a");
+        assert!(!deletions[0].history_only);
+
+        // Another rule keeping the tag wins
+        let rules = vec![
+            "app:release-*=keep-last:2".parse().unwrap(),
+            "app=keep-last:4".parse().unwrap(),
+        ];
+        assert!(plan(manifests, &blobs, &rules, SystemTime::now())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn deletes_old_untagged_manifests() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path();
+        // No manifests, so no hints
+        let blobs = dir.path().join("blobs");
+        write_tag(
+            manifests,
+            "app",
+            "latest",
+            &[
+                ("sha256:current", "2022-03-01T00:00:00Z"),
+                ("sha256:old", "2022-01-01T00:00:00Z"),
+                ("sha256:recent", "2022-02-25T00:00:00Z"),
+            ],
+        );
+        write_tag(
+            manifests,
+            "app",
+            "sha256:bydigest",
+            &[("sha256:bydigest", "")],
+        );
+        write_tag(
+            manifests,
+            "app",
+            "sha256:current",
+            &[("sha256:current", "")],
+        );
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1646092800); // 2022-03-01
+        let rules = vec!["app=untagged-older-than:7d".parse().unwrap()];
+        // Digest files have no date, so use the modification time
+        let deletions = plan(
+            manifests,
+            &blobs,
+            &rules,
+            now + Duration::from_secs(365 * 100 * DAY),
+        )
+        .unwrap();
+        let refs: Vec<(Option<&str>, &str)> = deletions
+            .iter()
+            .map(|d| (d.tag.as_deref(), d.digest.as_str()))
+            .collect();
+        assert!(refs.contains(&(Some("latest"), "sha256:old")));
+        assert!(refs.contains(&(Some("latest"), "sha256:recent")));
+        assert!(refs.contains(&(None, "sha256:bydigest")));
+        // Still tagged
+        assert!(!refs.contains(&(None, "sha256:current")));
+
+        let deletions = plan(manifests, &blobs, &rules, now).unwrap();
+        assert_eq!(deletions.len(), 1);
+        assert_eq!(deletions[0].digest, "sha256:old");
+        assert!(deletions[0].history_only);
+    }
+
+    #[test]
+    fn annotations_override_rules() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        let blobs = dir.path().join("blobs");
+        fs::create_dir_all(blobs.join("sha256")).unwrap();
+        let annotated = |annotations: &str| {
+            format!(
+                r#"{{"schemaVersion": 2, "mediaType": "application/vnd.oci.image.manifest.v1+json", "annotations": {}}}"#,
+                annotations
+            )
+        };
+        fs::write(
+            blobs.join("sha256").join("release"),
+            annotated(r#"{"trow.io/retention": "keep"}"#),
+        )
+        .unwrap();
+        for name in ["throwaway", "throwaway-old"] {
+            fs::write(
+                blobs.join("sha256").join(name),
+                annotated(r#"{"trow.io/expires-after": "1d"}"#),
+            )
+            .unwrap();
+        }
+
+        write_tag(
+            &manifests,
+            "app",
+            "v1",
+            &[("sha256:release", "2022-01-01T00:00:00Z")],
+        );
+        write_tag(
+            &manifests,
+            "app",
+            "v2",
+            &[("sha256:plain", "2022-02-01T00:00:00Z")],
+        );
+        write_tag(
+            &manifests,
+            "app",
+            "v3",
+            &[("sha256:plain3", "2022-02-20T00:00:00Z")],
+        );
+        write_tag(
+            &manifests,
+            "app",
+            "pr-1",
+            &[("sha256:throwaway", "2022-02-27T00:00:00Z")],
+        );
+        write_tag(
+            &manifests,
+            "app",
+            "pr-2",
+            &[
+                ("sha256:plain4", "2022-02-28T12:00:00Z"),
+                ("sha256:throwaway-old", "2022-01-01T00:00:00Z"),
+            ],
+        );
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1646092800); // 2022-03-01
+        let rules = vec!["app:v*=keep-last:1".parse().unwrap()];
+        let deletions = plan(&manifests, &blobs, &rules, now).unwrap();
+        let refs: Vec<(&str, &str, &str)> = deletions
+            .iter()
+            .map(|d| {
+                (
+                    d.tag.as_deref().unwrap(),
+                    d.digest.as_str(),
+                    d.rule.as_str(),
+                )
+            })
+            .collect();
+        // v1 is pinned, so only v3 is kept by the rule
+        assert_eq!(
+            refs,
+            vec![
+                ("pr-1", "sha256:throwaway", "trow.io/expires-after=1d"),
+                ("pr-2", "sha256:throwaway-old", "trow.io/expires-after=1d"),
+                ("v2", "sha256:plain", "app:v*=keep-last:1"),
+            ]
+        );
+        assert!(deletions[1].history_only);
+    }
+}
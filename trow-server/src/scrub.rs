@@ -0,0 +1,353 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+
+use crate::digest::sha256_tag_digest;
+use crate::jobs::JobHandle;
+use crate::maintenance::{blob_path, digest_for_blob, walk_files};
+use crate::manifest::{FromJson, Manifest};
+
+/*
+ * Checks the stored content is intact, so bit rot on the volume is found before a kubelet pulls
+ * a broken layer.
+ *
+ * The "scrub" job re-hashes every blob and compares it with its digest, then follows every tag to
+ * the manifests and blobs it refers to, reporting any that aren't stored. It can run every
+ * --scrub-interval as well as on demand.
+ *
+ * Corrupt blobs are left in place unless --scrub-quarantine is given, in which case they're moved
+ * to the quarantine dir in the data dir. Pulls of a quarantined blob then fail as not found rather
+ * than with a digest mismatch, and the next push of the image uploads it again. Quarantined blobs
+ * are kept for inspection and never deleted by Trow.
+ *
+ * The result is in the job message, the scrub_* metrics, and the last report is kept for the
+ * admin API until Trow restarts.
+ */
+
+// Largest blob read to see if it's a manifest list, which refers to further manifests
+const MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024;
+
+lazy_static! {
+    pub static ref CHECKED_BLOBS: IntGauge =
+        register_int_gauge!("scrub_blobs", "blobs re-hashed by the last scrub").unwrap();
+    pub static ref CORRUPT_BLOBS: IntGauge = register_int_gauge!(
+        "scrub_corrupt_blobs",
+        "blobs that didn't match their digest in the last scrub"
+    )
+    .unwrap();
+    pub static ref MISSING_BLOBS: IntGauge = register_int_gauge!(
+        "scrub_missing_blobs",
+        "blobs referred to by a tag but not stored, found by the last scrub"
+    )
+    .unwrap();
+    pub static ref QUARANTINED: IntCounter = register_int_counter!(
+        "scrub_quarantined_blobs_total",
+        "corrupt blobs moved to the quarantine dir"
+    )
+    .unwrap();
+    pub static ref LAST_SCRUB: IntGauge = register_int_gauge!(
+        "scrub_last_finished_timestamp_seconds",
+        "when the last scrub finished, in seconds since the epoch"
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingBlob {
+    pub digest: String,
+    // The first tag found referring to it, as repo:tag
+    pub referenced_by: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScrubReport {
+    pub checked: usize,
+    pub corrupt: Vec<String>,
+    pub missing: Vec<MissingBlob>,
+    pub quarantined: Vec<String>,
+    // Stopped part way, so not everything was checked
+    pub cancelled: bool,
+    pub finished: DateTime<Utc>,
+}
+
+impl ScrubReport {
+    fn new() -> ScrubReport {
+        ScrubReport {
+            checked: 0,
+            corrupt: vec![],
+            missing: vec![],
+            quarantined: vec![],
+            cancelled: false,
+            finished: Utc::now(),
+        }
+    }
+
+    pub fn record_metrics(&self) {
+        CHECKED_BLOBS.set(self.checked as i64);
+        CORRUPT_BLOBS.set(self.corrupt.len() as i64);
+        MISSING_BLOBS.set(self.missing.len() as i64);
+        LAST_SCRUB.set(self.finished.timestamp());
+    }
+
+    // For the job message
+    pub fn summary(&self) -> String {
+        let mut msg = format!(
+            "{} {} blobs, corrupt: {:?}",
+            if self.cancelled {
+                "Cancelled after checking"
+            } else {
+                "Checked"
+            },
+            self.checked,
+            self.corrupt
+        );
+        if !self.missing.is_empty() {
+            let missing: Vec<String> = self
+                .missing
+                .iter()
+                .map(|m| format!("{} (from {})", m.digest, m.referenced_by))
+                .collect();
+            msg.push_str(&format!(", missing: {}", missing.join(", ")));
+        }
+        if !self.quarantined.is_empty() {
+            msg.push_str(&format!(". Quarantined {}", self.quarantined.len()));
+        }
+        msg
+    }
+}
+
+// Moves the blob to the same place under the quarantine dir, out of reach of pulls
+fn quarantine(blobs_path: &Path, quarantine_path: &Path, blob: &Path) -> Result<()> {
+    let dest = quarantine_path.join(blob.strip_prefix(blobs_path)?);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(blob, &dest)?;
+    Ok(())
+}
+
+// Every digest in the tag file, the current one first
+fn tag_digests(tag: &Path) -> Result<Vec<String>> {
+    let file = match File::open(tag) {
+        Ok(f) => f,
+        // Deleted since the tags were listed
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut digests = vec![];
+    for line in BufReader::new(file).lines() {
+        if let Some(digest) = line?.split(' ').next().filter(|d| !d.is_empty()) {
+            digests.push(digest.to_string());
+        }
+    }
+    Ok(digests)
+}
+
+// What the manifest list or image manifest refers to, and whether those are manifests too
+fn manifest_children(blob: &Path) -> Vec<(String, bool)> {
+    let manifest = fs::read(blob)
+        .ok()
+        .and_then(|b| serde_json::from_slice(&b).ok())
+        .and_then(|v| Manifest::from_json(&v).ok());
+    match manifest {
+        Some(m) => {
+            let is_list = matches!(m, Manifest::List(_));
+            m.get_local_asset_digests()
+                .into_iter()
+                .map(|d| (d.to_string(), is_list))
+                .collect()
+        }
+        None => vec![],
+    }
+}
+
+/**
+ * Re-hashes every blob and checks everything the tags refer to is stored. Corrupt blobs are moved
+ * under quarantine_path if it's given.
+ */
+pub fn scrub(
+    manifests_path: &Path,
+    blobs_path: &Path,
+    quarantine_path: Option<&Path>,
+    handle: &JobHandle,
+) -> Result<ScrubReport> {
+    let blobs = walk_files(blobs_path)?;
+    let tags = walk_files(manifests_path)?;
+    let total = blobs.len() + tags.len();
+    let mut report = ScrubReport::new();
+
+    for (i, blob) in blobs.iter().enumerate() {
+        if handle.is_cancelled() {
+            report.cancelled = true;
+            report.finished = Utc::now();
+            return Ok(report);
+        }
+        handle.set_progress(i, total);
+
+        let digest = match digest_for_blob(blobs_path, blob) {
+            Some(d) => d,
+            None => continue,
+        };
+        let actual = match File::open(blob) {
+            Ok(f) => sha256_tag_digest(BufReader::new(f))?,
+            // Garbage collected since the blobs were listed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        report.checked += 1;
+        if actual == digest {
+            continue;
+        }
+        warn!(
+            "Blob {} is corrupt, contents have digest {}",
+            digest, actual
+        );
+        if let Some(q) = quarantine_path {
+            match quarantine(blobs_path, q, blob) {
+                Ok(_) => {
+                    info!("Quarantined corrupt blob {}", digest);
+                    QUARANTINED.inc();
+                    report.quarantined.push(digest.clone());
+                }
+                Err(e) => warn!("Failed to quarantine blob {}: {:?}", digest, e),
+            }
+        }
+        report.corrupt.push(digest);
+    }
+
+    let corrupt: HashSet<String> = report.corrupt.iter().cloned().collect();
+    let mut seen = HashSet::new();
+    for (i, tag) in tags.iter().enumerate() {
+        if handle.is_cancelled() {
+            report.cancelled = true;
+            break;
+        }
+        handle.set_progress(blobs.len() + i, total);
+
+        let referenced_by = match tag.strip_prefix(manifests_path) {
+            Ok(rel) => format!(
+                "{}:{}",
+                rel.parent().unwrap_or(rel).to_string_lossy(),
+                rel.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            Err(_) => continue,
+        };
+        let mut to_visit: Vec<(String, bool)> =
+            tag_digests(tag)?.into_iter().map(|d| (d, true)).collect();
+        while let Some((digest, is_manifest)) = to_visit.pop() {
+            if !seen.insert(digest.clone()) || corrupt.contains(&digest) {
+                continue;
+            }
+            let path = blob_path(blobs_path, &digest);
+            match path.as_ref().map(fs::metadata) {
+                Some(Ok(m)) => {
+                    if is_manifest && m.len() <= MAX_MANIFEST_SIZE {
+                        to_visit.extend(manifest_children(path.as_ref().unwrap()));
+                    }
+                }
+                _ => {
+                    warn!("Blob {} used by {} isn't stored", digest, referenced_by);
+                    report.missing.push(MissingBlob {
+                        digest,
+                        referenced_by: referenced_by.clone(),
+                    });
+                }
+            }
+        }
+    }
+    report.finished = Utc::now();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{scrub, ScrubReport};
+    use crate::digest::sha256_tag_digest;
+    use crate::jobs::{JobKind, Jobs};
+    use crate::maintenance::blob_path;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn store(blobs: &Path, bytes: &[u8]) -> String {
+        let digest = sha256_tag_digest(bytes).unwrap();
+        let path = blob_path(blobs, &digest).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, bytes).unwrap();
+        digest
+    }
+
+    async fn run(manifests: PathBuf, blobs: PathBuf, quarantine: Option<PathBuf>) -> ScrubReport {
+        let jobs = Jobs::new();
+        let report = Arc::new(Mutex::new(None));
+        let result = report.clone();
+        let job = jobs.start(JobKind::Scrub, move |h| {
+            let r = scrub(&manifests, &blobs, quarantine.as_deref(), h)?;
+            let msg = r.summary();
+            *result.lock().unwrap() = Some(r);
+            Ok(msg)
+        });
+        while jobs.get(&job.id).unwrap().finished.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let report = report.lock().unwrap().take();
+        report.unwrap()
+    }
+
+    #[tokio::test]
+    async fn finds_corrupt_and_missing_blobs() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        let blobs = dir.path().join("blobs");
+        let quarantine = dir.path().join("quarantine");
+
+        let layer = store(&blobs, b"layer contents");
+        let config = store(&blobs, b"{}");
+        let missing = format!("sha256:{}", "a".repeat(64));
+        let manifest = format!(
+            r#"{{"schemaVersion": 2, "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": {{"mediaType": "application/vnd.docker.container.image.v1+json", "size": 2, "digest": "{}"}},
+            "layers": [{{"mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "size": 1, "digest": "{}"}},
+            {{"mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "size": 1, "digest": "{}"}}]}}"#,
+            config, layer, missing
+        );
+        let manifest = store(&blobs, manifest.as_bytes());
+        fs::create_dir_all(manifests.join("team/app")).unwrap();
+        fs::write(
+            manifests.join("team/app/latest"),
+            format!("{} 2022-03-01T00:00:00Z\n", manifest),
+        )
+        .unwrap();
+
+        let report = run(manifests.clone(), blobs.clone(), None).await;
+        assert_eq!(report.checked, 3);
+        assert!(report.corrupt.is_empty());
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].digest, missing);
+        assert_eq!(report.missing[0].referenced_by, "team/app:latest");
+
+        // Bit rot
+        let layer_path = blob_path(&blobs, &layer).unwrap();
+        fs::write(&layer_path, b"layer c0ntents").unwrap();
+        let report = run(manifests.clone(), blobs.clone(), None).await;
+        assert_eq!(report.corrupt, vec![layer.clone()]);
+        assert!(report.quarantined.is_empty());
+        assert!(layer_path.exists());
+
+        let report = run(manifests, blobs, Some(quarantine.clone())).await;
+        assert_eq!(report.quarantined, vec![layer.clone()]);
+        assert!(!layer_path.exists());
+        assert!(blob_path(&quarantine, &layer).unwrap().exists());
+        // Reported once, as corrupt rather than missing
+        assert_eq!(report.missing.len(), 1);
+    }
+}
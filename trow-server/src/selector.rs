@@ -0,0 +1,97 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+// Selects tags by repository and tag name, given as REPOS[:TAGS] e.g. "myorg/*:v*".
+//
+// Patterns can use * to match any run of characters, including /. Leaving out the tag pattern
+// selects every tag in the matching repositories.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TagSelector {
+    pub repos: String,
+    pub tags: Option<String>,
+}
+
+// Only supports *, which matches any run of characters
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == s,
+        Some((prefix, rest)) => {
+            if !s.starts_with(prefix) {
+                return false;
+            }
+            let s = &s[prefix.len()..];
+            (0..=s.len())
+                .filter(|i| s.is_char_boundary(*i))
+                .any(|i| glob_match(rest, &s[i..]))
+        }
+    }
+}
+
+impl FromStr for TagSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (repos, tags) = match s.split_once(':') {
+            Some((r, t)) => (r.trim(), Some(t.trim())),
+            None => (s.trim(), None),
+        };
+        if repos.is_empty() {
+            return Err(anyhow!("{} has no repository pattern", s));
+        }
+        if tags == Some("") {
+            return Err(anyhow!("{} has an empty tag pattern", s));
+        }
+        Ok(TagSelector {
+            repos: repos.to_string(),
+            tags: tags.map(str::to_string),
+        })
+    }
+}
+
+impl fmt::Display for TagSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.tags {
+            Some(tags) => write!(f, "{}:{}", self.repos, tags),
+            None => write!(f, "{}", self.repos),
+        }
+    }
+}
+
+impl TagSelector {
+    pub fn matches_repo(&self, repo_name: &str) -> bool {
+        glob_match(&self.repos, repo_name)
+    }
+
+    pub fn matches(&self, repo_name: &str, tag: &str) -> bool {
+        self.matches_repo(repo_name) && self.tags.as_ref().map_or(true, |t| glob_match(t, tag))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{glob_match, TagSelector};
+
+    #[test]
+    fn match_selectors() {
+        assert!(glob_match("myorg/*", "myorg/app/sub"));
+        assert!(glob_match("release-*-rc", "release-1.2-rc"));
+        assert!(!glob_match("release-*", "v1"));
+        assert!(glob_match("*", ""));
+
+        let sel: TagSelector = "myorg/*:v*".parse().unwrap();
+        assert!(sel.matches("myorg/app", "v1.2.3"));
+        assert!(!sel.matches("myorg/app", "latest"));
+        assert!(!sel.matches("other/app", "v1"));
+        assert_eq!(sel.to_string(), "myorg/*:v*");
+
+        let sel: TagSelector = "prod/app".parse().unwrap();
+        assert!(sel.matches("prod/app", "anything"));
+        assert!(!sel.matches("prod/app2", "anything"));
+
+        assert!(":v*".parse::<TagSelector>().is_err());
+        assert!("myorg:".parse::<TagSelector>().is_err());
+    }
+}
@@ -4,10 +4,11 @@ use std::collections::HashSet;
 use std::fmt;
 use std::fs::{self, DirEntry, File};
 use std::io;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Result};
 use async_recursion::async_recursion;
@@ -21,17 +22,49 @@ use reqwest::{
     header::{HeaderMap, HeaderValue},
 };
 use thiserror::Error;
-use tokio::io::AsyncWriteExt;
-use tokio::sync::mpsc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 use uuid::Uuid;
 
+use crate::backup::{self, BackupTarget};
+use crate::capacity::{Capacity, Reserve};
 use crate::digest::sha256_tag_digest;
-use crate::manifest::{manifest_media_type, FromJson, Manifest};
+use crate::egress::EgressProxies;
+use crate::estargz::Estargz;
+use crate::events::{Event, EventAction, EventPublisher};
+use crate::freeze::{self, AdmittedImages, FreezeWindow};
+use crate::helm;
+use crate::index_summary;
+use crate::jobs::{Job, JobKind, JobState, Jobs};
+use crate::links;
+use crate::maintenance;
+use crate::manifest::{manifest_media_type, FromJson, InvalidManifest, Manifest};
+use crate::metadata::MetadataStore;
 use crate::metrics;
+use crate::mirror::{MirrorQueue, Priority};
+use crate::names::{is_valid_digest, is_valid_repo_name, is_valid_tag, is_valid_upload_id};
+use crate::oci_layout::{self, LayoutError};
+use crate::policy::{Policy, SharedPolicy};
+use crate::proxy_check::{self, CheckReport};
+use crate::quota::{self, Quota};
+use crate::referrers;
+use crate::retention::{self, RetentionRule};
+use crate::scrub::{self, ScrubReport};
+use crate::selector::TagSelector;
 use crate::server::trow_server::registry_server::Registry;
+use crate::storage::Storage;
+use crate::storage_usage::{self, StorageUsage};
 use crate::temporary_file::TemporaryFile;
+use crate::tenants::{self, TenantInUse, Tenants};
+use crate::transcode::{Compression, Transcoder};
+use crate::trash::{self, TagPushedSince, Trash};
+use crate::trow_policy;
+use crate::uploads::{self, InvalidRange, Session};
+use crate::usage;
+use crate::watcher::{self, RepoIndex};
+use crate::write_locks::WriteLocks;
 
 use self::trow_server::*;
 
@@ -43,18 +76,53 @@ static SUPPORTED_DIGESTS: [&str; 1] = ["sha256"];
 static MANIFESTS_DIR: &str = "manifests";
 static BLOBS_DIR: &str = "blobs";
 static UPLOADS_DIR: &str = "scratch";
+static LINKS_DIR: &str = "links";
+static TRANSCODED_DIR: &str = "transcoded";
+static ESTARGZ_DIR: &str = "estargz";
 
 static PROXY_DIR: &str = "f/"; //Repositories starting with this are considered proxies
 static HUB_PROXY_DIR: &str = "docker/"; //Repositories starting with this are considered proxies
 static HUB_ADDRESS: &str = "https://registry-1.docker.io/v2";
 static DIGEST_HEADER: &str = "Docker-Content-Digest";
+// Forgotten when there are this many, rather than tracking which are least used
+const MAX_MEDIA_TYPES: usize = 10_000;
+// Size of the messages image exports are sent in
+const ARCHIVE_CHUNK_SIZE: usize = 64 * 1024;
+static DEFAULT_READ_ONLY_REASON: &str = "Registry is read-only for maintenance";
 
 /* Struct implementing callbacks for the Frontend
  *
- * _active_uploads_: a HashSet of all uuids that are currently being tracked
+ * _active_uploads_: a HashSet of all uuids that are currently being tracked, also saved to disk
+ *   so they survive restarts, see uploads.rs
+ * _data_path_: the data dir, for files that aren't repository content
  * _manifests_path_: path to where the manifests are
  * _layers_path_: path to where blobs are stored
- * _scratch_path_: path to temporary storage for uploads
+ * _scratch_path_: path to temporary storage in the data dir, for files moved into it
+ * _uploads_path_: where uploads in progress are written, the scratch dir unless set elsewhere
+ *   with --scratch-dir
+ * _links_path_: path to the per-repository links to blobs, see links.rs
+ * _repo_index_: index of repos and tags, only present when watching the data dir
+ * _metadata_: database of tags and manifests, used instead of reading the data dir if present
+ * _jobs_: long running background jobs such as garbage collection
+ * _events_: publishes pushes and deletes to external systems, and every event to watchers
+ * _http_client_: for calls to proxied registries, using the egress proxies
+ * _policy_: the allow and deny lists for admission, quotas, immutable tags and change freezes,
+ *   which can be replaced while running, see policy.rs, and the rules from TrowPolicy resources
+ * _retention_: rules for automatically deleting old tags and manifests
+ * _tenants_: the tenants owning top-level namespaces, with their quotas and retention rules
+ * _proxy_check_sample_: how many proxied tags each proxy-check job compares with upstream
+ * _max_layers_: most layers a pushed image can have, 0 for no limit
+ * _backup_: where backup jobs copy the registry to, if anywhere
+ * _quarantine_path_: where scrub jobs move corrupt blobs to, if they do
+ * _scrub_report_: the result of the last scrub job, see scrub.rs
+ * _trash_: where deleted manifests are kept until the restore window passes, if they are
+ * _storage_: the bucket changes to the data dir are written through to, if the registry is kept
+ *   in one, see storage.rs
+ * _mirror_: Docker Hub images from admitted pods waiting to be fetched into the proxy cache
+ * _tags_lock_: held while changing tags, so listings see them all before or after the change.
+ *   Released before the changes are written to storage, so listings don't wait on the network
+ * _write_locks_: serializes pushes to the same tag, and storing uploads of the same blob
+ * _read_only_: why writes are refused, if they are, e.g. while a backup or migration runs
  *
  * Each "route" gets a clone of this struct.
  * The Arc makes sure they all point to the same data.
@@ -62,16 +130,40 @@ static DIGEST_HEADER: &str = "Docker-Content-Digest";
 #[derive(Clone)]
 pub struct TrowServer {
     active_uploads: Arc<RwLock<HashSet<Upload>>>,
+    data_path: PathBuf,
     manifests_path: PathBuf,
     blobs_path: PathBuf,
     scratch_path: PathBuf,
+    uploads_path: PathBuf,
+    links_path: PathBuf,
     proxy_hub: bool,
     hub_user: Option<String>,
     hub_pass: Option<String>,
-    allow_prefixes: Vec<String>,
-    allow_images: Vec<String>,
-    deny_local_prefixes: Vec<String>,
-    deny_local_images: Vec<String>,
+    repo_index: Option<Arc<RepoIndex>>,
+    metadata: Option<Arc<MetadataStore>>,
+    jobs: Jobs,
+    events: EventPublisher,
+    http_client: reqwest::Client,
+    policy: SharedPolicy,
+    retention: Vec<RetentionRule>,
+    tenants: Tenants,
+    proxy_check_sample: usize,
+    max_layers: usize,
+    backup: Option<Arc<dyn BackupTarget>>,
+    quarantine_path: Option<PathBuf>,
+    scrub_report: Arc<RwLock<Option<ScrubReport>>>,
+    trash: Option<Trash>,
+    storage: Option<Storage>,
+    mirror: Option<MirrorQueue>,
+    transcoder: Option<Transcoder>,
+    estargz: Option<Estargz>,
+    // Refuses uploads while storage is below a reserve, if set
+    capacity: Option<Arc<Capacity>>,
+    tags_lock: Arc<RwLock<()>>,
+    write_locks: WriteLocks,
+    read_only: Arc<RwLock<Option<String>>>,
+    // Media type of each manifest read, by digest, so HEAD requests needn't read it again
+    media_types: Arc<RwLock<HashMap<String, String>>>,
 }
 
 #[derive(Eq, PartialEq, Hash, Debug, Clone)]
@@ -93,6 +185,12 @@ pub struct DigestValidationError {
     actual_digest: String,
 }
 
+#[derive(Error, Debug)]
+#[error("Failed to find artifact with digest {digest}")]
+pub struct MissingBlobError {
+    digest: String,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Image {
     pub host: String, //Including port, docker.io by default
@@ -119,11 +217,12 @@ pub struct Auth {
 }
 
 fn create_accept_header() -> HeaderMap {
-    const ACCEPT: [&str; 4] = [
+    const ACCEPT: [&str; 5] = [
         manifest_media_type::OCI_V1,
         manifest_media_type::DOCKER_V2,
         manifest_media_type::DOCKER_LIST,
         manifest_media_type::OCI_INDEX,
+        manifest_media_type::OCI_ARTIFACT,
     ];
 
     let mut headers = HeaderMap::new();
@@ -237,6 +336,195 @@ fn is_digest(maybe_digest: &str) -> bool {
     false
 }
 
+/*
+ * What a manifest is asked for by, checked to be a valid tag or digest as it comes in, so a tag
+ * is never taken for a digest or the other way round.
+ */
+enum Reference {
+    // Can be moved to another manifest, unless it's immutable
+    Tag(String),
+    // Always refers to the same manifest
+    Digest(String),
+}
+
+impl Reference {
+    fn as_str(&self) -> &str {
+        match self {
+            Reference::Tag(s) | Reference::Digest(s) => s,
+        }
+    }
+
+    fn tag(&self) -> Option<&str> {
+        match self {
+            Reference::Tag(tag) => Some(tag),
+            Reference::Digest(_) => None,
+        }
+    }
+}
+
+/*
+ * Every name in a request is checked before it goes into a path, see names.rs. Manifest
+ * references are checked along with their repository by manifest_reference.
+ */
+fn check_repo_name(repo_name: &str) -> Result<(), Status> {
+    if is_valid_repo_name(repo_name) {
+        Ok(())
+    } else {
+        Err(Status::invalid_argument(format!(
+            "Invalid repository name {}",
+            repo_name
+        )))
+    }
+}
+
+fn check_digest(digest: &str) -> Result<(), Status> {
+    if is_valid_digest(digest) {
+        Ok(())
+    } else {
+        Err(Status::invalid_argument(format!(
+            "Invalid digest {}",
+            digest
+        )))
+    }
+}
+
+fn check_upload_id(uuid: &str) -> Result<(), Status> {
+    if is_valid_upload_id(uuid) {
+        Ok(())
+    } else {
+        Err(Status::invalid_argument(format!("Invalid upload {}", uuid)))
+    }
+}
+
+fn manifest_reference(mr: &ManifestRef) -> Result<Reference, Status> {
+    check_repo_name(&mr.repo_name)?;
+    match &mr.reference {
+        Some(manifest_ref::Reference::Tag(tag)) if is_valid_tag(tag) => {
+            Ok(Reference::Tag(tag.clone()))
+        }
+        Some(manifest_ref::Reference::Digest(digest)) if is_valid_digest(digest) => {
+            Ok(Reference::Digest(digest.clone()))
+        }
+        Some(manifest_ref::Reference::Tag(r)) | Some(manifest_ref::Reference::Digest(r)) => Err(
+            Status::invalid_argument(format!("Invalid manifest reference {}", r)),
+        ),
+        None => Err(Status::invalid_argument("Missing manifest reference")),
+    }
+}
+
+// Sends the file in chunks, stopping early if the client has gone
+async fn send_archive(
+    path: &Path,
+    tx: &mpsc::Sender<Result<ImageArchiveChunk, Status>>,
+) -> io::Result<()> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0; ARCHIVE_CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        let chunk = ImageArchiveChunk {
+            data: buf[..n].to_vec(),
+        };
+        if tx.send(Ok(chunk)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// Writes the data from the first chunk and the rest of the stream to the file
+async fn receive_archive(
+    first: Vec<u8>,
+    stream: &mut tonic::Streaming<ImportChunk>,
+    path: &Path,
+) -> Result<(), Status> {
+    let io_error = |e: io::Error| {
+        error!("Failed to write imported archive {:?}", e);
+        Status::internal("Internal error importing image")
+    };
+    let mut file = tokio::fs::File::create(path).await.map_err(io_error)?;
+    file.write_all(&first).await.map_err(io_error)?;
+    while let Some(chunk) = stream.message().await? {
+        file.write_all(&chunk.data).await.map_err(io_error)?;
+    }
+    file.flush().await.map_err(io_error)?;
+    Ok(())
+}
+
+fn repo_usage(usage: storage_usage::RepoUsage) -> RepoUsage {
+    RepoUsage {
+        repo_name: usage.repo_name,
+        tags: usage.tags,
+        blobs: usage.blobs,
+        unique_bytes: usage.unique_bytes,
+        shared_bytes: usage.shared_bytes,
+    }
+}
+
+fn to_timestamp(dt: &DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: dt.timestamp(),
+        nanos: dt.timestamp_subsec_nanos() as i32,
+    }
+}
+
+fn job_status(job: Job) -> JobStatus {
+    JobStatus {
+        id: job.id,
+        kind: job.kind.to_string(),
+        state: job.state.to_string(),
+        progress: job.progress,
+        message: job.message,
+        created: Some(to_timestamp(&job.created)),
+        finished: job.finished.as_ref().map(to_timestamp),
+    }
+}
+
+fn tenant_to_proto(t: tenants::Tenant) -> Tenant {
+    Tenant {
+        name: t.name,
+        members: t
+            .members
+            .into_iter()
+            .map(|m| TenantMember {
+                user: m.user,
+                permission: m.permission,
+            })
+            .collect(),
+        quota: t.quota.unwrap_or_default(),
+        retention: t.retention,
+    }
+}
+
+fn trash_entry_to_proto(t: &Trash, e: trash::TrashEntry) -> trow_server::TrashEntry {
+    trow_server::TrashEntry {
+        expires: Some(to_timestamp(&t.expires(&e))),
+        deleted: Some(to_timestamp(&e.deleted)),
+        id: e.id,
+        repo_name: e.repo_name,
+        digest: e.digest,
+        tags: e.tags,
+    }
+}
+
+// Removes what garbage collection did from the bucket too, if the registry is kept in one
+fn sync_collected(
+    storage: Option<&Storage>,
+    blobs_path: &Path,
+    links_path: &Path,
+    trash: Option<&Trash>,
+) -> Result<()> {
+    if let Some(s) = storage {
+        s.sync_dir(blobs_path)?;
+        s.sync_dir(links_path)?;
+        if let Some(t) = trash {
+            s.sync_dir(t.path())?;
+        }
+    }
+    Ok(())
+}
+
 fn is_path_writable(path: &PathBuf) -> io::Result<bool> {
     let file = File::open(path)?;
     let metadata = file.metadata()?;
@@ -244,7 +532,7 @@ fn is_path_writable(path: &PathBuf) -> io::Result<bool> {
     Ok(!permissions.readonly())
 }
 
-fn get_digest_from_manifest_path<P: AsRef<Path>>(path: P) -> Result<String> {
+pub(crate) fn get_digest_from_manifest_path<P: AsRef<Path>>(path: P) -> Result<String> {
     let digest_date = fs::read_to_string(path)?;
     //Should be digest followed by date, but allow for digest only
     Ok(digest_date
@@ -254,6 +542,40 @@ fn get_digest_from_manifest_path<P: AsRef<Path>>(path: P) -> Result<String> {
         .to_string())
 }
 
+/*
+ * Checks a push wouldn't take the namespace over quota, given its current usage. See
+ * TrowServer::check_quota.
+ */
+fn check_usage(
+    q: &Quota,
+    usage: &quota::Usage,
+    blobs: &[(String, u64)],
+    new_tag: bool,
+) -> Result<(), Status> {
+    if let Some(max_bytes) = q.max_bytes {
+        let added: u64 = blobs
+            .iter()
+            .filter(|(digest, _)| !usage.digests.contains(digest))
+            .map(|(_, size)| size)
+            .sum();
+        if usage.bytes + added > max_bytes {
+            return Err(Status::resource_exhausted(format!(
+                "Quota exceeded for {}: push needs {} bytes, {} of {} bytes already used",
+                q.name, added, usage.bytes, max_bytes
+            )));
+        }
+    }
+    if let Some(max_images) = q.max_images {
+        if new_tag && usage.images >= max_images {
+            return Err(Status::resource_exhausted(format!(
+                "Quota exceeded for {}: {} of {} images already used",
+                q.name, usage.images, max_images
+            )));
+        }
+    }
+    Ok(())
+}
+
 impl TrowServer {
     pub fn new(
         data_path: &str,
@@ -268,1032 +590,4095 @@ impl TrowServer {
         let manifests_path = create_path(data_path, MANIFESTS_DIR)?;
         let scratch_path = create_path(data_path, UPLOADS_DIR)?;
         let blobs_path = create_path(data_path, BLOBS_DIR)?;
+        let links_path = create_path(data_path, LINKS_DIR)?;
+        let tenants = Tenants::load(Path::new(data_path))?;
         let svc = TrowServer {
-            active_uploads: Arc::new(RwLock::new(HashSet::new())),
+            active_uploads: Arc::new(RwLock::new(
+                uploads::restore_all(&scratch_path)
+                    .into_values()
+                    .map(|s| Upload {
+                        repo_name: s.repo_name,
+                        uuid: s.uuid,
+                    })
+                    .collect(),
+            )),
+            data_path: PathBuf::from(data_path),
             manifests_path,
             blobs_path,
+            uploads_path: scratch_path.clone(),
             scratch_path,
+            links_path,
             proxy_hub,
             hub_user,
             hub_pass,
-            allow_prefixes,
-            allow_images,
-            deny_local_prefixes,
-            deny_local_images,
+            repo_index: None,
+            metadata: None,
+            jobs: Jobs::new(),
+            events: EventPublisher::default(),
+            http_client: reqwest::Client::new(),
+            policy: SharedPolicy::new(Policy {
+                allow_prefixes,
+                allow_images,
+                deny_local_prefixes,
+                deny_local_images,
+                ..Policy::default()
+            }),
+            retention: vec![],
+            tenants,
+            proxy_check_sample: 20,
+            max_layers: 0,
+            backup: None,
+            quarantine_path: None,
+            scrub_report: Arc::new(RwLock::new(None)),
+            trash: None,
+            storage: None,
+            mirror: None,
+            transcoder: None,
+            estargz: None,
+            capacity: None,
+            tags_lock: Arc::new(RwLock::new(())),
+            write_locks: WriteLocks::default(),
+            read_only: Arc::new(RwLock::new(None)),
+            media_types: Arc::new(RwLock::new(HashMap::new())),
         };
         Ok(svc)
     }
 
-    fn get_upload_path_for_blob(&self, uuid: &str) -> PathBuf {
-        self.scratch_path.join(uuid)
+    pub fn with_events(mut self, events: EventPublisher) -> Self {
+        self.events = events;
+        self
     }
 
-    fn get_catalog_path_for_blob(&self, digest: &str) -> Result<PathBuf> {
-        let mut iter = digest.split(':');
-        let alg = iter
-            .next()
-            .ok_or_else(|| anyhow!("Digest {} did not contain alg component", digest))?;
-        if !SUPPORTED_DIGESTS.contains(&alg) {
-            return Err(anyhow!("Hash algorithm {} not supported", alg));
-        }
-        let val = iter
-            .next()
-            .ok_or_else(|| anyhow!("Digest {} did not contain value component", digest))?;
-        assert_eq!(None, iter.next());
-        Ok(self.blobs_path.join(alg).join(val))
+    pub fn with_egress(mut self, egress: &EgressProxies) -> Result<Self> {
+        self.http_client = reqwest::Client::builder().proxy(egress.proxy()).build()?;
+        Ok(self)
     }
 
-    // Given a manifest digest, check if it is referenced by any tag in the repo
-    fn verify_manifest_digest_in_repo(&self, repo_name: &str, digest: &str) -> Result<bool> {
-        let mut ri = RepoIterator::new(&self.manifests_path.join(repo_name))?;
-        let res = ri.find(|de| does_manifest_match_digest(de, &digest));
-        Ok(res.is_some())
+    pub fn with_quotas(self, quotas: Vec<Quota>) -> Self {
+        self.policy.edit(|p| p.quotas = quotas);
+        self
     }
 
-    fn get_digest_from_manifest(&self, repo_name: &str, tag: &str) -> Result<String> {
-        get_digest_from_manifest_path(self.manifests_path.join(repo_name).join(tag))
+    pub fn with_retention(mut self, rules: Vec<RetentionRule>) -> Self {
+        self.retention = rules;
+        self
     }
 
-    async fn save_tag(&self, digest: &str, repo_name: &str, tag: &str) -> Result<()> {
-        // Tag files should contain list of digests with timestamp
-        // First line should always be the current digest
+    pub fn with_proxy_check_sample(mut self, sample_size: usize) -> Self {
+        self.proxy_check_sample = sample_size;
+        self
+    }
 
-        let repo_dir = self.manifests_path.join(repo_name);
-        let repo_path = repo_dir.join(tag);
-        fs::create_dir_all(&repo_dir)?;
+    pub fn with_max_layers(mut self, max_layers: usize) -> Self {
+        self.max_layers = max_layers;
+        self
+    }
 
-        let ts = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
-        let contents = format!("{} {}\n", digest, ts).into_bytes();
+    // Start refusing writes, until switched off with SetReadOnly
+    pub fn with_read_only(self) -> Self {
+        *self.read_only.write().unwrap() = Some(DEFAULT_READ_ONLY_REASON.to_string());
+        self
+    }
 
-        let mut file = tokio::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&repo_path)
-            .await?;
-        file.write_all(&contents).await?;
-        Ok(())
+    pub fn with_backup(mut self, target: Arc<dyn BackupTarget>) -> Self {
+        self.backup = Some(target);
+        self
     }
 
-    fn get_path_for_manifest(&self, repo_name: &str, reference: &str) -> Result<PathBuf> {
-        let digest = if is_digest(reference) {
-            if !self.verify_manifest_digest_in_repo(repo_name, reference)? {
-                error!("Digest {} not in repository {}", reference, repo_name);
-                return Err(anyhow!(
-                    "Digest {} not in repository {}",
-                    reference,
-                    repo_name
-                ));
-            }
-            reference.to_string()
-        } else {
-            //Content of tag is the digest
-            self.get_digest_from_manifest(repo_name, reference)?
-        };
+    // Move corrupt blobs found by scrub jobs to the quarantine dir, rather than leave them in place
+    pub fn with_scrub_quarantine(mut self) -> Self {
+        self.quarantine_path = Some(self.data_path.join("quarantine"));
+        self
+    }
 
-        self.get_catalog_path_for_blob(&digest)
+    /*
+     * Write uploads in progress to the directory instead of the data dir's scratch dir, e.g. on
+     * faster local storage. Uploads are moved or copied into the data dir once they complete.
+     */
+    pub fn with_uploads_dir(mut self, path: &str) -> Result<Self> {
+        let uploads_path = PathBuf::from(path);
+        fs::create_dir_all(&uploads_path)?;
+        let restored = uploads::restore_all(&uploads_path);
+        *self.active_uploads.write().unwrap() = restored
+            .into_values()
+            .map(|s| Upload {
+                repo_name: s.repo_name,
+                uuid: s.uuid,
+            })
+            .collect();
+        self.uploads_path = uploads_path;
+        Ok(self)
     }
 
-    fn create_verified_manifest(
-        &self,
-        manifest_path: &PathBuf,
-        verify_assets_exist: bool,
-    ) -> Result<VerifiedManifest> {
-        let manifest_bytes = std::fs::read(&manifest_path)?;
-        let manifest_json: serde_json::Value = serde_json::from_slice(&manifest_bytes)?;
-        let manifest = Manifest::from_json(&manifest_json)?;
+    /*
+     * Refuse new uploads while the data dir or the uploads dir has less than the reserve
+     * available (see capacity.rs). Set after with_uploads_dir.
+     */
+    pub fn with_capacity_reserve(mut self, reserve: Reserve) -> Self {
+        let capacity = Capacity::new(
+            reserve,
+            vec![
+                ("data", self.data_path.clone()),
+                ("uploads", self.uploads_path.clone()),
+            ],
+        );
+        capacity.check();
+        self.capacity = Some(Arc::new(capacity));
+        self
+    }
 
-        if verify_assets_exist {
-            for digest in manifest.get_local_asset_digests() {
-                let path = self.get_catalog_path_for_blob(digest)?;
+    // Write changes through to the bucket, which the data dir has been brought up to date from
+    pub fn with_storage(mut self, storage: Storage) -> Self {
+        info!("Keeping the registry in {}", storage.describe());
+        self.storage = Some(storage);
+        self
+    }
 
-                if !path.exists() {
-                    return Err(anyhow!("Failed to find artifact with digest {}", digest));
-                }
+    // Keep deleted manifests in the trash for the window, so they can be restored (see trash.rs)
+    pub fn with_trash(mut self, window: Duration) -> Result<Self> {
+        self.trash = Some(Trash::new(&self.data_path, window)?);
+        Ok(self)
+    }
+
+    /*
+     * Mirror Docker Hub images into the proxy cache when pods using them are admitted, with the
+     * given number of concurrent fetches (see mirror.rs).
+     */
+    pub fn with_admission_mirroring(mut self, workers: usize, max_queued: usize) -> Self {
+        let queue = MirrorQueue::new(max_queued);
+        let ts = self.clone();
+        queue.start(workers, move |repo_name, tag| {
+            let ts = ts.clone();
+            async move {
+                ts.create_manifest_read_location(repo_name, tag, false)
+                    .await
+                    .map(|_| ())
             }
-        }
+        });
+        self.mirror = Some(queue);
+        self
+    }
 
-        // Calculate the digest: sha256:...
-        let reader = BufReader::new(manifest_bytes.as_slice());
-        let digest = sha256_tag_digest(reader)?;
+    /*
+     * Recompress layers read at least min_pulls times, or as soon as they're asked for with
+     * on_demand, so clients can pull them gzip or zstd compressed (see transcode.rs).
+     */
+    pub fn with_transcoding(mut self, min_pulls: u64, on_demand: bool) -> Result<Self> {
+        self.transcoder = Some(Transcoder::new(
+            &self.data_path.join(TRANSCODED_DIR),
+            &self.blobs_path,
+            min_pulls,
+            on_demand,
+        )?);
+        Ok(self)
+    }
 
-        // For performance, could generate only if verification is on, otherwise copy from somewhere
-        Ok(VerifiedManifest {
-            digest,
-            content_type: manifest.get_media_type(),
-        })
+    /*
+     * Convert pushed layers to eStargz, so clients preferring it can pull lazily (see estargz.rs).
+     */
+    pub fn with_estargz(mut self) -> Result<Self> {
+        self.estargz = Some(Estargz::new(
+            &self.data_path.join(ESTARGZ_DIR),
+            &self.blobs_path,
+        )?);
+        Ok(self)
     }
 
-    /**
-    If repo is proxied to another registry, this will return the details of the remote image.
-    If the repo isn't proxied None is returned
-    **/
-    fn get_proxy_address_and_auth(
-        &self,
-        repo_name: &str,
-        reference: &str,
-    ) -> Option<(Image, Option<Auth>)> {
-        //All proxies are under "f_"
-        if repo_name.starts_with(PROXY_DIR) {
-            let proxy_name = repo_name.strip_prefix(PROXY_DIR).unwrap();
+    pub fn with_immutable_tags(self, immutable_tags: Vec<TagSelector>) -> Self {
+        self.policy.edit(|p| p.immutable_tags = immutable_tags);
+        self
+    }
 
-            if self.proxy_hub && proxy_name.starts_with(HUB_PROXY_DIR) {
-                let mut repo = proxy_name.strip_prefix(HUB_PROXY_DIR).unwrap().to_string();
+    // Only admit images from this registry that have been pushed to it
+    pub fn with_existing_images_required(self) -> Self {
+        self.policy.edit(|p| p.require_existing_images = true);
+        self
+    }
 
-                //Official images have to use the library/ repository
-                if !repo.contains('/') {
-                    repo = format!("library/{}", repo).to_string();
-                }
+    /*
+     * Only admit images already running in a namespace during the freeze windows.
+     *
+     * Loads the images admitted so far from the data dir.
+     */
+    pub fn with_freeze_windows(self, windows: Vec<FreezeWindow>, data_path: &Path) -> Result<Self> {
+        let admitted = AdmittedImages::load(data_path)?;
+        self.policy.edit(|p| {
+            p.admitted = Some(admitted);
+            p.freeze_windows = windows;
+        });
+        Ok(self)
+    }
 
-                return Some((
-                    Image {
-                        host: HUB_ADDRESS.to_string(),
-                        repo,
-                        tag: reference.to_string(),
-                    },
-                    Some(Auth {
-                        user: self.hub_user.clone(),
-                        pass: self.hub_pass.clone(),
-                    }),
-                ));
+    /*
+     * Keep tag and manifest metadata in the SQLite database at db_path, creating it if needed.
+     *
+     * The database is synced with the data dir first, so it's fine to delete it or to start
+     * using one on an existing registry.
+     */
+    pub fn with_metadata(mut self, db_path: &Path) -> Result<Self> {
+        let store = MetadataStore::open(db_path)?;
+        store.sync(&self.manifests_path, &self.blobs_path)?;
+        self.metadata = Some(Arc::new(store));
+        Ok(self)
+    }
+
+    /*
+     * Apply the retention rules every interval, followed by garbage collection if anything was
+     * deleted. The first run is after one interval, not at startup.
+     */
+    pub fn schedule_retention(self, interval: Duration) -> Self {
+        let ts = self.clone();
+        tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut ticker = tokio::time::interval_at(start, interval);
+            loop {
+                ticker.tick().await;
+                let running = ts
+                    .jobs
+                    .list()
+                    .iter()
+                    .any(|j| j.kind == JobKind::Retention && j.state == JobState::Running);
+                if running {
+                    warn!("Previous retention job still running, skipping this run");
+                } else if ts.check_writable().is_err() {
+                    warn!("Registry is read-only, skipping this retention run");
+                } else {
+                    ts.start_retention_job(true);
+                }
             }
-        }
+        });
+        self
+    }
 
-        None
+    /*
+     * Record which tags are running in the cluster every interval, starting now so the usage
+     * report has something in it.
+     */
+    pub fn schedule_usage(self, interval: Duration) -> Self {
+        let ts = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let running = ts
+                    .jobs
+                    .list()
+                    .iter()
+                    .any(|j| j.kind == JobKind::Usage && j.state == JobState::Running);
+                if running {
+                    warn!("Previous usage job still running, skipping this run");
+                } else {
+                    ts.start_usage_job();
+                }
+            }
+        });
+        self
     }
 
-    /// Download a blob that is part of `remote_image`.
-    async fn download_blob<T: Display>(
-        &self,
-        cl: &reqwest::Client,
-        token: &Option<T>,
-        remote_image: &Image,
-        digest: &str,
-    ) -> Result<()> {
-        if self.get_catalog_path_for_blob(digest)?.exists() {
-            info!("Already have blob {}", digest);
-            return Ok(());
-        }
-        let path = self.scratch_path.join(digest);
-        let mut file = match TemporaryFile::open_for_writing(path).await? {
-            Some(f) => f,
-            None => {
-                info!("Skip concurrently fetched blob {}", digest);
-                return Ok(());
+    /*
+     * Compare a sample of proxied tags with upstream every interval. The first run is after one
+     * interval, as nothing has been cached yet at startup.
+     */
+    pub fn schedule_proxy_check(self, interval: Duration) -> Self {
+        let ts = self.clone();
+        tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut ticker = tokio::time::interval_at(start, interval);
+            loop {
+                ticker.tick().await;
+                let running = ts
+                    .jobs
+                    .list()
+                    .iter()
+                    .any(|j| j.kind == JobKind::ProxyCheck && j.state == JobState::Running);
+                if running {
+                    warn!("Previous proxy check job still running, skipping this run");
+                } else {
+                    ts.start_proxy_check_job();
+                }
             }
-        };
+        });
+        self
+    }
 
-        let addr = format!(
-            "{}/{}/blobs/{}",
-            remote_image.host, remote_image.repo, digest
-        );
-        info!("Downloading blob {}", addr);
+    fn start_proxy_check_job(&self) -> Job {
+        let ts = self.clone();
+        // Jobs run on the blocking pool, so the upstream requests are run on the main runtime
+        let rt = tokio::runtime::Handle::current();
+        self.jobs.start(JobKind::ProxyCheck, move |h| {
+            if !ts.proxy_hub {
+                return Err(anyhow!("No registries are being proxied"));
+            }
+            let tags =
+                proxy_check::sample_tags(&ts.manifests_path, PROXY_DIR, ts.proxy_check_sample)?;
+            let mut report = CheckReport::default();
+            for (i, tag) in tags.iter().enumerate() {
+                if h.is_cancelled() {
+                    break;
+                }
+                let upstream = rt.block_on(ts.get_upstream_digest(&tag.repo_name, &tag.tag));
+                report.add(tag.clone(), upstream);
+                h.set_progress(i + 1, tags.len());
+            }
+            report.record_metrics();
+            Ok(report.summary())
+        })
+    }
 
-        let resp = if let Some(auth) = token {
-            cl.get(&addr).bearer_auth(auth).send().await?
-        } else {
-            cl.get(&addr).send().await?
-        };
-        file.write_all(&resp.bytes().await?).await?;
-        self.save_blob(file.path(), digest)?;
-        Ok(())
+    /*
+     * Makes sure every blob of the image is in the registry, then publishes a prewarm event if
+     * notify is set, so node agents can pull it ahead of a rollout.
+     */
+    fn start_prewarm_job(&self, repo_name: String, reference: String, notify: bool) -> Job {
+        let ts = self.clone();
+        // Jobs run on the blocking pool, so the upstream requests are run on the main runtime
+        let rt = tokio::runtime::Handle::current();
+        self.jobs.start(JobKind::Prewarm, move |h| {
+            let (digest, blobs) = rt.block_on(ts.prewarm(&repo_name, &reference))?;
+            h.set_progress(1, 1);
+            let image = format!("{}@{}", repo_name, digest);
+            if !notify {
+                return Ok(format!("{} has all {} blobs", image, blobs));
+            }
+            let tag = Some(reference.as_str()).filter(|r| !is_digest(r));
+            ts.events
+                .publish(Event::new(EventAction::Prewarm, &repo_name, tag, &digest));
+            Ok(format!(
+                "{} has all {} blobs, notified node agents",
+                image, blobs
+            ))
+        })
     }
 
-    #[async_recursion]
-    async fn download_manifest_and_layers<T: Display + Sync>(
-        &self,
-        cl: &reqwest::Client,
-        token: &Option<T>,
-        remote_image: &Image,
-        local_repo_name: &str,
-    ) -> Result<()> {
-        debug!("Downloading manifest + layers for {}", remote_image);
-        let mut req = cl.get(&remote_image.get_manifest_url());
-        if let Some(auth) = token {
-            req = req.bearer_auth(auth);
+    /*
+     * Fetches a proxied image if it isn't cached or upstream has a newer one, as a pull would,
+     * then fetches again any blobs missing from the cached copy. Returns the digest of the
+     * manifest and how many blobs the image has, or fails if any are still missing.
+     */
+    async fn prewarm(&self, repo_name: &str, reference: &str) -> Result<(String, usize)> {
+        let location = self
+            .create_manifest_read_location(repo_name.to_string(), reference.to_string(), false)
+            .await?;
+        let digest = location.digest;
+        let mut missing = self.missing_blobs(&digest);
+        if !missing.is_empty() {
+            if let Some((image, auth)) = self.get_proxy_address_and_auth(repo_name, reference) {
+                info!(
+                    "Fetching {} missing blobs of {} from {}",
+                    missing.len(),
+                    repo_name,
+                    image
+                );
+                let cl = self.http_client.clone();
+                let token = self.get_auth_token(&cl, &image, &auth).await.ok();
+                self.download_manifest_and_layers(&cl, &token, &image, repo_name)
+                    .await?;
+                missing = self.missing_blobs(&digest);
+            }
         }
-
-        let resp = req.headers(create_accept_header()).send().await?;
-
-        if !resp.status().is_success() {
+        if !missing.is_empty() {
+            missing.sort();
             return Err(anyhow!(
-                "GET {} returned unexpected {}",
-                &remote_image.get_manifest_url(),
-                resp.status()
+                "{}@{} is missing {} blobs: {}",
+                repo_name,
+                digest,
+                missing.len(),
+                missing.join(", ")
             ));
         }
+        let blobs = maintenance::follow_manifests(vec![digest.clone()], &self.blobs_path).len();
+        Ok((digest, blobs))
+    }
 
-        let mut buf =
-            TemporaryFile::open_for_writing(self.scratch_path.join(Uuid::new_v4().to_string()))
-                .await?
-                .unwrap();
-        let bytes = resp.bytes().await?;
-        buf.write_all(&bytes).await?;
+    // The blobs the manifest refers to, including through an index, that aren't in the registry
+    fn missing_blobs(&self, digest: &str) -> Vec<String> {
+        maintenance::follow_manifests(vec![digest.to_string()], &self.blobs_path)
+            .into_iter()
+            .filter(|d| {
+                !self
+                    .get_catalog_path_for_blob(d)
+                    .map_or(false, |p| p.exists())
+            })
+            .collect()
+    }
 
-        let mani: Manifest = serde_json::from_slice(&bytes)?;
-        match mani {
-            Manifest::List(_) => {
-                let images_to_dl = mani
-                    .get_local_asset_digests()
-                    .into_iter()
-                    .map(|digest| {
-                        let mut image = remote_image.clone();
-                        image.tag = digest.to_string();
-                        image
-                    })
-                    .collect::<Vec<_>>();
-                let futures = images_to_dl
+    /*
+     * Transcode frequently pulled layers every interval. The first run is after one interval, as
+     * nothing has been pulled yet at startup.
+     */
+    pub fn schedule_transcoding(self, interval: Duration) -> Self {
+        let ts = self.clone();
+        tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut ticker = tokio::time::interval_at(start, interval);
+            loop {
+                ticker.tick().await;
+                let running = ts
+                    .jobs
+                    .list()
                     .iter()
-                    .map(|img| self.download_manifest_and_layers(cl, token, &img, local_repo_name));
-                try_join_all(futures).await?;
+                    .any(|j| j.kind == JobKind::Transcode && j.state == JobState::Running);
+                if running {
+                    warn!("Previous transcode job still running, skipping this run");
+                } else {
+                    ts.start_transcode_job();
+                }
             }
-            Manifest::V2(_) => {
-                let futures = mani
-                    .get_local_asset_digests()
-                    .into_iter()
-                    .map(|digest| self.download_blob(cl, token, remote_image, &digest));
-                try_join_all(futures).await?;
+        });
+        self
+    }
+
+    fn start_transcode_job(&self) -> Job {
+        let transcoder = self.transcoder.clone();
+        self.jobs.start(JobKind::Transcode, move |h| {
+            transcoder
+                .ok_or_else(|| anyhow!("Layer transcoding isn't enabled"))?
+                .run_job(h)
+        })
+    }
+
+    /// Read the space available again every interval, if there's a reserve
+    pub fn schedule_capacity_check(self, interval: Duration) -> Self {
+        if let Some(capacity) = self.capacity.clone() {
+            tokio::spawn(async move {
+                let start = tokio::time::Instant::now() + interval;
+                let mut ticker = tokio::time::interval_at(start, interval);
+                loop {
+                    ticker.tick().await;
+                    capacity.check();
+                }
+            });
+        }
+        self
+    }
+
+    /*
+     * Convert the layers pushed since the last run to eStargz every interval, starting now to
+     * catch up with anything pushed while the registry was down.
+     */
+    pub fn schedule_estargz(self, interval: Duration) -> Self {
+        let ts = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let running = ts
+                    .jobs
+                    .list()
+                    .iter()
+                    .any(|j| j.kind == JobKind::Estargz && j.state == JobState::Running);
+                if running {
+                    warn!("Previous estargz job still running, skipping this run");
+                } else {
+                    ts.start_estargz_job();
+                }
+            }
+        });
+        self
+    }
+
+    fn start_estargz_job(&self) -> Job {
+        let estargz = self.estargz.clone();
+        self.jobs.start(JobKind::Estargz, move |h| {
+            estargz
+                .ok_or_else(|| anyhow!("eStargz conversion isn't enabled"))?
+                .run_job(h)
+        })
+    }
+
+    /*
+     * Back up the registry every interval, starting now so there's a backup straight away.
+     */
+    pub fn schedule_backup(self, interval: Duration) -> Self {
+        let ts = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let running = ts
+                    .jobs
+                    .list()
+                    .iter()
+                    .any(|j| j.kind == JobKind::Backup && j.state == JobState::Running);
+                if running {
+                    warn!("Previous backup job still running, skipping this run");
+                } else {
+                    ts.start_backup_job();
+                }
+            }
+        });
+        self
+    }
+
+    /*
+     * Check the stored blobs and tags every interval. The first run is after one interval, as
+     * re-hashing everything is slow and shouldn't hold up startup.
+     */
+    pub fn schedule_scrub(self, interval: Duration) -> Self {
+        let ts = self.clone();
+        tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut ticker = tokio::time::interval_at(start, interval);
+            loop {
+                ticker.tick().await;
+                let running = ts
+                    .jobs
+                    .list()
+                    .iter()
+                    .any(|j| j.kind == JobKind::Scrub && j.state == JobState::Running);
+                if running {
+                    warn!("Previous scrub job still running, skipping this run");
+                } else {
+                    ts.start_scrub_job();
+                }
+            }
+        });
+        self
+    }
+
+    fn start_scrub_job(&self) -> Job {
+        let manifests_path = self.manifests_path.clone();
+        let blobs_path = self.blobs_path.clone();
+        let quarantine_path = self.quarantine_path.clone();
+        let scrub_report = self.scrub_report.clone();
+        self.jobs.start(JobKind::Scrub, move |h| {
+            let report = scrub::scrub(&manifests_path, &blobs_path, quarantine_path.as_deref(), h)?;
+            report.record_metrics();
+            let msg = report.summary();
+            *scrub_report.write().unwrap() = Some(report);
+            Ok(msg)
+        })
+    }
+
+    /*
+     * Remove uploads idle for longer than ttl, and anything else that old in the scratch dir,
+     * checked a few times per ttl.
+     */
+    pub fn schedule_upload_expiry(self, ttl: Duration) -> Self {
+        let ts = self.clone();
+        let interval = (ttl / 4).clamp(Duration::from_secs(60), Duration::from_secs(3600));
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                ts.expire_uploads(ttl);
+            }
+        });
+        self
+    }
+
+    fn expire_uploads(&self, ttl: Duration) {
+        let mut dirs = vec![&self.uploads_path];
+        if self.scratch_path != self.uploads_path {
+            dirs.push(&self.scratch_path);
+        }
+        for dir in dirs {
+            match uploads::expire(dir, ttl, SystemTime::now()) {
+                Ok((0, 0)) => {}
+                Ok((expired, orphans)) => info!(
+                    "Removed {} abandoned uploads and {} other old files from {:?}",
+                    expired, orphans, dir
+                ),
+                Err(e) => warn!("Failed to remove abandoned uploads: {:?}", e),
             }
         }
+        // Also drops sessions expired or completed by other backends sharing the data dir
+        let mut active = self.active_uploads.write().unwrap();
+        active.retain(|u| uploads::exists(&self.uploads_path, &u.uuid));
+        uploads::ACTIVE.set(active.len() as i64);
+    }
 
-        //Save out manifest
-        let f = File::open(buf.path())?;
-        let reader = BufReader::new(f);
-        let calculated_digest = sha256_tag_digest(reader)?;
+    fn start_backup_job(&self) -> Job {
+        let data_path = self.data_path.clone();
+        let tags_lock = self.tags_lock.clone();
+        let target = self.backup.clone();
+        self.jobs.start(JobKind::Backup, move |h| {
+            let target = target.ok_or_else(|| anyhow!("No backup target configured"))?;
+            backup::run_job(&data_path, &tags_lock, target.as_ref(), h)
+        })
+    }
 
-        self.save_blob(buf.path(), &calculated_digest)?;
-        self.save_tag(&calculated_digest, local_repo_name, &remote_image.tag)
-            .await?;
+    fn start_archive_job(&self) -> Job {
+        let data_path = self.data_path.clone();
+        let scratch_path = self.scratch_path.clone();
+        let tags_lock = self.tags_lock.clone();
+        let target = self.backup.clone();
+        self.jobs.start(JobKind::Archive, move |h| {
+            let target = target.ok_or_else(|| anyhow!("No backup target configured"))?;
+            backup::run_archive_job(&data_path, &scratch_path, &tags_lock, target.as_ref(), h)
+        })
+    }
+
+    fn start_restore_job(&self) -> Job {
+        let data_path = self.data_path.clone();
+        let scratch_path = self.scratch_path.clone();
+        let tags_lock = self.tags_lock.clone();
+        let metadata = self.metadata.clone();
+        let repo_index = self.repo_index.clone();
+        let target = self.backup.clone();
+        let storage = self.storage.clone();
+        let synced = [
+            self.manifests_path.clone(),
+            self.links_path.clone(),
+            self.blobs_path.clone(),
+        ];
+        self.jobs.start(JobKind::Restore, move |h| {
+            let target = target.ok_or_else(|| anyhow!("No backup target configured"))?;
+            let msg = backup::run_restore_job(
+                &data_path,
+                &scratch_path,
+                &tags_lock,
+                metadata.as_deref(),
+                target.as_ref(),
+                h,
+            )?;
+            // Only adds files, so nothing the bucket has is lost
+            if let Some(s) = &storage {
+                for dir in &synced {
+                    s.sync_dir(dir)?;
+                }
+            }
+            // The watcher would catch up anyway, but not necessarily before the job finishes
+            if let Some(index) = &repo_index {
+                index.rebuild()?;
+            }
+            Ok(msg)
+        })
+    }
+
+    fn start_usage_job(&self) -> Job {
+        let manifests_path = self.manifests_path.clone();
+        let data_path = self.data_path.clone();
+        self.jobs.start(JobKind::Usage, move |h| {
+            usage::run_job(&manifests_path, &data_path, h)
+        })
+    }
+
+    fn start_retention_job(&self, collect_garbage: bool) -> Job {
+        let manifests_path = self.manifests_path.clone();
+        let blobs_path = self.blobs_path.clone();
+        let scratch_path = self.scratch_path.clone();
+        let links_path = self.links_path.clone();
+        let rules = self.retention_rules();
+        let repo_index = self.repo_index.clone();
+        let events = self.events.clone();
+        let tags_lock = self.tags_lock.clone();
+        let metadata = self.metadata.clone();
+        let trash = self.trash.clone();
+        let storage = self.storage.clone();
+
+        self.jobs.start(JobKind::Retention, move |h| {
+            let done = {
+                let _guard = tags_lock.write().unwrap();
+                let deletions =
+                    retention::plan(&manifests_path, &blobs_path, &rules, SystemTime::now())?;
+                let done = retention::apply(&manifests_path, &scratch_path, deletions, h)?;
+                for d in &done {
+                    let reference = d.tag.as_deref().unwrap_or(&d.digest);
+                    if let Some(m) = &metadata {
+                        m.reload_tag(&manifests_path, &blobs_path, &d.repo_name, reference)?;
+                    }
+                }
+                done
+            };
+            if let Some(s) = &storage {
+                for d in &done {
+                    let reference = d.tag.as_deref().unwrap_or(&d.digest);
+                    s.sync(&manifests_path.join(&d.repo_name).join(reference))?;
+                }
+            }
+
+            let (mut tags, mut untagged) = (0, 0);
+            for d in &done {
+                if d.history_only {
+                    untagged += 1;
+                    continue;
+                }
+                match &d.tag {
+                    Some(tag) => {
+                        tags += 1;
+                        if let Some(index) = &repo_index {
+                            index.remove(&d.repo_name, tag);
+                        }
+                    }
+                    None => untagged += 1,
+                }
+                events.publish(Event::new(
+                    EventAction::Delete,
+                    &d.repo_name,
+                    d.tag.as_deref(),
+                    &d.digest,
+                ));
+            }
+
+            let msg = format!("Deleted {} tags and {} untagged manifests", tags, untagged);
+            if collect_garbage && !done.is_empty() && !h.is_cancelled() {
+                let gc = maintenance::garbage_collect(
+                    &manifests_path,
+                    &blobs_path,
+                    &links_path,
+                    metadata.as_deref(),
+                    trash.as_ref(),
+                    &events,
+                    h,
+                )?;
+                sync_collected(storage.as_ref(), &blobs_path, &links_path, trash.as_ref())?;
+                Ok(format!("{}. {}", msg, gc))
+            } else {
+                Ok(msg)
+            }
+        })
+    }
+
+    /*
+     * Keep the admission rules from TrowPolicy resources up to date (see trow_policy.rs).
+     *
+     * Fails if not running in Kubernetes.
+     */
+    pub fn watch_policies(self) -> Result<Self> {
+        trow_policy::watch(self.policy.clone())?;
+        Ok(self)
+    }
+
+    /*
+     * Watch the manifests directory for changes made outside of Trow (e.g. restoring from
+     * backup or rsyncing in content) so they show up in the catalog without a restart.
+     *
+     * The catalog and tag lists are served from the index after this is called.
+     */
+    pub fn watch_data_dir(mut self) -> Result<Self> {
+        let index = Arc::new(RepoIndex::new(&self.manifests_path)?);
+        watcher::watch(index.clone())?;
+        self.repo_index = Some(index);
+        Ok(self)
+    }
 
+    // Pushing by digest is always allowed, as the content can't change
+    fn check_tag_writable(&self, repo_name: &str, reference: &str) -> Result<(), Status> {
+        if is_digest(reference) {
+            return Ok(());
+        }
+        let immutable = self
+            .policy
+            .get()
+            .immutable_tags
+            .iter()
+            .any(|s| s.matches(repo_name, reference));
+        if immutable && self.tag_exists(repo_name, reference) {
+            return Err(Status::already_exists(format!(
+                "Tag {} in {} is immutable and has already been pushed",
+                reference, repo_name
+            )));
+        }
         Ok(())
     }
 
-    /**
-    Authenticates to proxy server and returns auth token.
-    **/
-    async fn get_auth_token(
-        &self,
-        cl: &reqwest::Client,
-        image: &Image,
-        auth: &Option<Auth>,
-    ) -> Result<String> {
-        //First get auth address from remote server
-        let www_authenticate_header = self.get_www_authenticate_header(cl, image).await?;
+    fn tag_exists(&self, repo_name: &str, tag: &str) -> bool {
+        if let Some(m) = &self.metadata {
+            match m.tag_exists(repo_name, tag) {
+                Ok(exists) => return exists,
+                Err(e) => warn!("Failed to look up tag in metadata database {:?}", e),
+            }
+        }
+        self.manifests_path.join(repo_name).join(tag).exists()
+    }
 
-        let mut bearer_param_map = TrowServer::get_bearer_param_map(www_authenticate_header);
+    // Counts a pull of a manifest by the tag or digest, in the metadata database if there is one
+    fn count_pull(&self, repo_name: &str, reference: &str) {
+        metrics::MANIFEST_PULLS
+            .with_label_values(&[repo_name])
+            .inc();
+        if let Some(m) = &self.metadata {
+            let pulled = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+            if let Err(e) = m.record_pull(repo_name, reference, &pulled) {
+                warn!(
+                    "Failed to record pull of {}:{} {:?}",
+                    repo_name, reference, e
+                );
+            }
+        }
+        // Looking up the digest is only worth it if someone's watching
+        if self.events.is_watched() {
+            match self.get_digest_for_reference(repo_name, reference) {
+                Ok(digest) => {
+                    let tag = Some(reference).filter(|r| !is_digest(r));
+                    self.events
+                        .publish(Event::new(EventAction::Pull, repo_name, tag, &digest));
+                }
+                Err(e) => warn!(
+                    "Failed to find digest of {}:{} {:?}",
+                    repo_name, reference, e
+                ),
+            }
+        }
+    }
 
-        let realm = bearer_param_map
-            .get("realm")
-            .cloned()
-            .ok_or(anyhow!("Expected realm key in authenticate header"))?;
+    fn get_upload_path_for_blob(&self, uuid: &str) -> PathBuf {
+        self.uploads_path.join(uuid)
+    }
 
-        bearer_param_map.remove("realm");
+    /*
+     * Whether the upload is in progress. Sessions not known to this backend are looked for on
+     * disk, as they may have been started by another backend sharing the data dir.
+     */
+    fn is_active_upload(&self, upload: &Upload) -> bool {
+        if self.active_uploads.read().unwrap().contains(upload) {
+            return true;
+        }
+        match uploads::restore(&self.uploads_path, &upload.uuid) {
+            Some(s) if s.repo_name == upload.repo_name => {
+                info!("Restored upload session {}", upload.uuid);
+                let mut active = self.active_uploads.write().unwrap();
+                active.insert(upload.clone());
+                uploads::ACTIVE.set(active.len() as i64);
+                true
+            }
+            _ => false,
+        }
+    }
 
-        let mut request = cl.get(realm.as_str()).query(&bearer_param_map);
+    fn get_catalog_path_for_blob(&self, digest: &str) -> Result<PathBuf> {
+        let (alg, val) = digest
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Digest {} did not contain alg component", digest))?;
+        if !SUPPORTED_DIGESTS.contains(&alg) {
+            return Err(anyhow!("Hash algorithm {} not supported", alg));
+        }
+        if !is_valid_digest(digest) {
+            return Err(anyhow!("Invalid digest {}", digest));
+        }
+        Ok(self.blobs_path.join(alg).join(val))
+    }
 
-        if let Some(a) = auth {
-            if let Some(u) = &a.user {
-                info!("Attempting proxy authentication with user {}", u);
-                request = request.basic_auth(u, a.pass.as_ref())
+    /*
+     * Where the blob is stored, looking in the transcoded and eStargz layers if it's not in the
+     * blobs dir
+     */
+    fn find_blob(&self, digest: &str) -> Result<PathBuf> {
+        let path = self.get_catalog_path_for_blob(digest)?;
+        if path.exists() {
+            return Ok(path);
+        }
+        Ok(self.find_rewritten_blob(digest).unwrap_or(path))
+    }
+
+    // A transcoded or eStargz layer, or the config of an image with eStargz layers
+    fn find_rewritten_blob(&self, digest: &str) -> Option<PathBuf> {
+        self.transcoder
+            .as_ref()
+            .and_then(|t| t.layer_path(digest))
+            .or_else(|| self.estargz.as_ref().and_then(|e| e.blob_path(digest)))
+    }
+
+    /*
+     * The digest and path of the manifest the reference is to, including manifests rewritten to
+     * use transcoded or eStargz layers as long as the original is in the repository.
+     */
+    fn find_manifest(&self, repo_name: &str, reference: &str) -> Result<(String, PathBuf)> {
+        let variant = self
+            .transcoder
+            .as_ref()
+            .and_then(|t| t.manifest_path(reference))
+            .or_else(|| {
+                self.estargz
+                    .as_ref()
+                    .and_then(|e| e.manifest_path(reference))
+            });
+        if let Some((path, original)) = variant {
+            if self.verify_manifest_digest_in_repo(repo_name, &original)? {
+                return Ok((reference.to_string(), path));
             }
         }
+        let digest = self.get_digest_for_reference(repo_name, reference)?;
+        let path = self.get_catalog_path_for_blob(&digest)?;
+        Ok((digest, path))
+    }
 
-        let resp = request.send().await.or_else(|e| {
-            Err(anyhow!(
-                "Failed to send authenticate to {} request: {}",
-                realm,
-                e
-            ))
-        })?;
+    // Given a manifest digest, check if it is referenced by any tag in the repo
+    fn verify_manifest_digest_in_repo(&self, repo_name: &str, digest: &str) -> Result<bool> {
+        if let Some(m) = &self.metadata {
+            return m.is_current_in_repo(repo_name, digest);
+        }
+        let mut ri = RepoIterator::new(&self.manifests_path.join(repo_name))?;
+        let res = ri.find(|de| does_manifest_match_digest(de, &digest));
+        Ok(res.is_some())
+    }
 
-        if !resp.status().is_success() {
-            return Err(anyhow!("Failed to authenticate to {}", realm));
+    fn get_digest_from_manifest(&self, repo_name: &str, tag: &str) -> Result<String> {
+        get_digest_from_manifest_path(self.manifests_path.join(repo_name).join(tag))
+    }
+
+    async fn save_tag(&self, digest: &str, repo_name: &str, tag: &str) -> Result<()> {
+        // Tag files should contain list of digests with timestamp
+        // First line should always be the current digest, followed by the earlier ones, newest
+        // first, as the tag's history
+
+        let repo_dir = self.manifests_path.join(repo_name);
+        let repo_path = repo_dir.join(tag);
+        fs::create_dir_all(&repo_dir)?;
+
+        let ts = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let line = format!("{} {}\n", digest, ts);
+
+        {
+            let _guard = self.tags_lock.write().unwrap();
+            let mut contents = line.into_bytes();
+            match fs::read(&repo_path) {
+                Ok(c) => contents.extend_from_slice(&c),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            };
+            // Written in the scratch dir and moved into place, so it's never seen half written,
+            // and synced first so a crash can't leave the new file empty
+            let tmp_path = self.scratch_path.join(Uuid::new_v4().to_string());
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&contents)?;
+            tmp.sync_all()?;
+            fs::rename(&tmp_path, &repo_path)?;
+
+            if let Some(m) = &self.metadata {
+                m.add_tag(&self.blobs_path, repo_name, tag, digest, &ts)?;
+            }
+            if let Some(index) = &self.repo_index {
+                index.insert(repo_name, tag);
+            }
+        }
+        // A blocking upload for cloud storage, so listings aren't held up by it
+        self.store(&repo_path)?;
+        Ok(())
+    }
+
+    fn get_path_for_manifest(&self, repo_name: &str, reference: &str) -> Result<PathBuf> {
+        let digest = self.get_digest_for_reference(repo_name, reference)?;
+        self.get_catalog_path_for_blob(&digest)
+    }
+
+    fn get_digest_for_reference(&self, repo_name: &str, reference: &str) -> Result<String> {
+        if is_digest(reference) {
+            if !self.verify_manifest_digest_in_repo(repo_name, reference)? {
+                error!("Digest {} not in repository {}", reference, repo_name);
+                return Err(anyhow!(
+                    "Digest {} not in repository {}",
+                    reference,
+                    repo_name
+                ));
+            }
+            Ok(reference.to_string())
+        } else {
+            //Content of tag is the digest
+            self.get_digest_from_manifest(repo_name, reference)
+        }
+    }
+
+    fn remember_media_type(&self, digest: &str, media_type: &str) {
+        let mut types = self.media_types.write().unwrap();
+        if types.len() >= MAX_MEDIA_TYPES {
+            types.clear();
+        }
+        types.insert(digest.to_string(), media_type.to_string());
+    }
+
+    /// Media type of the manifest, only read from disk the first time it's asked for
+    fn get_manifest_media_type(&self, digest: &str, path: &PathBuf) -> Result<String> {
+        if let Some(t) = self.media_types.read().unwrap().get(digest) {
+            return Ok(t.clone());
+        }
+        Ok(self.create_verified_manifest(path, false)?.content_type)
+    }
+
+    /*
+     * Checks a manifest being pushed or imported before it's stored: that it's a supported type,
+     * the digests it has are valid, images have no more than max_layers layers, and the blobs
+     * they refer to exist and are the size given.
+     *
+     * Fails with InvalidManifest or MissingBlobError, for the frontend to tell the client which.
+     * Manifests already stored aren't checked again, so pulls aren't broken by a stricter check.
+     */
+    fn check_pushed_manifest(&self, manifest_path: &Path) -> Result<()> {
+        let manifest_bytes = std::fs::read(manifest_path)?;
+        let manifest_json: serde_json::Value =
+            serde_json::from_slice(&manifest_bytes).map_err(|e| InvalidManifest {
+                err: format!("not valid JSON: {}", e),
+            })?;
+        let manifest = Manifest::from_json(&manifest_json)?;
+        manifest.validate()?;
+        if let Manifest::V2(ref image) = manifest {
+            if self.max_layers > 0 && image.layers.len() > self.max_layers {
+                return Err(InvalidManifest {
+                    err: format!(
+                        "{} layers, over the limit of {}",
+                        image.layers.len(),
+                        self.max_layers
+                    ),
+                }
+                .into());
+            }
+        }
+
+        for (digest, size) in manifest.get_local_assets() {
+            let stored = match fs::metadata(self.find_blob(digest)?) {
+                Ok(m) => m.len(),
+                Err(_) => {
+                    return Err(MissingBlobError {
+                        digest: digest.to_string(),
+                    }
+                    .into())
+                }
+            };
+            // Clients check the blob against this size when pulling
+            if let Some(size) = size {
+                if size != stored {
+                    return Err(InvalidManifest {
+                        err: format!("size of {} is {}, not {}", digest, stored, size),
+                    }
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn create_verified_manifest(
+        &self,
+        manifest_path: &PathBuf,
+        verify_assets_exist: bool,
+    ) -> Result<VerifiedManifest> {
+        let manifest_bytes = std::fs::read(&manifest_path)?;
+        let manifest_json: serde_json::Value = serde_json::from_slice(&manifest_bytes)?;
+        let manifest = Manifest::from_json(&manifest_json)?;
+
+        if verify_assets_exist {
+            for digest in manifest.get_local_asset_digests() {
+                let path = self.find_blob(digest)?;
+
+                if !path.exists() {
+                    return Err(MissingBlobError {
+                        digest: digest.to_string(),
+                    }
+                    .into());
+                }
+            }
+        }
+
+        // Calculate the digest: sha256:...
+        let reader = BufReader::new(manifest_bytes.as_slice());
+        let digest = sha256_tag_digest(reader)?;
+
+        // Manifests never change, so the digest always has this media type
+        self.remember_media_type(&digest, &manifest.get_media_type());
+        if let Some(t) = &self.transcoder {
+            if let Err(e) = t.record_layers(&manifest_json) {
+                warn!(
+                    "Failed to record compression of layers in {}: {:?}",
+                    digest, e
+                );
+            }
+        }
+        if let Some(e) = &self.estargz {
+            if let Err(e) = e.record_layers(&manifest_json) {
+                warn!("Failed to queue layers in {} for eStargz: {:?}", digest, e);
+            }
+        }
+
+        // For performance, could generate only if verification is on, otherwise copy from somewhere
+        Ok(VerifiedManifest {
+            digest,
+            content_type: manifest.get_media_type(),
+        })
+    }
+
+    /**
+    If repo is proxied to another registry, this will return the details of the remote image.
+    If the repo isn't proxied None is returned
+    **/
+    fn get_proxy_address_and_auth(
+        &self,
+        repo_name: &str,
+        reference: &str,
+    ) -> Option<(Image, Option<Auth>)> {
+        //All proxies are under "f_"
+        if repo_name.starts_with(PROXY_DIR) {
+            let proxy_name = repo_name.strip_prefix(PROXY_DIR).unwrap();
+
+            if self.proxy_hub && proxy_name.starts_with(HUB_PROXY_DIR) {
+                let mut repo = proxy_name.strip_prefix(HUB_PROXY_DIR).unwrap().to_string();
+
+                //Official images have to use the library/ repository
+                if !repo.contains('/') {
+                    repo = format!("library/{}", repo).to_string();
+                }
+
+                return Some((
+                    Image {
+                        host: HUB_ADDRESS.to_string(),
+                        repo,
+                        tag: reference.to_string(),
+                    },
+                    Some(Auth {
+                        user: self.hub_user.clone(),
+                        pass: self.hub_pass.clone(),
+                    }),
+                ));
+            }
+        }
+
+        None
+    }
+
+    /// Download a blob that is part of `remote_image`.
+    async fn download_blob<T: Display>(
+        &self,
+        cl: &reqwest::Client,
+        token: &Option<T>,
+        remote_image: &Image,
+        digest: &str,
+    ) -> Result<()> {
+        if self.get_catalog_path_for_blob(digest)?.exists() {
+            info!("Already have blob {}", digest);
+            return Ok(());
+        }
+        let path = self.scratch_path.join(digest);
+        let mut file = match TemporaryFile::open_for_writing(path).await? {
+            Some(f) => f,
+            None => {
+                info!("Skip concurrently fetched blob {}", digest);
+                return Ok(());
+            }
+        };
+
+        let addr = format!(
+            "{}/{}/blobs/{}",
+            remote_image.host, remote_image.repo, digest
+        );
+        info!("Downloading blob {}", addr);
+
+        let resp = if let Some(auth) = token {
+            cl.get(&addr).bearer_auth(auth).send().await?
+        } else {
+            cl.get(&addr).send().await?
+        };
+        file.write_all(&resp.bytes().await?).await?;
+        let _guard = self.write_locks.lock_blob(digest).await;
+        self.save_blob(file.path(), digest)?;
+        Ok(())
+    }
+
+    #[async_recursion]
+    async fn download_manifest_and_layers<T: Display + Sync>(
+        &self,
+        cl: &reqwest::Client,
+        token: &Option<T>,
+        remote_image: &Image,
+        local_repo_name: &str,
+    ) -> Result<()> {
+        debug!("Downloading manifest + layers for {}", remote_image);
+        let mut req = cl.get(&remote_image.get_manifest_url());
+        if let Some(auth) = token {
+            req = req.bearer_auth(auth);
+        }
+
+        let resp = req.headers(create_accept_header()).send().await?;
+
+        if !resp.status().is_success() {
+            return Err(anyhow!(
+                "GET {} returned unexpected {}",
+                &remote_image.get_manifest_url(),
+                resp.status()
+            ));
+        }
+
+        let mut buf =
+            TemporaryFile::open_for_writing(self.scratch_path.join(Uuid::new_v4().to_string()))
+                .await?
+                .unwrap();
+        let bytes = resp.bytes().await?;
+        buf.write_all(&bytes).await?;
+
+        let mani: Manifest = serde_json::from_slice(&bytes)?;
+        match mani {
+            Manifest::List(_) => {
+                let images_to_dl = mani
+                    .get_local_asset_digests()
+                    .into_iter()
+                    .map(|digest| {
+                        let mut image = remote_image.clone();
+                        image.tag = digest.to_string();
+                        image
+                    })
+                    .collect::<Vec<_>>();
+                let futures = images_to_dl
+                    .iter()
+                    .map(|img| self.download_manifest_and_layers(cl, token, &img, local_repo_name));
+                try_join_all(futures).await?;
+            }
+            Manifest::V2(_) | Manifest::Artifact(_) => {
+                let futures = mani
+                    .get_local_asset_digests()
+                    .into_iter()
+                    .map(|digest| self.download_blob(cl, token, remote_image, &digest));
+                try_join_all(futures).await?;
+            }
+        }
+
+        //Save out manifest
+        let f = File::open(buf.path())?;
+        let reader = BufReader::new(f);
+        let calculated_digest = sha256_tag_digest(reader)?;
+
+        {
+            let _guard = self.write_locks.lock_blob(&calculated_digest).await;
+            self.save_blob(buf.path(), &calculated_digest)?;
+        }
+        self.save_tag(&calculated_digest, local_repo_name, &remote_image.tag)
+            .await?;
+
+        Ok(())
+    }
+
+    /**
+    Authenticates to proxy server and returns auth token.
+    **/
+    async fn get_auth_token(
+        &self,
+        cl: &reqwest::Client,
+        image: &Image,
+        auth: &Option<Auth>,
+    ) -> Result<String> {
+        //First get auth address from remote server
+        let www_authenticate_header = self.get_www_authenticate_header(cl, image).await?;
+
+        let mut bearer_param_map = TrowServer::get_bearer_param_map(www_authenticate_header);
+
+        let realm = bearer_param_map
+            .get("realm")
+            .cloned()
+            .ok_or(anyhow!("Expected realm key in authenticate header"))?;
+
+        bearer_param_map.remove("realm");
+
+        let mut request = cl.get(realm.as_str()).query(&bearer_param_map);
+
+        if let Some(a) = auth {
+            if let Some(u) = &a.user {
+                info!("Attempting proxy authentication with user {}", u);
+                request = request.basic_auth(u, a.pass.as_ref())
+            }
+        }
+
+        let resp = request.send().await.or_else(|e| {
+            Err(anyhow!(
+                "Failed to send authenticate to {} request: {}",
+                realm,
+                e
+            ))
+        })?;
+
+        if !resp.status().is_success() {
+            return Err(anyhow!("Failed to authenticate to {}", realm));
+        }
+
+        resp.json::<serde_json::Value>()
+            .await
+            .map_err(|e| anyhow!("Failed to deserialize auth response {}", e))?
+            .get("access_token")
+            .map(|s| s.as_str().unwrap_or(""))
+            .map(|s| strip_dquotes(s).unwrap_or(s).to_string())
+            .ok_or(anyhow!("Failed to find auth token in auth repsonse"))
+    }
+
+    async fn get_www_authenticate_header(
+        &self,
+        cl: &reqwest::Client,
+        image: &Image,
+    ) -> Result<String> {
+        let resp = cl
+            .head(&image.get_manifest_url())
+            .headers(create_accept_header())
+            .send()
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "Attempt to authenticate to {} failed with: {}",
+                    &image.get_manifest_url(),
+                    e
+                )
+            })?;
+
+        if resp.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Err(anyhow!(
+                "Expected request '{}' to fail with status unauthorized",
+                &image.get_manifest_url()
+            ));
+        }
+
+        resp.headers()
+            .get("www-authenticate")
+            .ok_or(anyhow!(
+                "Expected www-authenticate header to identify authentication server"
+            ))
+            .and_then(|v| {
+                v.to_str()
+                    .map_err(|e| anyhow!("Failed to read auth header {:?}", e))
+            })
+            .map(|s| s.to_string())
+    }
+
+    fn get_bearer_param_map(www_authenticate_header: String) -> HashMap<String, String> {
+        let base = www_authenticate_header.strip_prefix("Bearer ");
+
+        base.unwrap_or("")
+            .split(',')
+            .map(|kv| kv.split('=').collect::<Vec<&str>>())
+            .map(|vec| {
+                (
+                    vec[0].to_string(),
+                    strip_dquotes(vec[1]).unwrap_or(vec[1]).to_string(),
+                )
+            })
+            .collect()
+    }
+
+    async fn get_digest_from_header(
+        &self,
+        cl: &reqwest::Client,
+        image: &Image,
+        auth_token: &Option<String>,
+    ) -> Option<String> {
+        let resp = if let Some(auth) = auth_token {
+            cl.head(&image.get_manifest_url())
+                .bearer_auth(&auth)
+                .headers(create_accept_header())
+                .send()
+                .await
+        } else {
+            cl.head(&image.get_manifest_url())
+                .headers(create_accept_header())
+                .send()
+                .await
+        };
+
+        let resp = match resp {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Remote registry didn't respond to HEAD request {}", e);
+                return None;
+            }
+        };
+
+        if let Some(digest) = resp.headers().get(DIGEST_HEADER) {
+            let digest = format!("{:?}", digest);
+            Some(digest.trim_matches('"').to_string())
+        } else {
+            None
+        }
+    }
+
+    /*
+     * The digest the tag in a proxied repository currently points at upstream.
+     */
+    async fn get_upstream_digest(&self, repo_name: &str, tag: &str) -> Result<String> {
+        let (proxy_image, proxy_auth) = self
+            .get_proxy_address_and_auth(repo_name, tag)
+            .ok_or_else(|| anyhow!("{} is no longer proxied", repo_name))?;
+        let cl = self.http_client.clone();
+        let auth_token = match self.get_auth_token(&cl, &proxy_image, &proxy_auth).await {
+            Ok(a) => Some(a),
+            Err(e) => {
+                debug!("Failed to get auth token for {}. Error: {}", proxy_image, e);
+                None
+            }
+        };
+        self.get_digest_from_header(&cl, &proxy_image, &auth_token)
+            .await
+            .ok_or_else(|| anyhow!("no digest from {}", proxy_image))
+    }
+
+    async fn create_manifest_read_location(
+        &self,
+        repo_name: String,
+        reference: String,
+        do_verification: bool,
+    ) -> Result<ManifestReadLocation> {
+        if let Some((proxy_image, proxy_auth)) =
+            self.get_proxy_address_and_auth(&repo_name, &reference)
+        {
+            //TODO: May want to consider download tracking in case of simultaneous requests
+            //In short term this isn't a big problem as should just copy over itself in worst case
+            info!(
+                "Request for proxied repo {}:{} maps to {}",
+                repo_name, reference, proxy_image
+            );
+
+            let cl = self.http_client.clone();
+
+            let mut have_manifest = false;
+
+            //Get auth token for remote server.
+            //TODO: Consider caching
+            let auth_token = match self.get_auth_token(&cl, &proxy_image, &proxy_auth).await {
+                Ok(a) => Some(a),
+                Err(e) => {
+                    error!("Failed to get auth token for {}. Error: {}", proxy_image, e);
+                    None
+                }
+            };
+
+            let digest = self
+                .get_digest_from_header(&cl, &proxy_image, &auth_token)
+                .await;
+
+            if let Some(digest) = digest {
+                if self.get_catalog_path_for_blob(&digest)?.exists() {
+                    info!(
+                        "Have up to date manifest for {} digest {}",
+                        repo_name, digest
+                    );
+                    have_manifest = true;
+
+                    //Make sure our tag exists and is up-to-date
+                    if !is_digest(&reference) {
+                        let our_digest = self.get_digest_from_manifest(&repo_name, &reference);
+                        if our_digest.is_err() || (our_digest.unwrap() != digest) {
+                            let res = self.save_tag(&digest, &repo_name, &reference).await;
+                            if res.is_err() {
+                                error!(
+                                    "Internal error updating tag for proxied image {:?}",
+                                    res.unwrap()
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+
+            if !have_manifest {
+                if let Err(e) = self
+                    .download_manifest_and_layers(&cl, &auth_token, &proxy_image, &repo_name)
+                    .await
+                {
+                    //Note that we may still have an out-of-date version that will be returned
+                    error!("Failed to download proxied image {}", e);
+                }
+            }
+        }
+
+        //TODO: This isn't optimal
+        let (_, path) = self.find_manifest(&repo_name, &reference)?;
+        let vm = self.create_verified_manifest(&path, do_verification)?;
+        Ok(ManifestReadLocation {
+            content_type: vm.content_type.to_owned(),
+            digest: vm.digest,
+            path: path.to_string_lossy().to_string(),
+        })
+    }
+
+    /// Moves blob from scratch or uploads to blob catalog, unless it's already there
+    fn save_blob(&self, scratch_path: &Path, digest: &str) -> Result<()> {
+        let digest_path = self.get_catalog_path_for_blob(digest)?;
+        let repo_path = digest_path
+            .parent()
+            .ok_or_else(|| anyhow!("Error finding repository path"))?;
+
+        if digest_path.exists() {
+            // Same digest is the same content, so the copy pushed before can be shared
+            debug!("Already have blob {}", digest);
+            fs::remove_file(scratch_path)?;
+            return Ok(());
+        }
+        if !repo_path.exists() {
+            fs::create_dir_all(repo_path)?;
+        }
+        uploads::move_file(scratch_path, &digest_path, &self.scratch_path)?;
+        self.store(&digest_path)
+    }
+
+    fn link_blob(&self, repo_name: &str, digest: &str) -> Result<()> {
+        let path = links::link(&self.links_path, repo_name, digest)?;
+        self.store(&path)
+    }
+
+    // Writes the change to the file through to the bucket, if the registry is kept in one
+    fn store(&self, path: &Path) -> Result<()> {
+        match &self.storage {
+            Some(s) => s.sync(path),
+            None => Ok(()),
+        }
+    }
+
+    // As store, for a directory the files of which never change, e.g. the trash
+    fn store_dir(&self, dir: &Path) -> Result<()> {
+        match &self.storage {
+            Some(s) => s.sync_dir(dir),
+            None => Ok(()),
+        }
+    }
+
+    fn validate_and_save_blob(&self, repo_name: &str, user_digest: &str, uuid: &str) -> Result<()> {
+        debug!("Saving blob {}", user_digest);
+
+        let scratch_path = self.get_upload_path_for_blob(uuid);
+        let res = match validate_digest(&scratch_path, user_digest) {
+            Ok(_) => self
+                .save_blob(&scratch_path, user_digest)
+                .and_then(|_| self.link_blob(repo_name, user_digest)),
+            Err(e) => Err(e),
+        };
+
+        res?;
+        Ok(())
+    }
+
+    //Support functions for validate, would like to move these
+    pub fn image_exists(&self, image: &Image) -> bool {
+        match self.get_path_for_manifest(&image.repo, &image.tag) {
+            Ok(f) => f.exists(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn requires_existing_images(&self) -> bool {
+        self.policy.get().require_existing_images
+    }
+
+    /*
+     * The deny list entry matching the local image, if any.
+     */
+    pub fn local_deny_rule(&self, image: &Image) -> Option<String> {
+        //Try matching both with and without host name
+        //Deny images are expected without host as always local
+        let full_name = format!("{}", image);
+        let name_without_host = format!("{}:{}", image.repo, image.tag);
+        let policy = self.policy.get();
+
+        for prefix in &policy.deny_local_prefixes {
+            if full_name.starts_with(prefix) || name_without_host.starts_with(prefix) {
+                info!("Image {} matches prefix {} on deny list", image, prefix);
+                return Some(format!("deny prefix {}", prefix));
+            }
+        }
+
+        for name in &policy.deny_local_images {
+            if &full_name == name || &name_without_host == name {
+                info!("Image {} matches image {} on deny list", image, name);
+                return Some(format!("deny image {}", name));
+            }
+        }
+
+        None
+    }
+
+    /*
+     * Whether the image has a cosign signature, which is stored under a tag named after the
+     * image's digest. The signature itself isn't checked.
+     */
+    pub fn has_signature(&self, repo_name: &str, reference: &str) -> bool {
+        match self.get_digest_for_reference(repo_name, reference) {
+            Ok(digest) => self.tag_exists(repo_name, &format!("{}.sig", digest.replace(':', "-"))),
+            Err(_) => false,
+        }
+    }
+
+    /*
+     * The TrowPolicy entry requiring images of the local repository to be signed in the
+     * namespace, if any.
+     */
+    pub fn signature_rule(&self, repo_name: &str, namespace: &str) -> Option<String> {
+        self.policy
+            .get()
+            .cluster
+            .signature_rule(repo_name, namespace)
+    }
+
+    /*
+     * The allow list entry matching the image in the namespace, if any. A TrowPolicy rule for the
+     * namespace replaces the other lists.
+     */
+    pub fn allow_rule(&self, image: &Image, namespace: &str) -> Option<String> {
+        //Have full names with host here
+        let name = format!("{}", image);
+        let policy = self.policy.get();
+        if policy.cluster.restricts(namespace) {
+            return policy.cluster.allow_rule(&name, namespace);
+        }
+
+        for prefix in &policy.allow_prefixes {
+            if name.starts_with(prefix) {
+                info!("Image {} matches prefix {} on allow list", name, prefix);
+                return Some(format!("allow prefix {}", prefix));
+            }
+        }
+
+        for a_name in &policy.allow_images {
+            if &name == a_name {
+                info!("Image {} matches image {} on allow list", name, a_name);
+                return Some(format!("allow image {}", a_name));
+            }
+        }
+
+        policy.cluster.allow_rule(&name, namespace)
+    }
+
+    /*
+     * The freeze window the namespace is in right now, if any, with the images that aren't
+     * already running there.
+     */
+    pub fn frozen_images<'a>(
+        &self,
+        namespace: &str,
+        images: &'a [String],
+    ) -> Option<(FreezeWindow, Vec<&'a String>)> {
+        let policy = self.policy.get();
+        let window = freeze::active_window(&policy.freeze_windows, namespace, Utc::now())?;
+        let admitted = policy.admitted.as_ref()?;
+        let new_images = images
+            .iter()
+            .filter(|i| !admitted.contains(namespace, i))
+            .collect();
+        Some((window.clone(), new_images))
+    }
+
+    /*
+     * Queue Docker Hub images from an admitted pod to be fetched into the proxy cache, if
+     * mirroring is on. Images that aren't cached yet go first.
+     */
+    pub fn mirror_admitted(&self, hub_images: &[Image]) {
+        let queue = match &self.mirror {
+            Some(q) if self.proxy_hub => q,
+            _ => return,
+        };
+        for image in hub_images {
+            let local = Image {
+                host: String::new(),
+                repo: format!("{}{}{}", PROXY_DIR, HUB_PROXY_DIR, image.repo),
+                tag: image.tag.clone(),
+            };
+            let priority = if self.image_exists(&local) {
+                Priority::Refresh
+            } else {
+                Priority::Missing
+            };
+            queue.push(&local.repo, &local.tag, priority);
+        }
+    }
+
+    // Only tracked when there are freeze windows that need it
+    pub fn record_admitted(&self, namespace: &str, images: &[String]) {
+        if let Some(admitted) = &self.policy.get().admitted {
+            admitted.record(namespace, images);
+        }
+    }
+
+    /*
+     * The quota covering the repository. A tenant's quota is only used if there's no quota for its
+     * namespace from the policy, and a more specific one from the policy still wins.
+     */
+    // Fails with UNAVAILABLE during read-only maintenance
+    fn check_writable(&self) -> Result<(), Status> {
+        match &*self.read_only.read().unwrap() {
+            Some(reason) => Err(Status::unavailable(reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    // Whether there's room to start another upload
+    fn check_capacity(&self) -> Result<(), Status> {
+        match &self.capacity {
+            Some(c) => c.check_upload(),
+            None => Ok(()),
+        }
+    }
+
+    fn quota_for(&self, repo_name: &str) -> Option<Quota> {
+        let mut quotas = self.policy.get().quotas.clone();
+        for q in self.tenants.quotas() {
+            if !quotas.iter().any(|p| p.name == q.name) {
+                quotas.push(q);
+            }
+        }
+        quota::find_quota(&quotas, repo_name).cloned()
+    }
+
+    // The --retention rules followed by those of each tenant
+    fn retention_rules(&self) -> Vec<RetentionRule> {
+        let mut rules = self.retention.clone();
+        rules.extend(self.tenants.retention_rules());
+        rules
+    }
+
+    /*
+     * Checks a push wouldn't take the namespace over quota.
+     *
+     * _blobs_ are the digests and sizes of everything the push adds. Any already referenced in
+     * the namespace are free. _new_tag_ is whether the push creates a tag.
+     */
+    fn check_quota(&self, q: &Quota, blobs: &[(String, u64)], new_tag: bool) -> Result<(), Status> {
+        let usage = quota::usage(&self.manifests_path, &self.blobs_path, &q.name).map_err(|e| {
+            error!("Failed to work out usage of {}: {:?}", q.name, e);
+            Status::internal("Internal error checking quota")
+        })?;
+        check_usage(q, &usage, blobs, new_tag)
+    }
+
+    /*
+     * The checks a push of _size_ bytes would go through, for checking before uploading. Blobs
+     * already in the namespace aren't known so count towards the quota, unlike in the push.
+     */
+    fn check_upload_allowed(
+        &self,
+        req: &UploadCheckRequest,
+        usage: &quota::Usage,
+        disk_available: u64,
+    ) -> Result<(), Status> {
+        if !self.is_writable_repo(&req.repo_name) {
+            return Err(Status::invalid_argument(format!(
+                "Repository {} is not writable",
+                req.repo_name
+            )));
+        }
+        if !req.tag.is_empty() {
+            self.check_tag_writable(&req.repo_name, &req.tag)?;
+        }
+        if let Some(q) = self.quota_for(&req.repo_name) {
+            let new_tag = !req.tag.is_empty() && !self.tag_exists(&req.repo_name, &req.tag);
+            check_usage(&q, usage, &[(String::new(), req.size)], new_tag)?;
+        }
+        if req.size > disk_available {
+            return Err(Status::resource_exhausted(format!(
+                "Not enough disk space: push needs {} bytes, {} available",
+                req.size, disk_available
+            )));
+        }
+        Ok(())
+    }
+
+    // Returns the manifest and everything it references, for checking against quotas
+    fn manifest_blobs(&self, manifest_path: &Path, digest: &str) -> Result<Vec<(String, u64)>> {
+        let bytes = fs::read(manifest_path)?;
+        let manifest = Manifest::from_json(&serde_json::from_slice(&bytes)?)?;
+
+        let mut blobs = vec![(digest.to_string(), bytes.len() as u64)];
+        for asset in manifest.get_local_asset_digests() {
+            blobs.push((asset.to_string(), quota::blob_size(&self.blobs_path, asset)));
+        }
+        Ok(blobs)
+    }
+
+    // Every repository, sorted so paging with last_repo works
+    fn repo_names(&self) -> Result<Vec<String>, Status> {
+        let _guard = self.tags_lock.read().unwrap();
+        let catalog: Vec<String> = match (&self.repo_index, &self.metadata) {
+            (Some(index), _) => index.catalog(),
+            (None, Some(m)) => m.catalog().map_err(|e| {
+                error!("Error reading catalog from metadata database {:?}", e);
+                Status::internal("Internal error streaming catalog")
+            })?,
+            (None, None) => {
+                let mut repos: Vec<String> = RepoIterator::new(&self.manifests_path)
+                    .map_err(|e| {
+                        error!("Error accessing catalog {:?}", e);
+                        Status::internal("Internal error streaming catalog")
+                    })?
+                    .map(|de| de.path())
+                    .filter_map(|p| p.parent().map(|p| p.to_path_buf()))
+                    .filter_map(|r| {
+                        r.strip_prefix(&self.manifests_path)
+                            .ok()
+                            .map(|p| p.to_path_buf())
+                    })
+                    .map(|p| p.to_string_lossy().to_string())
+                    .collect::<HashSet<String>>()
+                    .into_iter()
+                    .collect();
+                repos.sort();
+                repos
+            }
+        };
+        Ok(catalog)
+    }
+
+    // Tag files directly in the repository, not those of repositories nested under it
+    fn repo_tag_files(&self, repo_name: &str) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for entry in fs::read_dir(self.manifests_path.join(repo_name))? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn storage_usage(&self) -> Result<StorageUsage, Status> {
+        let mut repos = vec![];
+        for repo_name in self.repo_names()? {
+            match self.repo_tag_files(&repo_name) {
+                Ok(files) => repos.push((repo_name, files)),
+                // Most likely deleted since the catalog was read
+                Err(e) => warn!("Failed to read repository {}: {:?}", repo_name, e),
+            }
+        }
+        storage_usage::storage_usage(&repos, &self.blobs_path).map_err(|e| {
+            error!("Failed to work out storage usage {:?}", e);
+            Status::internal("Internal error working out storage usage")
+        })
+    }
+
+    /*
+     * Stores the images from an imported OCI layout, with the same checks as a push. Blobs are
+     * moved out of the scratch dir as they're stored.
+     */
+    async fn store_layout(
+        &self,
+        repo_name: &str,
+        tag: Option<&str>,
+        layout: &oci_layout::Layout,
+    ) -> Result<ImageImported, Status> {
+        if tag.is_some() && layout.manifests.len() != 1 {
+            return Err(Status::invalid_argument(
+                "A tag can only be given when importing a single image",
+            ));
+        }
+        // Tagged as in the archive, or by digest if it has no tag
+        let mut manifests = vec![];
+        for (digest, archive_tag) in &layout.manifests {
+            let reference = match tag.map(str::to_string).or_else(|| archive_tag.clone()) {
+                Some(t) if is_valid_tag(&t) => t,
+                Some(t) => {
+                    return Err(Status::invalid_argument(format!(
+                        "Image {} is tagged {}, which isn't a valid tag, in the archive",
+                        digest, t
+                    )))
+                }
+                None => digest.clone(),
+            };
+            manifests.push((digest.clone(), reference));
+        }
+        // Locked in order, so two imports can't each wait for a tag the other has
+        let mut references: Vec<&str> = manifests.iter().map(|(_, r)| r.as_str()).collect();
+        references.sort_unstable();
+        references.dedup();
+        let mut guards = vec![];
+        for reference in references {
+            guards.push(self.write_locks.lock_tag(repo_name, reference).await);
+            self.check_tag_writable(repo_name, reference)?;
+        }
+
+        let sizes: Vec<(String, u64)> = layout
+            .blobs
+            .iter()
+            .map(|(d, p)| (d.clone(), fs::metadata(p).map(|m| m.len()).unwrap_or(0)))
+            .collect();
+        if let Some(q) = self.quota_for(repo_name) {
+            let new_tag = manifests
+                .iter()
+                .any(|(_, r)| !is_digest(r) && !self.tag_exists(repo_name, r));
+            self.check_quota(&q, &sizes, new_tag)?;
+        }
+
+        for (digest, path) in &layout.blobs {
+            let _guard = self.write_locks.lock_blob(digest).await;
+            self.save_blob(path, digest)
+                .and_then(|_| self.link_blob(repo_name, digest))
+                .map_err(|e| {
+                    error!("Failed to store imported blob {} {:?}", digest, e);
+                    Status::internal("Internal error storing image")
+                })?;
+        }
+
+        let mut imported = vec![];
+        for (digest, reference) in manifests {
+            // Everything it refers to must be in the archive or already stored
+            let verified = self
+                .get_catalog_path_for_blob(&digest)
+                .and_then(|p| self.check_pushed_manifest(&p));
+            if let Err(e) = verified {
+                warn!("Imported manifest {} is incomplete {:?}", digest, e);
+                return Err(Status::invalid_argument(format!(
+                    "Manifest {} is invalid or refers to blobs that aren't in the archive",
+                    digest
+                )));
+            }
+            self.save_tag(&digest, repo_name, &reference)
+                .await
+                .map_err(|e| {
+                    error!("Failure cataloguing imported manifest {} {:?}", digest, e);
+                    Status::internal("Internal error storing image")
+                })?;
+            let tag = Some(reference.as_str()).filter(|r| !is_digest(r));
+            self.events
+                .publish(Event::new(EventAction::Push, repo_name, tag, &digest));
+            imported.push(ImportedManifest {
+                digest,
+                tag: tag.unwrap_or_default().to_string(),
+            });
+        }
+
+        Ok(ImageImported {
+            repo_name: repo_name.to_string(),
+            manifests: imported,
+            blobs: sizes.len() as u64,
+            bytes: sizes.iter().map(|(_, s)| s).sum(),
+        })
+    }
+
+    fn is_writable_repo(&self, repo_name: &str) -> bool {
+        if repo_name.starts_with(PROXY_DIR) {
+            return false;
+        }
+
+        true
+    }
+}
+
+#[tonic::async_trait]
+impl Registry for TrowServer {
+    async fn request_upload(
+        &self,
+        request: Request<UploadRequest>,
+    ) -> Result<Response<UploadDetails>, Status> {
+        self.check_writable()?;
+        self.check_capacity()?;
+        let repo_name = request.into_inner().repo_name;
+        check_repo_name(&repo_name)?;
+        if self.is_writable_repo(&repo_name) {
+            // Only catches namespaces that are already full, the size isn't known yet
+            if let Some(q) = self.quota_for(&repo_name) {
+                self.check_quota(&q, &[], false)?;
+            }
+            let uuid = Uuid::new_v4().to_string();
+            let reply = UploadDetails { uuid: uuid.clone() };
+            let session = Session {
+                repo_name: repo_name.clone(),
+                uuid: uuid.clone(),
+                offset: 0,
+                writing: false,
+            };
+            if let Err(e) = uploads::save(&self.uploads_path, &session) {
+                warn!("Failed to save upload session {}: {:?}", uuid, e);
+            }
+            let upload = Upload { repo_name, uuid };
+            {
+                let mut active = self.active_uploads.write().unwrap();
+                active.insert(upload);
+                uploads::ACTIVE.set(active.len() as i64);
+                debug!("Upload Table: {:?}", active);
+            }
+            Ok(Response::new(reply))
+        } else {
+            Err(Status::invalid_argument(format!(
+                "Repository {} is not writable",
+                repo_name
+            )))
+        }
+    }
+
+    async fn get_write_location_for_blob(
+        &self,
+        req: Request<UploadRef>,
+    ) -> Result<Response<WriteLocation>, Status> {
+        self.check_writable()?;
+        let br = req.into_inner();
+        check_repo_name(&br.repo_name)?;
+        check_upload_id(&br.uuid)?;
+        let upload = Upload {
+            repo_name: br.repo_name.clone(),
+            uuid: br.uuid.clone(),
+        };
+
+        // Apparently unwrap() is correct here. From the docs:
+        // "We unwrap() the return value to assert that we are not expecting
+        // threads to ever fail while holding the lock."
+
+        if self.is_active_upload(&upload) {
+            let path = self.get_upload_path_for_blob(&br.uuid);
+            let offset = uploads::load(&self.uploads_path, &br.uuid).map_or(0, |s| s.offset);
+            Ok(Response::new(WriteLocation {
+                path: path.to_string_lossy().to_string(),
+                offset,
+                part: false,
+            }))
+        } else {
+            Err(Status::failed_precondition(format!(
+                "No current upload matching {:?}",
+                br
+            )))
+        }
+    }
+
+    /*
+     * The chunk is marked in the upload's session until UploadStored, so only one is appended at a
+     * time. Backends sharing the data dir see the mark, but each only serializes its own requests,
+     * so two chunks sent at the same moment to different backends can both be let through.
+     * Clients sending chunks in order wait for each to be stored first.
+     */
+    async fn start_chunk(&self, req: Request<ChunkRef>) -> Result<Response<WriteLocation>, Status> {
+        self.check_writable()?;
+        let cr = req.into_inner();
+        check_repo_name(&cr.repo_name)?;
+        check_upload_id(&cr.uuid)?;
+        let upload = Upload {
+            repo_name: cr.repo_name.clone(),
+            uuid: cr.uuid.clone(),
+        };
+        if !self.is_active_upload(&upload) {
+            return Err(Status::failed_precondition(format!(
+                "No current upload matching {:?}",
+                cr
+            )));
+        }
+
+        let _guard = self.write_locks.lock_upload(&cr.uuid).await;
+        // Drops any data from a chunk that was cut off, which the client will send again
+        let mut session = uploads::restore(&self.uploads_path, &cr.uuid)
+            .ok_or_else(|| Status::internal("Internal error restoring upload"))?;
+        if uploads::is_writing(&self.uploads_path, &session, SystemTime::now()) {
+            return Err(Status::out_of_range(format!(
+                "Upload {} already has a chunk being written",
+                cr.uuid
+            )));
+        }
+        let offset = session.offset;
+        let invalid_range = |e: anyhow::Error| match e.downcast::<InvalidRange>() {
+            Ok(r) => Status::out_of_range(r.to_string()),
+            Err(e) => {
+                warn!("Failed to find where to write {:?}: {:?}", cr, e);
+                Status::internal("Internal error finding upload")
+            }
+        };
+
+        if cr.ranged
+            && uploads::is_ahead(&cr.uuid, offset, cr.start, cr.end).map_err(invalid_range)?
+        {
+            let path = uploads::part_location(&self.uploads_path, &cr.uuid, cr.start, cr.end)
+                .map_err(invalid_range)?;
+            return Ok(Response::new(WriteLocation {
+                path: path.to_string_lossy().to_string(),
+                offset,
+                part: true,
+            }));
+        }
+        session.writing = true;
+        uploads::save(&self.uploads_path, &session).map_err(|e| {
+            warn!("Failed to save upload session {}: {:?}", cr.uuid, e);
+            Status::internal("Failed to save upload session")
+        })?;
+        let path = self.get_upload_path_for_blob(&cr.uuid);
+        Ok(Response::new(WriteLocation {
+            path: path.to_string_lossy().to_string(),
+            offset,
+            part: false,
+        }))
+    }
+
+    async fn upload_stored(
+        &self,
+        req: Request<StoredUpload>,
+    ) -> Result<Response<UploadSaved>, Status> {
+        self.check_writable()?;
+        let su = req.into_inner();
+        check_repo_name(&su.repo_name)?;
+        check_upload_id(&su.uuid)?;
+        let upload = Upload {
+            repo_name: su.repo_name.clone(),
+            uuid: su.uuid.clone(),
+        };
+        if !self.active_uploads.read().unwrap().contains(&upload) {
+            return Err(Status::failed_precondition(format!(
+                "No current upload matching {:?}",
+                su
+            )));
+        }
+        // Also ends the chunk
+        let _guard = self.write_locks.lock_upload(&su.uuid).await;
+        let session = Session {
+            repo_name: su.repo_name,
+            uuid: su.uuid,
+            offset: su.offset,
+            writing: false,
+        };
+        uploads::save(&self.uploads_path, &session).map_err(|e| {
+            warn!("Failed to save upload session {}: {:?}", session.uuid, e);
+            Status::internal("Failed to save upload session")
+        })?;
+        Ok(Response::new(UploadSaved {}))
+    }
+
+    async fn get_read_location_for_blob(
+        &self,
+        req: Request<BlobRef>,
+    ) -> Result<Response<BlobReadLocation>, Status> {
+        metrics::TOTAL_BLOB_REQUESTS.inc();
+        let br = req.into_inner();
+        check_repo_name(&br.repo_name)?;
+        check_digest(&br.digest)?;
+        let path = self
+            .get_catalog_path_for_blob(&br.digest)
+            .map_err(|e| Status::invalid_argument(format!("Error parsing digest {:?}", e)))?;
+
+        if path.exists() {
+            if let Some(t) = &self.transcoder {
+                t.record_pull(&br.digest);
+            }
+            return Ok(Response::new(BlobReadLocation {
+                path: path.to_string_lossy().to_string(),
+                local_only: false,
+            }));
+        }
+        match self.find_rewritten_blob(&br.digest) {
+            Some(path) => Ok(Response::new(BlobReadLocation {
+                path: path.to_string_lossy().to_string(),
+                local_only: true,
+            })),
+            None => {
+                warn!("Request for unknown blob: {:?}", path);
+                Err(Status::not_found(format!(
+                    "No blob found matching {:?}",
+                    br
+                )))
+            }
+        }
+    }
+
+    async fn stat_blob(&self, req: Request<BlobRef>) -> Result<Response<BlobStat>, Status> {
+        metrics::TOTAL_BLOB_REQUESTS.inc();
+        let br = req.into_inner();
+        check_repo_name(&br.repo_name)?;
+        check_digest(&br.digest)?;
+        let path = self
+            .find_blob(&br.digest)
+            .map_err(|e| Status::invalid_argument(format!("Error parsing digest {:?}", e)))?;
+
+        match fs::metadata(&path) {
+            Ok(m) => Ok(Response::new(BlobStat {
+                digest: br.digest,
+                size: m.len(),
+            })),
+            Err(_) => Err(Status::not_found(format!(
+                "No blob found matching {:?}",
+                br
+            ))),
+        }
+    }
+
+    /**
+     * Removes the repository's link to the blob. The blob itself is only deleted once no
+     * repository links to it.
+     *
+     * TODO: check if blob referenced by manifests. If so, refuse to delete.
+     */
+    async fn delete_blob(&self, req: Request<BlobRef>) -> Result<Response<BlobDeleted>, Status> {
+        self.check_writable()?;
+        let br = req.into_inner();
+        check_repo_name(&br.repo_name)?;
+        check_digest(&br.digest)?;
+        let path = self
+            .get_catalog_path_for_blob(&br.digest)
+            .map_err(|e| Status::invalid_argument(format!("Error parsing digest {:?}", e)))?;
+        let (unlinked, others) = links::unlink(&self.links_path, &br.repo_name, &br.digest)
+            .and_then(|unlinked| {
+                links::linked_repos(&self.links_path, &br.digest).map(|o| (unlinked, o))
+            })
+            .map_err(|e| {
+                error!("Failed to unlink blob {:?} {:?}", br, e);
+                Status::internal("Internal error deleting blob")
+            })?;
+        if unlinked {
+            if let Some(link) = links::link_path(&self.links_path, &br.repo_name, &br.digest) {
+                self.store(&link).map_err(|e| {
+                    error!("Failed to unlink blob {:?} in storage {:?}", br, e);
+                    Status::internal("Internal error deleting blob")
+                })?;
+            }
+        }
+        if !others.is_empty() {
+            return if unlinked {
+                Ok(Response::new(BlobDeleted {}))
+            } else {
+                Err(Status::not_found(format!(
+                    "No blob found matching {:?}",
+                    br
+                )))
+            };
+        }
+        if !path.exists() {
+            warn!("Request for unknown blob: {:?}", path);
+            Err(Status::not_found(format!(
+                "No blob found matching {:?}",
+                br
+            )))
+        } else {
+            fs::remove_file(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| self.store(&path))
+                .map_err(|e| {
+                    error!("Failed to delete blob {:?} {:?}", br, e);
+                    Status::internal("Internal error deleting blob")
+                })
+                .and(Ok(Response::new(BlobDeleted {})))
+        }
+    }
+
+    async fn delete_manifest(
+        &self,
+        req: Request<ManifestRef>,
+    ) -> Result<Response<ManifestDeleted>, Status> {
+        self.check_writable()?;
+        let mr = req.into_inner();
+        let digest = match manifest_reference(&mr)? {
+            Reference::Digest(digest) => digest,
+            Reference::Tag(tag) => {
+                return Err(Status::invalid_argument(format!(
+                    "Manifests can only be deleted by digest. Got {}",
+                    tag
+                )))
+            }
+        };
+        //For the repo, go through all tags and see if they reference the digest. Delete them.
+        //Can only delete manifest if no other tags in any repo reference it
+
+        let ri = RepoIterator::new(&self.manifests_path.join(&mr.repo_name)).map_err(|e| {
+            error!("Problem reading manifest catalog {:?}", e);
+            Status::failed_precondition("Repository not found")
+        })?;
+
+        //TODO: error if no manifest matches?
+        let guard = self.tags_lock.write().unwrap();
+        let matching: Vec<DirEntry> = ri
+            .filter(|de| does_manifest_match_digest(de, &digest))
+            .collect();
+        let removed = match &self.trash {
+            Some(trash) => {
+                let paths: Vec<PathBuf> = matching.iter().map(|m| m.path()).collect();
+                match trash.put(&self.manifests_path, &mr.repo_name, &digest, &paths) {
+                    Ok(entry) => {
+                        info!(
+                            "Moved {}@{} to the trash as {}",
+                            mr.repo_name, digest, entry.id
+                        );
+                        matching
+                    }
+                    Err(e) => {
+                        error!("Failed to move {} to the trash {:?}", digest, e);
+                        return Err(Status::internal("Internal error deleting manifest"));
+                    }
+                }
+            }
+            None => matching
+                .into_iter()
+                .filter(|man| match fs::remove_file(man.path()) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        error!("Failed to delete manifest {:?} {:?}", &man, e);
+                        false
+                    }
+                })
+                .collect(),
+        };
+        for man in &removed {
+            let tag = man.file_name().to_string_lossy().to_string();
+            if let Some(m) = &self.metadata {
+                if let Err(e) =
+                    m.reload_tag(&self.manifests_path, &self.blobs_path, &mr.repo_name, &tag)
+                {
+                    error!("Failed to remove {} from metadata database {:?}", tag, e);
+                }
+            }
+            if let Some(index) = &self.repo_index {
+                index.remove(&mr.repo_name, &tag);
+            }
+        }
+        drop(guard);
+        // Into the trash first, so the bucket always has the tags somewhere
+        let stored = match &self.trash {
+            Some(t) => self.store_dir(t.path()),
+            None => Ok(()),
+        }
+        .and_then(|_| removed.iter().try_for_each(|man| self.store(&man.path())));
+        if let Err(e) = stored {
+            error!("Failed to delete {} from storage {:?}", digest, e);
+            return Err(Status::internal("Internal error deleting manifest"));
+        }
+        self.events.publish(Event::new(
+            EventAction::Delete,
+            &mr.repo_name,
+            None,
+            &digest,
+        ));
+
+        Ok(Response::new(ManifestDeleted {}))
+    }
+
+    async fn get_write_details_for_manifest(
+        &self,
+        req: Request<ManifestRef>,
+    ) -> Result<Response<ManifestWriteDetails>, Status> {
+        self.check_writable()?;
+        let mr = req.into_inner();
+        let reference = manifest_reference(&mr)?;
+        let repo_name = mr.repo_name;
+        if self.is_writable_repo(&repo_name) {
+            if let Some(tag) = reference.tag() {
+                self.check_tag_writable(&repo_name, tag)?;
+            }
+
+            //Give the manifest a UUID and save it to the uploads dir
+            let uuid = Uuid::new_v4().to_string();
+
+            let manifest_path = self.get_upload_path_for_blob(&uuid);
+            Ok(Response::new(ManifestWriteDetails {
+                path: manifest_path.to_string_lossy().to_string(),
+                uuid,
+            }))
+        } else {
+            Err(Status::invalid_argument(format!(
+                "Repository {} is not writable",
+                repo_name
+            )))
+        }
+    }
+
+    async fn get_read_location_for_manifest(
+        &self,
+        req: Request<ManifestRef>,
+    ) -> Result<Response<ManifestReadLocation>, Status> {
+        //Don't actually need to verify here; could set to false
+
+        let mr = req.into_inner();
+        metrics::TOTAL_MANIFEST_REQUESTS.inc();
+        let reference = manifest_reference(&mr)?;
+        // TODO refactor to return directly
+        match self
+            .create_manifest_read_location(
+                mr.repo_name.clone(),
+                reference.as_str().to_string(),
+                true,
+            )
+            .await
+        {
+            Ok(vm) => {
+                self.count_pull(&mr.repo_name, reference.as_str());
+                Ok(Response::new(vm))
+            }
+            Err(e) => {
+                warn!("Internal error with manifest {:?}", e);
+                Err(Status::internal("Internal error finding manifest"))
+            }
+        }
+    }
+
+    async fn get_transcoded_manifest(
+        &self,
+        req: Request<TranscodedManifestRef>,
+    ) -> Result<Response<ManifestReadLocation>, Status> {
+        let tr = req.into_inner();
+        check_repo_name(&tr.repo_name)?;
+        if !is_valid_tag(&tr.reference) && !is_valid_digest(&tr.reference) {
+            return Err(Status::invalid_argument(format!(
+                "Invalid manifest reference {}",
+                tr.reference
+            )));
+        }
+        metrics::TOTAL_MANIFEST_REQUESTS.inc();
+        // eStargz is gzip too, but a conversion rather than a rendition
+        let prefer = match tr.compression.as_str() {
+            "estargz" => None,
+            c => Some(
+                c.parse::<Compression>()
+                    .map_err(|e| Status::invalid_argument(e.to_string()))?,
+            ),
+        };
+        let not_enabled = match prefer {
+            Some(_) if self.transcoder.is_none() => Some("Layer transcoding isn't enabled"),
+            None if self.estargz.is_none() => Some("eStargz conversion isn't enabled"),
+            _ => None,
+        };
+        if let Some(msg) = not_enabled {
+            return Err(Status::not_found(msg));
+        }
+
+        let (digest, path) = self
+            .find_manifest(&tr.repo_name, &tr.reference)
+            .map_err(|_| Status::not_found("Manifest not found"))?;
+        let res = self
+            .get_manifest_media_type(&digest, &path)
+            .and_then(
+                |content_type| match (prefer, &self.transcoder, &self.estargz) {
+                    (Some(c), Some(t), _) => t.variant(&digest, &content_type, c),
+                    (None, _, Some(e)) => e.variant(&digest, &content_type),
+                    _ => Ok(None),
+                },
+            );
+        let prefer = tr.compression;
+        match res {
+            Ok(Some(variant)) => {
+                self.remember_media_type(&variant.digest, &variant.content_type);
+                Ok(Response::new(ManifestReadLocation {
+                    digest: variant.digest,
+                    path: variant.path.to_string_lossy().to_string(),
+                    content_type: variant.content_type,
+                }))
+            }
+            Ok(None) => Err(Status::not_found(format!(
+                "No {} layers for {}@{}",
+                prefer, tr.repo_name, digest
+            ))),
+            Err(e) => {
+                warn!(
+                    "Failed to rewrite {} for {} layers: {:?}",
+                    digest, prefer, e
+                );
+                Err(Status::internal("Internal error rewriting manifest"))
+            }
+        }
+    }
+
+    async fn stat_manifest(
+        &self,
+        req: Request<ManifestRef>,
+    ) -> Result<Response<ManifestStat>, Status> {
+        let mr = req.into_inner();
+        metrics::TOTAL_MANIFEST_REQUESTS.inc();
+        let reference = manifest_reference(&mr)?;
+
+        if self
+            .get_proxy_address_and_auth(&mr.repo_name, reference.as_str())
+            .is_some()
+        {
+            // Checks upstream for a newer version first, the same as a pull
+            if let Err(e) = self
+                .create_manifest_read_location(
+                    mr.repo_name.clone(),
+                    reference.as_str().to_string(),
+                    false,
+                )
+                .await
+            {
+                warn!("Error finding proxied manifest {:?}", e);
+                return Err(Status::not_found("Manifest not found"));
+            }
+        }
+
+        let res = self
+            .find_manifest(&mr.repo_name, reference.as_str())
+            .and_then(|(digest, path)| {
+                let size = fs::metadata(&path)?.len();
+                let content_type = self.get_manifest_media_type(&digest, &path)?;
+                Ok(ManifestStat {
+                    digest,
+                    size,
+                    content_type,
+                })
+            });
+        match res {
+            Ok(stat) => Ok(Response::new(stat)),
+            Err(e) => {
+                debug!("No manifest for {:?}: {:?}", mr, e);
+                Err(Status::not_found("Manifest not found"))
+            }
+        }
+    }
+
+    /**
+     * Take uploaded manifest (which should be uuid in uploads), check it, put in catalog and
+     * by blob digest
+     */
+    async fn verify_manifest(
+        &self,
+        req: Request<VerifyManifestRequest>,
+    ) -> Result<Response<VerifiedManifest>, Status> {
+        self.check_writable()?;
+        let req = req.into_inner();
+        let mr = req.manifest.unwrap(); // Pissed off that the manifest is optional!
+        let reference = manifest_reference(&mr)?;
+        check_upload_id(&req.uuid)?;
+        let uploaded_manifest = self.get_upload_path_for_blob(&req.uuid);
+
+        match self
+            .check_pushed_manifest(&uploaded_manifest)
+            .and_then(|_| self.create_verified_manifest(&uploaded_manifest, false))
+        {
+            Ok(vm) => {
+                // Held until the tag is saved, so another push can't get in between the checks
+                let _guard = self
+                    .write_locks
+                    .lock_tag(&mr.repo_name, reference.as_str())
+                    .await;
+                // Another push to the tag may have finished since the write details were given
+                if let Some(tag) = reference.tag() {
+                    self.check_tag_writable(&mr.repo_name, tag)?;
+                }
+
+                // Layers may have been uploaded to another repo, so also need checking here
+                if let Some(q) = self.quota_for(&mr.repo_name) {
+                    let new_tag = !self.tag_exists(&mr.repo_name, reference.as_str());
+                    let blobs = self
+                        .manifest_blobs(&uploaded_manifest, &vm.digest)
+                        .map_err(|e| {
+                            error!("Error reading manifest for quota check {:?}", e);
+                            Status::internal("Internal error checking quota")
+                        })?;
+                    self.check_quota(&q, &blobs, new_tag)?;
+                }
+
+                // copy manifest to blobs and add tag
+                let digest = vm.digest.clone();
+                let _blob_guard = self.write_locks.lock_blob(&digest).await;
+                let failed = |e: anyhow::Error| {
+                    error!(
+                        "Failure cataloguing manifest {}/{} {:?}",
+                        &mr.repo_name,
+                        reference.as_str(),
+                        e
+                    );
+                    Status::internal("Internal error copying manifest")
+                };
+                // The tag mustn't point at a manifest that wasn't stored
+                self.save_blob(&uploaded_manifest, &digest)
+                    .map_err(failed)?;
+                self.link_blob(&mr.repo_name, &digest).map_err(failed)?;
+                self.save_tag(&digest, &mr.repo_name, reference.as_str())
+                    .await
+                    .map_err(failed)?;
+
+                self.events.publish(Event::new(
+                    EventAction::Push,
+                    &mr.repo_name,
+                    reference.tag(),
+                    &digest,
+                ));
+                Ok(Response::new(vm))
+            }
+            Err(e) => {
+                error!("Error verifying manifest {:?}", e);
+                if let Some(missing) = e.downcast_ref::<MissingBlobError>() {
+                    // The digest is the message, so the frontend can tell the client which
+                    Err(Status::not_found(missing.digest.clone()))
+                } else if e.is::<InvalidManifest>() {
+                    // Tells the client what's wrong with it
+                    Err(Status::invalid_argument(e.to_string()))
+                } else {
+                    Err(Status::invalid_argument("Failed to verify manifest"))
+                }
+            }
+        }
+    }
+
+    async fn complete_upload(
+        &self,
+        req: Request<CompleteRequest>,
+    ) -> Result<Response<CompletedUpload>, Status> {
+        self.check_writable()?;
+        let cr = req.into_inner();
+        check_repo_name(&cr.repo_name)?;
+        check_upload_id(&cr.uuid)?;
+        check_digest(&cr.user_digest)?;
+        // Held until the session is removed, so no chunk can start while the blob is saved
+        let _upload_guard = self.write_locks.lock_upload(&cr.uuid).await;
+        let writing = uploads::load(&self.uploads_path, &cr.uuid).map_or(false, |s| {
+            uploads::is_writing(&self.uploads_path, &s, SystemTime::now())
+        });
+        if writing {
+            return Err(Status::out_of_range(format!(
+                "Upload {} still has a chunk being written",
+                cr.uuid
+            )));
+        }
+        let scratch_path = self.get_upload_path_for_blob(&cr.uuid);
+        // Left in progress if a range is missing, so the client can send it and complete again
+        if let Err(e) = uploads::assemble(&self.uploads_path, &cr.uuid) {
+            return match e.downcast::<InvalidRange>() {
+                Ok(r) => Err(Status::out_of_range(r.to_string())),
+                Err(e) => {
+                    warn!("Failed to assemble upload {}: {:?}", cr.uuid, e);
+                    Err(Status::internal("Internal error saving layer"))
+                }
+            };
+        }
+        // Waits for any other upload of the blob, which this one then finds already stored
+        let blob_guard = self.write_locks.lock_blob(&cr.user_digest).await;
+        let quota_check = match self.quota_for(&cr.repo_name) {
+            Some(q) => {
+                let size = fs::metadata(&scratch_path).map(|m| m.len()).unwrap_or(0);
+                self.check_quota(&q, &[(cr.user_digest.clone(), size)], false)
+            }
+            None => Ok(()),
+        };
+
+        let ret = match quota_check {
+            Err(e) => {
+                if let Err(e) = fs::remove_file(&scratch_path) {
+                    warn!(
+                        "Failed to remove rejected upload {:?}: {:?}",
+                        scratch_path, e
+                    );
+                }
+                Err(e)
+            }
+            Ok(_) => match self.validate_and_save_blob(&cr.repo_name, &cr.user_digest, &cr.uuid) {
+                Ok(_) => Ok(Response::new(CompletedUpload {
+                    digest: cr.user_digest.clone(),
+                })),
+                Err(e) => match e.downcast::<DigestValidationError>() {
+                    Ok(v_e) => Err(Status::invalid_argument(v_e.to_string())),
+                    Err(e) => {
+                        warn!("Failure when saving layer: {:?}", e);
+                        Err(Status::internal("Internal error saving layer"))
+                    }
+                },
+            },
+        };
+        drop(blob_guard);
+
+        //delete uuid from uploads tracking
+        let upload = Upload {
+            repo_name: cr.repo_name.clone(),
+            uuid: cr.uuid,
+        };
+
+        {
+            let mut active = self.active_uploads.write().unwrap();
+            if !active.remove(&upload) {
+                warn!("Upload {:?} not found when deleting", upload);
+            }
+            uploads::ACTIVE.set(active.len() as i64);
+        }
+        uploads::remove(&self.uploads_path, &upload.uuid);
+        ret
+    }
+
+    type GetCatalogStream = ReceiverStream<Result<CatalogEntry, Status>>;
+
+    async fn get_catalog(
+        &self,
+        request: Request<CatalogRequest>,
+    ) -> Result<Response<Self::GetCatalogStream>, Status> {
+        let cr = request.into_inner();
+        let limit = cr.limit as usize;
+
+        let (tx, rx) = mpsc::channel(4);
+        let catalog = self
+            .repo_names()?
+            .into_iter()
+            .filter(|r| r.starts_with(&cr.prefix))
+            .filter(|r| cr.user.is_empty() || self.tenants.can_see(&cr.user, r));
+        let partial_catalog: Vec<String> = if cr.last_repo.is_empty() {
+            catalog.into_iter().take(limit).collect()
+        } else {
+            catalog
+                .into_iter()
+                .skip_while(|t| t != &cr.last_repo)
+                .skip(1)
+                .take(limit)
+                .collect()
+        };
+
+        tokio::spawn(async move {
+            for repo_name in partial_catalog {
+                let ce = CatalogEntry { repo_name };
+
+                tx.send(Ok(ce)).await.expect("Error streaming catalog");
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type ListTagsStream = ReceiverStream<Result<Tag, Status>>;
+
+    async fn list_tags(
+        &self,
+        request: Request<ListTagsRequest>,
+    ) -> Result<Response<Self::ListTagsStream>, Status> {
+        let (tx, rx) = mpsc::channel(4);
+        let mut path = PathBuf::from(&self.manifests_path);
+
+        let ltr = request.into_inner();
+        check_repo_name(&ltr.repo_name)?;
+
+        let limit = ltr.limit as usize;
+        path.push(&ltr.repo_name);
+        if !path.is_dir() {
+            return Err(Status::not_found(format!(
+                "Repository {} not found",
+                ltr.repo_name
+            )));
+        }
+
+        let _guard = self.tags_lock.read().unwrap();
+        let catalog: Vec<String> = match (&self.repo_index, &self.metadata) {
+            (Some(index), _) => index.tags(&ltr.repo_name).unwrap_or_default(),
+            (None, Some(m)) => m.tags(&ltr.repo_name).map_err(|e| {
+                error!("Error reading tags from metadata database {:?}", e);
+                Status::internal("Internal error streaming catalog")
+            })?,
+            (None, None) => {
+                let mut tags: Vec<String> = RepoIterator::new(&path)
+                    .map_err(|e| {
+                        error!("Error accessing catalog {:?}", e);
+                        Status::internal("Internal error streaming catalog")
+                    })?
+                    .map(|de| de.path().file_name().unwrap().to_string_lossy().to_string())
+                    .collect();
+                tags.sort();
+                tags
+            }
+        };
+        drop(_guard);
+        let partial_catalog: Vec<String> = if ltr.last_tag.is_empty() {
+            catalog.into_iter().take(limit).collect()
+        } else {
+            catalog
+                .into_iter()
+                .skip_while(|t| t != &ltr.last_tag)
+                .skip(1)
+                .take(limit)
+                .collect()
+        };
+
+        tokio::spawn(async move {
+            for tag in partial_catalog {
+                tx.send(Ok(Tag {
+                    tag: tag.to_string(),
+                }))
+                .await
+                .expect("Error streaming tags");
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type GetManifestHistoryStream = ReceiverStream<Result<ManifestHistoryEntry, Status>>;
+
+    async fn get_manifest_history(
+        &self,
+        request: Request<ManifestHistoryRequest>,
+    ) -> Result<Response<Self::GetManifestHistoryStream>, Status> {
+        let mr = request.into_inner();
+        check_repo_name(&mr.repo_name)?;
+        if !is_valid_tag(&mr.tag) {
+            return Err(Status::invalid_argument(
+                "Require valid tag (not digest) to search for history",
+            ));
+        }
+
+        let manifest_path = self.manifests_path.join(&mr.repo_name).join(&mr.tag);
+
+        let file = File::open(&manifest_path);
+
+        if file.is_err() {
+            return Err(Status::not_found(format!(
+                "Could not find the requested manifest at: {}",
+                &manifest_path.to_str().unwrap()
+            )));
+        }
+
+        // It's safe to unwrap here
+        let reader = BufReader::new(file.unwrap());
+
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            let mut searching_for_digest = mr.last_digest != ""; //Looking for a digest iff it's not empty
+
+            let mut sent = 0;
+            for line in reader.lines() {
+                if let Ok(line) = line {
+                    let (digest, date) = match line.find(' ') {
+                        Some(ind) => {
+                            let (digest_str, date_str) = line.split_at(ind);
+
+                            if searching_for_digest {
+                                if digest_str == mr.last_digest {
+                                    searching_for_digest = false;
+                                }
+                                //Remember we want digest following matched digest
+                                continue;
+                            }
+
+                            let dt_r = DateTime::parse_from_rfc3339(date_str.trim());
+
+                            let ts = if let Ok(dt) = dt_r {
+                                Some(Timestamp {
+                                    seconds: dt.timestamp(),
+                                    nanos: dt.timestamp_subsec_nanos() as i32,
+                                })
+                            } else {
+                                warn!("Failed to parse timestamp {}", date_str);
+                                None
+                            };
+                            (digest_str, ts)
+                        }
+                        None => {
+                            warn!("No timestamp found in manifest");
+                            (line.as_ref(), None)
+                        }
+                    };
+
+                    let entry = ManifestHistoryEntry {
+                        digest: digest.to_string(),
+                        date,
+                    };
+                    tx.send(Ok(entry))
+                        .await
+                        .expect("Error streaming manifest history");
+
+                    sent += 1;
+                    if sent >= mr.limit {
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    // Readiness check
+    async fn is_ready(
+        &self,
+        _request: Request<ReadinessRequest>,
+    ) -> Result<Response<ReadyStatus>, Status> {
+        for path in &[
+            &self.scratch_path,
+            &self.uploads_path,
+            &self.manifests_path,
+            &self.blobs_path,
+        ] {
+            match is_path_writable(path) {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Err(Status::unavailable(format!(
+                        "{} is not writable",
+                        path.to_string_lossy()
+                    )));
+                }
+                Err(error) => {
+                    return Err(Status::unavailable(error.to_string()));
+                }
+            }
         }
 
-        resp.json::<serde_json::Value>()
-            .await
-            .map_err(|e| anyhow!("Failed to deserialize auth response {}", e))?
-            .get("access_token")
-            .map(|s| s.as_str().unwrap_or(""))
-            .map(|s| strip_dquotes(s).unwrap_or(s).to_string())
-            .ok_or(anyhow!("Failed to find auth token in auth repsonse"))
+        //All paths writable
+        let reply = trow_server::ReadyStatus {
+            message: String::from("Ready"),
+        };
+
+        Ok(Response::new(reply))
     }
 
-    async fn get_www_authenticate_header(
+    async fn is_healthy(
         &self,
-        cl: &reqwest::Client,
-        image: &Image,
-    ) -> Result<String> {
-        let resp = cl
-            .head(&image.get_manifest_url())
-            .headers(create_accept_header())
-            .send()
-            .await
-            .map_err(|e| {
-                anyhow!(
-                    "Attempt to authenticate to {} failed with: {}",
-                    &image.get_manifest_url(),
-                    e
-                )
-            })?;
+        _request: Request<HealthRequest>,
+    ) -> Result<Response<HealthStatus>, Status> {
+        let reply = trow_server::HealthStatus {
+            message: String::from("Healthy"),
+        };
+        Ok(Response::new(reply))
+    }
 
-        if resp.status() != reqwest::StatusCode::UNAUTHORIZED {
-            return Err(anyhow!(
-                "Expected request '{}' to fail with status unauthorized",
-                &image.get_manifest_url()
-            ));
+    async fn get_metrics(
+        &self,
+        _request: Request<MetricsRequest>,
+    ) -> Result<Response<MetricsResponse>, Status> {
+        match metrics::gather_metrics(&self.blobs_path) {
+            Ok(metrics) => {
+                let reply = trow_server::MetricsResponse { metrics };
+                Ok(Response::new(reply))
+            }
+
+            Err(error) => Err(Status::unavailable(error.to_string())),
         }
+    }
 
-        resp.headers()
-            .get("www-authenticate")
-            .ok_or(anyhow!(
-                "Expected www-authenticate header to identify authentication server"
-            ))
-            .and_then(|v| {
-                v.to_str()
-                    .map_err(|e| anyhow!("Failed to read auth header {:?}", e))
-            })
-            .map(|s| s.to_string())
+    async fn start_job(
+        &self,
+        request: Request<StartJobRequest>,
+    ) -> Result<Response<JobStatus>, Status> {
+        let kind: JobKind = request
+            .into_inner()
+            .kind
+            .parse()
+            .map_err(|e: anyhow::Error| Status::invalid_argument(e.to_string()))?;
+
+        let manifests_path = self.manifests_path.clone();
+        let blobs_path = self.blobs_path.clone();
+        let links_path = self.links_path.clone();
+        let metadata = self.metadata.clone();
+        let trash = self.trash.clone();
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        let job = match kind {
+            JobKind::GarbageCollect => self.jobs.start(kind, move |h| {
+                let gc = maintenance::garbage_collect(
+                    &manifests_path,
+                    &blobs_path,
+                    &links_path,
+                    metadata.as_deref(),
+                    trash.as_ref(),
+                    &events,
+                    h,
+                )?;
+                sync_collected(storage.as_ref(), &blobs_path, &links_path, trash.as_ref())?;
+                Ok(gc)
+            }),
+            JobKind::Scrub => self.start_scrub_job(),
+            JobKind::Retention => {
+                self.check_writable()?;
+                self.start_retention_job(false)
+            }
+            JobKind::Usage => self.start_usage_job(),
+            JobKind::ProxyCheck => self.start_proxy_check_job(),
+            JobKind::Backup => self.start_backup_job(),
+            JobKind::Archive => self.start_archive_job(),
+            JobKind::Restore => self.start_restore_job(),
+            JobKind::Transcode => self.start_transcode_job(),
+            JobKind::Estargz => self.start_estargz_job(),
+            JobKind::Prewarm => {
+                return Err(Status::invalid_argument(
+                    "Prewarm jobs are started for an image with PrewarmImage",
+                ))
+            }
+        };
+        Ok(Response::new(job_status(job)))
     }
 
-    fn get_bearer_param_map(www_authenticate_header: String) -> HashMap<String, String> {
-        let base = www_authenticate_header.strip_prefix("Bearer ");
+    async fn prewarm_image(
+        &self,
+        request: Request<PrewarmRequest>,
+    ) -> Result<Response<JobStatus>, Status> {
+        let pr = request.into_inner();
+        check_repo_name(&pr.repo_name)?;
+        if !is_valid_tag(&pr.reference) && !is_valid_digest(&pr.reference) {
+            return Err(Status::invalid_argument(format!(
+                "Invalid manifest reference {}",
+                pr.reference
+            )));
+        }
+        // Proxied images are written to the data dir as they're fetched
+        if self
+            .get_proxy_address_and_auth(&pr.repo_name, &pr.reference)
+            .is_some()
+        {
+            self.check_writable()?;
+        }
+        let job = self.start_prewarm_job(pr.repo_name, pr.reference, pr.notify);
+        Ok(Response::new(job_status(job)))
+    }
 
-        base.unwrap_or("")
-            .split(',')
-            .map(|kv| kv.split('=').collect::<Vec<&str>>())
-            .map(|vec| {
-                (
-                    vec[0].to_string(),
-                    strip_dquotes(vec[1]).unwrap_or(vec[1]).to_string(),
-                )
-            })
-            .collect()
+    async fn get_job(&self, request: Request<JobRef>) -> Result<Response<JobStatus>, Status> {
+        let id = request.into_inner().id;
+        match self.jobs.get(&id) {
+            Some(job) => Ok(Response::new(job_status(job))),
+            None => Err(Status::not_found(format!("Job {} not found", id))),
+        }
     }
 
-    async fn get_digest_from_header(
+    type ListJobsStream = ReceiverStream<Result<JobStatus, Status>>;
+
+    async fn list_jobs(
         &self,
-        cl: &reqwest::Client,
-        image: &Image,
-        auth_token: &Option<String>,
-    ) -> Option<String> {
-        let resp = if let Some(auth) = auth_token {
-            cl.head(&image.get_manifest_url())
-                .bearer_auth(&auth)
-                .headers(create_accept_header())
-                .send()
-                .await
-        } else {
-            cl.head(&image.get_manifest_url())
-                .headers(create_accept_header())
-                .send()
-                .await
-        };
+        _request: Request<ListJobsRequest>,
+    ) -> Result<Response<Self::ListJobsStream>, Status> {
+        let (tx, rx) = mpsc::channel(4);
+        let jobs = self.jobs.list();
 
-        let resp = match resp {
-            Ok(r) => r,
-            Err(e) => {
-                error!("Remote registry didn't respond to HEAD request {}", e);
-                return None;
+        tokio::spawn(async move {
+            for job in jobs {
+                tx.send(Ok(job_status(job)))
+                    .await
+                    .expect("Error streaming jobs");
             }
-        };
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
 
-        if let Some(digest) = resp.headers().get(DIGEST_HEADER) {
-            let digest = format!("{:?}", digest);
-            Some(digest.trim_matches('"').to_string())
-        } else {
-            None
+    async fn cancel_job(&self, request: Request<JobRef>) -> Result<Response<JobStatus>, Status> {
+        let id = request.into_inner().id;
+        match self.jobs.cancel(&id) {
+            Some(job) => Ok(Response::new(job_status(job))),
+            None => Err(Status::not_found(format!("Job {} not found", id))),
         }
     }
 
-    async fn create_manifest_read_location(
+    async fn get_quota_usage(
         &self,
-        repo_name: String,
-        reference: String,
-        do_verification: bool,
-    ) -> Result<ManifestReadLocation> {
-        if let Some((proxy_image, proxy_auth)) =
-            self.get_proxy_address_and_auth(&repo_name, &reference)
-        {
-            //TODO: May want to consider download tracking in case of simultaneous requests
-            //In short term this isn't a big problem as should just copy over itself in worst case
-            info!(
-                "Request for proxied repo {}:{} maps to {}",
-                repo_name, reference, proxy_image
-            );
-
-            let cl = reqwest::Client::new();
-
-            let mut have_manifest = false;
+        request: Request<QuotaUsageRequest>,
+    ) -> Result<Response<QuotaUsage>, Status> {
+        let repo_name = request.into_inner().repo_name;
+        check_repo_name(&repo_name)?;
+        let q = self.quota_for(&repo_name);
+        let name = q.as_ref().map(|q| q.name.clone()).unwrap_or(repo_name);
 
-            //Get auth token for remote server.
-            //TODO: Consider caching
-            let auth_token = match self.get_auth_token(&cl, &proxy_image, &proxy_auth).await {
-                Ok(a) => Some(a),
-                Err(e) => {
-                    error!("Failed to get auth token for {}. Error: {}", proxy_image, e);
-                    None
-                }
-            };
+        let usage = quota::usage(&self.manifests_path, &self.blobs_path, &name).map_err(|e| {
+            error!("Failed to work out usage of {}: {:?}", name, e);
+            Status::internal("Internal error working out usage")
+        })?;
+        Ok(Response::new(QuotaUsage {
+            name,
+            bytes: usage.bytes,
+            images: usage.images,
+            max_bytes: q.as_ref().and_then(|q| q.max_bytes).unwrap_or(0),
+            max_images: q.as_ref().and_then(|q| q.max_images).unwrap_or(0),
+        }))
+    }
 
-            let digest = self
-                .get_digest_from_header(&cl, &proxy_image, &auth_token)
-                .await;
+    async fn check_upload(
+        &self,
+        request: Request<UploadCheckRequest>,
+    ) -> Result<Response<UploadCheck>, Status> {
+        let req = request.into_inner();
+        check_repo_name(&req.repo_name)?;
+        let q = self.quota_for(&req.repo_name);
+        let name = q
+            .as_ref()
+            .map(|q| q.name.clone())
+            .unwrap_or_else(|| req.repo_name.clone());
+
+        let usage = quota::usage(&self.manifests_path, &self.blobs_path, &name).map_err(|e| {
+            error!("Failed to work out usage of {}: {:?}", name, e);
+            Status::internal("Internal error working out usage")
+        })?;
+        // The upload is written to one and then kept in the other
+        let disk_available = [&self.uploads_path, &self.scratch_path]
+            .into_iter()
+            .map(|path| {
+                fs3::available_space(path).map_err(|e| {
+                    error!("Failed to find free space in {:?}: {:?}", path, e);
+                    Status::internal("Internal error checking disk space")
+                })
+            })
+            .collect::<Result<Vec<u64>, Status>>()?
+            .into_iter()
+            .min()
+            .unwrap_or(0);
+
+        let refusal = match self.check_upload_allowed(&req, &usage, disk_available) {
+            Ok(()) => String::new(),
+            Err(s) => s.message().to_string(),
+        };
+        Ok(Response::new(UploadCheck {
+            refusal,
+            disk_available,
+            quota: Some(QuotaUsage {
+                name,
+                bytes: usage.bytes,
+                images: usage.images,
+                max_bytes: q.as_ref().and_then(|q| q.max_bytes).unwrap_or(0),
+                max_images: q.as_ref().and_then(|q| q.max_images).unwrap_or(0),
+            }),
+        }))
+    }
 
-            if let Some(digest) = digest {
-                if self.get_catalog_path_for_blob(&digest)?.exists() {
-                    info!(
-                        "Have up to date manifest for {} digest {}",
-                        repo_name, digest
-                    );
-                    have_manifest = true;
+    type DryRunRetentionStream = ReceiverStream<Result<RetentionDeletion, Status>>;
 
-                    //Make sure our tag exists and is up-to-date
-                    if !is_digest(&reference) {
-                        let our_digest = self.get_digest_from_manifest(&repo_name, &reference);
-                        if our_digest.is_err() || (our_digest.unwrap() != digest) {
-                            let res = self.save_tag(&digest, &repo_name, &reference).await;
-                            if res.is_err() {
-                                error!(
-                                    "Internal error updating tag for proxied image {:?}",
-                                    res.unwrap()
-                                );
-                            }
-                        }
-                    }
-                }
-            }
+    async fn dry_run_retention(
+        &self,
+        _request: Request<RetentionRequest>,
+    ) -> Result<Response<Self::DryRunRetentionStream>, Status> {
+        let deletions = retention::plan(
+            &self.manifests_path,
+            &self.blobs_path,
+            &self.retention_rules(),
+            SystemTime::now(),
+        )
+        .map_err(|e| {
+            error!("Failed to evaluate retention rules: {:?}", e);
+            Status::internal("Internal error evaluating retention rules")
+        })?;
 
-            if !have_manifest {
-                if let Err(e) = self
-                    .download_manifest_and_layers(&cl, &auth_token, &proxy_image, &repo_name)
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            for d in deletions {
+                let entry = RetentionDeletion {
+                    repo_name: d.repo_name,
+                    tag: d.tag.unwrap_or_default(),
+                    digest: d.digest,
+                    history_only: d.history_only,
+                    rule: d.rule,
+                };
+                tx.send(Ok(entry))
                     .await
-                {
-                    //Note that we may still have an out-of-date version that will be returned
-                    error!("Failed to download proxied image {}", e);
-                }
+                    .expect("Error streaming retention report");
             }
-        }
-
-        //TODO: This isn't optimal
-        let path = self.get_path_for_manifest(&repo_name, &reference)?;
-        let vm = self.create_verified_manifest(&path, do_verification)?;
-        Ok(ManifestReadLocation {
-            content_type: vm.content_type.to_owned(),
-            digest: vm.digest,
-            path: path.to_string_lossy().to_string(),
-        })
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 
-    /// Moves blob from scratch to blob catalog
-    fn save_blob(&self, scratch_path: &Path, digest: &str) -> Result<()> {
-        let digest_path = self.get_catalog_path_for_blob(digest)?;
-        let repo_path = digest_path
-            .parent()
-            .ok_or_else(|| anyhow!("Error finding repository path"))?;
+    type ListRepositoriesStream = ReceiverStream<Result<RepositoryInfo, Status>>;
 
-        if !repo_path.exists() {
-            fs::create_dir_all(repo_path)?;
+    async fn list_repositories(
+        &self,
+        _request: Request<ListRepositoriesRequest>,
+    ) -> Result<Response<Self::ListRepositoriesStream>, Status> {
+        let mut repos = vec![];
+        for repo_name in self.repo_names()? {
+            let info = self
+                .repo_tag_files(&repo_name)
+                .map_err(anyhow::Error::from)
+                .and_then(|files| {
+                    let digests = maintenance::referenced_by_tags(&files, &self.blobs_path)?;
+                    let tags = files
+                        .iter()
+                        .filter_map(|f| f.file_name())
+                        .filter(|t| !is_digest(&t.to_string_lossy()))
+                        .count();
+                    Ok(RepositoryInfo {
+                        repo_name: repo_name.clone(),
+                        tags: tags as u64,
+                        bytes: digests
+                            .iter()
+                            .map(|d| quota::blob_size(&self.blobs_path, d))
+                            .sum(),
+                    })
+                });
+            match info {
+                Ok(info) => repos.push(info),
+                // Most likely deleted since the catalog was read
+                Err(e) => warn!("Failed to read repository {}: {:?}", repo_name, e),
+            }
         }
-        fs::rename(&scratch_path, &digest_path)?;
-        Ok(())
-    }
-
-    fn validate_and_save_blob(&self, user_digest: &str, uuid: &str) -> Result<()> {
-        debug!("Saving blob {}", user_digest);
-
-        let scratch_path = self.get_upload_path_for_blob(uuid);
-        let res = match validate_digest(&scratch_path, user_digest) {
-            Ok(_) => self.save_blob(&scratch_path, user_digest),
-            Err(e) => Err(e),
-        };
 
-        res?;
-        Ok(())
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            for info in repos {
+                tx.send(Ok(info))
+                    .await
+                    .expect("Error streaming repositories");
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 
-    //Support functions for validate, would like to move these
-    pub fn image_exists(&self, image: &Image) -> bool {
-        match self.get_path_for_manifest(&image.repo, &image.tag) {
-            Ok(f) => f.exists(),
-            Err(_) => false,
-        }
+    async fn get_repo_usage(
+        &self,
+        request: Request<RepositoryRef>,
+    ) -> Result<Response<RepoUsage>, Status> {
+        let repo_name = request.into_inner().repo_name;
+        check_repo_name(&repo_name)?;
+        let usage = self.storage_usage()?;
+        usage
+            .repositories
+            .into_iter()
+            .find(|r| r.repo_name == repo_name)
+            .map(|r| Response::new(repo_usage(r)))
+            .ok_or_else(|| Status::not_found(format!("Repository {} not found", repo_name)))
     }
 
-    pub fn is_local_denied(&self, image: &Image) -> bool {
-        //Try matching both with and without host name
-        //Deny images are expected without host as always local
-        let full_name = format!("{}", image);
-        let name_without_host = format!("{}:{}", image.repo, image.tag);
+    async fn get_registry_usage(
+        &self,
+        _request: Request<RegistryUsageRequest>,
+    ) -> Result<Response<RegistryUsage>, Status> {
+        let usage = self.storage_usage()?;
+        let registry = usage.registry;
+        Ok(Response::new(RegistryUsage {
+            repositories: registry.repositories,
+            tags: registry.tags,
+            blobs: registry.blobs,
+            bytes: registry.bytes,
+            stored_blobs: registry.stored_blobs,
+            stored_bytes: registry.stored_bytes,
+            repositories_by_size: usage.repositories.into_iter().map(repo_usage).collect(),
+        }))
+    }
 
-        for prefix in &self.deny_local_prefixes {
-            if full_name.starts_with(prefix) || name_without_host.starts_with(prefix) {
-                info!("Image {} matches prefix {} on deny list", image, prefix);
-                return true;
-            }
+    async fn delete_repository(
+        &self,
+        request: Request<RepositoryRef>,
+    ) -> Result<Response<RepositoryDeleted>, Status> {
+        self.check_writable()?;
+        let repo_name = request.into_inner().repo_name;
+        if !is_valid_repo_name(&repo_name) {
+            return Err(Status::invalid_argument(format!(
+                "Invalid repository name {}",
+                repo_name
+            )));
         }
 
-        for name in &self.deny_local_images {
-            if &full_name == name || &name_without_host == name {
-                info!("Image {} matches image {} on deny list", image, name);
-                return true;
+        let guard = self.tags_lock.write().unwrap();
+        let files = match self.repo_tag_files(&repo_name) {
+            Ok(files) => files,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => {
+                error!("Failed to read repository {}: {:?}", repo_name, e);
+                return Err(Status::internal("Internal error deleting repository"));
             }
+        };
+        if files.is_empty() {
+            return Err(Status::not_found(format!(
+                "Repository {} not found",
+                repo_name
+            )));
         }
 
-        false
-    }
-
-    pub fn is_allowed(&self, image: &Image) -> bool {
-        //Have full names with host here
-        let name = format!("{}", image);
+        let digests: Vec<String> = files
+            .iter()
+            .map(|path| get_digest_from_manifest_path(path).unwrap_or_default())
+            .collect();
+        let removed: Vec<(PathBuf, String)> = match &self.trash {
+            Some(trash) => match trash.put(&self.manifests_path, &repo_name, "", &files) {
+                Ok(entry) => {
+                    info!(
+                        "Moved repository {} to the trash as {}",
+                        repo_name, entry.id
+                    );
+                    files.into_iter().zip(digests).collect()
+                }
+                Err(e) => {
+                    error!("Failed to move {} to the trash: {:?}", repo_name, e);
+                    return Err(Status::internal("Internal error deleting repository"));
+                }
+            },
+            None => files
+                .into_iter()
+                .zip(digests)
+                .filter(|(path, _)| match fs::remove_file(path) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        error!("Failed to delete {:?}: {:?}", path, e);
+                        false
+                    }
+                })
+                .collect(),
+        };
 
-        for prefix in &self.allow_prefixes {
-            if name.starts_with(prefix) {
-                info!("Image {} matches prefix {} on allow list", name, prefix);
-                return true;
+        for (path, _) in &removed {
+            let reference = path.file_name().unwrap().to_string_lossy().to_string();
+            if let Some(m) = &self.metadata {
+                if let Err(e) = m.reload_tag(
+                    &self.manifests_path,
+                    &self.blobs_path,
+                    &repo_name,
+                    &reference,
+                ) {
+                    error!(
+                        "Failed to remove {} from metadata database {:?}",
+                        reference, e
+                    );
+                }
             }
-        }
-
-        for a_name in &self.allow_images {
-            if &name == a_name {
-                info!("Image {} matches image {} on allow list", name, a_name);
-                return true;
+            if let Some(index) = &self.repo_index {
+                index.remove(&repo_name, &reference);
             }
         }
+        drop(guard);
 
-        false
-    }
+        // Into the trash first, so the bucket always has the tags somewhere
+        let stored = match &self.trash {
+            Some(t) => self.store_dir(t.path()),
+            None => Ok(()),
+        }
+        .and_then(|_| removed.iter().try_for_each(|(path, _)| self.store(path)));
+        if let Err(e) = stored {
+            error!("Failed to delete {} from storage {:?}", repo_name, e);
+            return Err(Status::internal("Internal error deleting repository"));
+        }
 
-    fn is_writable_repo(&self, repo_name: &str) -> bool {
-        if repo_name.starts_with(PROXY_DIR) {
-            return false;
+        let deleted = removed.len() as u64;
+        for (path, digest) in removed {
+            let reference = path.file_name().unwrap().to_string_lossy().to_string();
+            let tag = if is_digest(&reference) {
+                None
+            } else {
+                Some(reference.as_str())
+            };
+            self.events.publish(Event::new(
+                EventAction::Delete,
+                &repo_name,
+                tag,
+                digest.trim(),
+            ));
+        }
+        // Still holds any repositories nested under this one
+        fs::remove_dir(self.manifests_path.join(&repo_name)).ok();
+        // Trashed tags keep their links until garbage collection finds them expired
+        if self.trash.is_none() {
+            if let Err(e) = links::unlink_repo(&self.links_path, &repo_name)
+                .and_then(|_| self.store_dir(&self.links_path.join(&repo_name)))
+            {
+                warn!("Failed to remove blob links of {}: {:?}", repo_name, e);
+            }
         }
 
-        true
+        info!("Deleted repository {} ({} manifests)", repo_name, deleted);
+        Ok(Response::new(RepositoryDeleted { manifests: deleted }))
     }
-}
 
-#[tonic::async_trait]
-impl Registry for TrowServer {
-    async fn request_upload(
+    type ListUploadsStream = ReceiverStream<Result<UploadSession, Status>>;
+
+    async fn list_uploads(
         &self,
-        request: Request<UploadRequest>,
-    ) -> Result<Response<UploadDetails>, Status> {
-        let repo_name = request.into_inner().repo_name;
-        if self.is_writable_repo(&repo_name) {
-            let uuid = Uuid::new_v4().to_string();
-            let reply = UploadDetails { uuid: uuid.clone() };
-            let upload = Upload { repo_name, uuid };
-            {
-                self.active_uploads.write().unwrap().insert(upload);
-                debug!("Upload Table: {:?}", self.active_uploads);
+        _request: Request<ListUploadsRequest>,
+    ) -> Result<Response<Self::ListUploadsStream>, Status> {
+        let mut uploads: Vec<Upload> = self
+            .active_uploads
+            .read()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+        uploads.sort_by(|a, b| (&a.repo_name, &a.uuid).cmp(&(&b.repo_name, &b.uuid)));
+
+        let sessions: Vec<UploadSession> = uploads
+            .into_iter()
+            .map(|u| {
+                let metadata = fs::metadata(self.get_upload_path_for_blob(&u.uuid)).ok();
+                let last_modified = metadata
+                    .as_ref()
+                    .and_then(|m| m.modified().ok())
+                    .map(|t| to_timestamp(&DateTime::<Utc>::from(t)));
+                UploadSession {
+                    repo_name: u.repo_name,
+                    uuid: u.uuid,
+                    bytes: metadata.map(|m| m.len()).unwrap_or(0),
+                    last_modified,
+                }
+            })
+            .collect();
+
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            for session in sessions {
+                tx.send(Ok(session)).await.expect("Error streaming uploads");
             }
-            Ok(Response::new(reply))
-        } else {
-            Err(Status::invalid_argument(format!(
-                "Repository {} is not writable",
-                repo_name
-            )))
-        }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 
-    async fn get_write_location_for_blob(
+    /*
+     * Removes the upload's session, data and any parts straight away, rather than waiting for it
+     * to expire. Writes the client makes to it afterwards fail as they would for an unknown
+     * upload. Sessions only on disk, started by another backend sharing the data dir, are found
+     * too.
+     */
+    async fn cancel_upload(
         &self,
-        req: Request<UploadRef>,
-    ) -> Result<Response<WriteLocation>, Status> {
-        let br = req.into_inner();
-        let upload = Upload {
-            repo_name: br.repo_name.clone(),
-            uuid: br.uuid.clone(),
+        request: Request<CancelUploadRequest>,
+    ) -> Result<Response<UploadCancelled>, Status> {
+        let uuid = request.into_inner().uuid;
+        check_upload_id(&uuid)?;
+        let active = self
+            .active_uploads
+            .read()
+            .unwrap()
+            .iter()
+            .find(|u| u.uuid == uuid)
+            .cloned();
+        let repo_name = match active {
+            Some(upload) => upload.repo_name,
+            None => match uploads::restore(&self.uploads_path, &uuid) {
+                Some(session) => session.repo_name,
+                None => return Err(Status::not_found(format!("No upload {}", uuid))),
+            },
         };
 
-        // Apparently unwrap() is correct here. From the docs:
-        // "We unwrap() the return value to assert that we are not expecting
-        // threads to ever fail while holding the lock."
-
-        let set = self.active_uploads.read().unwrap();
-        if set.contains(&upload) {
-            let path = self.get_upload_path_for_blob(&br.uuid);
-            Ok(Response::new(WriteLocation {
-                path: path.to_string_lossy().to_string(),
-            }))
-        } else {
-            Err(Status::failed_precondition(format!(
-                "No current upload matching {:?}",
-                br
-            )))
+        {
+            let mut active = self.active_uploads.write().unwrap();
+            active.retain(|u| u.uuid != uuid);
+            uploads::ACTIVE.set(active.len() as i64);
+        }
+        uploads::remove(&self.uploads_path, &uuid);
+        let path = self.get_upload_path_for_blob(&uuid);
+        let bytes = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove data of upload {}: {:?}", uuid, e);
+                return Err(Status::internal("Internal error removing upload"));
+            }
         }
+        info!(
+            "Cancelled upload {} to {}, freeing {} bytes",
+            uuid, repo_name, bytes
+        );
+        Ok(Response::new(UploadCancelled { repo_name, bytes }))
     }
 
-    async fn get_read_location_for_blob(
+    async fn update_policy(
         &self,
-        req: Request<BlobRef>,
-    ) -> Result<Response<BlobReadLocation>, Status> {
-        metrics::TOTAL_BLOB_REQUESTS.inc();
-        let br = req.into_inner();
-        let path = self
-            .get_catalog_path_for_blob(&br.digest)
-            .map_err(|e| Status::invalid_argument(format!("Error parsing digest {:?}", e)))?;
-
-        if !path.exists() {
-            warn!("Request for unknown blob: {:?}", path);
-            Err(Status::not_found(format!(
-                "No blob found matching {:?}",
-                br
-            )))
-        } else {
-            Ok(Response::new(BlobReadLocation {
-                path: path.to_string_lossy().to_string(),
-            }))
+        request: Request<PolicyUpdate>,
+    ) -> Result<Response<PolicyGeneration>, Status> {
+        let update = request.into_inner();
+        let current = self.policy.get();
+        let policy = Policy {
+            allow_prefixes: update.allow_prefixes,
+            allow_images: update.allow_images,
+            deny_local_prefixes: update.deny_local_prefixes,
+            deny_local_images: update.deny_local_images,
+            ..(*current).clone()
         }
+        .with_rules(
+            &update.quotas,
+            &update.immutable_tags,
+            &update.freeze_windows,
+            &self.data_path,
+        )
+        .map_err(|e| {
+            warn!("Rejected policy generation {}: {}", update.generation, e);
+            Status::invalid_argument(e.to_string())
+        })?;
+        self.policy.replace(update.generation, policy);
+        info!("Using policy generation {}", update.generation);
+        Ok(Response::new(PolicyGeneration {
+            generation: update.generation,
+        }))
     }
 
-    /**
-     * TODO: check if blob referenced by manifests. If so, refuse to delete.
-     */
-    async fn delete_blob(&self, req: Request<BlobRef>) -> Result<Response<BlobDeleted>, Status> {
-        let br = req.into_inner();
-        let path = self
-            .get_catalog_path_for_blob(&br.digest)
-            .map_err(|e| Status::invalid_argument(format!("Error parsing digest {:?}", e)))?;
-        if !path.exists() {
-            warn!("Request for unknown blob: {:?}", path);
-            Err(Status::not_found(format!(
-                "No blob found matching {:?}",
-                br
-            )))
-        } else {
-            fs::remove_file(&path)
-                .map_err(|e| {
-                    error!("Failed to delete blob {:?} {:?}", br, e);
-                    Status::internal("Internal error deleting blob")
-                })
-                .and(Ok(Response::new(BlobDeleted {})))
-        }
+    async fn get_policy_generation(
+        &self,
+        _request: Request<PolicyGenerationRequest>,
+    ) -> Result<Response<PolicyGeneration>, Status> {
+        Ok(Response::new(PolicyGeneration {
+            generation: self.policy.generation(),
+        }))
     }
 
-    async fn delete_manifest(
-        &self,
-        req: Request<ManifestRef>,
-    ) -> Result<Response<ManifestDeleted>, Status> {
-        let mr = req.into_inner();
-        if !is_digest(&mr.reference) {
-            return Err(Status::invalid_argument(format!(
-                "Manifests can only be deleted by digest. Got {}",
-                mr.reference
-            )));
-        }
-        let digest = mr.reference;
-        //For the repo, go through all tags and see if they reference the digest. Delete them.
-        //Can only delete manifest if no other tags in any repo reference it
+    type UsageReportStream = ReceiverStream<Result<UnusedImage, Status>>;
 
-        let ri = RepoIterator::new(&self.manifests_path.join(&mr.repo_name)).map_err(|e| {
-            error!("Problem reading manifest catalog {:?}", e);
-            Status::failed_precondition("Repository not found")
+    async fn usage_report(
+        &self,
+        request: Request<UsageRequest>,
+    ) -> Result<Response<Self::UsageReportStream>, Status> {
+        let unused_for = Duration::from_secs(request.into_inner().unused_for_secs);
+        let unused = usage::report(
+            &self.manifests_path,
+            &self.data_path,
+            unused_for,
+            Utc::now(),
+        )
+        .map_err(|e| {
+            error!("Failed to build usage report: {:?}", e);
+            Status::internal("Internal error building usage report")
         })?;
 
-        //TODO: error if no manifest matches?
-        ri.filter(|de| does_manifest_match_digest(de, &digest))
-            .for_each(|man| match fs::remove_file(man.path()) {
-                Ok(_) => (),
-                Err(e) => error!("Failed to delete manifest {:?} {:?}", &man, e),
-            });
-
-        Ok(Response::new(ManifestDeleted {}))
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            for u in unused {
+                let image = UnusedImage {
+                    repo_name: u.repo_name,
+                    tag: u.tag,
+                    digest: u.digest,
+                    pushed: Some(to_timestamp(&u.pushed)),
+                    last_seen: u.last_seen.as_ref().map(to_timestamp),
+                };
+                tx.send(Ok(image))
+                    .await
+                    .expect("Error streaming usage report");
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 
-    async fn get_write_details_for_manifest(
-        &self,
-        req: Request<ManifestRef>,
-    ) -> Result<Response<ManifestWriteDetails>, Status> {
-        let repo_name = req.into_inner().repo_name;
-        if self.is_writable_repo(&repo_name) {
-            //Give the manifest a UUID and save it to the uploads dir
-            let uuid = Uuid::new_v4().to_string();
+    type PullStatsStream = ReceiverStream<Result<RepositoryPulls, Status>>;
 
-            let manifest_path = self.get_upload_path_for_blob(&uuid);
-            Ok(Response::new(ManifestWriteDetails {
-                path: manifest_path.to_string_lossy().to_string(),
-                uuid,
-            }))
-        } else {
-            Err(Status::invalid_argument(format!(
-                "Repository {} is not writable",
-                repo_name
-            )))
+    async fn pull_stats(
+        &self,
+        request: Request<PullStatsRequest>,
+    ) -> Result<Response<Self::PullStatsStream>, Status> {
+        let req = request.into_inner();
+        let metadata = self.metadata.as_ref().ok_or_else(|| {
+            Status::failed_precondition("Pull counts are kept in the metadata database")
+        })?;
+        let repo_name = Some(req.repo_name.as_str()).filter(|r| !r.is_empty());
+        if let Some(name) = repo_name {
+            check_repo_name(name)?;
         }
+        let stats = metadata.pull_stats(repo_name).map_err(|e| {
+            error!("Failed to read pull counts {:?}", e);
+            Status::internal("Internal error reading pull counts")
+        })?;
+
+        let to_pulled = |pulled: &str| {
+            DateTime::parse_from_rfc3339(pulled)
+                .ok()
+                .map(|d| to_timestamp(&d.with_timezone(&Utc)))
+        };
+        let repos: Vec<RepositoryPulls> = stats
+            .into_iter()
+            .map(|r| RepositoryPulls {
+                repo_name: r.repo_name,
+                pulls: r.pulls,
+                last_pulled: to_pulled(&r.last_pulled),
+                references: r
+                    .references
+                    .into_iter()
+                    .map(|p| ReferencePulls {
+                        last_pulled: to_pulled(&p.last_pulled),
+                        reference: p.reference,
+                        pulls: p.pulls,
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            for repo in repos {
+                tx.send(Ok(repo))
+                    .await
+                    .expect("Error streaming pull counts");
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 
-    async fn get_read_location_for_manifest(
+    async fn get_index_summary(
         &self,
-        req: Request<ManifestRef>,
-    ) -> Result<Response<ManifestReadLocation>, Status> {
-        //Don't actually need to verify here; could set to false
-
-        let mr = req.into_inner();
-        metrics::TOTAL_MANIFEST_REQUESTS.inc();
-        // TODO refactor to return directly
-        match self
-            .create_manifest_read_location(mr.repo_name, mr.reference, true)
-            .await
+        request: Request<ManifestRef>,
+    ) -> Result<Response<IndexSummary>, Status> {
+        let mr = request.into_inner();
+        let reference = manifest_reference(&mr)?;
+        if self
+            .get_proxy_address_and_auth(&mr.repo_name, reference.as_str())
+            .is_some()
         {
-            Ok(vm) => Ok(Response::new(vm)),
-            Err(e) => {
-                warn!("Internal error with manifest {:?}", e);
-                Err(Status::internal("Internal error finding manifest"))
+            // The same as a pull, so the index is fetched if needed
+            if let Err(e) = self
+                .create_manifest_read_location(
+                    mr.repo_name.clone(),
+                    reference.as_str().to_string(),
+                    false,
+                )
+                .await
+            {
+                warn!("Error finding proxied manifest {:?}", e);
+                return Err(Status::not_found("Manifest not found"));
             }
         }
+        let (digest, _) = self
+            .find_manifest(&mr.repo_name, reference.as_str())
+            .map_err(|_| Status::not_found("Manifest not found"))?;
+
+        let cached = match &self.metadata {
+            Some(m) => m.summary(&digest).unwrap_or_else(|e| {
+                warn!("Failed to read summary of {} {:?}", digest, e);
+                None
+            }),
+            None => None,
+        };
+        let summary = match cached {
+            Some(s) => s,
+            None => {
+                let s = index_summary::summarize(&self.blobs_path, &digest).map_err(|e| {
+                    warn!("Failed to summarize {} {:?}", digest, e);
+                    Status::internal("Internal error summarizing manifest")
+                })?;
+                if let (Some(m), true) = (&self.metadata, s.is_complete()) {
+                    if let Err(e) = m.cache_summary(&s) {
+                        warn!("Failed to keep summary of {} {:?}", digest, e);
+                    }
+                }
+                s
+            }
+        };
+
+        Ok(Response::new(IndexSummary {
+            digest: summary.digest,
+            media_type: summary.media_type,
+            platforms: summary
+                .platforms
+                .into_iter()
+                .map(|p| PlatformImage {
+                    os: p.os,
+                    architecture: p.architecture,
+                    variant: p.variant.unwrap_or_default(),
+                    digest: p.digest,
+                    media_type: p.media_type,
+                    manifest_size: p.manifest_size,
+                    stored: p.image_size.is_some(),
+                    image_size: p.image_size.unwrap_or(0),
+                })
+                .collect(),
+        }))
     }
 
-    /**
-     * Take uploaded manifest (which should be uuid in uploads), check it, put in catalog and
-     * by blob digest
-     */
-    async fn verify_manifest(
+    async fn record_pull(
         &self,
-        req: Request<VerifyManifestRequest>,
-    ) -> Result<Response<VerifiedManifest>, Status> {
-        let req = req.into_inner();
-        let mr = req.manifest.unwrap(); // Pissed off that the manifest is optional!
-        let uploaded_manifest = self.get_upload_path_for_blob(&req.uuid);
+        request: Request<ManifestRef>,
+    ) -> Result<Response<PullRecorded>, Status> {
+        let mr = request.into_inner();
+        let reference = manifest_reference(&mr)?;
+        self.count_pull(&mr.repo_name, reference.as_str());
+        Ok(Response::new(PullRecorded {}))
+    }
 
-        match self.create_verified_manifest(&uploaded_manifest, true) {
-            Ok(vm) => {
-                // copy manifest to blobs and add tag
-                let digest = vm.digest.clone();
-                let ret = self
-                    .save_blob(&uploaded_manifest, &digest)
-                    .and(self.save_tag(&digest, &mr.repo_name, &mr.reference).await)
-                    .map(|_| Response::new(vm))
-                    .map_err(|e| {
-                        error!(
-                            "Failure cataloguing manifest {}/{} {:?}",
-                            &mr.repo_name, &mr.reference, e
-                        );
-                        Status::internal("Internal error copying manifest")
-                    });
+    type ExportImageStream = ReceiverStream<Result<ImageArchiveChunk, Status>>;
 
-                ret
+    async fn export_image(
+        &self,
+        request: Request<ManifestRef>,
+    ) -> Result<Response<Self::ExportImageStream>, Status> {
+        let mr = request.into_inner();
+        let reference = manifest_reference(&mr)?;
+        if self
+            .get_proxy_address_and_auth(&mr.repo_name, reference.as_str())
+            .is_some()
+        {
+            // The same as a pull, so the image is fetched if needed
+            if let Err(e) = self
+                .create_manifest_read_location(
+                    mr.repo_name.clone(),
+                    reference.as_str().to_string(),
+                    false,
+                )
+                .await
+            {
+                warn!("Error finding proxied manifest {:?}", e);
+                return Err(Status::not_found("Manifest not found"));
             }
-            Err(e) => {
-                error!("Error verifying manifest {:?}", e);
-                Err(Status::invalid_argument("Failed to verify manifest"))
+        }
+        let (digest, _) = self
+            .find_manifest(&mr.repo_name, reference.as_str())
+            .map_err(|_| Status::not_found("Manifest not found"))?;
+
+        // Written to the scratch dir first, so missing blobs are found before anything is sent
+        let tarball = self.get_upload_path_for_blob(&Uuid::new_v4().to_string());
+        let res = File::create(&tarball)
+            .map_err(anyhow::Error::from)
+            .and_then(|f| {
+                oci_layout::export(
+                    &self.blobs_path,
+                    &digest,
+                    reference.tag(),
+                    BufWriter::new(f),
+                )
+            });
+        if let Err(e) = res {
+            let _ = fs::remove_file(&tarball);
+            return Err(match e.downcast::<LayoutError>() {
+                Ok(e) => Status::failed_precondition(e.to_string()),
+                Err(e) => {
+                    error!("Failed to export {} {:?}", digest, e);
+                    Status::internal("Internal error exporting image")
+                }
+            });
+        }
+        info!("Exporting {}@{}", mr.repo_name, digest);
+
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            if let Err(e) = send_archive(&tarball, &tx).await {
+                warn!("Failed to send export of {} {:?}", digest, e);
+                let _ = tx
+                    .send(Err(Status::internal("Internal error exporting image")))
+                    .await;
             }
-        }
+            let _ = fs::remove_file(&tarball);
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 
-    async fn complete_upload(
+    async fn import_image(
         &self,
-        req: Request<CompleteRequest>,
-    ) -> Result<Response<CompletedUpload>, Status> {
-        let cr = req.into_inner();
-        let ret = match self.validate_and_save_blob(&cr.user_digest, &cr.uuid) {
-            Ok(_) => Ok(Response::new(CompletedUpload {
-                digest: cr.user_digest.clone(),
-            })),
-            Err(e) => match e.downcast::<DigestValidationError>() {
-                Ok(v_e) => Err(Status::invalid_argument(v_e.to_string())),
-                Err(e) => {
-                    warn!("Failure when saving layer: {:?}", e);
-                    Err(Status::internal("Internal error saving layer"))
-                }
-            },
-        };
+        request: Request<tonic::Streaming<ImportChunk>>,
+    ) -> Result<Response<ImageImported>, Status> {
+        self.check_writable()?;
+        self.check_capacity()?;
+        let mut stream = request.into_inner();
+        let first = stream
+            .message()
+            .await?
+            .ok_or_else(|| Status::invalid_argument("Nothing to import"))?;
+        let repo_name = first.repo_name;
+        if !is_valid_repo_name(&repo_name) {
+            return Err(Status::invalid_argument(format!(
+                "Invalid repository name {}",
+                repo_name
+            )));
+        }
+        if !self.is_writable_repo(&repo_name) {
+            return Err(Status::invalid_argument(format!(
+                "Repository {} is not writable",
+                repo_name
+            )));
+        }
+        let tag = Some(first.tag).filter(|t| !t.is_empty());
+        if let Some(t) = &tag {
+            if !is_valid_tag(t) {
+                return Err(Status::invalid_argument(format!("Invalid tag {}", t)));
+            }
+        }
 
-        //delete uuid from uploads tracking
-        let upload = Upload {
-            repo_name: cr.repo_name.clone(),
-            uuid: cr.uuid,
-        };
+        let tarball = self.get_upload_path_for_blob(&Uuid::new_v4().to_string());
+        let layout = receive_archive(first.data, &mut stream, &tarball)
+            .await
+            .and_then(|_| {
+                File::open(&tarball)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|f| oci_layout::import(BufReader::new(f), &self.scratch_path))
+                    .map_err(|e| match e.downcast::<LayoutError>() {
+                        Ok(e) => Status::invalid_argument(e.to_string()),
+                        Err(e) => {
+                            error!("Failed to read imported archive {:?}", e);
+                            Status::internal("Internal error importing image")
+                        }
+                    })
+            });
+        let _ = fs::remove_file(&tarball);
+        let layout = layout?;
 
-        let mut set = self.active_uploads.write().unwrap();
-        if !set.remove(&upload) {
-            warn!("Upload {:?} not found when deleting", upload);
+        let res = self.store_layout(&repo_name, tag.as_deref(), &layout).await;
+        // Anything not stored
+        layout.remove_blobs();
+        if let Ok(imported) = &res {
+            info!(
+                "Imported {} images into {}",
+                imported.manifests.len(),
+                repo_name
+            );
         }
-        ret
+        res.map(Response::new)
     }
 
-    type GetCatalogStream = ReceiverStream<Result<CatalogEntry, Status>>;
+    type ListChartsStream = ReceiverStream<Result<ChartVersion, Status>>;
 
-    async fn get_catalog(
+    async fn list_charts(
         &self,
-        request: Request<CatalogRequest>,
-    ) -> Result<Response<Self::GetCatalogStream>, Status> {
-        let cr = request.into_inner();
-        let limit = cr.limit as usize;
+        _request: Request<ListChartsRequest>,
+    ) -> Result<Response<Self::ListChartsStream>, Status> {
+        let charts = helm::list_charts(&self.manifests_path, &self.blobs_path).map_err(|e| {
+            error!("Failed to list charts: {:?}", e);
+            Status::internal("Internal error listing charts")
+        })?;
 
         let (tx, rx) = mpsc::channel(4);
-        let catalog: HashSet<String> = RepoIterator::new(&self.manifests_path)
-            .map_err(|e| {
-                error!("Error accessing catalog {:?}", e);
-                Status::internal("Internal error streaming catalog")
-            })?
-            .map(|de| de.path())
-            .filter_map(|p| p.parent().map(|p| p.to_path_buf()))
-            .filter_map(|r| {
-                r.strip_prefix(&self.manifests_path)
-                    .ok()
-                    .map(|p| p.to_path_buf())
-            })
-            .map(|p| p.to_string_lossy().to_string())
-            .collect();
-        let partial_catalog: Vec<String> = if cr.last_repo.is_empty() {
-            catalog.into_iter().take(limit).collect()
-        } else {
-            catalog
-                .into_iter()
-                .skip_while(|t| t != &cr.last_repo)
-                .skip(1)
-                .take(limit)
-                .collect()
-        };
-
         tokio::spawn(async move {
-            for repo_name in partial_catalog {
-                let ce = CatalogEntry { repo_name };
-
-                tx.send(Ok(ce)).await.expect("Error streaming catalog");
+            for c in charts {
+                let chart = ChartVersion {
+                    repo_name: c.repo_name,
+                    tag: c.tag,
+                    metadata: c.metadata,
+                    digest: c.digest,
+                    size: c.size,
+                    pushed: Some(to_timestamp(&DateTime::<Utc>::from(c.pushed))),
+                };
+                tx.send(Ok(chart)).await.expect("Error streaming charts");
             }
         });
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 
-    type ListTagsStream = ReceiverStream<Result<Tag, Status>>;
+    type ListReferrersStream = ReceiverStream<Result<Referrer, Status>>;
 
-    async fn list_tags(
+    async fn list_referrers(
         &self,
-        request: Request<ListTagsRequest>,
-    ) -> Result<Response<Self::ListTagsStream>, Status> {
-        let (tx, rx) = mpsc::channel(4);
-        let mut path = PathBuf::from(&self.manifests_path);
+        request: Request<ReferrersRequest>,
+    ) -> Result<Response<Self::ListReferrersStream>, Status> {
+        let req = request.into_inner();
+        if !is_valid_repo_name(&req.repo_name) || !is_valid_digest(&req.digest) {
+            return Err(Status::invalid_argument(format!(
+                "Invalid reference {}@{}",
+                req.repo_name, req.digest
+            )));
+        }
+        let found = match &self.metadata {
+            Some(m) => m.referrers(&req.repo_name, &req.digest),
+            None => referrers::list_referrers(
+                &self.manifests_path,
+                &self.blobs_path,
+                &req.repo_name,
+                &req.digest,
+            ),
+        }
+        .map_err(|e| {
+            error!("Failed to list referrers of {}: {:?}", req.digest, e);
+            Status::internal("Internal error listing referrers")
+        })?;
 
-        let ltr = request.into_inner();
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            for r in found {
+                let referrer = Referrer {
+                    digest: r.digest,
+                    media_type: r.media_type,
+                    size: r.size,
+                    artifact_type: r.artifact_type,
+                    annotations: serde_json::to_string(&r.annotations).unwrap_or_default(),
+                };
+                tx.send(Ok(referrer))
+                    .await
+                    .expect("Error streaming referrers");
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
 
-        let limit = ltr.limit as usize;
-        path.push(&ltr.repo_name);
+    type ListTenantsStream = ReceiverStream<Result<Tenant, Status>>;
 
-        let mut catalog: Vec<String> = RepoIterator::new(&path)
-            .map_err(|e| {
-                error!("Error accessing catalog {:?}", e);
-                Status::internal("Internal error streaming catalog")
-            })?
-            .map(|de| de.path().file_name().unwrap().to_string_lossy().to_string())
-            .collect();
-        catalog.sort();
-        let partial_catalog: Vec<String> = if ltr.last_tag.is_empty() {
-            catalog.into_iter().take(limit).collect()
-        } else {
-            catalog
-                .into_iter()
-                .skip_while(|t| t != &ltr.last_tag)
-                .skip(1)
-                .take(limit)
-                .collect()
-        };
+    async fn list_tenants(
+        &self,
+        _request: Request<ListTenantsRequest>,
+    ) -> Result<Response<Self::ListTenantsStream>, Status> {
+        let found = self.tenants.list();
 
+        let (tx, rx) = mpsc::channel(4);
         tokio::spawn(async move {
-            for tag in partial_catalog {
-                tx.send(Ok(Tag {
-                    tag: tag.to_string(),
-                }))
-                .await
-                .expect("Error streaming tags");
+            for t in found {
+                tx.send(Ok(tenant_to_proto(t)))
+                    .await
+                    .expect("Error streaming tenants");
             }
         });
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 
-    type GetManifestHistoryStream = ReceiverStream<Result<ManifestHistoryEntry, Status>>;
+    async fn put_tenant(&self, request: Request<Tenant>) -> Result<Response<Tenant>, Status> {
+        let t = request.into_inner();
+        let tenant = tenants::Tenant {
+            name: t.name,
+            members: t
+                .members
+                .into_iter()
+                .map(|m| tenants::Member {
+                    user: m.user,
+                    permission: m.permission,
+                })
+                .collect(),
+            quota: Some(t.quota).filter(|q| !q.is_empty()),
+            retention: t.retention,
+        };
+        if let Err(e) = tenant.validate() {
+            return Err(Status::invalid_argument(e.to_string()));
+        }
+        self.tenants
+            .put(tenant.clone())
+            .and_then(|_| self.store(self.tenants.path()))
+            .map_err(|e| {
+                error!("Failed to store tenant {}: {:?}", tenant.name, e);
+                Status::internal("Internal error storing tenant")
+            })?;
+        info!("Stored tenant {}", tenant.name);
+        Ok(Response::new(tenant_to_proto(tenant)))
+    }
 
-    async fn get_manifest_history(
+    async fn delete_tenant(
         &self,
-        request: Request<ManifestHistoryRequest>,
-    ) -> Result<Response<Self::GetManifestHistoryStream>, Status> {
-        let mr = request.into_inner();
-        if is_digest(&mr.tag) {
-            return Err(Status::invalid_argument(
-                "Require valid tag (not digest) to search for history",
-            ));
+        request: Request<TenantRef>,
+    ) -> Result<Response<TenantDeleted>, Status> {
+        let name = request.into_inner().name;
+        let removed = self
+            .tenants
+            .remove(&self.manifests_path, &name)
+            .and_then(|found| self.store(self.tenants.path()).map(|_| found));
+        match removed {
+            Ok(true) => {
+                info!("Deleted tenant {}", name);
+                Ok(Response::new(TenantDeleted { name }))
+            }
+            Ok(false) => Err(Status::not_found(format!("Tenant {} not found", name))),
+            Err(e) => match e.downcast_ref::<TenantInUse>() {
+                Some(in_use) => Err(Status::failed_precondition(in_use.to_string())),
+                None => {
+                    error!("Failed to delete tenant {}: {:?}", name, e);
+                    Err(Status::internal("Internal error deleting tenant"))
+                }
+            },
         }
+    }
 
-        let manifest_path = self.manifests_path.join(&mr.repo_name).join(&mr.tag);
-
-        let file = File::open(&manifest_path);
-
-        if file.is_err() {
-            return Err(Status::not_found(format!(
-                "Could not find the requested manifest at: {}",
-                &manifest_path.to_str().unwrap()
-            )));
-        }
+    type ListTrashStream = ReceiverStream<Result<trow_server::TrashEntry, Status>>;
 
-        // It's safe to unwrap here
-        let reader = BufReader::new(file.unwrap());
+    async fn list_trash(
+        &self,
+        _request: Request<ListTrashRequest>,
+    ) -> Result<Response<Self::ListTrashStream>, Status> {
+        let entries = match &self.trash {
+            Some(t) => t
+                .list()
+                .map_err(|e| {
+                    error!("Failed to read the trash: {:?}", e);
+                    Status::internal("Internal error reading the trash")
+                })?
+                .into_iter()
+                .map(|e| trash_entry_to_proto(t, e))
+                .collect(),
+            None => vec![],
+        };
 
         let (tx, rx) = mpsc::channel(4);
         tokio::spawn(async move {
-            let mut searching_for_digest = mr.last_digest != ""; //Looking for a digest iff it's not empty
-
-            let mut sent = 0;
-            for line in reader.lines() {
-                if let Ok(line) = line {
-                    let (digest, date) = match line.find(' ') {
-                        Some(ind) => {
-                            let (digest_str, date_str) = line.split_at(ind);
-
-                            if searching_for_digest {
-                                if digest_str == mr.last_digest {
-                                    searching_for_digest = false;
-                                }
-                                //Remember we want digest following matched digest
-                                continue;
-                            }
-
-                            let dt_r = DateTime::parse_from_rfc3339(date_str.trim());
-
-                            let ts = if let Ok(dt) = dt_r {
-                                Some(Timestamp {
-                                    seconds: dt.timestamp(),
-                                    nanos: dt.timestamp_subsec_nanos() as i32,
-                                })
-                            } else {
-                                warn!("Failed to parse timestamp {}", date_str);
-                                None
-                            };
-                            (digest_str, ts)
-                        }
-                        None => {
-                            warn!("No timestamp found in manifest");
-                            (line.as_ref(), None)
-                        }
-                    };
-
-                    let entry = ManifestHistoryEntry {
-                        digest: digest.to_string(),
-                        date,
-                    };
-                    tx.send(Ok(entry))
-                        .await
-                        .expect("Error streaming manifest history");
-
-                    sent += 1;
-                    if sent >= mr.limit {
-                        break;
-                    }
-                }
+            for e in entries {
+                tx.send(Ok(e)).await.expect("Error streaming trash");
             }
         });
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 
-    // Readiness check
-    async fn is_ready(
+    async fn restore_trash(
         &self,
-        _request: Request<ReadinessRequest>,
-    ) -> Result<Response<ReadyStatus>, Status> {
-        for path in &[&self.scratch_path, &self.manifests_path, &self.blobs_path] {
-            match is_path_writable(path) {
-                Ok(true) => {}
-                Ok(false) => {
-                    return Err(Status::unavailable(format!(
-                        "{} is not writable",
-                        path.to_string_lossy()
-                    )));
-                }
-                Err(error) => {
-                    return Err(Status::unavailable(error.to_string()));
+        request: Request<TrashRef>,
+    ) -> Result<Response<trow_server::TrashEntry>, Status> {
+        self.check_writable()?;
+        let id = request.into_inner().id;
+        let not_found = || Status::not_found(format!("Nothing in the trash with id {}", id));
+        let t = self.trash.as_ref().ok_or_else(not_found)?;
+
+        let guard = self.tags_lock.write().unwrap();
+        let entry = match t.restore(&self.manifests_path, &id) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Err(not_found()),
+            Err(e) => {
+                return match e.downcast_ref::<TagPushedSince>() {
+                    Some(pushed) => Err(Status::failed_precondition(pushed.to_string())),
+                    None => {
+                        error!("Failed to restore {} from the trash: {:?}", id, e);
+                        Err(Status::internal("Internal error restoring from the trash"))
+                    }
+                };
+            }
+        };
+        for reference in &entry.tags {
+            if let Some(m) = &self.metadata {
+                if let Err(e) = m.reload_tag(
+                    &self.manifests_path,
+                    &self.blobs_path,
+                    &entry.repo_name,
+                    reference,
+                ) {
+                    error!("Failed to add {} to metadata database {:?}", reference, e);
                 }
             }
+            if let Some(index) = &self.repo_index {
+                index.insert(&entry.repo_name, reference);
+            }
         }
+        drop(guard);
+
+        // Tags back first, so the bucket always has them somewhere
+        let repo_path = self.manifests_path.join(&entry.repo_name);
+        let stored = entry
+            .tags
+            .iter()
+            .try_for_each(|reference| self.store(&repo_path.join(reference)))
+            .and_then(|_| self.store_dir(t.path()));
+        if let Err(e) = stored {
+            error!("Failed to restore {} in storage {:?}", id, e);
+            return Err(Status::internal("Internal error restoring from the trash"));
+        }
+        for reference in &entry.tags {
+            let digest = self
+                .get_digest_from_manifest(&entry.repo_name, reference)
+                .unwrap_or_default();
+            let tag = Some(reference.as_str()).filter(|r| !is_digest(r));
+            self.events.publish(Event::new(
+                EventAction::Push,
+                &entry.repo_name,
+                tag,
+                digest.trim(),
+            ));
+        }
+        info!(
+            "Restored {}@{} from the trash",
+            entry.repo_name, entry.digest
+        );
+        Ok(Response::new(trash_entry_to_proto(t, entry)))
+    }
 
-        //All paths writable
-        let reply = trow_server::ReadyStatus {
-            message: String::from("Ready"),
-        };
-
-        Ok(Response::new(reply))
+    async fn get_read_only(
+        &self,
+        _request: Request<ReadOnlyRequest>,
+    ) -> Result<Response<ReadOnlyStatus>, Status> {
+        let reason = self.read_only.read().unwrap().clone();
+        Ok(Response::new(ReadOnlyStatus {
+            read_only: reason.is_some(),
+            reason: reason.unwrap_or_default(),
+        }))
     }
 
-    async fn is_healthy(
+    async fn set_read_only(
         &self,
-        _request: Request<HealthRequest>,
-    ) -> Result<Response<HealthStatus>, Status> {
-        let reply = trow_server::HealthStatus {
-            message: String::from("Healthy"),
+        request: Request<ReadOnlyUpdate>,
+    ) -> Result<Response<ReadOnlyStatus>, Status> {
+        let update = request.into_inner();
+        let reason = if update.read_only {
+            let reason = Some(update.reason)
+                .filter(|r| !r.is_empty())
+                .unwrap_or_else(|| DEFAULT_READ_ONLY_REASON.to_string());
+            warn!("Registry is now read-only: {}", reason);
+            Some(reason)
+        } else {
+            info!("Registry is writable again");
+            None
         };
-        Ok(Response::new(reply))
+        *self.read_only.write().unwrap() = reason.clone();
+        Ok(Response::new(ReadOnlyStatus {
+            read_only: reason.is_some(),
+            reason: reason.unwrap_or_default(),
+        }))
     }
 
-    async fn get_metrics(
+    async fn get_scrub_report(
         &self,
-        _request: Request<MetricsRequest>,
-    ) -> Result<Response<MetricsResponse>, Status> {
-        match metrics::gather_metrics(&self.blobs_path) {
-            Ok(metrics) => {
-                let reply = trow_server::MetricsResponse { metrics };
-                Ok(Response::new(reply))
-            }
+        _request: Request<ScrubReportRequest>,
+    ) -> Result<Response<trow_server::ScrubReport>, Status> {
+        let report = self
+            .scrub_report
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| Status::not_found("No scrub has finished since Trow started"))?;
+        Ok(Response::new(trow_server::ScrubReport {
+            checked: report.checked as u64,
+            corrupt: report.corrupt,
+            missing: report
+                .missing
+                .into_iter()
+                .map(|m| MissingBlob {
+                    digest: m.digest,
+                    referenced_by: m.referenced_by,
+                })
+                .collect(),
+            quarantined: report.quarantined,
+            cancelled: report.cancelled,
+            finished: Some(to_timestamp(&report.finished)),
+        }))
+    }
 
-            Err(error) => Err(Status::unavailable(error.to_string())),
-        }
+    type WatchEventsStream = ReceiverStream<Result<RegistryEvent, Status>>;
+
+    async fn watch_events(
+        &self,
+        request: Request<WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        let wr = request.into_inner();
+        let mut events = self.events.watch();
+        let tenants = self.tenants.clone();
+
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            loop {
+                let received = tokio::select! {
+                    r = events.recv() => r,
+                    // The watcher has gone, so stop counting it as one
+                    _ = tx.closed() => break,
+                };
+                let event = match received {
+                    Ok(e) => e,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Event watcher fell behind, skipped {} events", n);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                // Blobs may be shared between tenants, so only those seeing everything see gc
+                let visible = if event.action == EventAction::Gc {
+                    wr.user.is_empty() && wr.prefix.is_empty()
+                } else {
+                    event.repository.starts_with(&wr.prefix)
+                        && (wr.user.is_empty() || tenants.can_see(&wr.user, &event.repository))
+                };
+                if !visible {
+                    continue;
+                }
+                let re = RegistryEvent {
+                    id: event.id,
+                    action: event.action.as_str().to_string(),
+                    repository: event.repository,
+                    tag: event.tag.unwrap_or_default(),
+                    digest: event.digest,
+                    timestamp: event.timestamp,
+                };
+                if tx.send(Ok(re)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
     }
 }
@@ -0,0 +1,372 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    Certificate, ClientCertVerified, ClientCertVerifier, ClientConfig, ClientHello,
+    DistinguishedNames, PrivateKey, ResolvesClientCert, ResolvesServerCert, RootCertStore,
+    ServerCertVerified, ServerCertVerifier, ServerConfig, SignatureScheme, TLSError,
+};
+use tonic::transport::{ClientTlsConfig, ServerTlsConfig};
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+
+/*
+ * SPIFFE workload identity (https://spiffe.io).
+ *
+ * SVIDs are X.509 certificates with a spiffe://trust-domain/path URI SAN. Trow reads its own SVID
+ * and the trust bundle from PEM files, as written by the SPIRE agent or spiffe-helper. SPIRE
+ * rotates SVIDs often, so the files are read again whenever they change.
+ *
+ * The frontend to backend channel uses the SVID for mutual TLS. Both ends check the chain
+ * against the trust bundle and require the peer to have the same SPIFFE ID as they do, as they
+ * are the same workload. Host names aren't checked, as SVIDs don't need to have any.
+ */
+
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+static ALPN_H2: &[u8] = b"h2";
+
+// Matches SPIFFE IDs, either exactly or everything under a path ending in /*
+// e.g. spiffe://example.org/ns/ci/*
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdPattern(String);
+
+impl FromStr for IdPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let id = s.strip_suffix("/*").unwrap_or(s);
+        trust_domain(id)?;
+        Ok(IdPattern(s.to_string()))
+    }
+}
+
+impl fmt::Display for IdPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl IdPattern {
+    pub fn matches(&self, id: &str) -> bool {
+        match self.0.strip_suffix('*') {
+            Some(prefix) => id.starts_with(prefix) && id.len() > prefix.len(),
+            None => self.0 == id,
+        }
+    }
+}
+
+// Checks the ID is valid and returns the trust domain
+fn trust_domain(id: &str) -> Result<&str> {
+    let rest = id
+        .strip_prefix("spiffe://")
+        .ok_or_else(|| anyhow!("SPIFFE ID {} should start with spiffe://", id))?;
+    let domain = rest.split('/').next().unwrap_or_default();
+    if domain.is_empty() {
+        return Err(anyhow!("SPIFFE ID {} has no trust domain", id));
+    }
+    Ok(domain)
+}
+
+/// Returns the SPIFFE ID from a DER encoded certificate, if it has one
+pub fn spiffe_id(cert_der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert_der).ok()?;
+    cert.extensions()
+        .iter()
+        .filter_map(|ext| match ext.parsed_extension() {
+            ParsedExtension::SubjectAlternativeName(san) => Some(san),
+            _ => None,
+        })
+        .flat_map(|san| san.general_names.iter())
+        .find_map(|name| match name {
+            GeneralName::URI(uri) if uri.starts_with("spiffe://") => Some(uri.to_string()),
+            _ => None,
+        })
+}
+
+struct Svid {
+    id: String,
+    key: CertifiedKey,
+    bundle: RootCertStore,
+}
+
+fn read_pem<T>(
+    path: &Path,
+    parse: fn(&mut dyn std::io::BufRead) -> Result<Vec<T>, ()>,
+) -> Result<Vec<T>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    parse(&mut reader).map_err(|_| anyhow!("Failed to parse PEM file {:?}", path))
+}
+
+impl Svid {
+    fn load(cert: &Path, key: &Path, bundle: &Path) -> Result<Svid> {
+        let chain = read_pem(cert, pemfile::certs)?;
+        let leaf = chain
+            .first()
+            .ok_or_else(|| anyhow!("No certificates in SVID {:?}", cert))?;
+        let id = spiffe_id(&leaf.0).ok_or_else(|| anyhow!("{:?} is not an X.509 SVID", cert))?;
+
+        // SPIRE writes PKCS8 keys, but allow for RSA ones too
+        let mut keys = read_pem(key, pemfile::pkcs8_private_keys)?;
+        if keys.is_empty() {
+            keys = read_pem(key, pemfile::rsa_private_keys)?;
+        }
+        let key: PrivateKey = keys
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No private key in {:?}", key))?;
+        let signer = sign::any_supported_type(&key)
+            .map_err(|_| anyhow!("Unsupported private key type in SVID key"))?;
+
+        let mut roots = RootCertStore::empty();
+        for ca in read_pem(bundle, pemfile::certs)? {
+            roots.add(&ca)?;
+        }
+        if roots.is_empty() {
+            return Err(anyhow!("No certificates in trust bundle {:?}", bundle));
+        }
+
+        Ok(Svid {
+            id,
+            key: CertifiedKey::new(chain, Arc::new(signer)),
+            bundle: roots,
+        })
+    }
+
+    // Checks the chain is signed by the bundle and returns the SPIFFE ID
+    fn verify_peer(&self, presented: &[Certificate], as_server: bool) -> Result<String, TLSError> {
+        let leaf = presented.first().ok_or(TLSError::NoCertificatesPresented)?;
+        let cert = webpki::EndEntityCert::from(&leaf.0).map_err(TLSError::WebPKIError)?;
+        let intermediates: Vec<&[u8]> = presented[1..].iter().map(|c| c.0.as_ref()).collect();
+        let anchors: Vec<webpki::TrustAnchor> = self
+            .bundle
+            .roots
+            .iter()
+            .map(|r| r.to_trust_anchor())
+            .collect();
+        let now = webpki::Time::try_from(SystemTime::now())
+            .map_err(|_| TLSError::FailedToGetCurrentTime)?;
+
+        if as_server {
+            cert.verify_is_valid_tls_server_cert(
+                SUPPORTED_SIG_ALGS,
+                &webpki::TLSServerTrustAnchors(&anchors),
+                &intermediates,
+                now,
+            )
+        } else {
+            cert.verify_is_valid_tls_client_cert(
+                SUPPORTED_SIG_ALGS,
+                &webpki::TLSClientTrustAnchors(&anchors),
+                &intermediates,
+                now,
+            )
+        }
+        .map_err(TLSError::WebPKIError)?;
+
+        spiffe_id(&leaf.0)
+            .ok_or_else(|| TLSError::General("Peer certificate is not an X.509 SVID".to_string()))
+    }
+
+    fn authorize_peer(&self, presented: &[Certificate], as_server: bool) -> Result<(), TLSError> {
+        let id = self.verify_peer(presented, as_server)?;
+        if id != self.id {
+            warn!("Rejected connection from {}, expected {}", id, self.id);
+            return Err(TLSError::General(format!("SPIFFE ID {} not allowed", id)));
+        }
+        Ok(())
+    }
+}
+
+/*
+ * An SVID and trust bundle read from files, reloaded when any of them change.
+ *
+ * A failed reload (e.g. a file caught half written) keeps the previous SVID.
+ */
+pub struct SvidSource {
+    paths: [PathBuf; 3],
+    current: RwLock<(Vec<SystemTime>, Arc<Svid>)>,
+}
+
+impl SvidSource {
+    pub fn new(cert: &str, key: &str, bundle: &str) -> Result<Arc<SvidSource>> {
+        let paths = [cert.into(), key.into(), bundle.into()];
+        let modified = Self::modified(&paths);
+        let svid = Svid::load(&paths[0], &paths[1], &paths[2])?;
+        info!("Loaded SVID for {}", svid.id);
+        Ok(Arc::new(SvidSource {
+            paths,
+            current: RwLock::new((modified, Arc::new(svid))),
+        }))
+    }
+
+    fn modified(paths: &[PathBuf]) -> Vec<SystemTime> {
+        paths
+            .iter()
+            .map(|p| {
+                fs::metadata(p)
+                    .and_then(|m| m.modified())
+                    .unwrap_or(SystemTime::UNIX_EPOCH)
+            })
+            .collect()
+    }
+
+    fn svid(&self) -> Arc<Svid> {
+        let modified = Self::modified(&self.paths);
+        {
+            let current = self.current.read().unwrap();
+            if current.0 == modified {
+                return current.1.clone();
+            }
+        }
+
+        let mut current = self.current.write().unwrap();
+        match Svid::load(&self.paths[0], &self.paths[1], &self.paths[2]) {
+            Ok(svid) => {
+                info!("Reloaded SVID for {}", svid.id);
+                *current = (modified, Arc::new(svid));
+            }
+            Err(e) => warn!("Failed to reload SVID, keeping the old one: {:?}", e),
+        }
+        current.1.clone()
+    }
+
+    pub fn id(&self) -> String {
+        self.svid().id.clone()
+    }
+
+    pub fn trust_domain(&self) -> String {
+        let id = self.id();
+        trust_domain(&id).unwrap_or_default().to_string()
+    }
+}
+
+struct SpiffeVerifier(Arc<SvidSource>);
+
+impl ClientCertVerifier for SpiffeVerifier {
+    fn client_auth_root_subjects(
+        &self,
+        _sni: Option<&webpki::DNSName>,
+    ) -> Option<DistinguishedNames> {
+        Some(self.0.svid().bundle.get_subjects())
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented: &[Certificate],
+        _sni: Option<&webpki::DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        self.0
+            .svid()
+            .authorize_peer(presented, false)
+            .map(|_| ClientCertVerified::assertion())
+    }
+}
+
+impl ServerCertVerifier for SpiffeVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        self.0
+            .svid()
+            .authorize_peer(presented, true)
+            .map(|_| ServerCertVerified::assertion())
+    }
+}
+
+impl ResolvesServerCert for SpiffeVerifier {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.0.svid().key.clone())
+    }
+}
+
+impl ResolvesClientCert for SpiffeVerifier {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        Some(self.0.svid().key.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// TLS config for the backend, only accepting the frontend's SVID
+pub fn server_tls_config(source: Arc<SvidSource>) -> ServerTlsConfig {
+    let verifier = Arc::new(SpiffeVerifier(source));
+    let mut config = ServerConfig::new(verifier.clone());
+    config.cert_resolver = verifier;
+    config.set_protocols(&[ALPN_H2.to_vec()]);
+    let mut tls = ServerTlsConfig::new();
+    tls.rustls_server_config(config);
+    tls
+}
+
+/// TLS config for the frontend, only accepting the backend's SVID
+pub fn client_tls_config(source: Arc<SvidSource>) -> ClientTlsConfig {
+    // Only used for SNI, the verifier doesn't check names
+    let domain = source.trust_domain();
+    let verifier = Arc::new(SpiffeVerifier(source));
+    let mut config = ClientConfig::new();
+    config
+        .dangerous()
+        .set_certificate_verifier(verifier.clone());
+    config.client_auth_cert_resolver = verifier;
+    config.set_protocols(&[ALPN_H2.to_vec()]);
+    ClientTlsConfig::new()
+        .rustls_client_config(config)
+        .domain_name(domain)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{trust_domain, IdPattern};
+
+    #[test]
+    fn match_ids() {
+        let exact: IdPattern = "spiffe://example.org/ns/ci/sa/builder".parse().unwrap();
+        assert!(exact.matches("spiffe://example.org/ns/ci/sa/builder"));
+        assert!(!exact.matches("spiffe://example.org/ns/ci/sa/builder2"));
+
+        let prefix: IdPattern = "spiffe://example.org/ns/ci/*".parse().unwrap();
+        assert!(prefix.matches("spiffe://example.org/ns/ci/sa/builder"));
+        assert!(!prefix.matches("spiffe://example.org/ns/ci/"));
+        assert!(!prefix.matches("spiffe://example.org/ns/cd/sa/deployer"));
+        assert!(!prefix.matches("spiffe://other.org/ns/ci/sa/builder"));
+
+        assert!("https://example.org/ns/ci".parse::<IdPattern>().is_err());
+        assert!("spiffe:///ns/ci".parse::<IdPattern>().is_err());
+        assert_eq!(
+            trust_domain("spiffe://example.org/a").unwrap(),
+            "example.org"
+        );
+    }
+}
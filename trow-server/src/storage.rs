@@ -0,0 +1,542 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use uuid::Uuid;
+
+use crate::azure::AzureBlob;
+use crate::digest::sha256_tag_digest;
+use crate::egress::EgressProxies;
+use crate::gcs::Gcs;
+use crate::layout::LAYOUT_FILE;
+use crate::maintenance::{digest_for_blob, walk_files};
+
+/*
+ * Keeping the registry in a cloud storage bucket, so the backend doesn't need a persistent volume.
+ *
+ * With --storage, the bucket holds the blobs, tag files, repository links, trash, tenants and
+ * layout version, laid out like the data dir, and the data dir is a write-through mirror of it
+ * that can be an emptyDir. Every change is written through to the bucket before it's
+ * acknowledged, and at startup the data dir is brought up to date from the bucket, so the backend
+ * can be rescheduled onto a node with an empty data dir and carry on. Only blobs missing from the data dir are copied, and each is
+ * checked against its digest.
+ *
+ * The data dir isn't a cache: it has to have room for the whole registry, as blobs are still
+ * served from it. Pull counts in the metadata database, usage reports, transcoded layers and the images admitted before a change freeze are only kept
+ * in the data dir, and start again from nothing on a new one.
+ *
+ * Drivers implement StorageDriver: Azure Blob Storage (azure.rs) and Google Cloud Storage
+ * (gcs.rs). Both get credentials from the platform, through workload identity or the instance
+ * metadata service, so there are no keys to manage.
+ *
+ * As with a data dir, only one backend can use a bucket at a time.
+ */
+
+static MANIFESTS_DIR: &str = "manifests";
+static BLOBS_DIR: &str = "blobs";
+static LINKS_DIR: &str = "links";
+static TRASH_DIR: &str = "trash";
+static TENANTS_FILE: &str = "tenants.json";
+
+// Tokens are refreshed this long before they expire
+const TOKEN_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/*
+ * Somewhere to keep the registry. Paths are relative to the data dir and always use /.
+ *
+ * Methods block, so must be called off the async runtime or through Storage, which takes care
+ * of it.
+ */
+pub trait StorageDriver: Send + Sync {
+    // For logs
+    fn describe(&self) -> String;
+
+    /// Copies the object into the local file, returning false if there's no such object
+    fn get(&self, path: &str, local_path: &Path) -> Result<bool>;
+
+    fn put(&self, path: &str, local_path: &Path) -> Result<()>;
+
+    /// Succeeds if there's no such object
+    fn delete(&self, path: &str) -> Result<()>;
+
+    /// Every object under the directory, e.g. "manifests", with its size
+    fn list(&self, dir: &str) -> Result<Vec<(String, u64)>>;
+}
+
+/*
+ * The driver for a storage URL:
+ *
+ *   azure://<account>/<container>[/<prefix>]   Azure Blob Storage
+ *   gs://<bucket>[/<prefix>]                   Google Cloud Storage
+ */
+pub fn open_driver(url: &str, egress: &EgressProxies) -> Result<Arc<dyn StorageDriver>> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| anyhow!("Invalid storage URL {}, expected azure:// or gs://", url))?;
+    let mut parts = rest.trim_matches('/').splitn(3, '/');
+    let mut next = || parts.next().filter(|p| !p.is_empty()).map(str::to_string);
+    match scheme {
+        "azure" => {
+            let account = next().ok_or_else(|| anyhow!("No account in {}", url))?;
+            let container = next().ok_or_else(|| anyhow!("No container in {}", url))?;
+            Ok(Arc::new(AzureBlob::new(
+                &account,
+                &container,
+                next(),
+                egress,
+            )?))
+        }
+        "gs" => {
+            let bucket = next().ok_or_else(|| anyhow!("No bucket in {}", url))?;
+            let prefix = match (next(), next()) {
+                (Some(a), Some(b)) => Some(format!("{}/{}", a, b)),
+                (a, _) => a,
+            };
+            Ok(Arc::new(Gcs::new(&bucket, prefix, egress)?))
+        }
+        _ => Err(anyhow!(
+            "Unsupported storage URL {}, expected azure:// or gs://",
+            url
+        )),
+    }
+}
+
+// The object name for a path, under the prefix if there is one
+pub(crate) fn object_name(prefix: &Option<String>, path: &str) -> String {
+    match prefix {
+        Some(p) => format!("{}/{}", p.trim_end_matches('/'), path),
+        None => path.to_string(),
+    }
+}
+
+/*
+ * An access token from the platform, fetched again shortly before it expires.
+ */
+#[derive(Default)]
+pub(crate) struct CachedToken {
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl CachedToken {
+    /// The token, or a new one from fetch, which returns it with how long it lasts
+    pub fn get(&self, fetch: impl FnOnce() -> Result<(String, Duration)>) -> Result<String> {
+        let mut token = self.token.lock().unwrap();
+        if let Some((value, expires)) = token.as_ref() {
+            if Instant::now() + TOKEN_MARGIN < *expires {
+                return Ok(value.clone());
+            }
+        }
+        let (value, lasts) = fetch()?;
+        *token = Some((value.clone(), Instant::now() + lasts));
+        Ok(value)
+    }
+}
+
+// expires_in, which some token endpoints give as a string
+pub(crate) fn expires_in(resp: &serde_json::Value) -> Duration {
+    let secs = match &resp["expires_in"] {
+        serde_json::Value::String(s) => s.parse().unwrap_or(0),
+        v => v.as_u64().unwrap_or(0),
+    };
+    Duration::from_secs(secs)
+}
+
+// Blocking calls are moved off the async runtime's workers, or just made elsewhere
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    tokio::task::block_in_place(f)
+}
+
+/*
+ * The data dir kept in step with a storage driver. Cheap to clone.
+ *
+ * Writes of the same file are made one at a time, each uploading the file as it is when its turn
+ * comes, so the bucket ends up with the last change whatever order they were started in. Writes
+ * of different files, e.g. tags in different repositories, don't wait on each other.
+ */
+#[derive(Clone)]
+pub struct Storage {
+    driver: Arc<dyn StorageDriver>,
+    data_path: PathBuf,
+    // Paths being written, see one_at_a_time
+    writing: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+}
+
+impl Storage {
+    pub fn new(driver: Arc<dyn StorageDriver>, data_path: &Path) -> Storage {
+        Storage {
+            driver,
+            data_path: data_path.to_path_buf(),
+            writing: Arc::default(),
+        }
+    }
+
+    /// The data dir kept in step with the bucket at the URL, see open_driver
+    pub fn open(url: &str, data_path: &Path, egress: &EgressProxies) -> Result<Storage> {
+        // The drivers' HTTP clients can't be created on the async runtime
+        let driver = blocking(|| open_driver(url, egress))?;
+        Ok(Storage::new(driver, data_path))
+    }
+
+    pub fn describe(&self) -> String {
+        self.driver.describe()
+    }
+
+    fn relative(&self, local_path: &Path) -> Result<String> {
+        let rel = local_path
+            .strip_prefix(&self.data_path)
+            .map_err(|_| anyhow!("{:?} is outside the data dir", local_path))?;
+        Ok(rel.to_string_lossy().replace('\\', "/"))
+    }
+
+    // Runs f once no other write of the path is running. Must be called off the async runtime
+    fn one_at_a_time<T>(&self, path: &str, f: impl FnOnce() -> T) -> T {
+        let lock = self
+            .writing
+            .lock()
+            .unwrap()
+            .entry(path.to_string())
+            .or_default()
+            .clone();
+        let result = {
+            let _guard = lock.lock().unwrap();
+            f()
+        };
+        let mut writing = self.writing.lock().unwrap();
+        // Just this and the map's, so nothing else is waiting
+        if Arc::strong_count(&lock) == 2 {
+            writing.remove(path);
+        }
+        result
+    }
+
+    /// Writes the file through to the bucket, or removes it from the bucket if it's been removed
+    pub fn sync(&self, local_path: &Path) -> Result<()> {
+        let path = self.relative(local_path)?;
+        blocking(|| {
+            self.one_at_a_time(&path, || {
+                if local_path.is_file() {
+                    self.driver.put(&path, local_path)
+                } else {
+                    self.driver.delete(&path)
+                }
+            })
+        })
+    }
+
+    /*
+     * Makes the bucket match a directory the data dir has all of, e.g. the links, uploading new
+     * files and removing those that have gone. Files already in the bucket aren't uploaded again,
+     * so this is for directories whose files don't change.
+     */
+    pub fn sync_dir(&self, local_dir: &Path) -> Result<()> {
+        let dir = self.relative(local_dir)?;
+        blocking(|| {
+            let mut remote: HashMap<String, u64> = self.driver.list(&dir)?.into_iter().collect();
+            for file in walk_files(local_dir)? {
+                let path = self.relative(&file)?;
+                if remote.remove(&path).is_none() {
+                    self.one_at_a_time(&path, || self.driver.put(&path, &file))?;
+                }
+            }
+            for path in remote.keys() {
+                self.one_at_a_time(path, || self.driver.delete(path))?;
+            }
+            Ok(())
+        })
+    }
+
+    // What migrating the data dir changes (see layout.rs), the links and the layout version
+    pub fn sync_migrated(&self) -> Result<()> {
+        self.sync_dir(&self.data_path.join(LINKS_DIR))?;
+        self.sync(&self.data_path.join(LAYOUT_FILE))
+    }
+
+    /*
+     * Brings the data dir up to date from the bucket, before the server starts.
+     *
+     * Tag files, links, the trash, tenants and the layout version are replaced by the bucket's, and blobs the data
+     * dir is missing are copied. Blobs only in the data dir are left for garbage collection.
+     */
+    pub fn restore(&self) -> Result<()> {
+        info!("Reading the registry from {}", self.describe());
+        blocking(|| {
+            for dir in [MANIFESTS_DIR, LINKS_DIR, TRASH_DIR] {
+                let copied = self.replace_dir(dir)?;
+                info!("Read {} files from {}", copied, dir);
+            }
+            for file in [TENANTS_FILE, LAYOUT_FILE] {
+                let local_path = self.data_path.join(file);
+                if !self.get_file(file, &local_path)? && local_path.exists() {
+                    fs::remove_file(&local_path)?;
+                }
+            }
+            let copied = self.copy_missing_blobs()?;
+            info!("Read {} blobs from {}", copied, self.describe());
+            Ok(())
+        })
+    }
+
+    fn replace_dir(&self, dir: &str) -> Result<usize> {
+        let remote: HashMap<String, u64> = self.driver.list(dir)?.into_iter().collect();
+        for file in walk_files(&self.data_path.join(dir))? {
+            if !remote.contains_key(&self.relative(&file)?) {
+                fs::remove_file(&file)?;
+            }
+        }
+        for (path, size) in &remote {
+            let local_path = self.data_path.join(path);
+            // Links are empty, so there's nothing to read
+            if *size == 0 {
+                if let Some(parent) = local_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&local_path, b"")?;
+            } else {
+                self.get_file(path, &local_path)?;
+            }
+        }
+        Ok(remote.len())
+    }
+
+    fn copy_missing_blobs(&self) -> Result<usize> {
+        let blobs_path = self.data_path.join(BLOBS_DIR);
+        let mut copied = 0;
+        for (path, _) in self.driver.list(BLOBS_DIR)? {
+            let local_path = self.data_path.join(&path);
+            if local_path.exists() {
+                continue;
+            }
+            let digest = match digest_for_blob(&blobs_path, &local_path) {
+                Some(d) => d,
+                None => {
+                    warn!("Ignoring {} in {}, not a blob", path, self.describe());
+                    continue;
+                }
+            };
+            self.get_blob(&path, &digest, &local_path)?;
+            copied += 1;
+        }
+        Ok(copied)
+    }
+
+    // Written alongside and moved into place, so it's never seen half written
+    fn get_file(&self, path: &str, local_path: &Path) -> Result<bool> {
+        let dir = local_path
+            .parent()
+            .ok_or_else(|| anyhow!("No directory for {:?}", local_path))?;
+        fs::create_dir_all(dir)?;
+        let tmp_path = dir.join(format!(".{}", Uuid::new_v4()));
+        let res = self.driver.get(path, &tmp_path).and_then(|found| {
+            if found {
+                fs::rename(&tmp_path, local_path)?;
+            }
+            Ok(found)
+        });
+        let _ = fs::remove_file(&tmp_path);
+        res
+    }
+
+    fn get_blob(&self, path: &str, digest: &str, local_path: &Path) -> Result<()> {
+        if !self.get_file(path, local_path)? {
+            return Err(anyhow!(
+                "Blob {} went missing from {}",
+                digest,
+                self.describe()
+            ));
+        }
+        let actual = sha256_tag_digest(BufReader::new(File::open(local_path)?))?;
+        if actual != digest {
+            let _ = fs::remove_file(local_path);
+            return Err(anyhow!(
+                "Blob {} in {} has digest {}",
+                digest,
+                self.describe(),
+                actual
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Storage, StorageDriver};
+    use crate::maintenance::walk_files;
+    use anyhow::Result;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    // A bucket in a local directory
+    struct DirDriver {
+        root: PathBuf,
+    }
+
+    impl StorageDriver for DirDriver {
+        fn describe(&self) -> String {
+            "test bucket".to_string()
+        }
+
+        fn get(&self, path: &str, local_path: &Path) -> Result<bool> {
+            match fs::copy(self.root.join(path), local_path) {
+                Ok(_) => Ok(true),
+                Err(_) => Ok(false),
+            }
+        }
+
+        fn put(&self, path: &str, local_path: &Path) -> Result<()> {
+            let path = self.root.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::copy(local_path, path)?;
+            Ok(())
+        }
+
+        fn delete(&self, path: &str) -> Result<()> {
+            let _ = fs::remove_file(self.root.join(path));
+            Ok(())
+        }
+
+        fn list(&self, dir: &str) -> Result<Vec<(String, u64)>> {
+            Ok(walk_files(&self.root.join(dir))?
+                .iter()
+                .map(|p| {
+                    let rel = p.strip_prefix(&self.root).unwrap();
+                    (
+                        rel.to_string_lossy().to_string(),
+                        p.metadata().unwrap().len(),
+                    )
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn restores_data_dir_from_bucket() {
+        let bucket = tempdir().unwrap();
+        let data = tempdir().unwrap();
+        let storage = Storage::new(
+            Arc::new(DirDriver {
+                root: bucket.path().to_path_buf(),
+            }),
+            data.path(),
+        );
+
+        let tag = data.path().join("manifests/app/latest");
+        let blob = data
+            .path()
+            .join("blobs/sha256/b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+        let link = data.path().join("links/app/_blobs/sha256/b94d27b9");
+        for (path, contents) in [(&tag, "sha256:abc\n"), (&blob, "hello world"), (&link, "")] {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+            storage.sync(path).unwrap();
+        }
+
+        // A new, empty data dir
+        let data = tempdir().unwrap();
+        fs::create_dir_all(data.path().join("manifests/old")).unwrap();
+        fs::write(data.path().join("manifests/old/v1"), "sha256:def\n").unwrap();
+        let storage = Storage::new(
+            Arc::new(DirDriver {
+                root: bucket.path().to_path_buf(),
+            }),
+            data.path(),
+        );
+        storage.restore().unwrap();
+        assert_eq!(
+            fs::read_to_string(data.path().join("manifests/app/latest")).unwrap(),
+            "sha256:abc\n"
+        );
+        assert!(!data.path().join("manifests/old/v1").exists());
+        assert!(data
+            .path()
+            .join("links/app/_blobs/sha256/b94d27b9")
+            .exists());
+        let blobs = walk_files(&data.path().join("blobs")).unwrap();
+        assert_eq!(blobs.len(), 1);
+
+        // Removing the tag removes it from the bucket
+        let tag = data.path().join("manifests/app/latest");
+        fs::remove_file(&tag).unwrap();
+        storage.sync(&tag).unwrap();
+        assert!(!bucket.path().join("manifests/app/latest").exists());
+
+        // Corrupt blobs aren't copied
+        fs::write(bucket.path().join("blobs/sha256/0000"), "junk").unwrap();
+        assert!(storage.restore().is_err());
+    }
+
+    // Counts the puts under way at once
+    #[derive(Default)]
+    struct SlowDriver {
+        putting: AtomicUsize,
+        most: AtomicUsize,
+    }
+
+    impl StorageDriver for SlowDriver {
+        fn describe(&self) -> String {
+            "slow bucket".to_string()
+        }
+
+        fn get(&self, _path: &str, _local_path: &Path) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn put(&self, _path: &str, _local_path: &Path) -> Result<()> {
+            let n = self.putting.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most.fetch_max(n, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            self.putting.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn delete(&self, _path: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn list(&self, _dir: &str) -> Result<Vec<(String, u64)>> {
+            Ok(vec![])
+        }
+    }
+
+    #[test]
+    fn writes_each_file_one_at_a_time() {
+        let data = tempdir().unwrap();
+        let driver = Arc::new(SlowDriver::default());
+        let storage = Storage::new(driver.clone(), data.path());
+        for tag in ["a/latest", "b/latest"] {
+            let path = data.path().join("manifests").join(tag);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "sha256:abc\n").unwrap();
+        }
+
+        let sync = |tag: &'static str| {
+            let storage = storage.clone();
+            let path = data.path().join("manifests").join(tag);
+            thread::spawn(move || storage.sync(&path).unwrap())
+        };
+        let same: Vec<_> = (0..4).map(|_| sync("a/latest")).collect();
+        for t in same {
+            t.join().unwrap();
+        }
+        assert_eq!(driver.most.load(Ordering::SeqCst), 1);
+
+        // Different files go at once
+        driver.most.store(0, Ordering::SeqCst);
+        let different = [sync("a/latest"), sync("b/latest")];
+        for t in different {
+            t.join().unwrap();
+        }
+        assert_eq!(driver.most.load(Ordering::SeqCst), 2);
+        assert!(storage.writing.lock().unwrap().is_empty());
+    }
+}
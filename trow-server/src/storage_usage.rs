@@ -0,0 +1,185 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::maintenance::{referenced_by_tags, walk_files};
+use crate::quota::blob_size;
+use crate::retention::is_digest;
+
+/*
+ * What's using the space in the data dir, by repository.
+ *
+ * Blobs are stored once however many repositories use them, so each repository's bytes are split
+ * into those only it uses, which deleting it would free, and those it shares with other
+ * repositories. The registry totals count each blob once, and compare what's referenced by any
+ * tag with everything in the blobs dir, the difference being what garbage collection would free.
+ */
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepoUsage {
+    pub repo_name: String,
+    // Not counting manifests pushed by digest
+    pub tags: u64,
+    // Manifests and blobs referenced, including by the history of tags
+    pub blobs: u64,
+    // Used by no other repository
+    pub unique_bytes: u64,
+    pub shared_bytes: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegistryUsage {
+    pub repositories: u64,
+    pub tags: u64,
+    // Referenced by any repository, each counted once
+    pub blobs: u64,
+    pub bytes: u64,
+    // Everything in the blobs dir, referenced or not
+    pub stored_blobs: u64,
+    pub stored_bytes: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StorageUsage {
+    pub repositories: Vec<RepoUsage>,
+    pub registry: RegistryUsage,
+}
+
+// Given each repository with its tag files
+pub fn storage_usage(repos: &[(String, Vec<PathBuf>)], blobs_path: &Path) -> Result<StorageUsage> {
+    let mut referenced: Vec<(&str, u64, HashSet<String>)> = vec![];
+    // How many repositories use each digest
+    let mut users: HashMap<String, u64> = HashMap::new();
+    for (repo_name, files) in repos {
+        let digests = referenced_by_tags(files, blobs_path)?;
+        for d in &digests {
+            *users.entry(d.clone()).or_insert(0) += 1;
+        }
+        let tags = files
+            .iter()
+            .filter_map(|f| f.file_name())
+            .filter(|t| !is_digest(&t.to_string_lossy()))
+            .count();
+        referenced.push((repo_name, tags as u64, digests));
+    }
+
+    let sizes: HashMap<&String, u64> = users
+        .keys()
+        .map(|d| (d, blob_size(blobs_path, d)))
+        .collect();
+    let mut usage = StorageUsage::default();
+    for (repo_name, tags, digests) in referenced {
+        let mut repo = RepoUsage {
+            repo_name: repo_name.to_string(),
+            tags,
+            blobs: digests.len() as u64,
+            ..RepoUsage::default()
+        };
+        for d in &digests {
+            let size = sizes.get(d).copied().unwrap_or(0);
+            if users.get(d).copied().unwrap_or(0) > 1 {
+                repo.shared_bytes += size;
+            } else {
+                repo.unique_bytes += size;
+            }
+        }
+        usage.registry.tags += tags;
+        usage.repositories.push(repo);
+    }
+    // Largest first
+    usage.repositories.sort_by(|a, b| {
+        (b.unique_bytes + b.shared_bytes)
+            .cmp(&(a.unique_bytes + a.shared_bytes))
+            .then(a.repo_name.cmp(&b.repo_name))
+    });
+
+    usage.registry.repositories = repos.len() as u64;
+    usage.registry.blobs = users.len() as u64;
+    usage.registry.bytes = sizes.values().sum();
+    for path in walk_files(blobs_path)? {
+        usage.registry.stored_blobs += 1;
+        usage.registry.stored_bytes += fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod test {
+    use super::storage_usage;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn splits_shared_bytes() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        let blobs = dir.path().join("blobs");
+        fs::create_dir_all(manifests.join("a")).unwrap();
+        fs::create_dir_all(manifests.join("b")).unwrap();
+        fs::create_dir_all(blobs.join("sha256")).unwrap();
+
+        let manifest = |config: char, layer: char| {
+            format!(
+                r#"{{"schemaVersion": 2,
+                "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                "config": {{"mediaType": "application/vnd.docker.container.image.v1+json", "digest": "sha256:{}"}},
+                "layers": [{{"mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "digest": "sha256:{}"}}]}}"#,
+                config.to_string().repeat(64),
+                layer.to_string().repeat(64)
+            )
+        };
+        let blob = |hex: char, contents: &str| {
+            fs::write(
+                blobs.join("sha256").join(hex.to_string().repeat(64)),
+                contents,
+            )
+            .unwrap();
+        };
+        // Both images share the layer
+        let m1 = manifest('c', 'd');
+        let m2 = manifest('e', 'd');
+        blob('1', &m1);
+        blob('2', &m2);
+        blob('c', "cc");
+        blob('e', "eeee");
+        blob('d', "dddddddddd");
+        // Not referenced by anything
+        blob('f', "ff");
+        let tag = |repo: &str, name: &str, hex: char| {
+            let path = manifests.join(repo).join(name);
+            let line = format!(
+                "sha256:{} 2022-01-01T00:00:00Z\n",
+                hex.to_string().repeat(64)
+            );
+            fs::write(&path, line).unwrap();
+            path
+        };
+        let repos = vec![
+            ("a".to_string(), vec![tag("a", "v1", '1')]),
+            (
+                "b".to_string(),
+                vec![tag("b", "v1", '2'), tag("b", "latest", '2')],
+            ),
+        ];
+
+        let usage = storage_usage(&repos, &blobs).unwrap();
+        assert_eq!(usage.repositories[0].repo_name, "b");
+        assert_eq!(usage.repositories[0].tags, 2);
+        assert_eq!(usage.repositories[0].blobs, 3);
+        assert_eq!(usage.repositories[0].unique_bytes, m2.len() as u64 + 4);
+        assert_eq!(usage.repositories[0].shared_bytes, 10);
+        assert_eq!(usage.repositories[1].repo_name, "a");
+        assert_eq!(usage.repositories[1].unique_bytes, m1.len() as u64 + 2);
+        assert_eq!(usage.repositories[1].shared_bytes, 10);
+
+        let registry = &usage.registry;
+        assert_eq!(registry.repositories, 2);
+        assert_eq!(registry.tags, 3);
+        assert_eq!(registry.blobs, 5);
+        assert_eq!(registry.bytes, (m1.len() + m2.len()) as u64 + 2 + 4 + 10);
+        assert_eq!(registry.stored_blobs, 6);
+        assert_eq!(registry.stored_bytes, registry.bytes + 2);
+    }
+}
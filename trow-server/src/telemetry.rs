@@ -0,0 +1,145 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+
+use opentelemetry::global;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{FutureExt, SpanKind, SpanRef, StatusCode, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use tonic::codegen::http::{self, HeaderMap, HeaderValue};
+use tonic::codegen::Service;
+use tonic::transport::NamedService;
+
+/*
+ * OpenTelemetry spans for the gRPC calls between the frontend and backend.
+ *
+ * The frontend sets up the exporter and the W3C trace context propagator, so spans are only
+ * exported when tracing is configured. Otherwise the global tracer does nothing. The frontend
+ * injects the trace context into the call's metadata and the backend picks it up, so backend
+ * spans are children of the route that made the call.
+ */
+
+pub const TRACER_NAME: &str = "trow";
+
+pub struct HeaderExtractor<'a>(pub &'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+pub struct HeaderInjector<'a>(pub &'a mut HeaderMap);
+
+impl<'a> Injector for HeaderInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            http::header::HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/*
+ * Records the gRPC status of a call on its span.
+ *
+ * Only statuses in the response headers are seen, which covers calls failing straight away.
+ * Errors in the trailers of a successful response aren't recorded.
+ */
+pub fn record_grpc_response<R, E: std::fmt::Display>(
+    span: &SpanRef,
+    res: &Result<http::Response<R>, E>,
+) {
+    match res {
+        Ok(resp) => {
+            let code = resp
+                .headers()
+                .get("grpc-status")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<i64>().ok());
+            if let Some(code) = code {
+                span.set_attribute(KeyValue::new("rpc.grpc.status_code", code));
+                if code != 0 {
+                    let message = resp
+                        .headers()
+                        .get("grpc-message")
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default();
+                    span.set_status(StatusCode::Error, message.to_string());
+                }
+            }
+        }
+        Err(e) => span.set_status(StatusCode::Error, e.to_string()),
+    }
+}
+
+/*
+ * Wraps a gRPC service so each call gets a span, continuing the trace from the caller.
+ */
+#[derive(Clone)]
+pub struct Traced<S>(pub S);
+
+impl<S: NamedService> NamedService for Traced<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B, R> Service<http::Request<B>> for Traced<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<R>>,
+    S::Error: std::fmt::Display,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let parent =
+            global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(req.headers())));
+        let tracer = global::tracer(TRACER_NAME);
+        let span = tracer
+            .span_builder(req.uri().path().to_string())
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![KeyValue::new("rpc.system", "grpc")])
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+
+        let fut = self.0.call(req).with_context(cx.clone());
+        Box::pin(async move {
+            let res = fut.await;
+            record_grpc_response(&cx.span(), &res);
+            cx.span().end();
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HeaderExtractor, HeaderInjector};
+    use opentelemetry::propagation::{Extractor, Injector};
+    use tonic::codegen::http::HeaderMap;
+
+    #[test]
+    fn headers_round_trip() {
+        let mut headers = HeaderMap::new();
+        HeaderInjector(&mut headers).set("traceparent", "00-abc-def-01".to_string());
+        // Invalid values are dropped rather than failing the call
+        HeaderInjector(&mut headers).set("tracestate", "bad\nvalue".to_string());
+
+        let extractor = HeaderExtractor(&headers);
+        assert_eq!(extractor.get("traceparent"), Some("00-abc-def-01"));
+        assert_eq!(extractor.get("tracestate"), None);
+        assert_eq!(extractor.keys(), vec!["traceparent"]);
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::maintenance::walk_files;
+use crate::quota::Quota;
+use crate::retention::RetentionRule;
+
+/*
+ * Tenants, each owning a top-level namespace such as team-a, i.e. team-a and every repository
+ * under team-a/.
+ *
+ * A tenant has its members and what each can do, which the frontend checks, as well as a quota
+ * and retention rules for its namespace. The quota applies unless there's a more specific one
+ * from --quota, or one for the same namespace, and the retention rules are applied along with the
+ * --retention rules.
+ *
+ * Tenants are created and deleted through the admin API and kept in the data dir, so they're
+ * shared by every frontend. A tenant can only be deleted once its repositories are, so its
+ * images never end up readable by everyone.
+ */
+
+static TENANTS_FILE: &str = "tenants.json";
+
+const PERMISSIONS: [&str; 3] = ["pull", "push", "delete"];
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    pub user: String,
+    // pull, push or delete
+    pub permission: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tenant {
+    pub name: String,
+    pub members: Vec<Member>,
+    // BYTES[:IMAGES], as for --quota
+    pub quota: Option<String>,
+    // As for --retention, only selecting repositories in the tenant's namespace
+    pub retention: Vec<String>,
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_lowercase() || c.is_ascii_digit())
+        && chars
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '-'))
+}
+
+impl Tenant {
+    // Fails with why the tenant can't be stored
+    pub fn validate(&self) -> Result<()> {
+        if !is_valid_name(&self.name) {
+            return Err(anyhow!(
+                "Invalid tenant name {}, expected a single lowercase path component",
+                self.name
+            ));
+        }
+        for m in &self.members {
+            if m.user.is_empty() {
+                return Err(anyhow!("Tenant {} has a member with no user", self.name));
+            }
+            if !PERMISSIONS.contains(&m.permission.as_str()) {
+                return Err(anyhow!(
+                    "Unknown permission {} for {}, expected pull, push or delete",
+                    m.permission,
+                    m.user
+                ));
+            }
+        }
+        self.quota()?;
+        self.retention_rules()?;
+        Ok(())
+    }
+
+    pub fn quota(&self) -> Result<Option<Quota>> {
+        self.quota
+            .as_ref()
+            .map(|q| format!("{}={}", self.name, q).parse())
+            .transpose()
+    }
+
+    pub fn retention_rules(&self) -> Result<Vec<RetentionRule>> {
+        let namespace = format!("{}/", self.name);
+        self.retention
+            .iter()
+            .map(|r| {
+                if !r.starts_with(&namespace) {
+                    return Err(anyhow!(
+                        "Retention rule {} must only select repositories under {}",
+                        r,
+                        namespace
+                    ));
+                }
+                r.parse()
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct Tenants {
+    path: PathBuf,
+    tenants: Arc<RwLock<BTreeMap<String, Tenant>>>,
+}
+
+impl Tenants {
+    pub fn load(data_path: &Path) -> Result<Tenants> {
+        let path = data_path.join(TENANTS_FILE);
+        let tenants = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Tenants {
+            path,
+            tenants: Arc::new(RwLock::new(tenants)),
+        })
+    }
+
+    // By name
+    pub fn list(&self) -> Vec<Tenant> {
+        self.tenants.read().unwrap().values().cloned().collect()
+    }
+
+    // Creates the tenant, or replaces the one with the same name
+    // Where the tenants are stored
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn put(&self, tenant: Tenant) -> Result<()> {
+        tenant.validate()?;
+        let mut all = self.tenants.write().unwrap();
+        let mut updated = all.clone();
+        updated.insert(tenant.name.clone(), tenant);
+        self.save(&updated)?;
+        *all = updated;
+        Ok(())
+    }
+
+    /*
+     * Deletes the tenant, returning whether there was one. Fails if there are still repositories
+     * in its namespace.
+     */
+    pub fn remove(&self, manifests_path: &Path, name: &str) -> Result<bool> {
+        let mut all = self.tenants.write().unwrap();
+        if !all.contains_key(name) {
+            return Ok(false);
+        }
+        if is_valid_name(name) && !walk_files(&manifests_path.join(name))?.is_empty() {
+            return Err(TenantInUse {
+                name: name.to_string(),
+            }
+            .into());
+        }
+        let mut updated = all.clone();
+        updated.remove(name);
+        self.save(&updated)?;
+        *all = updated;
+        Ok(true)
+    }
+
+    // Whether the user can see the repository, i.e. it's not a tenant's or they're a member
+    pub fn can_see(&self, user: &str, repo_name: &str) -> bool {
+        let namespace = repo_name.split('/').next().unwrap_or_default();
+        match self.tenants.read().unwrap().get(namespace) {
+            Some(t) => t.members.iter().any(|m| m.user == user),
+            None => true,
+        }
+    }
+
+    pub fn quotas(&self) -> Vec<Quota> {
+        self.tenants
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|t| t.quota().ok().flatten())
+            .collect()
+    }
+
+    pub fn retention_rules(&self) -> Vec<RetentionRule> {
+        self.tenants
+            .read()
+            .unwrap()
+            .values()
+            .flat_map(|t| t.retention_rules().unwrap_or_default())
+            .collect()
+    }
+
+    // Renamed into place so a crash can't leave a partly written file
+    fn save(&self, all: &BTreeMap<String, Tenant>) -> Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(all)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Tenant {name} still has repositories, delete them first")]
+pub struct TenantInUse {
+    pub name: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Member, Tenant, Tenants};
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn tenant(name: &str) -> Tenant {
+        Tenant {
+            name: name.to_string(),
+            members: vec![Member {
+                user: "alice".to_string(),
+                permission: "push".to_string(),
+            }],
+            quota: Some("10G:100".to_string()),
+            retention: vec![format!("{}/*=keep-last:5", name)],
+        }
+    }
+
+    #[test]
+    fn validates_tenants() {
+        assert!(tenant("team-a").validate().is_ok());
+        assert!(tenant("team/a").validate().is_err());
+        assert!(tenant("Team").validate().is_err());
+        assert!(tenant("").validate().is_err());
+
+        let mut t = tenant("team-a");
+        t.members[0].permission = "admin".to_string();
+        assert!(t.validate().is_err());
+
+        let mut t = tenant("team-a");
+        t.quota = Some("lots".to_string());
+        assert!(t.validate().is_err());
+
+        // Can't reach into other namespaces
+        let mut t = tenant("team-a");
+        t.retention = vec!["*=untagged-older-than:1d".to_string()];
+        assert!(t.validate().is_err());
+        t.retention = vec!["team-ab/*=keep-last:1".to_string()];
+        assert!(t.validate().is_err());
+    }
+
+    #[test]
+    fn stores_tenants() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        let tenants = Tenants::load(dir.path()).unwrap();
+        tenants.put(tenant("team-b")).unwrap();
+        tenants.put(tenant("team-a")).unwrap();
+        assert!(tenants.put(tenant("Bad")).is_err());
+
+        let loaded = Tenants::load(dir.path()).unwrap();
+        let names: Vec<String> = loaded.list().into_iter().map(|t| t.name).collect();
+        assert_eq!(names, vec!["team-a", "team-b"]);
+        assert_eq!(loaded.quotas()[0].name, "team-a");
+        assert_eq!(loaded.quotas()[0].max_images, Some(100));
+        assert_eq!(loaded.retention_rules().len(), 2);
+        assert!(loaded.can_see("alice", "team-a/app"));
+        assert!(!loaded.can_see("bob", "team-a/app"));
+        assert!(!loaded.can_see("bob", "team-a"));
+        assert!(loaded.can_see("bob", "team-ab/app"));
+
+        // Not while it has repositories
+        fs::create_dir_all(manifests.join("team-a/app")).unwrap();
+        fs::write(manifests.join("team-a/app/latest"), "sha256:abc\n").unwrap();
+        assert!(loaded.remove(&manifests, "team-a").is_err());
+        fs::remove_file(manifests.join("team-a/app/latest")).unwrap();
+        assert!(loaded.remove(&manifests, "team-a").unwrap());
+        assert!(!loaded.remove(&manifests, "team-a").unwrap());
+        assert_eq!(Tenants::load(dir.path()).unwrap().list().len(), 1);
+    }
+}
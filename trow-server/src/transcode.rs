@@ -0,0 +1,800 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::{anyhow, Result};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::digest::sha256_tag_digest;
+use crate::jobs::JobHandle;
+use crate::maintenance::blob_path;
+
+/*
+ * Layers recompressed ahead of time, so clients can pull whichever compression suits them.
+ *
+ * Blob reads are counted, and the "transcode" job makes a zstd rendition of each gzip layer read
+ * at least min_pulls times since the backend started, and a gzip rendition of each zstd layer.
+ * The uncompressed tar is the same either way, so the image config and its diff IDs still hold.
+ * Renditions are stored in the transcoded dir, named by their own digest, outside the blobs dir so
+ * garbage collection leaves them alone. Renditions of layers that have since been deleted are
+ * removed when the job next runs.
+ *
+ * With on_demand, a layer is also transcoded as soon as a client asks for it in the compression it
+ * doesn't have, in the background so the request isn't held up. The client gets the manifest as
+ * it is until the rendition is ready, and every client after gets the rendition.
+ *
+ * The compression of each layer is recorded when a manifest using it is pushed, from the layer
+ * itself rather than the media type, as some clients push zstd layers labelled as gzip.
+ *
+ * A client preferring a compression is given a copy of the manifest with the layers that have
+ * renditions swapped for them, and the media types of the other layers corrected to match what
+ * they hold. Docker manifests become OCI manifests, as Docker has no media type
+ * for zstd layers, and index entries are rewritten the same way. The copy has its own digest, so
+ * it's stored too, with the digest of the original, so it can be pulled by digest for as long as
+ * the original exists.
+ */
+
+static INDEX_FILE: &str = "renditions.json";
+static MANIFESTS_DIR: &str = "manifests";
+
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+const OCI_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const OCI_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+const DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const DOCKER_CONFIG: &str = "application/vnd.docker.container.image.v1+json";
+const DOCKER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+const DOCKER_FOREIGN_GZIP: &str = "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
+const OCI_FOREIGN_GZIP: &str = "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip";
+
+// Quicker than the default, as the point is to save time, while still smaller than gzip
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn of_layer(media_type: &str) -> Option<Compression> {
+        match media_type {
+            OCI_GZIP | DOCKER_GZIP => Some(Compression::Gzip),
+            OCI_ZSTD => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// From the magic number at the start of the blob, None if it's neither
+    pub(crate) fn sniff(path: &Path) -> Result<Option<Compression>> {
+        let mut magic = [0; 4];
+        let mut file = File::open(path)?;
+        if file.read(&mut magic)? < magic.len() {
+            return Ok(None);
+        }
+        Ok(match magic {
+            [0x1f, 0x8b, _, _] => Some(Compression::Gzip),
+            [0x28, 0xb5, 0x2f, 0xfd] => Some(Compression::Zstd),
+            _ => None,
+        })
+    }
+
+    fn media_type(self) -> &'static str {
+        match self {
+            Compression::Gzip => OCI_GZIP,
+            Compression::Zstd => OCI_ZSTD,
+        }
+    }
+
+    fn other(self) -> Compression {
+        match self {
+            Compression::Gzip => Compression::Zstd,
+            Compression::Zstd => Compression::Gzip,
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(anyhow!("Unknown layer compression {}", s)),
+        }
+    }
+}
+
+/// The OCI equivalent of a media type, None if a Docker type has no equivalent
+pub(crate) fn oci_media_type(media_type: &str) -> Option<&str> {
+    match media_type {
+        DOCKER_MANIFEST => Some(OCI_MANIFEST),
+        DOCKER_CONFIG => Some(OCI_CONFIG),
+        DOCKER_GZIP => Some(OCI_GZIP),
+        DOCKER_FOREIGN_GZIP => Some(OCI_FOREIGN_GZIP),
+        t if t.starts_with("application/vnd.docker.") => None,
+        t => Some(t),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Rendition {
+    pub digest: String,
+    pub size: u64,
+    pub compression: Compression,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    // The rendition of each layer, by the digest of the original
+    layers: HashMap<String, Rendition>,
+    // The digest of the original of each rewritten manifest, by the rewritten one's digest
+    manifests: HashMap<String, String>,
+    // The compression of each layer pushed, by its digest
+    #[serde(default)]
+    compressions: HashMap<String, Compression>,
+}
+
+// A manifest rewritten to use renditions
+pub struct Variant {
+    pub digest: String,
+    pub content_type: String,
+    pub path: PathBuf,
+}
+
+#[derive(Clone)]
+pub struct Transcoder {
+    dir: PathBuf,
+    blobs_path: PathBuf,
+    min_pulls: u64,
+    on_demand: bool,
+    pulls: Arc<Mutex<HashMap<String, u64>>>,
+    // Layers being transcoded on demand
+    pending: Arc<Mutex<HashSet<String>>>,
+    index: Arc<RwLock<Index>>,
+}
+
+impl Transcoder {
+    pub fn new(
+        dir: &Path,
+        blobs_path: &Path,
+        min_pulls: u64,
+        on_demand: bool,
+    ) -> Result<Transcoder> {
+        fs::create_dir_all(dir.join(MANIFESTS_DIR))?;
+        let index = match fs::read(dir.join(INDEX_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Index::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Transcoder {
+            dir: dir.to_path_buf(),
+            blobs_path: blobs_path.to_path_buf(),
+            min_pulls,
+            on_demand,
+            pulls: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(Mutex::new(HashSet::new())),
+            index: Arc::new(RwLock::new(index)),
+        })
+    }
+
+    pub fn record_pull(&self, digest: &str) {
+        *self
+            .pulls
+            .lock()
+            .unwrap()
+            .entry(digest.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Where the rendition with this digest is stored, if there is one
+    pub fn layer_path(&self, digest: &str) -> Option<PathBuf> {
+        let index = self.index.read().unwrap();
+        index.layers.values().find(|r| r.digest == digest)?;
+        blob_path(&self.dir, digest)
+    }
+
+    /// Where the rewritten manifest with this digest is stored, with the original's digest
+    pub fn manifest_path(&self, digest: &str) -> Option<(PathBuf, String)> {
+        let original = self.index.read().unwrap().manifests.get(digest)?.clone();
+        let (_, hex) = digest.split_once(':')?;
+        Some((self.dir.join(MANIFESTS_DIR).join(hex), original))
+    }
+
+    /*
+     * Records the compression of the layers of a pushed image manifest that aren't known yet.
+     * Layers that are neither gzip nor zstd, and those not in the blobs dir, are skipped.
+     */
+    pub fn record_layers(&self, manifest: &Value) -> Result<()> {
+        let digests: Vec<&str> = manifest
+            .get("layers")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|l| l.get("digest")?.as_str())
+            .collect();
+        let mut found = vec![];
+        {
+            let index = self.index.read().unwrap();
+            for digest in digests {
+                if index.compressions.contains_key(digest) {
+                    continue;
+                }
+                let path = match blob_path(&self.blobs_path, digest) {
+                    Some(p) if p.exists() => p,
+                    _ => continue,
+                };
+                if let Some(c) = Compression::sniff(&path)? {
+                    found.push((digest.to_string(), c));
+                }
+            }
+        }
+        if found.is_empty() {
+            return Ok(());
+        }
+        self.index.write().unwrap().compressions.extend(found);
+        self.save()
+    }
+
+    /*
+     * Transcodes the layer in the background, unless it already is being. The rendition is picked
+     * up by the next request for the manifest.
+     */
+    fn transcode_later(&self, digest: &str, from: Compression) {
+        if !self.pending.lock().unwrap().insert(digest.to_string()) {
+            return;
+        }
+        let transcoder = self.clone();
+        let digest = digest.to_string();
+        tokio::task::spawn_blocking(move || {
+            let res = blob_path(&transcoder.blobs_path, &digest)
+                .ok_or_else(|| anyhow!("Invalid digest {}", digest))
+                .and_then(|path| transcode(&path, from, &transcoder.dir));
+            match res {
+                Ok(rendition) => {
+                    debug!("Transcoded {} to {} on demand", digest, rendition.digest);
+                    transcoder
+                        .index
+                        .write()
+                        .unwrap()
+                        .layers
+                        .insert(digest.clone(), rendition);
+                    if let Err(e) = transcoder.save() {
+                        warn!("Failed to save transcoded layers: {:?}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to transcode layer {}: {:?}", digest, e),
+            }
+            transcoder.pending.lock().unwrap().remove(&digest);
+        });
+    }
+
+    fn save(&self) -> Result<()> {
+        let bytes = serde_json::to_vec(&*self.index.read().unwrap())?;
+        let tmp_path = self.dir.join(Uuid::new_v4().to_string());
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, self.dir.join(INDEX_FILE))?;
+        Ok(())
+    }
+
+    /*
+     * Points the layers of an image manifest at their renditions in the preferred compression.
+     * None if no layer has one, or it's a Docker manifest with something OCI has no media type
+     * for.
+     */
+    fn rewrite_manifest(&self, bytes: &[u8], prefer: Compression) -> Option<Vec<u8>> {
+        let mut manifest: Value = serde_json::from_slice(bytes).ok()?;
+        let index = self.index.read().unwrap();
+        let mut changed = false;
+        for layer in manifest.get_mut("layers")?.as_array_mut()? {
+            let media_type = layer.get("mediaType")?.as_str()?.to_string();
+            let digest = layer
+                .get("digest")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            let labelled = Compression::of_layer(&media_type);
+            // What the layer holds, if that's been recorded
+            let compression = index.compressions.get(&digest).copied().or(labelled);
+            let rendition = match compression {
+                Some(c) if c != prefer => {
+                    let rendition = index.layers.get(&digest);
+                    if rendition.is_none() && self.on_demand {
+                        self.transcode_later(&digest, c);
+                    }
+                    rendition
+                }
+                _ => None,
+            };
+            let layer = layer.as_object_mut()?;
+            match rendition {
+                Some(r) => {
+                    layer.insert("mediaType".to_string(), r.compression.media_type().into());
+                    layer.insert("digest".to_string(), r.digest.clone().into());
+                    layer.insert("size".to_string(), r.size.into());
+                    // e.g. the table of contents of a seekable layer, which only fits the original
+                    layer.remove("annotations");
+                    changed = true;
+                }
+                None => match compression {
+                    Some(c) if labelled.map_or(false, |l| l != c) => {
+                        layer.insert("mediaType".to_string(), c.media_type().into());
+                        changed = true;
+                    }
+                    _ => {
+                        let media_type = oci_media_type(&media_type)?.to_string();
+                        layer.insert("mediaType".to_string(), media_type.into());
+                    }
+                },
+            }
+        }
+        if !changed {
+            return None;
+        }
+        let config = manifest.get_mut("config")?.as_object_mut()?;
+        let media_type = oci_media_type(config.get("mediaType")?.as_str()?)?.to_string();
+        config.insert("mediaType".to_string(), media_type.into());
+        let fields = manifest.as_object_mut()?;
+        fields.insert("mediaType".to_string(), OCI_MANIFEST.into());
+        serde_json::to_vec_pretty(&manifest).ok()
+    }
+
+    /*
+     * Points the entries of an index at rewritten copies of their manifests. None if none of them
+     * could be rewritten.
+     */
+    fn rewrite_index(&self, bytes: &[u8], prefer: Compression) -> Result<Option<Vec<u8>>> {
+        let mut index: Value = serde_json::from_slice(bytes)?;
+        let entries = match index.get_mut("manifests").and_then(Value::as_array_mut) {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let mut changed = false;
+        for entry in entries {
+            let media_type = entry.get("mediaType").and_then(Value::as_str);
+            let digest = entry.get("digest").and_then(Value::as_str);
+            let (media_type, variant) = match (media_type, digest) {
+                (Some(t @ (OCI_MANIFEST | DOCKER_MANIFEST)), Some(d)) => {
+                    (t.to_string(), self.variant(d, t, prefer)?)
+                }
+                (Some(t), _) => (t.to_string(), None),
+                _ => return Ok(None),
+            };
+            let entry = match entry.as_object_mut() {
+                Some(e) => e,
+                None => return Ok(None),
+            };
+            match variant {
+                Some(v) => {
+                    entry.insert("mediaType".to_string(), OCI_MANIFEST.into());
+                    entry.insert("digest".to_string(), v.digest.into());
+                    entry.insert("size".to_string(), fs::metadata(&v.path)?.len().into());
+                    changed = true;
+                }
+                None => match oci_media_type(&media_type) {
+                    Some(t) => {
+                        let t = t.to_string();
+                        entry.insert("mediaType".to_string(), t.into());
+                    }
+                    None => return Ok(None),
+                },
+            }
+        }
+        if !changed {
+            return Ok(None);
+        }
+        if let Some(fields) = index.as_object_mut() {
+            fields.insert("mediaType".to_string(), OCI_INDEX.into());
+        }
+        Ok(Some(serde_json::to_vec_pretty(&index)?))
+    }
+
+    /*
+     * A copy of the stored manifest using renditions of its layers in the preferred compression,
+     * or None if there aren't any. The copy is stored so it can be pulled by digest.
+     */
+    pub fn variant(
+        &self,
+        digest: &str,
+        content_type: &str,
+        prefer: Compression,
+    ) -> Result<Option<Variant>> {
+        let path = blob_path(&self.blobs_path, digest)
+            .ok_or_else(|| anyhow!("Invalid digest {}", digest))?;
+        let bytes = fs::read(&path)?;
+        let rewritten = match content_type {
+            OCI_MANIFEST | DOCKER_MANIFEST => self.rewrite_manifest(&bytes, prefer),
+            OCI_INDEX | DOCKER_LIST => self.rewrite_index(&bytes, prefer)?,
+            _ => None,
+        };
+        let rewritten = match rewritten {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let content_type = match content_type {
+            OCI_INDEX | DOCKER_LIST => OCI_INDEX,
+            _ => OCI_MANIFEST,
+        };
+
+        let variant_digest = sha256_tag_digest(rewritten.as_slice())?;
+        let (_, hex) = variant_digest
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid digest {}", variant_digest))?;
+        let variant_path = self.dir.join(MANIFESTS_DIR).join(hex);
+        if !variant_path.exists() {
+            let tmp_path = self.dir.join(Uuid::new_v4().to_string());
+            fs::write(&tmp_path, &rewritten)?;
+            fs::rename(&tmp_path, &variant_path)?;
+        }
+        let added = self
+            .index
+            .write()
+            .unwrap()
+            .manifests
+            .insert(variant_digest.clone(), digest.to_string())
+            .is_none();
+        if added {
+            debug!(
+                "Rewrote {} as {} for {} layers",
+                digest, variant_digest, prefer
+            );
+            self.save()?;
+        }
+        Ok(Some(Variant {
+            digest: variant_digest,
+            content_type: content_type.to_string(),
+            path: variant_path,
+        }))
+    }
+
+    /*
+     * Makes renditions of the layers read at least min_pulls times that don't have one yet, after
+     * removing those of layers that have been deleted.
+     */
+    pub fn run_job(&self, handle: &JobHandle) -> Result<String> {
+        let removed = self.remove_deleted()?;
+
+        let candidates: Vec<String> = {
+            let pulls = self.pulls.lock().unwrap();
+            let index = self.index.read().unwrap();
+            pulls
+                .iter()
+                .filter(|(d, n)| **n >= self.min_pulls && !index.layers.contains_key(*d))
+                .map(|(d, _)| d.clone())
+                .collect()
+        };
+        let (mut transcoded, mut bytes, mut failed) = (0, 0, 0);
+        for (i, digest) in candidates.iter().enumerate() {
+            if handle.is_cancelled() {
+                break;
+            }
+            handle.set_progress(i, candidates.len());
+            let path = match blob_path(&self.blobs_path, digest) {
+                Some(p) => p,
+                None => continue,
+            };
+            // Configs and other blobs that aren't compressed layers
+            let compression = match Compression::sniff(&path) {
+                Ok(Some(c)) => c,
+                _ => continue,
+            };
+            match transcode(&path, compression, &self.dir) {
+                Ok(rendition) => {
+                    debug!("Transcoded {} to {}", digest, rendition.digest);
+                    bytes += rendition.size;
+                    transcoded += 1;
+                    self.index
+                        .write()
+                        .unwrap()
+                        .layers
+                        .insert(digest.clone(), rendition);
+                }
+                Err(e) => {
+                    warn!("Failed to transcode layer {}: {:?}", digest, e);
+                    failed += 1;
+                }
+            }
+        }
+        self.save()?;
+
+        let summary = format!(
+            "Transcoded {} layers ({} bytes), removed {} renditions of deleted layers",
+            transcoded, bytes, removed
+        );
+        info!("{}", summary);
+        if failed > 0 {
+            return Err(anyhow!(
+                "Failed to transcode {} layers, see the logs. {}",
+                failed,
+                summary
+            ));
+        }
+        Ok(summary)
+    }
+
+    /// Removes renditions and rewritten manifests whose originals no longer exist
+    fn remove_deleted(&self) -> Result<usize> {
+        let exists =
+            |digest: &str| blob_path(&self.blobs_path, digest).map_or(false, |p| p.exists());
+        let mut index = self.index.write().unwrap();
+
+        let deleted: Vec<String> = index
+            .layers
+            .keys()
+            .filter(|d| !exists(d))
+            .cloned()
+            .collect();
+        let mut removed = vec![];
+        for digest in &deleted {
+            if let Some(r) = index.layers.remove(digest) {
+                if let Some(path) = blob_path(&self.dir, &r.digest) {
+                    let _ = fs::remove_file(path);
+                }
+                removed.push(r.digest);
+            }
+        }
+
+        let manifests_path = self.dir.join(MANIFESTS_DIR);
+        let path_of = |digest: &str| Some(manifests_path.join(digest.split_once(':')?.1));
+        // Including those using a rendition that's just been removed
+        let gone: Vec<String> = index
+            .manifests
+            .iter()
+            .filter(|(variant, original)| {
+                let uses_removed = path_of(variant)
+                    .and_then(|p| fs::read_to_string(p).ok())
+                    .map_or(true, |m| removed.iter().any(|r| m.contains(r.as_str())));
+                !exists(original) || uses_removed
+            })
+            .map(|(variant, _)| variant.clone())
+            .collect();
+        for digest in gone {
+            index.manifests.remove(&digest);
+            if let Some(path) = path_of(&digest) {
+                let _ = fs::remove_file(path);
+            }
+        }
+        Ok(deleted.len())
+    }
+}
+
+/// Decompresses the blob and compresses it the other way, storing it by its new digest
+fn transcode(src: &Path, from: Compression, dir: &Path) -> Result<Rendition> {
+    let tmp_path = dir.join(Uuid::new_v4().to_string());
+    let res = (|| {
+        let input = BufReader::new(File::open(src)?);
+        let output = File::create(&tmp_path)?;
+        match from {
+            Compression::Gzip => {
+                let mut decoder = MultiGzDecoder::new(input);
+                let mut encoder = zstd::stream::write::Encoder::new(output, ZSTD_LEVEL)?;
+                io::copy(&mut decoder, &mut encoder)?;
+                encoder.finish()?.sync_all()?;
+            }
+            Compression::Zstd => {
+                let mut decoder = zstd::stream::read::Decoder::new(input)?;
+                let mut encoder = GzEncoder::new(output, flate2::Compression::default());
+                io::copy(&mut decoder, &mut encoder)?;
+                encoder.finish()?.sync_all()?;
+            }
+        }
+        let digest = sha256_tag_digest(BufReader::new(File::open(&tmp_path)?))?;
+        let size = fs::metadata(&tmp_path)?.len();
+        let path = blob_path(dir, &digest).ok_or_else(|| anyhow!("Invalid digest {}", digest))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(Rendition {
+            digest,
+            size,
+            compression: from.other(),
+        })
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Compression, Transcoder};
+    use crate::digest::sha256_tag_digest;
+    use crate::jobs::{Job, JobKind, Jobs};
+    use crate::maintenance::blob_path;
+    use flate2::write::GzEncoder;
+    use serde_json::Value;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn store(blobs: &Path, bytes: &[u8]) -> String {
+        let digest = sha256_tag_digest(bytes).unwrap();
+        let path = blob_path(blobs, &digest).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, bytes).unwrap();
+        digest
+    }
+
+    async fn run(jobs: &Jobs, transcoder: &Transcoder) -> Job {
+        let t = transcoder.clone();
+        let job = jobs.start(JobKind::Transcode, move |h| t.run_job(h));
+        loop {
+            let job = jobs.get(&job.id).unwrap();
+            if job.finished.is_some() {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_zstd_renditions() {
+        let dir = tempdir().unwrap();
+        let blobs = dir.path().join("blobs");
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(b"layer contents").unwrap();
+        let layer = store(&blobs, &encoder.finish().unwrap());
+        let config = store(&blobs, b"{}");
+        let manifest = format!(
+            r#"{{"schemaVersion": 2, "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": {{"mediaType": "application/vnd.docker.container.image.v1+json", "size": 2, "digest": "{}"}},
+            "layers": [{{"mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "size": 1, "digest": "{}"}}]}}"#,
+            config, layer
+        );
+        let manifest = store(&blobs, manifest.as_bytes());
+        let docker_manifest = "application/vnd.docker.distribution.manifest.v2+json";
+
+        let transcoder = Transcoder::new(&dir.path().join("transcoded"), &blobs, 2, false).unwrap();
+        transcoder.record_pull(&layer);
+        transcoder.record_pull(&config);
+        transcoder.record_pull(&config);
+        let jobs = Jobs::new();
+        run(&jobs, &transcoder).await;
+        // Not pulled enough
+        assert!(transcoder
+            .variant(&manifest, docker_manifest, Compression::Zstd)
+            .unwrap()
+            .is_none());
+
+        transcoder.record_pull(&layer);
+        let job = run(&jobs, &transcoder).await;
+        assert!(
+            job.message.starts_with("Transcoded 1 layers"),
+            "{}",
+            job.message
+        );
+
+        let variant = transcoder
+            .variant(&manifest, docker_manifest, Compression::Zstd)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            variant.content_type,
+            "application/vnd.oci.image.manifest.v1+json"
+        );
+        let rewritten: Value = serde_json::from_slice(&fs::read(&variant.path).unwrap()).unwrap();
+        assert_eq!(
+            rewritten["config"]["mediaType"],
+            "application/vnd.oci.image.config.v1+json"
+        );
+        let rendition = rewritten["layers"][0]["digest"].as_str().unwrap();
+        assert_eq!(
+            rewritten["layers"][0]["mediaType"],
+            "application/vnd.oci.image.layer.v1.tar+zstd"
+        );
+        let mut contents = String::new();
+        let rendition_path = transcoder.layer_path(rendition).unwrap();
+        zstd::stream::read::Decoder::new(File::open(rendition_path).unwrap())
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "layer contents");
+        assert_eq!(
+            transcoder.manifest_path(&variant.digest).unwrap().1,
+            manifest
+        );
+        // Already gzip
+        assert!(transcoder
+            .variant(&manifest, docker_manifest, Compression::Gzip)
+            .unwrap()
+            .is_none());
+
+        // Garbage collected
+        fs::remove_file(blob_path(&blobs, &layer).unwrap()).unwrap();
+        assert_eq!(transcoder.remove_deleted().unwrap(), 1);
+        assert!(transcoder.layer_path(rendition).is_none());
+        assert!(transcoder.manifest_path(&variant.digest).is_none());
+    }
+
+    #[tokio::test]
+    async fn transcodes_mislabelled_layers_on_demand() {
+        let dir = tempdir().unwrap();
+        let blobs = dir.path().join("blobs");
+        let layer = store(
+            &blobs,
+            &zstd::stream::encode_all(&b"layer contents"[..], 3).unwrap(),
+        );
+        let config = store(&blobs, b"{}");
+        // zstd, but labelled as gzip
+        let manifest: Value = serde_json::from_str(&format!(
+            r#"{{"schemaVersion": 2, "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {{"mediaType": "application/vnd.oci.image.config.v1+json", "size": 2, "digest": "{}"}},
+            "layers": [{{"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "size": 1, "digest": "{}"}}]}}"#,
+            config, layer
+        ))
+        .unwrap();
+        let manifest_digest = store(&blobs, manifest.to_string().as_bytes());
+        let oci_manifest = "application/vnd.oci.image.manifest.v1+json";
+
+        let transcoder =
+            Transcoder::new(&dir.path().join("transcoded"), &blobs, 100, true).unwrap();
+        transcoder.record_layers(&manifest).unwrap();
+
+        // Already zstd, so only the media type changes
+        let variant = transcoder
+            .variant(&manifest_digest, oci_manifest, Compression::Zstd)
+            .unwrap()
+            .unwrap();
+        let rewritten: Value = serde_json::from_slice(&fs::read(&variant.path).unwrap()).unwrap();
+        assert_eq!(rewritten["layers"][0]["digest"], layer.as_str());
+        assert_eq!(
+            rewritten["layers"][0]["mediaType"],
+            "application/vnd.oci.image.layer.v1.tar+zstd"
+        );
+
+        // Transcoded on the first request, without waiting for pulls or the job
+        let rendition = loop {
+            let variant = transcoder
+                .variant(&manifest_digest, oci_manifest, Compression::Gzip)
+                .unwrap()
+                .unwrap();
+            let rewritten: Value =
+                serde_json::from_slice(&fs::read(&variant.path).unwrap()).unwrap();
+            let digest = rewritten["layers"][0]["digest"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            if digest != layer {
+                assert_eq!(
+                    rewritten["layers"][0]["mediaType"],
+                    "application/vnd.oci.image.layer.v1.tar+gzip"
+                );
+                break digest;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let mut contents = String::new();
+        flate2::read::GzDecoder::new(
+            File::open(transcoder.layer_path(&rendition).unwrap()).unwrap(),
+        )
+        .read_to_string(&mut contents)
+        .unwrap();
+        assert_eq!(contents, "layer contents");
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::maintenance::walk_files;
+
+/*
+ * Deleted manifests, kept for the restore window so a mistaken delete can be undone.
+ *
+ * With --trash-retention, deleting a manifest or repository moves its tag files from the manifests
+ * dir to trash/<id>/tags/ in the data dir, along with a record of what was deleted and when. The
+ * tags are gone from the registry, but garbage collection keeps everything they refer to until
+ * the window has passed, then removes the trash and collects the blobs as usual.
+ *
+ * Restoring moves the tag files back, history and all. It fails if any of the tags has been
+ * pushed again since, rather than losing the newer push.
+ */
+
+static ENTRY_FILE: &str = "entry.json";
+static TAGS_DIR: &str = "tags";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub id: String,
+    pub repo_name: String,
+    // The manifest deleted, empty if the whole repository was
+    pub digest: String,
+    // Tags and digests the manifest was stored under, relative to the repository
+    pub tags: Vec<String>,
+    pub deleted: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct Trash {
+    path: PathBuf,
+    window: Duration,
+}
+
+impl Trash {
+    pub fn new(data_path: &Path, window: Duration) -> Result<Trash> {
+        let path = data_path.join("trash");
+        fs::create_dir_all(&path)?;
+        Ok(Trash { path, window })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // When the entry is removed for good
+    pub fn expires(&self, entry: &TrashEntry) -> DateTime<Utc> {
+        chrono::Duration::from_std(self.window)
+            .ok()
+            .and_then(|w| entry.deleted.checked_add_signed(w))
+            .unwrap_or(chrono::MAX_DATETIME)
+    }
+
+    /*
+     * Moves the repository's tag files to the trash. The record is written first, so a crash
+     * part way leaves an entry that can be restored rather than tags that are lost.
+     */
+    pub fn put(
+        &self,
+        manifests_path: &Path,
+        repo_name: &str,
+        digest: &str,
+        files: &[PathBuf],
+    ) -> Result<TrashEntry> {
+        let repo_path = manifests_path.join(repo_name);
+        let tags = files
+            .iter()
+            .map(|f| Ok(f.strip_prefix(&repo_path)?.to_string_lossy().to_string()))
+            .collect::<Result<Vec<String>>>()?;
+        let entry = TrashEntry {
+            id: Uuid::new_v4().to_string(),
+            repo_name: repo_name.to_string(),
+            digest: digest.to_string(),
+            tags,
+            deleted: Utc::now(),
+        };
+
+        let entry_path = self.path.join(&entry.id);
+        fs::create_dir_all(entry_path.join(TAGS_DIR))?;
+        fs::write(entry_path.join(ENTRY_FILE), serde_json::to_vec(&entry)?)?;
+        for (file, tag) in files.iter().zip(&entry.tags) {
+            let trashed = entry_path.join(TAGS_DIR).join(tag);
+            if let Some(dir) = trashed.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::rename(file, trashed)?;
+        }
+        Ok(entry)
+    }
+
+    // Oldest first
+    pub fn list(&self) -> Result<Vec<TrashEntry>> {
+        let mut entries = vec![];
+        for dir in fs::read_dir(&self.path)? {
+            let dir = dir?;
+            match fs::read(dir.path().join(ENTRY_FILE)) {
+                Ok(bytes) => entries.push(serde_json::from_slice::<TrashEntry>(&bytes)?),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        entries.sort_by(|a, b| (a.deleted, &a.id).cmp(&(b.deleted, &b.id)));
+        Ok(entries)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<TrashEntry>> {
+        // Ids are always UUIDs, so can't reach outside the trash
+        if Uuid::parse_str(id).is_err() {
+            return Ok(None);
+        }
+        match fs::read(self.path.join(id).join(ENTRY_FILE)) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /*
+     * Moves the entry's tag files back to the manifests dir, returning the entry, or None if
+     * there's no such entry. Fails without restoring anything if a tag has been pushed since.
+     */
+    pub fn restore(&self, manifests_path: &Path, id: &str) -> Result<Option<TrashEntry>> {
+        let entry = match self.get(id)? {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let repo_path = manifests_path.join(&entry.repo_name);
+        if let Some(tag) = entry.tags.iter().find(|t| repo_path.join(t).exists()) {
+            return Err(TagPushedSince {
+                repo_name: entry.repo_name.clone(),
+                tag: tag.clone(),
+            }
+            .into());
+        }
+
+        let entry_path = self.path.join(&entry.id);
+        for tag in &entry.tags {
+            let restored = repo_path.join(tag);
+            if let Some(dir) = restored.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::rename(entry_path.join(TAGS_DIR).join(tag), restored)?;
+        }
+        fs::remove_dir_all(&entry_path)?;
+        Ok(Some(entry))
+    }
+
+    // Removes entries older than the window for good, returning how many there were
+    pub fn purge_expired(&self, now: SystemTime) -> Result<usize> {
+        let now = DateTime::<Utc>::from(now);
+        let mut purged = 0;
+        for entry in self.list()? {
+            if self.expires(&entry) > now {
+                continue;
+            }
+            match fs::remove_dir_all(self.path.join(&entry.id)) {
+                Ok(_) => {
+                    info!(
+                        "Emptied {}@{} from the trash",
+                        entry.repo_name, entry.digest
+                    );
+                    purged += 1;
+                }
+                Err(e) => warn!("Failed to remove {} from the trash: {:?}", entry.id, e),
+            }
+        }
+        Ok(purged)
+    }
+
+    // The trashed tag files of each repository, for garbage collection to keep what they refer to
+    pub fn tag_files(&self) -> Result<HashMap<String, Vec<PathBuf>>> {
+        let mut files: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for entry in self.list()? {
+            let tags_path = self.path.join(&entry.id).join(TAGS_DIR);
+            files
+                .entry(entry.repo_name)
+                .or_default()
+                .extend(walk_files(&tags_path)?);
+        }
+        Ok(files)
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("{repo_name}:{tag} has been pushed since it was deleted")]
+pub struct TagPushedSince {
+    pub repo_name: String,
+    pub tag: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::Trash;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+    use tempfile::tempdir;
+
+    #[test]
+    fn restores_deleted_tags() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        fs::create_dir_all(manifests.join("org/app")).unwrap();
+        let latest = manifests.join("org/app/latest");
+        fs::write(
+            &latest,
+            "sha256:new 2022-03-02T00:00:00Z\nsha256:old 2022-03-01T00:00:00Z\n",
+        )
+        .unwrap();
+        fs::write(
+            manifests.join("org/app/v1"),
+            "sha256:old 2022-03-01T00:00:00Z\n",
+        )
+        .unwrap();
+
+        let trash = Trash::new(dir.path(), Duration::from_secs(3600)).unwrap();
+        let entry = trash
+            .put(&manifests, "org/app", "sha256:new", &[latest.clone()])
+            .unwrap();
+        assert_eq!(entry.tags, vec!["latest"]);
+        assert!(!latest.exists());
+        assert_eq!(trash.list().unwrap(), vec![entry.clone()]);
+        assert_eq!(trash.tag_files().unwrap()["org/app"].len(), 1);
+
+        // Not while the tag is in use again
+        fs::write(&latest, "sha256:other 2022-03-03T00:00:00Z\n").unwrap();
+        assert!(trash.restore(&manifests, &entry.id).is_err());
+        fs::remove_file(&latest).unwrap();
+
+        let restored = trash.restore(&manifests, &entry.id).unwrap().unwrap();
+        assert_eq!(restored.id, entry.id);
+        assert!(fs::read_to_string(&latest).unwrap().contains("sha256:old"));
+        assert!(trash.list().unwrap().is_empty());
+        assert!(trash.restore(&manifests, &entry.id).unwrap().is_none());
+        assert!(trash.restore(&manifests, "../manifests").unwrap().is_none());
+    }
+
+    #[test]
+    fn purges_after_window() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        fs::create_dir_all(manifests.join("app")).unwrap();
+        fs::write(manifests.join("app/latest"), "sha256:abc\n").unwrap();
+
+        let trash = Trash::new(dir.path(), Duration::from_secs(3600)).unwrap();
+        trash
+            .put(
+                &manifests,
+                "app",
+                "sha256:abc",
+                &[manifests.join("app/latest")],
+            )
+            .unwrap();
+        assert_eq!(trash.purge_expired(SystemTime::now()).unwrap(), 0);
+        let later = SystemTime::now() + Duration::from_secs(3601);
+        assert_eq!(trash.purge_expired(later).unwrap(), 1);
+        assert!(trash.list().unwrap().is_empty());
+    }
+}
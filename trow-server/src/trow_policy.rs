@@ -0,0 +1,371 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::policy::SharedPolicy;
+use crate::selector::glob_match;
+
+/*
+ * Admission rules from TrowPolicy resources, so cluster operators can manage them with kubectl.
+ *
+ * TrowPolicy is a cluster scoped custom resource (trow.io/v1alpha1, see
+ * install/base/trowpolicy-crd.yaml):
+ *
+ *   spec:
+ *     allowedRegistries: [quay.io/myorg/]
+ *     allowedImages: [docker.io/nginx:1.21]
+ *     requireSignatures: [myorg/app]
+ *     namespaces:
+ *       - names: [prod, prod-*]
+ *         allowedRegistries: [gcr.io/distroless/]
+ *         requireSignatures: ["*"]
+ *
+ * allowedRegistries and allowedImages add to the allow lists given by flags. A namespace rule
+ * replaces all the allow lists for pods in the namespaces it names, so e.g. production can be
+ * kept to fewer registries than the rest of the cluster. requireSignatures are patterns of
+ * repositories in this registry whose images need a cosign signature stored alongside them.
+ *
+ * Several TrowPolicy resources are combined: every cluster wide list applies, and for namespace
+ * rules the first match, in order of resource name, wins.
+ *
+ * The backend lists the resources when it starts, then watches the Kubernetes API for changes,
+ * which apply to the next admission request. A resource with an invalid spec is logged and its
+ * previous version, if any, kept.
+ */
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+const API_PATH: &str = "/apis/trow.io/v1alpha1/trowpolicies";
+// Watches are restarted from a fresh list after this long
+const WATCH_TIMEOUT_SECS: u64 = 300;
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NamespaceRule {
+    // Namespaces the rule is for, where * matches any run of characters
+    pub names: Vec<String>,
+    pub allowed_registries: Vec<String>,
+    pub allowed_images: Vec<String>,
+    pub require_signatures: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TrowPolicySpec {
+    // Prefixes of images from other registries that are admitted
+    pub allowed_registries: Vec<String>,
+    pub allowed_images: Vec<String>,
+    pub require_signatures: Vec<String>,
+    pub namespaces: Vec<NamespaceRule>,
+}
+
+/*
+ * An allow list entry matching the image, full name with host, described with the policy it's
+ * from.
+ */
+fn allow_rule(
+    policy_name: &str,
+    registries: &[String],
+    images: &[String],
+    name: &str,
+) -> Option<String> {
+    if let Some(prefix) = registries.iter().find(|p| name.starts_with(p.as_str())) {
+        info!(
+            "Image {} allowed by registry {} in TrowPolicy {}",
+            name, prefix, policy_name
+        );
+        return Some(format!(
+            "TrowPolicy {} allows registry {}",
+            policy_name, prefix
+        ));
+    }
+    if let Some(image) = images.iter().find(|i| *i == name) {
+        info!("Image {} allowed by TrowPolicy {}", name, policy_name);
+        return Some(format!("TrowPolicy {} allows image {}", policy_name, image));
+    }
+    None
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ClusterPolicies {
+    // By resource name, so they're applied in name order
+    policies: BTreeMap<String, TrowPolicySpec>,
+}
+
+impl ClusterPolicies {
+    /// The first namespace rule naming the namespace, with the name of its TrowPolicy
+    fn namespace_rule(&self, namespace: &str) -> Option<(&str, &NamespaceRule)> {
+        self.policies.iter().find_map(|(name, spec)| {
+            spec.namespaces
+                .iter()
+                .find(|r| r.names.iter().any(|n| glob_match(n, namespace)))
+                .map(|r| (name.as_str(), r))
+        })
+    }
+
+    /// Whether a namespace rule replaces the other allow lists in the namespace
+    pub fn restricts(&self, namespace: &str) -> bool {
+        self.namespace_rule(namespace).is_some()
+    }
+
+    /*
+     * The entry admitting the image, full name with host, into the namespace. Only the namespace
+     * rule is checked if there's one for the namespace.
+     */
+    pub fn allow_rule(&self, name: &str, namespace: &str) -> Option<String> {
+        if let Some((policy_name, rule)) = self.namespace_rule(namespace) {
+            return allow_rule(
+                policy_name,
+                &rule.allowed_registries,
+                &rule.allowed_images,
+                name,
+            )
+            .map(|r| format!("{} in namespace {}", r, namespace));
+        }
+        self.policies.iter().find_map(|(policy_name, spec)| {
+            allow_rule(
+                policy_name,
+                &spec.allowed_registries,
+                &spec.allowed_images,
+                name,
+            )
+        })
+    }
+
+    /// The entry requiring images of the local repository to be signed in the namespace, if any
+    pub fn signature_rule(&self, repo_name: &str, namespace: &str) -> Option<String> {
+        let namespace_rule = self.namespace_rule(namespace);
+        let namespace_patterns = namespace_rule.iter().flat_map(|(policy_name, r)| {
+            r.require_signatures.iter().map(move |p| (*policy_name, p))
+        });
+        self.policies
+            .iter()
+            .flat_map(|(policy_name, spec)| {
+                spec.require_signatures
+                    .iter()
+                    .map(move |p| (policy_name.as_str(), p))
+            })
+            .chain(namespace_patterns)
+            .find(|(_, pattern)| glob_match(pattern, repo_name))
+            .map(|(policy_name, pattern)| {
+                format!(
+                    "TrowPolicy {} requires signatures for {}",
+                    policy_name, pattern
+                )
+            })
+    }
+
+    /*
+     * Applies a watch event for the resource, ADDED, MODIFIED or DELETED. Returns whether the
+     * policies changed.
+     */
+    fn apply_event(&mut self, kind: &str, object: &Value) -> bool {
+        let name = match object["metadata"]["name"].as_str() {
+            Some(n) => n.to_string(),
+            None => return false,
+        };
+        match kind {
+            "ADDED" | "MODIFIED" => {
+                let spec = object.get("spec").cloned().unwrap_or_else(|| json!({}));
+                match serde_json::from_value::<TrowPolicySpec>(spec) {
+                    Ok(spec) => self.policies.insert(name, spec.clone()) != Some(spec),
+                    Err(e) => {
+                        warn!("Ignoring invalid TrowPolicy {}: {}", name, e);
+                        false
+                    }
+                }
+            }
+            "DELETED" => self.policies.remove(&name).is_some(),
+            // e.g. BOOKMARK
+            _ => false,
+        }
+    }
+}
+
+fn token() -> Result<String> {
+    // Read on each use, as projected tokens are rotated by the kubelet
+    Ok(
+        fs::read_to_string(Path::new(SERVICE_ACCOUNT_DIR).join("token"))?
+            .trim()
+            .to_string(),
+    )
+}
+
+fn install(shared: &SharedPolicy, policies: &ClusterPolicies) {
+    shared.edit(|p| p.cluster = policies.clone());
+    info!("Using {} TrowPolicy resources", policies.policies.len());
+}
+
+/*
+ * Lists the policies and uses them, then applies changes until the watch ends.
+ *
+ * Returns Ok when the watch times out or falls too far behind, so it can be started again from a
+ * fresh list.
+ */
+fn list_and_watch(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    shared: &SharedPolicy,
+) -> Result<()> {
+    let list: Value = client
+        .get(url)
+        .bearer_auth(token()?)
+        .send()?
+        .error_for_status()?
+        .json()?;
+    let mut policies = ClusterPolicies::default();
+    for item in list["items"].as_array().into_iter().flatten() {
+        policies.apply_event("ADDED", item);
+    }
+    install(shared, &policies);
+
+    let version = list["metadata"]["resourceVersion"]
+        .as_str()
+        .unwrap_or_default();
+    let resp = client
+        .get(url)
+        .query(&[
+            ("watch", "1"),
+            ("resourceVersion", version),
+            ("timeoutSeconds", &WATCH_TIMEOUT_SECS.to_string()),
+        ])
+        .bearer_auth(token()?)
+        .send()?
+        .error_for_status()?;
+    for line in BufReader::new(resp).lines() {
+        let event: Value = serde_json::from_str(&line?)?;
+        let kind = event["type"].as_str().unwrap_or_default();
+        debug!("TrowPolicy watch event {}", kind);
+        if kind == "ERROR" {
+            // 410 Gone if the resource version is too old to watch from
+            if event["object"]["code"] == 410 {
+                return Ok(());
+            }
+            return Err(anyhow!("{}", event["object"]["message"]));
+        }
+        if policies.apply_event(kind, &event["object"]) {
+            install(shared, &policies);
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Starts a thread which keeps the TrowPolicy rules in the shared policy up to date, using the
+ * in-cluster configuration.
+ *
+ * Fails if not running in Kubernetes. Errors from the API, e.g. if the CRD isn't installed, are
+ * logged and the watch retried, keeping the rules already in use.
+ */
+pub fn watch(shared: SharedPolicy) -> Result<()> {
+    let host = env::var("KUBERNETES_SERVICE_HOST")
+        .map_err(|_| anyhow!("Not running in Kubernetes, KUBERNETES_SERVICE_HOST isn't set"))?;
+    let port = env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+    let ca =
+        reqwest::Certificate::from_pem(&fs::read(Path::new(SERVICE_ACCOUNT_DIR).join("ca.crt"))?)?;
+    let client = reqwest::blocking::Client::builder()
+        .add_root_certificate(ca)
+        // Leaves the server time to end the watch first
+        .timeout(Duration::from_secs(WATCH_TIMEOUT_SECS + 30))
+        .build()?;
+    let url = format!("https://{}:{}{}", host, port, API_PATH);
+    info!("Watching TrowPolicy resources at {}", url);
+
+    thread::Builder::new()
+        .name("trow-policy-watcher".to_string())
+        .spawn(move || loop {
+            if let Err(e) = list_and_watch(&client, &url, &shared) {
+                warn!(
+                    "Failed to watch TrowPolicy resources, retrying in {:?}: {}",
+                    RETRY_DELAY, e
+                );
+                thread::sleep(RETRY_DELAY);
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::ClusterPolicies;
+    use serde_json::json;
+
+    fn policy(name: &str, spec: serde_json::Value) -> serde_json::Value {
+        json!({"metadata": {"name": name}, "spec": spec})
+    }
+
+    #[test]
+    fn combines_policies() {
+        let mut policies = ClusterPolicies::default();
+        assert!(policies.apply_event(
+            "ADDED",
+            &policy(
+                "b-prod",
+                json!({
+                    "allowedRegistries": ["quay.io/"],
+                    "namespaces": [{
+                        "names": ["prod", "prod-*"],
+                        "allowedImages": ["gcr.io/distroless/static:nonroot"],
+                        "requireSignatures": ["*"]
+                    }]
+                })
+            )
+        ));
+        assert!(policies.apply_event(
+            "ADDED",
+            &policy("a-base", json!({"requireSignatures": ["myorg/*"]}))
+        ));
+
+        assert_eq!(
+            policies.allow_rule("quay.io/app:v1", "dev").unwrap(),
+            "TrowPolicy b-prod allows registry quay.io/"
+        );
+        assert!(policies.restricts("prod-eu"));
+        assert_eq!(policies.allow_rule("quay.io/app:v1", "prod-eu"), None);
+        assert_eq!(
+            policies
+                .allow_rule("gcr.io/distroless/static:nonroot", "prod")
+                .unwrap(),
+            "TrowPolicy b-prod allows image gcr.io/distroless/static:nonroot in namespace prod"
+        );
+
+        assert_eq!(
+            policies.signature_rule("myorg/app", "dev").unwrap(),
+            "TrowPolicy a-base requires signatures for myorg/*"
+        );
+        assert_eq!(policies.signature_rule("other/app", "dev"), None);
+        assert_eq!(
+            policies.signature_rule("other/app", "prod").unwrap(),
+            "TrowPolicy b-prod requires signatures for *"
+        );
+    }
+
+    #[test]
+    fn applies_watch_events() {
+        let mut policies = ClusterPolicies::default();
+        let spec = json!({"allowedRegistries": ["quay.io/"]});
+        assert!(policies.apply_event("ADDED", &policy("base", spec.clone())));
+        // Unchanged
+        assert!(!policies.apply_event("MODIFIED", &policy("base", spec)));
+        // Invalid, so the old version is kept
+        assert!(!policies.apply_event(
+            "MODIFIED",
+            &policy("base", json!({"allowedRegistries": "quay.io/"}))
+        ));
+        assert!(policies.allow_rule("quay.io/app:v1", "default").is_some());
+        assert!(!policies.apply_event("BOOKMARK", &policy("base", json!({}))));
+
+        assert!(policies.apply_event("DELETED", &policy("base", json!({}))));
+        assert!(policies.policies.is_empty());
+        assert_eq!(policies.allow_rule("quay.io/app:v1", "default"), None);
+    }
+}
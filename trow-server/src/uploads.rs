@@ -0,0 +1,695 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+/*
+ * Upload sessions saved to disk, so pushes in progress survive the backend restarting or
+ * crashing.
+ *
+ * Each session is saved next to its data in the scratch dir, as <uuid>.session, when it's started
+ * and after every chunk is written, and removed when the upload is completed. It records how many
+ * bytes the client has been told were stored. Any data after that is from a chunk cut off part
+ * way, so is dropped when the session is restored, letting the client carry on from the offset it
+ * last got back.
+ *
+ * A chunk has to start at that offset, so one sent out of order or twice is refused rather than
+ * appended to the data, and only one is written at a time (see start_chunk in server.rs). The
+ * session is marked while a chunk is being written, so whichever backend the next request goes to
+ * knows, and doesn't drop the data being appended. A backend that stops part way through a chunk
+ * leaves the mark behind, so it lapses once nothing has been written for CHUNK_TIMEOUT.
+ *
+ * Keeping a file per session means backends sharing the data dir never overwrite each other's
+ * sessions, and a session started on one can be found by another.
+ *
+ * The scratch dir is in the data dir unless --scratch-dir puts uploads elsewhere, such as a local
+ * SSD when the data dir is on slower network storage. Completed uploads are then copied into the
+ * data dir rather than moved, as it's on another filesystem.
+ *
+ * Clients can also upload ranges of a blob in parallel, sending each with a Content-Range that
+ * doesn't start where the data so far ends. Each is written to its own file, named
+ * <uuid>.<start>-<end>.part, and they're appended to the data in order when the upload is
+ * completed, before the digest is checked. A part shorter than its name says was cut off, so
+ * counts as missing and has to be sent again. Chunks sent in order are still appended to the data
+ * straight away, so can reach over parts sent ahead. The bytes the data already has are then
+ * dropped from those parts, and the digest check catches any that didn't match.
+ *
+ * Sessions nothing has been written to for longer than the TTL are abandoned, so are removed
+ * along with their data. So are any other files in the scratch dir that old, such as uploads
+ * from before sessions were saved or temporary files left by a crash.
+ */
+
+lazy_static! {
+    pub static ref ACTIVE: IntGauge =
+        register_int_gauge!("upload_sessions", "blob uploads in progress").unwrap();
+    pub static ref EXPIRED: IntCounter = register_int_counter!(
+        "upload_sessions_expired_total",
+        "blob uploads removed after being idle for longer than the TTL"
+    )
+    .unwrap();
+    pub static ref ORPHANS_REMOVED: IntCounter = register_int_counter!(
+        "scratch_files_removed_total",
+        "files in the scratch dir without an upload, removed once older than the TTL"
+    )
+    .unwrap();
+}
+
+static SESSION_EXT: &str = "session";
+static PART_EXT: &str = "part";
+// The error renaming across filesystems
+const EXDEV: i32 = 18;
+// Written when the backend shut down, before sessions were saved as they changed
+static LEGACY_SESSIONS_FILE: &str = "upload-sessions.json";
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub repo_name: String,
+    pub uuid: String,
+    // Bytes stored and acknowledged to the client
+    pub offset: u64,
+    // A chunk is being appended to the data
+    #[serde(default)]
+    pub writing: bool,
+}
+
+fn session_path(scratch_path: &Path, uuid: &str) -> PathBuf {
+    scratch_path.join(format!("{}.{}", uuid, SESSION_EXT))
+}
+
+/// Saves the session, replacing any earlier version of it
+pub fn save(scratch_path: &Path, session: &Session) -> Result<()> {
+    let tmp_path = scratch_path.join(Uuid::new_v4().to_string());
+    let res = serde_json::to_vec(session)
+        .map_err(anyhow::Error::from)
+        .and_then(|bytes| Ok(fs::write(&tmp_path, bytes)?))
+        .and_then(|_| {
+            Ok(fs::rename(
+                &tmp_path,
+                session_path(scratch_path, &session.uuid),
+            )?)
+        });
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+/*
+ * Moves a completed upload into the data dir. If the uploads are on another filesystem it's
+ * copied to tmp_dir, in the data dir, first, so the destination never has a partial file.
+ */
+pub fn move_file(from: &Path, to: &Path, tmp_dir: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.raw_os_error() == Some(EXDEV) => {
+            let tmp_path = tmp_dir.join(Uuid::new_v4().to_string());
+            let res = fs::copy(from, &tmp_path)
+                .and_then(|_| File::open(&tmp_path)?.sync_all())
+                .and_then(|_| fs::rename(&tmp_path, to));
+            if let Err(e) = res {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(e.into());
+            }
+            fs::remove_file(from)?;
+            Ok(())
+        }
+        res => Ok(res?),
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("Upload {uuid} {problem}")]
+pub struct InvalidRange {
+    pub uuid: String,
+    pub problem: String,
+}
+
+fn invalid_range(uuid: &str, problem: String) -> anyhow::Error {
+    InvalidRange {
+        uuid: uuid.to_string(),
+        problem,
+    }
+    .into()
+}
+
+// A range of the upload sent out of order, with the first and last byte
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Part {
+    start: u64,
+    end: u64,
+    path: PathBuf,
+}
+
+fn part_path(scratch_path: &Path, uuid: &str, start: u64, end: u64) -> PathBuf {
+    scratch_path.join(format!("{}.{}-{}.{}", uuid, start, end, PART_EXT))
+}
+
+// In order of where they start
+fn parts(scratch_path: &Path, uuid: &str) -> Result<Vec<Part>> {
+    let prefix = format!("{}.", uuid);
+    let mut parts = vec![];
+    for entry in fs::read_dir(scratch_path)? {
+        let path = entry?.path();
+        let range = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix(&prefix))
+            .and_then(|n| n.strip_suffix(&format!(".{}", PART_EXT)))
+            .and_then(|r| r.split_once('-'))
+            .and_then(|(s, e)| Some((s.parse().ok()?, e.parse().ok()?)));
+        if let Some((start, end)) = range {
+            parts.push(Part { start, end, path });
+        }
+    }
+    parts.sort_by_key(|p| (p.start, p.end));
+    Ok(parts)
+}
+
+/*
+ * Where to write bytes start to end of the upload, sent ahead of the data so far. Fails if they
+ * overlap what's already been sent, other than the same range being sent again.
+ */
+pub fn part_location(scratch_path: &Path, uuid: &str, start: u64, end: u64) -> Result<PathBuf> {
+    if end < start {
+        return Err(invalid_range(
+            uuid,
+            format!("can't take range {}-{}", start, end),
+        ));
+    }
+    let stored = fs::metadata(scratch_path.join(uuid))
+        .map(|m| m.len())
+        .unwrap_or(0);
+    if start < stored {
+        return Err(invalid_range(
+            uuid,
+            format!(
+                "already has bytes 0-{}, so can't take {}-{}",
+                stored - 1,
+                start,
+                end
+            ),
+        ));
+    }
+    let overlapping = parts(scratch_path, uuid)?
+        .into_iter()
+        .find(|p| (p.start, p.end) != (start, end) && p.start <= end && start <= p.end);
+    if let Some(p) = overlapping {
+        return Err(invalid_range(
+            uuid,
+            format!(
+                "already has bytes {}-{}, so can't take {}-{}",
+                p.start, p.end, start, end
+            ),
+        ));
+    }
+    Ok(part_path(scratch_path, uuid, start, end))
+}
+
+/*
+ * Whether a chunk sent with a Content-Range of start to end is ahead of the offset the client has
+ * been told was stored, so a range sent in parallel. Fails if it starts before the offset, as it
+ * would be appended after data the client already sent, or doesn't end after it starts.
+ */
+pub fn is_ahead(uuid: &str, offset: u64, start: u64, end: u64) -> Result<bool> {
+    if end < start {
+        return Err(invalid_range(
+            uuid,
+            format!("can't take range {}-{}", start, end),
+        ));
+    }
+    if start < offset {
+        return Err(invalid_range(
+            uuid,
+            format!(
+                "already has bytes 0-{}, so can't take {}-{}",
+                offset - 1,
+                start,
+                end
+            ),
+        ));
+    }
+    Ok(start > offset)
+}
+
+/*
+ * Appends the upload's parts to its data, returning how long it then is. Every part is checked
+ * before any is appended, so an upload with a range missing is left as it was and the client can
+ * send the range and complete it again. Parts, or the start of parts, the data already covers are
+ * skipped.
+ */
+pub fn assemble(scratch_path: &Path, uuid: &str) -> Result<u64> {
+    let data_path = scratch_path.join(uuid);
+    let mut len = fs::metadata(&data_path).map(|m| m.len()).unwrap_or(0);
+    let parts = parts(scratch_path, uuid)?;
+    if parts.is_empty() {
+        return Ok(len);
+    }
+
+    let mut end = len;
+    for p in &parts {
+        if p.start > end {
+            return Err(invalid_range(
+                uuid,
+                format!("is missing bytes {}-{}", end, p.start - 1),
+            ));
+        }
+        if fs::metadata(&p.path)?.len() != p.end - p.start + 1 {
+            return Err(invalid_range(
+                uuid,
+                format!("is missing part of bytes {}-{}", p.start, p.end),
+            ));
+        }
+        end = end.max(p.end + 1);
+    }
+
+    let mut data = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&data_path)?;
+    for p in &parts {
+        if p.end >= len {
+            let mut part = File::open(&p.path)?;
+            part.seek(SeekFrom::Start(len - p.start))?;
+            len += io::copy(&mut part, &mut data)?;
+        }
+        fs::remove_file(&p.path)?;
+    }
+    data.sync_all()?;
+    Ok(len)
+}
+
+pub fn exists(scratch_path: &Path, uuid: &str) -> bool {
+    session_path(scratch_path, uuid).exists()
+}
+
+// Removes the session, and any parts of the upload that were never assembled
+pub fn remove(scratch_path: &Path, uuid: &str) {
+    let path = session_path(scratch_path, uuid);
+    if let Err(e) = fs::remove_file(&path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove upload session {:?}: {}", path, e);
+        }
+    }
+    for p in parts(scratch_path, uuid).unwrap_or_default() {
+        if let Err(e) = fs::remove_file(&p.path) {
+            warn!("Failed to remove part of upload {:?}: {}", p.path, e);
+        }
+    }
+}
+
+/// Reads a saved session, leaving its data as it is. None if there's no session with the uuid.
+pub fn load(scratch_path: &Path, uuid: &str) -> Option<Session> {
+    // Uuids come from clients
+    if uuid.contains(['/', '\\', '.']) {
+        return None;
+    }
+    let path = session_path(scratch_path, uuid);
+    let session: Session = match fs::read(&path) {
+        Ok(bytes) => match serde_json::from_slice(&bytes) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Ignoring unreadable upload session {:?}: {}", path, e);
+                return None;
+            }
+        },
+        Err(_) => return None,
+    };
+    if session.uuid != uuid {
+        warn!("Ignoring upload session {:?} for {}", path, session.uuid);
+        return None;
+    }
+    Some(session)
+}
+
+/*
+ * Whether a chunk is being written to the upload, by this or another backend. False once neither
+ * the session nor the data has changed for CHUNK_TIMEOUT, as the chunk was cut off.
+ */
+pub fn is_writing(scratch_path: &Path, session: &Session, now: SystemTime) -> bool {
+    if !session.writing {
+        return false;
+    }
+    let idle = [
+        session_path(scratch_path, &session.uuid),
+        scratch_path.join(&session.uuid),
+    ]
+    .iter()
+    .filter_map(|p| idle_for(p, now))
+    .min();
+    matches!(idle, Some(idle) if idle <= CHUNK_TIMEOUT)
+}
+
+/*
+ * Restores a saved session, dropping any data past the acknowledged offset unless a chunk is
+ * still being written. None if there's no session with the uuid.
+ */
+pub fn restore(scratch_path: &Path, uuid: &str) -> Option<Session> {
+    let session = load(scratch_path, uuid)?;
+    if is_writing(scratch_path, &session, SystemTime::now()) {
+        return Some(session);
+    }
+    let data_path = scratch_path.join(uuid);
+    let res = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(&data_path)
+        .and_then(|f| {
+            if f.metadata()?.len() > session.offset {
+                f.set_len(session.offset)?;
+            }
+            Ok(())
+        });
+    if let Err(e) = res {
+        warn!("Failed to restore upload {:?}: {}", data_path, e);
+        return None;
+    }
+    Some(session)
+}
+
+/*
+ * Restores every saved session, converting the file written by older versions at shutdown.
+ */
+pub fn restore_all(scratch_path: &Path) -> HashMap<String, Session> {
+    convert_legacy(scratch_path);
+
+    let uuids: Vec<String> = fs::read_dir(scratch_path)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().map_or(false, |ext| ext == SESSION_EXT))
+                .filter_map(|p| Some(p.file_stem()?.to_string_lossy().to_string()))
+                .collect()
+        })
+        .unwrap_or_else(|e| {
+            warn!(
+                "Failed to read upload sessions in {:?}: {}",
+                scratch_path, e
+            );
+            vec![]
+        });
+    let sessions: HashMap<String, Session> = uuids
+        .iter()
+        .filter_map(|uuid| restore(scratch_path, uuid))
+        .map(|s| (s.uuid.clone(), s))
+        .collect();
+    if !sessions.is_empty() {
+        info!("Restored {} upload sessions", sessions.len());
+    }
+    ACTIVE.set(sessions.len() as i64);
+    sessions
+}
+
+fn idle_for(path: &Path, now: SystemTime) -> Option<Duration> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    Some(now.duration_since(modified).unwrap_or(Duration::ZERO))
+}
+
+/*
+ * Removes sessions where neither the session nor its data has changed for longer than ttl, then
+ * any other files in the scratch dir older than ttl. Returns how many sessions and other files
+ * were removed.
+ */
+pub fn expire(scratch_path: &Path, ttl: Duration, now: SystemTime) -> Result<(usize, usize)> {
+    let paths: Vec<PathBuf> = fs::read_dir(scratch_path)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .collect();
+    let is_session = |p: &Path| p.extension().map_or(false, |ext| ext == SESSION_EXT);
+
+    let mut expired = 0;
+    let mut live = vec![];
+    for path in paths.iter().filter(|p| is_session(p)) {
+        let uuid = match path.file_stem() {
+            Some(s) => s.to_string_lossy().to_string(),
+            None => continue,
+        };
+        let data_path = scratch_path.join(&uuid);
+        let part_paths: Vec<PathBuf> = parts(scratch_path, &uuid)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|p| p.path)
+            .collect();
+        let idle = [path, &data_path]
+            .into_iter()
+            .chain(&part_paths)
+            .filter_map(|p| idle_for(p, now))
+            .min();
+        if matches!(idle, Some(idle) if idle <= ttl) {
+            live.push(data_path);
+            live.extend(part_paths);
+            continue;
+        }
+        info!(
+            "Removing upload {} as it has been idle for over {:?}",
+            uuid, ttl
+        );
+        remove(scratch_path, &uuid);
+        if let Err(e) = fs::remove_file(&data_path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove upload {:?}: {}", data_path, e);
+            }
+        }
+        expired += 1;
+    }
+
+    let mut orphans = 0;
+    for path in paths.iter().filter(|p| !is_session(p) && !live.contains(p)) {
+        if !matches!(idle_for(path, now), Some(idle) if idle > ttl) {
+            continue;
+        }
+        match fs::remove_file(path) {
+            Ok(()) => orphans += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove {:?} from the scratch dir: {}", path, e),
+        }
+    }
+
+    EXPIRED.inc_by(expired as u64);
+    ORPHANS_REMOVED.inc_by(orphans as u64);
+    Ok((expired, orphans))
+}
+
+#[derive(Deserialize)]
+struct LegacySession {
+    repo_name: String,
+    uuid: String,
+}
+
+fn convert_legacy(scratch_path: &Path) {
+    let path = scratch_path.join(LEGACY_SESSIONS_FILE);
+    let legacy: Vec<LegacySession> = match fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable upload sessions in {:?}: {}", path, e);
+            vec![]
+        }),
+        Err(_) => return,
+    };
+    for l in legacy {
+        // Saved once requests had stopped, so all the data was acknowledged
+        let offset = fs::metadata(scratch_path.join(&l.uuid))
+            .map(|m| m.len())
+            .unwrap_or(0);
+        let session = Session {
+            repo_name: l.repo_name,
+            uuid: l.uuid,
+            offset,
+            writing: false,
+        };
+        if let Err(e) = save(scratch_path, &session) {
+            warn!("Failed to convert upload session {}: {:?}", session.uuid, e);
+        }
+    }
+    if let Err(e) = fs::remove_file(&path) {
+        warn!("Failed to remove {:?}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        assemble, exists, expire, is_ahead, is_writing, load, part_location, remove, restore,
+        restore_all, save, Session, CHUNK_TIMEOUT,
+    };
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use tempfile::tempdir;
+
+    #[test]
+    fn restores_acknowledged_data() {
+        let dir = tempdir().unwrap();
+        let scratch = dir.path();
+        let session = Session {
+            repo_name: "myorg/app".to_string(),
+            uuid: "1234".to_string(),
+            offset: 5,
+            writing: false,
+        };
+        save(scratch, &session).unwrap();
+        // The second chunk was cut off before it was acknowledged
+        fs::write(scratch.join("1234"), "hello wor").unwrap();
+
+        let sessions = restore_all(scratch);
+        assert_eq!(sessions.get("1234"), Some(&session));
+        assert_eq!(fs::read(scratch.join("1234")).unwrap(), b"hello");
+
+        assert!(restore(scratch, "../1234").is_none());
+        remove(scratch, "1234");
+        assert!(restore(scratch, "1234").is_none());
+    }
+
+    #[test]
+    fn keeps_chunks_being_written() {
+        let dir = tempdir().unwrap();
+        let scratch = dir.path();
+        let mut session = Session {
+            repo_name: "myorg/app".to_string(),
+            uuid: "1234".to_string(),
+            offset: 5,
+            writing: true,
+        };
+        save(scratch, &session).unwrap();
+        fs::write(scratch.join("1234"), "hello wor").unwrap();
+
+        // e.g. another backend looking for the upload while the chunk comes in
+        let now = SystemTime::now();
+        assert!(is_writing(scratch, &session, now));
+        assert_eq!(restore(scratch, "1234"), Some(session.clone()));
+        assert_eq!(fs::read(scratch.join("1234")).unwrap(), b"hello wor");
+        // Left behind by a backend that stopped
+        assert!(!is_writing(scratch, &session, now + CHUNK_TIMEOUT * 2));
+
+        session.writing = false;
+        session.offset = 9;
+        save(scratch, &session).unwrap();
+        assert!(!is_writing(scratch, &load(scratch, "1234").unwrap(), now));
+    }
+
+    fn start_upload(scratch: &Path, uuid: &str) {
+        let session = Session {
+            repo_name: "myorg/app".to_string(),
+            uuid: uuid.to_string(),
+            offset: 0,
+            writing: false,
+        };
+        save(scratch, &session).unwrap();
+        fs::write(scratch.join(uuid), "data").unwrap();
+    }
+
+    #[test]
+    fn expires_idle_uploads() {
+        let dir = tempdir().unwrap();
+        let scratch = dir.path();
+        let ttl = Duration::from_millis(500);
+        start_upload(scratch, "idle");
+        start_upload(scratch, "busy");
+        fs::write(scratch.join("leftover"), "data").unwrap();
+        assert_eq!(expire(scratch, ttl, SystemTime::now()).unwrap(), (0, 0));
+
+        std::thread::sleep(ttl * 2);
+        // Data written recently keeps a session alive
+        fs::write(scratch.join("busy"), "more data").unwrap();
+        assert_eq!(expire(scratch, ttl, SystemTime::now()).unwrap(), (1, 1));
+        assert!(!exists(scratch, "idle"));
+        assert!(!scratch.join("idle").exists());
+        assert!(!scratch.join("leftover").exists());
+        assert!(exists(scratch, "busy"));
+        assert!(scratch.join("busy").exists());
+    }
+
+    #[test]
+    fn assembles_ranges_sent_in_parallel() {
+        let dir = tempdir().unwrap();
+        let scratch = dir.path();
+        start_upload(scratch, "1234");
+
+        let second = part_location(scratch, "1234", 8, 11).unwrap();
+        let first = part_location(scratch, "1234", 4, 7).unwrap();
+        assert!(part_location(scratch, "1234", 2, 5).is_err());
+        assert!(part_location(scratch, "1234", 6, 9).is_err());
+        fs::write(&second, "2222").unwrap();
+        // Not until the first range has been sent
+        assert!(assemble(scratch, "1234").is_err());
+        fs::write(&first, "11").unwrap();
+        assert!(assemble(scratch, "1234").is_err());
+        // Sending a range again replaces it
+        assert_eq!(part_location(scratch, "1234", 4, 7).unwrap(), first);
+        fs::write(&first, "1111").unwrap();
+
+        assert_eq!(assemble(scratch, "1234").unwrap(), 12);
+        assert_eq!(fs::read(scratch.join("1234")).unwrap(), b"data11112222");
+        assert!(!first.exists() && !second.exists());
+
+        fs::write(part_location(scratch, "1234", 20, 23).unwrap(), "3333").unwrap();
+        remove(scratch, "1234");
+        assert_eq!(fs::read_dir(scratch).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn skips_parts_chunks_sent_in_order_reach_over() {
+        let dir = tempdir().unwrap();
+        let scratch = dir.path();
+        start_upload(scratch, "1234");
+
+        let ahead = part_location(scratch, "1234", 8, 11).unwrap();
+        let further = part_location(scratch, "1234", 12, 13).unwrap();
+        fs::write(&ahead, "2222").unwrap();
+        fs::write(&further, "33").unwrap();
+        // A chunk sent in order, overlapping the first part
+        fs::write(scratch.join("1234"), "data111122").unwrap();
+
+        assert_eq!(assemble(scratch, "1234").unwrap(), 14);
+        assert_eq!(fs::read(scratch.join("1234")).unwrap(), b"data1111222233");
+        assert!(!ahead.exists() && !further.exists());
+
+        // Or covering a part entirely
+        let covered = part_location(scratch, "1234", 14, 15).unwrap();
+        fs::write(&covered, "44").unwrap();
+        fs::write(scratch.join("1234"), "data11112222334455").unwrap();
+        assert_eq!(assemble(scratch, "1234").unwrap(), 18);
+        assert_eq!(
+            fs::read(scratch.join("1234")).unwrap(),
+            b"data11112222334455"
+        );
+        assert!(!covered.exists());
+    }
+
+    #[test]
+    fn checks_chunks_follow_on() {
+        assert!(!is_ahead("1234", 0, 0, 9).unwrap());
+        assert!(!is_ahead("1234", 10, 10, 19).unwrap());
+        assert!(is_ahead("1234", 10, 20, 29).unwrap());
+        // Sent again, or overlapping what's stored
+        assert!(is_ahead("1234", 10, 0, 9).is_err());
+        assert!(is_ahead("1234", 10, 5, 14).is_err());
+        assert!(is_ahead("1234", 10, 19, 10).is_err());
+    }
+
+    #[test]
+    fn converts_legacy_sessions() {
+        let dir = tempdir().unwrap();
+        let scratch = dir.path();
+        fs::write(
+            scratch.join("upload-sessions.json"),
+            r#"[{"repo_name": "myorg/app", "uuid": "1234"}]"#,
+        )
+        .unwrap();
+        fs::write(scratch.join("1234"), "hello").unwrap();
+
+        let sessions = restore_all(scratch);
+        assert_eq!(sessions["1234"].offset, 5);
+        assert!(!scratch.join("upload-sessions.json").exists());
+        assert!(restore(scratch, "1234").is_some());
+    }
+}
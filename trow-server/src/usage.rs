@@ -0,0 +1,345 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde_json::Value;
+
+use crate::jobs::JobHandle;
+use crate::retention::{is_digest, read_repos};
+use crate::validate::parse_image;
+
+/*
+ * Reconciles the images stored in Trow with the images running in the cluster, to show which
+ * haven't been used in a while and could be cleaned up by retention.
+ *
+ * The "usage" job lists the running pods from the Kubernetes API, using the pod's service
+ * account (which needs permission to list pods in all namespaces). Each tag that's running is
+ * marked as seen in usage.json in the data dir. A tag is running if a container refers to it by
+ * repository and tag, or to the digest it points at, either in the pod spec or the image ID
+ * reported by the kubelet.
+ *
+ * Trow only knows what was running when the job ran, so the job should be run regularly, e.g.
+ * with --usage-interval. Tags never seen running count as unused since they were pushed.
+ */
+
+static USAGE_FILE: &str = "usage.json";
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+const DOCKER_HUB_HOSTNAME: &str = "docker.io";
+
+// A tag that hasn't been seen running for a while
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnusedImage {
+    pub repo_name: String,
+    pub tag: String,
+    pub digest: String,
+    pub pushed: DateTime<Utc>,
+    // None if never seen running
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/*
+ * References to images in running pods.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunningImages {
+    digests: HashSet<String>,
+    // Repository and tag, without the host name
+    tags: HashSet<(String, String)>,
+}
+
+impl RunningImages {
+    fn add(&mut self, reference: &str) {
+        // e.g. docker-pullable://host/repo@sha256:...
+        if let Some((_, digest)) = reference.rsplit_once('@') {
+            if is_digest(digest) {
+                self.digests.insert(digest.to_string());
+            }
+            return;
+        }
+        if is_digest(reference) {
+            self.digests.insert(reference.to_string());
+            return;
+        }
+        let image = parse_image(reference);
+        // Docker Hub images can't be ones stored in Trow
+        if image.host != DOCKER_HUB_HOSTNAME {
+            self.tags.insert((image.repo, image.tag));
+        }
+    }
+
+    /*
+     * Collects the images from a PodList returned by the Kubernetes API.
+     */
+    pub fn from_pod_list(pods: &Value) -> RunningImages {
+        let mut running = RunningImages::default();
+        let empty = vec![];
+        for pod in pods["items"].as_array().unwrap_or(&empty) {
+            for kind in ["containers", "initContainers", "ephemeralContainers"] {
+                for c in pod["spec"][kind].as_array().unwrap_or(&empty) {
+                    if let Some(image) = c["image"].as_str() {
+                        running.add(image);
+                    }
+                }
+            }
+            for kind in [
+                "containerStatuses",
+                "initContainerStatuses",
+                "ephemeralContainerStatuses",
+            ] {
+                for c in pod["status"][kind].as_array().unwrap_or(&empty) {
+                    if let Some(image_id) = c["imageID"].as_str() {
+                        running.add(image_id);
+                    }
+                }
+            }
+        }
+        running
+    }
+
+    fn contains(&self, repo_name: &str, tag: &str, digest: &str) -> bool {
+        self.digests.contains(digest)
+            || self
+                .tags
+                .contains(&(repo_name.to_string(), tag.to_string()))
+    }
+}
+
+/*
+ * Lists the images in running pods, using the in-cluster configuration.
+ */
+pub fn list_running_images() -> Result<RunningImages> {
+    let host = env::var("KUBERNETES_SERVICE_HOST")
+        .map_err(|_| anyhow!("Not running in Kubernetes, KUBERNETES_SERVICE_HOST isn't set"))?;
+    let port = env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+    let sa_dir = Path::new(SERVICE_ACCOUNT_DIR);
+    let token = fs::read_to_string(sa_dir.join("token"))?;
+    let ca = reqwest::Certificate::from_pem(&fs::read(sa_dir.join("ca.crt"))?)?;
+
+    let url = format!(
+        "https://{}:{}/api/v1/pods?fieldSelector=status.phase%3DRunning",
+        host, port
+    );
+    debug!("Listing pods from {}", url);
+    let pods: Value = reqwest::blocking::Client::builder()
+        .add_root_certificate(ca)
+        .timeout(Duration::from_secs(60))
+        .build()?
+        .get(&url)
+        .bearer_auth(token.trim())
+        .send()?
+        .error_for_status()?
+        .json()?;
+    Ok(RunningImages::from_pod_list(&pods))
+}
+
+/*
+ * When each tag was last seen running, keyed by repo:tag.
+ */
+pub struct LastSeen {
+    path: PathBuf,
+    seen: HashMap<String, DateTime<Utc>>,
+}
+
+fn tag_key(repo_name: &str, tag: &str) -> String {
+    format!("{}:{}", repo_name, tag)
+}
+
+impl LastSeen {
+    pub fn load(data_path: &Path) -> Result<LastSeen> {
+        let path = data_path.join(USAGE_FILE);
+        let seen = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(LastSeen { path, seen })
+    }
+
+    // Renamed into place so a crash can't leave a partly written file
+    fn save(&self) -> Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&self.seen)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    fn get(&self, repo_name: &str, tag: &str) -> Option<DateTime<Utc>> {
+        self.seen.get(&tag_key(repo_name, tag)).cloned()
+    }
+}
+
+// Every tag in the registry, with the digest it points at and when it was pushed
+fn stored_tags(manifests_path: &Path) -> Result<Vec<(String, String, String, SystemTime)>> {
+    let mut tags = vec![];
+    for (repo_name, files) in read_repos(manifests_path)? {
+        for (tag, tf) in files {
+            if is_digest(&tag) {
+                continue;
+            }
+            if let Some(current) = tf.history.first() {
+                tags.push((repo_name.clone(), tag, current.digest.clone(), tf.pushed));
+            }
+        }
+    }
+    tags.sort();
+    Ok(tags)
+}
+
+/*
+ * Marks the stored tags that are running as seen now, returning how many were.
+ */
+pub fn reconcile(
+    manifests_path: &Path,
+    last_seen: &mut LastSeen,
+    running: &RunningImages,
+    now: DateTime<Utc>,
+) -> Result<(usize, usize)> {
+    let tags = stored_tags(manifests_path)?;
+    let mut seen = 0;
+    for (repo_name, tag, digest, _) in &tags {
+        if running.contains(repo_name, tag, digest) {
+            last_seen.seen.insert(tag_key(repo_name, tag), now);
+            seen += 1;
+        }
+    }
+    // Forget tags that have been deleted
+    let keys: HashSet<String> = tags.iter().map(|(r, t, _, _)| tag_key(r, t)).collect();
+    last_seen.seen.retain(|k, _| keys.contains(k));
+    last_seen.save()?;
+    Ok((seen, tags.len()))
+}
+
+/*
+ * Runs as the "usage" job.
+ */
+pub fn run_job(manifests_path: &Path, data_path: &Path, handle: &JobHandle) -> Result<String> {
+    let running = list_running_images()?;
+    handle.set_progress(1, 2);
+    let mut last_seen = LastSeen::load(data_path)?;
+    let (seen, total) = reconcile(manifests_path, &mut last_seen, &running, Utc::now())?;
+    Ok(format!(
+        "{} of {} tags are running in the cluster",
+        seen, total
+    ))
+}
+
+/*
+ * Tags that haven't been seen running for at least unused_for, oldest first.
+ */
+pub fn report(
+    manifests_path: &Path,
+    data_path: &Path,
+    unused_for: Duration,
+    now: DateTime<Utc>,
+) -> Result<Vec<UnusedImage>> {
+    let last_seen = LastSeen::load(data_path)?;
+    let unused_for = chrono::Duration::from_std(unused_for)?;
+    let mut unused = vec![];
+    for (repo_name, tag, digest, pushed) in stored_tags(manifests_path)? {
+        let pushed = DateTime::<Utc>::from(pushed);
+        let seen = last_seen.get(&repo_name, &tag);
+        let since = match seen {
+            Some(s) if s > pushed => s,
+            _ => pushed,
+        };
+        if now - since >= unused_for {
+            unused.push(UnusedImage {
+                repo_name,
+                tag,
+                digest,
+                pushed,
+                last_seen: seen,
+            });
+        }
+    }
+    unused.sort_by_key(|u| u.last_seen.map_or(u.pushed, |s| s.max(u.pushed)));
+    Ok(unused)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{reconcile, report, LastSeen, RunningImages};
+    use chrono::{Duration, TimeZone, Utc};
+    use serde_json::json;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn images_from_pods() {
+        let pods = json!({
+            "items": [{
+                "spec": {
+                    "containers": [
+                        {"image": "trow.kube-public:31000/myorg/app:v1"},
+                        {"image": "nginx"}
+                    ],
+                    "initContainers": [{"image": "trow.kube-public:31000/init@sha256:1234"}]
+                },
+                "status": {
+                    "containerStatuses": [
+                        {"imageID": "docker-pullable://trow.kube-public:31000/myorg/app@sha256:abcd"}
+                    ]
+                }
+            }]
+        });
+        let running = RunningImages::from_pod_list(&pods);
+        assert!(running.contains("myorg/app", "v1", "sha256:other"));
+        assert!(running.contains("other", "v2", "sha256:abcd"));
+        assert!(running.contains("init", "v3", "sha256:1234"));
+        assert!(!running.contains("nginx", "latest", "sha256:other"));
+    }
+
+    #[test]
+    fn reconcile_and_report() {
+        let dir = tempdir().unwrap();
+        let manifests = dir.path().join("manifests");
+        fs::create_dir_all(manifests.join("myorg/app")).unwrap();
+        let pushed = "2022-01-01T00:00:00Z";
+        for (tag, digest) in [("v1", "sha256:aaaa"), ("v2", "sha256:bbbb")] {
+            fs::write(
+                manifests.join("myorg/app").join(tag),
+                format!("{} {}\n", digest, pushed),
+            )
+            .unwrap();
+        }
+
+        let running = RunningImages::from_pod_list(&json!({
+            "items": [{"spec": {"containers": [{"image": "trow.local/myorg/app:v1"}]}}]
+        }));
+        let seen_at = Utc.ymd(2022, 3, 1).and_hms(0, 0, 0);
+        let mut last_seen = LastSeen::load(dir.path()).unwrap();
+        assert_eq!(
+            reconcile(&manifests, &mut last_seen, &running, seen_at).unwrap(),
+            (1, 2)
+        );
+
+        // v1 was seen 10 days ago, v2 never and was pushed months ago
+        let now = seen_at + Duration::days(10);
+        let unused = report(
+            &manifests,
+            dir.path(),
+            std::time::Duration::from_secs(30 * 24 * 60 * 60),
+            now,
+        )
+        .unwrap();
+        assert_eq!(unused.len(), 1);
+        assert_eq!(unused[0].tag, "v2");
+        assert_eq!(unused[0].last_seen, None);
+
+        let unused = report(
+            &manifests,
+            dir.path(),
+            std::time::Duration::from_secs(7 * 24 * 60 * 60),
+            now,
+        )
+        .unwrap();
+        assert_eq!(unused.len(), 2);
+        assert_eq!(unused[1].tag, "v1");
+        assert_eq!(unused[1].last_seen, Some(seen_at));
+    }
+}
@@ -1,8 +1,10 @@
-use log::info;
+use log::{info, warn};
 use tonic::{Request, Response, Status};
 
 use crate::server::trow_server::admission_controller_server::AdmissionController;
-use crate::server::trow_server::{AdmissionRequest, AdmissionResponse};
+use crate::server::trow_server::{
+    AdmissionRequest, AdmissionResponse, ImageDecision, PolicyDecision, PolicyRequest,
+};
 use crate::server::{Image, TrowServer};
 
 const DOCKER_HUB_HOSTNAME: &str = "docker.io";
@@ -21,7 +23,7 @@ const DOCKER_HUB_HOSTNAME: &str = "docker.io";
  *
  * The tests should clarify a bit.
  */
-fn parse_image(image_str: &str) -> Image {
+pub(crate) fn parse_image(image_str: &str) -> Image {
     let host;
     let after_host;
     let repo;
@@ -62,49 +64,216 @@ fn parse_image(image_str: &str) -> Image {
     }
 }
 
+// The decision for a single image, with the rule that made it
+#[derive(Clone, Debug, PartialEq)]
+struct ImageCheck {
+    allowed: bool,
+    reason: String,
+    rule: String,
+}
+
+impl ImageCheck {
+    fn new(allowed: bool, reason: String, rule: &str) -> ImageCheck {
+        ImageCheck {
+            allowed,
+            reason,
+            rule: rule.to_string(),
+        }
+    }
+}
+
+/*
+ * The image as stored in this registry, with the digest as the tag if it's pinned to one, e.g.
+ * host/repo@sha256:... rather than host/repo:tag.
+ */
+fn stored_image(image_raw: &str) -> Image {
+    match image_raw.split_once('@') {
+        Some((name, digest)) => Image {
+            tag: digest.to_string(),
+            ..parse_image(name)
+        },
+        None => parse_image(image_raw),
+    }
+}
+
+/*
+ * Local images not in the registry are only allowed if they're on an allow list, unless
+ * require_existing is set, when they're always denied.
+ */
 #[allow(clippy::needless_return)]
 fn check_image(
     image_raw: &str,
     local_hosts: &[String],
+    require_existing: bool,
     image_exists: &dyn Fn(&Image) -> bool,
-    deny: &dyn Fn(&Image) -> bool,
-    allow: &dyn Fn(&Image) -> bool,
-) -> (bool, String) {
+    deny: &dyn Fn(&Image) -> Option<String>,
+    allow: &dyn Fn(&Image) -> Option<String>,
+) -> ImageCheck {
     let image = parse_image(&image_raw);
     if local_hosts.contains(&image.host) {
         //local image
-        if image_exists(&image) {
-            if deny(&image) {
-                return (false, format!("Local image {} on deny list", &image_raw));
+        if image_exists(&stored_image(image_raw)) {
+            if let Some(rule) = deny(&image) {
+                return ImageCheck::new(
+                    false,
+                    format!("Local image {} on deny list", &image_raw),
+                    &rule,
+                );
             } else {
                 let reason = format!("Image {} allowed as local image", &image_raw);
                 info!("{}", reason);
-                return (true, "".to_owned());
+                return ImageCheck::new(true, reason, "local image in registry");
             }
-        } else if allow(&image) {
-            info!(
+        } else if require_existing {
+            let reason = format!(
+                "Local image {} disallowed as not contained in this registry, which is required",
+                &image_raw
+            );
+            info!("{}", reason);
+            return ImageCheck::new(false, reason, "existing images required");
+        } else if let Some(rule) = allow(&image) {
+            let reason = format!(
                 "Local image {} allowed as on allow list (but not in registry)",
                 &image_raw
             );
-            return (true, "".to_owned());
+            info!("{}", reason);
+            return ImageCheck::new(true, reason, &rule);
         } else {
             let reason = format!(
                 "Local image {} disallowed as not contained in this registry and not in allow list",
                 &image_raw
             );
             info!("{}", reason);
-            return (false, reason);
+            return ImageCheck::new(false, reason, "local image not in registry");
         }
-    } else if allow(&image) {
-        info!("Remote image {} allowed as on allow list", &image_raw);
-        return (true, "".to_owned());
+    } else if let Some(rule) = allow(&image) {
+        let reason = format!("Remote image {} allowed as on allow list", &image_raw);
+        info!("{}", reason);
+        return ImageCheck::new(true, reason, &rule);
     } else {
         let reason = format!(
             "Remote image {} disallowed as not contained in this registry and not in allow list",
             &image_raw
         );
-        return (false, reason);
+        return ImageCheck::new(false, reason, "remote image not on allow list");
+    }
+}
+
+/*
+ * Denies a local image that a TrowPolicy requires to be signed if there's no cosign signature
+ * for it in the registry.
+ */
+fn check_signature(
+    ts: &TrowServer,
+    image_raw: &str,
+    namespace: &str,
+    local_hosts: &[String],
+) -> Option<ImageCheck> {
+    let (name, digest) = match image_raw.split_once('@') {
+        Some((name, digest)) => (name, Some(digest)),
+        None => (image_raw, None),
+    };
+    let image = parse_image(name);
+    if !local_hosts.contains(&image.host) {
+        return None;
+    }
+    let rule = ts.signature_rule(&image.repo, namespace)?;
+    if ts.has_signature(&image.repo, digest.unwrap_or(&image.tag)) {
+        return None;
+    }
+    let reason = format!(
+        "Local image {} isn't signed, as required by {}",
+        image_raw, rule
+    );
+    info!("{}", reason);
+    Some(ImageCheck::new(false, reason, &rule))
+}
+
+// The decision for a pod's images
+struct Decision {
+    allowed: bool,
+    // Of each image denied, blank if allowed
+    reason: String,
+    images: Vec<ImageDecision>,
+    freeze_window: Option<String>,
+    // Allowed only because break_glass overrode a change freeze
+    break_glass: bool,
+}
+
+/*
+ * Decides whether to admit the images to the namespace, without recording anything, so it can
+ * be used both for admission and for trying out policies.
+ *
+ * Every image is checked, even once one is denied, so the reason covers all that need fixing.
+ * An image used by several of a pod's containers is only checked once.
+ */
+fn evaluate(
+    ts: &TrowServer,
+    images: &[String],
+    namespace: &str,
+    host_names: &[String],
+    break_glass: &str,
+) -> Decision {
+    let mut decision = Decision {
+        allowed: true,
+        reason: "".to_string(),
+        images: vec![],
+        freeze_window: None,
+        break_glass: false,
+    };
+
+    let require_existing = ts.requires_existing_images();
+    for image_raw in images {
+        if decision.images.iter().any(|d| &d.image == image_raw) {
+            continue;
+        }
+        //Using a closure here is inefficient but makes it easier to test check_image
+        let check = check_image(
+            image_raw,
+            host_names,
+            require_existing,
+            &|image| ts.image_exists(image),
+            &|i| ts.local_deny_rule(i),
+            &|i| ts.allow_rule(i, namespace),
+        );
+        let check = if check.allowed {
+            check_signature(ts, image_raw, namespace, host_names).unwrap_or(check)
+        } else {
+            check
+        };
+        if !check.allowed {
+            if !decision.allowed {
+                decision.reason.push_str("; ");
+            }
+            decision.allowed = false;
+            decision.reason.push_str(&check.reason);
+        }
+        decision.images.push(ImageDecision {
+            image: image_raw.clone(),
+            is_allowed: check.allowed,
+            reason: check.reason,
+            rule: check.rule,
+        });
+    }
+
+    if decision.allowed {
+        if let Some((window, new_images)) = ts.frozen_images(namespace, images) {
+            decision.freeze_window = Some(window.to_string());
+            if let Some(image) = new_images.first() {
+                if break_glass.is_empty() {
+                    decision.allowed = false;
+                    decision.reason = format!(
+                        "Image {} isn't already running in namespace {} during change freeze {}",
+                        image, namespace, window
+                    );
+                    info!("{}", decision.reason);
+                } else {
+                    decision.break_glass = true;
+                }
+            }
+        }
     }
+    decision
 }
 
 #[tonic::async_trait]
@@ -114,38 +283,73 @@ impl AdmissionController for TrowServer {
         ar: Request<AdmissionRequest>,
     ) -> Result<Response<AdmissionResponse>, Status> {
         let ar = ar.into_inner();
-        let mut valid = true;
-        let mut reason = "".to_string();
-
-        for image_raw in ar.images {
-            //Using a closure here is inefficient but makes it easier to test check_image
-            let (v, r) = check_image(
-                &image_raw,
-                &ar.host_names,
-                &|image| self.image_exists(image),
-                &|i| self.is_local_denied(i),
-                &|i| self.is_allowed(i),
+        let decision = evaluate(
+            self,
+            &ar.images,
+            &ar.namespace,
+            &ar.host_names,
+            &ar.break_glass,
+        );
+
+        if decision.break_glass {
+            warn!(
+                "Change freeze {} overridden for {:?} in namespace {}: {}",
+                decision.freeze_window.as_deref().unwrap_or_default(),
+                ar.images,
+                ar.namespace,
+                ar.break_glass
             );
-            if !v {
-                valid = false;
-                reason = r;
-                break;
-            }
+        }
+        if decision.allowed {
+            self.record_admitted(&ar.namespace, &ar.images);
+            // Images pinned by digest can't be parsed yet, so aren't mirrored
+            let hub_images: Vec<Image> = ar
+                .images
+                .iter()
+                .filter(|i| !i.contains('@'))
+                .map(|i| parse_image(i))
+                .filter(|i| i.host == DOCKER_HUB_HOSTNAME)
+                .collect();
+            self.mirror_admitted(&hub_images);
         }
 
         let ar = AdmissionResponse {
-            is_allowed: valid,
-            reason,
+            is_allowed: decision.allowed,
+            reason: decision.reason,
+            break_glass: decision.break_glass,
+            images: decision.images,
         };
         Ok(Response::new(ar))
     }
+
+    async fn evaluate_policy(
+        &self,
+        pr: Request<PolicyRequest>,
+    ) -> Result<Response<PolicyDecision>, Status> {
+        let pr = pr.into_inner();
+        let decision = evaluate(
+            self,
+            &pr.images,
+            &pr.namespace,
+            &pr.host_names,
+            &pr.break_glass,
+        );
+
+        Ok(Response::new(PolicyDecision {
+            is_allowed: decision.allowed,
+            reason: decision.reason,
+            images: decision.images,
+            freeze_window: decision.freeze_window.unwrap_or_default(),
+            break_glass: decision.break_glass,
+        }))
+    }
 }
 
 #[cfg(test)]
 mod test {
 
     use super::Image;
-    use super::{check_image, parse_image};
+    use super::{check_image, parse_image, stored_image};
 
     #[test]
     fn test_parse() {
@@ -210,63 +414,150 @@ mod test {
     #[test]
     fn test_check() {
         //Image hosted in this registry, should be ok
-        let (v, _) = check_image(
+        let v = check_image(
             "localhost:8080/mydir/myimage:test",
             &vec!["localhost:8080".to_owned()],
+            false,
             &|_| true, //determines if in this registry
-            &|_| false,
-            &|_| false,
-        );
+            &|_| None,
+            &|_| None,
+        )
+        .allowed;
         assert_eq!(true, v); //Easier to read than assert!(!v)
 
         //Image refers to this registry but not present in registry (so deny)
-        let (v, _) = check_image(
+        let v = check_image(
             "localhost:8080/mydir/myimage:test",
             &vec!["localhost:8080".to_owned()],
+            false,
             &|_| false,
-            &|_| false,
-            &|_| false,
-        );
+            &|_| None,
+            &|_| None,
+        )
+        .allowed;
         assert_eq!(false, v);
 
         //Image refers to this registry & not present but is in allow list (so allow)
-        let (v, _) = check_image(
+        let v = check_image(
             "localhost:8080/mydir/myimage:test",
             &vec!["localhost:8080".to_owned()],
+            false,
             &|_| false, //determines if in this registry
-            &|_| false,
-            &|_| true,
-        );
+            &|_| None,
+            &|_| Some("allow".to_string()),
+        )
+        .allowed;
         assert_eq!(true, v);
 
         //Image local and present but on deny list
-        let (v, _) = check_image(
+        let v = check_image(
             "localhost:8080/mydir/myimage:test",
             &vec!["localhost:8080".to_owned()],
+            false,
             &|_| true, //determines if in this registry
-            &|_| true,
-            &|_| false,
-        );
+            &|_| Some("deny".to_string()),
+            &|_| None,
+        )
+        .allowed;
         assert_eq!(false, v);
 
         //Image remote and not on allow list (deny)
-        let (v, _) = check_image(
+        let v = check_image(
             "quay.io/mydir/myimage:test",
             &vec!["localhost:8080".to_owned()],
+            false,
             &|_| true, //determines if in this registry
-            &|_| false,
-            &|_| false,
-        );
+            &|_| None,
+            &|_| None,
+        )
+        .allowed;
         assert_eq!(false, v);
 
         //Image remote and on allow list (allow)
-        let (v, _) = check_image(
+        let v = check_image(
             "quay.io/mydir/myimage:test",
             &vec!["localhost:8080".to_owned()],
+            false,
             &|_| true, //determines if in this registry
-            &|_| false,
+            &|_| None,
+            &|_| Some("allow".to_string()),
+        )
+        .allowed;
+        assert_eq!(true, v);
+    }
+
+    #[test]
+    fn check_gives_matching_rule() {
+        let check = check_image(
+            "localhost:8080/mydir/myimage:test",
+            &vec!["localhost:8080".to_owned()],
+            false,
             &|_| true,
+            &|_| Some("deny prefix mydir/".to_string()),
+            &|_| None,
+        );
+        assert!(!check.allowed);
+        assert_eq!(check.rule, "deny prefix mydir/");
+
+        let check = check_image(
+            "quay.io/mydir/myimage:test",
+            &vec!["localhost:8080".to_owned()],
+            false,
+            &|_| false,
+            &|_| None,
+            &|_| None,
+        );
+        assert!(!check.allowed);
+        assert_eq!(check.rule, "remote image not on allow list");
+    }
+
+    #[test]
+    fn requires_existing_images() {
+        let stored = |i: &Image| i.repo == "mydir/myimage" && i.tag == "sha256:abc";
+        let allow = |_: &Image| Some("allow prefix localhost:8080/".to_string());
+        let hosts = vec!["localhost:8080".to_owned()];
+
+        // Checked by digest if pinned to one
+        let check = check_image(
+            "localhost:8080/mydir/myimage@sha256:abc",
+            &hosts,
+            true,
+            &stored,
+            &|_| None,
+            &allow,
+        );
+        assert!(check.allowed);
+
+        // The allow list no longer lets through images that were never pushed
+        let check = check_image(
+            "localhost:8080/mydir/myimage:tpyo",
+            &hosts,
+            true,
+            &stored,
+            &|_| None,
+            &allow,
+        );
+        assert!(!check.allowed);
+        assert_eq!(check.rule, "existing images required");
+        assert!(
+            check_image(
+                "localhost:8080/mydir/myimage:tpyo",
+                &hosts,
+                false,
+                &stored,
+                &|_| None,
+                &allow,
+            )
+            .allowed
+        );
+
+        assert_eq!(
+            stored_image("localhost:8080/mydir/myimage@sha256:abc"),
+            Image {
+                host: "localhost:8080".to_string(),
+                repo: "mydir/myimage".to_string(),
+                tag: "sha256:abc".to_string(),
+            }
         );
-        assert_eq!(true, v);
     }
 }
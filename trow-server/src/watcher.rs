@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+
+// How long to wait for the filesystem to settle before applying changes.
+// Large rsyncs generate a lot of events, so this batches them up.
+const DEBOUNCE_DELAY: Duration = Duration::from_secs(2);
+
+/*
+ * In-memory index of repositories and their tags.
+ *
+ * Built by walking the manifests directory once at startup and then kept up to date by Trow's
+ * own writes and, when watching is enabled, by changes made to the data directory outside of
+ * Trow (e.g. a restore from backup).
+ *
+ * A BTreeMap is used so the catalog and tag lists come out sorted, which keeps pagination stable.
+ */
+pub struct RepoIndex {
+    manifests_path: PathBuf,
+    repos: RwLock<BTreeMap<String, BTreeSet<String>>>,
+}
+
+impl RepoIndex {
+    pub fn new(manifests_path: &Path) -> Result<RepoIndex> {
+        let index = RepoIndex {
+            manifests_path: manifests_path.to_path_buf(),
+            repos: RwLock::new(BTreeMap::new()),
+        };
+        index.rebuild()?;
+        Ok(index)
+    }
+
+    /// Throws away the current index and walks the manifests directory again.
+    pub fn rebuild(&self) -> Result<()> {
+        let mut repos = BTreeMap::new();
+        add_dir_to_index(&self.manifests_path, &self.manifests_path, &mut repos)?;
+        *self.repos.write().unwrap() = repos;
+        Ok(())
+    }
+
+    pub fn insert(&self, repo_name: &str, tag: &str) {
+        self.repos
+            .write()
+            .unwrap()
+            .entry(repo_name.to_string())
+            .or_insert_with(BTreeSet::new)
+            .insert(tag.to_string());
+    }
+
+    pub fn remove(&self, repo_name: &str, tag: &str) {
+        let mut repos = self.repos.write().unwrap();
+        if let Some(tags) = repos.get_mut(repo_name) {
+            tags.remove(tag);
+            if tags.is_empty() {
+                repos.remove(repo_name);
+            }
+        }
+    }
+
+    pub fn catalog(&self) -> Vec<String> {
+        self.repos.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns None if the repository isn't known
+    pub fn tags(&self, repo_name: &str) -> Option<Vec<String>> {
+        self.repos
+            .read()
+            .unwrap()
+            .get(repo_name)
+            .map(|tags| tags.iter().cloned().collect())
+    }
+
+    // Repository and tag for a path under the manifests directory
+    fn repo_and_tag(&self, path: &Path) -> Option<(String, String)> {
+        let rel = path.strip_prefix(&self.manifests_path).ok()?;
+        let repo = rel.parent()?.to_string_lossy().to_string();
+        let tag = rel.file_name()?.to_string_lossy().to_string();
+        if repo.is_empty() {
+            // Files directly under the manifests dir aren't tags
+            return None;
+        }
+        Some((repo, tag))
+    }
+
+    fn path_added(&self, path: &Path) {
+        if path.is_dir() {
+            let mut repos = BTreeMap::new();
+            if let Err(e) = add_dir_to_index(&self.manifests_path, path, &mut repos) {
+                warn!("Failed to index new directory {:?}: {:?}", path, e);
+                return;
+            }
+            let mut index = self.repos.write().unwrap();
+            for (repo, tags) in repos {
+                index.entry(repo).or_insert_with(BTreeSet::new).extend(tags);
+            }
+        } else if let Some((repo, tag)) = self.repo_and_tag(path) {
+            self.insert(&repo, &tag);
+        }
+    }
+
+    fn path_removed(&self, path: &Path) {
+        // The path is gone, so we can't tell if it was a tag or a whole directory
+        if let Some((repo, tag)) = self.repo_and_tag(path) {
+            self.remove(&repo, &tag);
+        }
+        if let Ok(rel) = path.strip_prefix(&self.manifests_path) {
+            let prefix = rel.to_string_lossy().to_string();
+            let nested = format!("{}/", prefix);
+            self.repos
+                .write()
+                .unwrap()
+                .retain(|repo, _| repo != &prefix && !repo.starts_with(&nested));
+        }
+    }
+
+    /// Applies a filesystem event to the index
+    pub fn apply(&self, event: DebouncedEvent) {
+        match event {
+            DebouncedEvent::Create(p) | DebouncedEvent::Write(p) => {
+                debug!("External change: {:?} added", p);
+                self.path_added(&p);
+            }
+            DebouncedEvent::Remove(p) => {
+                debug!("External change: {:?} removed", p);
+                self.path_removed(&p);
+            }
+            DebouncedEvent::Rename(from, to) => {
+                debug!("External change: {:?} moved to {:?}", from, to);
+                self.path_removed(&from);
+                self.path_added(&to);
+            }
+            DebouncedEvent::Rescan => {
+                info!("Rescanning manifests after watch events were dropped");
+                if let Err(e) = self.rebuild() {
+                    error!("Failed to rebuild repository index {:?}", e);
+                }
+            }
+            DebouncedEvent::Error(e, p) => {
+                warn!("Error watching data directory {:?}: {:?}", p, e);
+            }
+            // Notices are followed by the real event, and permissions don't affect the index
+            DebouncedEvent::NoticeWrite(_)
+            | DebouncedEvent::NoticeRemove(_)
+            | DebouncedEvent::Chmod(_) => {}
+        }
+    }
+}
+
+fn add_dir_to_index(
+    manifests_path: &Path,
+    dir: &Path,
+    repos: &mut BTreeMap<String, BTreeSet<String>>,
+) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            add_dir_to_index(manifests_path, &path, repos)?;
+        } else if dir != manifests_path {
+            let repo = dir
+                .strip_prefix(manifests_path)?
+                .to_string_lossy()
+                .to_string();
+            let tag = entry.file_name().to_string_lossy().to_string();
+            repos.entry(repo).or_insert_with(BTreeSet::new).insert(tag);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Starts a thread which watches the manifests directory and applies any changes to the index.
+ *
+ * Runs for the lifetime of the process.
+ */
+pub fn watch(index: Arc<RepoIndex>) -> Result<()> {
+    let (tx, rx) = channel();
+    let mut w = watcher(tx, DEBOUNCE_DELAY)?;
+    w.watch(&index.manifests_path, RecursiveMode::Recursive)?;
+    info!("Watching {:?} for external changes", index.manifests_path);
+
+    thread::Builder::new()
+        .name("trow-data-watcher".to_string())
+        .spawn(move || {
+            // Watcher stops when dropped, so keep it alive in the thread
+            let _watcher = w;
+            for event in rx {
+                index.apply(event);
+            }
+            warn!("Data directory watcher exited");
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::RepoIndex;
+    use notify::DebouncedEvent;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn index_built_from_manifests() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("org/app")).unwrap();
+        fs::write(dir.path().join("org/app/latest"), "sha256:abc").unwrap();
+        fs::write(dir.path().join("org/app/v1"), "sha256:abc").unwrap();
+        fs::create_dir_all(dir.path().join("onename")).unwrap();
+        fs::write(dir.path().join("onename/tag"), "sha256:abc").unwrap();
+
+        let index = RepoIndex::new(dir.path()).unwrap();
+        assert_eq!(index.catalog(), vec!["onename", "org/app"]);
+        assert_eq!(index.tags("org/app").unwrap(), vec!["latest", "v1"]);
+        assert!(index.tags("missing").is_none());
+    }
+
+    #[test]
+    fn index_follows_external_changes() {
+        let dir = tempdir().unwrap();
+        let index = RepoIndex::new(dir.path()).unwrap();
+        assert!(index.catalog().is_empty());
+
+        // Simulate restoring a repository from backup
+        let restored = dir.path().join("restored/app");
+        fs::create_dir_all(&restored).unwrap();
+        fs::write(restored.join("v2"), "sha256:abc").unwrap();
+        index.apply(DebouncedEvent::Create(dir.path().join("restored")));
+        assert_eq!(index.catalog(), vec!["restored/app"]);
+        assert_eq!(index.tags("restored/app").unwrap(), vec!["v2"]);
+
+        fs::remove_dir_all(dir.path().join("restored")).unwrap();
+        index.apply(DebouncedEvent::Remove(dir.path().join("restored")));
+        assert!(index.catalog().is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/*
+ * Serializes writes to the same tag, blob or upload session, while writes to different ones go
+ * ahead at the same time.
+ *
+ * Two pushes of a tag can't interleave between checking it (e.g. that it's not immutable, or
+ * whether it's new for the quota) and saving it. Two uploads of the same layer are each written
+ * to their own scratch file, but only one at a time is moved into place, so the second finds the
+ * blob already stored and its copy is dropped. Two chunks of an upload can't both find no chunk
+ * being written, and none can start while the upload is being completed.
+ *
+ * Tags are always locked before blobs, and several of either in order, so writers can't each wait
+ * for something the other holds. An upload session is locked before the blob it's saved as.
+ * Locks are only kept while someone holds or waits for them.
+ *
+ * The locks are in memory, so only writes through this backend are serialized. Backends sharing
+ * a data dir with --ha don't see each other's, and file locks aren't used for the same reason as
+ * in lease.rs. Uploads of the same blob are still safe, as the move into place is atomic, but two
+ * pushes of a tag through different backends can both pass the checks.
+ */
+
+#[derive(Clone, Default)]
+pub struct WriteLocks {
+    locks: Arc<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>>,
+}
+
+pub struct WriteGuard {
+    key: String,
+    locks: Arc<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>>,
+    // Dropped after the entry is tidied away, see drop
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl WriteLocks {
+    // Waits for any other write to the tag, or digest reference, to finish
+    pub async fn lock_tag(&self, repo_name: &str, reference: &str) -> WriteGuard {
+        self.lock(format!("tag:{}:{}", repo_name, reference)).await
+    }
+
+    // Waits for any other upload of the blob to be stored
+    pub async fn lock_blob(&self, digest: &str) -> WriteGuard {
+        self.lock(format!("blob:{}", digest)).await
+    }
+
+    // Waits for any other change to the upload's session, e.g. a chunk starting or being stored
+    pub async fn lock_upload(&self, uuid: &str) -> WriteGuard {
+        self.lock(format!("upload:{}", uuid)).await
+    }
+
+    async fn lock(&self, key: String) -> WriteGuard {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        WriteGuard {
+            key,
+            locks: self.locks.clone(),
+            _guard: lock.lock_owned().await,
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+}
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap();
+        // Held by the map and this guard only, so no one is waiting for it
+        if locks
+            .get(&self.key)
+            .map_or(false, |l| Arc::strong_count(l) == 2)
+        {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WriteLocks;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn serializes_writes_to_a_tag() {
+        let locks = WriteLocks::default();
+        let guard = locks.lock_tag("app", "latest").await;
+
+        // Other tags, and blobs, aren't held up
+        let other = timeout(Duration::from_millis(100), locks.lock_tag("app", "v1")).await;
+        assert!(other.is_ok());
+        drop(other);
+        let blob = timeout(Duration::from_millis(100), locks.lock_blob("latest")).await;
+        assert!(blob.is_ok());
+        drop(blob);
+
+        let mut waiting = tokio::spawn({
+            let locks = locks.clone();
+            async move {
+                let _guard = locks.lock_tag("app", "latest").await;
+            }
+        });
+        assert!(timeout(Duration::from_millis(50), &mut waiting)
+            .await
+            .is_err());
+
+        drop(guard);
+        timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(locks.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn serializes_uploads_of_a_blob() {
+        let locks = WriteLocks::default();
+        let guard = locks.lock_blob("sha256:abc").await;
+        assert!(
+            timeout(Duration::from_millis(50), locks.lock_blob("sha256:abc"))
+                .await
+                .is_err()
+        );
+        drop(guard);
+        assert!(
+            timeout(Duration::from_millis(50), locks.lock_blob("sha256:abc"))
+                .await
+                .is_ok()
+        );
+        assert_eq!(locks.len(), 0);
+    }
+}